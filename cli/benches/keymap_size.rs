@@ -0,0 +1,111 @@
+//! Compares the size of some typical keymaps as .ltn files, as JSON (see
+//! `lumatone::batch::ltn_to_json`), and in the compact form, and times encoding and decoding
+//! the compact form. Build with `--features zstd` to include the compressed sizes.
+
+use std::{hint::black_box, time::Instant};
+
+use lumatone::{
+  batch::ltn_to_json,
+  keymap::{
+    compact::Compression,
+    ltn::{KeyDefinition, LumatoneKeyMap},
+  },
+  midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, RGBColor},
+};
+
+const ROUNDS: u32 = 1000;
+
+/// The factory layout: each board plays notes 0-55 on its own channel.
+fn factory() -> LumatoneKeyMap {
+  layout(|loc| {
+    let function = LumatoneKeyFunction::NoteOnOff {
+      channel: MidiChannel::unchecked(loc.board_index() as u8),
+      note_num: loc.key_index().get(),
+    };
+    (function, RGBColor(0x40, 0x40, 0x40))
+  })
+}
+
+/// A 31-EDO layout on a single channel, colored by scale degree.
+fn edo31() -> LumatoneKeyMap {
+  layout(|loc| {
+    let step = (loc.board_index() as u8 - 1) * 10 + loc.key_index().get();
+    let function = LumatoneKeyFunction::NoteOnOff {
+      channel: MidiChannel::unchecked(1),
+      note_num: step,
+    };
+    let degree = (step % 31) as u32;
+    (function, RGBColor::from(0x0f0f0f * (degree % 8) + degree))
+  })
+}
+
+/// A different color on every key, as left by gradient tools.
+fn gradient() -> LumatoneKeyMap {
+  layout(|loc| {
+    let i = (loc.board_index() as usize - 1) * 56 + loc.key_index().get() as usize;
+    let function = LumatoneKeyFunction::NoteOnOff {
+      channel: MidiChannel::unchecked(1),
+      note_num: (i / 3) as u8,
+    };
+    (
+      function,
+      RGBColor(i as u8, (255 - i % 256) as u8, (i / 2) as u8),
+    )
+  })
+}
+
+fn layout<F: Fn(LumatoneKeyLocation) -> (LumatoneKeyFunction, RGBColor)>(f: F) -> LumatoneKeyMap {
+  let mut keymap = LumatoneKeyMap::new();
+  for loc in LumatoneKeyLocation::all() {
+    let (function, color) = f(loc);
+    keymap.set_key(loc, KeyDefinition { function, color });
+  }
+  keymap
+}
+
+fn main() {
+  println!(
+    "{:<10} {:>8} {:>8} {:>8} {:>8}",
+    "layout", "ltn", "json", "compact", "zstd"
+  );
+  for (name, keymap) in [
+    ("factory", factory()),
+    ("31edo", edo31()),
+    ("gradient", gradient()),
+  ] {
+    let ltn = keymap.to_ini_string();
+    let json = ltn_to_json(&keymap.to_ini());
+    let compact = keymap.to_compact(Compression::None);
+    #[cfg(feature = "zstd")]
+    let compressed = keymap.to_compact(Compression::Zstd).len().to_string();
+    #[cfg(not(feature = "zstd"))]
+    let compressed = "-".to_string();
+    println!(
+      "{name:<10} {:>8} {:>8} {:>8} {compressed:>8}",
+      ltn.len(),
+      json.len(),
+      compact.len()
+    );
+  }
+
+  let keymap = edo31();
+  let start = Instant::now();
+  for _ in 0..ROUNDS {
+    black_box(keymap.to_compact(Compression::None));
+  }
+  println!("\nencode {:>10.3?}", start.elapsed() / ROUNDS);
+
+  let bytes = keymap.to_compact(Compression::None);
+  let start = Instant::now();
+  for _ in 0..ROUNDS {
+    black_box(LumatoneKeyMap::from_compact(&bytes).unwrap());
+  }
+  println!("decode {:>10.3?}", start.elapsed() / ROUNDS);
+
+  let ltn = keymap.to_ini_string();
+  let start = Instant::now();
+  for _ in 0..ROUNDS {
+    black_box(LumatoneKeyMap::from_ini_str(&ltn).unwrap());
+  }
+  println!("parse .ltn {:>6.3?}", start.elapsed() / ROUNDS);
+}
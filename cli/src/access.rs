@@ -0,0 +1,228 @@
+//! Token-based access control for the HTTP servers: the REST facade and the metrics
+//! endpoint.
+//!
+//! Each token grants a [Capability]: [Capability::Monitor] can read device info, events and
+//! metrics, and [Capability::Control] can also change things. That way an iPad showing
+//! what's being played can't also upload keymaps. Clients send their token as an
+//! `Authorization: Bearer <token>` header, or as a `?token=` query parameter where headers
+//! can't be set (e.g. a browser `EventSource`).
+//!
+//! Tokens are kept in a file with one `<capability> <token>` per line:
+//!
+//! ```text
+//! # the editor on my laptop
+//! control 6f1d9c2b8e0a4e27
+//! # the iPad on the music stand
+//! monitor 0b7e51aa39c64d08
+//! ```
+//!
+//! [AccessPolicy::open] skips authentication entirely, which is only sensible when a server
+//! is listening on a loopback address.
+//!
+//! The servers speak plain HTTP, so tokens are sent in the clear, and anyone who can watch
+//! the network can pick them up. On a network you don't trust, keep the servers on a
+//! loopback address and reach them through an SSH or TLS tunnel.
+
+use std::{
+  fmt::Display,
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use super::error::LumatoneError;
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+/// What a client is allowed to do. `Control` includes everything `Monitor` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+  /// Read device info, events and metrics.
+  Monitor,
+  /// Also send keymaps, colors and actions to the device.
+  Control,
+}
+
+impl Display for Capability {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Capability::Monitor => write!(f, "monitor"),
+      Capability::Control => write!(f, "control"),
+    }
+  }
+}
+
+impl FromStr for Capability {
+  type Err = error_stack::Report<LumatoneError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "monitor" => Ok(Capability::Monitor),
+      "control" => Ok(Capability::Control),
+      _ => Err(report!(LumatoneError::InvalidAccessPolicy(format!(
+        "unknown capability {s:?}"
+      )))),
+    }
+  }
+}
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDenied {
+  /// The request had no token, or one we don't know.
+  Unauthenticated,
+  /// The token is valid, but doesn't grant the capability the request needs.
+  Forbidden,
+}
+
+impl Display for AccessDenied {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      AccessDenied::Unauthenticated => write!(f, "a valid access token is required"),
+      AccessDenied::Forbidden => write!(f, "this token isn't allowed to do that"),
+    }
+  }
+}
+
+/// Maps access tokens to the [Capability] they grant.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+  tokens: Vec<(String, Capability)>,
+  anonymous: Option<Capability>,
+}
+
+impl AccessPolicy {
+  /// A policy with no tokens, which refuses every request until some are added.
+  pub fn new() -> Self {
+    AccessPolicy::default()
+  }
+
+  /// A policy that gives every request full control, without checking tokens.
+  pub fn open() -> Self {
+    AccessPolicy {
+      tokens: vec![],
+      anonymous: Some(Capability::Control),
+    }
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LumatoneError> {
+    let path: PathBuf = path.as_ref().to_path_buf();
+    let text = std::fs::read_to_string(&path)
+      .report()
+      .change_context(LumatoneError::AccessPolicyLoadFailed(path.clone()))?;
+    text
+      .parse()
+      .change_context(LumatoneError::AccessPolicyLoadFailed(path))
+  }
+
+  pub fn add_token<S: Into<String>>(&mut self, token: S, capability: Capability) {
+    self.tokens.push((token.into(), capability));
+  }
+
+  /// Grants `capability` to requests without a token (or with an unknown one).
+  pub fn allow_anonymous(&mut self, capability: Option<Capability>) {
+    self.anonymous = capability;
+  }
+
+  /// The capability granted by `token`, or to anonymous requests if it's missing or unknown.
+  pub fn capability(&self, token: Option<&str>) -> Option<Capability> {
+    self.token_capability(token).max(self.anonymous)
+  }
+
+  /// Checks that `token` grants at least `needed`.
+  pub fn check(
+    &self,
+    token: Option<&str>,
+    needed: Capability,
+  ) -> std::result::Result<(), AccessDenied> {
+    let known = self.token_capability(token);
+    match known.max(self.anonymous) {
+      Some(capability) if capability >= needed => Ok(()),
+      _ if known.is_some() => Err(AccessDenied::Forbidden),
+      _ => Err(AccessDenied::Unauthenticated),
+    }
+  }
+
+  fn token_capability(&self, token: Option<&str>) -> Option<Capability> {
+    let token = token?;
+    // check every token, so the time taken doesn't hint at which one nearly matched
+    self
+      .tokens
+      .iter()
+      .filter(|(t, _)| constant_time_eq(t.as_bytes(), token.as_bytes()))
+      .map(|(_, capability)| *capability)
+      .max()
+  }
+}
+
+impl FromStr for AccessPolicy {
+  type Err = error_stack::Report<LumatoneError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let mut policy = AccessPolicy::new();
+    for (n, line) in s.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let invalid = || {
+        report!(LumatoneError::InvalidAccessPolicy(format!(
+          "line {}: expected `<capability> <token>`",
+          n + 1
+        )))
+      };
+      let (capability, token) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+      let token = token.trim();
+      if token.len() < 8 {
+        bail!(LumatoneError::InvalidAccessPolicy(format!(
+          "line {}: tokens must be at least 8 characters",
+          n + 1
+        )));
+      }
+      policy.add_token(token, capability.parse()?);
+    }
+    Ok(policy)
+  }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_tokens_grant_capabilities() {
+    let policy: AccessPolicy = "# comment\ncontrol editor-token\nmonitor ipad-token\n"
+      .parse()
+      .unwrap();
+    assert_eq!(
+      policy.check(Some("editor-token"), Capability::Control),
+      Ok(())
+    );
+    assert_eq!(
+      policy.check(Some("ipad-token"), Capability::Monitor),
+      Ok(())
+    );
+    assert_eq!(
+      policy.check(Some("ipad-token"), Capability::Control),
+      Err(AccessDenied::Forbidden)
+    );
+    assert_eq!(
+      policy.check(Some("guess"), Capability::Monitor),
+      Err(AccessDenied::Unauthenticated)
+    );
+    assert_eq!(
+      policy.check(None, Capability::Monitor),
+      Err(AccessDenied::Unauthenticated)
+    );
+
+    assert_eq!(
+      AccessPolicy::open().check(None, Capability::Control),
+      Ok(())
+    );
+    assert!("admin some-token".parse::<AccessPolicy>().is_err());
+    assert!("control short".parse::<AccessPolicy>().is_err());
+  }
+}
@@ -0,0 +1,361 @@
+//! Every user-facing operation as a typed [Action], so frontends (a TUI, the GUI, OSC, MIDI
+//! macros bound to keys) all trigger the same behavior through one entry point,
+//! [ActionDispatcher::dispatch].
+//!
+//! Actions can also be written as text, e.g. `transpose-up` or `upload presets/a.ltn`, for
+//! frontends that bind them by name.
+//!
+//! `set-brightness` fades the LEDs to a percentage of the keymap's colors over
+//! [BRIGHTNESS_RAMP] (see [lumatone_midi::brightness]), e.g. to dim the board in a dark venue.
+//!
+//! "Scale lock" locks the current layout: while it's on, actions that would send a new
+//! keymap to the device (uploads and scenes with a keymap) are refused, so a layout can't be
+//! changed by accident mid-performance.
+//!
+//! Faders and encoders send a stream of small changes; dispatch those with
+//! [ActionDispatcher::dispatch_continuous] so a whole fader move is undone in one step.
+
+use std::{
+  fmt::Display,
+  mem::{discriminant, Discriminant},
+  path::PathBuf,
+  str::FromStr,
+  time::Duration,
+};
+
+use log::info;
+use lumatone_midi::{controller::Lumatone, proxy::NoteMapping};
+
+use super::{
+  error::LumatoneError,
+  scene::{load_keymap_commands, SceneList},
+};
+
+use error_stack::{bail, report, Result, ResultExt};
+
+/// How long a brightness change takes to fade in.
+pub const BRIGHTNESS_RAMP: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+  /// Sends a .ltn preset to the device.
+  Upload(PathBuf),
+
+  /// Transposes the note proxy's output up a semitone.
+  TransposeUp,
+
+  /// Transposes the note proxy's output down a semitone.
+  TransposeDown,
+
+  /// Sets the note proxy's transpose, in semitones.
+  SetTranspose(i8),
+
+  NextScene,
+  PreviousScene,
+  SelectScene(usize),
+  ToggleScaleLock,
+
+  /// Fades the LEDs to a percentage (0 ..= 100) of the keymap's colors.
+  SetBrightness(u8),
+
+  /// Silences everything on the note proxy's output.
+  Panic,
+
+  /// Reverts the last action that changed something, other than [Action::Panic].
+  Undo,
+}
+
+impl Display for Action {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use Action::*;
+    match self {
+      Upload(path) => write!(f, "upload {}", path.display()),
+      TransposeUp => write!(f, "transpose-up"),
+      TransposeDown => write!(f, "transpose-down"),
+      SetTranspose(semitones) => write!(f, "set-transpose {semitones}"),
+      NextScene => write!(f, "next-scene"),
+      PreviousScene => write!(f, "previous-scene"),
+      SelectScene(index) => write!(f, "select-scene {index}"),
+      ToggleScaleLock => write!(f, "toggle-scale-lock"),
+      SetBrightness(percent) => write!(f, "set-brightness {percent}"),
+      Panic => write!(f, "panic"),
+      Undo => write!(f, "undo"),
+    }
+  }
+}
+
+impl FromStr for Action {
+  type Err = error_stack::Report<LumatoneError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    use Action::*;
+    let invalid = || LumatoneError::InvalidAction(s.to_string());
+    let (name, arg) = match s.trim().split_once(' ') {
+      Some((name, arg)) => (name, Some(arg.trim())),
+      None => (s.trim(), None),
+    };
+    let action = match (name, arg) {
+      ("upload", Some(path)) => Upload(PathBuf::from(path)),
+      ("transpose-up", None) => TransposeUp,
+      ("transpose-down", None) => TransposeDown,
+      ("set-transpose", Some(semitones)) => {
+        SetTranspose(semitones.parse().map_err(|_| report!(invalid()))?)
+      }
+      ("next-scene", None) => NextScene,
+      ("previous-scene", None) => PreviousScene,
+      ("select-scene", Some(index)) => SelectScene(index.parse().map_err(|_| report!(invalid()))?),
+      ("toggle-scale-lock", None) => ToggleScaleLock,
+      ("set-brightness", Some(percent)) => match percent.parse() {
+        Ok(percent) if percent <= 100 => SetBrightness(percent),
+        _ => bail!(invalid()),
+      },
+      ("panic", None) => Panic,
+      ("undo", None) => Undo,
+      _ => bail!(invalid()),
+    };
+    Ok(action)
+  }
+}
+
+/// What to restore when an action is undone.
+#[derive(Debug, Clone)]
+enum UndoStep {
+  Upload(PathBuf),
+  Mapping(NoteMapping),
+  Scene(usize),
+  ScaleLock(bool),
+  Brightness(f32),
+}
+
+/// Performs [Action]s on a connected device, keeping the state they need (the current
+/// scene, transpose, and what to undo).
+pub struct ActionDispatcher<'a> {
+  lumatone: &'a Lumatone,
+  scenes: SceneList,
+  mapping: NoteMapping,
+  keymap: Option<PathBuf>,
+  scale_lock: bool,
+  history: Vec<UndoStep>,
+
+  /// The kind of undo step pushed by the last action, if it was dispatched with
+  /// [dispatch_continuous](Self::dispatch_continuous).
+  continuous: Option<Discriminant<UndoStep>>,
+}
+
+impl<'a> ActionDispatcher<'a> {
+  pub fn new(lumatone: &'a Lumatone, scenes: SceneList) -> Self {
+    let mapping = scenes.current().map(|s| s.mapping).unwrap_or_default();
+    ActionDispatcher {
+      lumatone,
+      scenes,
+      mapping,
+      keymap: None,
+      scale_lock: false,
+      history: vec![],
+      continuous: None,
+    }
+  }
+
+  pub fn lumatone(&self) -> &'a Lumatone {
+    self.lumatone
+  }
+
+  pub fn scenes(&self) -> &SceneList {
+    &self.scenes
+  }
+
+  pub fn mapping(&self) -> &NoteMapping {
+    &self.mapping
+  }
+
+  /// The preset most recently sent by an upload or scene, if known.
+  pub fn keymap(&self) -> Option<&PathBuf> {
+    self.keymap.as_ref()
+  }
+
+  pub fn scale_lock(&self) -> bool {
+    self.scale_lock
+  }
+
+  /// Dispatches an action that continues a fader or encoder move. If the previous action
+  /// was also continuous and of the same kind, its undo step is kept and this one isn't
+  /// added, so undoing goes back to where the move started.
+  pub async fn dispatch_continuous(&mut self, action: Action) -> Result<(), LumatoneError> {
+    let merging = self.continuous.take();
+    let depth = self.history.len();
+    self.dispatch(action).await?;
+    if let Some(step) = self.history.get(depth) {
+      let kind = discriminant(step);
+      if merging == Some(kind) {
+        self.history.pop();
+      }
+      self.continuous = Some(kind);
+    }
+    Ok(())
+  }
+
+  pub async fn dispatch(&mut self, action: Action) -> Result<(), LumatoneError> {
+    info!("dispatching action: {action}");
+    self.continuous = None;
+    let failed = || LumatoneError::ActionFailed(action.to_string());
+    match &action {
+      Action::Upload(path) => {
+        let previous = self.keymap.clone();
+        self
+          .upload(path.clone())
+          .await
+          .change_context_lazy(failed)?;
+        if let Some(previous) = previous {
+          self.history.push(UndoStep::Upload(previous));
+        }
+      }
+      Action::TransposeUp | Action::TransposeDown | Action::SetTranspose(_) => {
+        let transpose = match action {
+          Action::TransposeUp => self.mapping.transpose.saturating_add(1),
+          Action::TransposeDown => self.mapping.transpose.saturating_sub(1),
+          Action::SetTranspose(t) => t,
+          _ => unreachable!(),
+        };
+        let mapping = NoteMapping {
+          transpose,
+          ..self.mapping
+        };
+        let previous = self.mapping;
+        self
+          .set_mapping(mapping)
+          .await
+          .change_context_lazy(failed)?;
+        self.history.push(UndoStep::Mapping(previous));
+      }
+      Action::NextScene | Action::PreviousScene | Action::SelectScene(_) => {
+        let index = match action {
+          Action::NextScene => self.scenes.current_index() + 1,
+          Action::PreviousScene => match self.scenes.current_index().checked_sub(1) {
+            Some(i) => i,
+            None => bail!(failed()),
+          },
+          Action::SelectScene(i) => i,
+          _ => unreachable!(),
+        };
+        let previous = self.scenes.current_index();
+        self.select_scene(index).await.change_context_lazy(failed)?;
+        self.history.push(UndoStep::Scene(previous));
+      }
+      Action::ToggleScaleLock => {
+        self.history.push(UndoStep::ScaleLock(self.scale_lock));
+        self.scale_lock = !self.scale_lock;
+        info!("scale lock {}", if self.scale_lock { "on" } else { "off" });
+      }
+      Action::SetBrightness(percent) => {
+        let previous = self.lumatone.brightness();
+        self
+          .lumatone
+          .ramp_brightness(*percent as f32 / 100.0, BRIGHTNESS_RAMP)
+          .await
+          .change_context_lazy(failed)?;
+        self.history.push(UndoStep::Brightness(previous));
+      }
+      Action::Panic => {
+        self.lumatone.panic().await.change_context_lazy(failed)?;
+      }
+      Action::Undo => {
+        // The step stays in the history until it has been undone, so a failed undo can be
+        // retried.
+        let step = match self.history.last() {
+          Some(step) => step.clone(),
+          None => bail!(failed()),
+        };
+        match step {
+          UndoStep::Upload(path) => self.upload(path).await,
+          UndoStep::Mapping(mapping) => self.set_mapping(mapping).await,
+          UndoStep::Scene(index) => self.select_scene(index).await,
+          UndoStep::ScaleLock(locked) => {
+            self.scale_lock = locked;
+            Ok(())
+          }
+          UndoStep::Brightness(level) => self
+            .lumatone
+            .ramp_brightness(level, BRIGHTNESS_RAMP)
+            .await
+            .change_context(LumatoneError::DeviceError),
+        }
+        .change_context_lazy(failed)?;
+        self.history.pop();
+      }
+    }
+    Ok(())
+  }
+
+  async fn upload(&mut self, path: PathBuf) -> Result<(), LumatoneError> {
+    if self.scale_lock {
+      bail!(LumatoneError::ScaleLocked);
+    }
+    self
+      .lumatone
+      .upload(load_keymap_commands(&path)?)
+      .await
+      .change_context(LumatoneError::DeviceError)?;
+    self.keymap = Some(path);
+    Ok(())
+  }
+
+  async fn set_mapping(&mut self, mapping: NoteMapping) -> Result<(), LumatoneError> {
+    let proxy = match self.lumatone.proxy() {
+      Some(p) => p,
+      None => bail!(LumatoneError::ProxyNotRunning),
+    };
+    proxy
+      .set_mapping(mapping)
+      .await
+      .change_context(LumatoneError::DeviceError)?;
+    self.mapping = mapping;
+    Ok(())
+  }
+
+  async fn select_scene(&mut self, index: usize) -> Result<(), LumatoneError> {
+    let has_keymap = match self.scenes.scenes().get(index) {
+      Some(scene) => scene.keymap.is_some(),
+      None => bail!(LumatoneError::SceneNotFound(index)),
+    };
+    if self.scale_lock && has_keymap {
+      bail!(LumatoneError::ScaleLocked);
+    }
+
+    let scene = self.scenes.select(index).expect("scene index was checked");
+    scene.apply(self.lumatone).await?;
+    self.mapping = scene.mapping;
+    if let Some(keymap) = &scene.keymap {
+      self.keymap = Some(keymap.clone());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_action_names() {
+    let actions = [
+      Action::Upload(PathBuf::from("presets/a b.ltn")),
+      Action::TransposeUp,
+      Action::TransposeDown,
+      Action::SetTranspose(-3),
+      Action::NextScene,
+      Action::PreviousScene,
+      Action::SelectScene(3),
+      Action::ToggleScaleLock,
+      Action::SetBrightness(40),
+      Action::Panic,
+      Action::Undo,
+    ];
+    for action in actions {
+      assert_eq!(action.to_string().parse::<Action>().unwrap(), action);
+    }
+    assert!("upload".parse::<Action>().is_err());
+    assert!("select-scene x".parse::<Action>().is_err());
+    assert!("jump".parse::<Action>().is_err());
+    assert!("set-brightness 101".parse::<Action>().is_err());
+  }
+}
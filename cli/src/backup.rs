@@ -0,0 +1,154 @@
+//! Reads the device's whole configuration back into a [LumatoneKeyMap], e.g. to save a
+//! backup before sending a new layout.
+//!
+//! The device has no per-key queries; it reads back a board at a time. So
+//! [request_complete_config] asks each board for its key colors and functions (six queries
+//! per board, see [Lumatone::get_key_colors] and [Lumatone::get_key_functions]), then reads
+//! the response tables and the MIDI channels of the wheels and pedals.
+//!
+//! Some settings can't be read back at all: the macro button colors, the expression pedal
+//! sensitivity, and the on/off [Toggle]s. The toggles are filled in if they were set
+//! through this connection (see [DeviceSettings::get](lumatone_midi::settings::DeviceSettings::get)),
+//! and the rest are left at their defaults, which a .ltn file treats as "leave as is".
+
+use std::collections::HashMap;
+
+use log::info;
+use lumatone_keymap::{
+  ltn::{KeyDefinition, LumatoneKeyMap},
+  tables::{ConfigTableDefinition, TableKind},
+};
+use lumatone_midi::{
+  constants::{BoardIndex, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation, RGBColor},
+  controller::Lumatone,
+  responses::BoardKeyValues,
+  settings::Toggle,
+};
+
+use super::error::LumatoneError;
+
+use error_stack::{report, Result, ResultExt};
+
+/// Reads every key's function and color, the response tables, and the peripheral channels
+/// from the device.
+pub async fn request_complete_config(lumatone: &Lumatone) -> Result<LumatoneKeyMap, LumatoneError> {
+  let mut functions = vec![];
+  let mut colors = vec![];
+  for board in BoardIndex::all_octaves() {
+    info!("reading {board}");
+    functions.push(
+      lumatone
+        .get_key_functions(board)
+        .await
+        .change_context(LumatoneError::DeviceError)?,
+    );
+    colors.push(
+      lumatone
+        .get_key_colors(board)
+        .await
+        .change_context(LumatoneError::DeviceError)?,
+    );
+  }
+  let mut keymap = assemble_keymap(&functions, &colors)?;
+
+  let opts = keymap.global_options_mut();
+  for kind in TableKind::ALL {
+    let response = lumatone
+      .send(kind.get_command())
+      .await
+      .change_context(LumatoneError::DeviceError)?;
+    let table = ConfigTableDefinition::from_response(kind, &response)
+      .map_err(|e| report!(LumatoneError::DeviceError).attach_printable(format!("{e:?}")))?;
+    opts.config_tables.set(kind, Some(table));
+  }
+  let intervals = lumatone
+    .get_velocity_intervals()
+    .await
+    .change_context(LumatoneError::DeviceError)?;
+  opts.config_tables.velocity_intervals = Some(*intervals);
+  let channels = lumatone
+    .get_peripheral_channels()
+    .await
+    .change_context(LumatoneError::DeviceError)?;
+  opts.peripheral_channels = Some(channels);
+
+  let settings = lumatone.settings();
+  let toggles = [
+    (Toggle::LightOnKeystrokes, &mut opts.light_on_key_strokes),
+    (Toggle::InvertSustain, &mut opts.invert_sustain),
+    (
+      Toggle::InvertFootController,
+      &mut opts.invert_foot_controller,
+    ),
+    (Toggle::AftertouchEnabled, &mut opts.after_touch_active),
+  ];
+  for (toggle, value) in toggles {
+    if let Some(on) = settings.get(toggle) {
+      *value = on;
+    }
+  }
+
+  Ok(keymap)
+}
+
+/// Builds a keymap from the functions and colors read back from each board.
+pub fn assemble_keymap(
+  functions: &[BoardKeyValues<LumatoneKeyFunction>],
+  colors: &[BoardKeyValues<RGBColor>],
+) -> Result<LumatoneKeyMap, LumatoneError> {
+  let colors: HashMap<BoardIndex, &[RGBColor]> = colors
+    .iter()
+    .map(|c| (c.board_index, c.values.as_slice()))
+    .collect();
+
+  let mut keymap = LumatoneKeyMap::new();
+  for board_functions in functions {
+    let board = board_functions.board_index;
+    let board_colors = colors.get(&board).ok_or_else(|| {
+      report!(LumatoneError::DeviceError).attach_printable(format!("no colors read for {board}"))
+    })?;
+    let keys = LumatoneKeyIndex::all()
+      .into_iter()
+      .zip(board_functions.values.iter().zip(board_colors.iter()));
+    for (key_index, (function, color)) in keys {
+      keymap.set_key(
+        LumatoneKeyLocation(board, key_index),
+        KeyDefinition {
+          function: *function,
+          color: *color,
+        },
+      );
+    }
+  }
+  Ok(keymap)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::{key_loc_unchecked, MidiChannel};
+
+  #[test]
+  fn test_assemble_keymap() {
+    let function = LumatoneKeyFunction::NoteOnOff {
+      channel: MidiChannel::unchecked(2),
+      note_num: 60,
+    };
+    let functions = vec![BoardKeyValues {
+      board_index: BoardIndex::Octave1,
+      values: vec![function; 56],
+    }];
+    let colors = vec![BoardKeyValues {
+      board_index: BoardIndex::Octave1,
+      values: vec![RGBColor::red(); 56],
+    }];
+
+    let keymap = assemble_keymap(&functions, &colors).unwrap();
+    let key = keymap.get_key(key_loc_unchecked(1, 55)).unwrap();
+    assert_eq!(key.function, function);
+    assert_eq!(key.color, RGBColor::red());
+    assert!(keymap.get_key(key_loc_unchecked(2, 0)).is_none());
+
+    assert!(assemble_keymap(&functions, &[]).is_err());
+  }
+}
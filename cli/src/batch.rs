@@ -0,0 +1,428 @@
+//! Converts whole directories of presets between formats, e.g. to keep a large community
+//! preset archive in a diffable format, or to make thumbnails for a website.
+//!
+//! - `ltn` ↔ `json`: a .ltn preset's sections and values as a JSON object, keyed by section
+//!   name, with the top-level options under `General`. Keys are sorted, so diffs are stable.
+//! - `scl` → `ltn` or `json`: each Scala scale laid out by a
+//!   [keymap template](lumatone_keymap::template), which gives the layout and colors.
+//! - `ltn` or `json` → `svg`: an image of the keyboard (see [lumatone_keymap::svg]), with
+//!   the legend written in the user's [display preferences](Conversion::with_preferences).
+//!
+//! Every file with the input format's extension under the input directory is converted into
+//! the same relative path under the output directory. Files are converted in parallel, and a
+//! file that fails doesn't stop the others; the [BatchReport] says what happened to each.
+
+use std::{
+  fmt::Display,
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+use ini::Ini;
+use lumatone_keymap::{
+  ltn::LumatoneKeyMap,
+  preferences::DisplayPreferences,
+  svg::render_svg_with,
+  template::{KeymapTemplate, TemplateVars},
+  tuning::Tuning,
+};
+use rayon::prelude::*;
+use serde_json::{Map, Value};
+
+use super::error::LumatoneError;
+
+/// The JSON key for the options outside any section of a .ltn file.
+const GENERAL_SECTION: &str = "General";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  Ltn,
+  Json,
+  Scl,
+  Svg,
+}
+
+impl Format {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Format::Ltn => "ltn",
+      Format::Json => "json",
+      Format::Scl => "scl",
+      Format::Svg => "svg",
+    }
+  }
+
+  fn matches(&self, path: &Path) -> bool {
+    path
+      .extension()
+      .map(|ext| ext.eq_ignore_ascii_case(self.extension()))
+      .unwrap_or(false)
+  }
+}
+
+impl Display for Format {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.extension())
+  }
+}
+
+impl FromStr for Format {
+  type Err = error_stack::Report<LumatoneError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s
+      .trim()
+      .trim_start_matches('.')
+      .to_ascii_lowercase()
+      .as_str()
+    {
+      "ltn" => Ok(Format::Ltn),
+      "json" => Ok(Format::Json),
+      "scl" => Ok(Format::Scl),
+      "svg" => Ok(Format::Svg),
+      _ => Err(report!(LumatoneError::InvalidConversion(format!(
+        "unknown format {s:?}"
+      )))),
+    }
+  }
+}
+
+/// What happened to each file in a batch. Paths are the inputs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchReport {
+  pub converted: Vec<PathBuf>,
+
+  /// Files whose output already existed.
+  pub skipped: Vec<PathBuf>,
+
+  /// Files that couldn't be converted, with the reason.
+  pub failed: Vec<(PathBuf, String)>,
+}
+
+impl BatchReport {
+  pub fn is_success(&self) -> bool {
+    self.failed.is_empty()
+  }
+}
+
+impl Display for BatchReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "converted {}, skipped {}, failed {}",
+      self.converted.len(),
+      self.skipped.len(),
+      self.failed.len()
+    )?;
+    for (path, reason) in &self.failed {
+      write!(f, "\n  {}: {reason}", path.display())?;
+    }
+    Ok(())
+  }
+}
+
+enum Outcome {
+  Converted,
+  Skipped,
+  Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Conversion {
+  from: Format,
+  to: Format,
+  layout: Option<KeymapTemplate>,
+  tuning: Tuning,
+  preferences: DisplayPreferences,
+  overwrite: bool,
+}
+
+impl Conversion {
+  /// A conversion from `from` to `to`. Converting scales needs a `layout` template.
+  pub fn new(
+    from: Format,
+    to: Format,
+    layout: Option<KeymapTemplate>,
+  ) -> Result<Self, LumatoneError> {
+    let invalid = |msg: String| report!(LumatoneError::InvalidConversion(msg));
+    match (from, to) {
+      (Format::Svg, _) | (_, Format::Scl) => {
+        bail!(invalid(format!("can't convert {from} to {to}")))
+      }
+      (Format::Scl, Format::Svg) => {
+        bail!(invalid("scales can only be converted to keymaps".into()))
+      }
+      (Format::Scl, _) if layout.is_none() => {
+        bail!(invalid("converting scales needs a layout template".into()))
+      }
+      _ if from == to => bail!(invalid(format!("{from} is already {to}"))),
+      _ => {}
+    }
+    Ok(Conversion {
+      from,
+      to,
+      layout,
+      tuning: Tuning::equal(12),
+      preferences: DisplayPreferences::default(),
+      overwrite: false,
+    })
+  }
+
+  /// Sets the tuning that keys are labelled in when drawing images. Defaults to 12-EDO.
+  pub fn with_tuning(mut self, tuning: Tuning) -> Self {
+    self.tuning = tuning;
+    self
+  }
+
+  /// Sets how images name notes and format cents.
+  pub fn with_preferences(mut self, preferences: DisplayPreferences) -> Self {
+    self.preferences = preferences;
+    self
+  }
+
+  /// Replaces outputs that already exist, instead of skipping their inputs.
+  pub fn overwriting(mut self, overwrite: bool) -> Self {
+    self.overwrite = overwrite;
+    self
+  }
+
+  /// Converts the contents of one file. `name` is the file's name without its extension,
+  /// used as the title of images.
+  pub fn convert(&self, source: &str, name: &str) -> Result<String, LumatoneError> {
+    let failed = || LumatoneError::ConversionFailed(PathBuf::from(name));
+    let keymap_failed = |e| report!(failed()).attach_printable(format!("{e:?}"));
+    let (ini, keymap) = match self.from {
+      Format::Ltn | Format::Json => {
+        let ini = match self.from {
+          Format::Ltn => {
+            Ini::load_from_str(source).map_err(|e| report!(failed()).attach_printable(e))?
+          }
+          _ => ltn_from_json(source).change_context_lazy(failed)?,
+        };
+        let keymap = LumatoneKeyMap::from_ini(&ini).map_err(keymap_failed)?;
+        (ini, keymap)
+      }
+      Format::Scl => {
+        let layout = self.layout.as_ref().ok_or_else(|| report!(failed()))?;
+        let keymap = Tuning::from_scl_str(source)
+          .and_then(|scale| layout.render_scale(&TemplateVars::new(), &scale))
+          .map_err(keymap_failed)?
+          .keymap;
+        (keymap.to_ini(), keymap)
+      }
+      Format::Svg => bail!(failed()),
+    };
+    match self.to {
+      Format::Ltn => {
+        let mut out = vec![];
+        ini
+          .write_to(&mut out)
+          .report()
+          .change_context_lazy(failed)?;
+        Ok(String::from_utf8_lossy(&out).to_string())
+      }
+      Format::Json => Ok(ltn_to_json(&ini)),
+      Format::Svg => Ok(render_svg_with(
+        &keymap,
+        name,
+        &self.tuning,
+        &self.preferences,
+      )),
+      Format::Scl => bail!(failed()),
+    }
+  }
+
+  /// Converts every matching file under `input` into `output`, on up to `jobs` threads.
+  pub fn run(
+    &self,
+    input: &Path,
+    output: &Path,
+    jobs: usize,
+  ) -> Result<BatchReport, LumatoneError> {
+    let mut inputs = vec![];
+    self.find_inputs(input, &mut inputs)?;
+    inputs.sort();
+
+    let pool = rayon::ThreadPoolBuilder::new()
+      .num_threads(jobs.max(1))
+      .build()
+      .report()
+      .change_context(LumatoneError::InvalidConversion(format!(
+        "unable to start {jobs} threads"
+      )))?;
+    let outcomes: Vec<Outcome> = pool.install(|| {
+      inputs
+        .par_iter()
+        .map(|path| match self.convert_file(path, input, output) {
+          Ok(outcome) => outcome,
+          Err(e) => Outcome::Failed(format!("{e:#}")),
+        })
+        .collect()
+    });
+
+    let mut report = BatchReport::default();
+    for (path, outcome) in inputs.into_iter().zip(outcomes) {
+      match outcome {
+        Outcome::Converted => report.converted.push(path),
+        Outcome::Skipped => report.skipped.push(path),
+        Outcome::Failed(reason) => report.failed.push((path, reason)),
+      }
+    }
+    Ok(report)
+  }
+
+  fn find_inputs(&self, dir: &Path, inputs: &mut Vec<PathBuf>) -> Result<(), LumatoneError> {
+    let entries = std::fs::read_dir(dir)
+      .report()
+      .change_context_lazy(|| LumatoneError::ConversionFailed(dir.to_path_buf()))?;
+    for entry in entries.flatten() {
+      let path = entry.path();
+      if path.is_dir() {
+        self.find_inputs(&path, inputs)?;
+      } else if self.from.matches(&path) {
+        inputs.push(path);
+      }
+    }
+    Ok(())
+  }
+
+  fn convert_file(
+    &self,
+    path: &Path,
+    input: &Path,
+    output: &Path,
+  ) -> Result<Outcome, LumatoneError> {
+    let failed = || LumatoneError::ConversionFailed(path.to_path_buf());
+    let relative = path.strip_prefix(input).unwrap_or(path);
+    let destination = output.join(relative).with_extension(self.to.extension());
+    if destination.exists() && !self.overwrite {
+      return Ok(Outcome::Skipped);
+    }
+
+    let source = std::fs::read_to_string(path)
+      .report()
+      .change_context_lazy(failed)?;
+    let name = path
+      .file_stem()
+      .map(|s| s.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let converted = self.convert(&source, &name).change_context_lazy(failed)?;
+    if let Some(parent) = destination.parent() {
+      std::fs::create_dir_all(parent)
+        .report()
+        .change_context_lazy(failed)?;
+    }
+    std::fs::write(&destination, converted)
+      .report()
+      .change_context_lazy(failed)?;
+    Ok(Outcome::Converted)
+  }
+}
+
+/// The sections and values of a .ltn file as a JSON object.
+pub fn ltn_to_json(ini: &Ini) -> String {
+  let mut sections = Map::new();
+  for (name, properties) in ini.iter() {
+    let values: Map<String, Value> = properties
+      .iter()
+      .map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+      .collect();
+    if !values.is_empty() {
+      sections.insert(
+        name.unwrap_or(GENERAL_SECTION).to_string(),
+        Value::Object(values),
+      );
+    }
+  }
+  serde_json::to_string_pretty(&Value::Object(sections))
+    .expect("ini values are always serializable")
+}
+
+/// Reads a .ltn file written as JSON by [ltn_to_json]. Values may be strings or numbers.
+pub fn ltn_from_json(source: &str) -> Result<Ini, LumatoneError> {
+  let invalid = |msg: String| report!(LumatoneError::InvalidConversion(msg));
+  let sections: Map<String, Value> =
+    serde_json::from_str(source).map_err(|e| invalid(format!("invalid keymap JSON: {e}")))?;
+  let mut ini = Ini::new();
+  for (name, values) in sections {
+    let values = values
+      .as_object()
+      .ok_or_else(|| invalid(format!("section {name} isn't an object")))?;
+    let section = match name.as_str() {
+      GENERAL_SECTION => None,
+      _ => Some(name.clone()),
+    };
+    for (key, value) in values {
+      let value = match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => (*b as u8).to_string(),
+        _ => bail!(invalid(format!("invalid value for {key} in {name}"))),
+      };
+      ini.with_section(section.clone()).set(key, value);
+    }
+  }
+  Ok(ini)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_keymap::{geometry::key_at, layout::IsomorphicLayout};
+  use lumatone_midi::constants::RGBColor;
+
+  const LAYOUT: &str = "
+[Layout]
+Right=2
+DownRight=-5
+Anchor=3:27
+AnchorNote=60
+Colors=ff0000,0000ff
+";
+
+  #[test]
+  fn test_batch_convert() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let (input, output) = (dir.join("in"), dir.join("out"));
+    std::fs::create_dir_all(input.join("nested")).unwrap();
+
+    let layout = IsomorphicLayout::new(2, -5, key_at(3, 27).unwrap(), 60);
+    let keymap = layout.generate(&Tuning::equal(12), &[RGBColor::red(), RGBColor::blue()]);
+    std::fs::write(input.join("a.ltn"), keymap.to_ini_string()).unwrap();
+    std::fs::write(input.join("nested/b.ltn"), keymap.to_ini_string()).unwrap();
+    std::fs::write(input.join("broken.ltn"), "Col_0=zzz\n[Board0]\nCol_0=zzz\n").unwrap();
+
+    let to_json = Conversion::new(Format::Ltn, Format::Json, None).unwrap();
+    let report = to_json.run(&input, &output, 4).unwrap();
+    assert_eq!(
+      report.converted,
+      vec![input.join("a.ltn"), input.join("nested/b.ltn")]
+    );
+    assert_eq!(report.failed.len(), 1);
+    assert!(!report.is_success());
+
+    let json = std::fs::read_to_string(output.join("nested/b.json")).unwrap();
+    assert_eq!(ltn_to_json(&ltn_from_json(&json).unwrap()), json);
+    assert_eq!(
+      ltn_to_json(&Ini::load_from_str(&keymap.to_ini_string()).unwrap()),
+      json
+    );
+    assert_eq!(to_json.run(&input, &output, 4).unwrap().skipped.len(), 2);
+
+    let to_svg = Conversion::new(Format::Json, Format::Svg, None).unwrap();
+    let report = to_svg.run(&output, &output, 2).unwrap();
+    assert_eq!(report.converted.len(), 2);
+    assert!(output.join("a.svg").exists());
+
+    let template = KeymapTemplate::from_ini_str(LAYOUT).unwrap();
+    assert!(Conversion::new(Format::Scl, Format::Ltn, None).is_err());
+    assert!(Conversion::new(Format::Ltn, Format::Ltn, None).is_err());
+    let from_scale = Conversion::new(Format::Scl, Format::Ltn, Some(template)).unwrap();
+    let ltn = from_scale
+      .convert("Pentatonic\n5\n9/8\n5/4\n3/2\n5/3\n2/1\n", "penta")
+      .unwrap();
+    assert!(LumatoneKeyMap::from_ini_str(ltn).is_ok());
+  }
+}
@@ -0,0 +1,278 @@
+//! Binds faders, wheels and CC-configured keys on the board to parameters of the software,
+//! for hands-on control without reaching for the computer.
+//!
+//! Each [Binding] in a [ParameterBindings] table maps one controller to a
+//! [ParameterTarget]: the note proxy's transpose, the current scene, the LED brightness, or
+//! a named value for the application to use (e.g. animation speed). Transpose, scene and
+//! brightness changes go through an [ActionDispatcher], so they behave exactly like the same actions
+//! triggered any other way, and a fader move is undone in one step (see
+//! [ActionDispatcher::dispatch_continuous]). Changes to named values are broadcast to
+//! [subscribers](ParameterBindings::subscribe).
+//!
+//! Absolute and relative bindings share each parameter's current value. While
+//! [running](ParameterBindings::run), transpose, scene and brightness are read back from the
+//! dispatcher before each event, so an encoder continues from wherever a fader or any other
+//! action left the parameter.
+//!
+//! Controllers are still passed through the note proxy to the synth. Block them with a
+//! [CcMap](lumatone_midi::cc_map::CcMap) if the synth shouldn't see them.
+
+use std::collections::HashMap;
+
+use log::warn;
+use lumatone_midi::{constants::MidiChannel, events::ChannelMessage};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{
+  actions::{Action, ActionDispatcher},
+  error::LumatoneError,
+};
+
+use error_stack::{Result, ResultExt};
+
+/// Number of parameter changes buffered for each subscriber before the oldest are dropped.
+const CHANGES_BUFFER_SIZE: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ParameterTarget {
+  /// The note proxy's transpose, in semitones.
+  Transpose,
+
+  /// The index of the current scene.
+  SceneIndex,
+
+  /// The LED brightness, from 0 to 1 (see [lumatone_midi::brightness]).
+  Brightness,
+
+  /// A value for the application, reported to subscribers.
+  Named(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindingMode {
+  /// The controller's value sets the parameter, scaled from 0 ..= 127 to the binding's
+  /// range. For faders and wheels.
+  Absolute,
+
+  /// The controller sends increments, for jog wheels and endless encoders: values 1 ..= 63
+  /// step up and 65 ..= 127 step down (127 is -1), each step being `step`.
+  Relative { step: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+  /// If `None`, the binding matches the controller on every channel.
+  pub channel: Option<MidiChannel>,
+  pub controller: u8,
+  pub target: ParameterTarget,
+  pub min: f32,
+  pub max: f32,
+  pub mode: BindingMode,
+}
+
+impl Binding {
+  pub fn new(controller: u8, target: ParameterTarget, min: f32, max: f32) -> Self {
+    Binding {
+      channel: None,
+      controller,
+      target,
+      min,
+      max,
+      mode: BindingMode::Absolute,
+    }
+  }
+
+  fn matches(&self, channel: MidiChannel, controller: u8) -> bool {
+    self.controller == controller && self.channel.is_none_or(|c| c == channel)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterChange {
+  pub target: ParameterTarget,
+  pub value: f32,
+}
+
+pub struct ParameterBindings {
+  bindings: Vec<Binding>,
+  values: HashMap<ParameterTarget, f32>,
+  changes: broadcast::Sender<ParameterChange>,
+}
+
+impl ParameterBindings {
+  pub fn new(bindings: Vec<Binding>) -> Self {
+    let (changes, _) = broadcast::channel(CHANGES_BUFFER_SIZE);
+    ParameterBindings {
+      bindings,
+      values: HashMap::new(),
+      changes,
+    }
+  }
+
+  pub fn bindings(&self) -> &[Binding] {
+    &self.bindings
+  }
+
+  /// The current value of a parameter, if a binding has set it.
+  pub fn value(&self, target: &ParameterTarget) -> Option<f32> {
+    self.values.get(target).copied()
+  }
+
+  /// Sets a parameter's current value, e.g. its starting point for relative bindings.
+  pub fn set_value(&mut self, target: ParameterTarget, value: f32) {
+    self.values.insert(target, value);
+  }
+
+  /// Reads the current transpose, scene and brightness back from `dispatcher`.
+  fn sync_values(&mut self, dispatcher: &ActionDispatcher<'_>) {
+    let transpose = dispatcher.mapping().transpose as f32;
+    let scene = dispatcher.scenes().current_index() as f32;
+    let brightness = dispatcher.lumatone().brightness();
+    self.set_value(ParameterTarget::Transpose, transpose);
+    self.set_value(ParameterTarget::SceneIndex, scene);
+    self.set_value(ParameterTarget::Brightness, brightness);
+  }
+
+  /// Returns a receiver for changes to [ParameterTarget::Named] parameters.
+  pub fn subscribe(&self) -> broadcast::Receiver<ParameterChange> {
+    self.changes.subscribe()
+  }
+
+  /// Updates the parameter bound to `msg`'s controller, using the first matching binding,
+  /// and returns its new value. Returns `None` if nothing is bound or the value didn't
+  /// change.
+  pub fn evaluate(&mut self, msg: &ChannelMessage) -> Option<ParameterChange> {
+    let (channel, controller, value) = match *msg {
+      ChannelMessage::ControlChange {
+        channel,
+        controller,
+        value,
+      } => (channel, controller, value),
+      _ => return None,
+    };
+    let binding = self
+      .bindings
+      .iter()
+      .find(|b| b.matches(channel, controller))?;
+    let (low, high) = (binding.min.min(binding.max), binding.min.max(binding.max));
+
+    let new_value = match binding.mode {
+      BindingMode::Absolute => binding.min + (binding.max - binding.min) * value as f32 / 127.0,
+      BindingMode::Relative { step } => {
+        let delta = match value {
+          0 => return None,
+          1..=63 => value as f32,
+          _ => value as f32 - 128.0,
+        };
+        let current = self.values.get(&binding.target).copied().unwrap_or(low);
+        current + delta * step
+      }
+    }
+    .clamp(low, high);
+
+    let target = binding.target.clone();
+    if self.values.insert(target.clone(), new_value) == Some(new_value) {
+      return None;
+    }
+    Some(ParameterChange {
+      target,
+      value: new_value,
+    })
+  }
+
+  /// Evaluates the bindings for each event from the device, until the event stream closes
+  /// or the device shuts down. Transpose, scene and brightness changes are dispatched as
+  /// [Action]s.
+  pub async fn run(&mut self, dispatcher: &mut ActionDispatcher<'_>) -> Result<(), LumatoneError> {
+    let lumatone = dispatcher.lumatone();
+    let mut events = lumatone
+      .driver()
+      .subscribe_events()
+      .change_context(LumatoneError::DeviceError)?;
+    let shutdown = lumatone.shutdown_token();
+
+    loop {
+      let msg = tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        res = events.recv() => match res {
+          Ok(msg) => msg,
+          Err(RecvError::Lagged(n)) => {
+            warn!("parameter bindings missed {n} events");
+            lumatone.driver().count_missed(n);
+            continue;
+          }
+          Err(RecvError::Closed) => return Ok(()),
+        },
+      };
+
+      self.sync_values(dispatcher);
+      let change = match self.evaluate(&msg) {
+        Some(c) => c,
+        None => continue,
+      };
+      let action = match &change.target {
+        ParameterTarget::Transpose => {
+          let transpose = change.value.round() as i8;
+          if transpose == dispatcher.mapping().transpose {
+            continue;
+          }
+          Action::SetTranspose(transpose)
+        }
+        ParameterTarget::SceneIndex => {
+          let index = change.value.round().max(0.0) as usize;
+          if index == dispatcher.scenes().current_index() {
+            continue;
+          }
+          Action::SelectScene(index)
+        }
+        ParameterTarget::Brightness => {
+          let percent = (change.value.clamp(0.0, 1.0) * 100.0).round();
+          if percent == (lumatone.brightness() * 100.0).round() {
+            continue;
+          }
+          Action::SetBrightness(percent as u8)
+        }
+        ParameterTarget::Named(_) => {
+          // No subscribers isn't an error.
+          let _ = self.changes.send(change);
+          continue;
+        }
+      };
+      if let Err(err) = dispatcher.dispatch_continuous(action).await {
+        warn!("{err:?}");
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_evaluate_bindings() {
+    let brightness = ParameterTarget::Named("brightness".to_string());
+    let mut bindings = ParameterBindings::new(vec![
+      Binding::new(1, brightness.clone(), 0.0, 1.0),
+      Binding {
+        mode: BindingMode::Relative { step: 1.0 },
+        ..Binding::new(2, ParameterTarget::Transpose, -12.0, 12.0)
+      },
+    ]);
+    let cc = |controller, value| ChannelMessage::ControlChange {
+      channel: MidiChannel::default(),
+      controller,
+      value,
+    };
+
+    assert_eq!(bindings.evaluate(&cc(1, 127)).unwrap().value, 1.0);
+    assert_eq!(bindings.evaluate(&cc(1, 127)), None);
+    assert_eq!(bindings.value(&brightness), Some(1.0));
+    assert_eq!(bindings.evaluate(&cc(3, 127)), None);
+
+    bindings.set_value(ParameterTarget::Transpose, 0.0);
+    assert_eq!(bindings.evaluate(&cc(2, 2)).unwrap().value, 2.0);
+    assert_eq!(bindings.evaluate(&cc(2, 127)).unwrap().value, 1.0);
+    assert_eq!(bindings.evaluate(&cc(2, 50)).unwrap().value, 12.0);
+  }
+}
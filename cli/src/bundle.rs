@@ -0,0 +1,642 @@
+//! Bundles package a set list with everything it refers to (the scenes' keymaps and
+//! lighting themes) into one zip file, so a complete performance setup can be shared. Other
+//! files, like the tunings the keymaps were made from, can be added with
+//! [Bundle::add_asset].
+//!
+//! A bundle contains:
+//!
+//! ```text
+//! bundle.ini          name, author and description (see [BundleMetadata])
+//! setlist.ini         the set list, with keymap and theme paths relative to the bundle root
+//! keymaps/*.ltn      or *.ltnk, in the compact form (see [Bundle::add_keymap])
+//! tunings/*
+//! themes/*
+//! ```
+//!
+//! Loading a bundle refuses files that would unpack outside the import directory, or to
+//! more than their declared size, so a malicious bundle can't overwrite other files or
+//! exhaust memory.
+//!
+//! [Bundle::import] unpacks a bundle into a directory. Files that already exist with the
+//! same contents are reused; files that exist with different contents are handled
+//! according to a [CollisionPolicy].
+
+use std::{
+  collections::BTreeMap,
+  io::{Cursor, Read, Write},
+  path::{Path, PathBuf},
+};
+
+use ini::Ini;
+use lumatone_keymap::{
+  compact::{self, COMPACT_EXTENSION},
+  ltn::LumatoneKeyMap,
+};
+
+use zip::{write::FileOptions, CompressionMethod, ZipArchive};
+
+use super::{error::LumatoneError, scene::Scene, setlist::SetList};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+/// The newest version of the bundle format this version of the library reads and writes.
+/// Version 2 added compact keymaps; bundles without any are still written as version 1, so
+/// older versions can read them.
+pub const BUNDLE_FORMAT_VERSION: u32 = 2;
+
+const METADATA_FILE: &str = "bundle.ini";
+const SET_LIST_FILE: &str = "setlist.ini";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+  Keymap,
+  Tuning,
+  Theme,
+}
+
+impl AssetKind {
+  /// The directory the bundle stores this kind of asset in.
+  pub fn dir(&self) -> &'static str {
+    match self {
+      AssetKind::Keymap => "keymaps",
+      AssetKind::Tuning => "tunings",
+      AssetKind::Theme => "themes",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BundleMetadata {
+  pub name: String,
+  pub author: String,
+  pub description: String,
+}
+
+/// What to do when an imported file already exists with different contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollisionPolicy {
+  /// Use the existing file, and don't import the bundle's.
+  KeepExisting,
+
+  /// Replace the existing file.
+  Overwrite,
+
+  /// Import the bundle's file under a new name, e.g. `song-2.ltn`.
+  Rename,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportOutcome {
+  Written,
+
+  /// The file already existed with the same contents.
+  Identical,
+  KeptExisting,
+  Overwritten,
+  Renamed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedFile {
+  /// The file's path within the bundle.
+  pub name: String,
+
+  /// Where the file was imported to, or the existing file used instead.
+  pub path: PathBuf,
+  pub outcome: ImportOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+  /// The imported set list, with keymap paths pointing at the imported files.
+  pub set_list: SetList,
+
+  /// Where the set list file was written.
+  pub set_list_path: PathBuf,
+
+  pub files: Vec<ImportedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+  pub metadata: BundleMetadata,
+
+  /// The set list, with keymap paths relative to the root of the bundle.
+  pub set_list: SetList,
+
+  files: BTreeMap<String, Vec<u8>>,
+}
+
+impl Bundle {
+  pub fn new(metadata: BundleMetadata) -> Self {
+    Bundle {
+      metadata,
+      set_list: SetList::default(),
+      files: BTreeMap::new(),
+    }
+  }
+
+  /// Bundles `set_list`, reading each scene's keymap and theme and pointing the scene at
+  /// their copies in the bundle.
+  pub fn from_set_list(
+    metadata: BundleMetadata,
+    set_list: &SetList,
+  ) -> Result<Bundle, LumatoneError> {
+    let mut bundle = Bundle::new(metadata);
+    let mut set_list = set_list.clone();
+    let mut added: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+    for scene in set_list.songs.iter_mut().flat_map(|s| s.scenes.iter_mut()) {
+      for (kind, asset) in scene_assets(scene) {
+        let path = match asset {
+          Some(p) => p,
+          None => continue,
+        };
+        if let Some(name) = added.get(&*path) {
+          *path = name.clone();
+          continue;
+        }
+        let contents = std::fs::read(&*path)
+          .report()
+          .change_context_lazy(|| match kind {
+            AssetKind::Theme => LumatoneError::ThemeLoadFailed(path.clone()),
+            _ => LumatoneError::KeymapLoadFailed(path.clone()),
+          })?;
+        let file_name = path
+          .file_name()
+          .map(|n| n.to_string_lossy().to_string())
+          .unwrap_or_else(|| "asset".to_string());
+        let name = PathBuf::from(bundle.add_asset(kind, &file_name, contents));
+        added.insert(path.clone(), name.clone());
+        *path = name;
+      }
+    }
+    bundle.set_list = set_list;
+    Ok(bundle)
+  }
+
+  /// Adds a file to the bundle, and returns its path within the bundle. If a different
+  /// file with the same name was already added, the new one is renamed; identical files are
+  /// only stored once.
+  pub fn add_asset(&mut self, kind: AssetKind, file_name: &str, contents: Vec<u8>) -> String {
+    let name = format!("{}/{file_name}", kind.dir());
+    let name = unique_name(&name, |n| self.files.get(n).is_some_and(|c| *c != contents));
+    self.files.insert(name.clone(), contents);
+    name
+  }
+
+  /// Adds a keymap in the compact form, and returns its path within the bundle. Bundles are
+  /// already compressed, so the keymap isn't compressed again.
+  pub fn add_keymap(&mut self, name: &str, keymap: &LumatoneKeyMap) -> String {
+    let contents = keymap.to_compact(compact::Compression::None);
+    self.add_asset(
+      AssetKind::Keymap,
+      &format!("{name}.{COMPACT_EXTENSION}"),
+      contents,
+    )
+  }
+
+  /// The oldest format version that can hold this bundle.
+  fn format_version(&self) -> u32 {
+    match self.files.values().any(|c| compact::is_compact(c)) {
+      true => 2,
+      false => 1,
+    }
+  }
+
+  /// The bundle's files other than the metadata and set list, by path within the bundle.
+  pub fn files(&self) -> &BTreeMap<String, Vec<u8>> {
+    &self.files
+  }
+
+  pub fn to_zip(&self) -> Vec<u8> {
+    let mut metadata = Ini::new();
+    metadata
+      .with_section(Some("Bundle"))
+      .set("Name", &self.metadata.name)
+      .set("Author", &self.metadata.author)
+      .set("Description", &self.metadata.description)
+      .set("FormatVersion", self.format_version().to_string());
+
+    let mut zip = ZipWriter::default();
+    zip.add(METADATA_FILE, &ini_to_bytes(&metadata));
+    zip.add(
+      SET_LIST_FILE,
+      &ini_to_bytes(&self.set_list.to_ini(Path::new(""))),
+    );
+    for (name, contents) in &self.files {
+      zip.add(name, contents);
+    }
+    zip.finish()
+  }
+
+  pub fn from_zip(bytes: &[u8]) -> Result<Bundle, LumatoneError> {
+    let mut files = read_zip(bytes)?;
+    let mut take_ini = |name: &str| {
+      let bytes = files
+        .remove(name)
+        .ok_or_else(|| report!(invalid(&format!("missing {name}"))))?;
+      let s = String::from_utf8(bytes)
+        .report()
+        .change_context_lazy(|| invalid(&format!("{name} isn't UTF-8")))?;
+      Ini::load_from_str(&s)
+        .report()
+        .change_context_lazy(|| invalid(&format!("{name} isn't a valid ini file")))
+    };
+
+    let metadata_ini = take_ini(METADATA_FILE)?;
+    let set_list_ini = take_ini(SET_LIST_FILE)?;
+    let section = metadata_ini
+      .section(Some("Bundle"))
+      .ok_or_else(|| report!(invalid("missing [Bundle] section")))?;
+    let version: u32 = section
+      .get("FormatVersion")
+      .and_then(|v| v.parse().ok())
+      .unwrap_or(0);
+    if version > BUNDLE_FORMAT_VERSION {
+      bail!(invalid(&format!(
+        "format version {version} is newer than this version of lumatone supports"
+      )));
+    }
+    let metadata = BundleMetadata {
+      name: section.get("Name").unwrap_or_default().to_string(),
+      author: section.get("Author").unwrap_or_default().to_string(),
+      description: section.get("Description").unwrap_or_default().to_string(),
+    };
+    let set_list = SetList::from_ini(&set_list_ini, Path::new(""))?;
+    for scene in set_list.songs.iter().flat_map(|s| s.scenes.iter()) {
+      for path in scene.keymap.iter().chain(scene.theme.iter()) {
+        if !files.contains_key(&path.to_string_lossy().to_string()) {
+          bail!(invalid(&format!(
+            "scene {} refers to {}, which isn't in the bundle",
+            scene.name,
+            path.display()
+          )));
+        }
+      }
+    }
+    Ok(Bundle {
+      metadata,
+      set_list,
+      files,
+    })
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Bundle, LumatoneError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)
+      .report()
+      .change_context(LumatoneError::BundleLoadFailed(path.to_path_buf()))?;
+    Bundle::from_zip(&bytes).change_context(LumatoneError::BundleLoadFailed(path.to_path_buf()))
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LumatoneError> {
+    let path = path.as_ref();
+    std::fs::write(path, self.to_zip())
+      .report()
+      .change_context(LumatoneError::BundleSaveFailed(path.to_path_buf()))
+  }
+
+  /// Unpacks the bundle into `dir`, keeping the bundle's layout, and writes the set list to
+  /// `<dir>/<set list name>.ini`.
+  pub fn import(&self, dir: &Path, policy: CollisionPolicy) -> Result<ImportReport, LumatoneError> {
+    let failed = || LumatoneError::BundleImportFailed(dir.to_path_buf());
+    let mut files = vec![];
+    let mut paths: BTreeMap<&str, PathBuf> = BTreeMap::new();
+    for (name, contents) in &self.files {
+      let (path, outcome) = import_file(dir, name, contents, policy).change_context_lazy(failed)?;
+      paths.insert(name, path.clone());
+      files.push(ImportedFile {
+        name: name.clone(),
+        path,
+        outcome,
+      });
+    }
+
+    // Saved with paths relative to `dir`, returned with the full paths.
+    let mut set_list = self.set_list.clone();
+    for scene in set_list.songs.iter_mut().flat_map(|s| s.scenes.iter_mut()) {
+      for (_, asset) in scene_assets(scene) {
+        if let Some(path) = asset {
+          if let Some(imported) = paths.get(path.to_string_lossy().as_ref()) {
+            *path = imported.clone();
+          }
+        }
+      }
+    }
+    let file_name = match sanitize_file_name(&self.set_list.name) {
+      name if name.is_empty() => "setlist.ini".to_string(),
+      name => format!("{name}.ini"),
+    };
+    let (set_list_path, _) = import_file(
+      dir,
+      &file_name,
+      &ini_to_bytes(&set_list.to_ini(dir)),
+      policy,
+    )
+    .change_context_lazy(failed)?;
+
+    Ok(ImportReport {
+      set_list,
+      set_list_path,
+      files,
+    })
+  }
+}
+
+/// The files a scene refers to, and what kind of asset each is.
+fn scene_assets(scene: &mut Scene) -> [(AssetKind, &mut Option<PathBuf>); 2] {
+  [
+    (AssetKind::Keymap, &mut scene.keymap),
+    (AssetKind::Theme, &mut scene.theme),
+  ]
+}
+
+fn invalid(msg: &str) -> LumatoneError {
+  LumatoneError::InvalidBundle(msg.to_string())
+}
+
+fn ini_to_bytes(ini: &Ini) -> Vec<u8> {
+  let mut bytes = vec![];
+  ini
+    .write_to(&mut bytes)
+    .expect("writing to a Vec can't fail");
+  bytes
+}
+
+/// Replaces characters that can't be used in file names.
+fn sanitize_file_name(name: &str) -> String {
+  name
+    .trim()
+    .chars()
+    .map(|c| match c {
+      '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+      c => c,
+    })
+    .collect()
+}
+
+/// Returns `name`, or `name` with a number added before the extension (`song-2.ltn`,
+/// `song-3.ltn`, ...), whichever is the first for which `taken` returns false.
+fn unique_name<F: Fn(&str) -> bool>(name: &str, taken: F) -> String {
+  if !taken(name) {
+    return name.to_string();
+  }
+  let (stem, ext) = match name.rfind('.') {
+    Some(i) if i > name.rfind('/').map_or(0, |j| j + 1) => name.split_at(i),
+    _ => (name, ""),
+  };
+  (2..)
+    .map(|n| format!("{stem}-{n}{ext}"))
+    .find(|n| !taken(n))
+    .unwrap()
+}
+
+/// Writes `contents` to `dir/name`, handling an existing file according to `policy`.
+fn import_file(
+  dir: &Path,
+  name: &str,
+  contents: &[u8],
+  policy: CollisionPolicy,
+) -> Result<(PathBuf, ImportOutcome), LumatoneError> {
+  let differs = |path: &Path| !std::fs::read(path).is_ok_and(|c| c == contents);
+  let path = dir.join(name);
+  let (path, outcome) = match policy {
+    _ if !path.exists() => (path, ImportOutcome::Written),
+    _ if !differs(&path) => return Ok((path, ImportOutcome::Identical)),
+    CollisionPolicy::KeepExisting => return Ok((path, ImportOutcome::KeptExisting)),
+    CollisionPolicy::Overwrite => (path, ImportOutcome::Overwritten),
+    CollisionPolicy::Rename => {
+      // reuse a copy renamed by an earlier import
+      let name = unique_name(name, |n| {
+        let path = dir.join(n);
+        path.exists() && differs(&path)
+      });
+      let path = dir.join(name);
+      if path.exists() {
+        return Ok((path, ImportOutcome::Identical));
+      }
+      (path, ImportOutcome::Renamed)
+    }
+  };
+  let write_failed = || LumatoneError::BundleImportFailed(path.clone());
+  if let Some(parent) = path.parent() {
+    std::fs::create_dir_all(parent)
+      .report()
+      .change_context_lazy(write_failed)?;
+  }
+  std::fs::write(&path, contents)
+    .report()
+    .change_context_lazy(write_failed)?;
+  Ok((path, outcome))
+}
+
+/// The largest file a bundle may unpack to, so a small archive can't expand to fill memory.
+const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The most a whole bundle may unpack to.
+const MAX_TOTAL_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Writes deflated zip archives, for bundles and bug reports.
+pub(crate) struct ZipWriter {
+  zip: zip::ZipWriter<Cursor<Vec<u8>>>,
+}
+
+impl Default for ZipWriter {
+  fn default() -> Self {
+    ZipWriter {
+      zip: zip::ZipWriter::new(Cursor::new(vec![])),
+    }
+  }
+}
+
+impl ZipWriter {
+  pub(crate) fn add(&mut self, name: &str, contents: &[u8]) {
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    self
+      .zip
+      .start_file(name, options)
+      .expect("writing to a Vec can't fail");
+    self
+      .zip
+      .write_all(contents)
+      .expect("writing to a Vec can't fail");
+  }
+
+  pub(crate) fn finish(mut self) -> Vec<u8> {
+    self
+      .zip
+      .finish()
+      .expect("writing to a Vec can't fail")
+      .into_inner()
+  }
+}
+
+/// Reads every file in a zip archive, by path within the archive. Fails if a file would be
+/// unpacked outside the directory it's imported into, or unpacks to more than its declared
+/// size or [MAX_FILE_SIZE].
+pub(crate) fn read_zip(bytes: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, LumatoneError> {
+  let mut archive = ZipArchive::new(Cursor::new(bytes))
+    .report()
+    .change_context_lazy(|| invalid("not a zip file"))?;
+  let mut files = BTreeMap::new();
+  let mut total = 0;
+  for i in 0..archive.len() {
+    let mut file = archive
+      .by_index(i)
+      .report()
+      .change_context_lazy(|| invalid("bad central directory entry"))?;
+    if file.is_dir() {
+      continue;
+    }
+    let name = file.name().to_string();
+    // Don't let a file escape the directory it's imported into.
+    if file.enclosed_name().is_none() || name.contains('\\') {
+      bail!(invalid(&format!("unsafe file name: {name}")));
+    }
+
+    let size = file.size();
+    total += size;
+    if size > MAX_FILE_SIZE || total > MAX_TOTAL_SIZE {
+      bail!(invalid(&format!("{name} is too large to unpack")));
+    }
+    // The declared size can't be trusted either, so read at most one byte past it.
+    let mut contents = Vec::with_capacity(size as usize);
+    (&mut file)
+      .take(size + 1)
+      .read_to_end(&mut contents)
+      .report()
+      .change_context_lazy(|| invalid(&format!("{name} is corrupt")))?;
+    if contents.len() as u64 != size {
+      bail!(invalid(&format!(
+        "{name} doesn't unpack to its declared size of {size} bytes"
+      )));
+    }
+    files.insert(name, contents);
+  }
+  Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::setlist::Song;
+
+  #[test]
+  fn test_bundle_round_trip_and_import_collisions() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let source = dir.join("source");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("song.ltn"), "[Board0]\n").unwrap();
+    std::fs::write(
+      source.join("dark.ini"),
+      "[Options]\nLightOnKeyStrokes=0>1\n",
+    )
+    .unwrap();
+
+    let mut a = Scene::new("a");
+    a.keymap = Some(source.join("song.ltn"));
+    let b = a.clone();
+    a.theme = Some(source.join("dark.ini"));
+    let set_list = SetList {
+      name: "gig".to_string(),
+      songs: vec![Song::new("first", vec![a, b])],
+      ..Default::default()
+    };
+    let metadata = BundleMetadata {
+      name: "Friday".to_string(),
+      author: "me".to_string(),
+      description: "".to_string(),
+    };
+    let mut bundle = Bundle::from_set_list(metadata, &set_list).unwrap();
+    bundle.add_asset(AssetKind::Tuning, "31edo.scl", b"31 EDO\n".to_vec());
+    assert_eq!(
+      bundle.files().keys().collect::<Vec<_>>(),
+      ["keymaps/song.ltn", "themes/dark.ini", "tunings/31edo.scl"]
+    );
+    assert_eq!(
+      bundle.set_list.songs[0].scenes[0].theme,
+      Some(PathBuf::from("themes/dark.ini"))
+    );
+    // the same keymap is only stored once
+    assert_eq!(
+      bundle.add_asset(AssetKind::Keymap, "song.ltn", b"[Board0]\n".to_vec()),
+      "keymaps/song.ltn"
+    );
+    assert_eq!(
+      bundle
+        .clone()
+        .add_asset(AssetKind::Keymap, "song.ltn", b"other".to_vec()),
+      "keymaps/song-2.ltn"
+    );
+
+    let parsed = Bundle::from_zip(&bundle.to_zip()).unwrap();
+    assert_eq!(parsed, bundle);
+    assert_eq!(bundle.format_version(), 1);
+
+    let mut compact = bundle.clone();
+    let keymap = LumatoneKeyMap::from_ini_str("[Board0]\n").unwrap();
+    assert_eq!(compact.add_keymap("song", &keymap), "keymaps/song.ltnk");
+    assert_eq!(compact.format_version(), 2);
+    let parsed_compact = Bundle::from_zip(&compact.to_zip()).unwrap();
+    let contents = &parsed_compact.files()["keymaps/song.ltnk"];
+    assert_eq!(
+      LumatoneKeyMap::from_bytes(contents).unwrap().fingerprint(),
+      keymap.fingerprint()
+    );
+
+    let target = dir.join("library");
+    std::fs::create_dir_all(target.join("keymaps")).unwrap();
+    std::fs::write(target.join("keymaps/song.ltn"), "something else").unwrap();
+    let report = parsed.import(&target, CollisionPolicy::Rename).unwrap();
+    let outcome = |name: &str| {
+      report
+        .files
+        .iter()
+        .find(|f| f.name == name)
+        .map(|f| (f.path.clone(), f.outcome))
+        .unwrap()
+    };
+    assert_eq!(
+      outcome("keymaps/song.ltn"),
+      (target.join("keymaps/song-2.ltn"), ImportOutcome::Renamed)
+    );
+    assert_eq!(
+      report.set_list.songs[0].scenes[1].keymap,
+      Some(target.join("keymaps/song-2.ltn"))
+    );
+    assert_eq!(
+      report.set_list.songs[0].scenes[0].theme,
+      Some(target.join("themes/dark.ini"))
+    );
+    assert_eq!(
+      SetList::load(&report.set_list_path).unwrap(),
+      report.set_list
+    );
+
+    // importing again reuses the identical files
+    let again = parsed.import(&target, CollisionPolicy::Rename).unwrap();
+    assert_eq!(outcome("tunings/31edo.scl").1, ImportOutcome::Written);
+    assert!(again
+      .files
+      .iter()
+      .all(|f| f.outcome == ImportOutcome::Identical));
+    assert_eq!(again.set_list, report.set_list);
+  }
+
+  #[test]
+  fn test_files_larger_than_declared_are_refused() {
+    let mut zip = ZipWriter::default();
+    zip.add("keymaps/big.ltn", &[b'x'; 1000]);
+    let mut bytes = zip.finish();
+    assert_eq!(read_zip(&bytes).unwrap()["keymaps/big.ltn"].len(), 1000);
+
+    // declare one byte less than the file unpacks to
+    let central = bytes
+      .windows(4)
+      .position(|w| w == [0x50, 0x4b, 0x01, 0x02])
+      .unwrap();
+    bytes[central + 24..central + 28].copy_from_slice(&999u32.to_le_bytes());
+    assert!(read_zip(&bytes).is_err());
+  }
+}
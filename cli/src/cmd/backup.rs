@@ -0,0 +1,15 @@
+use std::path::PathBuf;
+
+use lumatone::{backup::request_complete_config, prelude::Lumatone};
+
+/// Reads the device's configuration back and saves it as a .ltn preset to `output`.
+pub async fn run_backup(output: &PathBuf) {
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+  let keymap = request_complete_config(&lumatone)
+    .await
+    .expect("unable to read configuration");
+  lumatone.shutdown().await;
+
+  std::fs::write(output, keymap.to_ini_string()).expect("unable to save preset");
+  println!("saved the device's configuration to {}", output.display());
+}
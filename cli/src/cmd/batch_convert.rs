@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use lumatone::{
+  batch::{Conversion, Format},
+  keymap::{template::KeymapTemplate, tuning::Tuning},
+  preferences::{load_preferences, preferences_file},
+};
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_batch_convert(
+  input: &Path,
+  output: &Path,
+  from: &str,
+  to: &str,
+  layout: &Option<PathBuf>,
+  divisions: usize,
+  overwrite: bool,
+  jobs: Option<usize>,
+) {
+  let from: Format = from.parse().expect("invalid input format");
+  let to: Format = to.parse().expect("invalid output format");
+  let layout = layout.as_ref().map(|path| {
+    let contents = fs::read_to_string(path).expect("unable to read layout template");
+    KeymapTemplate::from_ini_str(contents).expect("unable to load layout template")
+  });
+  let conversion = Conversion::new(from, to, layout)
+    .expect("unsupported conversion")
+    .with_tuning(Tuning::equal(divisions))
+    .with_preferences(load_preferences(preferences_file()).expect("unable to load preferences"))
+    .overwriting(overwrite);
+
+  let jobs = jobs.unwrap_or_else(|| {
+    std::thread::available_parallelism()
+      .map(|n| n.get())
+      .unwrap_or(1)
+  });
+  let report = conversion
+    .run(input, output, jobs)
+    .expect("unable to convert presets");
+  println!("{report}");
+  if !report.is_success() {
+    std::process::exit(1);
+  }
+}
@@ -0,0 +1,30 @@
+use std::fs;
+use std::path::PathBuf;
+
+use lumatone::keymap::template::{KeymapTemplate, TemplateVars};
+
+fn parse_vars(vars: &[String]) -> TemplateVars {
+  vars
+    .iter()
+    .map(|v| {
+      let (name, value) = v
+        .split_once('=')
+        .expect("template variables must be in the form name=value");
+      (name.trim().to_string(), value.trim().to_string())
+    })
+    .collect()
+}
+
+pub fn run_build_keymap(template: &PathBuf, vars: &[String], output: &Option<PathBuf>) {
+  let contents = fs::read_to_string(template).expect("unable to read template");
+  let template = KeymapTemplate::from_ini_str(contents).expect("unable to load template");
+  let rendered = template
+    .render(&parse_vars(vars))
+    .expect("unable to render template");
+
+  let output = output
+    .clone()
+    .unwrap_or_else(|| PathBuf::from(format!("{}.ltn", rendered.name)));
+  fs::write(&output, rendered.keymap.to_ini_string()).expect("unable to write keymap");
+  println!("wrote {} to {}", rendered.name, output.display());
+}
@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+
+use lumatone::{
+  midi::conformance::{conformance_queries, record_fixtures, restoring_commands, FixtureSet},
+  prelude::Lumatone,
+};
+
+/// Records the device's response to every query, and with `writes` to the commands that
+/// write the queried settings back, and saves them as conformance fixtures to `output`.
+pub async fn run_conformance(output: &PathBuf, writes: bool) {
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+  let firmware = lumatone
+    .sync_firmware_version()
+    .await
+    .expect("unable to read firmware version");
+
+  let driver = lumatone.driver();
+  let (fixtures, mut failures) = record_fixtures(&driver, conformance_queries()).await;
+  let mut set = FixtureSet {
+    firmware: Some(firmware),
+    fixtures,
+  };
+  if writes {
+    let (fixtures, write_failures) = record_fixtures(&driver, restoring_commands(&set)).await;
+    set.fixtures.extend(fixtures);
+    failures.extend(write_failures);
+  }
+  lumatone.shutdown().await;
+
+  set.save(output).expect("unable to save fixtures");
+  println!(
+    "recorded {} fixtures from firmware {firmware} to {}",
+    set.fixtures.len(),
+    output.display()
+  );
+  for (command, reason) in &failures {
+    eprintln!("no fixture for {command}: {reason}");
+  }
+}
@@ -1,19 +1,9 @@
-use lumatone_midi::{
-  commands::set_key_color,
-  constants::{LumatoneKeyLocation, RGBColor},
-  detect::detect_device,
-  driver::MidiDriver,
-};
+use lumatone::prelude::{set_key_color, Lumatone, LumatoneKeyLocation, RGBColor};
 
 use log::debug;
-use tokio;
 
 pub async fn run_debug_cmd() {
-  let device = detect_device().await.expect("device detection failed");
-  let (driver, driver_future) = MidiDriver::new(&device).expect("driver creation failed");
-
-  debug!("starting driver loop");
-  let h = tokio::spawn(driver_future);
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
   debug!("driver loop spawned");
 
   let commands = LumatoneKeyLocation::all()
@@ -23,11 +13,10 @@ pub async fn run_debug_cmd() {
   debug!("sending commands");
   for c in commands {
     debug!("sending command");
-    let res = driver.send(c).await;
+    let res = lumatone.send(c).await;
     debug!("received response: {res:?}");
   }
 
-  debug!("sending done signal");
-  driver.done().await.expect("error sending done signal");
-  tokio::join!(h).0.expect("error joining driver future");
+  debug!("shutting down");
+  lumatone.shutdown().await;
 }
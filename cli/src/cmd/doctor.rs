@@ -0,0 +1,34 @@
+use lumatone::{midi::diagnostics::hints, prelude::Lumatone};
+
+pub async fn run_doctor() {
+  let lumatone = match Lumatone::detect().await {
+    Ok(lumatone) => lumatone,
+    Err(report) => {
+      println!("device not found");
+      let hints = hints(&report);
+      if hints.is_empty() {
+        println!("{report:?}");
+      }
+      for hint in hints {
+        println!("  {hint}");
+      }
+      return;
+    }
+  };
+
+  match lumatone.device_info() {
+    Some(info) => {
+      println!("serial number:    {}", info.serial);
+      println!("firmware version: {}", info.firmware_version);
+      println!("input port:       {}", info.in_port_name);
+      println!("output port:      {}", info.out_port_name);
+      println!("boards found:     {}", info.board_count());
+      for board in &info.boards {
+        println!("  {}: {} keys", board.board_index, board.key_count);
+      }
+    }
+    None => println!("device detected, but didn't respond to info queries"),
+  }
+
+  lumatone.shutdown().await;
+}
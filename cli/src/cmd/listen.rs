@@ -0,0 +1,15 @@
+use tokio::net::TcpListener;
+
+/// Listens on `addr`. Without access tokens (`open`), only loopback addresses are allowed.
+pub async fn bind(addr: &str, open: bool) -> TcpListener {
+  let listener = TcpListener::bind(addr)
+    .await
+    .expect("unable to listen on address");
+  let local = listener
+    .local_addr()
+    .expect("unable to read listening address");
+  if open && !local.ip().is_loopback() {
+    panic!("refusing to listen on {local} without access tokens, pass --tokens");
+  }
+  listener
+}
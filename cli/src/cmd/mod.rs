@@ -1,29 +1,424 @@
+mod backup;
+mod batch_convert;
+mod build_keymap;
+mod conformance;
 mod debug;
+mod doctor;
+mod listen;
+mod play_macro;
+mod preferences;
+mod record_session;
+mod render_keymap;
+mod report;
+#[cfg(feature = "rest")]
+mod rest;
+mod retune;
 mod send_preset;
+mod service;
+#[cfg(feature = "soak")]
+mod soak;
+mod velocity_intervals;
+mod verify_colors;
 
 use clap::Subcommand;
+use lumatone::midi::readback::{Sample, VerifyOptions};
 use std::path::PathBuf;
 
-use self::{debug::run_debug_cmd, send_preset::run_send_preset};
+use self::{
+  backup::run_backup, batch_convert::run_batch_convert, build_keymap::run_build_keymap,
+  conformance::run_conformance, debug::run_debug_cmd, doctor::run_doctor,
+  play_macro::run_play_macro, preferences::run_preferences, record_session::run_record_session,
+  render_keymap::run_render_keymap, report::run_report, retune::run_retune_cmd,
+  send_preset::run_send_preset, service::run_service_cmd,
+  velocity_intervals::run_velocity_intervals, verify_colors::run_verify_colors,
+};
+
+#[cfg(feature = "rest")]
+use self::rest::run_rest;
+#[cfg(feature = "soak")]
+use self::soak::run_soak_cmd;
 
 #[derive(Subcommand)]
 pub enum CliCommand {
+  /// Reads the device's key configuration and tables back and saves them as a .ltn preset,
+  /// e.g. to back it up before sending a new one. Macro button colors and the on/off
+  /// settings can't be read back
+  Backup {
+    /// Where to write the preset
+    #[clap(value_parser)]
+    output: PathBuf,
+  },
+
+  /// Converts every preset in a directory to another format (see the `batch` module docs),
+  /// e.g. .ltn to JSON, Scala scales to .ltn, or .ltn to SVG thumbnails
+  BatchConvert {
+    #[clap(value_parser)]
+    input: PathBuf,
+
+    /// The directory to write the converted files to, with the same layout as the input
+    #[clap(value_parser)]
+    output: PathBuf,
+
+    /// The format to convert from: ltn, json or scl
+    #[clap(long)]
+    from: String,
+
+    /// The format to convert to: ltn, json or svg
+    #[clap(long)]
+    to: String,
+
+    /// A keymap template to lay out scales with, when converting from scl
+    #[clap(long, value_parser)]
+    layout: Option<PathBuf>,
+
+    /// The number of equal divisions of the octave keys are labelled in, for svg
+    #[clap(long, default_value_t = 12)]
+    divisions: usize,
+
+    /// Replace converted files that already exist
+    #[clap(long)]
+    overwrite: bool,
+
+    /// The number of files to convert at once. Defaults to the number of CPU cores
+    #[clap(long)]
+    jobs: Option<usize>,
+  },
+
+  /// Generates a .ltn preset from a keymap template (see the keymap `template` module docs)
+  BuildKeymap {
+    #[clap(value_parser)]
+    template: PathBuf,
+
+    /// A value for one of the template's variables, e.g. `--set root=D`
+    #[clap(long = "set")]
+    vars: Vec<String>,
+
+    /// Where to write the preset. Defaults to `<template name>.ltn`
+    #[clap(long, short, value_parser)]
+    output: Option<PathBuf>,
+  },
+
+  /// Records the device's responses to every query as fixtures for the emulator and golden
+  /// tests (see the midi `conformance` module docs)
+  Conformance {
+    /// Where to write the fixtures
+    #[clap(value_parser)]
+    output: PathBuf,
+
+    /// Also record the commands that change settings, by writing back the values read
+    #[clap(long)]
+    writes: bool,
+  },
+
   /// Does quick sanity-check debugging stuff. Actual behavior subject to change as I muck with things.
   Debug,
 
+  /// Detects the device and prints its serial number, firmware version, and connected boards,
+  /// or hints about why it can't be found
+  Doctor,
+
+  /// Sends the commands in a recorded macro file to the device
+  PlayMacro {
+    #[clap(value_parser)]
+    path: PathBuf,
+
+    /// A value for one of the macro's parameters, e.g. `--param cc=64`
+    #[clap(long = "param")]
+    params: Vec<String>,
+  },
+
+  /// Shows how notes are named and cents are formatted in keymap images, and changes it
+  /// with `--set`, e.g. `--set accidentals=flats` (see the keymap `preferences` module docs)
+  Preferences {
+    /// A setting to change, as `name=value`
+    #[clap(long = "set")]
+    settings: Vec<String>,
+  },
+
+  /// Records what's played on the device to a session file, which can be replayed on the
+  /// LEDs or used to train a phrase (see the midi `session` module docs)
+  RecordSession {
+    /// Where to write the session
+    #[clap(value_parser)]
+    output: PathBuf,
+
+    /// Stop after this many seconds. Otherwise, records until ctrl-c
+    #[clap(long)]
+    duration_secs: Option<u64>,
+  },
+
+  /// Draws a .ltn preset as an SVG image, with a legend of its colors and channels
+  RenderKeymap {
+    #[clap(value_parser)]
+    preset: PathBuf,
+
+    /// The heading of the legend, e.g. the scale's name. Defaults to the preset's file name
+    #[clap(long)]
+    title: Option<String>,
+
+    /// The number of equal divisions of the octave the keys are labelled in
+    #[clap(long, default_value_t = 12)]
+    divisions: usize,
+
+    /// The pitch index (note number plus 128 per channel above 1) of scale degree 0
+    #[clap(long, default_value_t = 60)]
+    root: i32,
+
+    /// Where to write the image. Defaults to `<preset name>.svg`
+    #[clap(long, short, value_parser)]
+    output: Option<PathBuf>,
+  },
+
+  /// Writes a redacted archive of device info and recent logs to attach to a bug report
+  Report {
+    /// Where to write the archive. Defaults to `lumatone-report-<time>.zip`
+    #[clap(long, short, value_parser)]
+    output: Option<PathBuf>,
+
+    /// Don't try to connect to the device
+    #[clap(long)]
+    no_device: bool,
+  },
+
+  /// Serves a small HTTP API for the device (see the `rest` module docs for the endpoints)
+  #[cfg(feature = "rest")]
+  Rest {
+    /// The address to listen on
+    #[clap(long, default_value = "127.0.0.1:8330")]
+    listen: String,
+
+    /// Also serve Prometheus metrics at `/metrics` on this address, e.g. `0.0.0.0:9330`
+    #[clap(long)]
+    metrics: Option<String>,
+
+    /// A file of access tokens (see the `access` module docs). Required unless listening
+    /// on a loopback address
+    #[clap(long, value_parser)]
+    tokens: Option<PathBuf>,
+
+    /// Let clients change this resource (e.g. `keymap` or `proxy`) even while another
+    /// client has locked it. Locks on other resources block changes until released
+    #[clap(long = "last-writer-wins")]
+    last_writer_wins: Vec<String>,
+  },
+
+  /// Sends a preset, then retunes it as MIDI Tuning Standard changes arrive from a tuning
+  /// source, e.g. an MTS-ESP bridge, until ctrl-c. The synth's pitch bends follow the
+  /// tuning through the note proxy
+  Retune {
+    #[clap(value_parser)]
+    preset: PathBuf,
+
+    /// The MIDI input port the tuning source sends to
+    #[clap(long)]
+    tuning_port: String,
+
+    /// The MIDI output port of the synth that plays the Lumatone's notes
+    #[clap(long)]
+    synth_port: String,
+
+    /// A Scala scale to start from. Defaults to 12-TET
+    #[clap(long, value_parser)]
+    scale: Option<PathBuf>,
+
+    /// The synth's pitch bend range, in semitones
+    #[clap(long, default_value_t = 2.0)]
+    bend_range: f64,
+  },
+
   /// Sends a .ltn preset file to the device
   SendPreset {
     #[clap(value_parser)]
     preset: PathBuf,
+
+    /// Read the keys back after sending, re-send any that don't match, and report how
+    /// confident we can be that the preset landed
+    #[clap(long)]
+    verify: bool,
+
+    /// Only read back this many keys, chosen at random, instead of all of them
+    #[clap(long, requires = "verify")]
+    sample: Option<usize>,
+
+    /// How many times to re-send keys that don't match
+    #[clap(long, requires = "verify", default_value_t = 2)]
+    retries: usize,
+  },
+
+  /// Waits for the device, configures it, and keeps it configured, e.g. as a system service
+  /// that restores the rig after power-up
+  Service {
+    /// The .ltn preset to apply
+    #[clap(
+      long,
+      value_parser,
+      conflicts_with = "set-list",
+      required_unless_present = "set-list"
+    )]
+    keymap: Option<PathBuf>,
+
+    /// A set list to take the scene from
+    #[clap(long, value_parser, requires = "scene")]
+    set_list: Option<PathBuf>,
+
+    /// The name of the scene to apply
+    #[clap(long, requires = "set-list")]
+    scene: Option<String>,
+
+    /// How often to check the device is still there, in seconds
+    #[clap(long, default_value_t = 5)]
+    health_interval_secs: u64,
+
+    /// Serve Prometheus metrics at `/metrics` on this address, e.g. `0.0.0.0:9330`
+    #[clap(long)]
+    metrics: Option<String>,
+
+    /// A file of access tokens for the metrics endpoint (see the `access` module docs).
+    /// Required unless listening on a loopback address
+    #[clap(long, value_parser)]
+    tokens: Option<PathBuf>,
+  },
+
+  /// Runs mixed traffic against the device for a long time and reports stalls, leaks, etc.
+  #[cfg(feature = "soak")]
+  Soak {
+    /// How long to run for, in seconds
+    #[clap(long, default_value_t = 3600)]
+    duration_secs: u64,
+  },
+
+  /// Prints the device's velocity interval table, or sends one to it
+  VelocityIntervals {
+    /// A file with the table to send, in the same format as a .ltn preset's
+    /// `VelocityIntrvlTbl`
+    #[clap(long, value_parser)]
+    set: Option<PathBuf>,
+  },
+
+  /// Reads back the key colors from the device and compares them with a .ltn preset file
+  VerifyColors {
+    #[clap(value_parser)]
+    preset: PathBuf,
   },
 }
 
 impl CliCommand {
+  /// Whether this is the long-running `service` command, which starts a new log file.
+  pub fn is_daemon(&self) -> bool {
+    matches!(self, Self::Service { .. })
+  }
+
   pub async fn run(&self) {
     match self {
+      Self::Backup { output } => run_backup(output).await,
+
+      Self::BatchConvert {
+        input,
+        output,
+        from,
+        to,
+        layout,
+        divisions,
+        overwrite,
+        jobs,
+      } => run_batch_convert(
+        input, output, from, to, layout, *divisions, *overwrite, *jobs,
+      ),
+
+      Self::BuildKeymap {
+        template,
+        vars,
+        output,
+      } => run_build_keymap(template, vars, output),
+
+      Self::Conformance { output, writes } => run_conformance(output, *writes).await,
+
       Self::Debug => run_debug_cmd().await,
 
-      Self::SendPreset { preset } => run_send_preset(preset).await,
+      Self::Doctor => run_doctor().await,
+
+      Self::PlayMacro { path, params } => run_play_macro(path, params).await,
+
+      Self::Preferences { settings } => run_preferences(settings),
+
+      Self::RecordSession {
+        output,
+        duration_secs,
+      } => run_record_session(output, *duration_secs).await,
+
+      Self::RenderKeymap {
+        preset,
+        title,
+        divisions,
+        root,
+        output,
+      } => run_render_keymap(preset, title, *divisions, *root, output),
+
+      Self::Report { output, no_device } => run_report(output, *no_device).await,
+
+      #[cfg(feature = "rest")]
+      Self::Rest {
+        listen,
+        metrics,
+        tokens,
+        last_writer_wins,
+      } => {
+        run_rest(
+          listen,
+          metrics.as_deref(),
+          tokens.as_ref(),
+          last_writer_wins,
+        )
+        .await
+      }
+
+      Self::Retune {
+        preset,
+        tuning_port,
+        synth_port,
+        scale,
+        bend_range,
+      } => run_retune_cmd(preset, tuning_port, synth_port, scale, *bend_range).await,
+
+      Self::SendPreset {
+        preset,
+        verify,
+        sample,
+        retries,
+      } => {
+        let verify = verify.then(|| VerifyOptions {
+          sample: sample.map_or(Sample::All, Sample::Random),
+          max_retries: *retries,
+          ..Default::default()
+        });
+        run_send_preset(preset, verify).await
+      }
+
+      Self::Service {
+        keymap,
+        set_list,
+        scene,
+        health_interval_secs,
+        metrics,
+        tokens,
+      } => {
+        run_service_cmd(
+          keymap,
+          set_list,
+          scene,
+          *health_interval_secs,
+          metrics.as_deref(),
+          tokens.as_ref(),
+        )
+        .await
+      }
+
+      #[cfg(feature = "soak")]
+      Self::Soak { duration_secs } => run_soak_cmd(*duration_secs).await,
+
+      Self::VelocityIntervals { set } => run_velocity_intervals(set).await,
+
+      Self::VerifyColors { preset } => run_verify_colors(preset).await,
     }
   }
 }
@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use lumatone::{
+  midi::recorder::{CommandMacro, MacroParams},
+  prelude::Lumatone,
+};
+
+/// Parses `name=value` pairs into [MacroParams].
+fn parse_params(params: &[String]) -> MacroParams {
+  params
+    .iter()
+    .map(|p| {
+      let (name, value) = p
+        .split_once('=')
+        .expect("macro parameters must be in the form name=value");
+      let value = value
+        .parse()
+        .expect("macro parameter values must be 0 - 127");
+      (name.to_string(), value)
+    })
+    .collect()
+}
+
+pub async fn run_play_macro(path: &PathBuf, params: &[String]) {
+  let command_macro = CommandMacro::load(path).expect("unable to load macro");
+  let params = parse_params(params);
+
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+
+  log::debug!("playing macro {}", command_macro.name);
+  match lumatone.play_macro(&command_macro, &params).await {
+    Ok(responses) => println!("sent {} commands", responses.len()),
+    Err(err) => eprintln!("error playing macro: {err:?}"),
+  }
+
+  lumatone.shutdown().await;
+}
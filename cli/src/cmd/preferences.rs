@@ -0,0 +1,13 @@
+use lumatone::preferences::{load_preferences, preferences_file, save_preferences, set_preference};
+
+pub fn run_preferences(settings: &[String]) {
+  let path = preferences_file();
+  let mut prefs = load_preferences(&path).expect("unable to load preferences");
+  if !settings.is_empty() {
+    for setting in settings {
+      prefs = set_preference(&prefs, setting).expect("invalid preference");
+    }
+    save_preferences(&path, &prefs).expect("unable to save preferences");
+  }
+  print!("{prefs}");
+}
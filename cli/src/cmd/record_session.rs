@@ -0,0 +1,39 @@
+use std::{path::PathBuf, time::Duration};
+
+use lumatone::{
+  midi::{session::SessionRecorder, shutdown::CancellationToken},
+  prelude::Lumatone,
+};
+
+pub async fn run_record_session(output: &PathBuf, duration_secs: Option<u64>) {
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+
+  let stop = CancellationToken::new();
+  let stopper = stop.clone();
+  tokio::spawn(async move {
+    let limit = async {
+      match duration_secs {
+        Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+        None => std::future::pending().await,
+      }
+    };
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => {}
+      _ = limit => {}
+    }
+    stopper.cancel();
+  });
+
+  println!("recording, press ctrl-c to stop");
+  let mut recorder = SessionRecorder::new();
+  recorder
+    .run(&lumatone.driver(), &stop)
+    .await
+    .expect("unable to record events");
+
+  let session = recorder.finish();
+  session.save(output).expect("unable to save session");
+  println!("recorded {} messages", session.len());
+
+  lumatone.shutdown().await;
+}
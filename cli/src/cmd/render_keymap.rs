@@ -0,0 +1,33 @@
+use std::fs;
+use std::path::PathBuf;
+
+use lumatone::{
+  keymap::{ltn::LumatoneKeyMap, svg::render_svg_with, tuning::Tuning},
+  preferences::{load_preferences, preferences_file},
+};
+
+pub fn run_render_keymap(
+  preset: &PathBuf,
+  title: &Option<String>,
+  divisions: usize,
+  root: i32,
+  output: &Option<PathBuf>,
+) {
+  let contents = fs::read_to_string(preset).expect("unable to read preset");
+  let keymap = LumatoneKeyMap::from_ini_str(contents).expect("unable to load preset");
+  let mut tuning = Tuning::equal(divisions);
+  tuning.root_index = root;
+  let prefs = load_preferences(preferences_file()).expect("unable to load preferences");
+
+  let name = preset
+    .file_stem()
+    .map(|s| s.to_string_lossy().to_string())
+    .unwrap_or_default();
+  let title = title.clone().unwrap_or_else(|| name.clone());
+  let output = output
+    .clone()
+    .unwrap_or_else(|| PathBuf::from(format!("{name}.svg")));
+  let svg = render_svg_with(&keymap, &title, &tuning, &prefs);
+  fs::write(&output, svg).expect("unable to write image");
+  println!("wrote {}", output.display());
+}
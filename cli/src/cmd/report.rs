@@ -0,0 +1,54 @@
+use std::{
+  path::PathBuf,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use lumatone::{
+  prelude::Lumatone,
+  report::{ErrorReport, Redactor},
+};
+
+use crate::logs::{log_files, service_traffic_file};
+
+pub async fn run_report(output: &Option<PathBuf>, no_device: bool) {
+  let mut report = ErrorReport::new();
+  let mut redactor = Redactor::for_environment();
+
+  if no_device {
+    report.add_summary("device", "not queried");
+  } else {
+    match Lumatone::detect().await {
+      Ok(mut lumatone) => {
+        match lumatone.refresh_device_info().await {
+          Ok(info) => {
+            report.add_device_info(info);
+            redactor.add_device(info);
+          }
+          Err(e) => report.add_summary("device", format!("detected, but info query failed: {e}")),
+        }
+        report.add_traffic(&lumatone.recent_traffic());
+        lumatone.shutdown().await;
+      }
+      Err(e) => report.add_summary("device", format!("not detected: {e}")),
+    }
+  }
+
+  for path in log_files() {
+    report.add_log_file(&path);
+  }
+  report.add_service_traffic(&service_traffic_file());
+
+  let output = output.clone().unwrap_or_else(|| {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs())
+      .unwrap_or_default();
+    PathBuf::from(format!("lumatone-report-{now}.zip"))
+  });
+  report
+    .save(&output, &redactor)
+    .expect("failed to save bug report");
+  println!("wrote {}", output.display());
+  println!("serial numbers and your home directory have been redacted, but please look over the");
+  println!("report before attaching it to an issue");
+}
@@ -0,0 +1,62 @@
+use std::{path::PathBuf, sync::Arc};
+
+use lumatone::{
+  access::AccessPolicy,
+  coordination::{ConflictPolicy, Coordinator, Resource},
+  metrics::serve_metrics,
+  prelude::Lumatone,
+  rest::serve,
+};
+
+use super::listen::bind;
+
+pub async fn run_rest(
+  listen: &str,
+  metrics: Option<&str>,
+  tokens: Option<&PathBuf>,
+  last_writer_wins: &[String],
+) {
+  let access = match tokens {
+    Some(path) => AccessPolicy::load(path).expect("unable to load access tokens"),
+    None => AccessPolicy::open(),
+  };
+  let open = tokens.is_none();
+  let access = Arc::new(access);
+  let coordinator = Coordinator::new();
+  for resource in last_writer_wins {
+    let resource: Resource = resource.parse().expect("invalid resource");
+    coordinator.set_policy(resource, ConflictPolicy::LastWriterWins);
+  }
+
+  let listener = bind(listen, open).await;
+  let metrics_listener = match metrics {
+    Some(addr) => Some(bind(addr, open).await),
+    None => None,
+  };
+  let lumatone = Arc::new(Lumatone::detect().await.expect("device detection failed"));
+  let shutdown = lumatone.shutdown_token();
+
+  let mut servers = vec![tokio::spawn(serve(
+    listener,
+    lumatone.clone(),
+    access.clone(),
+    Arc::new(coordinator),
+    shutdown.clone(),
+  ))];
+  if let Some(metrics_listener) = metrics_listener {
+    servers.push(tokio::spawn(serve_metrics(
+      metrics_listener,
+      lumatone,
+      access,
+      shutdown.clone(),
+    )));
+  }
+  tokio::signal::ctrl_c()
+    .await
+    .expect("unable to listen for ctrl-c");
+  log::debug!("shutting down");
+  shutdown.cancel();
+  for server in servers {
+    let _ = server.await;
+  }
+}
@@ -0,0 +1,60 @@
+use std::{fs, path::PathBuf};
+
+use lumatone::{
+  keymap::{pitch_bend::DEFAULT_BEND_TOLERANCE, retune::RetuneClient, tuning::Tuning},
+  midi::{
+    proxy::{MultiOutput, NoteMapping},
+    shutdown::CancellationToken,
+  },
+  prelude::Lumatone,
+  retune::{connect_tuning_input, run_retune},
+  scene::load_keymap,
+};
+
+pub async fn run_retune_cmd(
+  preset: &PathBuf,
+  tuning_port: &str,
+  synth_port: &str,
+  scale: &Option<PathBuf>,
+  bend_range: f64,
+) {
+  let keymap = load_keymap(preset).expect("unable to load preset");
+  let tuning = match scale {
+    Some(path) => {
+      let contents = fs::read_to_string(path).expect("unable to read scale");
+      Tuning::from_scl_str(contents).expect("unable to load scale")
+    }
+    None => Tuning::equal(12),
+  };
+
+  let mut lumatone = Lumatone::detect().await.expect("device detection failed");
+  let synth = MultiOutput::new()
+    .connect_port(synth_port)
+    .expect("unable to connect to the synth");
+  lumatone
+    .start_proxy(NoteMapping::default(), synth)
+    .expect("unable to start the note proxy");
+  let (_connection, mut updates) =
+    connect_tuning_input(tuning_port).expect("unable to connect to the tuning source");
+
+  lumatone
+    .upload(keymap.to_midi_commands())
+    .await
+    .expect("unable to send preset");
+
+  let stop = CancellationToken::new();
+  let stopper = stop.clone();
+  tokio::spawn(async move {
+    let _ = tokio::signal::ctrl_c().await;
+    stopper.cancel();
+  });
+
+  println!("following the tuning on {tuning_port}, press ctrl-c to stop");
+  let mut client = RetuneClient::new(keymap, tuning).with_bends(bend_range, DEFAULT_BEND_TOLERANCE);
+  match run_retune(&lumatone, &mut client, &mut updates, &stop).await {
+    Ok(applied) => println!("applied {applied} tuning changes"),
+    Err(err) => eprintln!("error retuning: {err:?}"),
+  }
+
+  lumatone.shutdown().await;
+}
@@ -1,30 +1,49 @@
 use std::fs;
 use std::path::PathBuf;
 
-use lumatone_keymap::ltn::LumatoneKeyMap;
-use lumatone_midi::detect::detect_device;
-use lumatone_midi::driver::MidiDriver;
+use lumatone::midi::readback::VerifyOptions;
+use lumatone::prelude::{Lumatone, LumatoneKeyMap};
+use lumatone::send::apply;
 
-pub async fn run_send_preset(path: &PathBuf) {
+pub async fn run_send_preset(path: &PathBuf, verify: Option<VerifyOptions>) {
   let contents = fs::read_to_string(path).expect("unable to read preset");
   let keymap = LumatoneKeyMap::from_ini_str(contents).expect("unable to load presest");
 
-  let device = detect_device().await.expect("device detection failed");
-  let (driver, driver_future) = MidiDriver::new(&device).expect("driver creation failed");
-
-  log::debug!("starting driver loop");
-  let h = tokio::spawn(driver_future);
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
   log::debug!("driver loop spawned");
 
   let commands = keymap.to_midi_commands();
+  if let Some(options) = verify {
+    let report = lumatone
+      .send_verified(commands, &options)
+      .await
+      .expect("unable to send and verify preset");
+    println!("{report}");
+    for location in &report.mismatched {
+      println!("  {location} doesn't match");
+    }
+    lumatone.shutdown().await;
+    if !report.is_verified() {
+      std::process::exit(1);
+    }
+    return;
+  }
+
   log::debug!("sending commands");
-  for c in commands {
-    log::debug!("sending command {}", c);
-    let res = driver.send(c).await;
-    log::debug!("received response: {res:?}");
+  let report = apply(&keymap, &lumatone.driver(), |progress| {
+    eprint!("\r{progress}");
+  })
+  .await;
+  eprintln!();
+  match report {
+    Ok(report) => {
+      for (command, reason) in &report.failures {
+        eprintln!("{command} failed: {reason}");
+      }
+    }
+    Err(err) => eprintln!("{err:?}"),
   }
 
-  log::debug!("sending done signal");
-  driver.done().await.expect("error sending done signal");
-  tokio::join!(h).0.expect("error joining driver future");
+  log::debug!("shutting down");
+  lumatone.shutdown().await;
 }
@@ -0,0 +1,88 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use lumatone::{
+  access::AccessPolicy,
+  metrics::serve_metrics,
+  prelude::CancellationToken,
+  service::{run_service, ServiceConfig, ServiceMetrics, ServiceTarget},
+};
+
+use super::listen::bind;
+use crate::logs::service_traffic_file;
+
+pub async fn run_service_cmd(
+  keymap: &Option<PathBuf>,
+  set_list: &Option<PathBuf>,
+  scene: &Option<String>,
+  health_interval_secs: u64,
+  metrics_addr: Option<&str>,
+  tokens: Option<&PathBuf>,
+) {
+  let target = match (keymap, set_list, scene) {
+    (Some(keymap), _, _) => ServiceTarget::Keymap(keymap.clone()),
+    (None, Some(set_list), Some(scene)) => ServiceTarget::Scene {
+      set_list: set_list.clone(),
+      scene: scene.clone(),
+    },
+    _ => panic!("pass either --keymap, or --set-list and --scene"),
+  };
+  let mut config = ServiceConfig::new(target);
+  config.health_interval = Duration::from_secs(health_interval_secs);
+  config.traffic_file = Some(service_traffic_file());
+
+  let shutdown = CancellationToken::new();
+  let metrics = Arc::new(ServiceMetrics::default());
+  let metrics_server = match metrics_addr {
+    Some(addr) => {
+      let access = match tokens {
+        Some(path) => AccessPolicy::load(path).expect("unable to load access tokens"),
+        None => AccessPolicy::open(),
+      };
+      let listener = bind(addr, tokens.is_none()).await;
+      Some(tokio::spawn(serve_metrics(
+        listener,
+        metrics.clone(),
+        Arc::new(access),
+        shutdown.clone(),
+      )))
+    }
+    None => None,
+  };
+  let mut service = tokio::spawn({
+    let shutdown = shutdown.clone();
+    let metrics = metrics.clone();
+    async move { run_service(&config, &metrics, &shutdown).await }
+  });
+  let result = tokio::select! {
+    res = &mut service => res,
+    _ = wait_for_stop_signal() => {
+      log::debug!("shutting down");
+      shutdown.cancel();
+      service.await
+    }
+  };
+  shutdown.cancel();
+  if let Some(server) = metrics_server {
+    let _ = server.await;
+  }
+  result
+    .expect("service task panicked")
+    .expect("service failed");
+}
+
+/// Waits for ctrl-c, or for SIGTERM from the service manager.
+async fn wait_for_stop_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("unable to listen for SIGTERM");
+    tokio::select! {
+      _ = tokio::signal::ctrl_c() => {}
+      _ = terminate.recv() => {}
+    }
+  }
+  #[cfg(not(unix))]
+  tokio::signal::ctrl_c()
+    .await
+    .expect("unable to listen for ctrl-c");
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+use lumatone::{
+  midi::soak::{run_soak, SoakConfig},
+  prelude::Lumatone,
+};
+
+pub async fn run_soak_cmd(duration_secs: u64) {
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+
+  let config = SoakConfig {
+    duration: Duration::from_secs(duration_secs),
+    ..SoakConfig::default()
+  };
+  let report = run_soak(&lumatone.driver(), &config).await;
+  println!("{report}");
+
+  let problems = report.problems(&config);
+  lumatone.shutdown().await;
+
+  if !problems.is_empty() {
+    eprintln!("soak test failed: {}", problems.join(", "));
+    std::process::exit(1);
+  }
+}
@@ -0,0 +1,35 @@
+use std::fs;
+use std::path::PathBuf;
+
+use lumatone::{
+  keymap::tables::{parse_velocity_intervals, velocity_intervals_to_string},
+  midi::commands::set_velocity_intervals,
+  prelude::Lumatone,
+};
+
+/// Prints the device's velocity interval table in the .ltn `VelocityIntrvlTbl` format, or
+/// sends the table in the file at `set` to the device.
+pub async fn run_velocity_intervals(set: &Option<PathBuf>) {
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+
+  match set {
+    Some(path) => {
+      let contents = fs::read_to_string(path).expect("unable to read table");
+      let table = parse_velocity_intervals(contents.trim()).expect("unable to parse table");
+      lumatone
+        .send(set_velocity_intervals(table))
+        .await
+        .expect("unable to send table");
+      println!("velocity interval table sent");
+    }
+    None => {
+      let table = lumatone
+        .get_velocity_intervals()
+        .await
+        .expect("unable to read table");
+      println!("{}", velocity_intervals_to_string(&table));
+    }
+  }
+
+  lumatone.shutdown().await;
+}
@@ -0,0 +1,30 @@
+use std::fs;
+use std::path::PathBuf;
+
+use lumatone::{
+  keymap::verify::DEFAULT_COLOR_TOLERANCE,
+  prelude::{Lumatone, LumatoneKeyMap},
+};
+
+pub async fn run_verify_colors(path: &PathBuf) {
+  let contents = fs::read_to_string(path).expect("unable to read preset");
+  let keymap = LumatoneKeyMap::from_ini_str(contents).expect("unable to load preset");
+
+  let lumatone = Lumatone::detect().await.expect("device detection failed");
+  let device_colors = lumatone
+    .get_all_key_colors()
+    .await
+    .expect("unable to read key colors");
+
+  let mismatches = keymap.verify_colors(&device_colors, DEFAULT_COLOR_TOLERANCE);
+  if mismatches.is_empty() {
+    println!("all key colors match");
+  } else {
+    println!("{} keys don't match:", mismatches.len());
+    for m in mismatches {
+      println!("  {m}");
+    }
+  }
+
+  lumatone.shutdown().await;
+}
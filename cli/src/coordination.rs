@@ -0,0 +1,422 @@
+//! Coordinates several clients editing the same device, e.g. an editor on a laptop and a
+//! performance surface on an iPad connected at once.
+//!
+//! A client can lock a [Resource] to say it's working on it. While it holds the lock,
+//! other clients can't lock an overlapping resource, and whether their writes still go
+//! through depends on the resource's [ConflictPolicy]. Locks are leases: they expire unless
+//! renewed by locking again, so a client that vanishes without unlocking doesn't block
+//! everyone else forever.
+//!
+//! Every lock, unlock and successful write is broadcast as a [CoordinationEvent], so each
+//! client can show what the others are doing. Only writes made through a server sharing
+//! the coordinator are seen: the REST facade's keymap and key color requests, and the
+//! keymaps and actions of [protocol](crate::protocol) sessions. Anything else that talks
+//! to the device, e.g. another `lumatone` command, the `service` daemon or key bindings
+//! in the note proxy, changes it without anyone being told.
+//!
+//! Locks are only as strong as the [ClientId]s behind them. The coordinator trusts
+//! whatever id a server gives it, and the REST facade takes ids from the `X-Client-Id`
+//! header, scoped to the request's access token. So without tokens, or between clients
+//! sharing a token, any client can claim another's id, write through its
+//! [ConflictPolicy::Exclusive] locks and release them. Treat locks as advisory unless every
+//! client has its own token.
+
+use std::{
+  fmt::Display,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, MutexGuard, PoisonError,
+  },
+  time::{Duration, Instant},
+};
+
+use lumatone_midi::constants::{BoardIndex, LumatoneKeyIndex, LumatoneKeyLocation};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::error::LumatoneError;
+
+use error_stack::{report, Report};
+
+/// How long a lock lasts without being renewed.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(60);
+
+/// Something clients can lock and change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Resource {
+  /// Every key's function and color. Overlaps each [Resource::Key].
+  Keymap,
+  /// One key's function and color.
+  Key(LumatoneKeyLocation),
+  /// The note proxy's transpose and scale lock.
+  Proxy,
+}
+
+impl Resource {
+  /// Whether locking one of these should get in the way of changing the other.
+  pub fn overlaps(&self, other: &Resource) -> bool {
+    use Resource::*;
+    match (self, other) {
+      (Keymap, Keymap) | (Keymap, Key(_)) | (Key(_), Keymap) | (Proxy, Proxy) => true,
+      (Key(a), Key(b)) => a == b,
+      _ => false,
+    }
+  }
+}
+
+impl Display for Resource {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Resource::Keymap => write!(f, "keymap"),
+      Resource::Key(LumatoneKeyLocation(board, index)) => {
+        write!(f, "key/{}/{}", *board as u8, index.get())
+      }
+      Resource::Proxy => write!(f, "proxy"),
+    }
+  }
+}
+
+impl FromStr for Resource {
+  type Err = Report<LumatoneError>;
+
+  /// Parses `keymap`, `proxy` or `key/<board>/<index>`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || report!(LumatoneError::InvalidResource(s.to_string()));
+    match s.split('/').collect::<Vec<_>>().as_slice() {
+      ["keymap"] => Ok(Resource::Keymap),
+      ["proxy"] => Ok(Resource::Proxy),
+      ["key", board, index] => parse_location(board, index)
+        .map(Resource::Key)
+        .ok_or_else(invalid),
+      _ => Err(invalid()),
+    }
+  }
+}
+
+impl TryFrom<String> for Resource {
+  type Error = Report<LumatoneError>;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    value.parse()
+  }
+}
+
+impl From<Resource> for String {
+  fn from(resource: Resource) -> Self {
+    resource.to_string()
+  }
+}
+
+/// Parses a key location from its board (1 to 5) and index (0 to 55).
+pub(crate) fn parse_location(board: &str, index: &str) -> Option<LumatoneKeyLocation> {
+  let board = BoardIndex::try_from(board.parse::<u8>().ok()?).ok()?;
+  if board == BoardIndex::Server {
+    return None;
+  }
+  let index = LumatoneKeyIndex::new(index.parse().ok()?)?;
+  Some(LumatoneKeyLocation(board, index))
+}
+
+/// What happens when a client changes a resource someone else has locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+  /// The change is refused until the lock is released or expires.
+  Exclusive,
+  /// The change goes through anyway, and the lock only signals who has focus.
+  LastWriterWins,
+}
+
+/// Identifies a client in locks and events. Ids aren't authenticated, so a client that
+/// can send another's id can act as that client (see the [module docs](self)).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ClientId(pub String);
+
+impl Display for ClientId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl From<&str> for ClientId {
+  fn from(id: &str) -> Self {
+    ClientId(id.to_string())
+  }
+}
+
+/// A lock or change was refused because another client holds an overlapping lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+  /// The resource the other client has locked, which may not be the one asked for.
+  pub resource: Resource,
+  pub holder: ClientId,
+}
+
+impl Display for Conflict {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} is locked by {}", self.resource, self.holder)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoordinationEvent {
+  Locked {
+    resource: Resource,
+    client: ClientId,
+  },
+  /// The lock was released, or expired.
+  Unlocked {
+    resource: Resource,
+    client: ClientId,
+  },
+  Changed {
+    resource: Resource,
+    client: ClientId,
+  },
+}
+
+#[derive(Debug)]
+struct Lock {
+  resource: Resource,
+  holder: ClientId,
+  expires: Instant,
+}
+
+#[derive(Debug)]
+struct State {
+  locks: Vec<Lock>,
+  policies: Vec<(Resource, ConflictPolicy)>,
+  default_policy: ConflictPolicy,
+}
+
+/// Shared by every client of a server, to keep track of locks and tell clients about each
+/// other's changes.
+#[derive(Debug)]
+pub struct Coordinator {
+  state: Mutex<State>,
+  events: broadcast::Sender<CoordinationEvent>,
+  lease: Duration,
+  next_client: AtomicU64,
+}
+
+impl Default for Coordinator {
+  fn default() -> Self {
+    Coordinator::new()
+  }
+}
+
+impl Coordinator {
+  /// A coordinator where every resource is [ConflictPolicy::Exclusive], with the
+  /// [DEFAULT_LEASE].
+  pub fn new() -> Self {
+    let (events, _) = broadcast::channel(64);
+    Coordinator {
+      state: Mutex::new(State {
+        locks: vec![],
+        policies: vec![],
+        default_policy: ConflictPolicy::Exclusive,
+      }),
+      events,
+      lease: DEFAULT_LEASE,
+      next_client: AtomicU64::new(0),
+    }
+  }
+
+  pub fn with_lease(mut self, lease: Duration) -> Self {
+    self.lease = lease;
+    self
+  }
+
+  pub fn lease(&self) -> Duration {
+    self.lease
+  }
+
+  /// Every update leaves the state consistent, so a client task that panicked while
+  /// holding the lock doesn't stop the others from using it.
+  fn state(&self) -> MutexGuard<'_, State> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Sets the policy for changes to `resource` while it's locked. A [Resource::Key] without
+  /// a policy of its own uses the default.
+  pub fn set_policy(&self, resource: Resource, policy: ConflictPolicy) {
+    let mut state = self.state();
+    state.policies.retain(|(r, _)| *r != resource);
+    state.policies.push((resource, policy));
+  }
+
+  pub fn set_default_policy(&self, policy: ConflictPolicy) {
+    self.state().default_policy = policy;
+  }
+
+  /// A new, unique id for a client that doesn't have one of its own.
+  pub fn connect(&self) -> ClientId {
+    let n = self.next_client.fetch_add(1, Ordering::Relaxed);
+    ClientId(format!("client-{n}"))
+  }
+
+  /// Releases every lock held by `client`, e.g. when it disconnects.
+  pub fn disconnect(&self, client: &ClientId) {
+    let mut state = self.state();
+    self.release_where(&mut state, |lock| lock.holder == *client);
+  }
+
+  /// Locks `resource` for `client`, or renews its lock, until the lease runs out.
+  pub fn lock(&self, client: &ClientId, resource: Resource) -> Result<Instant, Conflict> {
+    let mut state = self.state();
+    self.release_expired(&mut state);
+    if let Some(lock) = state
+      .locks
+      .iter()
+      .find(|l| l.holder != *client && l.resource.overlaps(&resource))
+    {
+      return Err(Conflict {
+        resource: lock.resource,
+        holder: lock.holder.clone(),
+      });
+    }
+
+    let expires = Instant::now() + self.lease;
+    match state
+      .locks
+      .iter_mut()
+      .find(|l| l.holder == *client && l.resource == resource)
+    {
+      Some(lock) => lock.expires = expires,
+      None => {
+        state.locks.push(Lock {
+          resource,
+          holder: client.clone(),
+          expires,
+        });
+        let _ = self.events.send(CoordinationEvent::Locked {
+          resource,
+          client: client.clone(),
+        });
+      }
+    }
+    Ok(expires)
+  }
+
+  /// Releases `client`'s lock on `resource`, if it has one.
+  pub fn unlock(&self, client: &ClientId, resource: Resource) {
+    let mut state = self.state();
+    self.release_where(&mut state, |lock| {
+      lock.holder == *client && lock.resource == resource
+    });
+  }
+
+  /// Checks whether `client` may change `resource` now.
+  pub fn check_write(&self, client: &ClientId, resource: Resource) -> Result<(), Conflict> {
+    let mut state = self.state();
+    self.release_expired(&mut state);
+    let blocking = state.locks.iter().find(|lock| {
+      lock.holder != *client
+        && lock.resource.overlaps(&resource)
+        && state.policy(lock.resource) == ConflictPolicy::Exclusive
+    });
+    match blocking {
+      Some(lock) => Err(Conflict {
+        resource: lock.resource,
+        holder: lock.holder.clone(),
+      }),
+      None => Ok(()),
+    }
+  }
+
+  /// Tells everyone that `client` changed `resource`.
+  pub fn publish(&self, client: &ClientId, resource: Resource) {
+    let _ = self.events.send(CoordinationEvent::Changed {
+      resource,
+      client: client.clone(),
+    });
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<CoordinationEvent> {
+    self.events.subscribe()
+  }
+
+  fn release_expired(&self, state: &mut State) {
+    let now = Instant::now();
+    self.release_where(state, |lock| lock.expires <= now);
+  }
+
+  fn release_where<F: Fn(&Lock) -> bool>(&self, state: &mut State, released: F) {
+    let (gone, kept) = state.locks.drain(..).partition(|lock| released(lock));
+    state.locks = kept;
+    for lock in gone {
+      let _ = self.events.send(CoordinationEvent::Unlocked {
+        resource: lock.resource,
+        client: lock.holder,
+      });
+    }
+  }
+}
+
+impl State {
+  fn policy(&self, resource: Resource) -> ConflictPolicy {
+    self
+      .policies
+      .iter()
+      .find(|(r, _)| *r == resource)
+      .map(|(_, policy)| *policy)
+      .unwrap_or(self.default_policy)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::key_loc_unchecked;
+
+  #[test]
+  fn test_locks_block_overlapping_writes() {
+    let coordinator = Coordinator::new();
+    let mut events = coordinator.subscribe();
+    let editor = coordinator.connect();
+    let surface = coordinator.connect();
+    let key = Resource::Key(key_loc_unchecked(2, 10));
+
+    coordinator.lock(&editor, Resource::Keymap).unwrap();
+    assert_eq!(
+      coordinator.check_write(&surface, key),
+      Err(Conflict {
+        resource: Resource::Keymap,
+        holder: editor.clone()
+      })
+    );
+    assert!(coordinator.lock(&surface, key).is_err());
+    assert_eq!(coordinator.check_write(&editor, key), Ok(()));
+    assert_eq!(coordinator.check_write(&surface, Resource::Proxy), Ok(()));
+
+    coordinator.set_policy(Resource::Keymap, ConflictPolicy::LastWriterWins);
+    assert_eq!(coordinator.check_write(&surface, key), Ok(()));
+
+    coordinator.disconnect(&editor);
+    assert!(coordinator.lock(&surface, key).is_ok());
+    assert_eq!(
+      events.try_recv().unwrap(),
+      CoordinationEvent::Locked {
+        resource: Resource::Keymap,
+        client: editor.clone()
+      }
+    );
+    assert_eq!(
+      events.try_recv().unwrap(),
+      CoordinationEvent::Unlocked {
+        resource: Resource::Keymap,
+        client: editor
+      }
+    );
+
+    let expired = Coordinator::new().with_lease(Duration::ZERO);
+    expired.lock(&ClientId::from("a"), Resource::Proxy).unwrap();
+    assert!(expired.lock(&ClientId::from("b"), Resource::Proxy).is_ok());
+
+    assert_eq!("key/2/10".parse::<Resource>().unwrap(), key);
+    assert_eq!(key.to_string(), "key/2/10");
+    assert!("key/0/1".parse::<Resource>().is_err());
+  }
+}
@@ -0,0 +1,125 @@
+use std::{fmt::Display, path::PathBuf};
+
+#[cfg(all(feature = "driver", feature = "ltn"))]
+use crate::library::ContentHash;
+
+use error_stack::Context;
+
+#[derive(Debug)]
+pub enum LumatoneError {
+  KeymapLoadFailed(PathBuf),
+  ThemeLoadFailed(PathBuf),
+  InvalidKeymap(String),
+  SceneApplyFailed(String),
+  SetListLoadFailed(PathBuf),
+  SetListSaveFailed(PathBuf),
+  InvalidSetList(String),
+  InvalidAction(String),
+  ActionFailed(String),
+  ScaleLocked,
+  SceneNotFound(usize),
+  SceneNameNotFound(String),
+  ProxyNotRunning,
+  InvalidBundle(String),
+  BundleLoadFailed(PathBuf),
+  BundleSaveFailed(PathBuf),
+  BundleImportFailed(PathBuf),
+  LibraryIoError(PathBuf),
+  InvalidRefName(String),
+  InvalidContentHash(String),
+  #[cfg(all(feature = "driver", feature = "ltn"))]
+  MissingObject(ContentHash),
+  #[cfg(all(feature = "driver", feature = "ltn"))]
+  CorruptObject(ContentHash),
+  SyncFailed(String),
+  ReportSaveFailed(PathBuf),
+  AccessPolicyLoadFailed(PathBuf),
+  InvalidAccessPolicy(String),
+  PreferencesLoadFailed(PathBuf),
+  PreferencesSaveFailed(PathBuf),
+  InvalidPreference(String),
+  InvalidResource(String),
+  InvalidConversion(String),
+  ConversionFailed(PathBuf),
+  DeviceError,
+}
+
+impl Context for LumatoneError {}
+
+impl Display for LumatoneError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use LumatoneError::*;
+    match self {
+      KeymapLoadFailed(path) => write!(f, "unable to load keymap from {}", path.display()),
+
+      ThemeLoadFailed(path) => write!(f, "unable to load theme from {}", path.display()),
+
+      InvalidKeymap(msg) => write!(f, "invalid keymap: {msg}"),
+
+      SceneApplyFailed(name) => write!(f, "failed to apply scene {name}"),
+
+      SetListLoadFailed(path) => write!(f, "unable to load set list from {}", path.display()),
+
+      SetListSaveFailed(path) => write!(f, "unable to save set list to {}", path.display()),
+
+      InvalidSetList(msg) => write!(f, "invalid set list: {msg}"),
+
+      InvalidAction(s) => write!(f, "invalid action: {s}"),
+
+      ActionFailed(action) => write!(f, "unable to {action}"),
+
+      ScaleLocked => write!(f, "scale lock is on, so the keymap can't be changed"),
+
+      SceneNotFound(index) => write!(f, "no scene at index {index}"),
+      SceneNameNotFound(name) => write!(f, "no scene named {name:?}"),
+
+      ProxyNotRunning => write!(f, "note proxy isn't running"),
+
+      InvalidBundle(msg) => write!(f, "invalid bundle: {msg}"),
+
+      BundleLoadFailed(path) => write!(f, "unable to load bundle from {}", path.display()),
+
+      BundleSaveFailed(path) => write!(f, "unable to save bundle to {}", path.display()),
+
+      BundleImportFailed(path) => write!(f, "unable to import bundle into {}", path.display()),
+
+      LibraryIoError(path) => write!(f, "unable to access library at {}", path.display()),
+
+      InvalidRefName(name) => write!(f, "invalid preset name: {name:?}"),
+
+      InvalidContentHash(s) => write!(f, "invalid content hash: {s}"),
+
+      #[cfg(all(feature = "driver", feature = "ltn"))]
+      MissingObject(hash) => write!(f, "library has no blob {hash}"),
+
+      #[cfg(all(feature = "driver", feature = "ltn"))]
+      CorruptObject(hash) => write!(f, "library blob {hash} doesn't match its hash"),
+
+      SyncFailed(msg) => write!(f, "sync failed: {msg}"),
+
+      ReportSaveFailed(path) => write!(f, "unable to save bug report to {}", path.display()),
+
+      AccessPolicyLoadFailed(path) => {
+        write!(f, "unable to load access tokens from {}", path.display())
+      }
+
+      InvalidAccessPolicy(msg) => write!(f, "invalid access tokens: {msg}"),
+
+      PreferencesLoadFailed(path) => {
+        write!(f, "unable to load preferences from {}", path.display())
+      }
+
+      PreferencesSaveFailed(path) => write!(f, "unable to save preferences to {}", path.display()),
+
+      InvalidPreference(setting) => write!(f, "invalid preference {setting:?}"),
+
+      InvalidResource(s) => write!(f, "invalid resource {s:?}"),
+
+      InvalidConversion(msg) => write!(f, "invalid conversion: {msg}"),
+
+      ConversionFailed(path) => write!(f, "unable to convert {}", path.display()),
+
+      DeviceError => write!(f, "error communicating with device"),
+    }
+  }
+}
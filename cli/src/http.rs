@@ -0,0 +1,216 @@
+//! Just enough HTTP/1.1 for the small servers in this crate: one request per connection,
+//! and every response closes the connection. Chunked request bodies aren't supported, and
+//! are refused with a 411 so the client sends a `Content-Length` instead. Clients that are
+//! too slow to send their request get a 408, so they can't hold a connection open forever.
+
+use std::{collections::HashMap, time::Duration};
+
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpStream,
+  time::{timeout, timeout_at, Instant},
+};
+
+/// Requests with a longer header section are rejected.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Requests with a larger body are rejected. Full .ltn files are well under this.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// How long a client has to send the request line and headers.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to send the whole request, body included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+  pub method: String,
+  pub path: String,
+  pub headers: HashMap<String, String>,
+  pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+  /// The path without its query string, which may hold an access token, so it's safe to
+  /// log.
+  pub fn path_without_query(&self) -> &str {
+    self.path.split('?').next().unwrap_or_default()
+  }
+
+  /// The access token from an `Authorization: Bearer` header, or else a `token` query
+  /// parameter.
+  pub fn token(&self) -> Option<&str> {
+    let header = self
+      .headers
+      .get("authorization")
+      .and_then(|value| value.strip_prefix("Bearer "));
+    let query = || {
+      let (_, query) = self.path.split_once('?')?;
+      query
+        .split('&')
+        .find_map(|param| param.strip_prefix("token="))
+    };
+    header.or_else(query)
+  }
+
+  pub fn is_json(&self) -> bool {
+    match self.headers.get("content-type") {
+      Some(content_type) => content_type.contains("json"),
+      None => self.body.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{'),
+    }
+  }
+}
+
+/// Reads one request. Returns `Err(status)` for requests that are malformed, too big, too
+/// slow or chunked.
+pub(crate) async fn read_request(
+  stream: &mut TcpStream,
+) -> std::io::Result<std::result::Result<HttpRequest, u16>> {
+  match timeout(REQUEST_TIMEOUT, read_request_within(stream)).await {
+    Ok(result) => result,
+    Err(_) => Ok(Err(408)),
+  }
+}
+
+async fn read_request_within(
+  stream: &mut TcpStream,
+) -> std::io::Result<std::result::Result<HttpRequest, u16>> {
+  let head_deadline = Instant::now() + HEAD_TIMEOUT;
+  let mut buf = Vec::new();
+  let mut chunk = [0u8; 4096];
+  let head_end = loop {
+    if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+      break i;
+    }
+    if buf.len() > MAX_HEAD_SIZE {
+      return Ok(Err(431));
+    }
+    let n = match timeout_at(head_deadline, stream.read(&mut chunk)).await {
+      Ok(read) => read?,
+      Err(_) => return Ok(Err(408)),
+    };
+    if n == 0 {
+      return Ok(Err(400));
+    }
+    buf.extend_from_slice(&chunk[..n]);
+  };
+
+  let head = String::from_utf8_lossy(&buf[..head_end]).to_string();
+  let mut lines = head.lines();
+  let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+  let (method, path) = match (request_line.next(), request_line.next()) {
+    (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+    _ => return Ok(Err(400)),
+  };
+  let headers = lines
+    .filter_map(|line| line.split_once(':'))
+    .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+    .collect::<HashMap<_, _>>();
+
+  match headers.get("transfer-encoding") {
+    Some(encoding) if !encoding.eq_ignore_ascii_case("identity") => return Ok(Err(411)),
+    _ => {}
+  }
+  let length = match headers.get("content-length").map(|l| l.parse::<usize>()) {
+    Some(Ok(length)) => length,
+    Some(Err(_)) => return Ok(Err(400)),
+    None => 0,
+  };
+  if length > MAX_BODY_SIZE {
+    return Ok(Err(413));
+  }
+  let mut body = buf[head_end + 4..].to_vec();
+  while body.len() < length {
+    let n = stream.read(&mut chunk).await?;
+    if n == 0 {
+      return Ok(Err(400));
+    }
+    body.extend_from_slice(&chunk[..n]);
+  }
+  body.truncate(length);
+
+  Ok(Ok(HttpRequest {
+    method,
+    path,
+    headers,
+    body,
+  }))
+}
+
+pub(crate) fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    408 => "Request Timeout",
+    409 => "Conflict",
+    411 => "Length Required",
+    413 => "Payload Too Large",
+    431 => "Request Header Fields Too Large",
+    502 => "Bad Gateway",
+    503 => "Service Unavailable",
+    _ => "Error",
+  }
+}
+
+pub(crate) async fn write_response(
+  stream: &mut TcpStream,
+  status: u16,
+  content_type: &str,
+  body: &[u8],
+) -> std::io::Result<()> {
+  let head = format!(
+    "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+    reason(status),
+    body.len()
+  );
+  stream.write_all(head.as_bytes()).await?;
+  stream.write_all(body).await?;
+  stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::net::TcpListener;
+
+  async fn read(raw: &'static [u8]) -> std::result::Result<HttpRequest, u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+      let mut stream = TcpStream::connect(addr).await.unwrap();
+      stream.write_all(raw).await.unwrap();
+      stream
+    });
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let request = read_request(&mut stream).await.unwrap();
+    drop(client.await.unwrap());
+    request
+  }
+
+  #[tokio::test]
+  async fn test_read_request() {
+    let request = read(b"PUT /keymap HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+      .await
+      .unwrap();
+    assert_eq!(
+      (request.method.as_str(), request.path.as_str()),
+      ("PUT", "/keymap")
+    );
+    assert_eq!(request.body, b"hello");
+
+    let request = read(b"GET /events?token=abc HTTP/1.1\r\n\r\n")
+      .await
+      .unwrap();
+    assert_eq!(request.token(), Some("abc"));
+    assert_eq!(request.path_without_query(), "/events");
+
+    let chunked =
+      b"PUT /keymap HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+    assert_eq!(read(chunked).await.unwrap_err(), 411);
+  }
+}
@@ -0,0 +1,102 @@
+//! Tools for controlling the Lumatone isomorphic keyboard.
+//!
+//! This crate bundles the MIDI driver ([lumatone_midi]) and the preset file parser
+//! ([lumatone_keymap]) behind one dependency. Most programs only need the [prelude]:
+//!
+//! ```ignore
+//! use lumatone::prelude::*;
+//!
+//! let lumatone = Lumatone::detect().await?;
+//! let keymap = LumatoneKeyMap::from_ini_str(std::fs::read_to_string(path)?)?;
+//! for command in keymap.to_midi_commands() {
+//!   lumatone.send(command).await?;
+//! }
+//! ```
+//!
+//! The `lumatone` command line tool is built on top of this crate.
+//!
+//! ## Features
+//!
+//! - `driver`: the async MIDI driver, which needs tokio and a native MIDI backend.
+//! - `ltn`: reading and writing .ltn preset files, set lists, bundles and the
+//!   preset library.
+//! - `batch`: converting preset collections between formats in parallel. Enables `ltn`.
+//! - `webdav`: syncing the preset library with a WebDAV server.
+//! - `zstd`: compressing the compact keymaps stored in the preset library.
+//! - `protocol`: the message types of a versioned JSON protocol for remote clients. With
+//!   `driver`, also a session that performs requests against a connected device.
+//! - `rest`: a minimal HTTP facade for scripts and web pages. Enables `driver`, `ltn` and
+//!   `protocol`.
+//! - `metrics`: driver, proxy and service metrics in Prometheus format.
+//! - `cli` (default): the command line tool. Enables `driver`, `ltn`, `batch` and `metrics`.
+//!
+//! To use only the protocol and keymap types (e.g. when targeting WASM), depend on this
+//! crate with `default-features = false` and the `protocol` feature.
+
+pub use lumatone_keymap as keymap;
+pub use lumatone_midi as midi;
+
+#[cfg(any(feature = "protocol", feature = "metrics"))]
+pub mod access;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod actions;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod backup;
+#[cfg(feature = "batch")]
+pub mod batch;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod bindings;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod bundle;
+#[cfg(feature = "protocol")]
+pub mod coordination;
+pub mod error;
+#[cfg(any(feature = "rest", feature = "metrics"))]
+mod http;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod library;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod lighting;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "protocol")]
+pub mod protocol;
+pub mod preferences;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod report;
+#[cfg(feature = "rest")]
+pub mod rest;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod retune;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod scene;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod send;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod service;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod setlist;
+#[cfg(all(feature = "driver", feature = "ltn"))]
+pub mod sync;
+
+/// The types and functions needed to connect to a device, send commands, and load presets.
+pub mod prelude {
+  pub use lumatone_midi::{
+    commands::{ping, set_key_color, set_key_function, Command},
+    constants::{
+      key_loc_unchecked, BoardIndex, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation,
+      MidiChannel, RGBColor,
+    },
+    error::LumatoneMidiError,
+    events::ChannelMessage,
+    responses::{FirmwareVersion, Response, SerialIdentity},
+  };
+
+  #[cfg(feature = "driver")]
+  pub use lumatone_midi::{
+    controller::Lumatone, detect::detect_device, device::LumatoneDevice, driver::MidiDriver,
+    info::DeviceInfo, settings::Toggle, shutdown::CancellationToken,
+  };
+
+  pub use lumatone_keymap::{error::LumatoneKeymapError, ltn::LumatoneKeyMap};
+}
@@ -0,0 +1,424 @@
+//! A preset library backed by content-addressed storage.
+//!
+//! Every keymap, tuning or theme stored in the library is written once as a blob named by
+//! the SHA-256 hash of its contents, so identical files are only stored once and any blob can
+//! be checked against its name. Human-readable names are refs: small text files listing the
+//! hashes a name has pointed to, oldest first. Saving an edited preset appends a line,
+//! which makes the edit history of a preset cheap to keep.
+//!
+//! ```text
+//! <root>/objects/ab/cdef0123...    blob, named by its hash
+//! <root>/refs/keymaps/<name>       one hash per line, the last one is current
+//! ```
+//!
+//! Keymaps saved with [Library::store_keymap] are stored in the compact binary form (see
+//! [lumatone_keymap::compact]), which is a small fraction of the size of a .ltn file and so
+//! much quicker to sync. [Library::load_keymap] reads either form.
+
+use std::{
+  fmt::Display,
+  io::Write,
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use lumatone_keymap::{compact::Compression, ltn::LumatoneKeyMap};
+use sha2::{Digest, Sha256};
+
+use super::{bundle::AssetKind, error::LumatoneError};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+const OBJECTS_DIR: &str = "objects";
+const REFS_DIR: &str = "refs";
+
+pub(crate) const ASSET_KINDS: [AssetKind; 3] =
+  [AssetKind::Keymap, AssetKind::Tuning, AssetKind::Theme];
+
+/// The SHA-256 hash of a blob's contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentHash(pub [u8; 32]);
+
+impl ContentHash {
+  pub fn of(contents: &[u8]) -> Self {
+    ContentHash(Sha256::digest(contents).into())
+  }
+}
+
+impl Display for ContentHash {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for b in self.0 {
+      write!(f, "{b:02x}")?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for ContentHash {
+  type Err = error_stack::Report<LumatoneError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let invalid = || report!(LumatoneError::InvalidContentHash(s.to_string()));
+    if s.len() != 64 || !s.is_ascii() {
+      return Err(invalid());
+    }
+    let mut hash = [0u8; 32];
+    for (i, b) in hash.iter_mut().enumerate() {
+      *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(ContentHash(hash))
+  }
+}
+
+/// A content-addressed store of presets, rooted at a directory.
+#[derive(Debug, Clone)]
+pub struct Library {
+  root: PathBuf,
+}
+
+impl Library {
+  /// Opens the library at `root`, creating its directories if needed.
+  pub fn open<P: AsRef<Path>>(root: P) -> Result<Library, LumatoneError> {
+    let root = root.as_ref().to_path_buf();
+    for dir in [root.join(OBJECTS_DIR), root.join(REFS_DIR)] {
+      std::fs::create_dir_all(&dir)
+        .report()
+        .change_context(LumatoneError::LibraryIoError(dir))?;
+    }
+    Ok(Library { root })
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.root
+  }
+
+  fn object_path(&self, hash: &ContentHash) -> PathBuf {
+    let hex = hash.to_string();
+    self.root.join(OBJECTS_DIR).join(&hex[..2]).join(&hex[2..])
+  }
+
+  fn ref_path(&self, kind: AssetKind, name: &str) -> Result<PathBuf, LumatoneError> {
+    let valid = !name.is_empty()
+      && !name.starts_with('.')
+      && !name.contains(|c: char| c == '/' || c == '\\' || c == ':' || c.is_control());
+    if !valid {
+      bail!(LumatoneError::InvalidRefName(name.to_string()));
+    }
+    Ok(self.root.join(REFS_DIR).join(kind.dir()).join(name))
+  }
+
+  pub fn contains(&self, hash: &ContentHash) -> bool {
+    self.object_path(hash).is_file()
+  }
+
+  /// Stores a blob and returns its hash. Storing contents that are already in the library
+  /// doesn't write anything.
+  pub fn put(&self, contents: &[u8]) -> Result<ContentHash, LumatoneError> {
+    let hash = ContentHash::of(contents);
+    let path = self.object_path(&hash);
+    if path.is_file() {
+      return Ok(hash);
+    }
+    // write to a temporary file first, so an interrupted write never leaves a blob whose
+    // contents don't match its name
+    let tmp = path.with_extension("tmp");
+    let write_failed = || LumatoneError::LibraryIoError(path.clone());
+    std::fs::create_dir_all(path.parent().unwrap())
+      .report()
+      .change_context_lazy(write_failed)?;
+    std::fs::write(&tmp, contents)
+      .report()
+      .change_context_lazy(write_failed)?;
+    std::fs::rename(&tmp, &path)
+      .report()
+      .change_context_lazy(write_failed)?;
+    Ok(hash)
+  }
+
+  /// Reads a blob, checking that its contents still match its hash.
+  pub fn get(&self, hash: &ContentHash) -> Result<Vec<u8>, LumatoneError> {
+    let path = self.object_path(hash);
+    if !path.is_file() {
+      bail!(LumatoneError::MissingObject(*hash));
+    }
+    let contents = std::fs::read(&path)
+      .report()
+      .change_context(LumatoneError::LibraryIoError(path))?;
+    if ContentHash::of(&contents) != *hash {
+      bail!(LumatoneError::CorruptObject(*hash));
+    }
+    Ok(contents)
+  }
+
+  /// The hashes of every blob in the library, sorted.
+  pub fn hashes(&self) -> Result<Vec<ContentHash>, LumatoneError> {
+    let objects = self.root.join(OBJECTS_DIR);
+    let mut hashes = Vec::new();
+    for dir in read_dir_names(&objects)? {
+      for file in read_dir_names(&objects.join(&dir))? {
+        // skips leftover temporary files, and anything else that isn't a blob
+        if let Ok(hash) = format!("{dir}{file}").parse() {
+          hashes.push(hash);
+        }
+      }
+    }
+    hashes.sort();
+    Ok(hashes)
+  }
+
+  /// Checks every blob against its hash, and returns the ones that don't match.
+  pub fn verify(&self) -> Result<Vec<ContentHash>, LumatoneError> {
+    let mut corrupt = Vec::new();
+    for hash in self.hashes()? {
+      let path = self.object_path(&hash);
+      let contents = std::fs::read(&path)
+        .report()
+        .change_context(LumatoneError::LibraryIoError(path))?;
+      if ContentHash::of(&contents) != hash {
+        corrupt.push(hash);
+      }
+    }
+    Ok(corrupt)
+  }
+
+  /// Every hash `name` has pointed to, oldest first. Empty if there's no such ref.
+  pub fn history(&self, kind: AssetKind, name: &str) -> Result<Vec<ContentHash>, LumatoneError> {
+    let path = self.ref_path(kind, name)?;
+    if !path.is_file() {
+      return Ok(vec![]);
+    }
+    let text = std::fs::read_to_string(&path)
+      .report()
+      .change_context_lazy(|| LumatoneError::LibraryIoError(path.clone()))?;
+    text
+      .lines()
+      .map(str::trim)
+      .filter(|l| !l.is_empty())
+      .map(|l| {
+        l.parse()
+          .change_context_lazy(|| LumatoneError::LibraryIoError(path.clone()))
+      })
+      .collect()
+  }
+
+  /// The hash `name` currently points to.
+  pub fn resolve(&self, kind: AssetKind, name: &str) -> Result<Option<ContentHash>, LumatoneError> {
+    Ok(self.history(kind, name)?.last().copied())
+  }
+
+  /// Points `name` at `hash`, keeping its previous targets in its history. The blob must
+  /// already be in the library.
+  pub fn set_ref(
+    &self,
+    kind: AssetKind,
+    name: &str,
+    hash: &ContentHash,
+  ) -> Result<(), LumatoneError> {
+    if !self.contains(hash) {
+      bail!(LumatoneError::MissingObject(*hash));
+    }
+    if self.resolve(kind, name)? == Some(*hash) {
+      return Ok(());
+    }
+    let path = self.ref_path(kind, name)?;
+    let write_failed = || LumatoneError::LibraryIoError(path.clone());
+    std::fs::create_dir_all(path.parent().unwrap())
+      .report()
+      .change_context_lazy(write_failed)?;
+    let mut file = std::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&path)
+      .report()
+      .change_context_lazy(write_failed)?;
+    writeln!(file, "{hash}")
+      .report()
+      .change_context_lazy(write_failed)
+  }
+
+  /// Replaces the whole history of `name`, e.g. with one merged by [sync](crate::sync::sync).
+  /// Every blob in the history must already be in the library.
+  pub fn replace_history(
+    &self,
+    kind: AssetKind,
+    name: &str,
+    history: &[ContentHash],
+  ) -> Result<(), LumatoneError> {
+    if let Some(missing) = history.iter().find(|hash| !self.contains(hash)) {
+      bail!(LumatoneError::MissingObject(*missing));
+    }
+    let path = self.ref_path(kind, name)?;
+    // ref names can't start with a dot, so the temporary file can't clash with a ref
+    let tmp = path.with_file_name(format!(".{name}.tmp"));
+    let write_failed = || LumatoneError::LibraryIoError(path.clone());
+    std::fs::create_dir_all(path.parent().unwrap())
+      .report()
+      .change_context_lazy(write_failed)?;
+    let text = history
+      .iter()
+      .map(|hash| format!("{hash}\n"))
+      .collect::<String>();
+    std::fs::write(&tmp, text)
+      .report()
+      .change_context_lazy(write_failed)?;
+    std::fs::rename(&tmp, &path)
+      .report()
+      .change_context_lazy(write_failed)
+  }
+
+  /// Removes a ref. The blobs it pointed to stay in the library.
+  pub fn delete_ref(&self, kind: AssetKind, name: &str) -> Result<(), LumatoneError> {
+    let path = self.ref_path(kind, name)?;
+    if path.is_file() {
+      std::fs::remove_file(&path)
+        .report()
+        .change_context(LumatoneError::LibraryIoError(path))?;
+    }
+    Ok(())
+  }
+
+  /// The names of all refs of the given kind, sorted.
+  pub fn refs(&self, kind: AssetKind) -> Result<Vec<String>, LumatoneError> {
+    let dir = self.root.join(REFS_DIR).join(kind.dir());
+    let mut names = read_dir_names(&dir)?;
+    // skips leftover temporary files
+    names.retain(|name| !name.starts_with('.'));
+    names.sort();
+    Ok(names)
+  }
+
+  /// Stores `contents` and points `name` at it.
+  pub fn store(
+    &self,
+    kind: AssetKind,
+    name: &str,
+    contents: &[u8],
+  ) -> Result<ContentHash, LumatoneError> {
+    // validate the name before writing the blob
+    self.ref_path(kind, name)?;
+    let hash = self.put(contents)?;
+    self.set_ref(kind, name, &hash)?;
+    Ok(hash)
+  }
+
+  /// Reads the current contents of `name`.
+  pub fn load(&self, kind: AssetKind, name: &str) -> Result<Option<Vec<u8>>, LumatoneError> {
+    self
+      .resolve(kind, name)?
+      .map(|hash| self.get(&hash))
+      .transpose()
+  }
+
+  /// Stores a keymap in the compact form, compressed if zstd is available, and points
+  /// `name` at it.
+  pub fn store_keymap(
+    &self,
+    name: &str,
+    keymap: &LumatoneKeyMap,
+  ) -> Result<ContentHash, LumatoneError> {
+    let contents = keymap.to_compact(Compression::default());
+    self.store(AssetKind::Keymap, name, &contents)
+  }
+
+  /// Reads the current version of the keymap `name`, whether it was stored in the compact
+  /// form or as a .ltn file.
+  pub fn load_keymap(&self, name: &str) -> Result<Option<LumatoneKeyMap>, LumatoneError> {
+    let contents = match self.load(AssetKind::Keymap, name)? {
+      Some(contents) => contents,
+      None => return Ok(None),
+    };
+    LumatoneKeyMap::from_bytes(&contents)
+      .map(Some)
+      .map_err(|e| report!(LumatoneError::InvalidKeymap(format!("{e:?}"))))
+  }
+
+  /// Every blob that some ref currently points to or has pointed to.
+  pub fn referenced_hashes(&self) -> Result<Vec<ContentHash>, LumatoneError> {
+    let mut hashes = Vec::new();
+    for kind in ASSET_KINDS {
+      for name in self.refs(kind)? {
+        hashes.extend(self.history(kind, &name)?);
+      }
+    }
+    hashes.sort();
+    hashes.dedup();
+    Ok(hashes)
+  }
+}
+
+/// The file names in `dir`, or nothing if it doesn't exist.
+fn read_dir_names(dir: &Path) -> Result<Vec<String>, LumatoneError> {
+  if !dir.is_dir() {
+    return Ok(vec![]);
+  }
+  let read_failed = || LumatoneError::LibraryIoError(dir.to_path_buf());
+  let mut names = Vec::new();
+  for entry in std::fs::read_dir(dir)
+    .report()
+    .change_context_lazy(read_failed)?
+  {
+    let entry = entry.report().change_context_lazy(read_failed)?;
+    if let Some(name) = entry.file_name().to_str() {
+      names.push(name.to_string());
+    }
+  }
+  Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_library_dedup_history_and_verify() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let library = Library::open(&root).unwrap();
+
+    let v1 = library
+      .store(AssetKind::Keymap, "31edo", b"[Board0]\n")
+      .unwrap();
+    let v2 = library
+      .store(AssetKind::Keymap, "31edo", b"[Board1]\n")
+      .unwrap();
+    // storing the same contents again doesn't add to the history
+    library
+      .store(AssetKind::Keymap, "31edo", b"[Board1]\n")
+      .unwrap();
+    let copy = library
+      .store(AssetKind::Keymap, "copy", b"[Board0]\n")
+      .unwrap();
+    assert_eq!(copy, v1);
+    assert_eq!(library.hashes().unwrap().len(), 2);
+
+    assert_eq!(
+      library.history(AssetKind::Keymap, "31edo").unwrap(),
+      vec![v1, v2]
+    );
+    assert_eq!(
+      library.load(AssetKind::Keymap, "31edo").unwrap().unwrap(),
+      b"[Board1]\n"
+    );
+    assert_eq!(
+      library.refs(AssetKind::Keymap).unwrap(),
+      vec!["31edo", "copy"]
+    );
+    assert!(library.load(AssetKind::Tuning, "31edo").unwrap().is_none());
+    assert!(library.store(AssetKind::Tuning, "../escape", b"").is_err());
+
+    assert_eq!(v1.to_string().parse::<ContentHash>().unwrap(), v1);
+
+    std::fs::write(library.object_path(&v2), b"tampered").unwrap();
+    assert_eq!(library.verify().unwrap(), vec![v2]);
+    assert!(library.get(&v2).is_err());
+
+    let keymap = LumatoneKeyMap::from_ini_str("[Board0]\nKey_0=61\nCol_0=ff0000\n").unwrap();
+    let hash = library.store_keymap("compact", &keymap).unwrap();
+    assert!(library.get(&hash).unwrap().len() < keymap.to_ini_string().len() / 5);
+    let loaded = library.load_keymap("compact").unwrap().unwrap();
+    assert_eq!(loaded.fingerprint(), keymap.fingerprint());
+    assert!(library.load_keymap("missing").unwrap().is_none());
+    // keymaps stored as .ltn text load too
+    assert!(library.load_keymap("copy").is_ok());
+  }
+}
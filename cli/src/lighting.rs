@@ -0,0 +1,180 @@
+//! Runs a [LightingMode] against the device's event stream, or against a recorded
+//! [Replay], to watch a phrase played back on the layout. [run_trainer] combines the two
+//! to practice a phrase (see [PhraseTrainer]).
+//!
+//! Lighting updates are sent through the driver rather than [Lumatone::send], so they're
+//! not recorded in the config mirror: after a device reset, the keymap's own colors are
+//! restored, not whatever was lit at the time.
+//!
+//! Key colors set in response to events are sent without waiting for the device to
+//! acknowledge them, coalesced per key (see [MidiDriver::send_and_forget_coalesced]): if
+//! the device falls behind, a key's queued color is replaced by its newer one, so fast
+//! playing doesn't queue up stale updates, and every key still ends up with its latest
+//! color. In particular, a key going back to its resting color is never dropped.
+
+use std::time::Duration;
+
+use log::{info, warn};
+use lumatone_keymap::{lighting::LightingMode, trainer::PhraseTrainer};
+use lumatone_midi::{
+  clock::Clock, commands::Command, controller::Lumatone, driver::MidiDriver, session::Replay,
+};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+
+use super::error::LumatoneError;
+
+use error_stack::{Result, ResultExt};
+
+/// How often the trainer updates which keys are lit.
+const TRAINER_TICK: Duration = Duration::from_millis(10);
+
+/// Sets every key to `mode`'s resting color, then updates key colors as the device is
+/// played, until the event stream closes or the device shuts down.
+pub async fn run_lighting<M: LightingMode>(
+  lumatone: &Lumatone,
+  mode: &mut M,
+) -> Result<(), LumatoneError> {
+  let driver = lumatone.driver();
+  let mut events = driver
+    .subscribe_events()
+    .change_context(LumatoneError::DeviceError)?;
+  let shutdown = lumatone.shutdown_token();
+
+  send_all(&driver, mode.reset()).await?;
+  loop {
+    let commands = tokio::select! {
+      _ = shutdown.cancelled() => return Ok(()),
+      res = events.recv() => match res {
+        Ok(msg) => mode.handle(&msg),
+        Err(RecvError::Lagged(n)) => {
+          // We may have missed note-offs, so start over from the resting colors.
+          warn!("lighting mode missed {n} events, resetting key colors");
+          driver.count_missed(n);
+          mode.reset()
+        }
+        Err(RecvError::Closed) => return Ok(()),
+      },
+    };
+    send_and_forget_all(&driver, commands).await?;
+  }
+}
+
+/// Plays `replay` through `mode` as if it were being played on the device. Returns after
+/// one pass, or if the replay loops, when the device shuts down. Key colors are reset at
+/// the start of each pass.
+pub async fn run_replay<M: LightingMode>(
+  lumatone: &Lumatone,
+  replay: &Replay,
+  mode: &mut M,
+) -> Result<(), LumatoneError> {
+  let driver = lumatone.driver();
+  let shutdown = lumatone.shutdown_token();
+  let schedule = replay.schedule();
+  let clock = driver.clock();
+
+  loop {
+    send_all(&driver, mode.reset()).await?;
+    let start = clock.now();
+    for event in &schedule {
+      tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        _ = clock.sleep_until(start + event.time) => {}
+      }
+      send_and_forget_all(&driver, mode.handle(&event.message)).await?;
+    }
+    tokio::select! {
+      _ = shutdown.cancelled() => return Ok(()),
+      _ = clock.sleep_until(start + replay.pass_length()) => {}
+    }
+    if !replay.looping {
+      return Ok(());
+    }
+  }
+}
+
+/// Loops the trainer's phrase for `passes` passes, or until the device shuts down if
+/// `passes` is `None`, logging the score of each pass. The scores are kept in the trainer's
+/// [history](PhraseTrainer::history).
+///
+/// When training stops, the keys the trainer lights are set back to the keymap's colors
+/// (see [PhraseTrainer::restore]), unless the device is shutting down.
+pub async fn run_trainer(
+  lumatone: &Lumatone,
+  trainer: &mut PhraseTrainer,
+  passes: Option<usize>,
+) -> Result<(), LumatoneError> {
+  let result = train(lumatone, trainer, passes).await;
+  if lumatone.shutdown_token().is_cancelled() {
+    return result;
+  }
+  // Restore the board even if training stopped with an error.
+  let restored = send_all(&lumatone.driver(), trainer.restore()).await;
+  result.and(restored)
+}
+
+async fn train(
+  lumatone: &Lumatone,
+  trainer: &mut PhraseTrainer,
+  passes: Option<usize>,
+) -> Result<(), LumatoneError> {
+  let driver = lumatone.driver();
+  let mut events = driver
+    .subscribe_timed_events()
+    .change_context(LumatoneError::DeviceError)?;
+  let shutdown = lumatone.shutdown_token();
+
+  while passes.is_none_or(|n| trainer.history().len() < n) {
+    send_all(&driver, trainer.start_pass()).await?;
+    let start = Instant::now();
+    let end = start + trainer.pass_length();
+    let mut ticks = tokio::time::interval(TRAINER_TICK);
+    loop {
+      let commands = tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        _ = tokio::time::sleep_until(end) => break,
+        _ = ticks.tick() => trainer.tick(start.elapsed()),
+        res = events.recv() => match res {
+          // score the note by when it was played, not when we got around to it
+          Ok(timed) => {
+            let played_at = Instant::from_std(timed.played_at(driver.input_latency()));
+            trainer.handle(played_at.saturating_duration_since(start), &timed.message)
+          }
+          Err(RecvError::Lagged(n)) => {
+            warn!("phrase trainer missed {n} events");
+            driver.count_missed(n);
+            continue;
+          }
+          Err(RecvError::Closed) => return Ok(()),
+        },
+      };
+      send_and_forget_all(&driver, commands).await?;
+    }
+    send_and_forget_all(&driver, trainer.tick(end - start)).await?;
+    let score = trainer.finish_pass();
+    info!("pass {}: {score}", trainer.history().len());
+  }
+  Ok(())
+}
+
+async fn send_all(driver: &MidiDriver, commands: Vec<Command>) -> Result<(), LumatoneError> {
+  for command in commands {
+    driver
+      .send(command)
+      .await
+      .change_context(LumatoneError::DeviceError)?;
+  }
+  Ok(())
+}
+
+async fn send_and_forget_all(
+  driver: &MidiDriver,
+  commands: Vec<Command>,
+) -> Result<(), LumatoneError> {
+  for command in commands {
+    driver
+      .send_and_forget_coalesced(command)
+      .await
+      .change_context(LumatoneError::DeviceError)?;
+  }
+  Ok(())
+}
@@ -0,0 +1,126 @@
+//! Logging for the command line tool. Log lines go to stderr as usual, and are also appended
+//! to a log file so `lumatone report` can include them after the fact.
+//!
+//! Every process appends to the same file, tagging its lines with its process id, so a short
+//! command run while the `service` daemon is up doesn't push the daemon's log aside. Only the
+//! daemon starts a new file when it starts. Whichever process grows the file past
+//! [MAX_LOG_FILE_SIZE] also starts a new one, so the current and previous files never take up
+//! much more than twice that.
+
+use std::{
+  fs::{File, OpenOptions},
+  io::{self, Write},
+  path::PathBuf,
+};
+
+pub const LOG_FILE_NAME: &str = "lumatone.log";
+pub const PREVIOUS_LOG_FILE_NAME: &str = "lumatone.log.1";
+pub const SERVICE_TRAFFIC_FILE_NAME: &str = "service-traffic.txt";
+
+/// The size at which the log file is rotated.
+pub const MAX_LOG_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Where log files are kept, e.g. `~/.local/share/lumatone` on Linux.
+pub fn log_dir() -> PathBuf {
+  dirs_next::data_local_dir()
+    .unwrap_or_else(std::env::temp_dir)
+    .join("lumatone")
+}
+
+/// The current and previous log files, in that order.
+pub fn log_files() -> [PathBuf; 2] {
+  let dir = log_dir();
+  [dir.join(LOG_FILE_NAME), dir.join(PREVIOUS_LOG_FILE_NAME)]
+}
+
+/// Where the `service` daemon saves its recent device traffic for `lumatone report`.
+pub fn service_traffic_file() -> PathBuf {
+  log_dir().join(SERVICE_TRAFFIC_FILE_NAME)
+}
+
+/// Writes to stderr and, if it could be opened, the log file.
+struct Tee {
+  file: Option<File>,
+  /// The size of the log file, as far as this process knows.
+  size: u64,
+  prefix: String,
+}
+
+impl Tee {
+  fn write_to_file(&mut self, buf: &[u8]) -> io::Result<()> {
+    if self.size > MAX_LOG_FILE_SIZE {
+      // another process may have rotated the file already, leaving this one writing to the
+      // previous file
+      let (file, size) = open_log_file(current_log_size()? > MAX_LOG_FILE_SIZE)?;
+      self.file = Some(file);
+      self.size = size;
+    }
+    if let Some(file) = &mut self.file {
+      file.write_all(self.prefix.as_bytes())?;
+      file.write_all(buf)?;
+      self.size += (self.prefix.len() + buf.len()) as u64;
+    }
+    Ok(())
+  }
+}
+
+impl Write for Tee {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    // losing the log file shouldn't stop logging to stderr
+    if self.file.is_some() && self.write_to_file(buf).is_err() {
+      self.file = None;
+    }
+    io::stderr().write_all(buf)?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    if let Some(file) = &mut self.file {
+      let _ = file.flush();
+    }
+    io::stderr().flush()
+  }
+}
+
+fn current_log_size() -> io::Result<u64> {
+  let [current, _] = log_files();
+  match std::fs::metadata(current) {
+    Ok(metadata) => Ok(metadata.len()),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+    Err(e) => Err(e),
+  }
+}
+
+/// Opens the current log file for appending, first moving it to the previous log file if
+/// `rotate` is set. Returns the file and its size.
+fn open_log_file(rotate: bool) -> io::Result<(File, u64)> {
+  let [current, previous] = log_files();
+  std::fs::create_dir_all(log_dir())?;
+  if rotate && current.exists() {
+    std::fs::rename(&current, previous)?;
+  }
+  let file = OpenOptions::new().create(true).append(true).open(current)?;
+  let size = file.metadata()?.len();
+  Ok((file, size))
+}
+
+/// Sets up logging. `daemon` starts a new log file, for the long-running `service` command.
+pub fn init_logging(default_log_level: &str, daemon: bool) {
+  let env = env_logger::Env::default().filter_or("RUST_LOG", default_log_level);
+  let (file, size) =
+    match current_log_size().and_then(|size| open_log_file(daemon || size > MAX_LOG_FILE_SIZE)) {
+      Ok((file, size)) => (Some(file), size),
+      Err(e) => {
+        eprintln!("not writing a log file: {e}");
+        (None, 0)
+      }
+    };
+  let tee = Tee {
+    file,
+    size,
+    prefix: format!("[pid {}] ", std::process::id()),
+  };
+  env_logger::Builder::from_env(env)
+    .target(env_logger::Target::Pipe(Box::new(tee)))
+    .init();
+}
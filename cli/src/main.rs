@@ -1,4 +1,5 @@
 mod cmd;
+mod logs;
 
 use crate::cmd::CliCommand;
 
@@ -14,10 +15,9 @@ struct Cli {
 
 #[tokio::main]
 async fn main() {
-  let default_log_level = "debug";
-  let env = env_logger::Env::default().filter_or("RUST_LOG", default_log_level);
-  env_logger::init_from_env(env);
-
   let cli = Cli::parse();
+
+  let default_log_level = "debug";
+  logs::init_logging(default_log_level, cli.command.is_daemon());
   cli.command.run().await;
 }
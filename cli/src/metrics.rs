@@ -0,0 +1,253 @@
+//! Serves driver and proxy metrics in the Prometheus text format, so a long-running
+//! installation can be scraped and alerted on like any other service.
+//!
+//! `GET /metrics` returns the counters from [MidiDriver::metrics](lumatone_midi::driver::MidiDriver::metrics),
+//! plus the number of notes played and events missed if the note proxy is running.
+//! Prometheus works out rates (e.g. notes per second) from the counters itself.
+//!
+//! The server takes any [MetricsSource]. A [Lumatone] reports on its own connection, and
+//! the `service` daemon's [ServiceMetrics](crate::service::ServiceMetrics) also counts how
+//! often the service reconnected to the device.
+//!
+//! Scraping needs a token with [Capability::Monitor] (see [crate::access]), unless the
+//! server was started with an open policy.
+
+use std::{fmt::Write, sync::Arc};
+
+use log::{debug, info, warn};
+use lumatone_midi::{controller::Lumatone, metrics::MetricsSnapshot, shutdown::CancellationToken};
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{
+  access::{AccessPolicy, Capability},
+  http::{read_request, reason, write_response},
+};
+
+/// Something the metrics server can report on.
+pub trait MetricsSource: Send + Sync {
+  /// The current metrics in the Prometheus text exposition format.
+  fn render_metrics(&self) -> String;
+}
+
+impl MetricsSource for Lumatone {
+  fn render_metrics(&self) -> String {
+    let proxy = self.proxy().map(|p| ProxyCounts {
+      notes_played: p.notes_played(),
+      events_missed: p.events_missed(),
+    });
+    render(&self.driver().metrics(), proxy)
+  }
+}
+
+/// The note proxy's counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProxyCounts {
+  pub notes_played: u64,
+  pub events_missed: u64,
+}
+
+/// Writes one metric with its help and type lines.
+pub fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+  let _ = writeln!(out, "# HELP {name} {help}");
+  let _ = writeln!(out, "# TYPE {name} {kind}");
+  let _ = writeln!(out, "{name} {value}");
+}
+
+/// Renders `driver`'s metrics, and the proxy's if it's running, in the Prometheus text
+/// exposition format.
+pub fn render(driver: &MetricsSnapshot, proxy: Option<ProxyCounts>) -> String {
+  let mut out = String::new();
+  let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+    write_metric(&mut out, name, kind, help, value)
+  };
+
+  metric(
+    "lumatone_driver_commands_sent_total",
+    "counter",
+    "Commands sent to the device, not counting resends.",
+    driver.commands_sent,
+  );
+  metric(
+    "lumatone_driver_commands_resent_total",
+    "counter",
+    "Commands sent again after the device was busy or the driver restarted.",
+    driver.commands_resent,
+  );
+  metric(
+    "lumatone_driver_responses_total",
+    "counter",
+    "Commands the device answered successfully.",
+    driver.responses,
+  );
+  metric(
+    "lumatone_driver_failures_total",
+    "counter",
+    "Commands that failed or timed out.",
+    driver.failures,
+  );
+  metric(
+    "lumatone_driver_restarts_total",
+    "counter",
+    "Times the driver restarted after a transient failure.",
+    driver.restarts,
+  );
+  metric(
+    "lumatone_driver_reconnects_total",
+    "counter",
+    "Times the driver reopened the MIDI connection.",
+    driver.reconnects,
+  );
+  metric(
+    "lumatone_driver_shed_total",
+    "counter",
+    "Commands dropped because their deadline passed.",
+    driver.shed,
+  );
+  metric(
+    "lumatone_driver_unexpected_messages_total",
+    "counter",
+    "Messages that didn't answer the command waiting for a response.",
+    driver.unexpected,
+  );
+  metric(
+    "lumatone_driver_dropped_incoming_total",
+    "counter",
+    "Messages from the device dropped because the driver fell behind.",
+    driver.dropped_incoming,
+  );
+  metric(
+    "lumatone_driver_subscriber_missed_total",
+    "counter",
+    "Broadcast messages that subscribers lost because they fell behind.",
+    driver.missed_by_subscribers,
+  );
+  metric(
+    "lumatone_driver_queue_depth",
+    "gauge",
+    "Commands waiting to be sent.",
+    driver.queue_depth as u64,
+  );
+  if let Some(proxy) = proxy {
+    metric(
+      "lumatone_proxy_notes_total",
+      "counter",
+      "Notes played on the device since the proxy started.",
+      proxy.notes_played,
+    );
+    metric(
+      "lumatone_proxy_missed_events_total",
+      "counter",
+      "Events from the device the proxy lost because it fell behind.",
+      proxy.events_missed,
+    );
+  }
+
+  let name = "lumatone_driver_response_latency_seconds";
+  let _ = writeln!(
+    out,
+    "# HELP {name} Time from sending a command to its response."
+  );
+  let _ = writeln!(out, "# TYPE {name} histogram");
+  for (bound, count) in &driver.latency.buckets {
+    let _ = writeln!(
+      out,
+      "{name}_bucket{{le=\"{}\"}} {count}",
+      bound.as_secs_f64()
+    );
+  }
+  let latency = &driver.latency;
+  let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", latency.count);
+  let _ = writeln!(out, "{name}_sum {}", latency.sum.as_secs_f64());
+  let _ = writeln!(out, "{name}_count {}", latency.count);
+  out
+}
+
+/// Serves `GET /metrics` on `listener` until `shutdown` is cancelled.
+pub async fn serve_metrics(
+  listener: TcpListener,
+  source: Arc<dyn MetricsSource>,
+  access: Arc<AccessPolicy>,
+  shutdown: CancellationToken,
+) {
+  if let Ok(addr) = listener.local_addr() {
+    info!("serving metrics on http://{addr}/metrics");
+  }
+  loop {
+    let (stream, addr) = tokio::select! {
+      _ = shutdown.cancelled() => break,
+      res = listener.accept() => match res {
+        Ok(conn) => conn,
+        Err(err) => {
+          warn!("unable to accept connection: {err}");
+          continue;
+        }
+      },
+    };
+    let source = source.clone();
+    let access = access.clone();
+    tokio::spawn(async move {
+      if let Err(err) = handle_connection(stream, source.as_ref(), &access).await {
+        debug!("metrics connection from {addr} failed: {err}");
+      }
+    });
+  }
+}
+
+async fn handle_connection(
+  mut stream: TcpStream,
+  source: &dyn MetricsSource,
+  access: &AccessPolicy,
+) -> std::io::Result<()> {
+  let status = match read_request(&mut stream).await? {
+    Ok(request) if access.check(request.token(), Capability::Monitor).is_err() => 401,
+    Ok(request) if request.method == "GET" && request.path_without_query() == "/metrics" => {
+      let body = source.render_metrics();
+      let content_type = "text/plain; version=0.0.4";
+      return write_response(&mut stream, 200, content_type, body.as_bytes()).await;
+    }
+    Ok(_) => 404,
+    Err(status) => status,
+  };
+  write_response(&mut stream, status, "text/plain", reason(status).as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::metrics::HistogramSnapshot;
+  use std::time::Duration;
+
+  #[test]
+  fn test_render_prometheus_text() {
+    let snapshot = MetricsSnapshot {
+      commands_sent: 12,
+      missed_by_subscribers: 5,
+      queue_depth: 3,
+      latency: HistogramSnapshot {
+        buckets: vec![
+          (Duration::from_millis(5), 4),
+          (Duration::from_millis(10), 6),
+        ],
+        count: 7,
+        sum: Duration::from_millis(500),
+      },
+      ..Default::default()
+    };
+    let proxy = ProxyCounts {
+      notes_played: 42,
+      events_missed: 2,
+    };
+    let text = render(&snapshot, Some(proxy));
+    assert!(text.contains("# TYPE lumatone_driver_commands_sent_total counter\n"));
+    assert!(text.contains("\nlumatone_driver_commands_sent_total 12\n"));
+    assert!(text.contains("\nlumatone_driver_queue_depth 3\n"));
+    assert!(text.contains("\nlumatone_driver_subscriber_missed_total 5\n"));
+    assert!(text.contains("\nlumatone_proxy_notes_total 42\n"));
+    assert!(text.contains("\nlumatone_proxy_missed_events_total 2\n"));
+    assert!(text.contains("_bucket{le=\"0.005\"} 4\n"));
+    assert!(text.contains("_bucket{le=\"+Inf\"} 7\n"));
+    assert!(text.contains("lumatone_driver_response_latency_seconds_sum 0.5\n"));
+
+    assert!(!render(&snapshot, None).contains("lumatone_proxy_notes_total"));
+  }
+}
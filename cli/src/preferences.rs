@@ -0,0 +1,117 @@
+//! Saves the user's [DisplayPreferences] between runs, in a file in the user's config
+//! directory (see [preferences_file]). See the keymap `preferences` module for the file
+//! format.
+//!
+//! The `preferences` command shows and changes them (see [set_preference]). The commands
+//! that draw keymaps, `render-keymap` and `batch-convert`'s SVG output, write their legends
+//! with them. Nothing else the CLI prints names notes or formats cents yet.
+
+use std::path::{Path, PathBuf};
+
+use lumatone_keymap::preferences::DisplayPreferences;
+
+use super::error::LumatoneError;
+
+use error_stack::{report, IntoReport, Result, ResultExt};
+
+pub const PREFERENCES_FILE_NAME: &str = "preferences";
+
+/// The names of the settings in a preferences file.
+const SETTINGS: [&str; 4] = [
+  "accidentals",
+  "cents_precision",
+  "interval_labels",
+  "octave_numbering",
+];
+
+/// Where preferences are kept, e.g. `~/.config/lumatone/preferences` on Linux.
+#[cfg(feature = "cli")]
+pub fn preferences_file() -> PathBuf {
+  dirs_next::config_dir()
+    .unwrap_or_else(std::env::temp_dir)
+    .join("lumatone")
+    .join(PREFERENCES_FILE_NAME)
+}
+
+/// Loads preferences from `path`, or the defaults if the file doesn't exist yet.
+pub fn load_preferences<P: AsRef<Path>>(path: P) -> Result<DisplayPreferences, LumatoneError> {
+  let path = path.as_ref();
+  if !path.exists() {
+    return Ok(DisplayPreferences::default());
+  }
+  let failed = || LumatoneError::PreferencesLoadFailed(path.to_path_buf());
+  std::fs::read_to_string(path)
+    .report()
+    .change_context_lazy(failed)?
+    .parse::<DisplayPreferences>()
+    .map_err(|e| report!(failed()).attach_printable(format!("{e:?}")))
+}
+
+/// Changes one setting, given as `name=value` like a line of the preferences file, e.g.
+/// `accidentals=flats`.
+pub fn set_preference(
+  prefs: &DisplayPreferences,
+  setting: &str,
+) -> Result<DisplayPreferences, LumatoneError> {
+  let invalid = || LumatoneError::InvalidPreference(setting.to_string());
+  match setting.split_once('=') {
+    Some((name, _)) if SETTINGS.contains(&name.trim()) => {}
+    _ => return Err(report!(invalid())),
+  }
+  // later lines override earlier ones
+  format!("{prefs}{setting}\n")
+    .parse()
+    .map_err(|e| report!(invalid()).attach_printable(format!("{e:?}")))
+}
+
+pub fn save_preferences<P: AsRef<Path>>(
+  path: P,
+  prefs: &DisplayPreferences,
+) -> Result<(), LumatoneError> {
+  let path = path.as_ref();
+  let failed = || LumatoneError::PreferencesSaveFailed(path.to_path_buf());
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)
+      .report()
+      .change_context_lazy(failed)?;
+  }
+  std::fs::write(path, prefs.to_string())
+    .report()
+    .change_context_lazy(failed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_keymap::spelling::Accidentals;
+
+  #[test]
+  fn test_save_and_load() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path();
+    let path = dir.join(PREFERENCES_FILE_NAME);
+    assert_eq!(
+      load_preferences(&path).unwrap(),
+      DisplayPreferences::default()
+    );
+
+    let prefs = DisplayPreferences {
+      accidentals: Accidentals::Flats,
+      ..Default::default()
+    };
+    save_preferences(&path, &prefs).unwrap();
+    assert_eq!(load_preferences(&path).unwrap(), prefs);
+  }
+
+  #[test]
+  fn test_set_preference() {
+    let prefs = DisplayPreferences::default();
+    let flats = set_preference(&prefs, "accidentals=flats").unwrap();
+    assert_eq!(flats.accidentals, Accidentals::Flats);
+    assert_eq!(flats.cents_precision, prefs.cents_precision);
+
+    assert!(set_preference(&prefs, "accidental=flats").is_err());
+    assert!(set_preference(&prefs, "accidentals=naturals").is_err());
+    assert!(set_preference(&prefs, "accidentals").is_err());
+  }
+}
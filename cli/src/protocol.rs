@@ -0,0 +1,894 @@
+//! A versioned JSON protocol for clients that control the device over a text transport,
+//! e.g. a WebSocket or a pipe to another program.
+//!
+//! Every message is one JSON object. A client sends [Request]s, each with an `id` of its
+//! choosing, and gets back exactly one [Response] with the same `id`, holding either a
+//! result or a typed [ProtocolError]. After subscribing to a [Topic], the client also
+//! receives [EventFrame]s, tagged with the subscription they belong to.
+//!
+//! ```text
+//! -> {"version": 1, "id": 1, "method": "subscribe", "params": {"topic": "notes"}}
+//! <- {"version": 1, "id": 1, "result": {"subscription": 0}}
+//! -> {"version": 1, "id": 2, "method": "action", "params": {"action": "next-scene"}}
+//! <- {"version": 1, "id": 2, "result": {}}
+//! <- {"version": 1, "subscription": 0, "event": {"type": "note_on", "channel": 1, "note": 60, "velocity": 90}}
+//! ```
+//!
+//! Sessions sharing a [Coordinator] can lock resources and subscribe to each other's
+//! changes with the `changes` topic (see [crate::coordination]).
+//!
+//! The full contract is the JSON schema in `protocol/schema.json`, also available as
+//! [SCHEMA], which the tests check against the serde types below. Breaking changes bump
+//! [PROTOCOL_VERSION]; [parse_request] rejects requests for any other version, so
+//! third-party clients fail loudly instead of misbehaving.
+//!
+//! The message types, [parse_request] and the keymap encoding don't need the MIDI driver,
+//! so clients can use them on their own. With the `driver` feature, a `Session` performs
+//! requests against a connected device. This crate doesn't include a transport for it: one
+//! only has to move lines of text around, handing each incoming message to
+//! `Session::handle` and writing back what it returns, plus the output of
+//! `Session::frame_events` for every event from the device, and of
+//! `Session::frame_coordination` for every [CoordinationEvent]. The HTTP facade in
+//! `rest` reuses the protocol's replies, errors and events.
+
+#[cfg(feature = "driver")]
+use std::{collections::BTreeMap, sync::Arc};
+
+use lumatone_keymap::{compact::Compression, ltn::LumatoneKeyMap};
+use lumatone_midi::events::ChannelMessage;
+#[cfg(feature = "driver")]
+use lumatone_midi::{commands::ping, info::DeviceInfo, responses::Response as DeviceResponse};
+use serde::{Deserialize, Serialize};
+
+use super::{
+  access::{AccessDenied, Capability},
+  coordination::{ClientId, Conflict, CoordinationEvent, Resource},
+  error::LumatoneError,
+};
+#[cfg(feature = "driver")]
+use super::{
+  actions::{Action, ActionDispatcher},
+  coordination::Coordinator,
+};
+
+use error_stack::Report;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The JSON schema for every message in the protocol.
+pub const SCHEMA: &str = include_str!("../protocol/schema.json");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Request {
+  pub version: u32,
+  pub id: u64,
+  #[serde(flatten)]
+  pub method: Method,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Method {
+  /// Pings the device with `value`, which it echoes back.
+  Ping {
+    value: u32,
+  },
+  /// Performs an [Action], given in its text form.
+  Action {
+    action: String,
+  },
+  /// Returns the device's serial number, firmware version and board count.
+  DeviceInfo,
+  /// Sends a whole keymap, in the compact form (see [lumatone_keymap::compact]), base64
+  /// encoded. See [encode_keymap].
+  SendKeymap {
+    keymap: String,
+  },
+  Subscribe {
+    topic: Topic,
+  },
+  Unsubscribe {
+    subscription: u64,
+  },
+  /// Locks a resource, or renews the lock, for the session's client.
+  Lock {
+    resource: Resource,
+  },
+  Unlock {
+    resource: Resource,
+  },
+}
+
+impl Method {
+  /// What the client must be allowed to do to make this request.
+  pub fn required_capability(&self) -> Capability {
+    match self {
+      Method::Action { .. }
+      | Method::SendKeymap { .. }
+      | Method::Lock { .. }
+      | Method::Unlock { .. } => Capability::Control,
+      Method::Ping { .. }
+      | Method::DeviceInfo
+      | Method::Subscribe { .. }
+      | Method::Unsubscribe { .. } => Capability::Monitor,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+  /// Notes played on the device.
+  Notes,
+  /// Locks and changes made by other clients.
+  Changes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Response {
+  pub version: u32,
+  /// `None` if the request couldn't be parsed far enough to find its id.
+  pub id: Option<u64>,
+  #[serde(flatten)]
+  pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+  Result(Reply),
+  Error(ProtocolError),
+}
+
+/// The result of a successful request. Which variant depends on the request's [Method].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Reply {
+  Pong {
+    value: u32,
+  },
+  DeviceInfo {
+    serial: String,
+    firmware: String,
+    boards: usize,
+  },
+  Subscribed {
+    subscription: u64,
+  },
+  Locked {
+    /// How long until the lock expires, unless it's renewed.
+    lease_ms: u64,
+  },
+  Sent {
+    /// The number of commands sent.
+    sent: usize,
+  },
+  Done {},
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolError {
+  pub code: ErrorCode,
+  pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  /// The message isn't valid JSON.
+  ParseError,
+  UnsupportedVersion,
+  /// The message is JSON, but not a valid [Request].
+  InvalidRequest,
+  InvalidAction,
+  ActionFailed,
+  DeviceError,
+  UnknownSubscription,
+  /// The client needs a valid access token (see [crate::access]).
+  Unauthenticated,
+  /// The client's token doesn't allow the request.
+  Forbidden,
+  /// Another client has locked the resource.
+  Conflict,
+}
+
+#[cfg(feature = "driver")]
+impl Reply {
+  pub fn device_info(info: &DeviceInfo) -> Self {
+    Reply::DeviceInfo {
+      serial: info.serial.to_string(),
+      firmware: info.firmware_version.to_string(),
+      boards: info.board_count(),
+    }
+  }
+}
+
+impl ProtocolError {
+  pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+    ProtocolError {
+      code,
+      message: message.into(),
+    }
+  }
+
+  pub fn access_denied(denied: AccessDenied) -> Self {
+    let code = match denied {
+      AccessDenied::Unauthenticated => ErrorCode::Unauthenticated,
+      AccessDenied::Forbidden => ErrorCode::Forbidden,
+    };
+    ProtocolError::new(code, denied.to_string())
+  }
+
+  pub fn conflict(conflict: &Conflict) -> Self {
+    ProtocolError::new(ErrorCode::Conflict, conflict.to_string())
+  }
+
+  pub fn from_report(err: &Report<LumatoneError>) -> Self {
+    use LumatoneError::*;
+    let code = match err.current_context() {
+      InvalidAction(_) => ErrorCode::InvalidAction,
+      ActionFailed(_) | ScaleLocked | SceneNotFound(_) | ProxyNotRunning => ErrorCode::ActionFailed,
+      _ => ErrorCode::DeviceError,
+    };
+    ProtocolError::new(code, err.current_context().to_string())
+  }
+}
+
+impl Response {
+  fn new(id: Option<u64>, outcome: Outcome) -> Self {
+    Response {
+      version: PROTOCOL_VERSION,
+      id,
+      outcome,
+    }
+  }
+
+  pub fn to_json(&self) -> String {
+    serde_json::to_string(self).expect("responses are always serializable")
+  }
+}
+
+/// An event for one of the client's subscriptions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventFrame {
+  pub version: u32,
+  pub subscription: u64,
+  pub event: Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  NoteOn {
+    channel: u8,
+    note: u8,
+    velocity: u8,
+  },
+  NoteOff {
+    channel: u8,
+    note: u8,
+    velocity: u8,
+  },
+  Locked {
+    resource: Resource,
+    client: ClientId,
+  },
+  Unlocked {
+    resource: Resource,
+    client: ClientId,
+  },
+  Changed {
+    resource: Resource,
+    client: ClientId,
+  },
+}
+
+impl Event {
+  /// The event for a message from the device, and the topic it belongs to, if any.
+  pub fn from_channel_message(message: &ChannelMessage) -> Option<(Topic, Event)> {
+    match *message {
+      ChannelMessage::NoteOn {
+        channel,
+        note,
+        velocity,
+      } => Some((
+        Topic::Notes,
+        Event::NoteOn {
+          channel: channel.get(),
+          note,
+          velocity,
+        },
+      )),
+      ChannelMessage::NoteOff {
+        channel,
+        note,
+        velocity,
+      } => Some((
+        Topic::Notes,
+        Event::NoteOff {
+          channel: channel.get(),
+          note,
+          velocity,
+        },
+      )),
+      _ => None,
+    }
+  }
+
+  pub fn from_coordination(event: &CoordinationEvent) -> Event {
+    match event.clone() {
+      CoordinationEvent::Locked { resource, client } => Event::Locked { resource, client },
+      CoordinationEvent::Unlocked { resource, client } => Event::Unlocked { resource, client },
+      CoordinationEvent::Changed { resource, client } => Event::Changed { resource, client },
+    }
+  }
+}
+
+/// The resources an action changes, which it needs to be allowed to write.
+#[cfg(feature = "driver")]
+fn action_resources(action: &Action) -> Vec<Resource> {
+  use Action::*;
+  match action {
+    Upload(_) | SetBrightness(_) => vec![Resource::Keymap],
+    TransposeUp | TransposeDown | SetTranspose(_) | ToggleScaleLock => vec![Resource::Proxy],
+    // scenes and undo can change either
+    NextScene | PreviousScene | SelectScene(_) | Undo => vec![Resource::Keymap, Resource::Proxy],
+    // always allowed, so anyone can silence stuck notes
+    Panic => vec![],
+  }
+}
+
+/// Encodes a keymap for [Method::SendKeymap]. It isn't compressed, since the server may not
+/// support zstd.
+pub fn encode_keymap(keymap: &LumatoneKeyMap) -> String {
+  base64::encode(keymap.to_compact(Compression::None))
+}
+
+pub fn decode_keymap(encoded: &str) -> std::result::Result<LumatoneKeyMap, ProtocolError> {
+  let invalid = |msg: String| ProtocolError::new(ErrorCode::InvalidRequest, msg);
+  let bytes = base64::decode(encoded).map_err(|e| invalid(format!("keymap isn't base64: {e}")))?;
+  LumatoneKeyMap::from_compact(&bytes).map_err(|e| invalid(format!("invalid keymap: {e:?}")))
+}
+
+/// Parses a request, checking its version. On failure, returns the error response to send
+/// back, with the request's id if it could be found.
+pub fn parse_request(text: &str) -> std::result::Result<Request, Response> {
+  let fail = |id, code, message: String| {
+    Response::new(id, Outcome::Error(ProtocolError::new(code, message)))
+  };
+
+  let value: serde_json::Value =
+    serde_json::from_str(text).map_err(|e| fail(None, ErrorCode::ParseError, e.to_string()))?;
+  let id = value.get("id").and_then(|id| id.as_u64());
+  match value.get("version").and_then(|v| v.as_u64()) {
+    Some(version) if version == PROTOCOL_VERSION as u64 => {}
+    Some(version) => {
+      return Err(fail(
+        id,
+        ErrorCode::UnsupportedVersion,
+        format!("unsupported protocol version {version}, expected {PROTOCOL_VERSION}"),
+      ))
+    }
+    None => {
+      return Err(fail(
+        id,
+        ErrorCode::InvalidRequest,
+        "missing protocol version".to_string(),
+      ))
+    }
+  }
+  serde_json::from_value(value).map_err(|e| fail(id, ErrorCode::InvalidRequest, e.to_string()))
+}
+
+/// One client's connection: performs its requests and tracks its subscriptions.
+#[cfg(feature = "driver")]
+pub struct Session<'a> {
+  dispatcher: ActionDispatcher<'a>,
+  subscriptions: BTreeMap<u64, Topic>,
+  next_subscription: u64,
+  capability: Capability,
+  coordinator: Arc<Coordinator>,
+  client: ClientId,
+}
+
+#[cfg(feature = "driver")]
+impl<'a> Session<'a> {
+  /// A session with full control, e.g. for a local transport.
+  pub fn new(dispatcher: ActionDispatcher<'a>) -> Self {
+    let coordinator = Arc::new(Coordinator::new());
+    let client = coordinator.connect();
+    Session {
+      dispatcher,
+      subscriptions: BTreeMap::new(),
+      next_subscription: 0,
+      capability: Capability::Control,
+      coordinator,
+      client,
+    }
+  }
+
+  /// Shares locks and changes with the other sessions using `coordinator`. Each session
+  /// gets its own [ClientId], and its locks are released when it's dropped.
+  pub fn with_coordinator(mut self, coordinator: Arc<Coordinator>) -> Self {
+    self.coordinator.disconnect(&self.client);
+    self.client = coordinator.connect();
+    self.coordinator = coordinator;
+    self
+  }
+
+  pub fn client(&self) -> &ClientId {
+    &self.client
+  }
+
+  /// Limits the session to what `capability` allows. Transports that accept remote
+  /// clients should look the client's token up in an
+  /// [AccessPolicy](crate::access::AccessPolicy) when it connects, and pass the result here.
+  pub fn with_capability(mut self, capability: Capability) -> Self {
+    self.capability = capability;
+    self
+  }
+
+  pub fn dispatcher(&self) -> &ActionDispatcher<'a> {
+    &self.dispatcher
+  }
+
+  /// Handles one incoming message, returning the response to send back.
+  pub async fn handle(&mut self, text: &str) -> String {
+    match parse_request(text) {
+      Ok(request) => self.handle_request(request).await,
+      Err(response) => response,
+    }
+    .to_json()
+  }
+
+  pub async fn handle_request(&mut self, request: Request) -> Response {
+    let outcome = match self.perform(request.method).await {
+      Ok(reply) => Outcome::Result(reply),
+      Err(err) => Outcome::Error(err),
+    };
+    Response::new(Some(request.id), outcome)
+  }
+
+  async fn perform(&mut self, method: Method) -> std::result::Result<Reply, ProtocolError> {
+    if method.required_capability() > self.capability {
+      return Err(ProtocolError::access_denied(AccessDenied::Forbidden));
+    }
+    let lumatone = self.dispatcher.lumatone();
+    match method {
+      Method::Ping { value } => match lumatone.send(ping(value)).await {
+        Ok(DeviceResponse::Pong(value)) => Ok(Reply::Pong { value }),
+        Ok(res) => Err(ProtocolError::new(
+          ErrorCode::DeviceError,
+          format!("unexpected response to ping: {res}"),
+        )),
+        Err(err) => Err(ProtocolError::new(
+          ErrorCode::DeviceError,
+          err.current_context().to_string(),
+        )),
+      },
+
+      Method::Action { action } => {
+        let action: Action = action.parse().map_err(|e| ProtocolError::from_report(&e))?;
+        let resources = action_resources(&action);
+        for resource in &resources {
+          self
+            .coordinator
+            .check_write(&self.client, *resource)
+            .map_err(|c| ProtocolError::conflict(&c))?;
+        }
+        self
+          .dispatcher
+          .dispatch(action)
+          .await
+          .map_err(|e| ProtocolError::from_report(&e))?;
+        for resource in resources {
+          self.coordinator.publish(&self.client, resource);
+        }
+        Ok(Reply::Done {})
+      }
+
+      Method::SendKeymap { keymap } => {
+        let keymap = decode_keymap(&keymap)?;
+        self
+          .coordinator
+          .check_write(&self.client, Resource::Keymap)
+          .map_err(|c| ProtocolError::conflict(&c))?;
+        let commands = keymap.to_midi_commands();
+        let sent = commands.len();
+        lumatone.upload(commands).await.map_err(|e| {
+          ProtocolError::new(ErrorCode::DeviceError, e.current_context().to_string())
+        })?;
+        self.coordinator.publish(&self.client, Resource::Keymap);
+        Ok(Reply::Sent { sent })
+      }
+
+      Method::DeviceInfo => match lumatone.device_info() {
+        Some(info) => Ok(Reply::device_info(info)),
+        None => Err(ProtocolError::new(
+          ErrorCode::DeviceError,
+          "device info hasn't been read",
+        )),
+      },
+
+      Method::Subscribe { topic } => {
+        let subscription = self.next_subscription;
+        self.next_subscription += 1;
+        self.subscriptions.insert(subscription, topic);
+        Ok(Reply::Subscribed { subscription })
+      }
+
+      Method::Unsubscribe { subscription } => match self.subscriptions.remove(&subscription) {
+        Some(_) => Ok(Reply::Done {}),
+        None => Err(ProtocolError::new(
+          ErrorCode::UnknownSubscription,
+          format!("no subscription {subscription}"),
+        )),
+      },
+
+      Method::Lock { resource } => {
+        self
+          .coordinator
+          .lock(&self.client, resource)
+          .map_err(|c| ProtocolError::conflict(&c))?;
+        let lease_ms = self.coordinator.lease().as_millis() as u64;
+        Ok(Reply::Locked { lease_ms })
+      }
+
+      Method::Unlock { resource } => {
+        self.coordinator.unlock(&self.client, resource);
+        Ok(Reply::Done {})
+      }
+    }
+  }
+
+  /// Frames a message from the device for each subscription that wants it.
+  pub fn frame_events(&self, message: &ChannelMessage) -> Vec<String> {
+    match Event::from_channel_message(message) {
+      Some((topic, event)) => self.frame(topic, event),
+      None => vec![],
+    }
+  }
+
+  /// Frames a lock or change for each subscription to [Topic::Changes]. The session's own
+  /// changes are left out, since it already knows about them.
+  pub fn frame_coordination(&self, event: &CoordinationEvent) -> Vec<String> {
+    let client = match event {
+      CoordinationEvent::Locked { client, .. }
+      | CoordinationEvent::Unlocked { client, .. }
+      | CoordinationEvent::Changed { client, .. } => client,
+    };
+    match *client == self.client {
+      true => vec![],
+      false => self.frame(Topic::Changes, Event::from_coordination(event)),
+    }
+  }
+
+  fn frame(&self, topic: Topic, event: Event) -> Vec<String> {
+    self
+      .subscriptions
+      .iter()
+      .filter(|(_, t)| **t == topic)
+      .map(|(subscription, _)| {
+        let frame = EventFrame {
+          version: PROTOCOL_VERSION,
+          subscription: *subscription,
+          event: event.clone(),
+        };
+        serde_json::to_string(&frame).expect("events are always serializable")
+      })
+      .collect()
+  }
+}
+
+#[cfg(feature = "driver")]
+impl Drop for Session<'_> {
+  fn drop(&mut self) {
+    self.coordinator.disconnect(&self.client);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_requests_and_errors_follow_the_schema() {
+    let request = parse_request(
+      r#"{"version": 1, "id": 7, "method": "action", "params": {"action": "next-scene"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+      request,
+      Request {
+        version: 1,
+        id: 7,
+        method: Method::Action {
+          action: "next-scene".to_string()
+        }
+      }
+    );
+    let request = parse_request(r#"{"version": 1, "id": 8, "method": "device_info"}"#).unwrap();
+    assert_eq!(request.method, Method::DeviceInfo);
+    let keymap = LumatoneKeyMap::from_ini_str("[Board0]\nKey_0=61\n").unwrap();
+    let request = parse_request(&format!(
+      r#"{{"version": 1, "id": 10, "method": "send_keymap", "params": {{"keymap": "{}"}}}}"#,
+      encode_keymap(&keymap)
+    ))
+    .unwrap();
+    match request.method {
+      Method::SendKeymap { keymap: encoded } => assert_eq!(
+        decode_keymap(&encoded).unwrap().fingerprint(),
+        keymap.fingerprint()
+      ),
+      method => panic!("expected send_keymap, got {method:?}"),
+    }
+    assert_eq!(
+      decode_keymap("not base64!").unwrap_err().code,
+      ErrorCode::InvalidRequest
+    );
+    let request = parse_request(
+      r#"{"version": 1, "id": 9, "method": "lock", "params": {"resource": "key/1/5"}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+      request.method,
+      Method::Lock {
+        resource: "key/1/5".parse().unwrap()
+      }
+    );
+
+    let code = |text: &str| match parse_request(text).unwrap_err().outcome {
+      Outcome::Error(err) => err.code,
+      Outcome::Result(_) => panic!("expected an error"),
+    };
+    assert_eq!(code("{"), ErrorCode::ParseError);
+    assert_eq!(
+      code(r#"{"version": 2, "id": 1, "method": "device_info"}"#),
+      ErrorCode::UnsupportedVersion
+    );
+    let response = parse_request(r#"{"version": 1, "id": 3, "method": "reboot"}"#).unwrap_err();
+    assert_eq!(response.id, Some(3));
+    assert!(response.to_json().contains(r#""code":"invalid_request""#));
+
+    let response = Response::new(
+      Some(1),
+      Outcome::Result(Reply::Subscribed { subscription: 0 }),
+    );
+    assert_eq!(
+      response.to_json(),
+      r#"{"version":1,"id":1,"result":{"subscription":0}}"#
+    );
+
+    let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+    let codes = &schema["$defs"]["error"]["properties"]["code"]["enum"];
+    for code in [
+      ErrorCode::ParseError,
+      ErrorCode::Forbidden,
+      ErrorCode::Conflict,
+    ] {
+      assert!(codes
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::to_value(code).unwrap()));
+    }
+  }
+
+  /// Checks `value` against the parts of JSON schema that `protocol/schema.json` uses,
+  /// except `pattern`.
+  fn conforms(
+    value: &serde_json::Value,
+    schema: &serde_json::Value,
+    root: &serde_json::Value,
+  ) -> bool {
+    use serde_json::Value;
+    if let Some(reference) = schema["$ref"].as_str() {
+      let name = reference.strip_prefix("#/$defs/").unwrap();
+      return conforms(value, &root["$defs"][name], root);
+    }
+    if let Some(expected) = schema.get("const") {
+      if value != expected {
+        return false;
+      }
+    }
+    if let Some(allowed) = schema["enum"].as_array() {
+      if !allowed.contains(value) {
+        return false;
+      }
+    }
+    let type_ok = match schema["type"].as_str() {
+      Some("object") => value.is_object(),
+      Some("string") => value.is_string(),
+      Some("integer") => value.is_u64() || value.is_i64(),
+      Some("null") => value.is_null(),
+      _ => true,
+    };
+    let in_range = match value.as_i64() {
+      Some(n) => {
+        schema["minimum"].as_i64().map_or(true, |min| n >= min)
+          && schema["maximum"].as_i64().map_or(true, |max| n <= max)
+      }
+      None => true,
+    };
+    if !type_ok || !in_range {
+      return false;
+    }
+    if let Value::Object(fields) = value {
+      let mut required = schema["required"].as_array().into_iter().flatten();
+      if !required.all(|name| fields.contains_key(name.as_str().unwrap())) {
+        return false;
+      }
+      let properties = &schema["properties"];
+      for (name, field) in fields {
+        match properties.get(name) {
+          Some(property) if !conforms(field, property, root) => return false,
+          None if schema["additionalProperties"] == false => return false,
+          _ => {}
+        }
+      }
+    }
+    match schema["oneOf"].as_array() {
+      Some(branches) => branches.iter().filter(|b| conforms(value, b, root)).count() == 1,
+      None => true,
+    }
+  }
+
+  #[test]
+  fn test_schema_matches_serde_types() {
+    let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+    let check = |def: &str, value: serde_json::Value| {
+      assert!(
+        conforms(&value, &schema["$defs"][def], &schema),
+        "{value} doesn't match the {def} schema"
+      );
+      assert!(conforms(&value, &schema, &schema), "{value}");
+    };
+
+    let keymap = LumatoneKeyMap::from_ini_str("[Board0]\nKey_0=61\n").unwrap();
+    let methods = vec![
+      Method::Ping { value: 3 },
+      Method::Action {
+        action: "next-scene".to_string(),
+      },
+      Method::DeviceInfo,
+      Method::SendKeymap {
+        keymap: encode_keymap(&keymap),
+      },
+      Method::Subscribe {
+        topic: Topic::Notes,
+      },
+      Method::Subscribe {
+        topic: Topic::Changes,
+      },
+      Method::Unsubscribe { subscription: 0 },
+      Method::Lock {
+        resource: Resource::Keymap,
+      },
+      Method::Unlock {
+        resource: "key/2/7".parse().unwrap(),
+      },
+    ];
+    for method in methods {
+      // a new method must be added to the list above and to the schema
+      match method {
+        Method::Ping { .. }
+        | Method::Action { .. }
+        | Method::DeviceInfo
+        | Method::SendKeymap { .. }
+        | Method::Subscribe { .. }
+        | Method::Unsubscribe { .. }
+        | Method::Lock { .. }
+        | Method::Unlock { .. } => {}
+      }
+      let request = Request {
+        version: PROTOCOL_VERSION,
+        id: 1,
+        method,
+      };
+      check("request", serde_json::to_value(&request).unwrap());
+    }
+    check(
+      "request",
+      serde_json::json!({"version": 1, "id": 1, "method": "ping", "params": {"value": 1}}),
+    );
+    assert!(!conforms(
+      &serde_json::json!({"version": 1, "id": 1, "method": "reboot"}),
+      &schema["$defs"]["request"],
+      &schema
+    ));
+
+    let replies = vec![
+      Reply::Pong { value: 3 },
+      Reply::DeviceInfo {
+        serial: "0a0b0c0d0e0f".to_string(),
+        firmware: "1.0.14".to_string(),
+        boards: 5,
+      },
+      Reply::Subscribed { subscription: 0 },
+      Reply::Locked { lease_ms: 60000 },
+      Reply::Sent { sent: 280 },
+      Reply::Done {},
+    ];
+    for reply in replies {
+      match reply {
+        Reply::Pong { .. }
+        | Reply::DeviceInfo { .. }
+        | Reply::Subscribed { .. }
+        | Reply::Locked { .. }
+        | Reply::Sent { .. }
+        | Reply::Done {} => {}
+      }
+      let response = Response::new(Some(1), Outcome::Result(reply));
+      check("response", serde_json::to_value(&response).unwrap());
+    }
+
+    let codes = [
+      ErrorCode::ParseError,
+      ErrorCode::UnsupportedVersion,
+      ErrorCode::InvalidRequest,
+      ErrorCode::InvalidAction,
+      ErrorCode::ActionFailed,
+      ErrorCode::DeviceError,
+      ErrorCode::UnknownSubscription,
+      ErrorCode::Unauthenticated,
+      ErrorCode::Forbidden,
+      ErrorCode::Conflict,
+    ];
+    for code in codes {
+      match code {
+        ErrorCode::ParseError
+        | ErrorCode::UnsupportedVersion
+        | ErrorCode::InvalidRequest
+        | ErrorCode::InvalidAction
+        | ErrorCode::ActionFailed
+        | ErrorCode::DeviceError
+        | ErrorCode::UnknownSubscription
+        | ErrorCode::Unauthenticated
+        | ErrorCode::Forbidden
+        | ErrorCode::Conflict => {}
+      }
+      let response = Response::new(None, Outcome::Error(ProtocolError::new(code, "oops")));
+      check("response", serde_json::to_value(&response).unwrap());
+    }
+
+    let client = ClientId("laptop".to_string());
+    let events = vec![
+      Event::NoteOn {
+        channel: 1,
+        note: 60,
+        velocity: 90,
+      },
+      Event::NoteOff {
+        channel: 16,
+        note: 127,
+        velocity: 0,
+      },
+      Event::Locked {
+        resource: Resource::Proxy,
+        client: client.clone(),
+      },
+      Event::Unlocked {
+        resource: Resource::Keymap,
+        client: client.clone(),
+      },
+      Event::Changed {
+        resource: "key/5/55".parse().unwrap(),
+        client,
+      },
+    ];
+    for event in events {
+      match event {
+        Event::NoteOn { .. }
+        | Event::NoteOff { .. }
+        | Event::Locked { .. }
+        | Event::Unlocked { .. }
+        | Event::Changed { .. } => {}
+      }
+      let frame = EventFrame {
+        version: PROTOCOL_VERSION,
+        subscription: 0,
+        event,
+      };
+      check("event", serde_json::to_value(&frame).unwrap());
+    }
+  }
+}
@@ -0,0 +1,284 @@
+//! Bug report archives.
+//!
+//! An [ErrorReport] collects what a maintainer needs to reproduce a problem (the crate
+//! version, platform, device info, recent logs and recent device traffic) into one zip file.
+//! The traffic a command sees itself is only its own device queries, so the `service` daemon
+//! saves its recent traffic to a file (see
+//! [ServiceConfig::traffic_file](crate::service::ServiceConfig::traffic_file)) for the
+//! report to pick up. Nothing is sent anywhere: the user attaches the file to an issue themselves. Before anything is written,
+//! every text file is passed through a [Redactor], which replaces identifying strings like
+//! the device serial number and the user's home directory with placeholders. The user name is
+//! only replaced where it's a path component, e.g. in `/Users/<user>`, so a short name
+//! doesn't mangle unrelated words in the logs.
+
+use std::{
+  collections::BTreeMap,
+  path::{Path, PathBuf},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use lumatone_midi::{info::DeviceInfo, traffic::TrafficEntry};
+
+use super::{bundle::ZipWriter, error::LumatoneError};
+
+use error_stack::{IntoReport, Result, ResultExt};
+
+/// How many lines to keep from the end of each log file.
+pub const MAX_LOG_LINES: usize = 5000;
+
+/// Replaces identifying strings with placeholders.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+  replacements: Vec<(String, String)>,
+  /// Replaced only where they appear as a whole path component.
+  path_components: Vec<(String, String)>,
+}
+
+impl Redactor {
+  pub fn new() -> Self {
+    Redactor::default()
+  }
+
+  /// A redactor for the current user's home directory, and the user name in other paths.
+  pub fn for_environment() -> Self {
+    let mut redactor = Redactor::new();
+    for var in ["HOME", "USERPROFILE"] {
+      if let Ok(home) = std::env::var(var) {
+        redactor.add(home, "~");
+      }
+    }
+    for var in ["USER", "USERNAME"] {
+      if let Ok(user) = std::env::var(var) {
+        redactor.add_path_component(user, "<user>");
+      }
+    }
+    redactor
+  }
+
+  /// Replaces `secret` with `placeholder`. Empty or very short secrets are ignored, since
+  /// replacing them would mangle unrelated text.
+  pub fn add<S: Into<String>, P: Into<String>>(&mut self, secret: S, placeholder: P) {
+    let secret = secret.into();
+    if secret.len() < 3 || self.replacements.iter().any(|(s, _)| *s == secret) {
+      return;
+    }
+    self.replacements.push((secret, placeholder.into()));
+    // longest first, so a home directory is replaced before the user name inside it
+    self
+      .replacements
+      .sort_by_key(|(secret, _)| std::cmp::Reverse(secret.len()));
+  }
+
+  /// Replaces `name` with `placeholder` where it's a whole path component, i.e. follows a
+  /// path separator and is followed by another one or something that can't be part of a
+  /// file name.
+  pub fn add_path_component<S: Into<String>, P: Into<String>>(&mut self, name: S, placeholder: P) {
+    let name = name.into();
+    if name.is_empty() || self.path_components.iter().any(|(n, _)| *n == name) {
+      return;
+    }
+    self.path_components.push((name, placeholder.into()));
+  }
+
+  /// Redacts the device's serial number, in the forms it appears in logs and traffic dumps.
+  pub fn add_device(&mut self, info: &DeviceInfo) {
+    self.add(info.serial.to_string(), "<serial>");
+    self.add(format!("{:?}", info.serial), "<serial>");
+    let bytes = info.serial.0.map(|b| format!("{b:x}"));
+    self.add(bytes.join(" "), "<serial>");
+  }
+
+  pub fn apply(&self, text: &str) -> String {
+    let text = self
+      .replacements
+      .iter()
+      .fold(text.to_string(), |text, (secret, placeholder)| {
+        text.replace(secret.as_str(), placeholder)
+      });
+    self
+      .path_components
+      .iter()
+      .fold(text, |text, (name, placeholder)| {
+        replace_path_component(&text, name, placeholder)
+      })
+  }
+}
+
+/// One line per message, as in `traffic.txt`.
+pub fn format_traffic(traffic: &[TrafficEntry]) -> String {
+  traffic.iter().map(|entry| format!("{entry}\n")).collect()
+}
+
+fn replace_path_component(text: &str, name: &str, placeholder: &str) -> String {
+  let is_separator = |c: char| c == '/' || c == '\\';
+  let is_name_char = |c: char| c.is_alphanumeric() || "-_.".contains(c);
+  let mut redacted = String::with_capacity(text.len());
+  let mut rest = text;
+  while let Some(i) = rest.find(name) {
+    let (before, after) = (&rest[..i], &rest[i + name.len()..]);
+    let whole = before.ends_with(is_separator)
+      && after
+        .chars()
+        .next()
+        .map_or(true, |c| is_separator(c) || !is_name_char(c));
+    redacted.push_str(before);
+    redacted.push_str(if whole { placeholder } else { name });
+    rest = after;
+  }
+  redacted.push_str(rest);
+  redacted
+}
+
+/// The contents of a bug report, before redaction.
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+  summary: Vec<(String, String)>,
+  files: BTreeMap<String, String>,
+}
+
+impl ErrorReport {
+  /// A report with the crate version and platform already filled in.
+  pub fn new() -> Self {
+    let created = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs())
+      .unwrap_or_default();
+    let mut report = ErrorReport {
+      summary: vec![],
+      files: BTreeMap::new(),
+    };
+    report.add_summary("lumatone version", env!("CARGO_PKG_VERSION"));
+    report.add_summary(
+      "platform",
+      format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+    );
+    report.add_summary("created (unix time)", created.to_string());
+    report
+  }
+
+  /// Adds a line to the report's summary file.
+  pub fn add_summary<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+    self.summary.push((key.into(), value.into()));
+  }
+
+  pub fn add_device_info(&mut self, info: &DeviceInfo) {
+    self.add_summary("device", info.to_string());
+    for board in &info.boards {
+      self.add_summary(
+        format!("board {}", board.board_index),
+        format!("{} keys", board.key_count),
+      );
+    }
+  }
+
+  pub fn add_file<N: Into<String>, T: Into<String>>(&mut self, name: N, text: T) {
+    self.files.insert(name.into(), text.into());
+  }
+
+  /// Adds the last [MAX_LOG_LINES] lines of a log file under `logs/`. Missing files are
+  /// noted in the summary rather than failing the report.
+  pub fn add_log_file(&mut self, path: &Path) {
+    let name = path
+      .file_name()
+      .map(|n| n.to_string_lossy().to_string())
+      .unwrap_or_else(|| "log".to_string());
+    match std::fs::read(path) {
+      Ok(bytes) => {
+        let text = String::from_utf8_lossy(&bytes);
+        let lines = text.lines().collect::<Vec<_>>();
+        let tail = &lines[lines.len().saturating_sub(MAX_LOG_LINES)..];
+        self.add_file(format!("logs/{name}"), tail.join("\n"));
+      }
+      Err(e) => self.add_summary(format!("log {name}"), format!("not included ({e})")),
+    }
+  }
+
+  /// Adds decoded device traffic (see [lumatone_midi::traffic]) from the process writing
+  /// the report as `traffic.txt`.
+  pub fn add_traffic(&mut self, traffic: &[TrafficEntry]) {
+    self.add_file("traffic.txt", format_traffic(traffic));
+    self.add_summary("traffic.txt", "only this command's own device queries");
+  }
+
+  /// Adds the traffic the `service` daemon saved to `path` as `service-traffic.txt`, or notes
+  /// in the summary that it isn't available.
+  pub fn add_service_traffic(&mut self, path: &Path) {
+    match std::fs::read_to_string(path) {
+      Ok(text) => self.add_file("service-traffic.txt", text),
+      Err(e) => self.add_summary("service traffic", format!("not available ({e})")),
+    }
+  }
+
+  fn summary_text(&self) -> String {
+    self
+      .summary
+      .iter()
+      .map(|(key, value)| format!("{key}: {value}\n"))
+      .collect()
+  }
+
+  /// Redacts every file and packs them into a zip archive.
+  pub fn to_zip(&self, redactor: &Redactor) -> Vec<u8> {
+    let mut zip = ZipWriter::default();
+    zip.add(
+      "report.txt",
+      redactor.apply(&self.summary_text()).as_bytes(),
+    );
+    for (name, text) in &self.files {
+      zip.add(name, redactor.apply(text).as_bytes());
+    }
+    zip.finish()
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P, redactor: &Redactor) -> Result<(), LumatoneError> {
+    let path: PathBuf = path.as_ref().to_path_buf();
+    std::fs::write(&path, self.to_zip(redactor))
+      .report()
+      .change_context(LumatoneError::ReportSaveFailed(path))
+  }
+}
+
+impl Default for ErrorReport {
+  fn default() -> Self {
+    ErrorReport::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::bundle::read_zip;
+
+  #[test]
+  fn test_report_is_redacted() {
+    let mut redactor = Redactor::new();
+    redactor.add("/home/alice", "~");
+    redactor.add_path_component("alice", "<user>");
+    redactor.add("0a0b", "<serial>");
+
+    let mut report = ErrorReport::new();
+    report.add_summary("note", "run by alice");
+    report.add_file(
+      "logs/lumatone.log",
+      "loaded /home/alice/a.ltn\nserial 0a0b\nsaved /mnt/alice\n",
+    );
+
+    let files = read_zip(&report.to_zip(&redactor)).unwrap();
+    assert_eq!(
+      files["logs/lumatone.log"],
+      b"loaded ~/a.ltn\nserial <serial>\nsaved /mnt/<user>\n"
+    );
+    let summary = String::from_utf8(files["report.txt"].clone()).unwrap();
+    assert!(summary.starts_with("lumatone version: "));
+    // the user name is only redacted in paths
+    assert!(summary.contains("note: run by alice\n"));
+    assert_eq!(redactor.apply("ab"), "ab");
+
+    let mut redactor = Redactor::new();
+    redactor.add_path_component("dev", "<user>");
+    assert_eq!(
+      redactor.apply("device at /Users/dev, C:\\Users\\dev\\x, /Users/dev.old /Users/developer"),
+      "device at /Users/<user>, C:\\Users\\<user>\\x, /Users/dev.old /Users/developer"
+    );
+  }
+}
@@ -0,0 +1,512 @@
+//! A small HTTP facade over a connected device, for scripts and web pages.
+//!
+//! | Endpoint                            | Does                                          |
+//! |-------------------------------------|-----------------------------------------------|
+//! | `GET /device`                       | Returns the serial number, firmware and boards |
+//! | `PUT /keymap`                       | Sends a keymap, as .ltn text, a compact keymap or [JsonKeymap] |
+//! | `POST /keys/{board}/{index}/color`  | Sets one key's color, e.g. `{"color": "ff8000"}` |
+//! | `GET /events`                       | Streams played notes as server-sent events    |
+//! | `PUT /locks/{resource}`             | Locks a [Resource], e.g. `/locks/key/1/5`     |
+//! | `DELETE /locks/{resource}`          | Releases a lock                               |
+//!
+//! Responses and events use the same JSON shapes as the protocol, and errors are a
+//! [ProtocolError] with a matching HTTP status. Every response closes the connection.
+//!
+//! Clients that lock resources must send the same `X-Client-Id` header with every
+//! request, so their own writes aren't blocked by their locks; locking without one is a
+//! 400. Ids are scoped to the request's access token, so clients with different tokens
+//! can't take over each other's locks, but clients sharing a token (or with no token) can
+//! by sending the same id, so treat locks as advisory between them. Locks and changes made
+//! by other clients (see [crate::coordination]) are streamed on `/events` as
+//! `coordination` events.
+//!
+//! Requests are checked against an [AccessPolicy]: `GET` endpoints need
+//! [Capability::Monitor], and the rest need [Capability::Control]. The facade speaks plain
+//! HTTP, so tokens cross the network unencrypted (see [crate::access]). Paths are logged
+//! without their query string, so `?token=` parameters don't end up in the log.
+
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+use lumatone_keymap::ltn::{KeyDefinition, LumatoneKeyMap};
+use lumatone_midi::{
+  commands::set_key_color,
+  constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, RGBColor},
+  controller::Lumatone,
+  shutdown::CancellationToken,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+  io::AsyncWriteExt,
+  net::{TcpListener, TcpStream},
+  sync::broadcast::error::RecvError,
+};
+
+use super::{
+  access::{AccessPolicy, Capability},
+  coordination::{parse_location, ClientId, Coordinator, Resource},
+  http::{read_request, reason, write_response, HttpRequest},
+  library::ContentHash,
+  protocol::{ErrorCode, Event, ProtocolError, Reply},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+  Device,
+  Keymap,
+  KeyColor(LumatoneKeyLocation),
+  Events,
+  Lock(Resource),
+  Unlock(Resource),
+}
+
+impl Route {
+  fn required_capability(&self) -> Capability {
+    match self {
+      Route::Device | Route::Events => Capability::Monitor,
+      Route::Keymap | Route::KeyColor(_) | Route::Lock(_) | Route::Unlock(_) => Capability::Control,
+    }
+  }
+
+  /// The resource the request changes, if any.
+  fn changes(&self) -> Option<Resource> {
+    match self {
+      Route::Keymap => Some(Resource::Keymap),
+      Route::KeyColor(location) => Some(Resource::Key(*location)),
+      _ => None,
+    }
+  }
+}
+
+/// Matches a request line to a [Route], or returns the HTTP status to fail with.
+fn route(method: &str, path: &str) -> std::result::Result<Route, u16> {
+  let path = path.split('?').next().unwrap_or_default();
+  let path = path.trim_matches('/');
+  if let Some(resource) = path.strip_prefix("locks/") {
+    let resource = resource.parse::<Resource>().map_err(|_| 404u16)?;
+    return match method {
+      "PUT" => Ok(Route::Lock(resource)),
+      "DELETE" => Ok(Route::Unlock(resource)),
+      _ => Err(405),
+    };
+  }
+  let segments = path.split('/').collect::<Vec<_>>();
+  let (route, allowed) = match segments.as_slice() {
+    ["device"] => (Route::Device, "GET"),
+    ["keymap"] => (Route::Keymap, "PUT"),
+    ["events"] => (Route::Events, "GET"),
+    ["keys", board, index, "color"] => {
+      let location = parse_location(board, index).ok_or(404u16)?;
+      (Route::KeyColor(location), "POST")
+    }
+    _ => return Err(404),
+  };
+  match method == allowed {
+    true => Ok(route),
+    false => Err(405),
+  }
+}
+
+/// Parses a color like `ff8000`, with or without a leading `#`.
+fn parse_color(s: &str) -> Option<RGBColor> {
+  let s = s.trim_start_matches('#');
+  if s.len() != 6 || !s.is_ascii() {
+    return None;
+  }
+  let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok();
+  Some(RGBColor(channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// A keymap in JSON, for clients that don't want to write .ltn files. Keys with a channel
+/// and note send note on/off messages; keys without are disabled.
+///
+/// ```text
+/// {"keys": [{"board": 1, "key": 0, "color": "ff0000", "channel": 1, "note": 60}]}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonKeymap {
+  pub keys: Vec<JsonKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JsonKey {
+  pub board: u8,
+  pub key: u8,
+  pub color: String,
+  pub channel: Option<u8>,
+  pub note: Option<u8>,
+}
+
+impl JsonKeymap {
+  pub fn to_keymap(&self) -> std::result::Result<LumatoneKeyMap, ProtocolError> {
+    let invalid = |msg: String| ProtocolError::new(ErrorCode::InvalidRequest, msg);
+    let mut keymap = LumatoneKeyMap::new();
+    for key in &self.keys {
+      let location = parse_location(&key.board.to_string(), &key.key.to_string())
+        .ok_or_else(|| invalid(format!("no key {} on board {}", key.key, key.board)))?;
+      let color =
+        parse_color(&key.color).ok_or_else(|| invalid(format!("invalid color {:?}", key.color)))?;
+      let function = match (key.channel, key.note) {
+        (Some(_), Some(note_num)) if note_num > 127 => {
+          return Err(invalid(format!(
+            "invalid note {note_num}, notes go from 0 to 127"
+          )))
+        }
+        (Some(channel), Some(note_num)) => LumatoneKeyFunction::NoteOnOff {
+          channel: MidiChannel::new(channel)
+            .ok_or_else(|| invalid(format!("invalid channel {channel}")))?,
+          note_num,
+        },
+        (None, None) => LumatoneKeyFunction::Disabled,
+        _ => return Err(invalid("keys need both a channel and a note".to_string())),
+      };
+      keymap.set_key(location, KeyDefinition { function, color });
+    }
+    Ok(keymap)
+  }
+}
+
+/// Accepts connections on `listener` until `shutdown` is cancelled.
+pub async fn serve(
+  listener: TcpListener,
+  lumatone: Arc<Lumatone>,
+  access: Arc<AccessPolicy>,
+  coordinator: Arc<Coordinator>,
+  shutdown: CancellationToken,
+) {
+  if let Ok(addr) = listener.local_addr() {
+    info!("REST facade listening on http://{addr}");
+  }
+  loop {
+    let (stream, addr) = tokio::select! {
+      _ = shutdown.cancelled() => break,
+      res = listener.accept() => match res {
+        Ok(conn) => conn,
+        Err(err) => {
+          warn!("unable to accept connection: {err}");
+          continue;
+        }
+      },
+    };
+    let lumatone = lumatone.clone();
+    let access = access.clone();
+    let coordinator = coordinator.clone();
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+      let result = handle_connection(stream, &lumatone, &access, &coordinator, &shutdown).await;
+      if let Err(err) = result {
+        debug!("connection from {addr} failed: {err}");
+      }
+    });
+  }
+}
+
+/// The client named by the request's `X-Client-Id` header, prefixed with a short hash of
+/// its access token so that another token can't claim the same id.
+fn client_id(request: &HttpRequest) -> Option<ClientId> {
+  let id = request.headers.get("x-client-id")?;
+  Some(match request.token() {
+    Some(token) => {
+      let hash = ContentHash::of(token.as_bytes()).to_string();
+      ClientId(format!("{}:{id}", &hash[..12]))
+    }
+    None => ClientId::from(id.as_str()),
+  })
+}
+
+async fn handle_connection(
+  mut stream: TcpStream,
+  lumatone: &Lumatone,
+  access: &AccessPolicy,
+  coordinator: &Coordinator,
+  shutdown: &CancellationToken,
+) -> std::io::Result<()> {
+  let request = match read_request(&mut stream).await? {
+    Ok(request) => request,
+    Err(status) => {
+      let err = ProtocolError::new(ErrorCode::InvalidRequest, reason(status));
+      return write_error(&mut stream, status, &err).await;
+    }
+  };
+  debug!("{} {}", request.method, request.path_without_query());
+
+  let route = match route(&request.method, &request.path) {
+    Ok(route) => route,
+    Err(status) => {
+      let err = ProtocolError::new(ErrorCode::InvalidRequest, reason(status));
+      return write_error(&mut stream, status, &err).await;
+    }
+  };
+  if let Err(denied) = access.check(request.token(), route.required_capability()) {
+    let err = ProtocolError::access_denied(denied);
+    return write_error(&mut stream, status_for(err.code), &err).await;
+  }
+
+  let client = match client_id(&request) {
+    Some(client) => client,
+    None if matches!(route, Route::Lock(_) | Route::Unlock(_)) => {
+      let err = ProtocolError::new(ErrorCode::InvalidRequest, "locks need an X-Client-Id");
+      return write_error(&mut stream, 400, &err).await;
+    }
+    None => coordinator.connect(),
+  };
+  if let Some(Err(conflict)) = route.changes().map(|r| coordinator.check_write(&client, r)) {
+    let err = ProtocolError::conflict(&conflict);
+    return write_error(&mut stream, status_for(err.code), &err).await;
+  }
+
+  let result = match route {
+    Route::Events => return stream_events(stream, lumatone, coordinator, shutdown).await,
+    Route::Device => match lumatone.device_info() {
+      Some(info) => Ok(serde_json::to_string(&Reply::device_info(info)).unwrap()),
+      None => Err(ProtocolError::new(
+        ErrorCode::DeviceError,
+        "device info hasn't been read",
+      )),
+    },
+    Route::Keymap => upload_keymap(&request, lumatone).await,
+    Route::KeyColor(location) => set_color(&request, location, lumatone).await,
+    Route::Lock(resource) => match coordinator.lock(&client, resource) {
+      Ok(_) => {
+        let lease_ms = coordinator.lease().as_millis() as u64;
+        Ok(serde_json::to_string(&Reply::Locked { lease_ms }).unwrap())
+      }
+      Err(conflict) => Err(ProtocolError::conflict(&conflict)),
+    },
+    Route::Unlock(resource) => {
+      coordinator.unlock(&client, resource);
+      Ok(serde_json::to_string(&Reply::Done {}).unwrap())
+    }
+  };
+  if let (Ok(_), Some(resource)) = (&result, route.changes()) {
+    coordinator.publish(&client, resource);
+  }
+  match result {
+    Ok(body) => write_response(&mut stream, 200, "application/json", body.as_bytes()).await,
+    Err(err) => write_error(&mut stream, status_for(err.code), &err).await,
+  }
+}
+
+async fn upload_keymap(
+  request: &HttpRequest,
+  lumatone: &Lumatone,
+) -> std::result::Result<String, ProtocolError> {
+  let invalid = |msg: String| ProtocolError::new(ErrorCode::InvalidRequest, msg);
+  let keymap = match request.is_json() {
+    true => serde_json::from_slice::<JsonKeymap>(&request.body)
+      .map_err(|e| invalid(e.to_string()))?
+      .to_keymap()?,
+    false => LumatoneKeyMap::from_bytes(&request.body).map_err(|e| invalid(format!("{e:?}")))?,
+  };
+  let commands = keymap.to_midi_commands();
+  let sent = commands.len();
+  lumatone
+    .upload(commands)
+    .await
+    .map_err(|e| ProtocolError::new(ErrorCode::DeviceError, e.current_context().to_string()))?;
+  Ok(format!(r#"{{"sent":{sent}}}"#))
+}
+
+async fn set_color(
+  request: &HttpRequest,
+  location: LumatoneKeyLocation,
+  lumatone: &Lumatone,
+) -> std::result::Result<String, ProtocolError> {
+  #[derive(Deserialize)]
+  struct ColorBody {
+    color: String,
+  }
+
+  let invalid = |msg: String| ProtocolError::new(ErrorCode::InvalidRequest, msg);
+  let body: ColorBody =
+    serde_json::from_slice(&request.body).map_err(|e| invalid(e.to_string()))?;
+  let color =
+    parse_color(&body.color).ok_or_else(|| invalid(format!("invalid color {:?}", body.color)))?;
+  lumatone
+    .send(set_key_color(location, color))
+    .await
+    .map_err(|e| ProtocolError::new(ErrorCode::DeviceError, e.current_context().to_string()))?;
+  Ok(serde_json::to_string(&Reply::Done {}).unwrap())
+}
+
+/// Sends each note played on the device, and each lock and change made through
+/// `coordinator`, as a server-sent event, until the client disconnects or `shutdown` is
+/// cancelled.
+async fn stream_events(
+  mut stream: TcpStream,
+  lumatone: &Lumatone,
+  coordinator: &Coordinator,
+  shutdown: &CancellationToken,
+) -> std::io::Result<()> {
+  let mut changes = coordinator.subscribe();
+  let mut events = match lumatone.driver().subscribe_events() {
+    Ok(events) => events,
+    Err(err) => {
+      let err = ProtocolError::new(ErrorCode::DeviceError, err.current_context().to_string());
+      return write_error(&mut stream, 503, &err).await;
+    }
+  };
+  stream
+    .write_all(
+      b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+    )
+    .await?;
+
+  loop {
+    let frame = tokio::select! {
+      _ = shutdown.cancelled() => return Ok(()),
+      res = events.recv() => match res {
+        Ok(msg) => match Event::from_channel_message(&msg) {
+          Some((_, event)) => format!("data: {}\n\n", serde_json::to_string(&event).unwrap()),
+          None => continue,
+        },
+        Err(RecvError::Lagged(n)) => {
+          lumatone.driver().count_missed(n);
+          format!(": missed {n} events\n\n")
+        }
+        Err(RecvError::Closed) => return Ok(()),
+      },
+      res = changes.recv() => match res {
+        Ok(change) => format!(
+          "event: coordination\ndata: {}\n\n",
+          serde_json::to_string(&Event::from_coordination(&change)).unwrap()
+        ),
+        Err(RecvError::Lagged(n)) => {
+          lumatone.driver().count_missed(n);
+          format!(": missed {n} events\n\n")
+        }
+        Err(RecvError::Closed) => return Ok(()),
+      },
+    };
+    stream.write_all(frame.as_bytes()).await?;
+  }
+}
+
+fn status_for(code: ErrorCode) -> u16 {
+  match code {
+    ErrorCode::DeviceError => 502,
+    ErrorCode::ActionFailed | ErrorCode::Conflict => 409,
+    ErrorCode::Unauthenticated => 401,
+    ErrorCode::Forbidden => 403,
+    _ => 400,
+  }
+}
+
+async fn write_error(
+  stream: &mut TcpStream,
+  status: u16,
+  err: &ProtocolError,
+) -> std::io::Result<()> {
+  let body = serde_json::to_string(err).unwrap();
+  write_response(stream, status, "application/json", body.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::key_loc_unchecked;
+  use std::collections::HashMap;
+
+  #[test]
+  fn test_routes_and_json_keymaps() {
+    assert_eq!(route("GET", "/device"), Ok(Route::Device));
+    assert_eq!(route("PUT", "/keymap/"), Ok(Route::Keymap));
+    assert_eq!(route("GET", "/events?since=0"), Ok(Route::Events));
+    assert_eq!(
+      route("POST", "/keys/2/55/color"),
+      Ok(Route::KeyColor(key_loc_unchecked(2, 55)))
+    );
+    assert_eq!(route("POST", "/keys/0/1/color"), Err(404));
+    assert_eq!(route("POST", "/keys/1/56/color"), Err(404));
+    assert_eq!(route("GET", "/keymap"), Err(405));
+    assert_eq!(route("GET", "/nope"), Err(404));
+    assert_eq!(
+      route("PUT", "/locks/key/1/5"),
+      Ok(Route::Lock(Resource::Key(key_loc_unchecked(1, 5))))
+    );
+    assert_eq!(
+      route("DELETE", "/locks/keymap"),
+      Ok(Route::Unlock(Resource::Keymap))
+    );
+    assert_eq!(route("PUT", "/locks/everything"), Err(404));
+
+    assert_eq!(parse_color("#ff8000"), Some(RGBColor(0xff, 0x80, 0)));
+    assert_eq!(parse_color("ff80"), None);
+
+    let json: JsonKeymap = serde_json::from_str(
+      r#"{"keys": [
+        {"board": 1, "key": 0, "color": "ff0000", "channel": 1, "note": 60},
+        {"board": 1, "key": 1, "color": "000000"}
+      ]}"#,
+    )
+    .unwrap();
+    let keymap = json.to_keymap().unwrap();
+    let key = keymap.get_key(key_loc_unchecked(1, 0)).unwrap();
+    assert_eq!(key.color, RGBColor::red());
+    assert_eq!(
+      key.function,
+      LumatoneKeyFunction::NoteOnOff {
+        channel: MidiChannel::unchecked(1),
+        note_num: 60
+      }
+    );
+    assert_eq!(
+      keymap.get_key(key_loc_unchecked(1, 1)).unwrap().function,
+      LumatoneKeyFunction::Disabled
+    );
+
+    let bad = JsonKeymap {
+      keys: vec![JsonKey {
+        board: 1,
+        key: 0,
+        color: "red".to_string(),
+        channel: None,
+        note: None,
+      }],
+    };
+    assert_eq!(bad.to_keymap().unwrap_err().code, ErrorCode::InvalidRequest);
+
+    let high = JsonKeymap {
+      keys: vec![JsonKey {
+        board: 1,
+        key: 0,
+        color: "ff0000".to_string(),
+        channel: Some(1),
+        note: Some(128),
+      }],
+    };
+    assert!(high
+      .to_keymap()
+      .unwrap_err()
+      .message
+      .contains("invalid note 128"));
+  }
+
+  #[test]
+  fn test_client_ids_are_scoped_by_token() {
+    let request = |headers: &[(&str, &str)]| HttpRequest {
+      method: "PUT".to_string(),
+      path: "/locks/keymap".to_string(),
+      headers: headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<HashMap<_, _>>(),
+      body: vec![],
+    };
+    assert_eq!(client_id(&request(&[])), None);
+    assert_eq!(
+      client_id(&request(&[("x-client-id", "a")])),
+      Some(ClientId::from("a"))
+    );
+    let alice = client_id(&request(&[
+      ("x-client-id", "a"),
+      ("authorization", "Bearer alice"),
+    ]));
+    let bob = client_id(&request(&[
+      ("x-client-id", "a"),
+      ("authorization", "Bearer bob"),
+    ]));
+    assert_ne!(alice, bob);
+    assert_ne!(alice, Some(ClientId::from("a")));
+  }
+}
@@ -0,0 +1,109 @@
+//! Retunes the Lumatone while it's played, following MIDI Tuning Standard messages from a
+//! tuning source such as an MTS-ESP bridge (see [lumatone_keymap::retune]).
+//!
+//! [connect_tuning_input] listens for single note tuning changes on a MIDI input port, and
+//! [run_retune] applies each one with a [RetuneClient]: keys whose color or function
+//! changed are uploaded to the device, and the new pitch bends go to the synth through the
+//! note proxy, which has to be running for the synth to follow the tuning.
+
+use log::{debug, info, warn};
+use lumatone_keymap::retune::{RetuneClient, Retuned, TuningUpdate};
+use lumatone_midi::{controller::Lumatone, device::get_port_by_name, shutdown::CancellationToken};
+use midir::{MidiInput, MidiInputConnection};
+use tokio::sync::mpsc;
+
+use super::error::LumatoneError;
+
+use error_stack::{report, IntoReport, Result, ResultExt};
+
+/// Listens for MTS single note tuning changes on the MIDI input port named `port_name`,
+/// and sends them on the returned channel. Other messages are ignored. The connection stays
+/// open until it's dropped.
+pub fn connect_tuning_input(
+  port_name: &str,
+) -> Result<(MidiInputConnection<()>, mpsc::Receiver<TuningUpdate>), LumatoneError> {
+  let input = MidiInput::new("lumatone-rs tuning")
+    .report()
+    .change_context(LumatoneError::DeviceError)?;
+  let port = get_port_by_name(&input, port_name).change_context(LumatoneError::DeviceError)?;
+
+  let (tx, rx) = mpsc::channel(16);
+  let conn = input
+    .connect(
+      &port,
+      port_name,
+      move |_, msg, _| {
+        if msg.first() != Some(&0xf0) {
+          return;
+        }
+        match TuningUpdate::from_mts_sysex(msg) {
+          Ok(update) => {
+            if let Err(err) = tx.try_send(update) {
+              warn!("unable to queue tuning change: {err}");
+            }
+          }
+          Err(err) => debug!("ignoring sysex from tuning source: {err}"),
+        }
+      },
+      (),
+    )
+    .map_err(|e| {
+      report!(LumatoneError::DeviceError)
+        .attach_printable(format!("midi input connection error: {e}"))
+    })?;
+  Ok((conn, rx))
+}
+
+/// Sends the result of a tuning update: the changed keys to the device, and the pitch
+/// bends to the note proxy, if it's running.
+pub async fn send_retuned(lumatone: &Lumatone, retuned: Retuned) -> Result<(), LumatoneError> {
+  if retuned.reanchored {
+    info!("tuning root moved, re-anchoring the layout");
+  }
+  if !retuned.commands.is_empty() {
+    lumatone
+      .upload(retuned.commands)
+      .await
+      .change_context(LumatoneError::DeviceError)?;
+  }
+  if !retuned.bends.is_valid() {
+    warn!(
+      "{} keys can't be played in tune with the synth's bend range",
+      retuned.bends.problems.len()
+    );
+  }
+  match lumatone.proxy() {
+    Some(proxy) => proxy
+      .send_messages(retuned.bends.messages())
+      .await
+      .change_context(LumatoneError::DeviceError),
+    None => Err(report!(LumatoneError::ProxyNotRunning)),
+  }
+}
+
+/// Applies tuning updates from `updates` with `client` until `stop` is cancelled or the
+/// tuning source goes away. Starts by sending the client's current tuning, so the synth's
+/// bends match the keymap before the first update. Returns how many updates were applied.
+pub async fn run_retune(
+  lumatone: &Lumatone,
+  client: &mut RetuneClient,
+  updates: &mut mpsc::Receiver<TuningUpdate>,
+  stop: &CancellationToken,
+) -> Result<usize, LumatoneError> {
+  let current = TuningUpdate::Tuning(client.tuning().clone());
+  send_retuned(lumatone, client.apply(current)).await?;
+
+  let mut applied = 0;
+  loop {
+    let update = tokio::select! {
+      _ = stop.cancelled() => break,
+      update = updates.recv() => match update {
+        Some(update) => update,
+        None => break,
+      },
+    };
+    send_retuned(lumatone, client.apply(update)).await?;
+    applied += 1;
+  }
+  Ok(applied)
+}
@@ -0,0 +1,674 @@
+//! Scenes bundle everything needed to switch the Lumatone (and the synth behind it) from one
+//! song or section to another: a keymap, a lighting mode, the note proxy's mapping and
+//! output routing, and program changes and pitch bends for the synth. Static pitch bends
+//! let a 12-TET synth play a microtonal layout (see [lumatone_keymap::pitch_bend]). Scenes
+//! can also send arbitrary messages when they're applied (see [SceneMessage]), e.g. CC
+//! snapshots or sysex patch changes for external gear, to reconfigure the whole rig.
+//!
+//! A scene can also have a theme: a [keymap patch](lumatone_keymap::patch), e.g. a color
+//! scheme, layered over its keymap.
+//!
+//! A scene's keymap can be revealed a few keys at a time instead of all at once (see
+//! [Scene::apply_with_reveal]), e.g. as a set list's connect animation.
+//!
+//! Or it can [crossfade](Scene::apply_with_crossfade) from the outgoing scene's lighting: the
+//! key colors blend from what the device is showing to the new keymap's colors over the
+//! fade, while the note proxy's mapping switches at a chosen point, e.g. on the downbeat of
+//! the next section, and the key functions that differ follow straight after.
+//!
+//! A [SceneList] holds scenes in order and steps through them. Assign a macro button (or
+//! any key) to send a CC or note, and use that as a [SceneTrigger] to move to the next or
+//! previous scene without touching the computer.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use log::{info, warn};
+use lumatone_keymap::{
+  gradient::Gradient, ltn::LumatoneKeyMap, patch::KeymapPatch, pitch_bend::BendPlan, reveal::Reveal,
+};
+use lumatone_midi::{
+  clock::Clock,
+  commands::Command,
+  constants::{LumatoneKeyLocation, MidiChannel, RGBColor},
+  controller::Lumatone,
+  driver::MidiDriver,
+  events::{ChannelMessage, PEDAL_DOWN_THRESHOLD},
+  proxy::NoteMapping,
+  routing::RoutingMatrix,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+use super::error::LumatoneError;
+
+use error_stack::{report, Result, ResultExt};
+
+/// The pause between the frames of a [Reveal].
+pub const REVEAL_FRAME_INTERVAL: Duration = Duration::from_millis(40);
+
+/// How often a [Crossfade] moves to its next blend. A quarter of a beat at 120 BPM, so fades
+/// measured in beats line up with their frames.
+pub const CROSSFADE_FRAME: Duration = Duration::from_millis(125);
+
+/// How a scene's lighting fades in over the outgoing scene's (see
+/// [Scene::apply_with_crossfade]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crossfade {
+  /// How long the key colors take to blend into the incoming scene's.
+  pub duration: Duration,
+
+  /// When, from the start of the fade, the note proxy's mapping switches to the incoming
+  /// scene's, followed by the key functions. Clamped to the end of the fade.
+  pub switch_at: Duration,
+}
+
+impl Crossfade {
+  /// A fade that switches the mapping halfway through.
+  pub fn new(duration: Duration) -> Self {
+    Crossfade {
+      duration,
+      switch_at: duration / 2,
+    }
+  }
+
+  /// A fade `beats` long at `bpm`, switching the mapping on beat `switch_beat` (counting
+  /// from 0 at the start of the fade).
+  pub fn on_beat(bpm: f64, beats: u32, switch_beat: u32) -> Self {
+    let beat = Duration::from_secs_f64(60.0 / bpm.max(1.0));
+    Crossfade {
+      duration: beat * beats,
+      switch_at: beat * switch_beat,
+    }
+  }
+
+  /// The number of [CROSSFADE_FRAME]s the fade takes, at least one.
+  pub fn frame_count(&self) -> usize {
+    ((self.duration.as_millis() / CROSSFADE_FRAME.as_millis()) as usize).max(1)
+  }
+
+  /// Returns the key color commands to send in each frame, blending from the colors set by
+  /// `outgoing` to the colors set by `incoming`. Keys that `outgoing` doesn't set start from
+  /// black, and keys whose color doesn't change are only sent in the last frame, which sets
+  /// every key to its `incoming` color. The blend moves the same amount every frame.
+  pub fn frames(&self, outgoing: &[Command], incoming: &[Command]) -> Vec<Vec<Command>> {
+    let from: HashMap<LumatoneKeyLocation, RGBColor> = outgoing
+      .iter()
+      .filter_map(|command| match command {
+        Command::SetKeyColor { location, color } => Some((*location, *color)),
+        _ => None,
+      })
+      .collect();
+    let targets: Vec<(LumatoneKeyLocation, Gradient)> = incoming
+      .iter()
+      .filter_map(|command| match command {
+        Command::SetKeyColor { location, color } => {
+          let start = from.get(location).copied().unwrap_or(RGBColor(0, 0, 0));
+          Some((*location, Gradient::new(vec![start, *color])))
+        }
+        _ => None,
+      })
+      .collect();
+
+    let count = self.frame_count();
+    let mut frames: Vec<Vec<Command>> = (1..count)
+      .map(|i| {
+        let level = i as f32 / count as f32;
+        targets
+          .iter()
+          .filter(|(_, gradient)| gradient.stops()[0] != gradient.stops()[1])
+          .map(|(location, gradient)| Command::SetKeyColor {
+            location: *location,
+            color: gradient.at(level),
+          })
+          .collect()
+      })
+      .collect();
+    frames.push(
+      incoming
+        .iter()
+        .filter(|command| matches!(command, Command::SetKeyColor { .. }))
+        .cloned()
+        .collect(),
+    );
+    frames
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightingMode {
+  /// Keys always show their keymap colors.
+  Static,
+
+  /// Keys light up when they're pressed.
+  OnKeystrokes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramChange {
+  pub channel: MidiChannel,
+  pub program: u8,
+}
+
+impl From<ProgramChange> for ChannelMessage {
+  fn from(pc: ProgramChange) -> Self {
+    ChannelMessage::ProgramChange {
+      channel: pc.channel,
+      program: pc.program,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PitchBend {
+  pub channel: MidiChannel,
+  /// 14-bit value from 0 to 16383, with 8192 as the center position.
+  pub value: u16,
+}
+
+impl From<PitchBend> for ChannelMessage {
+  fn from(pb: PitchBend) -> Self {
+    ChannelMessage::PitchBend {
+      channel: pb.channel,
+      value: pb.value,
+    }
+  }
+}
+
+/// A message sent to the note proxy's outputs when a scene is applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SceneMessage {
+  /// Sent through the proxy's routing matrix, like the notes it plays.
+  Channel(ChannelMessage),
+
+  /// A system exclusive message, sent to the named output (see
+  /// [MultiOutput](lumatone_midi::proxy::MultiOutput)), or to every output if `None`.
+  Sysex {
+    output: Option<String>,
+    message: Vec<u8>,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scene {
+  pub name: String,
+
+  /// A .ltn preset to send when the scene is applied. If `None`, the keys are left as-is.
+  pub keymap: Option<PathBuf>,
+
+  /// A keymap patch saved as an ini file, applied over the keymap before it's sent, whatever
+  /// the keymap had in place of the patch's changes. Ignored without a keymap.
+  pub theme: Option<PathBuf>,
+
+  /// If `None`, the lighting mode is left as-is.
+  pub lighting: Option<LightingMode>,
+
+  /// The note proxy's mapping (transpose, output channel) while the scene is active.
+  pub mapping: NoteMapping,
+
+  /// Which of the note proxy's outputs each zone is sent to. If `None`, the routing is left
+  /// as-is.
+  pub routing: Option<RoutingMatrix>,
+
+  /// Sent to the note proxy's output when the scene is applied.
+  pub program_changes: Vec<ProgramChange>,
+
+  /// Sent to the note proxy's output after the program changes.
+  pub pitch_bends: Vec<PitchBend>,
+
+  /// Sent after the pitch bends, in order.
+  pub on_activate: Vec<SceneMessage>,
+
+  /// If set, a [SetListPlayer](crate::setlist::SetListPlayer) moves on to the next scene
+  /// automatically after this long.
+  pub advance_after: Option<Duration>,
+}
+
+impl Scene {
+  pub fn new<S: Into<String>>(name: S) -> Self {
+    Scene {
+      name: name.into(),
+      keymap: None,
+      theme: None,
+      lighting: None,
+      mapping: NoteMapping::default(),
+      routing: None,
+      program_changes: vec![],
+      pitch_bends: vec![],
+      on_activate: vec![],
+      advance_after: None,
+    }
+  }
+
+  /// Replaces the scene's pitch bends with the bends from `plan`.
+  pub fn set_pitch_bends(&mut self, plan: &BendPlan) {
+    self.pitch_bends = plan
+      .messages()
+      .into_iter()
+      .filter_map(|msg| match msg {
+        ChannelMessage::PitchBend { channel, value } => Some(PitchBend { channel, value }),
+        _ => None,
+      })
+      .collect();
+  }
+
+  /// Returns the commands that configure the device for this scene, loading the keymap
+  /// and theme files if there are any.
+  pub fn device_commands(&self) -> Result<Vec<Command>, LumatoneError> {
+    let mut commands = vec![];
+    if let Some(path) = &self.keymap {
+      let mut keymap = load_keymap(path)?;
+      if let Some(theme) = &self.theme {
+        load_theme(theme)?.apply_unchecked(&mut keymap);
+      }
+      commands.extend(keymap.to_midi_commands());
+    }
+    if let Some(lighting) = self.lighting {
+      commands.push(Command::SetLightOnKeystrokes(
+        lighting == LightingMode::OnKeystrokes,
+      ));
+    }
+    Ok(commands)
+  }
+
+  /// Configures the device, and the note proxy if it's running, for this scene. The device
+  /// commands go out as one [upload](Lumatone::upload).
+  pub async fn apply(&self, lumatone: &Lumatone) -> Result<(), LumatoneError> {
+    self.apply_with_reveal(lumatone, None).await
+  }
+
+  /// Like [Scene::apply], but if `reveal` is given, the keymap's colors are sent in its
+  /// frames, [REVEAL_FRAME_INTERVAL] apart.
+  pub async fn apply_with_reveal(
+    &self,
+    lumatone: &Lumatone,
+    reveal: Option<&Reveal>,
+  ) -> Result<(), LumatoneError> {
+    let failed = || LumatoneError::SceneApplyFailed(self.name.clone());
+    info!("applying scene {}", self.name);
+
+    let commands = self.device_commands().change_context_lazy(failed)?;
+    let frames = match reveal {
+      Some(reveal) => reveal.frames(commands),
+      None => {
+        lumatone
+          .upload(commands)
+          .await
+          .change_context_lazy(failed)?;
+        return self.apply_to_proxy(lumatone).await;
+      }
+    };
+    let clock = lumatone.driver().clock();
+    for (i, frame) in frames.into_iter().enumerate() {
+      if i > 0 {
+        clock.sleep(REVEAL_FRAME_INTERVAL).await;
+      }
+      for command in frame {
+        lumatone.send(command).await.change_context_lazy(failed)?;
+      }
+    }
+
+    self.apply_to_proxy(lumatone).await
+  }
+
+  /// Like [Scene::apply], but crossfades the key colors from what the device is showing (as
+  /// recorded by its [mirror](Lumatone::mirrored_config)) to the scene's keymap, sending a
+  /// frame every [CROSSFADE_FRAME].
+  ///
+  /// The note proxy switches to the scene at [switch_at](Crossfade::switch_at), and the key
+  /// functions and settings that differ from the mirror are uploaded straight after, while
+  /// the fade goes on. Only the changes are sent, which for scenes that share a layout is
+  /// far fewer than the whole keymap.
+  ///
+  /// A key whose color the device hasn't been sent yet when the next frame comes picks up
+  /// the newer blend instead, and the last frame is sent like [Scene::apply], so the board
+  /// always ends up with the scene's colors.
+  pub async fn apply_with_crossfade(
+    &self,
+    lumatone: &Lumatone,
+    fade: &Crossfade,
+  ) -> Result<(), LumatoneError> {
+    let failed = || LumatoneError::SceneApplyFailed(self.name.clone());
+    info!("crossfading to scene {}", self.name);
+
+    let (colors, others): (Vec<Command>, Vec<Command>) = self
+      .device_commands()
+      .change_context_lazy(failed)?
+      .into_iter()
+      .partition(|command| matches!(command, Command::SetKeyColor { .. }));
+    let mirrored = lumatone.mirrored_config();
+    let mut frames = fade.frames(&mirrored, &colors);
+    let last = frames.pop().unwrap_or_default();
+    let changes = others
+      .into_iter()
+      .filter(|command| !mirrored.contains(command))
+      .collect();
+
+    let driver = lumatone.driver();
+    let clock = driver.clock();
+    let switch = async {
+      clock.sleep(fade.switch_at.min(fade.duration)).await;
+      self.switch(lumatone, changes).await
+    };
+    let (faded, switched) = tokio::join!(self.fade(&driver, frames), switch);
+    faded?;
+    switched?;
+    lumatone.upload(last).await.change_context_lazy(failed)?;
+    Ok(())
+  }
+
+  /// Sends a crossfade's frames, one every [CROSSFADE_FRAME], and waits out the last
+  /// frame.
+  async fn fade(
+    &self,
+    driver: &MidiDriver,
+    frames: Vec<Vec<Command>>,
+  ) -> Result<(), LumatoneError> {
+    let failed = || LumatoneError::SceneApplyFailed(self.name.clone());
+    let clock = driver.clock();
+    let mut ticker = clock.ticker(CROSSFADE_FRAME);
+    for frame in frames {
+      ticker.tick().await;
+      for command in frame {
+        driver
+          .send_and_forget_coalesced(command)
+          .await
+          .change_context_lazy(failed)?;
+      }
+    }
+    ticker.tick().await;
+    Ok(())
+  }
+
+  /// Switches the note proxy to the scene, then uploads `changes`, the non-lighting half of
+  /// a crossfade. The proxy goes first, since it switches at once and the keys can't.
+  async fn switch(&self, lumatone: &Lumatone, changes: Vec<Command>) -> Result<(), LumatoneError> {
+    let failed = || LumatoneError::SceneApplyFailed(self.name.clone());
+    self.apply_to_proxy(lumatone).await?;
+    lumatone.upload(changes).await.change_context_lazy(failed)?;
+    Ok(())
+  }
+
+  /// Sets the note proxy's mapping and routing for this scene, and sends the scene's
+  /// messages through it.
+  async fn apply_to_proxy(&self, lumatone: &Lumatone) -> Result<(), LumatoneError> {
+    let failed = || LumatoneError::SceneApplyFailed(self.name.clone());
+    match lumatone.proxy() {
+      Some(proxy) => {
+        proxy
+          .set_mapping(self.mapping)
+          .await
+          .change_context_lazy(failed)?;
+        if let Some(routing) = &self.routing {
+          proxy
+            .set_routing(Some(routing.clone()))
+            .await
+            .change_context_lazy(failed)?;
+        }
+        let messages = self
+          .program_changes
+          .iter()
+          .map(|pc| (*pc).into())
+          .chain(self.pitch_bends.iter().map(|pb| (*pb).into()))
+          .collect();
+        proxy
+          .send_messages(messages)
+          .await
+          .change_context_lazy(failed)?;
+        for msg in &self.on_activate {
+          match msg {
+            SceneMessage::Channel(msg) => proxy.send_messages(vec![*msg]).await,
+            SceneMessage::Sysex { output, message } => {
+              proxy.send_sysex(output.clone(), message.clone()).await
+            }
+          }
+          .change_context_lazy(failed)?;
+        }
+      }
+      None
+        if !self.program_changes.is_empty()
+          || !self.pitch_bends.is_empty()
+          || !self.on_activate.is_empty()
+          || self.mapping != NoteMapping::default()
+          || self.routing.is_some() =>
+      {
+        warn!(
+          "note proxy isn't running, so scene {} can't set the mapping, routing, or send messages",
+          self.name
+        );
+      }
+      None => {}
+    }
+    Ok(())
+  }
+}
+
+/// Loads the preset at `path`, a .ltn file or a compact keymap, and returns the commands
+/// that send it to the device.
+pub fn load_keymap_commands(path: &Path) -> Result<Vec<Command>, LumatoneError> {
+  Ok(load_keymap(path)?.to_midi_commands())
+}
+
+/// Loads the preset at `path`, a .ltn file or a compact keymap.
+pub fn load_keymap(path: &Path) -> Result<LumatoneKeyMap, LumatoneError> {
+  let failed = || LumatoneError::KeymapLoadFailed(path.to_path_buf());
+  let contents = std::fs::read(path).map_err(|e| report!(failed()).attach_printable(e))?;
+  LumatoneKeyMap::from_bytes(&contents)
+    .map_err(|e| report!(failed()).attach_printable(format!("{e:?}")))
+}
+
+/// Loads the keymap patch at `path`, for a scene's [theme](Scene::theme).
+pub fn load_theme(path: &Path) -> Result<KeymapPatch, LumatoneError> {
+  let failed = || LumatoneError::ThemeLoadFailed(path.to_path_buf());
+  let contents =
+    std::fs::read_to_string(path).map_err(|e| report!(failed()).attach_printable(e))?;
+  KeymapPatch::from_ini_str(contents)
+    .map_err(|e| report!(failed()).attach_printable(format!("{e:?}")))
+}
+
+/// A key or button that moves through a [SceneList] when it's pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneTrigger {
+  /// Fires on a note on with non-zero velocity.
+  Note { channel: MidiChannel, note: u8 },
+
+  /// Fires when the controller value goes to [PEDAL_DOWN_THRESHOLD] or above.
+  Control {
+    channel: MidiChannel,
+    controller: u8,
+  },
+}
+
+impl SceneTrigger {
+  pub fn matches(&self, msg: &ChannelMessage) -> bool {
+    match (*self, *msg) {
+      (
+        SceneTrigger::Note { channel, note },
+        ChannelMessage::NoteOn {
+          channel: c,
+          note: n,
+          velocity,
+        },
+      ) => channel == c && note == n && velocity > 0,
+
+      (
+        SceneTrigger::Control {
+          channel,
+          controller,
+        },
+        ChannelMessage::ControlChange {
+          channel: c,
+          controller: cc,
+          value,
+        },
+      ) => channel == c && controller == cc && value >= PEDAL_DOWN_THRESHOLD,
+
+      _ => false,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SceneList {
+  scenes: Vec<Scene>,
+  current: usize,
+  pub next_trigger: Option<SceneTrigger>,
+  pub previous_trigger: Option<SceneTrigger>,
+}
+
+impl SceneList {
+  pub fn new(scenes: Vec<Scene>) -> Self {
+    SceneList {
+      scenes,
+      ..Default::default()
+    }
+  }
+
+  pub fn scenes(&self) -> &[Scene] {
+    &self.scenes
+  }
+
+  pub fn current_index(&self) -> usize {
+    self.current
+  }
+
+  pub fn current(&self) -> Option<&Scene> {
+    self.scenes.get(self.current)
+  }
+
+  /// Makes the scene at `index` current and returns it, or returns `None` if it's out of range.
+  pub fn select(&mut self, index: usize) -> Option<&Scene> {
+    if index >= self.scenes.len() {
+      return None;
+    }
+    self.current = index;
+    self.current()
+  }
+
+  /// Moves to the next scene and returns it. Returns `None` at the end of the list.
+  #[allow(clippy::should_implement_trait)]
+  pub fn next(&mut self) -> Option<&Scene> {
+    self.select(self.current + 1)
+  }
+
+  /// Moves to the previous scene and returns it. Returns `None` at the start of the list.
+  pub fn previous(&mut self) -> Option<&Scene> {
+    let index = self.current.checked_sub(1)?;
+    self.select(index)
+  }
+
+  /// Moves to the next or previous scene if `msg` matches one of the triggers, returning
+  /// the new scene.
+  pub fn handle_message(&mut self, msg: &ChannelMessage) -> Option<&Scene> {
+    if self.next_trigger.is_some_and(|t| t.matches(msg)) {
+      return self.next();
+    }
+    if self.previous_trigger.is_some_and(|t| t.matches(msg)) {
+      return self.previous();
+    }
+    None
+  }
+
+  /// Applies a scene each time a trigger is played on the device, until the device's event
+  /// stream closes or the [Lumatone] shuts down. Doesn't apply the current scene first.
+  pub async fn follow_triggers(&mut self, lumatone: &Lumatone) -> Result<(), LumatoneError> {
+    let mut events = lumatone
+      .driver()
+      .subscribe_events()
+      .change_context(LumatoneError::DeviceError)?;
+    let shutdown = lumatone.shutdown_token();
+
+    loop {
+      let msg = tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+        res = events.recv() => match res {
+          Ok(msg) => msg,
+          Err(RecvError::Lagged(n)) => {
+            warn!("scene list missed {n} events");
+            lumatone.driver().count_missed(n);
+            continue;
+          }
+          Err(RecvError::Closed) => return Ok(()),
+        },
+      };
+
+      if let Some(scene) = self.handle_message(&msg) {
+        if let Err(err) = scene.apply(lumatone).await {
+          warn!("{err:?}");
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_scene_list_navigation() {
+    let ch = MidiChannel::default();
+    let mut list = SceneList::new(vec![Scene::new("intro"), Scene::new("verse")]);
+    list.next_trigger = Some(SceneTrigger::Control {
+      channel: ch,
+      controller: 20,
+    });
+    list.previous_trigger = Some(SceneTrigger::Note {
+      channel: ch,
+      note: 10,
+    });
+
+    let cc = |value| ChannelMessage::ControlChange {
+      channel: ch,
+      controller: 20,
+      value,
+    };
+    assert_eq!(list.current().unwrap().name, "intro");
+    assert!(list.handle_message(&cc(0)).is_none());
+    assert_eq!(list.handle_message(&cc(127)).unwrap().name, "verse");
+    assert!(list.handle_message(&cc(127)).is_none());
+    assert_eq!(list.current_index(), 1);
+
+    let note_on = ChannelMessage::NoteOn {
+      channel: ch,
+      note: 10,
+      velocity: 100,
+    };
+    assert_eq!(list.handle_message(&note_on).unwrap().name, "intro");
+    assert!(list.previous().is_none());
+    assert!(list.select(5).is_none());
+  }
+
+  #[test]
+  fn test_crossfade_frames() {
+    use lumatone_midi::{commands::set_key_color, constants::key_loc_unchecked};
+
+    let (a, b) = (key_loc_unchecked(1, 0), key_loc_unchecked(1, 1));
+    let outgoing = [
+      set_key_color(a, RGBColor(0, 0, 0)),
+      set_key_color(b, RGBColor::green()),
+    ];
+    let incoming = [
+      set_key_color(a, RGBColor(255, 255, 255)),
+      set_key_color(b, RGBColor::green()),
+    ];
+    let fade = Crossfade::on_beat(120.0, 2, 1);
+    assert_eq!(fade.frame_count(), 8);
+    assert_eq!(fade.switch_at, Duration::from_millis(500));
+
+    let frames = fade.frames(&outgoing, &incoming);
+    assert_eq!(frames.len(), 8);
+    // the unchanged key is only sent at the end
+    assert!(frames[..7].iter().all(|frame| frame.len() == 1));
+    assert_eq!(frames[7], incoming.to_vec());
+
+    let brightness = |frame: &Vec<Command>| match frame[0] {
+      Command::SetKeyColor { color, .. } => color.0,
+      _ => unreachable!(),
+    };
+    assert!(brightness(&frames[0]) < brightness(&frames[1]));
+    assert!(brightness(&frames[1]) < brightness(&frames[2]));
+  }
+}
@@ -0,0 +1,183 @@
+//! Sends a whole keymap to the device, reporting progress as it goes.
+//!
+//! A full keymap is around 560 commands (a function and a color for each of the 280 keys),
+//! plus the global settings and tables, and takes a while to send. [apply] calls back after
+//! each command with an [ApplyProgress], e.g. to draw a progress bar:
+//!
+//! ```ignore
+//! let report = apply(&keymap, &lumatone.driver(), |progress| {
+//!   eprint!("\r{progress}");
+//! })
+//! .await?;
+//! ```
+//!
+//! A command that fails doesn't stop the rest from being sent; it's listed in
+//! [ApplyProgress::failures] instead. Losing the connection does stop it, since nothing
+//! after that would get through either.
+//!
+//! Commands go straight to the driver, so they aren't [mirrored](lumatone_midi::mirror) by
+//! a [Lumatone](lumatone_midi::controller::Lumatone) that shares it.
+
+use std::{collections::HashMap, fmt::Display};
+
+use log::warn;
+use lumatone_keymap::ltn::LumatoneKeyMap;
+use lumatone_midi::{
+  commands::Command, constants::LumatoneKeyLocation, driver::MidiDriver, error::LumatoneMidiError,
+};
+
+use super::error::LumatoneError;
+
+use error_stack::{report, Result};
+
+/// How far [apply] has got.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApplyProgress {
+  /// Commands sent so far, including the ones that failed.
+  pub sent: usize,
+
+  pub total: usize,
+
+  /// Keys whose commands have all been sent.
+  pub keys_done: usize,
+
+  pub keys_total: usize,
+
+  /// Commands the device didn't accept, and why.
+  pub failures: Vec<(Command, String)>,
+}
+
+impl ApplyProgress {
+  pub fn is_complete(&self) -> bool {
+    self.sent == self.total
+  }
+
+  pub fn is_ok(&self) -> bool {
+    self.is_complete() && self.failures.is_empty()
+  }
+}
+
+impl Display for ApplyProgress {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{}/{} keys, {}/{} commands",
+      self.keys_done, self.keys_total, self.sent, self.total
+    )?;
+    if !self.failures.is_empty() {
+      write!(f, ", {} failed", self.failures.len())?;
+    }
+    Ok(())
+  }
+}
+
+/// Sends `keymap`'s commands through `driver`, calling `on_progress` after each one, and
+/// returns the final progress. See the [module docs](self).
+pub async fn apply<F>(
+  keymap: &LumatoneKeyMap,
+  driver: &MidiDriver,
+  mut on_progress: F,
+) -> Result<ApplyProgress, LumatoneError>
+where
+  F: FnMut(&ApplyProgress),
+{
+  let commands = keymap.to_midi_commands();
+  let last_for_key = last_command_for_each_key(&commands);
+  let mut progress = ApplyProgress {
+    total: commands.len(),
+    keys_total: last_for_key.len(),
+    ..Default::default()
+  };
+
+  for (i, command) in commands.into_iter().enumerate() {
+    let res = driver.send(command.clone()).await;
+    if key_location(&command).and_then(|loc| last_for_key.get(&loc)) == Some(&i) {
+      progress.keys_done += 1;
+    }
+    progress.sent += 1;
+    if let Err(err) = res {
+      match err.current_context() {
+        LumatoneMidiError::DeviceConnectionError | LumatoneMidiError::DriverFailed(_) => {
+          return Err(
+            report!(LumatoneError::DeviceError).attach_printable(format!(
+              "connection lost after {} of {} commands",
+              progress.sent - 1,
+              progress.total
+            )),
+          );
+        }
+        reason => {
+          warn!("{command} failed: {reason}");
+          progress.failures.push((command, reason.to_string()));
+        }
+      }
+    }
+    on_progress(&progress);
+  }
+  Ok(progress)
+}
+
+fn key_location(command: &Command) -> Option<LumatoneKeyLocation> {
+  match command {
+    Command::SetKeyFunction { location, .. } | Command::SetKeyColor { location, .. } => {
+      Some(*location)
+    }
+    _ => None,
+  }
+}
+
+/// The index of the last command that sets each key.
+fn last_command_for_each_key(commands: &[Command]) -> HashMap<LumatoneKeyLocation, usize> {
+  commands
+    .iter()
+    .enumerate()
+    .filter_map(|(i, command)| key_location(command).map(|loc| (loc, i)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_keymap::ltn::KeyDefinition;
+  use lumatone_midi::{
+    constants::{key_loc_unchecked, LumatoneKeyFunction, MidiChannel, RGBColor},
+    shutdown::CancellationToken,
+    testing::FakeDevice,
+  };
+
+  #[tokio::test]
+  async fn test_apply_reports_progress() {
+    let mut keymap = LumatoneKeyMap::new();
+    for key in 0..3 {
+      keymap.set_key(
+        key_loc_unchecked(1, key),
+        KeyDefinition {
+          function: LumatoneKeyFunction::NoteOnOff {
+            channel: MidiChannel::unchecked(1),
+            note_num: 60 + key,
+          },
+          color: RGBColor::green(),
+        },
+      );
+    }
+
+    let shutdown = CancellationToken::new();
+    let (driver, driver_future) = MidiDriver::with_transport(FakeDevice::new(), shutdown.clone());
+    tokio::spawn(driver_future);
+
+    let mut updates = vec![];
+    let report = apply(&keymap, &driver, |progress| updates.push(progress.clone()))
+      .await
+      .unwrap();
+    shutdown.cancel();
+
+    assert!(report.is_ok());
+    assert_eq!(report.keys_total, 3);
+    assert_eq!(report.keys_done, 3);
+    assert_eq!(updates.len(), report.total);
+    assert_eq!(updates.last(), Some(&report));
+    // keys are only done once their color has been sent too
+    let keys_done: Vec<usize> = updates.iter().map(|p| p.keys_done).collect();
+    assert_eq!(&keys_done[keys_done.len() - 6..], &[0, 1, 1, 2, 2, 3]);
+  }
+}
@@ -0,0 +1,337 @@
+//! Unattended operation as a system service, e.g. on a Raspberry Pi in a touring rack.
+//!
+//! [run_service] waits for the device to show up, gives it the configured keymap or scene,
+//! and then keeps it that way with a [reconciler](lumatone_midi::reconcile): the device is
+//! pinged every `health_interval`, and the whole configuration is sent again if it resets.
+//! If the device stays unresponsive for longer than `reconnect_after` (e.g. it was unplugged
+//! and the MIDI ports went away), the service drops the connection and goes back to waiting
+//! for it, so the rig comes back by itself after a power cycle.
+//!
+//! If [ServiceConfig::traffic_file] is set, the service saves the device's recent traffic
+//! there every `health_interval` and before it disconnects, so `lumatone report` can include
+//! it even though the report runs in another process.
+//!
+//! The service keeps a [ServiceMetrics] up to date, which outlives each connection to the
+//! device, so `lumatone service --metrics` can serve the current connection's driver
+//! metrics and how often the service reconnected (see [crate::metrics]).
+//!
+//! A systemd unit for this looks like:
+//!
+//! ```text
+//! [Unit]
+//! Description=Lumatone configuration
+//! After=sound.target
+//!
+//! [Service]
+//! ExecStart=/usr/local/bin/lumatone service --keymap /home/pi/rig.ltn
+//! Restart=on-failure
+//!
+//! [Install]
+//! WantedBy=multi-user.target
+//! ```
+
+use std::{
+  path::PathBuf,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, PoisonError,
+  },
+  time::Duration,
+};
+
+use log::{info, warn};
+use lumatone_midi::{
+  commands::Command,
+  controller::Lumatone,
+  detect::detect_device_until,
+  driver::MidiDriver,
+  metrics::MetricsSnapshot,
+  proxy::NoteMapping,
+  reconcile::{DesiredState, ReconcileEvent},
+  shutdown::CancellationToken,
+};
+use tokio::{
+  sync::broadcast::error::RecvError,
+  time::{interval, sleep, sleep_until, Instant},
+};
+
+use super::{
+  error::LumatoneError,
+  report::format_traffic,
+  scene::{load_keymap_commands, Scene},
+  setlist::SetList,
+};
+
+use error_stack::{report, Result};
+
+/// What to give the device when it connects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceTarget {
+  /// A .ltn preset.
+  Keymap(PathBuf),
+  /// The first scene with this name in a set list.
+  Scene { set_list: PathBuf, scene: String },
+}
+
+impl ServiceTarget {
+  /// Loads the commands that give the device this configuration.
+  pub fn device_commands(&self) -> Result<Vec<Command>, LumatoneError> {
+    match self {
+      ServiceTarget::Keymap(path) => load_keymap_commands(path),
+      ServiceTarget::Scene { set_list, scene } => {
+        let set_list = SetList::load(set_list)?;
+        let scene = find_scene(&set_list, scene)
+          .ok_or_else(|| report!(LumatoneError::SceneNameNotFound(scene.clone())))?;
+        if scene.mapping != NoteMapping::default()
+          || scene.routing.is_some()
+          || !scene.program_changes.is_empty()
+          || !scene.pitch_bends.is_empty()
+          || !scene.on_activate.is_empty()
+        {
+          warn!(
+            "the service doesn't run the note proxy, so scene {} only sets the keymap and lighting",
+            scene.name
+          );
+        }
+        scene.device_commands()
+      }
+    }
+  }
+}
+
+fn find_scene<'a>(set_list: &'a SetList, name: &str) -> Option<&'a Scene> {
+  set_list
+    .songs
+    .iter()
+    .flat_map(|song| song.scenes.iter())
+    .find(|scene| scene.name == name)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceConfig {
+  pub target: ServiceTarget,
+
+  /// How often to ping the device to check it's still there.
+  pub health_interval: Duration,
+
+  /// How long the device can be unresponsive before reconnecting to it.
+  pub reconnect_after: Duration,
+
+  /// How long to wait between attempts to detect the device.
+  pub detect_interval: Duration,
+
+  /// Where to save the device's recent traffic for bug reports, if anywhere.
+  pub traffic_file: Option<PathBuf>,
+}
+
+impl ServiceConfig {
+  pub fn new(target: ServiceTarget) -> Self {
+    ServiceConfig {
+      target,
+      health_interval: Duration::from_secs(5),
+      reconnect_after: Duration::from_secs(30),
+      detect_interval: Duration::from_secs(5),
+      traffic_file: None,
+    }
+  }
+}
+
+/// What the service has been doing, for the metrics endpoint.
+#[derive(Default)]
+pub struct ServiceMetrics {
+  /// The driver of the current connection, if the device is connected.
+  driver: Mutex<Option<MidiDriver>>,
+  connections: AtomicU64,
+}
+
+impl ServiceMetrics {
+  fn connected(&self, driver: MidiDriver) {
+    *self.driver.lock().unwrap_or_else(PoisonError::into_inner) = Some(driver);
+    self.connections.fetch_add(1, Ordering::Relaxed);
+  }
+
+  fn disconnected(&self) {
+    *self.driver.lock().unwrap_or_else(PoisonError::into_inner) = None;
+  }
+
+  /// The driver metrics of the current connection, or `None` while the service is waiting
+  /// for the device. They start from zero on each connection.
+  pub fn driver_metrics(&self) -> Option<MetricsSnapshot> {
+    let driver = self.driver.lock().unwrap_or_else(PoisonError::into_inner);
+    driver.as_ref().map(|driver| driver.metrics())
+  }
+
+  /// How many times the service has connected to the device, counting the first time.
+  pub fn connections(&self) -> u64 {
+    self.connections.load(Ordering::Relaxed)
+  }
+}
+
+#[cfg(feature = "metrics")]
+impl crate::metrics::MetricsSource for ServiceMetrics {
+  fn render_metrics(&self) -> String {
+    use crate::metrics::{render, write_metric};
+
+    let driver = self.driver_metrics();
+    let mut out = driver
+      .as_ref()
+      .map(|driver| render(driver, None))
+      .unwrap_or_default();
+    write_metric(
+      &mut out,
+      "lumatone_service_connected",
+      "gauge",
+      "Whether the service is connected to the device.",
+      driver.is_some() as u64,
+    );
+    write_metric(
+      &mut out,
+      "lumatone_service_connections_total",
+      "counter",
+      "Times the service connected to the device, including the first time.",
+      self.connections(),
+    );
+    out
+  }
+}
+
+/// Keeps the device configured as `config` says until `shutdown` is cancelled, and keeps
+/// `metrics` up to date.
+///
+/// The configuration is loaded once up front, so a missing or broken file fails straight
+/// away instead of on every reconnect.
+pub async fn run_service(
+  config: &ServiceConfig,
+  metrics: &ServiceMetrics,
+  shutdown: &CancellationToken,
+) -> Result<(), LumatoneError> {
+  let commands = config.target.device_commands()?;
+  info!("service started, with {} commands to apply", commands.len());
+
+  while let Some(mut lumatone) = wait_for_device(config, shutdown).await {
+    metrics.connected(lumatone.driver());
+    let reconciler = lumatone.start_reconciler(
+      DesiredState::from_commands(commands.clone()),
+      config.health_interval,
+    );
+    let mut events = reconciler.subscribe();
+    let mut reconnect_at: Option<Instant> = None;
+    let mut save_traffic_tick = interval(config.health_interval);
+
+    let stopping = loop {
+      let reconnect = async {
+        match reconnect_at {
+          Some(at) => sleep_until(at).await,
+          None => std::future::pending().await,
+        }
+      };
+      tokio::select! {
+        _ = shutdown.cancelled() => break true,
+        _ = save_traffic_tick.tick() => save_traffic(config, &lumatone),
+        _ = reconnect => {
+          warn!("device unresponsive for {:?}, reconnecting", config.reconnect_after);
+          break false;
+        }
+        event = events.recv() => match event {
+          Ok(ReconcileEvent::Converged(report)) => {
+            info!("device configured ({} sent, {} unchanged)", report.sent, report.unchanged);
+            reconnect_at = None;
+          }
+          Ok(ReconcileEvent::DeviceUnresponsive) => {
+            reconnect_at.get_or_insert(Instant::now() + config.reconnect_after);
+          }
+          Ok(ReconcileEvent::Failed(err)) => {
+            warn!("unable to configure device: {err}");
+            reconnect_at.get_or_insert(Instant::now() + config.reconnect_after);
+          }
+          Err(RecvError::Lagged(n)) => lumatone.driver().count_missed(n),
+          Err(RecvError::Closed) => break false,
+        },
+      }
+    };
+    save_traffic(config, &lumatone);
+    metrics.disconnected();
+    lumatone.shutdown().await;
+    if stopping {
+      break;
+    }
+  }
+  info!("service stopped");
+  Ok(())
+}
+
+/// Writes the device's recent traffic to [ServiceConfig::traffic_file], through a temporary
+/// file so a report never reads it half-written.
+fn save_traffic(config: &ServiceConfig, lumatone: &Lumatone) {
+  let path = match &config.traffic_file {
+    Some(path) => path,
+    None => return,
+  };
+  let tmp = path.with_extension("tmp");
+  let text = format_traffic(&lumatone.recent_traffic());
+  if let Err(err) = std::fs::write(&tmp, text).and_then(|_| std::fs::rename(&tmp, path)) {
+    warn!("unable to save device traffic to {}: {err}", path.display());
+  }
+}
+
+/// Detects and connects to the device, trying again every `detect_interval`. Returns
+/// `None` if `shutdown` is cancelled first.
+async fn wait_for_device(config: &ServiceConfig, shutdown: &CancellationToken) -> Option<Lumatone> {
+  loop {
+    match detect_device_until(shutdown).await {
+      Ok(device) => match Lumatone::connect(&device) {
+        Ok(mut lumatone) => {
+          if let Err(err) = lumatone.refresh_device_info().await {
+            warn!("unable to read device info, assuming latest firmware: {err:?}");
+          }
+          return Some(lumatone);
+        }
+        Err(err) => warn!("unable to connect to device: {err:?}"),
+      },
+      Err(_) if shutdown.is_cancelled() => return None,
+      Err(err) => info!("waiting for device: {}", err.current_context()),
+    }
+    tokio::select! {
+      _ = shutdown.cancelled() => return None,
+      _ = sleep(config.detect_interval) => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{scene::LightingMode, setlist::Song};
+
+  #[test]
+  fn test_scene_targets_only_configure_the_device() {
+    let mut intro = Scene::new("Intro");
+    intro.lighting = Some(LightingMode::OnKeystrokes);
+    let set_list = SetList {
+      name: "Friday".to_string(),
+      songs: vec![
+        Song::new("Opener", vec![Scene::new("Verse")]),
+        Song::new("Closer", vec![intro]),
+      ],
+      ..Default::default()
+    };
+    let scene = find_scene(&set_list, "Intro").unwrap();
+    assert_eq!(
+      scene.device_commands().unwrap(),
+      vec![Command::SetLightOnKeystrokes(true)]
+    );
+    assert!(find_scene(&set_list, "Outro").is_none());
+  }
+
+  #[cfg(feature = "metrics")]
+  #[test]
+  fn test_metrics_while_waiting_for_the_device() {
+    use crate::metrics::MetricsSource;
+
+    let metrics = ServiceMetrics::default();
+    let text = metrics.render_metrics();
+    assert!(text.contains("\nlumatone_service_connected 0\n"));
+    assert!(text.contains("\nlumatone_service_connections_total 0\n"));
+    assert!(!text.contains("lumatone_driver_"));
+  }
+}
@@ -0,0 +1,876 @@
+//! Set lists group [Scene]s into songs, so a whole gig's worth of configurations can be
+//! stepped through in order.
+//!
+//! A [SetListPlayer] keeps track of the current song and scene, and moves between them when:
+//!
+//! - one of the set list's [SceneTrigger]s is played on the device,
+//! - a scene's `advance_after` time runs out, or
+//! - a MIDI song select message arrives for a song with a matching `song_number` (see
+//!   [connect_song_select_input]).
+//!
+//! Set lists are saved as ini files, with a section for the set list, one for each song
+//! and one for each scene:
+//!
+//! ```text
+//! [SetList]
+//! Name=Friday
+//! NextTrigger=CC:1:20
+//! PreviousTrigger=CC:1:21
+//! ConnectReveal=ripple
+//!
+//! [Song0]
+//! Name=Opener
+//! SongNumber=1
+//!
+//! [Song0.Scene0]
+//! Name=Intro
+//! Keymap=presets/opener.ltn
+//! Theme=themes/stage.ini
+//! Lighting=OnKeystrokes
+//! Transpose=-2
+//! OutputChannel=2
+//! ProgramChanges=2:10,3:41
+//! PitchBends=1:10240,2:8192
+//! Routes=*:0-59>Bass Synth,2:0-127>DAW
+//! OnActivate=b0 07 64,f0 43 10 4c 00 00 7e 00 f7>Reface
+//! AdvanceAfter=32.5
+//! ```
+//!
+//! Each route is `channel:low-high>output`, where `channel` is `*` for every channel and
+//! `low` is at most `high`; see [RoutingMatrix]. Output names are written with `%2C` for `,`,
+//! `%3E` for `>` and `%25` for `%`, so any name survives the round trip.
+//!
+//! `OnActivate` messages are written in hex. Sysex messages can be followed by
+//! `>output` to send them to one output; see [SceneMessage].
+//!
+//! `ConnectReveal` is the [Reveal] used for the first scene the player applies, in place of
+//! rewriting the whole board at once.
+//!
+//! Keymap and theme paths are saved relative to the set list file's directory, and
+//! relative paths are resolved against it when loading, so a folder with a set list and its
+//! keymaps can be moved to another machine.
+
+use std::{
+  path::{Component, Path, PathBuf},
+  time::Duration,
+};
+
+use ini::{Ini, Properties};
+use log::{debug, info, warn};
+use lumatone_keymap::reveal::Reveal;
+use lumatone_midi::{
+  constants::MidiChannel,
+  controller::Lumatone,
+  device::get_port_by_name,
+  events::ChannelMessage,
+  proxy::NoteMapping,
+  routing::{Route, RoutingMatrix},
+  zones::Zone,
+};
+use midir::{MidiInput, MidiInputConnection};
+use tokio::{
+  sync::{broadcast::error::RecvError, mpsc},
+  time::{sleep_until, Instant},
+};
+
+use super::{
+  error::LumatoneError,
+  scene::{LightingMode, PitchBend, ProgramChange, Scene, SceneList, SceneMessage, SceneTrigger},
+};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+/// Status byte for the MIDI song select message.
+pub const SONG_SELECT: u8 = 0xf3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Song {
+  pub name: String,
+
+  /// If set, a song select message with this number jumps to the song.
+  pub song_number: Option<u8>,
+
+  pub scenes: Vec<Scene>,
+}
+
+impl Song {
+  pub fn new<S: Into<String>>(name: S, scenes: Vec<Scene>) -> Self {
+    Song {
+      name: name.into(),
+      song_number: None,
+      scenes,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetList {
+  pub name: String,
+  pub songs: Vec<Song>,
+  pub next_trigger: Option<SceneTrigger>,
+  pub previous_trigger: Option<SceneTrigger>,
+
+  /// How to reveal the first scene's keymap when the player starts.
+  pub connect_reveal: Option<Reveal>,
+}
+
+impl SetList {
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<SetList, LumatoneError> {
+    let path = path.as_ref();
+    let ini = Ini::load_from_file(path)
+      .report()
+      .change_context(LumatoneError::SetListLoadFailed(path.to_path_buf()))?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    Self::from_ini(&ini, base_dir)
+      .change_context(LumatoneError::SetListLoadFailed(path.to_path_buf()))
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LumatoneError> {
+    let path = path.as_ref();
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    self
+      .to_ini(base_dir)
+      .write_to_file(path)
+      .report()
+      .change_context(LumatoneError::SetListSaveFailed(path.to_path_buf()))
+  }
+
+  /// Writes the set list as an ini file. Keymap paths are written relative to `base_dir`.
+  pub fn to_ini(&self, base_dir: &Path) -> Ini {
+    let mut conf = Ini::new();
+    conf.with_section(Some("SetList")).set("Name", &self.name);
+    if let Some(t) = &self.next_trigger {
+      conf
+        .with_section(Some("SetList"))
+        .set("NextTrigger", trigger_to_string(t));
+    }
+    if let Some(t) = &self.previous_trigger {
+      conf
+        .with_section(Some("SetList"))
+        .set("PreviousTrigger", trigger_to_string(t));
+    }
+    if let Some(reveal) = &self.connect_reveal {
+      conf
+        .with_section(Some("SetList"))
+        .set("ConnectReveal", reveal.to_string());
+    }
+
+    for (i, song) in self.songs.iter().enumerate() {
+      let section = format!("Song{i}");
+      conf.with_section(Some(&section)).set("Name", &song.name);
+      if let Some(n) = song.song_number {
+        conf
+          .with_section(Some(&section))
+          .set("SongNumber", n.to_string());
+      }
+
+      for (j, scene) in song.scenes.iter().enumerate() {
+        let section = format!("Song{i}.Scene{j}");
+        conf.with_section(Some(&section)).set("Name", &scene.name);
+        let mut set = |key: &str, value: String| {
+          conf.with_section(Some(&section)).set(key, value);
+        };
+        if let Some(keymap) = &scene.keymap {
+          set(
+            "Keymap",
+            relative_path(keymap, base_dir).display().to_string(),
+          );
+        }
+        if let Some(theme) = &scene.theme {
+          set(
+            "Theme",
+            relative_path(theme, base_dir).display().to_string(),
+          );
+        }
+        if let Some(lighting) = scene.lighting {
+          let s = match lighting {
+            LightingMode::Static => "Static",
+            LightingMode::OnKeystrokes => "OnKeystrokes",
+          };
+          set("Lighting", s.to_string());
+        }
+        if scene.mapping.transpose != 0 {
+          set("Transpose", scene.mapping.transpose.to_string());
+        }
+        if let Some(ch) = scene.mapping.output_channel {
+          set("OutputChannel", ch.get().to_string());
+        }
+        if !scene.program_changes.is_empty() {
+          let pcs: Vec<String> = scene
+            .program_changes
+            .iter()
+            .map(|pc| format!("{}:{}", pc.channel.get(), pc.program))
+            .collect();
+          set("ProgramChanges", pcs.join(","));
+        }
+        if !scene.pitch_bends.is_empty() {
+          let bends: Vec<String> = scene
+            .pitch_bends
+            .iter()
+            .map(|pb| format!("{}:{}", pb.channel.get(), pb.value))
+            .collect();
+          set("PitchBends", bends.join(","));
+        }
+        if let Some(routing) = &scene.routing {
+          let routes: Vec<String> = routing.routes.iter().map(route_to_string).collect();
+          set("Routes", routes.join(","));
+        }
+        if !scene.on_activate.is_empty() {
+          let messages: Vec<String> = scene.on_activate.iter().map(message_to_string).collect();
+          set("OnActivate", messages.join(","));
+        }
+        if let Some(d) = scene.advance_after {
+          set("AdvanceAfter", d.as_secs_f64().to_string());
+        }
+      }
+    }
+    conf
+  }
+
+  /// Reads a set list from `ini`. Relative keymap paths are joined onto `base_dir`.
+  pub fn from_ini(ini: &Ini, base_dir: &Path) -> Result<SetList, LumatoneError> {
+    let mut set_list = SetList::default();
+    if let Some(section) = ini.section(Some("SetList")) {
+      set_list.name = section.get("Name").unwrap_or_default().to_string();
+      set_list.next_trigger = section.get("NextTrigger").map(parse_trigger).transpose()?;
+      set_list.previous_trigger = section
+        .get("PreviousTrigger")
+        .map(parse_trigger)
+        .transpose()?;
+      set_list.connect_reveal = section
+        .get("ConnectReveal")
+        .map(|r| {
+          r.parse().map_err(|_| {
+            report!(LumatoneError::InvalidSetList(format!(
+              "invalid connect reveal: {r}"
+            )))
+          })
+        })
+        .transpose()?;
+    }
+
+    for i in 0.. {
+      let section = match ini.section(Some(format!("Song{i}"))) {
+        Some(s) => s,
+        None => break,
+      };
+      let mut song = Song::new(section.get("Name").unwrap_or_default(), vec![]);
+      song.song_number = section
+        .get("SongNumber")
+        .map(|n| parse_value(n, "SongNumber"))
+        .transpose()?;
+
+      for j in 0.. {
+        match ini.section(Some(format!("Song{i}.Scene{j}"))) {
+          Some(section) => song.scenes.push(scene_from_ini_section(section, base_dir)?),
+          None => break,
+        }
+      }
+      set_list.songs.push(song);
+    }
+    Ok(set_list)
+  }
+}
+
+fn scene_from_ini_section(section: &Properties, base_dir: &Path) -> Result<Scene, LumatoneError> {
+  let mut scene = Scene::new(section.get("Name").unwrap_or_default());
+  scene.keymap = section.get("Keymap").map(|p| base_dir.join(p));
+  scene.theme = section.get("Theme").map(|p| base_dir.join(p));
+  scene.lighting = match section.get("Lighting") {
+    None => None,
+    Some("Static") => Some(LightingMode::Static),
+    Some("OnKeystrokes") => Some(LightingMode::OnKeystrokes),
+    Some(other) => bail!(LumatoneError::InvalidSetList(format!(
+      "unknown lighting mode: {other}"
+    ))),
+  };
+  scene.mapping = NoteMapping {
+    transpose: section
+      .get("Transpose")
+      .map(|t| parse_value(t, "Transpose"))
+      .transpose()?
+      .unwrap_or(0),
+    output_channel: section
+      .get("OutputChannel")
+      .map(parse_channel)
+      .transpose()?,
+  };
+  if let Some(pcs) = section.get("ProgramChanges") {
+    for pc in pcs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+      let (channel, program) = pc.split_once(':').ok_or_else(|| {
+        report!(LumatoneError::InvalidSetList(format!(
+          "invalid program change: {pc}"
+        )))
+      })?;
+      scene.program_changes.push(ProgramChange {
+        channel: parse_channel(channel)?,
+        program: parse_value(program, "ProgramChanges")?,
+      });
+    }
+  }
+  if let Some(bends) = section.get("PitchBends") {
+    for pb in bends.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+      let (channel, value) = pb.split_once(':').ok_or_else(|| {
+        report!(LumatoneError::InvalidSetList(format!(
+          "invalid pitch bend: {pb}"
+        )))
+      })?;
+      let value = parse_value(value, "PitchBends")?;
+      if value > 0x3fff {
+        bail!(LumatoneError::InvalidSetList(format!(
+          "pitch bend out of range (0-16383): {pb}"
+        )));
+      }
+      scene.pitch_bends.push(PitchBend {
+        channel: parse_channel(channel)?,
+        value,
+      });
+    }
+  }
+  if let Some(routes) = section.get("Routes") {
+    let routes = routes
+      .split(',')
+      .map(str::trim)
+      .filter(|s| !s.is_empty())
+      .map(parse_route)
+      .collect::<Result<Vec<_>, _>>()?;
+    scene.routing = Some(RoutingMatrix::new(routes));
+  }
+  if let Some(messages) = section.get("OnActivate") {
+    scene.on_activate = messages
+      .split(',')
+      .map(str::trim)
+      .filter(|s| !s.is_empty())
+      .map(parse_message)
+      .collect::<Result<Vec<_>, _>>()?;
+  }
+  scene.advance_after = section
+    .get("AdvanceAfter")
+    .map(|s| {
+      Duration::try_from_secs_f64(parse_value(s, "AdvanceAfter")?).map_err(|_| {
+        report!(LumatoneError::InvalidSetList(format!(
+          "invalid AdvanceAfter: {s}"
+        )))
+      })
+    })
+    .transpose()?;
+  Ok(scene)
+}
+
+/// `path` relative to `base_dir`, going up with `..` where needed. Paths that can't be made
+/// relative, e.g. ones on another drive, are returned as they are.
+fn relative_path(path: &Path, base_dir: &Path) -> PathBuf {
+  if path.is_absolute() != base_dir.is_absolute() {
+    return path.to_path_buf();
+  }
+  let mut path_parts = path.components().peekable();
+  let mut base_parts = base_dir.components().peekable();
+  while let (Some(a), Some(b)) = (path_parts.peek(), base_parts.peek()) {
+    if a != b {
+      break;
+    }
+    path_parts.next();
+    base_parts.next();
+  }
+  if base_parts
+    .peek()
+    .is_some_and(|c| matches!(c, Component::Prefix(_) | Component::RootDir))
+  {
+    return path.to_path_buf();
+  }
+  base_parts
+    .map(|_| Component::ParentDir)
+    .chain(path_parts)
+    .collect()
+}
+
+fn parse_value<T: std::str::FromStr>(s: &str, key: &str) -> Result<T, LumatoneError> {
+  s.trim()
+    .parse()
+    .map_err(|_| report!(LumatoneError::InvalidSetList(format!("invalid {key}: {s}"))))
+}
+
+fn parse_channel(s: &str) -> Result<MidiChannel, LumatoneError> {
+  MidiChannel::new(parse_value(s, "channel")?).ok_or_else(|| {
+    report!(LumatoneError::InvalidSetList(format!(
+      "invalid channel: {s}"
+    )))
+  })
+}
+
+fn parse_trigger(s: &str) -> Result<SceneTrigger, LumatoneError> {
+  let invalid = || LumatoneError::InvalidSetList(format!("invalid trigger: {s}"));
+  let parts: Vec<&str> = s.split(':').collect();
+  match parts[..] {
+    ["CC", channel, controller] => Ok(SceneTrigger::Control {
+      channel: parse_channel(channel).change_context_lazy(invalid)?,
+      controller: parse_value(controller, "controller").change_context_lazy(invalid)?,
+    }),
+    ["Note", channel, note] => Ok(SceneTrigger::Note {
+      channel: parse_channel(channel).change_context_lazy(invalid)?,
+      note: parse_value(note, "note").change_context_lazy(invalid)?,
+    }),
+    _ => Err(report!(invalid())),
+  }
+}
+
+fn parse_route(s: &str) -> Result<Route, LumatoneError> {
+  let invalid = || LumatoneError::InvalidSetList(format!("invalid route: {s}"));
+  let (zone, output) = s.split_once('>').ok_or_else(|| report!(invalid()))?;
+  let (channel, notes) = zone.split_once(':').ok_or_else(|| report!(invalid()))?;
+  let (low, high) = notes.split_once('-').ok_or_else(|| report!(invalid()))?;
+  let channel = match channel.trim() {
+    "*" => None,
+    c => Some(parse_channel(c).change_context_lazy(invalid)?),
+  };
+  let low_note: u8 = parse_value(low, "note").change_context_lazy(invalid)?;
+  let high_note: u8 = parse_value(high, "note").change_context_lazy(invalid)?;
+  if high_note > 127 || low_note > high_note {
+    return Err(report!(invalid()).attach_printable("notes must be in 0-127, lowest first"));
+  }
+  Ok(Route {
+    zone: Zone {
+      channel,
+      low_note,
+      high_note,
+    },
+    output: unescape_output(output.trim()).change_context_lazy(invalid)?,
+  })
+}
+
+fn route_to_string(route: &Route) -> String {
+  let channel = match route.zone.channel {
+    Some(c) => c.get().to_string(),
+    None => "*".to_string(),
+  };
+  format!(
+    "{channel}:{}-{}>{}",
+    route.zone.low_note,
+    route.zone.high_note,
+    escape_output(&route.output)
+  )
+}
+
+/// Escapes the characters that separate routes and messages in an output name.
+fn escape_output(name: &str) -> String {
+  name
+    .replace('%', "%25")
+    .replace(',', "%2C")
+    .replace('>', "%3E")
+}
+
+fn unescape_output(s: &str) -> Result<String, LumatoneError> {
+  let mut name = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(i) = rest.find('%') {
+    name.push_str(&rest[..i]);
+    let c = match rest.get(i + 1..i + 3) {
+      Some("25") => '%',
+      Some("2C" | "2c") => ',',
+      Some("3E" | "3e") => '>',
+      _ => bail!(LumatoneError::InvalidSetList(format!(
+        "invalid escape in output name: {s}"
+      ))),
+    };
+    name.push(c);
+    rest = &rest[i + 3..];
+  }
+  name.push_str(rest);
+  Ok(name)
+}
+
+fn parse_message(s: &str) -> Result<SceneMessage, LumatoneError> {
+  let invalid = || LumatoneError::InvalidSetList(format!("invalid message: {s}"));
+  let (bytes, output) = match s.split_once('>') {
+    Some((bytes, output)) => (
+      bytes,
+      Some(unescape_output(output.trim()).change_context_lazy(invalid)?),
+    ),
+    None => (s, None),
+  };
+  let message = bytes
+    .split_whitespace()
+    .map(|b| u8::from_str_radix(b, 16))
+    .collect::<std::result::Result<Vec<u8>, _>>()
+    .map_err(|_| report!(invalid()))?;
+  match message.first() {
+    Some(0xf0) => {
+      let framed = message.len() >= 2 && message.last() == Some(&0xf7);
+      if !framed || message[1..message.len() - 1].iter().any(|b| b & 0x80 != 0) {
+        return Err(
+          report!(invalid())
+            .attach_printable("sysex messages must end with f7 and have only 7-bit data bytes"),
+        );
+      }
+      Ok(SceneMessage::Sysex { output, message })
+    }
+    _ if output.is_some() => Err(report!(invalid()).attach_printable(
+      "only sysex messages can be sent to a single output; use Routes for channel messages",
+    )),
+    _ => ChannelMessage::from_bytes(&message)
+      .change_context_lazy(invalid)
+      .map(SceneMessage::Channel),
+  }
+}
+
+fn message_to_string(msg: &SceneMessage) -> String {
+  let hex = |bytes: &[u8]| {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    bytes.join(" ")
+  };
+  match msg {
+    SceneMessage::Channel(msg) => hex(&msg.to_bytes()),
+    SceneMessage::Sysex {
+      output: Some(output),
+      message,
+    } => format!("{}>{}", hex(message), escape_output(output)),
+    SceneMessage::Sysex {
+      output: None,
+      message,
+    } => hex(message),
+  }
+}
+
+fn trigger_to_string(trigger: &SceneTrigger) -> String {
+  match trigger {
+    SceneTrigger::Control {
+      channel,
+      controller,
+    } => format!("CC:{}:{controller}", channel.get()),
+    SceneTrigger::Note { channel, note } => format!("Note:{}:{note}", channel.get()),
+  }
+}
+
+/// Steps through the songs and scenes in a [SetList].
+pub struct SetListPlayer {
+  set_list: SetList,
+  song: usize,
+  scenes: SceneList,
+}
+
+impl SetListPlayer {
+  pub fn new(set_list: SetList) -> Self {
+    let mut player = SetListPlayer {
+      set_list,
+      song: 0,
+      scenes: SceneList::default(),
+    };
+    player.select_song(0);
+    player
+  }
+
+  pub fn set_list(&self) -> &SetList {
+    &self.set_list
+  }
+
+  pub fn current_song(&self) -> Option<&Song> {
+    self.set_list.songs.get(self.song)
+  }
+
+  pub fn current_scene(&self) -> Option<&Scene> {
+    self.scenes.current()
+  }
+
+  /// Moves to the first scene of the song at `index`, and returns the scene.
+  pub fn select_song(&mut self, index: usize) -> Option<&Scene> {
+    let song = self.set_list.songs.get(index)?;
+    self.song = index;
+    self.scenes = SceneList::new(song.scenes.clone());
+    self.scenes.next_trigger = self.set_list.next_trigger;
+    self.scenes.previous_trigger = self.set_list.previous_trigger;
+    self.scenes.current()
+  }
+
+  /// Moves to the first song with the given `song_number`, e.g. from a song select message.
+  pub fn select_song_number(&mut self, song_number: u8) -> Option<&Scene> {
+    let index = self
+      .set_list
+      .songs
+      .iter()
+      .position(|s| s.song_number == Some(song_number))?;
+    self.select_song(index)
+  }
+
+  /// Moves to the next scene, or the first scene of the next song. Returns `None` at the
+  /// end of the set list.
+  #[allow(clippy::should_implement_trait)]
+  pub fn next(&mut self) -> Option<&Scene> {
+    if self.scenes.next().is_some() {
+      return self.scenes.current();
+    }
+    self.select_song(self.song + 1)
+  }
+
+  /// Moves to the previous scene, or the last scene of the previous song. Returns `None`
+  /// at the start of the set list.
+  pub fn previous(&mut self) -> Option<&Scene> {
+    if self.scenes.previous().is_some() {
+      return self.scenes.current();
+    }
+    let song = self.song.checked_sub(1)?;
+    self.select_song(song)?;
+    let last = self.scenes.scenes().len().saturating_sub(1);
+    self.scenes.select(last)
+  }
+
+  /// Moves to the next or previous scene if `msg` matches one of the set list's triggers,
+  /// returning the new scene.
+  pub fn handle_message(&mut self, msg: &ChannelMessage) -> Option<&Scene> {
+    if self.set_list.next_trigger.is_some_and(|t| t.matches(msg)) {
+      return self.next();
+    }
+    if self
+      .set_list
+      .previous_trigger
+      .is_some_and(|t| t.matches(msg))
+    {
+      return self.previous();
+    }
+    None
+  }
+
+  /// Applies the current scene, then keeps applying scenes as the player moves through the
+  /// set list, until the device's event stream closes or the [Lumatone] shuts down.
+  ///
+  /// `song_select` receives song numbers from song select messages (see
+  /// [connect_song_select_input]).
+  pub async fn run(
+    &mut self,
+    lumatone: &Lumatone,
+    mut song_select: Option<mpsc::Receiver<u8>>,
+  ) -> Result<(), LumatoneError> {
+    let mut events = lumatone
+      .driver()
+      .subscribe_events()
+      .change_context(LumatoneError::DeviceError)?;
+    let shutdown = lumatone.shutdown_token();
+
+    let mut deadline = self
+      .apply_current(lumatone, self.set_list.connect_reveal.as_ref())
+      .await;
+    loop {
+      let changed = tokio::select! {
+        _ = shutdown.cancelled() => return Ok(()),
+
+        res = events.recv() => match res {
+          Ok(msg) => self.handle_message(&msg).is_some(),
+          Err(RecvError::Lagged(n)) => {
+            warn!("set list missed {n} events");
+            lumatone.driver().count_missed(n);
+            false
+          }
+          Err(RecvError::Closed) => return Ok(()),
+        },
+
+        Some(number) = recv_optional(&mut song_select) => {
+          debug!("song select {number}");
+          self.select_song_number(number).is_some()
+        }
+
+        _ = sleep_until_optional(deadline) => {
+          deadline = None;
+          self.next().is_some()
+        }
+      };
+
+      if changed {
+        deadline = self.apply_current(lumatone, None).await;
+      }
+    }
+  }
+
+  /// Applies the current scene, returning when it should automatically advance.
+  async fn apply_current(&self, lumatone: &Lumatone, reveal: Option<&Reveal>) -> Option<Instant> {
+    let scene = self.current_scene()?;
+    if let Some(song) = self.current_song() {
+      info!("song {}: scene {}", song.name, scene.name);
+    }
+    if let Err(err) = scene.apply_with_reveal(lumatone, reveal).await {
+      warn!("{err:?}");
+    }
+    scene.advance_after.map(|d| Instant::now() + d)
+  }
+}
+
+async fn recv_optional<T>(rx: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+  match rx {
+    Some(rx) => rx.recv().await,
+    None => std::future::pending().await,
+  }
+}
+
+async fn sleep_until_optional(deadline: Option<Instant>) {
+  match deadline {
+    Some(deadline) => sleep_until(deadline).await,
+    None => std::future::pending().await,
+  }
+}
+
+/// Listens for song select messages on the MIDI input port named `port_name`, and sends
+/// the song numbers on the returned channel. The connection stays open until it's dropped.
+pub fn connect_song_select_input(
+  port_name: &str,
+) -> Result<(MidiInputConnection<()>, mpsc::Receiver<u8>), LumatoneError> {
+  let input = MidiInput::new("lumatone-rs song select")
+    .report()
+    .change_context(LumatoneError::DeviceError)?;
+  let port = get_port_by_name(&input, port_name).change_context(LumatoneError::DeviceError)?;
+
+  let (tx, rx) = mpsc::channel(16);
+  let conn = input
+    .connect(
+      &port,
+      port_name,
+      move |_, msg, _| {
+        if let [SONG_SELECT, number, ..] = msg {
+          if let Err(err) = tx.try_send(*number & 0x7f) {
+            warn!("unable to queue song select: {err}");
+          }
+        }
+      },
+      (),
+    )
+    .map_err(|e| {
+      report!(LumatoneError::DeviceError)
+        .attach_printable(format!("midi input connection error: {e}"))
+    })?;
+  Ok((conn, rx))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+
+  fn scene(name: &str) -> Scene {
+    Scene::new(name)
+  }
+
+  fn set_list() -> SetList {
+    let mut second = Song::new("second", vec![scene("c")]);
+    second.song_number = Some(7);
+    SetList {
+      name: "gig".to_string(),
+      songs: vec![Song::new("first", vec![scene("a"), scene("b")]), second],
+      next_trigger: Some(SceneTrigger::Control {
+        channel: MidiChannel::default(),
+        controller: 20,
+      }),
+      previous_trigger: None,
+      connect_reveal: Some(Reveal::Boards),
+    }
+  }
+
+  #[test]
+  fn test_player_moves_across_songs() {
+    let mut player = SetListPlayer::new(set_list());
+    assert_eq!(player.current_scene().unwrap().name, "a");
+    assert_eq!(player.next().unwrap().name, "b");
+    assert_eq!(player.next().unwrap().name, "c");
+    assert_eq!(player.current_song().unwrap().name, "second");
+    assert!(player.next().is_none());
+    assert_eq!(player.previous().unwrap().name, "b");
+    assert_eq!(player.previous().unwrap().name, "a");
+    assert!(player.previous().is_none());
+
+    assert_eq!(player.select_song_number(7).unwrap().name, "c");
+    assert!(player.select_song_number(8).is_none());
+  }
+
+  #[test]
+  fn test_set_list_ini_round_trip() {
+    let mut list = set_list();
+    let s = &mut list.songs[0].scenes[0];
+    s.keymap = Some(PathBuf::from("/gig/presets/a.ltn"));
+    s.theme = Some(PathBuf::from("/gig/themes/dark.ini"));
+    list.songs[0].scenes[1].keymap = Some(PathBuf::from("/shared/b.ltn"));
+    let s = &mut list.songs[0].scenes[0];
+    s.lighting = Some(LightingMode::OnKeystrokes);
+    s.mapping = NoteMapping {
+      transpose: -2,
+      output_channel: MidiChannel::new(3),
+    };
+    s.program_changes = vec![ProgramChange {
+      channel: MidiChannel::new(2).unwrap(),
+      program: 41,
+    }];
+    s.pitch_bends = vec![PitchBend {
+      channel: MidiChannel::new(1).unwrap(),
+      value: 10240,
+    }];
+    s.advance_after = Some(Duration::from_millis(32500));
+    s.routing = Some(
+      RoutingMatrix::default()
+        .route(Zone::notes(0, 59), "Bass, Synth>1 (100%)")
+        .route(Zone::channel(MidiChannel::new(2).unwrap()), "DAW"),
+    );
+    s.on_activate = vec![
+      SceneMessage::Channel(ChannelMessage::ControlChange {
+        channel: MidiChannel::new(1).unwrap(),
+        controller: 7,
+        value: 100,
+      }),
+      SceneMessage::Sysex {
+        output: Some("Reface, CP>".to_string()),
+        message: vec![0xf0, 0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7],
+      },
+      SceneMessage::Sysex {
+        output: None,
+        message: vec![0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7],
+      },
+    ];
+
+    let ini = list.to_ini(Path::new("/gig"));
+    let keymap = |scene: &str| ini.section(Some(scene)).unwrap().get("Keymap").unwrap();
+    assert_eq!(keymap("Song0.Scene0"), "presets/a.ltn");
+    assert_eq!(keymap("Song0.Scene1"), "../shared/b.ltn");
+
+    let parsed = SetList::from_ini(&ini, Path::new("/gig")).unwrap();
+    assert_eq!(parsed, list);
+
+    // Moving the folder moves the keymaps with it.
+    let moved = SetList::from_ini(&ini, Path::new("/backup/gig")).unwrap();
+    assert_eq!(
+      moved.songs[0].scenes[0].keymap,
+      Some(PathBuf::from("/backup/gig/presets/a.ltn"))
+    );
+  }
+
+  #[test]
+  fn test_invalid_routes() {
+    for route in ["*:60-59>a", "1:0-128>a", "*:0-127>a%2", "*:0-127>a%41"] {
+      assert!(parse_route(route).is_err(), "{route}");
+    }
+  }
+
+  #[test]
+  fn test_pitch_bend_out_of_range() {
+    let ini = |value: u16| {
+      Ini::load_from_str(&format!(
+        "[Song0]\nName=a\n[Song0.Scene0]\nName=b\nPitchBends=1:{value}\n"
+      ))
+      .unwrap()
+    };
+    assert!(SetList::from_ini(&ini(16383), Path::new("")).is_ok());
+    assert!(SetList::from_ini(&ini(16384), Path::new("")).is_err());
+  }
+
+  #[test]
+  fn test_invalid_sysex() {
+    for msg in ["f0", "f0 43 10", "f0 43 90 f7", "f0 f7 f7"] {
+      assert!(parse_message(msg).is_err(), "{msg}");
+    }
+    assert!(parse_message("f0 f7").is_ok());
+  }
+
+  #[test]
+  fn test_invalid_advance_after() {
+    for value in ["-1", "NaN", "inf", "1e30"] {
+      let ini = Ini::load_from_str(&format!(
+        "[Song0]\nName=a\n[Song0.Scene0]\nName=b\nAdvanceAfter={value}\n"
+      ))
+      .unwrap();
+      assert!(SetList::from_ini(&ini, Path::new("")).is_err(), "{value}");
+    }
+  }
+}
@@ -0,0 +1,853 @@
+//! Synchronizing a [Library] with a remote store, e.g. to keep the presets on a studio
+//! desktop and a gig laptop in step.
+//!
+//! A [SyncBackend] stores blobs addressed by their [ContentHash], plus a copy of each ref's
+//! history. Syncing blobs never has to compare or merge contents: [sync] copies whatever
+//! blobs one side has and the other doesn't, and checks everything pulled against its hash
+//! before it's added to the library. Refs are synced after the blobs they point to, by
+//! merging the two histories (see [merge_histories]), so both sides end up with the same
+//! history and the same current version. Deleting a ref isn't synced: a ref deleted on one
+//! side comes back from the other.
+//!
+//! Two backends are included:
+//!
+//! - [FilesystemBackend]: a directory, e.g. on a USB stick or a network share.
+//! - `WebDavBackend` (with the `webdav` feature): a collection on a WebDAV server, over
+//!   plain HTTP. Credentials are only sent to servers on the loopback interface, e.g.
+//!   through an SSH or TLS tunnel. For servers that are only reachable over HTTPS, mount
+//!   the share and use a [FilesystemBackend] instead.
+//!
+//! An S3 backend is out of scope for now; S3-compatible stores can be used through a
+//! mounted filesystem in the same way.
+
+use std::path::{Path, PathBuf};
+
+use super::{
+  bundle::AssetKind,
+  error::LumatoneError,
+  library::{ContentHash, Library, ASSET_KINDS},
+};
+
+use error_stack::{bail, IntoReport, Result, ResultExt};
+
+const REFS_DIR: &str = "refs";
+
+/// A remote store of blobs and ref histories. Backends store each blob under its hash, and
+/// don't need to verify contents themselves.
+pub trait SyncBackend {
+  /// The hashes of all blobs in the store.
+  fn list(&mut self) -> Result<Vec<ContentHash>, LumatoneError>;
+
+  /// Adds a blob to the store.
+  fn push(&mut self, hash: &ContentHash, contents: &[u8]) -> Result<(), LumatoneError>;
+
+  /// Fetches a blob from the store.
+  fn pull(&mut self, hash: &ContentHash) -> Result<Vec<u8>, LumatoneError>;
+
+  /// The names of all refs of the given kind in the store.
+  fn list_refs(&mut self, kind: AssetKind) -> Result<Vec<String>, LumatoneError>;
+
+  /// The stored history of a ref, oldest first. Empty if there's no such ref.
+  fn pull_ref(&mut self, kind: AssetKind, name: &str) -> Result<Vec<ContentHash>, LumatoneError>;
+
+  /// Replaces the stored history of a ref.
+  fn push_ref(
+    &mut self,
+    kind: AssetKind,
+    name: &str,
+    history: &[ContentHash],
+  ) -> Result<(), LumatoneError>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+  pub pushed: Vec<ContentHash>,
+  pub pulled: Vec<ContentHash>,
+  /// Refs whose history was updated in the store.
+  pub pushed_refs: Vec<(AssetKind, String)>,
+  /// Refs whose history was updated in the library.
+  pub pulled_refs: Vec<(AssetKind, String)>,
+}
+
+/// Pushes the library's blobs that are missing from the backend, pulls the backend's blobs
+/// that are missing from the library, then merges the ref histories on both sides.
+pub fn sync<B: SyncBackend + ?Sized>(
+  library: &Library,
+  backend: &mut B,
+) -> Result<SyncReport, LumatoneError> {
+  let local = library.hashes()?;
+  let remote = backend.list()?;
+  let mut report = SyncReport::default();
+
+  for hash in local.iter().filter(|h| remote.binary_search(h).is_err()) {
+    let contents = library.get(hash)?;
+    backend.push(hash, &contents)?;
+    report.pushed.push(*hash);
+  }
+
+  for hash in remote.iter().filter(|h| local.binary_search(h).is_err()) {
+    let contents = backend.pull(hash)?;
+    if ContentHash::of(&contents) != *hash {
+      bail!(LumatoneError::SyncFailed(format!(
+        "remote blob {hash} doesn't match its hash"
+      )));
+    }
+    library.put(&contents)?;
+    report.pulled.push(*hash);
+  }
+
+  for kind in ASSET_KINDS {
+    let mut names = library.refs(kind)?;
+    names.extend(backend.list_refs(kind)?);
+    names.sort();
+    names.dedup();
+    for name in names {
+      let local = library.history(kind, &name)?;
+      let remote = backend.pull_ref(kind, &name)?;
+      let merged = merge_histories(&local, &remote);
+      if merged != remote {
+        backend.push_ref(kind, &name, &merged)?;
+        report.pushed_refs.push((kind, name.clone()));
+      }
+      if merged != local {
+        library.replace_history(kind, &name, &merged)?;
+        report.pulled_refs.push((kind, name));
+      }
+    }
+  }
+
+  Ok(report)
+}
+
+/// Merges the local and remote histories of a ref. If one history extends the other, the
+/// longer one wins. If they've diverged, the remote history is kept and the local versions
+/// it's missing are appended, so the version synced last becomes current and the next sync
+/// on the other side is a plain fast-forward.
+pub fn merge_histories(local: &[ContentHash], remote: &[ContentHash]) -> Vec<ContentHash> {
+  let common = local.iter().zip(remote).take_while(|(l, r)| l == r).count();
+  if common == remote.len() {
+    return local.to_vec();
+  }
+  let mut merged = remote.to_vec();
+  for hash in &local[common..] {
+    if !merged.contains(hash) {
+      merged.push(*hash);
+    }
+  }
+  merged
+}
+
+/// Parses a ref history stored by a backend: one hash per line, oldest first.
+fn parse_history(text: &str) -> Result<Vec<ContentHash>, LumatoneError> {
+  text
+    .lines()
+    .map(str::trim)
+    .filter(|l| !l.is_empty())
+    .map(|l| {
+      l.parse()
+        .change_context_lazy(|| LumatoneError::SyncFailed(format!("invalid remote ref {l:?}")))
+    })
+    .collect()
+}
+
+fn format_history(history: &[ContentHash]) -> String {
+  history.iter().map(|hash| format!("{hash}\n")).collect()
+}
+
+/// Stores blobs as files in a directory, named by their hash, and ref histories under
+/// `refs/<kind>/<name>`.
+#[derive(Debug, Clone)]
+pub struct FilesystemBackend {
+  dir: PathBuf,
+}
+
+impl FilesystemBackend {
+  pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+    FilesystemBackend {
+      dir: dir.as_ref().to_path_buf(),
+    }
+  }
+
+  fn ref_path(&self, kind: AssetKind, name: &str) -> PathBuf {
+    self.dir.join(REFS_DIR).join(kind.dir()).join(name)
+  }
+
+  /// Writes `path` through a temporary file, so an interrupted write never leaves a
+  /// partial blob or ref.
+  fn write(&self, path: &Path, contents: &[u8]) -> Result<(), LumatoneError> {
+    let tmp = path.with_file_name(format!(
+      ".{}.tmp",
+      path.file_name().unwrap().to_string_lossy()
+    ));
+    let write_failed = || LumatoneError::SyncFailed(format!("can't write {}", path.display()));
+    std::fs::create_dir_all(path.parent().unwrap())
+      .report()
+      .change_context_lazy(write_failed)?;
+    std::fs::write(&tmp, contents)
+      .report()
+      .change_context_lazy(write_failed)?;
+    std::fs::rename(&tmp, path)
+      .report()
+      .change_context_lazy(write_failed)
+  }
+
+  /// The names of the files in `dir`, or nothing if it doesn't exist.
+  fn file_names(dir: &Path) -> Result<Vec<String>, LumatoneError> {
+    if !dir.is_dir() {
+      return Ok(vec![]);
+    }
+    let read_failed = || LumatoneError::SyncFailed(format!("can't read {}", dir.display()));
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)
+      .report()
+      .change_context_lazy(read_failed)?
+    {
+      let entry = entry.report().change_context_lazy(read_failed)?;
+      let is_file = entry.file_type().map_or(false, |t| t.is_file());
+      match entry.file_name().to_str() {
+        Some(name) if is_file && !name.starts_with('.') => names.push(name.to_string()),
+        _ => {}
+      }
+    }
+    names.sort();
+    Ok(names)
+  }
+}
+
+impl SyncBackend for FilesystemBackend {
+  fn list(&mut self) -> Result<Vec<ContentHash>, LumatoneError> {
+    let mut hashes = Self::file_names(&self.dir)?
+      .iter()
+      .filter_map(|name| name.parse().ok())
+      .collect::<Vec<_>>();
+    hashes.sort();
+    Ok(hashes)
+  }
+
+  fn push(&mut self, hash: &ContentHash, contents: &[u8]) -> Result<(), LumatoneError> {
+    self.write(&self.dir.join(hash.to_string()), contents)
+  }
+
+  fn pull(&mut self, hash: &ContentHash) -> Result<Vec<u8>, LumatoneError> {
+    let path = self.dir.join(hash.to_string());
+    std::fs::read(&path)
+      .report()
+      .change_context_lazy(|| LumatoneError::SyncFailed(format!("can't read {}", path.display())))
+  }
+
+  fn list_refs(&mut self, kind: AssetKind) -> Result<Vec<String>, LumatoneError> {
+    Self::file_names(&self.dir.join(REFS_DIR).join(kind.dir()))
+  }
+
+  fn pull_ref(&mut self, kind: AssetKind, name: &str) -> Result<Vec<ContentHash>, LumatoneError> {
+    let path = self.ref_path(kind, name);
+    if !path.is_file() {
+      return Ok(vec![]);
+    }
+    let text = std::fs::read_to_string(&path)
+      .report()
+      .change_context_lazy(|| {
+        LumatoneError::SyncFailed(format!("can't read {}", path.display()))
+      })?;
+    parse_history(&text)
+  }
+
+  fn push_ref(
+    &mut self,
+    kind: AssetKind,
+    name: &str,
+    history: &[ContentHash],
+  ) -> Result<(), LumatoneError> {
+    self.write(
+      &self.ref_path(kind, name),
+      format_history(history).as_bytes(),
+    )
+  }
+}
+
+#[cfg(feature = "webdav")]
+pub use webdav::WebDavBackend;
+
+#[cfg(feature = "webdav")]
+mod webdav {
+  use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    net::{IpAddr, Ipv6Addr, TcpStream},
+    time::Duration,
+  };
+
+  use quick_xml::{
+    events::Event,
+    name::{Namespace, ResolveResult},
+    reader::NsReader,
+  };
+
+  use super::{
+    format_history, parse_history, AssetKind, ContentHash, LumatoneError, SyncBackend, REFS_DIR,
+  };
+
+  use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+  const TIMEOUT: Duration = Duration::from_secs(30);
+
+  /// Stores blobs in a WebDAV collection, one resource per blob, named by its hash, and
+  /// ref histories in the `refs/<kind>/` collections below it.
+  #[derive(Debug, Clone)]
+  pub struct WebDavBackend {
+    /// The server's host name or IP address, without the brackets around IPv6 addresses.
+    host: String,
+    port: u16,
+    /// The collection's path, always ending in `/`.
+    path: String,
+    authorization: Option<String>,
+    /// Collections that are known to exist.
+    collections: HashSet<String>,
+  }
+
+  impl WebDavBackend {
+    /// `url` is the collection to sync with, e.g. `http://studio.local:8080/presets/` or
+    /// `http://[fd00::1]/presets/`.
+    pub fn new(url: &str) -> Result<Self, LumatoneError> {
+      let invalid = || {
+        report!(LumatoneError::SyncFailed(format!(
+          "invalid WebDAV url {url}"
+        )))
+      };
+      let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+      let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+      let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+          let (host, port) = bracketed.split_once(']').ok_or_else(invalid)?;
+          host.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+          (host, port)
+        }
+        None => match authority.find(':') {
+          Some(i) => authority.split_at(i),
+          None => (authority, ""),
+        },
+      };
+      let port = match port {
+        "" => 80,
+        port => port
+          .strip_prefix(':')
+          .and_then(|p| p.parse().ok())
+          .ok_or_else(invalid)?,
+      };
+      if host.is_empty() {
+        return Err(invalid());
+      }
+      let path = format!("/{}/", path.trim_matches('/')).replace("//", "/");
+      Ok(WebDavBackend {
+        host: host.to_string(),
+        port,
+        path,
+        authorization: None,
+        collections: HashSet::new(),
+      })
+    }
+
+    /// Uses HTTP basic authentication. Requests go over plain HTTP, so this is refused
+    /// unless the server is on the loopback interface, e.g. at the near end of an SSH or
+    /// TLS tunnel.
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Result<Self, LumatoneError> {
+      let loopback = self.host.eq_ignore_ascii_case("localhost")
+        || self
+          .host
+          .parse::<IpAddr>()
+          .map_or(false, |ip| ip.is_loopback());
+      if !loopback {
+        bail!(LumatoneError::SyncFailed(format!(
+          "refusing to send credentials to {} over plain HTTP",
+          self.host
+        )));
+      }
+      let credentials = base64::encode(format!("{user}:{password}"));
+      self.authorization = Some(format!("Basic {credentials}"));
+      Ok(self)
+    }
+
+    /// The value of the Host header.
+    fn authority(&self) -> String {
+      if self.host.contains(':') {
+        format!("[{}]:{}", self.host, self.port)
+      } else {
+        format!("{}:{}", self.host, self.port)
+      }
+    }
+
+    fn request(
+      &self,
+      method: &str,
+      path: &str,
+      headers: &[(&str, &str)],
+      body: &[u8],
+    ) -> Result<(u16, Vec<u8>), LumatoneError> {
+      let failed = || LumatoneError::SyncFailed(format!("{method} {path} failed"));
+      let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+        .report()
+        .change_context_lazy(failed)?;
+      stream
+        .set_read_timeout(Some(TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+        .report()
+        .change_context_lazy(failed)?;
+
+      let mut head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        self.authority(),
+        body.len()
+      );
+      if let Some(auth) = &self.authorization {
+        head.push_str(&format!("Authorization: {auth}\r\n"));
+      }
+      for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+      }
+      head.push_str("\r\n");
+      stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body))
+        .report()
+        .change_context_lazy(failed)?;
+
+      let mut response = Vec::new();
+      stream
+        .read_to_end(&mut response)
+        .report()
+        .change_context_lazy(failed)?;
+      parse_response(&response).change_context_lazy(failed)
+    }
+
+    /// The hrefs of the members of a collection, or nothing if it doesn't exist.
+    fn list_collection(&mut self, path: &str) -> Result<Vec<String>, LumatoneError> {
+      let (status, body) = self.request(
+        "PROPFIND",
+        path,
+        &[("Depth", "1"), ("Content-Type", "application/xml")],
+        b"",
+      )?;
+      match status {
+        404 => return Ok(vec![]),
+        200..=299 => {}
+        _ => bail!(LumatoneError::SyncFailed(format!(
+          "listing {path} returned HTTP {status}"
+        ))),
+      }
+      self.collections.insert(path.to_string());
+      hrefs(&String::from_utf8_lossy(&body))
+    }
+
+    /// Creates `path` and the collections above it that aren't known to exist, starting
+    /// from the backend's root collection.
+    fn create_collections(&mut self, path: &str) -> Result<(), LumatoneError> {
+      let relative = path.strip_prefix(&self.path).unwrap_or_default();
+      let mut collection = self.path.clone();
+      for segment in std::iter::once("").chain(relative.split_terminator('/')) {
+        if !segment.is_empty() {
+          collection = format!("{collection}{segment}/");
+        }
+        if self.collections.contains(&collection) {
+          continue;
+        }
+        // 405 means the collection already exists
+        let (status, _) = self.request("MKCOL", &collection, &[], b"")?;
+        if !(200..=299).contains(&status) && status != 405 {
+          bail!(LumatoneError::SyncFailed(format!(
+            "creating {collection} returned HTTP {status}"
+          )));
+        }
+        self.collections.insert(collection.clone());
+      }
+      Ok(())
+    }
+
+    fn blob_path(&self, hash: &ContentHash) -> String {
+      format!("{}{hash}", self.path)
+    }
+
+    fn refs_path(&self, kind: AssetKind) -> String {
+      format!("{}{REFS_DIR}/{}/", self.path, kind.dir())
+    }
+
+    fn ref_path(&self, kind: AssetKind, name: &str) -> String {
+      format!("{}{}", self.refs_path(kind), percent_encode(name))
+    }
+  }
+
+  impl SyncBackend for WebDavBackend {
+    fn list(&mut self) -> Result<Vec<ContentHash>, LumatoneError> {
+      let path = self.path.clone();
+      let mut hashes = self
+        .list_collection(&path)?
+        .iter()
+        .filter_map(|href| href.rsplit('/').next()?.parse().ok())
+        .collect::<Vec<ContentHash>>();
+      hashes.sort();
+      hashes.dedup();
+      Ok(hashes)
+    }
+
+    fn push(&mut self, hash: &ContentHash, contents: &[u8]) -> Result<(), LumatoneError> {
+      let root = self.path.clone();
+      self.create_collections(&root)?;
+      let path = self.blob_path(hash);
+      let (status, _) = self.request(
+        "PUT",
+        &path,
+        &[("Content-Type", "application/octet-stream")],
+        contents,
+      )?;
+      if !(200..=299).contains(&status) {
+        bail!(LumatoneError::SyncFailed(format!(
+          "uploading {path} returned HTTP {status}"
+        )));
+      }
+      Ok(())
+    }
+
+    fn pull(&mut self, hash: &ContentHash) -> Result<Vec<u8>, LumatoneError> {
+      let path = self.blob_path(hash);
+      let (status, body) = self.request("GET", &path, &[], b"")?;
+      if !(200..=299).contains(&status) {
+        bail!(LumatoneError::SyncFailed(format!(
+          "downloading {path} returned HTTP {status}"
+        )));
+      }
+      Ok(body)
+    }
+
+    fn list_refs(&mut self, kind: AssetKind) -> Result<Vec<String>, LumatoneError> {
+      let path = self.refs_path(kind);
+      let mut names = self
+        .list_collection(&path)?
+        .iter()
+        // the collection lists itself, and members that are collections end in a slash
+        .filter(|href| !href.ends_with('/'))
+        .filter_map(|href| percent_decode(href.rsplit('/').next()?))
+        .collect::<Vec<_>>();
+      names.sort();
+      names.dedup();
+      Ok(names)
+    }
+
+    fn pull_ref(&mut self, kind: AssetKind, name: &str) -> Result<Vec<ContentHash>, LumatoneError> {
+      let path = self.ref_path(kind, name);
+      let (status, body) = self.request("GET", &path, &[], b"")?;
+      match status {
+        404 => Ok(vec![]),
+        200..=299 => parse_history(&String::from_utf8_lossy(&body)),
+        _ => bail!(LumatoneError::SyncFailed(format!(
+          "downloading {path} returned HTTP {status}"
+        ))),
+      }
+    }
+
+    fn push_ref(
+      &mut self,
+      kind: AssetKind,
+      name: &str,
+      history: &[ContentHash],
+    ) -> Result<(), LumatoneError> {
+      let refs = self.refs_path(kind);
+      self.create_collections(&refs)?;
+      let path = self.ref_path(kind, name);
+      let (status, _) = self.request(
+        "PUT",
+        &path,
+        &[("Content-Type", "text/plain")],
+        format_history(history).as_bytes(),
+      )?;
+      if !(200..=299).contains(&status) {
+        bail!(LumatoneError::SyncFailed(format!(
+          "uploading {path} returned HTTP {status}"
+        )));
+      }
+      Ok(())
+    }
+  }
+
+  /// Splits an HTTP/1.1 response into its status code and (de-chunked) body.
+  fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>), LumatoneError> {
+    let invalid = || {
+      report!(LumatoneError::SyncFailed(
+        "malformed HTTP response".to_string()
+      ))
+    };
+    let split = response
+      .windows(4)
+      .position(|w| w == b"\r\n\r\n")
+      .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+
+    let mut lines = head.lines();
+    let status = lines
+      .next()
+      .and_then(|l| l.split_whitespace().nth(1))
+      .and_then(|s| s.parse().ok())
+      .ok_or_else(invalid)?;
+    let chunked = lines.any(|l| {
+      let l = l.to_ascii_lowercase();
+      l.starts_with("transfer-encoding:") && l.contains("chunked")
+    });
+    if !chunked {
+      return Ok((status, body.to_vec()));
+    }
+
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+      let line_end = rest
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .ok_or_else(invalid)?;
+      let size = String::from_utf8_lossy(&rest[..line_end]);
+      let size =
+        usize::from_str_radix(size.split(';').next().unwrap().trim(), 16).map_err(|_| invalid())?;
+      rest = &rest[line_end + 2..];
+      if size == 0 {
+        return Ok((status, decoded));
+      }
+      if rest.len() < size {
+        return Err(invalid());
+      }
+      decoded.extend_from_slice(&rest[..size]);
+      rest = rest.get(size + 2..).unwrap_or_default();
+    }
+  }
+
+  /// The contents of every `DAV:href` element in a PROPFIND response.
+  fn hrefs(xml: &str) -> Result<Vec<String>, LumatoneError> {
+    let invalid = |e: quick_xml::Error| {
+      report!(LumatoneError::SyncFailed(format!(
+        "malformed PROPFIND response: {e}"
+      )))
+    };
+    let mut reader = NsReader::from_str(xml);
+    reader.trim_text(true);
+    let mut hrefs = Vec::new();
+    let mut in_href = false;
+    loop {
+      match reader.read_resolved_event().map_err(invalid)? {
+        (ResolveResult::Bound(Namespace(b"DAV:")), Event::Start(e))
+          if e.local_name().as_ref() == b"href" =>
+        {
+          in_href = true;
+        }
+        (_, Event::Text(text)) if in_href => {
+          hrefs.push(text.unescape().map_err(invalid)?.trim().to_string());
+        }
+        (_, Event::End(_)) => in_href = false,
+        (_, Event::Eof) => return Ok(hrefs),
+        _ => {}
+      }
+    }
+  }
+
+  /// Escapes everything but unreserved characters, so `name` can be used as one path
+  /// segment.
+  fn percent_encode(name: &str) -> String {
+    name
+      .bytes()
+      .map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+          (b as char).to_string()
+        }
+        _ => format!("%{b:02X}"),
+      })
+      .collect()
+  }
+
+  fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+      if bytes[i] == b'%' {
+        let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        i += 3;
+      } else {
+        decoded.push(bytes[i]);
+        i += 1;
+      }
+    }
+    String::from_utf8(decoded).ok()
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test]
+    fn test_webdav_url_and_response_parsing() {
+      let backend = WebDavBackend::new("http://studio.local:8080/presets").unwrap();
+      assert_eq!(
+        (backend.host.as_str(), backend.port, backend.path.as_str()),
+        ("studio.local", 8080, "/presets/")
+      );
+      assert_eq!(WebDavBackend::new("http://nas").unwrap().path, "/");
+      assert!(WebDavBackend::new("https://nas/presets").is_err());
+
+      let ipv6 = WebDavBackend::new("http://[fd00::1]:8080/presets/").unwrap();
+      assert_eq!((ipv6.host.as_str(), ipv6.port), ("fd00::1", 8080));
+      assert_eq!(ipv6.authority(), "[fd00::1]:8080");
+      assert_eq!(WebDavBackend::new("http://[::1]/").unwrap().port, 80);
+      assert!(WebDavBackend::new("http://::1/presets").is_err());
+      assert!(WebDavBackend::new("http://[::1/presets").is_err());
+
+      // credentials only go to the loopback interface
+      assert!(WebDavBackend::new("http://nas/presets")
+        .unwrap()
+        .with_credentials("me", "secret")
+        .is_err());
+      assert!(WebDavBackend::new("http://[::1]:8080/presets")
+        .unwrap()
+        .with_credentials("me", "secret")
+        .is_ok());
+
+      let response = b"HTTP/1.1 207 Multi-Status\r\nTransfer-Encoding: chunked\r\n\r\n\
+        5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+      assert_eq!(
+        parse_response(response).unwrap(),
+        (207, b"hello world".to_vec())
+      );
+    }
+
+    #[test]
+    fn test_propfind_hrefs() {
+      let hash = ContentHash::of(b"keymap");
+      let xml = format!(
+        "<?xml version=\"1.0\"?>\
+         <multistatus xmlns=\"DAV:\" xmlns:x=\"urn:other\">\
+         <response><href>/presets/</href></response>\
+         <response><href>\n  /presets/{hash}\n</href><x:href>/presets/ignored</x:href></response>\
+         <response><href>/presets/refs/keymaps/31%20EDO%20%26%20friends</href></response>\
+         <response><href>/presets/a&amp;b</href></response>\
+         </multistatus>"
+      );
+      assert_eq!(
+        hrefs(&xml).unwrap(),
+        vec![
+          "/presets/".to_string(),
+          format!("/presets/{hash}"),
+          "/presets/refs/keymaps/31%20EDO%20%26%20friends".to_string(),
+          "/presets/a&b".to_string(),
+        ]
+      );
+      assert!(
+        hrefs("<d:multistatus xmlns:d=\"DAV:\"><d:href>x</d:response></d:multistatus>").is_err()
+      );
+
+      let name = "31 EDO & friends";
+      assert_eq!(percent_encode(name), "31%20EDO%20%26%20friends");
+      assert_eq!(percent_decode(&percent_encode(name)).unwrap(), name);
+      assert!(percent_decode("bad%2").is_none());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_sync_libraries_through_filesystem_backend() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let studio = Library::open(root.join("studio")).unwrap();
+    let laptop = Library::open(root.join("laptop")).unwrap();
+    let mut remote = FilesystemBackend::new(root.join("remote"));
+
+    let a = studio.put(b"[Board0]\n").unwrap();
+    let b = laptop.put(b"[Board1]\n").unwrap();
+
+    let report = sync(&studio, &mut remote).unwrap();
+    assert_eq!(report.pushed, vec![a]);
+    assert!(report.pulled.is_empty());
+
+    let report = sync(&laptop, &mut remote).unwrap();
+    assert_eq!(report.pushed, vec![b]);
+    assert_eq!(report.pulled, vec![a]);
+    assert_eq!(laptop.get(&a).unwrap(), b"[Board0]\n");
+
+    let report = sync(&studio, &mut remote).unwrap();
+    assert_eq!(report.pulled, vec![b]);
+    assert_eq!(sync(&studio, &mut remote).unwrap(), SyncReport::default());
+
+    // a corrupted remote blob isn't pulled
+    let fresh = Library::open(root.join("fresh")).unwrap();
+    std::fs::write(root.join("remote").join(a.to_string()), b"tampered").unwrap();
+    assert!(sync(&fresh, &mut remote).is_err());
+    assert!(!fresh.contains(&a));
+  }
+
+  #[test]
+  fn test_sync_refs_and_history_between_libraries() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let studio = Library::open(root.join("studio")).unwrap();
+    let laptop = Library::open(root.join("laptop")).unwrap();
+    let mut remote = FilesystemBackend::new(root.join("remote"));
+
+    let v1 = studio
+      .store(AssetKind::Keymap, "31 EDO", b"[Board0]\n")
+      .unwrap();
+    let v2 = studio
+      .store(AssetKind::Keymap, "31 EDO", b"[Board1]\n")
+      .unwrap();
+    studio
+      .store(AssetKind::Tuning, "meantone", b"! meantone.scl\n")
+      .unwrap();
+
+    let report = sync(&studio, &mut remote).unwrap();
+    assert_eq!(report.pushed_refs.len(), 2);
+    assert!(report.pulled_refs.is_empty());
+    let report = sync(&laptop, &mut remote).unwrap();
+    assert_eq!(
+      report.pulled_refs,
+      vec![
+        (AssetKind::Keymap, "31 EDO".to_string()),
+        (AssetKind::Tuning, "meantone".to_string())
+      ]
+    );
+    assert_eq!(
+      laptop.history(AssetKind::Keymap, "31 EDO").unwrap(),
+      vec![v1, v2]
+    );
+    assert_eq!(
+      laptop.load(AssetKind::Keymap, "31 EDO").unwrap().unwrap(),
+      b"[Board1]\n"
+    );
+
+    // both sides edit the keymap; the edit synced last becomes current everywhere
+    let studio_edit = studio
+      .store(AssetKind::Keymap, "31 EDO", b"[Board2]\n")
+      .unwrap();
+    let laptop_edit = laptop
+      .store(AssetKind::Keymap, "31 EDO", b"[Board3]\n")
+      .unwrap();
+    sync(&studio, &mut remote).unwrap();
+    sync(&laptop, &mut remote).unwrap();
+    let report = sync(&studio, &mut remote).unwrap();
+    assert_eq!(
+      report.pulled_refs,
+      vec![(AssetKind::Keymap, "31 EDO".to_string())]
+    );
+    let merged = vec![v1, v2, studio_edit, laptop_edit];
+    assert_eq!(studio.history(AssetKind::Keymap, "31 EDO").unwrap(), merged);
+    assert_eq!(laptop.history(AssetKind::Keymap, "31 EDO").unwrap(), merged);
+    assert_eq!(sync(&laptop, &mut remote).unwrap(), SyncReport::default());
+  }
+
+  #[test]
+  fn test_merge_histories() {
+    let [a, b, c, d] = [b"a", b"b", b"c", b"d"].map(|c| ContentHash::of(&c[..]));
+    assert_eq!(merge_histories(&[a], &[a, b]), vec![a, b]);
+    assert_eq!(merge_histories(&[a, b], &[a]), vec![a, b]);
+    assert_eq!(merge_histories(&[], &[a]), vec![a]);
+    assert_eq!(merge_histories(&[a, c], &[a, b]), vec![a, b, c]);
+    assert_eq!(merge_histories(&[a, b, d], &[a, c, b]), vec![a, c, b, d]);
+  }
+}
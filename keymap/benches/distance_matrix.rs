@@ -0,0 +1,59 @@
+//! Compares looking up key distances in the [DistanceMatrix] with computing them from
+//! coordinates on every query, over all 280 × 280 pairs of keys.
+
+use std::{hint::black_box, time::Instant};
+
+use lumatone_keymap::geometry::{DistanceMatrix, KeyCoord};
+use lumatone_midi::constants::LumatoneKeyLocation;
+
+const ROUNDS: u32 = 20;
+
+fn time<F: FnMut() -> i64>(name: &str, mut f: F) {
+  let start = Instant::now();
+  let mut total = 0;
+  for _ in 0..ROUNDS {
+    total += black_box(f());
+  }
+  println!(
+    "{name:<24} {:>10.3?} per round (checksum {total})",
+    start.elapsed() / ROUNDS
+  );
+}
+
+fn main() {
+  let keys = LumatoneKeyLocation::all();
+
+  let start = Instant::now();
+  black_box(DistanceMatrix::compute());
+  println!("{:<24} {:>10.3?}", "compute matrix", start.elapsed());
+
+  let matrix = DistanceMatrix::get();
+  time("matrix steps", || {
+    let mut sum = 0;
+    for a in &keys {
+      for b in &keys {
+        sum += matrix.steps(*a, *b).unwrap() as i64;
+      }
+    }
+    sum
+  });
+  time("coordinate steps", || {
+    let mut sum = 0;
+    for a in &keys {
+      for b in &keys {
+        let (a, b) = (KeyCoord::of(*a).unwrap(), KeyCoord::of(*b).unwrap());
+        sum += a.distance(&b) as i64;
+      }
+    }
+    sum
+  });
+  time("matrix travel", || {
+    let mut sum = 0.0;
+    for a in &keys {
+      for b in &keys {
+        sum += matrix.travel(*a, *b).unwrap();
+      }
+    }
+    sum as i64
+  });
+}
@@ -0,0 +1,311 @@
+//! Checks that a keymap's colors can be told apart, by everyone and under stage lighting.
+//!
+//! [audit] compares every pair of colors a keymap uses by their distance in the Oklab color
+//! space (see [crate::gradient]), where a distance of about 0.02 is just noticeable. Pairs
+//! closer than [AuditOptions::min_difference] are reported, both with normal color vision
+//! and with each [color vision deficiency](Vision) simulated, using the matrices from
+//! Machado, Oliveira and Fernandes (2009) at full severity.
+//!
+//! Colors are compared as the key LEDs show them (see [LedGamut]): the LEDs don't light at
+//! all for very low channel values, so a dim color can look the same as an unlit key.
+//!
+//! Each violation comes with a suggested replacement if one can be found: the nearest color
+//! from the [high_contrast] and [large_step] palettes that passes against the others.
+
+use lumatone_midi::constants::{LumatoneKeyLocation, RGBColor};
+
+use super::{
+  gradient::{from_linear, to_linear, Oklab},
+  ltn::LumatoneKeyMap,
+};
+
+/// Color vision, normal or with a deficiency to simulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Vision {
+  Normal,
+  /// No red cones.
+  Protanopia,
+  /// No green cones, the most common deficiency.
+  Deuteranopia,
+  /// No blue cones.
+  Tritanopia,
+}
+
+impl Vision {
+  pub const ALL: [Vision; 4] = [
+    Vision::Normal,
+    Vision::Protanopia,
+    Vision::Deuteranopia,
+    Vision::Tritanopia,
+  ];
+
+  /// Returns the color as someone with this vision sees it.
+  pub fn simulate(&self, color: RGBColor) -> RGBColor {
+    let matrix = match self {
+      Vision::Normal => return color,
+      Vision::Protanopia => [
+        [0.152286, 1.052583, -0.204868],
+        [0.114503, 0.786281, 0.099216],
+        [-0.003882, -0.048116, 1.051998],
+      ],
+      Vision::Deuteranopia => [
+        [0.367322, 0.860646, -0.227968],
+        [0.280085, 0.672501, 0.047413],
+        [-0.011820, 0.042940, 0.968881],
+      ],
+      Vision::Tritanopia => [
+        [1.255528, -0.076749, -0.178779],
+        [-0.078411, 0.930809, 0.147602],
+        [0.004733, 0.691367, 0.303900],
+      ],
+    };
+    let RGBColor(r, g, b) = color;
+    let rgb = [to_linear(r), to_linear(g), to_linear(b)];
+    let [r, g, b] = matrix.map(|row| row.iter().zip(rgb).map(|(m, c)| m * c).sum::<f32>());
+    RGBColor(from_linear(r), from_linear(g), from_linear(b))
+  }
+}
+
+/// How the key LEDs reproduce colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedGamut {
+  /// Channel values below this don't light the LED.
+  pub black_level: u8,
+}
+
+impl Default for LedGamut {
+  fn default() -> Self {
+    LedGamut { black_level: 12 }
+  }
+}
+
+impl LedGamut {
+  /// Returns the color as the LEDs show it.
+  pub fn render(&self, color: RGBColor) -> RGBColor {
+    let channel = |c: u8| match c < self.black_level {
+      true => 0,
+      false => c,
+    };
+    RGBColor(channel(color.0), channel(color.1), channel(color.2))
+  }
+}
+
+/// Eight colors that stay distinct with each color vision deficiency: white and the
+/// Okabe-Ito palette (<https://jfly.uni-koeln.de/color/>), without its black, which an
+/// unlit key already shows.
+pub fn high_contrast() -> Vec<RGBColor> {
+  vec![
+    RGBColor(0xff, 0xff, 0xff),
+    RGBColor(0xe6, 0x9f, 0x00),
+    RGBColor(0x56, 0xb4, 0xe9),
+    RGBColor(0x00, 0x9e, 0x73),
+    RGBColor(0xf0, 0xe4, 0x42),
+    RGBColor(0x00, 0x72, 0xb2),
+    RGBColor(0xd5, 0x5e, 0x00),
+    RGBColor(0xcc, 0x79, 0xa7),
+  ]
+}
+
+/// `count` colors evenly spaced around the hue circle, alternating between light and dark
+/// so neighbors differ in lightness as well as hue, which survives color blindness.
+pub fn large_step(count: usize) -> Vec<RGBColor> {
+  (0..count)
+    .map(|i| {
+      let hue = std::f32::consts::TAU * i as f32 / count.max(1) as f32;
+      let lightness = match i % 2 {
+        0 => 0.85,
+        _ => 0.6,
+      };
+      Oklab(lightness, 0.12 * hue.cos(), 0.12 * hue.sin()).into()
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditOptions {
+  /// The smallest Oklab distance between colors that counts as distinct.
+  pub min_difference: f32,
+
+  /// The kinds of vision to check.
+  pub visions: Vec<Vision>,
+
+  pub gamut: LedGamut,
+}
+
+impl Default for AuditOptions {
+  fn default() -> Self {
+    AuditOptions {
+      // three times the just noticeable difference, for lit keys on a bright stage
+      min_difference: 0.06,
+      visions: Vision::ALL.to_vec(),
+      gamut: LedGamut::default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+  /// The LEDs don't light for this color, so it looks like an unlit key.
+  TooDim,
+
+  /// The color is hard to tell from `other` with `vision`.
+  TooSimilar {
+    other: RGBColor,
+    vision: Vision,
+    difference: f32,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Violation {
+  pub color: RGBColor,
+  pub problem: Problem,
+
+  /// A replacement for `color` that passes against the other colors, if there is one.
+  pub suggestion: Option<RGBColor>,
+}
+
+/// Audits the colors of the keys in `keymap` (see [audit]).
+pub fn audit_keymap(keymap: &LumatoneKeyMap, options: &AuditOptions) -> Vec<Violation> {
+  let colors: Vec<RGBColor> = LumatoneKeyLocation::all()
+    .into_iter()
+    .filter_map(|loc| keymap.get_key(loc).map(|def| def.color))
+    .collect();
+  audit(&colors, options)
+}
+
+/// Returns the problems with telling `colors` apart. Duplicate colors are only checked once,
+/// and unlit keys (black) are checked against the others but never reported themselves.
+pub fn audit(colors: &[RGBColor], options: &AuditOptions) -> Vec<Violation> {
+  let mut unique: Vec<RGBColor> = vec![];
+  for color in colors {
+    if !unique.contains(color) {
+      unique.push(*color);
+    }
+  }
+
+  let mut violations = vec![];
+  for (i, color) in unique.iter().enumerate() {
+    let others: Vec<RGBColor> = unique
+      .iter()
+      .enumerate()
+      .filter(|(j, _)| *j != i)
+      .map(|(_, c)| *c)
+      .collect();
+    let mut problems = vec![];
+    if is_lit(color) && !is_lit(&options.gamut.render(*color)) {
+      problems.push(Problem::TooDim);
+    }
+    // each pair is reported once, against the later color
+    problems.extend(
+      unique[..i]
+        .iter()
+        .filter_map(|other| closest_vision(*color, *other, options))
+        .map(|(other, vision, difference)| Problem::TooSimilar {
+          other,
+          vision,
+          difference,
+        }),
+    );
+    if problems.is_empty() || !is_lit(color) {
+      continue;
+    }
+    let suggestion = suggest(*color, &others, options);
+    violations.extend(problems.into_iter().map(|problem| Violation {
+      color: *color,
+      problem,
+      suggestion,
+    }));
+  }
+  violations
+}
+
+fn is_lit(color: &RGBColor) -> bool {
+  *color != RGBColor(0, 0, 0)
+}
+
+/// The Oklab distance between two colors as the LEDs show them and `vision` sees them.
+fn difference(a: RGBColor, b: RGBColor, vision: Vision, gamut: &LedGamut) -> f32 {
+  let Oklab(l1, a1, b1) = vision.simulate(gamut.render(a)).into();
+  let Oklab(l2, a2, b2) = vision.simulate(gamut.render(b)).into();
+  ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+}
+
+/// Returns the vision that finds `color` and `other` hardest to tell apart, if they're too
+/// similar with it.
+fn closest_vision(
+  color: RGBColor,
+  other: RGBColor,
+  options: &AuditOptions,
+) -> Option<(RGBColor, Vision, f32)> {
+  options
+    .visions
+    .iter()
+    .map(|vision| (*vision, difference(color, other, *vision, &options.gamut)))
+    .filter(|(_, d)| *d < options.min_difference)
+    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    .map(|(vision, d)| (other, vision, d))
+}
+
+/// The palette color nearest to `color` that passes against every one of `others`.
+fn suggest(color: RGBColor, others: &[RGBColor], options: &AuditOptions) -> Option<RGBColor> {
+  high_contrast()
+    .into_iter()
+    .chain(large_step(12))
+    .filter(|candidate| {
+      is_lit(&options.gamut.render(*candidate))
+        && others
+          .iter()
+          .all(|other| closest_vision(*candidate, *other, options).is_none())
+    })
+    .min_by(|a, b| {
+      let da = difference(color, *a, Vision::Normal, &options.gamut);
+      let db = difference(color, *b, Vision::Normal, &options.gamut);
+      da.total_cmp(&db)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_audit_finds_confusable_colors() {
+    let options = AuditOptions::default();
+    assert!(audit(&high_contrast(), &options).is_empty());
+    assert!(audit(&large_step(6), &options).is_empty());
+
+    // red and a green of similar lightness look alike without green cones
+    let red = RGBColor(0xd0, 0x40, 0x20);
+    let green = RGBColor(0x70, 0x70, 0x10);
+    let white = RGBColor(0xff, 0xff, 0xff);
+    let dim = RGBColor(0x08, 0x00, 0x08);
+    let violations = audit(
+      &[red, green, white, green, dim, RGBColor(0, 0, 0)],
+      &options,
+    );
+
+    let similar = violations
+      .iter()
+      .find(|v| matches!(v.problem, Problem::TooSimilar { .. }))
+      .unwrap();
+    assert!(matches!(
+      similar.problem,
+      Problem::TooSimilar {
+        vision: Vision::Protanopia | Vision::Deuteranopia,
+        ..
+      }
+    ));
+    let replacement = similar.suggestion.unwrap();
+    assert!(audit(&[red, green, white, replacement], &options)
+      .iter()
+      .all(|v| v.color != replacement));
+
+    assert!(violations
+      .iter()
+      .any(|v| v.color == dim && v.problem == Problem::TooDim));
+    assert!(!violations
+      .iter()
+      .any(|v| v.color == white || v.color == RGBColor(0, 0, 0)));
+  }
+}
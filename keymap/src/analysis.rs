@@ -0,0 +1,174 @@
+//! Compares a [Tuning] with 12-TET, for driving hardware that only knows 12-TET and for
+//! working out pitch-bend offsets.
+//!
+//! A [DeviationReport] lists, for a range of pitch indices, the nearest 12-TET note and the
+//! deviation from it in cents. The nearest notes form the best 12-TET approximation of the
+//! tuning; [DeviationReport::collisions] shows where that approximation maps more than one
+//! pitch to the same note.
+
+use std::{collections::BTreeMap, fmt::Display, ops::Range};
+
+use super::{
+  preferences::DisplayPreferences,
+  spelling::SpellingPolicy,
+  tuning::{nearest_12tet, Tuning},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteDeviation {
+  /// The pitch index in the tuning.
+  pub index: i32,
+  pub degree: usize,
+
+  /// The pitch, in cents above the tuning's root.
+  pub cents: f64,
+
+  /// The nearest 12-TET MIDI note.
+  pub nearest_note: i32,
+
+  /// The pitch's deviation from `nearest_note`, in cents, between -50 and +50.
+  pub deviation: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviationReport {
+  pub notes: Vec<NoteDeviation>,
+
+  /// How to spell note names, from the tuning.
+  pub spelling: SpellingPolicy,
+
+  /// The number of decimal places cents are written with.
+  pub cents_precision: usize,
+}
+
+impl DeviationReport {
+  /// Analyses the pitch indices in `indices`.
+  pub fn new(tuning: &Tuning, indices: Range<i32>) -> Self {
+    let notes = indices
+      .map(|index| {
+        let (nearest_note, deviation) = nearest_12tet(tuning.midi_pitch(index));
+        NoteDeviation {
+          index,
+          degree: tuning.degree(index).0,
+          cents: tuning.cents(index),
+          nearest_note,
+          deviation,
+        }
+      })
+      .collect();
+    DeviationReport {
+      notes,
+      spelling: tuning.spelling.clone(),
+      cents_precision: 2,
+    }
+  }
+
+  /// Names notes and writes cents as the user prefers.
+  pub fn with_preferences(mut self, prefs: &DisplayPreferences) -> Self {
+    prefs.apply_to_spelling(&mut self.spelling);
+    self.cents_precision = prefs.cents_precision;
+    self
+  }
+
+  /// The preferences the report is written with, for formatting its values.
+  pub(crate) fn display_preferences(&self) -> DisplayPreferences {
+    DisplayPreferences {
+      cents_precision: self.cents_precision,
+      ..Default::default()
+    }
+  }
+
+  /// Analyses one period of the tuning, starting at the root.
+  pub fn for_period(tuning: &Tuning) -> Self {
+    DeviationReport::new(
+      tuning,
+      tuning.root_index..tuning.root_index + tuning.len() as i32,
+    )
+  }
+
+  /// The largest deviation from 12-TET, ignoring sign.
+  pub fn max_deviation(&self) -> f64 {
+    self
+      .notes
+      .iter()
+      .map(|n| n.deviation.abs())
+      .fold(0.0, f64::max)
+  }
+
+  /// The mean deviation from 12-TET, ignoring sign.
+  pub fn mean_deviation(&self) -> f64 {
+    if self.notes.is_empty() {
+      return 0.0;
+    }
+    self.notes.iter().map(|n| n.deviation.abs()).sum::<f64>() / self.notes.len() as f64
+  }
+
+  /// Returns the nearest 12-TET note for each pitch index.
+  pub fn approximation(&self) -> BTreeMap<i32, i32> {
+    self
+      .notes
+      .iter()
+      .map(|n| (n.index, n.nearest_note))
+      .collect()
+  }
+
+  /// Returns each 12-TET note that's nearest to more than one pitch index, with those
+  /// indices. Hardware tuned to 12-TET can't tell these pitches apart.
+  pub fn collisions(&self) -> BTreeMap<i32, Vec<i32>> {
+    let mut by_note: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+    for n in &self.notes {
+      by_note.entry(n.nearest_note).or_default().push(n.index);
+    }
+    by_note.retain(|_, indices| indices.len() > 1);
+    by_note
+  }
+}
+
+impl Display for DeviationReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let prefs = self.display_preferences();
+    writeln!(f, "# index\tdegree\tcents\tnearest\tdeviation")?;
+    for n in &self.notes {
+      writeln!(
+        f,
+        "{}\t{}\t{}\t{}\t{}",
+        n.index,
+        n.degree,
+        prefs.cents(n.cents),
+        self.spelling.note_name(n.nearest_note),
+        prefs.deviation(n.deviation)
+      )?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_deviation_report() {
+    let report = DeviationReport::for_period(&Tuning::equal(24));
+    assert_eq!(report.notes.len(), 24);
+    assert!((report.max_deviation() - 50.0).abs() < 1e-9);
+    assert!((report.mean_deviation() - 25.0).abs() < 1e-9);
+    assert_eq!(report.approximation()[&61], 61);
+
+    let collisions = report.collisions();
+    // quarter tones round up to the semitone above
+    assert_eq!(collisions[&61], vec![61, 62]);
+    assert_eq!(collisions.len(), 11);
+
+    let prefs = DisplayPreferences {
+      cents_precision: 0,
+      ..Default::default()
+    };
+    let text = report.clone().with_preferences(&prefs).to_string();
+    assert_eq!(text.lines().nth(2), Some("61\t1\t50\tC#4\t-50"));
+
+    let edo = DeviationReport::for_period(&Tuning::equal(12));
+    assert!(edo.max_deviation() < 1e-9);
+    assert!(edo.collisions().is_empty());
+  }
+}
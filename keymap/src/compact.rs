@@ -0,0 +1,529 @@
+//! A compact binary form of [LumatoneKeyMap]s, for storing and moving lots of them.
+//!
+//! A .ltn file spends four `Name_N=value` lines on each of the 280 keys, around 13 KB for a
+//! full keymap. Most of that is predictable: layouts tend to use a few colors, and most keys
+//! play a note on their board's channel. So the compact form stores:
+//!
+//! - which keys are defined, as a bitmap.
+//! - each key's function, but only if it differs from the default board, where key `k` on
+//!   board `b` plays note `k` on channel `b`. Another bitmap says which keys differ.
+//! - a palette of the keymap's colors, and each key's index into it.
+//! - the general options and tables, as raw bytes.
+//!
+//! A full keymap takes under a kilobyte, and less with [Compression::Zstd] (with the `zstd`
+//! feature). The cli crate's `keymap_size` benchmark compares the sizes with the .ltn and
+//! JSON forms for some typical layouts.
+//!
+//! ```text
+//! "LTNK"  version  flags  body (zstd-compressed if flags & 1)
+//! ```
+//!
+//! Encoding is deterministic, so equal keymaps always encode to the same bytes (with the
+//! same compression), and the bytes can be content-addressed.
+
+use lumatone_midi::{
+  constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, RGBColor},
+  responses::PeripheralChannels,
+  sysex::{SysexTable, VelocityIntervalTable},
+};
+
+use super::{
+  error::LumatoneKeymapError,
+  ltn::{GeneralOptions, KeyDefinition, LumatoneKeyMap, MacroButtonColors},
+  tables::{ConfigTableDefinition, ConfigurationTables, EditingStrategy, TableKind},
+};
+
+const MAGIC: &[u8; 4] = b"LTNK";
+const FORMAT_VERSION: u8 = 1;
+
+const FLAG_ZSTD: u8 = 1;
+
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 19;
+
+/// The most a compressed body may expand to. Even a keymap with every key and table set is
+/// a few kilobytes, so anything bigger (e.g. a decompression bomb uploaded to a server)
+/// isn't a keymap.
+#[cfg(feature = "zstd")]
+const MAX_DECOMPRESSED: u64 = 64 * 1024;
+
+const KEY_COUNT: usize = 280;
+const BITMAP_LEN: usize = KEY_COUNT / 8;
+
+/// The file extension for compact keymaps.
+pub const COMPACT_EXTENSION: &str = "ltnk";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+  None,
+
+  /// zstd, which about halves the size of a typical keymap again.
+  #[cfg(feature = "zstd")]
+  Zstd,
+}
+
+/// [Compression::Zstd] if it's available.
+#[cfg(feature = "zstd")]
+impl Default for Compression {
+  fn default() -> Self {
+    Compression::Zstd
+  }
+}
+
+#[cfg(not(feature = "zstd"))]
+impl Default for Compression {
+  fn default() -> Self {
+    Compression::None
+  }
+}
+
+/// Whether `bytes` look like a compact keymap rather than a .ltn file.
+pub fn is_compact(bytes: &[u8]) -> bool {
+  bytes.starts_with(MAGIC)
+}
+
+impl LumatoneKeyMap {
+  /// Encodes the keymap in the compact form. See the [module docs](self).
+  pub fn to_compact(&self, compression: Compression) -> Vec<u8> {
+    let body = encode_body(self);
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(FORMAT_VERSION);
+    match compression {
+      Compression::None => {
+        bytes.push(0);
+        bytes.extend(body);
+      }
+      #[cfg(feature = "zstd")]
+      Compression::Zstd => {
+        bytes.push(FLAG_ZSTD);
+        bytes.extend(
+          zstd::encode_all(body.as_slice(), ZSTD_LEVEL).expect("compressing in memory can't fail"),
+        );
+      }
+    }
+    bytes
+  }
+
+  pub fn from_compact(bytes: &[u8]) -> Result<LumatoneKeyMap, LumatoneKeymapError> {
+    if !is_compact(bytes) || bytes.len() < MAGIC.len() + 2 {
+      return Err(invalid("not a compact keymap"));
+    }
+    let version = bytes[MAGIC.len()];
+    if version > FORMAT_VERSION {
+      return Err(invalid(&format!(
+        "format version {version} is newer than this version supports"
+      )));
+    }
+    let flags = bytes[MAGIC.len() + 1];
+    let body = &bytes[MAGIC.len() + 2..];
+    match flags & FLAG_ZSTD {
+      0 => decode_body(body),
+      _ => decode_body(&decompress(body)?),
+    }
+  }
+
+  /// Reads a keymap in either the compact form or as .ltn text.
+  #[cfg(feature = "ltn")]
+  pub fn from_bytes(bytes: &[u8]) -> Result<LumatoneKeyMap, LumatoneKeymapError> {
+    if is_compact(bytes) {
+      return LumatoneKeyMap::from_compact(bytes);
+    }
+    let text = std::str::from_utf8(bytes).map_err(|_| LumatoneKeymapError::ValueParseError)?;
+    LumatoneKeyMap::from_ini_str(text)
+  }
+}
+
+/// Decompresses a body, stopping as soon as it's bigger than [MAX_DECOMPRESSED].
+#[cfg(feature = "zstd")]
+fn decompress(body: &[u8]) -> Result<Vec<u8>, LumatoneKeymapError> {
+  use std::io::Read;
+
+  let decoder = zstd::Decoder::new(body).map_err(|e| invalid(&e.to_string()))?;
+  let mut decompressed = Vec::new();
+  decoder
+    .take(MAX_DECOMPRESSED + 1)
+    .read_to_end(&mut decompressed)
+    .map_err(|e| invalid(&e.to_string()))?;
+  if decompressed.len() as u64 > MAX_DECOMPRESSED {
+    return Err(invalid(&format!(
+      "decompresses to more than {MAX_DECOMPRESSED} bytes"
+    )));
+  }
+  Ok(decompressed)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_body: &[u8]) -> Result<Vec<u8>, LumatoneKeymapError> {
+  Err(invalid("zstd-compressed, and zstd support isn't enabled"))
+}
+
+fn invalid(msg: &str) -> LumatoneKeymapError {
+  LumatoneKeymapError::InvalidCompactKeymap(msg.to_string())
+}
+
+/// The function of `location` on the default board.
+fn default_function(location: LumatoneKeyLocation) -> LumatoneKeyFunction {
+  LumatoneKeyFunction::NoteOnOff {
+    channel: MidiChannel::unchecked(location.board_index() as u8),
+    note_num: location.key_index().get(),
+  }
+}
+
+fn edit_strategy_code(strategy: &EditingStrategy) -> u8 {
+  match strategy {
+    EditingStrategy::FreeDrawing => 0,
+    EditingStrategy::LinearSegments => 1,
+    EditingStrategy::QuadraticCurves => 2,
+  }
+}
+
+fn table_slots(tables: &ConfigurationTables) -> [Option<&ConfigTableDefinition>; 4] {
+  TableKind::ALL.map(|kind| tables.get(kind))
+}
+
+fn encode_body(keymap: &LumatoneKeyMap) -> Vec<u8> {
+  let opts = keymap.global_options();
+  let mut out = vec![];
+
+  let flags = [
+    opts.after_touch_active,
+    opts.light_on_key_strokes,
+    opts.invert_foot_controller,
+    opts.invert_sustain,
+    opts.macro_button_colors.is_some(),
+    opts.peripheral_channels.is_some(),
+  ];
+  out.push(pack_bits(&flags));
+  out.push(opts.expression_controller_sensitivity);
+  if let Some(colors) = &opts.macro_button_colors {
+    push_color(&mut out, colors.active);
+    push_color(&mut out, colors.inactive);
+  }
+  if let Some(c) = &opts.peripheral_channels {
+    for channel in [c.pitch_wheel, c.mod_wheel, c.expression, c.sustain] {
+      out.push(channel.get_as_zero_indexed());
+    }
+  }
+
+  let tables = table_slots(&opts.config_tables);
+  let mut present: Vec<bool> = tables.iter().map(Option::is_some).collect();
+  present.push(opts.config_tables.velocity_intervals.is_some());
+  out.push(pack_bits(&present));
+  for table in tables.into_iter().flatten() {
+    out.push(edit_strategy_code(&table.edit_strategy));
+    out.extend(table.table);
+  }
+  if let Some(intervals) = &opts.config_tables.velocity_intervals {
+    for v in intervals {
+      out.extend(v.to_le_bytes());
+    }
+  }
+
+  let keys: Vec<(LumatoneKeyLocation, Option<&KeyDefinition>)> = LumatoneKeyLocation::all()
+    .into_iter()
+    .map(|loc| (loc, keymap.get_key(loc)))
+    .collect();
+  let defined: Vec<bool> = keys.iter().map(|(_, def)| def.is_some()).collect();
+  let custom: Vec<bool> = keys
+    .iter()
+    .map(|(loc, def)| def.is_some_and(|d| d.function != default_function(*loc)))
+    .collect();
+  out.extend(pack_bitmap(&defined));
+  out.extend(pack_bitmap(&custom));
+  for ((_, def), is_custom) in keys.iter().zip(&custom) {
+    if let (Some(def), true) = (def, is_custom) {
+      out.push(def.function.type_code());
+      out.push(def.function.midi_channel_byte());
+      out.push(def.function.note_or_cc_num());
+    }
+  }
+
+  let mut palette: Vec<RGBColor> = vec![];
+  let mut indices = vec![];
+  for def in keys.iter().filter_map(|(_, def)| *def) {
+    let index = match palette.iter().position(|c| *c == def.color) {
+      Some(i) => i,
+      None => {
+        palette.push(def.color);
+        palette.len() - 1
+      }
+    };
+    indices.push(index);
+  }
+  out.extend((palette.len() as u16).to_le_bytes());
+  for color in palette.iter() {
+    push_color(&mut out, *color);
+  }
+  for i in indices {
+    match palette.len() > 256 {
+      true => out.extend((i as u16).to_le_bytes()),
+      false => out.push(i as u8),
+    }
+  }
+
+  out
+}
+
+fn decode_body(body: &[u8]) -> Result<LumatoneKeyMap, LumatoneKeymapError> {
+  let mut r = Reader { bytes: body };
+
+  let flags = r.u8()?;
+  let flag = |bit: u8| flags & (1 << bit) != 0;
+  let mut opts = GeneralOptions {
+    after_touch_active: flag(0),
+    light_on_key_strokes: flag(1),
+    invert_foot_controller: flag(2),
+    invert_sustain: flag(3),
+    expression_controller_sensitivity: r.u8()?,
+    ..Default::default()
+  };
+  if flag(4) {
+    opts.macro_button_colors = Some(MacroButtonColors {
+      active: r.color()?,
+      inactive: r.color()?,
+    });
+  }
+  if flag(5) {
+    opts.peripheral_channels = Some(PeripheralChannels {
+      pitch_wheel: channel_from_byte(r.u8()?)?,
+      mod_wheel: channel_from_byte(r.u8()?)?,
+      expression: channel_from_byte(r.u8()?)?,
+      sustain: channel_from_byte(r.u8()?)?,
+    });
+  }
+
+  let present = r.u8()?;
+  for (i, kind) in TableKind::ALL.into_iter().enumerate() {
+    if present & (1 << i) == 0 {
+      continue;
+    }
+    let edit_strategy = match r.u8()? {
+      0 => EditingStrategy::FreeDrawing,
+      1 => EditingStrategy::LinearSegments,
+      2 => EditingStrategy::QuadraticCurves,
+      _ => return Err(invalid("unknown table editing strategy")),
+    };
+    let table: SysexTable = r.take(128)?.try_into().unwrap();
+    opts.config_tables.set(
+      kind,
+      Some(ConfigTableDefinition::new_with_edit_strategy(
+        table,
+        edit_strategy,
+      )),
+    );
+  }
+  if present & (1 << TableKind::ALL.len()) != 0 {
+    let mut intervals: VelocityIntervalTable = [0; 127];
+    for v in intervals.iter_mut() {
+      *v = r.u16()?;
+    }
+    opts.config_tables.velocity_intervals = Some(intervals);
+  }
+
+  let defined = unpack_bitmap(r.take(BITMAP_LEN)?);
+  let custom = unpack_bitmap(r.take(BITMAP_LEN)?);
+  let mut functions = vec![];
+  let locations = LumatoneKeyLocation::all().into_iter().zip(&defined);
+  for ((loc, is_defined), is_custom) in locations.zip(&custom) {
+    if !is_defined {
+      continue;
+    }
+    let function = match is_custom {
+      false => default_function(loc),
+      true => {
+        let (type_code, channel, number) = (r.u8()?, channel_from_byte(r.u8()?)?, r.u8()?);
+        LumatoneKeyFunction::from_type_code(type_code, channel, number)
+          .ok_or_else(|| invalid(&format!("unknown key type {type_code}")))?
+      }
+    };
+    functions.push((loc, function));
+  }
+
+  let palette_len = r.u16()? as usize;
+  let mut palette = Vec::with_capacity(palette_len);
+  for _ in 0..palette_len {
+    palette.push(r.color()?);
+  }
+
+  let mut keymap = LumatoneKeyMap::new();
+  for (loc, function) in functions {
+    let index = match palette_len > 256 {
+      true => r.u16()? as usize,
+      false => r.u8()? as usize,
+    };
+    let color = *palette
+      .get(index)
+      .ok_or_else(|| invalid("color index out of range"))?;
+    keymap.set_key(loc, KeyDefinition { function, color });
+  }
+  if !r.bytes.is_empty() {
+    return Err(invalid("trailing bytes"));
+  }
+
+  keymap.set_global_options(opts);
+  Ok(keymap)
+}
+
+/// Reads a zero-indexed channel.
+fn channel_from_byte(byte: u8) -> Result<MidiChannel, LumatoneKeymapError> {
+  MidiChannel::new(byte.saturating_add(1)).ok_or_else(|| invalid("invalid MIDI channel"))
+}
+
+fn push_color(out: &mut Vec<u8>, color: RGBColor) {
+  let RGBColor(r, g, b) = color;
+  out.extend([r, g, b]);
+}
+
+fn pack_bits(bits: &[bool]) -> u8 {
+  bits
+    .iter()
+    .enumerate()
+    .fold(0, |byte, (i, b)| byte | ((*b as u8) << i))
+}
+
+fn pack_bitmap(bits: &[bool]) -> Vec<u8> {
+  bits.chunks(8).map(pack_bits).collect()
+}
+
+fn unpack_bitmap(bytes: &[u8]) -> Vec<bool> {
+  bytes
+    .iter()
+    .flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0))
+    .collect()
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8], LumatoneKeymapError> {
+    if self.bytes.len() < n {
+      return Err(invalid("truncated"));
+    }
+    let (taken, rest) = self.bytes.split_at(n);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn u8(&mut self) -> Result<u8, LumatoneKeymapError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, LumatoneKeymapError> {
+    let b = self.take(2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+  }
+
+  fn color(&mut self) -> Result<RGBColor, LumatoneKeymapError> {
+    let b = self.take(3)?;
+    Ok(RGBColor(b[0], b[1], b[2]))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::key_loc_unchecked;
+
+  fn example_keymap() -> LumatoneKeyMap {
+    let mut keymap = LumatoneKeyMap::new();
+    for loc in LumatoneKeyLocation::all() {
+      let key = loc.key_index().get();
+      let function = match key {
+        0 => LumatoneKeyFunction::LumaTouch {
+          channel: MidiChannel::unchecked(9),
+          note_num: 12,
+          fader_up_is_null: true,
+        },
+        1 => LumatoneKeyFunction::Disabled,
+        _ => default_function(loc),
+      };
+      let color = match key % 3 {
+        0 => RGBColor::red(),
+        1 => RGBColor(0x20, 0x40, 0x60),
+        _ => RGBColor::blue(),
+      };
+      keymap.set_key(loc, KeyDefinition { function, color });
+    }
+    let opts = keymap.global_options_mut();
+    opts.invert_sustain = true;
+    opts.expression_controller_sensitivity = 42;
+    opts.macro_button_colors = Some(MacroButtonColors {
+      active: RGBColor::green(),
+      inactive: RGBColor(1, 2, 3),
+    });
+    opts.peripheral_channels = Some(PeripheralChannels {
+      sustain: MidiChannel::unchecked(16),
+      ..Default::default()
+    });
+    opts.config_tables.set(
+      TableKind::FaderVelocity,
+      Some(ConfigTableDefinition::new_with_edit_strategy(
+        [7; 128],
+        EditingStrategy::QuadraticCurves,
+      )),
+    );
+    opts.config_tables.velocity_intervals = Some([300; 127]);
+    keymap
+  }
+
+  #[test]
+  fn test_compact_round_trip() {
+    let keymap = example_keymap();
+    let bytes = keymap.to_compact(Compression::None);
+    assert!(is_compact(&bytes));
+    // 280 keys plus two tables, in well under the size of one .ltn board section
+    assert!(bytes.len() < 1000, "{} bytes", bytes.len());
+
+    let decoded = LumatoneKeyMap::from_compact(&bytes).unwrap();
+    assert_eq!(decoded.fingerprint(), keymap.fingerprint());
+    assert_eq!(decoded.to_compact(Compression::None), bytes);
+
+    let sparse = {
+      let mut k = LumatoneKeyMap::new();
+      k.set_key(
+        key_loc_unchecked(3, 10),
+        KeyDefinition {
+          function: LumatoneKeyFunction::Disabled,
+          color: RGBColor::green(),
+        },
+      );
+      k
+    };
+    let decoded = LumatoneKeyMap::from_compact(&sparse.to_compact(Compression::None)).unwrap();
+    assert_eq!(decoded.fingerprint(), sparse.fingerprint());
+    assert!(decoded.get_key(key_loc_unchecked(3, 11)).is_none());
+
+    assert!(LumatoneKeyMap::from_compact(&bytes[..bytes.len() - 1]).is_err());
+    assert!(LumatoneKeyMap::from_compact(b"[Board0]\n").is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "zstd")]
+  fn test_compact_zstd() {
+    let keymap = example_keymap();
+    let plain = keymap.to_compact(Compression::None);
+    let compressed = keymap.to_compact(Compression::Zstd);
+    assert!(compressed.len() < plain.len());
+    let decoded = LumatoneKeyMap::from_compact(&compressed).unwrap();
+    assert_eq!(decoded.fingerprint(), keymap.fingerprint());
+
+    // a few hundred bytes that would expand to a megabyte
+    let mut bomb = compressed[..MAGIC.len() + 2].to_vec();
+    bomb.extend(zstd::encode_all(vec![0u8; 1 << 20].as_slice(), ZSTD_LEVEL).unwrap());
+    assert!(bomb.len() < 1024);
+    assert!(LumatoneKeyMap::from_compact(&bomb).is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "ltn")]
+  fn test_from_bytes_reads_either_form() {
+    let ltn = example_keymap().to_ini_string();
+    let from_ltn = LumatoneKeyMap::from_bytes(ltn.as_bytes()).unwrap();
+    let compact = from_ltn.to_compact(Compression::None);
+    let from_compact = LumatoneKeyMap::from_bytes(&compact).unwrap();
+    assert_eq!(from_ltn.fingerprint(), from_compact.fingerprint());
+    assert!(compact.len() * 5 < ltn.len());
+  }
+}
@@ -0,0 +1,143 @@
+//! Suggests equal divisions of the octave (EDOs) that approximate a just intonation scale,
+//! to help pick a practical tuning before laying it out on the board.
+//!
+//! Each degree of the scale is approximated by the nearest step of the EDO. An EDO is
+//! suggested if every degree is within the tolerance, and suggestions come back smallest
+//! first, since fewer divisions are easier to lay out and play. Scales whose period isn't an
+//! octave are divided at their own period.
+
+use std::collections::HashSet;
+
+use super::tuning::Tuning;
+
+/// How well one degree of a scale is approximated by an EDO.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntervalError {
+  pub degree: usize,
+
+  /// The degree's pitch in the scale, in cents above the root.
+  pub cents: f64,
+
+  /// The nearest step of the EDO.
+  pub steps: usize,
+
+  /// The EDO's pitch minus the scale's pitch, in cents.
+  pub error: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdoSuggestion {
+  pub divisions: usize,
+
+  /// One entry per degree of the scale, not including the root.
+  pub intervals: Vec<IntervalError>,
+}
+
+impl EdoSuggestion {
+  /// Approximates each degree of `scale` in `divisions`-EDO.
+  pub fn new(scale: &Tuning, divisions: usize) -> Self {
+    let step = scale.period / divisions as f64;
+    let intervals = scale
+      .degrees
+      .iter()
+      .enumerate()
+      .skip(1)
+      .map(|(degree, &cents)| {
+        let steps = (cents / step).round() as usize;
+        IntervalError {
+          degree,
+          cents,
+          steps,
+          error: steps as f64 * step - cents,
+        }
+      })
+      .collect();
+    EdoSuggestion {
+      divisions,
+      intervals,
+    }
+  }
+
+  /// The largest error of any degree, ignoring sign.
+  pub fn max_error(&self) -> f64 {
+    self
+      .intervals
+      .iter()
+      .map(|i| i.error.abs())
+      .fold(0.0, f64::max)
+  }
+
+  /// The mean error, ignoring sign.
+  pub fn mean_error(&self) -> f64 {
+    if self.intervals.is_empty() {
+      return 0.0;
+    }
+    self.intervals.iter().map(|i| i.error.abs()).sum::<f64>() / self.intervals.len() as f64
+  }
+
+  /// Returns the approximation as a tuning with the same root as the scale it was made
+  /// from, with one degree per step of the EDO.
+  pub fn to_tuning(&self, scale: &Tuning) -> Tuning {
+    Tuning {
+      name: format!("{}-EDO", self.divisions),
+      degrees: (0..self.divisions)
+        .map(|i| i as f64 * scale.period / self.divisions as f64)
+        .collect(),
+      ..scale.clone()
+    }
+  }
+}
+
+/// Returns every EDO with up to `max_divisions` steps that approximates each degree of
+/// `scale` within `tolerance` cents, smallest first.
+///
+/// EDOs where two degrees of the scale land on the same step are skipped.
+pub fn suggest_edos(scale: &Tuning, max_divisions: usize, tolerance: f64) -> Vec<EdoSuggestion> {
+  (1..=max_divisions)
+    .map(|divisions| EdoSuggestion::new(scale, divisions))
+    .filter(|s| {
+      // Scala files don't have to list degrees in order, so duplicates needn't be adjacent.
+      let mut seen = HashSet::new();
+      s.intervals
+        .iter()
+        .all(|i| i.steps != 0 && seen.insert(i.steps))
+        && s.max_error() <= tolerance
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_suggest_edos() {
+    // 5-limit major triad: 5/4, 3/2
+    let triad = Tuning::from_ratios("triad", &[(5, 4), (3, 2), (2, 1)]);
+
+    let suggestions = suggest_edos(&triad, 60, 10.0);
+    let divisions: Vec<usize> = suggestions.iter().map(|s| s.divisions).collect();
+    assert_eq!(divisions[..4], [19, 22, 31, 34]);
+
+    let edo19 = &suggestions[0];
+    assert_eq!(edo19.intervals[0].steps, 6);
+    assert_eq!(edo19.intervals[1].steps, 11);
+    assert!(edo19.max_error() < 8.0);
+    assert!(edo19.mean_error() <= edo19.max_error());
+    assert_eq!(edo19.to_tuning(&triad).len(), 19);
+
+    // 12-EDO's major third is 13.7 cents sharp
+    assert!(!divisions.contains(&12));
+  }
+
+  #[test]
+  fn test_suggest_edos_unsorted_degrees() {
+    // In 6-EDO the first and last degrees both land on step 1.
+    let scale = Tuning::from_scale("unsorted", &[200.0, 400.0, 204.0, 1200.0]);
+    let divisions: Vec<usize> = suggest_edos(&scale, 6, 100.0)
+      .iter()
+      .map(|s| s.divisions)
+      .collect();
+    assert!(!divisions.contains(&6));
+  }
+}
@@ -1,14 +1,30 @@
-use ini;
-
 #[derive(Debug)]
 pub enum LumatoneKeymapError {
   InvalidTableDefinition(String),
 
   ValueParseError,
 
+  InvalidPatch(String),
+
+  InvalidTemplate(String),
+
+  MissingTemplateVariable(String),
+
+  InvalidReveal(String),
+
+  InvalidScale(String),
+
+  InvalidTuningUpdate(String),
+
+  InvalidPreferences(String),
+
+  InvalidCompactKeymap(String),
+
+  #[cfg(feature = "ltn")]
   ParseError(ini::ParseError),
 }
 
+#[cfg(feature = "ltn")]
 impl From<ini::ParseError> for LumatoneKeymapError {
   fn from(err: ini::ParseError) -> Self {
     LumatoneKeymapError::ParseError(err)
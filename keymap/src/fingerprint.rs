@@ -0,0 +1,73 @@
+//! Stable content hashes for keymaps.
+//!
+//! [std::hash::Hash] isn't guaranteed to be stable across Rust versions (and the default
+//! hasher is randomly seeded), so fingerprints are computed with a fixed FNV-1a hash over an
+//! explicit encoding of the keymap contents. That makes them safe to persist, e.g. alongside
+//! backups or in a preset library index.
+
+use std::fmt::Display;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// A 64-bit content hash. Two keymaps with the same contents have the same fingerprint,
+/// regardless of the order their keys were defined in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint(pub u64);
+
+impl Display for Fingerprint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:016x}", self.0)
+  }
+}
+
+/// Incremental FNV-1a hasher.
+pub(crate) struct FingerprintHasher {
+  state: u64,
+}
+
+impl FingerprintHasher {
+  pub fn new() -> Self {
+    FingerprintHasher {
+      state: FNV_OFFSET_BASIS,
+    }
+  }
+
+  pub fn write(&mut self, bytes: &[u8]) -> &mut Self {
+    for b in bytes {
+      self.state ^= *b as u64;
+      self.state = self.state.wrapping_mul(FNV_PRIME);
+    }
+    self
+  }
+
+  pub fn write_u8(&mut self, b: u8) -> &mut Self {
+    self.write(&[b])
+  }
+
+  pub fn write_bool(&mut self, b: bool) -> &mut Self {
+    self.write_u8(b as u8)
+  }
+
+  pub fn finish(&self) -> Fingerprint {
+    Fingerprint(self.state)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fnv1a_known_values() {
+    assert_eq!(FingerprintHasher::new().finish().0, FNV_OFFSET_BASIS);
+    assert_eq!(
+      FingerprintHasher::new().write(b"a").finish().0,
+      0xaf63dc4c8601ec8c
+    );
+    assert_eq!(
+      FingerprintHasher::new().write(b"foobar").finish().0,
+      0x85944171f73967e8
+    );
+  }
+}
@@ -0,0 +1,304 @@
+//! The physical arrangement of the Lumatone's keys on a hexagonal grid.
+//!
+//! Keys are addressed by [KeyCoord]s: (column, row) pairs in "odd-r" offset coordinates
+//! (see <https://www.redblobgames.com/grids/hexagons/#coordinates>), the same system the GUI
+//! uses to draw a board. Row 0 is the top of the first board, and odd rows are shifted half
+//! a key to the right.
+//!
+//! Each board is a copy of the same 56-key shape, shifted 6 columns right and 2 rows down
+//! from the board before it, so the coordinates cover the whole keyboard without gaps.
+//!
+//! The [DistanceMatrix] has the distance between every pair of keys worked out in advance,
+//! for features that compare many keys with many others.
+
+use std::sync::OnceLock;
+
+use lumatone_midi::constants::{BoardIndex, LumatoneKeyIndex, LumatoneKeyLocation};
+use num_traits::FromPrimitive;
+
+/// (row, number of keys, first column) for each row of a single board.
+const BOARD_ROWS: [(i32, i32, i32); 11] = [
+  (0, 2, 0),
+  (1, 5, 0),
+  (2, 6, 0),
+  (3, 6, 0),
+  (4, 6, 0),
+  (5, 6, 0),
+  (6, 6, 0),
+  (7, 6, 0),
+  (8, 6, 0),
+  (9, 5, 1),
+  (10, 2, 4),
+];
+
+/// How far each board is shifted from the one before it.
+const BOARD_OFFSET: KeyCoord = KeyCoord { q: 6, r: 2 };
+
+/// The vertical distance between rows, in key widths.
+pub const ROW_HEIGHT: f64 = 0.8660254037844386;
+
+/// The number of keys on the five boards.
+pub const KEY_COUNT: usize = 280;
+
+const KEYS_PER_BOARD: usize = 56;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyCoord {
+  pub q: i32,
+  pub r: i32,
+}
+
+/// Returns the coordinate of a key within its own board.
+fn board_coord(key: LumatoneKeyIndex) -> KeyCoord {
+  let mut remaining = key.get() as i32;
+  for (r, len, start) in BOARD_ROWS {
+    if remaining < len {
+      return KeyCoord {
+        q: start + remaining,
+        r,
+      };
+    }
+    remaining -= len;
+  }
+  unreachable!("key index is out of range")
+}
+
+impl KeyCoord {
+  pub fn new(q: i32, r: i32) -> Self {
+    KeyCoord { q, r }
+  }
+
+  /// Returns the coordinate of a key, or `None` for the server board, which has no keys.
+  pub fn of(location: LumatoneKeyLocation) -> Option<KeyCoord> {
+    let board = location.board_index() as i32;
+    if board == 0 {
+      return None;
+    }
+    let local = board_coord(location.key_index());
+    Some(KeyCoord {
+      q: local.q + BOARD_OFFSET.q * (board - 1),
+      r: local.r + BOARD_OFFSET.r * (board - 1),
+    })
+  }
+
+  /// Returns the key at this coordinate, or `None` if there isn't one.
+  pub fn location(&self) -> Option<LumatoneKeyLocation> {
+    // Boards overlap in rows, so check each board that could contain the row.
+    for board in BoardIndex::all_octaves() {
+      let n = board as i32 - 1;
+      let local = KeyCoord {
+        q: self.q - BOARD_OFFSET.q * n,
+        r: self.r - BOARD_OFFSET.r * n,
+      };
+      let mut index = 0;
+      for (r, len, start) in BOARD_ROWS {
+        if r == local.r && (start..start + len).contains(&local.q) {
+          let key = LumatoneKeyIndex::new((index + local.q - start) as u8)?;
+          return Some(LumatoneKeyLocation(board, key));
+        }
+        index += len;
+      }
+    }
+    None
+  }
+
+  /// Returns the coordinate as cube coordinates (x, y, z), which make distances and
+  /// reflections easy to compute.
+  pub fn to_cube(&self) -> (i32, i32, i32) {
+    let x = self.q - (self.r - (self.r & 1)) / 2;
+    let z = self.r;
+    (x, -x - z, z)
+  }
+
+  /// Reflects the coordinate across the vertical line through `axis`. Rows stay the same,
+  /// and the key to the right of `axis` becomes the key to its left.
+  pub fn mirror_around(&self, axis: &KeyCoord) -> Self {
+    let (x, y, z) = self.to_cube();
+    let (ax, ay, az) = axis.to_cube();
+    let (dx, dy, dz) = (x - ax, y - ay, z - az);
+    KeyCoord::from_cube(ax + dy, ay + dx, az + dz)
+  }
+
+  pub fn from_cube(x: i32, _y: i32, z: i32) -> Self {
+    KeyCoord {
+      q: x + (z - (z & 1)) / 2,
+      r: z,
+    }
+  }
+
+  /// The number of steps between two keys on the grid.
+  pub fn distance(&self, other: &KeyCoord) -> i32 {
+    let (x1, y1, z1) = self.to_cube();
+    let (x2, y2, z2) = other.to_cube();
+    (x1 - x2).abs().max((y1 - y2).abs()).max((z1 - z2).abs())
+  }
+
+  /// The center of the key, in key widths, with y increasing downwards.
+  pub fn center(&self) -> (f64, f64) {
+    let shift = if self.r & 1 == 1 { 0.5 } else { 0.0 };
+    (self.q as f64 + shift, self.r as f64 * ROW_HEIGHT)
+  }
+}
+
+/// The horizontal middle of the keyboard, in key widths.
+pub fn keyboard_center_x() -> f64 {
+  let xs: Vec<f64> = LumatoneKeyLocation::all()
+    .into_iter()
+    .filter_map(KeyCoord::of)
+    .map(|c| c.center().0)
+    .collect();
+  let min = xs.iter().copied().fold(f64::MAX, f64::min);
+  let max = xs.iter().copied().fold(f64::MIN, f64::max);
+  (min + max) / 2.0
+}
+
+/// Returns the key nearest to the mirror image of `location` across the vertical line
+/// through the middle of the keyboard.
+///
+/// The keyboard's outline isn't symmetric, so the mirror image of a key near a corner can
+/// fall off the board, in which case the nearest key is returned.
+pub fn mirror_location(location: LumatoneKeyLocation) -> Option<LumatoneKeyLocation> {
+  let (x, y) = KeyCoord::of(location)?.center();
+  let target = (2.0 * keyboard_center_x() - x, y);
+  let dist = |c: KeyCoord| {
+    let (cx, cy) = c.center();
+    (cx - target.0).powi(2) + (cy - target.1).powi(2)
+  };
+  LumatoneKeyLocation::all()
+    .into_iter()
+    .filter_map(|loc| Some((loc, dist(KeyCoord::of(loc)?))))
+    .fold(
+      None,
+      |best: Option<(LumatoneKeyLocation, f64)>, (loc, d)| match best {
+        Some((_, best_d)) if best_d <= d => best,
+        _ => Some((loc, d)),
+      },
+    )
+    .map(|(loc, _)| loc)
+}
+
+/// Returns the key with the given board number (1 ..= 5) and key index, for when the board
+/// is computed rather than named.
+pub fn key_at(board: u8, key: u8) -> Option<LumatoneKeyLocation> {
+  let board = BoardIndex::from_u8(board).filter(|b| *b != BoardIndex::Server)?;
+  Some(LumatoneKeyLocation(board, LumatoneKeyIndex::new(key)?))
+}
+
+/// Returns a key's position in [LumatoneKeyLocation::all], from 0 to [KEY_COUNT] - 1, or
+/// `None` for the server board.
+pub fn key_number(location: LumatoneKeyLocation) -> Option<usize> {
+  match location.board_index() as usize {
+    0 => None,
+    board => Some((board - 1) * KEYS_PER_BOARD + location.key_index().get() as usize),
+  }
+}
+
+/// The distances between every pair of keys, computed once (see [DistanceMatrix::get]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceMatrix {
+  /// Grid steps, indexed by `a * KEY_COUNT + b` for key numbers `a` and `b`.
+  steps: Vec<u8>,
+
+  /// Distances between key centers, in key widths, indexed like `steps`.
+  travel: Vec<f32>,
+}
+
+static DISTANCE_MATRIX: OnceLock<DistanceMatrix> = OnceLock::new();
+
+impl DistanceMatrix {
+  /// Returns the shared matrix, computing it on first use.
+  pub fn get() -> &'static DistanceMatrix {
+    DISTANCE_MATRIX.get_or_init(DistanceMatrix::compute)
+  }
+
+  /// Computes a new matrix. Prefer [DistanceMatrix::get], which only does this once.
+  pub fn compute() -> Self {
+    let coords: Vec<KeyCoord> = LumatoneKeyLocation::all()
+      .into_iter()
+      .filter_map(KeyCoord::of)
+      .collect();
+    let mut steps = Vec::with_capacity(KEY_COUNT * KEY_COUNT);
+    let mut travel = Vec::with_capacity(KEY_COUNT * KEY_COUNT);
+    for a in &coords {
+      let (ax, ay) = a.center();
+      for b in &coords {
+        let (bx, by) = b.center();
+        steps.push(a.distance(b) as u8);
+        travel.push(((ax - bx).powi(2) + (ay - by).powi(2)).sqrt() as f32);
+      }
+    }
+    DistanceMatrix { steps, travel }
+  }
+
+  fn offset(a: LumatoneKeyLocation, b: LumatoneKeyLocation) -> Option<usize> {
+    Some(key_number(a)? * KEY_COUNT + key_number(b)?)
+  }
+
+  /// The number of steps between two keys on the grid, like [KeyCoord::distance].
+  pub fn steps(&self, a: LumatoneKeyLocation, b: LumatoneKeyLocation) -> Option<i32> {
+    Some(self.steps[Self::offset(a, b)?] as i32)
+  }
+
+  /// An estimate of how far the hand travels between two keys: the straight-line distance
+  /// between their centers, in key widths. Unlike [Self::steps], a move straight up or
+  /// down the board is shorter than the same number of steps sideways.
+  pub fn travel(&self, a: LumatoneKeyLocation, b: LumatoneKeyLocation) -> Option<f64> {
+    Some(self.travel[Self::offset(a, b)?] as f64)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_coords_round_trip() {
+    let mut seen = std::collections::HashSet::new();
+    for location in LumatoneKeyLocation::all() {
+      let coord = KeyCoord::of(location).unwrap();
+      assert!(seen.insert(coord), "{location} overlaps another key");
+      assert_eq!(coord.location(), Some(location));
+    }
+    assert_eq!(KeyCoord::new(-1, 0).location(), None);
+
+    // the last key of board 1 sits next to a key of board 2
+    let last = KeyCoord::of(key_at(1, 55).unwrap()).unwrap();
+    assert_eq!(last, KeyCoord::new(5, 10));
+    assert_eq!(KeyCoord::new(6, 10).location(), key_at(2, 43));
+    assert_eq!(last.distance(&KeyCoord::new(6, 10)), 1);
+
+    let axis = KeyCoord::new(3, 5);
+    assert_eq!(
+      KeyCoord::new(4, 5).mirror_around(&axis),
+      KeyCoord::new(2, 5)
+    );
+    // down-right of an odd row becomes down-left
+    assert_eq!(
+      KeyCoord::new(4, 6).mirror_around(&axis),
+      KeyCoord::new(3, 6)
+    );
+
+    let matrix = DistanceMatrix::get();
+    let (a, b) = (key_at(1, 55).unwrap(), key_at(2, 43).unwrap());
+    assert_eq!(key_number(b), Some(56 + 43));
+    assert_eq!(matrix.steps(a, b), Some(1));
+    assert_eq!(matrix.steps(a, a), Some(0));
+    let (far, near) = (key_at(1, 0).unwrap(), key_at(5, 55).unwrap());
+    assert_eq!(
+      matrix.steps(far, near),
+      Some(
+        KeyCoord::of(far)
+          .unwrap()
+          .distance(&KeyCoord::of(near).unwrap())
+      )
+    );
+    // two rows straight up is two steps, but less than two key widths of travel
+    let below = KeyCoord::new(last.q, last.r - 2).location().unwrap();
+    assert_eq!(matrix.steps(a, below), Some(2));
+    assert!(matrix.travel(a, below).unwrap() < 2.0);
+    assert_eq!(
+      matrix.steps(a, LumatoneKeyLocation(BoardIndex::Server, a.1)),
+      None
+    );
+  }
+}
@@ -0,0 +1,128 @@
+//! Color gradients, interpolated in the Oklab color space
+//! (<https://bottosson.github.io/posts/oklab/>) so that equal steps along the gradient look
+//! like equal changes in color. Interpolating RGB values directly makes the middle of e.g.
+//! a blue to red gradient a dull purple, and its brightness uneven.
+
+use lumatone_midi::constants::RGBColor;
+
+/// A color in the Oklab space: lightness, green-red, and blue-yellow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Oklab(pub(crate) f32, pub(crate) f32, pub(crate) f32);
+
+pub(crate) fn to_linear(c: u8) -> f32 {
+  let c = c as f32 / 255.0;
+  match c <= 0.04045 {
+    true => c / 12.92,
+    false => ((c + 0.055) / 1.055).powf(2.4),
+  }
+}
+
+pub(crate) fn from_linear(c: f32) -> u8 {
+  let c = match c <= 0.0031308 {
+    true => c * 12.92,
+    false => 1.055 * c.powf(1.0 / 2.4) - 0.055,
+  };
+  (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+// The matrices are from the reference implementation, at its precision.
+#[allow(clippy::excessive_precision)]
+impl From<RGBColor> for Oklab {
+  fn from(color: RGBColor) -> Self {
+    let RGBColor(r, g, b) = color;
+    let (r, g, b) = (to_linear(r), to_linear(g), to_linear(b));
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    Oklab(
+      0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+      1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+      0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    )
+  }
+}
+
+#[allow(clippy::excessive_precision)]
+impl From<Oklab> for RGBColor {
+  fn from(lab: Oklab) -> Self {
+    let Oklab(l, a, b) = lab;
+    let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    RGBColor(
+      from_linear(4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_),
+      from_linear(-1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_),
+      from_linear(-0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_),
+    )
+  }
+}
+
+/// Returns `color` with its Oklab lightness set to `lightness`, from 0.0 (black) to 1.0
+/// (white), keeping its hue. Very saturated colors may shift a little at the extremes, where
+/// they fall outside what RGB can show.
+pub fn with_lightness(color: RGBColor, lightness: f32) -> RGBColor {
+  let Oklab(_, a, b) = Oklab::from(color);
+  Oklab(lightness.clamp(0.0, 1.0), a, b).into()
+}
+
+/// Evenly spaced color stops, e.g. blue to red.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+  stops: Vec<RGBColor>,
+}
+
+impl Gradient {
+  pub fn new(stops: Vec<RGBColor>) -> Self {
+    Gradient { stops }
+  }
+
+  pub fn stops(&self) -> &[RGBColor] {
+    &self.stops
+  }
+
+  /// Returns the color at `t`, from 0.0 (the first stop) to 1.0 (the last). Values outside
+  /// that range are clamped. An empty gradient is black.
+  pub fn at(&self, t: f32) -> RGBColor {
+    let segments = match self.stops.len() {
+      0 => return RGBColor(0, 0, 0),
+      1 => return self.stops[0],
+      n => n - 1,
+    };
+    let pos = t.clamp(0.0, 1.0) * segments as f32;
+    let i = (pos.floor() as usize).min(segments - 1);
+    let frac = pos - i as f32;
+    if frac <= 0.0 {
+      return self.stops[i];
+    } else if frac >= 1.0 {
+      return self.stops[i + 1];
+    }
+    let (Oklab(l1, a1, b1), Oklab(l2, a2, b2)) =
+      (Oklab::from(self.stops[i]), Oklab::from(self.stops[i + 1]));
+    Oklab(
+      l1 + (l2 - l1) * frac,
+      a1 + (a2 - a1) * frac,
+      b1 + (b2 - b1) * frac,
+    )
+    .into()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_gradient_interpolates_in_oklab() {
+    let gradient = Gradient::new(vec![RGBColor::blue(), RGBColor::red()]);
+    assert_eq!(gradient.at(0.0), RGBColor::blue());
+    assert_eq!(gradient.at(1.0), RGBColor::red());
+    assert_eq!(gradient.at(2.0), RGBColor::red());
+
+    // Oklab keeps the middle brighter than a plain RGB blend, which is (128, 0, 128)
+    let RGBColor(r, g, b) = gradient.at(0.5);
+    assert!(r > 128 && b > 128, "{r} {g} {b}");
+
+    let white = RGBColor(0xff, 0xff, 0xff);
+    assert_eq!(Gradient::new(vec![white, white]).at(0.3), white);
+  }
+}
@@ -0,0 +1,326 @@
+//! Graph data for drawing a tuning, for frontends that show a just intonation lattice or
+//! an EDO circle alongside the board view.
+//!
+//! A [Lattice] has a node for each degree of the tuning and an edge for each pair of
+//! degrees a chosen interval apart, e.g. fifths and major thirds. Nodes of a just
+//! intonation lattice have coordinates giving the exponent of each odd prime in their ratio,
+//! which is the usual way to lay the lattice out: one axis per prime. Every node also has an
+//! angle for drawing the tuning as a circle, and lists the keys that play it (see
+//! [Lattice::with_keys]). Nodes are labelled with their ratio, or their pitch in cents if
+//! they don't have one; [Lattice::with_preferences] relabels them as the user prefers.
+//!
+//! With the `serde` feature, lattices can be serialized, e.g. to send to a web frontend.
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use lumatone_midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation};
+
+use super::{
+  ltn::LumatoneKeyMap,
+  preferences::DisplayPreferences,
+  tuning::{pitch_index, ratio_to_cents, Tuning},
+};
+
+/// The primes used for lattice coordinates. Factors above these are ignored.
+pub const LATTICE_PRIMES: [u64; 10] = [3, 5, 7, 11, 13, 17, 19, 23, 29, 31];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct KeyPosition {
+  pub board: u8,
+  pub key: u8,
+}
+
+impl From<LumatoneKeyLocation> for KeyPosition {
+  fn from(loc: LumatoneKeyLocation) -> Self {
+    KeyPosition {
+      board: loc.board_index().into(),
+      key: loc.key_index().get(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct LatticeNode {
+  pub degree: usize,
+
+  /// The pitch in cents above the root.
+  pub cents: f64,
+
+  /// The frequency ratio above the root, for just intonation lattices.
+  pub ratio: Option<(u32, u32)>,
+
+  /// The text to show on the node.
+  pub label: String,
+
+  /// The exponent of each of [Lattice::primes] in `ratio`. Empty for EDOs.
+  pub coordinates: Vec<i32>,
+
+  /// The node's position around a circle of one period, in degrees clockwise from the root.
+  pub angle: f64,
+
+  /// The keys that play this degree, in any period.
+  pub keys: Vec<KeyPosition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct LatticeEdge {
+  /// Indices into [Lattice::nodes].
+  pub from: usize,
+  pub to: usize,
+
+  /// Index into [Lattice::intervals].
+  pub interval: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Lattice {
+  pub name: String,
+
+  /// The primes each node's coordinates refer to. Empty for EDOs.
+  pub primes: Vec<u64>,
+
+  /// The intervals that edges are drawn for, as names like `3/2` or `7\31`.
+  pub intervals: Vec<String>,
+
+  pub nodes: Vec<LatticeNode>,
+  pub edges: Vec<LatticeEdge>,
+}
+
+impl Lattice {
+  /// Builds a lattice for a just intonation scale, given as ratios above the root in the
+  /// same form as [Tuning::from_ratios]. Edges join nodes `intervals` apart, treating
+  /// octaves as equivalent.
+  pub fn just<S: Into<String>>(name: S, ratios: &[(u32, u32)], intervals: &[(u32, u32)]) -> Self {
+    let period = ratios
+      .last()
+      .map(|(n, d)| ratio_to_cents(*n, *d))
+      .unwrap_or(1200.0);
+    let mut scale = vec![(1, 1)];
+    scale.extend(ratios.iter().take(ratios.len().saturating_sub(1)));
+
+    let mut coordinates: Vec<Vec<i32>> = scale
+      .iter()
+      .map(|(n, d)| prime_exponents(*n as u64, *d as u64))
+      .collect();
+    // Only keep the axes that are used.
+    let used = (0..LATTICE_PRIMES.len())
+      .rev()
+      .find(|i| coordinates.iter().any(|c| c[*i] != 0))
+      .map_or(0, |i| i + 1);
+    coordinates.iter_mut().for_each(|c| c.truncate(used));
+
+    let nodes = scale
+      .iter()
+      .zip(coordinates)
+      .enumerate()
+      .map(|(degree, ((n, d), coordinates))| {
+        let cents = ratio_to_cents(*n, *d);
+        LatticeNode {
+          degree,
+          cents,
+          ratio: Some((*n, *d)),
+          label: DisplayPreferences::default().interval(cents, Some((*n, *d))),
+          coordinates,
+          angle: cents / period * 360.0,
+          keys: vec![],
+        }
+      })
+      .collect::<Vec<_>>();
+
+    let reduced: Vec<(u64, u64)> = intervals
+      .iter()
+      .map(|(n, d)| octave_reduce(*n as u64, *d as u64))
+      .collect();
+    let mut edges = vec![];
+    for (from, a) in scale.iter().enumerate() {
+      for (to, b) in scale.iter().enumerate() {
+        // b / a, as a fraction
+        let ratio = octave_reduce(b.0 as u64 * a.1 as u64, b.1 as u64 * a.0 as u64);
+        if let Some(interval) = reduced.iter().position(|r| *r == ratio) {
+          edges.push(LatticeEdge { from, to, interval });
+        }
+      }
+    }
+
+    Lattice {
+      name: name.into(),
+      primes: LATTICE_PRIMES[..used].to_vec(),
+      intervals: intervals.iter().map(|(n, d)| format!("{n}/{d}")).collect(),
+      nodes,
+      edges,
+    }
+  }
+
+  /// Builds a circle for an equal or other non-just tuning. Edges join degrees that are
+  /// `steps` apart, wrapping around at the period, e.g. `&[18]` for a circle of fifths in
+  /// 31-EDO.
+  pub fn circle(tuning: &Tuning, steps: &[usize]) -> Self {
+    let len = tuning.len();
+    let nodes = tuning
+      .degrees
+      .iter()
+      .enumerate()
+      .map(|(degree, cents)| LatticeNode {
+        degree,
+        cents: *cents,
+        ratio: None,
+        label: DisplayPreferences::default().cents(*cents),
+        coordinates: vec![],
+        angle: cents / tuning.period * 360.0,
+        keys: vec![],
+      })
+      .collect();
+
+    let mut edges = vec![];
+    for (interval, step) in steps.iter().enumerate() {
+      if step % len.max(1) == 0 {
+        continue;
+      }
+      for from in 0..len {
+        edges.push(LatticeEdge {
+          from,
+          to: (from + step) % len,
+          interval,
+        });
+      }
+    }
+
+    Lattice {
+      name: tuning.name.clone(),
+      primes: vec![],
+      intervals: steps.iter().map(|s| format!("{s}\\{len}")).collect(),
+      nodes,
+      edges,
+    }
+  }
+
+  /// Relabels the nodes with ratios or cents, as `prefs` says.
+  pub fn with_preferences(mut self, prefs: &DisplayPreferences) -> Self {
+    for node in self.nodes.iter_mut() {
+      node.label = prefs.interval(node.cents, node.ratio);
+    }
+    self
+  }
+
+  /// Fills in the keys that play each node, from the note keys of `keymap` and the
+  /// [pitch indices](pitch_index) of `tuning`.
+  pub fn with_keys(mut self, keymap: &LumatoneKeyMap, tuning: &Tuning) -> Self {
+    self.nodes.iter_mut().for_each(|n| n.keys.clear());
+    for location in LumatoneKeyLocation::all() {
+      let (channel, note) = match keymap.get_key(location).map(|def| def.function) {
+        Some(LumatoneKeyFunction::NoteOnOff { channel, note_num })
+        | Some(LumatoneKeyFunction::LumaTouch {
+          channel, note_num, ..
+        }) => (channel, note_num),
+        _ => continue,
+      };
+      let (degree, _) = tuning.degree(pitch_index(channel, note));
+      if let Some(node) = self.nodes.iter_mut().find(|n| n.degree == degree) {
+        node.keys.push(location.into());
+      }
+    }
+    self
+  }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+  if b == 0 {
+    a
+  } else {
+    gcd(b, a % b)
+  }
+}
+
+/// Reduces `n / d` to lowest terms, within the octave 1/1 <= ratio < 2/1.
+fn octave_reduce(mut n: u64, mut d: u64) -> (u64, u64) {
+  if n == 0 || d == 0 {
+    return (n, d);
+  }
+  let g = gcd(n, d);
+  n /= g;
+  d /= g;
+  while n >= 2 * d {
+    if n.is_multiple_of(2) {
+      n /= 2;
+    } else {
+      d *= 2;
+    }
+  }
+  while n < d {
+    if d.is_multiple_of(2) {
+      d /= 2;
+    } else {
+      n *= 2;
+    }
+  }
+  (n, d)
+}
+
+/// Returns the exponent of each of [LATTICE_PRIMES] in `n / d`.
+fn prime_exponents(n: u64, d: u64) -> Vec<i32> {
+  let count = |mut x: u64, p: u64| {
+    let mut e = 0;
+    while x > 0 && x.is_multiple_of(p) {
+      x /= p;
+      e += 1;
+    }
+    e
+  };
+  LATTICE_PRIMES
+    .iter()
+    .map(|p| count(n, *p) - count(d, *p))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_just_lattice() {
+    let ratios = [(9, 8), (5, 4), (3, 2), (15, 8), (2, 1)];
+    let lattice = Lattice::just("ji", &ratios, &[(3, 2), (5, 4)]);
+
+    assert_eq!(lattice.primes, vec![3, 5]);
+    let coords: Vec<Vec<i32>> = lattice
+      .nodes
+      .iter()
+      .map(|n| n.coordinates.clone())
+      .collect();
+    assert_eq!(coords, vec![[0, 0], [2, 0], [0, 1], [1, 0], [1, 1]]);
+    assert!((lattice.nodes[3].angle - 210.59).abs() < 0.01);
+
+    // 1/1 -> 5/4, 1/1 -> 3/2, 5/4 -> 15/8, 3/2 -> 9/8 (octave reduced), 3/2 -> 15/8
+    let edges: Vec<(usize, usize, usize)> = lattice
+      .edges
+      .iter()
+      .map(|e| (e.from, e.to, e.interval))
+      .collect();
+    assert_eq!(
+      edges,
+      vec![(0, 2, 1), (0, 3, 0), (2, 4, 0), (3, 1, 0), (3, 4, 1)]
+    );
+
+    assert_eq!(lattice.nodes[2].label, "5/4");
+    let prefs = DisplayPreferences {
+      interval_labels: crate::preferences::IntervalLabels::Cents,
+      cents_precision: 1,
+      ..Default::default()
+    };
+    assert_eq!(lattice.with_preferences(&prefs).nodes[2].label, "386.3");
+  }
+
+  #[test]
+  fn test_circle() {
+    let lattice = Lattice::circle(&Tuning::equal(12), &[7]);
+    assert_eq!(lattice.nodes.len(), 12);
+    assert_eq!(lattice.edges.len(), 12);
+    assert_eq!(lattice.intervals, vec!["7\\12"]);
+    assert!((lattice.nodes[3].angle - 90.0).abs() < 1e-9);
+  }
+}
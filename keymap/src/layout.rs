@@ -0,0 +1,276 @@
+//! Generates isomorphic keymaps, where every interval has the same shape wherever it's
+//! played.
+//!
+//! An [IsomorphicLayout] assigns each key a [pitch index](crate::tuning::pitch_index): the
+//! anchor key gets `anchor_index`, each step right adds `right` and each step down and to the
+//! right adds `down_right`. The pitch index picks the channel and note the key sends.
+//!
+//! A [mirrored](IsomorphicLayout::mirrored) layout is the mirror image of the layout across
+//! the vertical axis of the keyboard, for left-handed players. Pitch rises to the left
+//! instead of the right, and the anchor moves to the mirror image of its position (see
+//! [mirror_location]). Every interval keeps its shape, flipped left to right.
+//!
+//! A [DuetLayout] splits the keyboard into two copies of one layout, one per player.
+
+use lumatone_midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, RGBColor};
+
+use super::{
+  geometry::{key_at, mirror_location, KeyCoord},
+  ltn::{KeyDefinition, LumatoneKeyMap},
+  seams::{check_layout, LayoutWarning, Shape},
+  selection::KeySelection,
+  tuning::Tuning,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IsomorphicLayout {
+  /// Scale steps for a move of one key to the right.
+  pub right: i32,
+
+  /// Scale steps for a move of one key down and to the right.
+  pub down_right: i32,
+
+  pub anchor: LumatoneKeyLocation,
+
+  /// The pitch index of the anchor key.
+  pub anchor_index: i32,
+
+  pub mirrored: bool,
+}
+
+impl IsomorphicLayout {
+  pub fn new(right: i32, down_right: i32, anchor: LumatoneKeyLocation, anchor_index: i32) -> Self {
+    IsomorphicLayout {
+      right,
+      down_right,
+      anchor,
+      anchor_index,
+      mirrored: false,
+    }
+  }
+
+  /// Returns the layout flipped left to right.
+  pub fn mirror(&self) -> Self {
+    IsomorphicLayout {
+      mirrored: !self.mirrored,
+      ..self.clone()
+    }
+  }
+
+  /// The anchor key, after mirroring if the layout is mirrored.
+  pub fn effective_anchor(&self) -> LumatoneKeyLocation {
+    match self.mirrored {
+      true => mirror_location(self.anchor).unwrap_or(self.anchor),
+      false => self.anchor,
+    }
+  }
+
+  /// Returns the pitch index of the key at `location`, or `None` for the server board.
+  pub fn pitch_index(&self, location: LumatoneKeyLocation) -> Option<i32> {
+    self.pitch_index_from(self.effective_anchor(), location)
+  }
+
+  /// Like [Self::pitch_index], but with the anchor at `anchor` instead, mirrored or not.
+  fn pitch_index_from(
+    &self,
+    anchor: LumatoneKeyLocation,
+    location: LumatoneKeyLocation,
+  ) -> Option<i32> {
+    let anchor = KeyCoord::of(anchor)?;
+    let mut coord = KeyCoord::of(location)?;
+    if self.mirrored {
+      coord = coord.mirror_around(&anchor);
+    }
+    let (x, _, z) = coord.to_cube();
+    let (ax, _, az) = anchor.to_cube();
+    // In cube coordinates, a step right is +x and a step down-right is +z.
+    Some(self.anchor_index + (x - ax) * self.right + (z - az) * self.down_right)
+  }
+
+  /// Generates a keymap for `tuning`. Each key is colored by its scale degree, cycling
+  /// through `colors`. Keys whose pitch index doesn't fit on a MIDI channel and note are
+  /// disabled.
+  pub fn generate(&self, tuning: &Tuning, colors: &[RGBColor]) -> LumatoneKeyMap {
+    let mut keymap = LumatoneKeyMap::new();
+    self.fill(
+      &mut keymap,
+      self.effective_anchor(),
+      &KeySelection::all(),
+      tuning,
+      colors,
+    );
+    keymap
+  }
+
+  /// Like [Self::generate], but also checks how `shapes` fall on the keyboard, with
+  /// warnings for ones that straddle a seam between boards or run off the edge (see
+  /// [crate::seams]).
+  pub fn generate_checked(
+    &self,
+    tuning: &Tuning,
+    colors: &[RGBColor],
+    shapes: &[Shape],
+  ) -> (LumatoneKeyMap, Vec<LayoutWarning>) {
+    (self.generate(tuning, colors), check_layout(self, shapes))
+  }
+
+  /// Sets the keys in `region` of `keymap`, with the anchor at `anchor`.
+  fn fill(
+    &self,
+    keymap: &mut LumatoneKeyMap,
+    anchor: LumatoneKeyLocation,
+    region: &KeySelection,
+    tuning: &Tuning,
+    colors: &[RGBColor],
+  ) {
+    for location in region.iter() {
+      let index = match self.pitch_index_from(anchor, *location) {
+        Some(i) => i,
+        None => continue,
+      };
+      let (degree, _) = tuning.degree(index);
+      let color = match colors.len() {
+        0 => RGBColor(0, 0, 0),
+        n => colors[degree % n],
+      };
+      keymap.set_key(
+        *location,
+        KeyDefinition {
+          function: key_function(index),
+          color,
+        },
+      );
+    }
+  }
+}
+
+/// Two copies of the same layout side by side, one in each half of the keyboard, for a
+/// teacher and student to play the same patterns at the same pitches.
+///
+/// The layout's anchor is used for the left half and `right_anchor` for the right half;
+/// both play the layout's `anchor_index`. A mirrored duet mirrors each half around its own
+/// anchor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuetLayout {
+  pub layout: IsomorphicLayout,
+  pub right_anchor: LumatoneKeyLocation,
+}
+
+impl DuetLayout {
+  pub fn new(layout: IsomorphicLayout, right_anchor: LumatoneKeyLocation) -> Self {
+    DuetLayout {
+      layout,
+      right_anchor,
+    }
+  }
+
+  /// Anchors the right half on the same key index `boards` boards to the right of the
+  /// layout's anchor. Every board has the same shape, so both players get the same keys
+  /// around the anchor. Returns `None` if that board doesn't exist.
+  pub fn shifted_by_boards(layout: IsomorphicLayout, boards: u8) -> Option<Self> {
+    let LumatoneKeyLocation(board, key) = layout.anchor;
+    let right_anchor = key_at((board as u8).checked_add(boards)?, key.get())?;
+    Some(DuetLayout::new(layout, right_anchor))
+  }
+
+  /// Returns the pitch index of the key at `location`, from the anchor of its half.
+  pub fn pitch_index(&self, location: LumatoneKeyLocation) -> Option<i32> {
+    let anchor = match KeySelection::left_half().contains(&location) {
+      true => self.layout.anchor,
+      false => self.right_anchor,
+    };
+    self.layout.pitch_index_from(anchor, location)
+  }
+
+  pub fn generate(&self, tuning: &Tuning, colors: &[RGBColor]) -> LumatoneKeyMap {
+    let mut keymap = LumatoneKeyMap::new();
+    let left = KeySelection::left_half();
+    let right = left.invert();
+    self
+      .layout
+      .fill(&mut keymap, self.layout.anchor, &left, tuning, colors);
+    self
+      .layout
+      .fill(&mut keymap, self.right_anchor, &right, tuning, colors);
+    keymap
+  }
+}
+
+/// Returns the note key function for a pitch index, the inverse of
+/// [pitch_index](crate::tuning::pitch_index).
+pub fn key_function(index: i32) -> LumatoneKeyFunction {
+  let channel = MidiChannel::new((index.div_euclid(128) + 1).clamp(0, 255) as u8);
+  match channel {
+    Some(channel) if index >= 0 => LumatoneKeyFunction::NoteOnOff {
+      channel,
+      note_num: index.rem_euclid(128) as u8,
+    },
+    _ => LumatoneKeyFunction::Disabled,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_mirrored_layout_preserves_intervals() {
+    // Bosanquet-style 31-EDO layout
+    let layout = IsomorphicLayout::new(5, 3, key_at(3, 28).unwrap(), 60);
+    let mirrored = layout.mirror();
+
+    let anchor = KeyCoord::of(layout.anchor).unwrap();
+    let mirrored_anchor = KeyCoord::of(mirrored.effective_anchor()).unwrap();
+    assert_eq!(mirrored.pitch_index(mirrored.effective_anchor()), Some(60));
+
+    // each step from the anchor has the same interval in both layouts, flipped left to right
+    for (dq, dr) in [(1, 0), (-1, 0), (0, 2), (1, 2), (2, -2)] {
+      let key = KeyCoord::new(anchor.q + dq, anchor.r + dr);
+      let mirrored_key = key.mirror_around(&anchor);
+      let offset = KeyCoord::new(
+        mirrored_anchor.q + mirrored_key.q - anchor.q,
+        mirrored_anchor.r + mirrored_key.r - anchor.r,
+      );
+      assert_eq!(
+        layout.pitch_index(key.location().unwrap()),
+        mirrored.pitch_index(offset.location().unwrap()),
+      );
+    }
+
+    // pitch rises to the right, and to the left when mirrored
+    let right_of = |c: KeyCoord| KeyCoord::new(c.q + 1, c.r).location().unwrap();
+    assert_eq!(layout.pitch_index(right_of(anchor)), Some(65));
+    assert_eq!(mirrored.pitch_index(right_of(mirrored_anchor)), Some(55));
+
+    let keymap = layout.generate(&Tuning::equal(31), &[RGBColor::red()]);
+    assert!(matches!(
+      keymap.get_key(layout.anchor).unwrap().function,
+      LumatoneKeyFunction::NoteOnOff { note_num: 60, .. }
+    ));
+  }
+
+  #[test]
+  fn test_duet_halves_play_the_same_region() {
+    let layout = IsomorphicLayout::new(2, 1, key_at(1, 27).unwrap(), 60);
+    let duet = DuetLayout::shifted_by_boards(layout.clone(), 3).unwrap();
+    assert_eq!(duet.right_anchor, key_at(4, 27).unwrap());
+    assert!(DuetLayout::shifted_by_boards(layout, 5).is_none());
+
+    let left = KeySelection::left_half();
+    for key in 0..56 {
+      let student = key_at(1, key).unwrap();
+      let teacher = key_at(4, key).unwrap();
+      if left.contains(&student) && !left.contains(&teacher) {
+        assert_eq!(duet.pitch_index(student), duet.pitch_index(teacher));
+      }
+    }
+
+    let keymap = duet.generate(&Tuning::equal(12), &[RGBColor::red()]);
+    let note = |loc| match keymap.get_key(loc).unwrap().function {
+      LumatoneKeyFunction::NoteOnOff { note_num, .. } => note_num,
+      _ => panic!("{loc} isn't a note"),
+    };
+    assert_eq!(note(duet.layout.anchor), 60);
+    assert_eq!(note(duet.right_anchor), 60);
+  }
+}
@@ -1,4 +1,31 @@
+pub mod accessibility;
+pub mod analysis;
+pub mod compact;
+pub mod edo;
 pub mod error;
+pub mod fingerprint;
+pub mod geometry;
+pub mod gradient;
+pub mod lattice;
+pub mod layout;
+pub mod lighting;
 pub mod ltn;
+pub mod notation;
+pub mod octave;
+pub mod patch;
+pub mod pitch_bend;
+pub mod preferences;
+pub mod retune;
+pub mod reveal;
+pub mod seams;
+pub mod selection;
+pub mod spelling;
+pub mod svg;
 mod table_defaults;
 pub mod tables;
+#[cfg(feature = "ltn")]
+pub mod template;
+pub mod trainer;
+pub mod tuning;
+pub mod verify;
+pub mod voice_leading;
@@ -0,0 +1,304 @@
+//! Lighting modes, which change key colors in response to what's played.
+//!
+//! A [LightingMode] turns the device's channel messages (see `MidiDriver::subscribe_events`
+//! in `lumatone_midi`) into [SetKeyColor](Command::SetKeyColor) commands. Modes only compute the commands; sending
+//! them is up to the caller.
+//!
+//! Keys are found by the channel and note they send, so when several keys share a note,
+//! playing any of them lights all of them.
+
+use std::collections::{HashMap, HashSet};
+
+use lumatone_midi::{
+  commands::{set_key_color, Command},
+  constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, RGBColor},
+  events::ChannelMessage,
+};
+
+use super::{
+  gradient::Gradient,
+  ltn::LumatoneKeyMap,
+  tuning::{pitch_index, Tuning},
+};
+
+/// The default brightness of ghosted keys.
+pub const DEFAULT_GHOST_BRIGHTNESS: f32 = 0.15;
+
+pub trait LightingMode {
+  /// Returns the commands that set every key to its resting color.
+  fn reset(&mut self) -> Vec<Command>;
+
+  /// Returns the commands for the key colors that change because of `msg`.
+  fn handle(&mut self, msg: &ChannelMessage) -> Vec<Command>;
+}
+
+/// Returns the note keys of `keymap`, grouped by the channel and note they send.
+pub fn keys_by_note(
+  keymap: &LumatoneKeyMap,
+) -> HashMap<(MidiChannel, u8), Vec<LumatoneKeyLocation>> {
+  let mut keys: HashMap<_, Vec<_>> = HashMap::new();
+  for location in LumatoneKeyLocation::all() {
+    let (channel, note) = match keymap.get_key(location).map(|def| def.function) {
+      Some(LumatoneKeyFunction::NoteOnOff { channel, note_num })
+      | Some(LumatoneKeyFunction::LumaTouch {
+        channel, note_num, ..
+      }) => (channel, note_num),
+      _ => continue,
+    };
+    keys.entry((channel, note)).or_default().push(location);
+  }
+  keys
+}
+
+/// A practice mode that shows a target scale "ghosted" at low brightness, and lights a key
+/// fully while it's held if it's in the scale. Keys outside the scale stay dark, so a wrong
+/// note gets no light.
+#[derive(Debug, Clone)]
+pub struct GhostLighting {
+  keys: HashMap<(MidiChannel, u8), Vec<LumatoneKeyLocation>>,
+
+  /// The keys that play a degree of the target scale.
+  in_scale: HashSet<LumatoneKeyLocation>,
+
+  color: RGBColor,
+  ghost_brightness: f32,
+}
+
+impl GhostLighting {
+  /// Ghosts the keys of `keymap` that play one of the `scale` degrees of `tuning`, in
+  /// `color`.
+  pub fn new(keymap: &LumatoneKeyMap, tuning: &Tuning, scale: &[usize], color: RGBColor) -> Self {
+    let keys = keys_by_note(keymap);
+    let in_scale = keys
+      .iter()
+      .filter(|((channel, note), _)| {
+        let (degree, _) = tuning.degree(pitch_index(*channel, *note));
+        scale.contains(&degree)
+      })
+      .flat_map(|(_, locations)| locations.iter().copied())
+      .collect();
+    GhostLighting {
+      keys,
+      in_scale,
+      color,
+      ghost_brightness: DEFAULT_GHOST_BRIGHTNESS,
+    }
+  }
+
+  pub fn with_ghost_brightness(mut self, brightness: f32) -> Self {
+    self.ghost_brightness = brightness;
+    self
+  }
+
+  fn resting_color(&self, location: &LumatoneKeyLocation) -> RGBColor {
+    match self.in_scale.contains(location) {
+      true => self.color.scaled(self.ghost_brightness),
+      false => RGBColor(0, 0, 0),
+    }
+  }
+
+  /// Returns the commands to color the scale keys that play `note` on `channel`.
+  fn light(&self, channel: MidiChannel, note: u8, held: bool) -> Vec<Command> {
+    let locations = match self.keys.get(&(channel, note)) {
+      Some(locations) => locations,
+      None => return vec![],
+    };
+    locations
+      .iter()
+      .filter(|loc| self.in_scale.contains(loc))
+      .map(|loc| match held {
+        true => set_key_color(*loc, self.color),
+        false => set_key_color(*loc, self.resting_color(loc)),
+      })
+      .collect()
+  }
+}
+
+impl LightingMode for GhostLighting {
+  fn reset(&mut self) -> Vec<Command> {
+    LumatoneKeyLocation::all()
+      .into_iter()
+      .map(|loc| set_key_color(loc, self.resting_color(&loc)))
+      .collect()
+  }
+
+  fn handle(&mut self, msg: &ChannelMessage) -> Vec<Command> {
+    match *msg {
+      ChannelMessage::NoteOn { channel, note, .. } => self.light(channel, note, true),
+      ChannelMessage::NoteOff { channel, note, .. } => self.light(channel, note, false),
+      _ => vec![],
+    }
+  }
+}
+
+/// A feedback mode that lights each held key with a color for how hard it was struck, from
+/// the start of the gradient for velocity 0 to the end for 127, to help with playing at an
+/// even velocity. Released keys go back to their keymap color.
+#[derive(Debug, Clone)]
+pub struct VelocityLighting {
+  keys: HashMap<(MidiChannel, u8), Vec<LumatoneKeyLocation>>,
+  colors: HashMap<LumatoneKeyLocation, RGBColor>,
+  gradient: Gradient,
+}
+
+impl VelocityLighting {
+  pub fn new(keymap: &LumatoneKeyMap, gradient: Gradient) -> Self {
+    let colors = LumatoneKeyLocation::all()
+      .into_iter()
+      .filter_map(|loc| Some((loc, keymap.get_key(loc)?.color)))
+      .collect();
+    VelocityLighting {
+      keys: keys_by_note(keymap),
+      colors,
+      gradient,
+    }
+  }
+
+  /// Uses a blue to red gradient.
+  pub fn blue_to_red(keymap: &LumatoneKeyMap) -> Self {
+    VelocityLighting::new(
+      keymap,
+      Gradient::new(vec![RGBColor::blue(), RGBColor::red()]),
+    )
+  }
+
+  fn resting_color(&self, location: &LumatoneKeyLocation) -> RGBColor {
+    self
+      .colors
+      .get(location)
+      .copied()
+      .unwrap_or(RGBColor(0, 0, 0))
+  }
+
+  fn color_keys<F: Fn(&LumatoneKeyLocation) -> RGBColor>(
+    &self,
+    channel: MidiChannel,
+    note: u8,
+    color: F,
+  ) -> Vec<Command> {
+    self
+      .keys
+      .get(&(channel, note))
+      .into_iter()
+      .flatten()
+      .map(|loc| set_key_color(*loc, color(loc)))
+      .collect()
+  }
+}
+
+impl LightingMode for VelocityLighting {
+  fn reset(&mut self) -> Vec<Command> {
+    LumatoneKeyLocation::all()
+      .into_iter()
+      .map(|loc| set_key_color(loc, self.resting_color(&loc)))
+      .collect()
+  }
+
+  fn handle(&mut self, msg: &ChannelMessage) -> Vec<Command> {
+    match *msg {
+      ChannelMessage::NoteOn {
+        channel,
+        note,
+        velocity,
+      } => {
+        let color = self.gradient.at(velocity as f32 / 127.0);
+        self.color_keys(channel, note, |_| color)
+      }
+      ChannelMessage::NoteOff { channel, note, .. } => {
+        self.color_keys(channel, note, |loc| self.resting_color(loc))
+      }
+      _ => vec![],
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ltn::KeyDefinition;
+  use lumatone_midi::constants::key_loc_unchecked;
+
+  #[test]
+  fn test_ghost_lighting_brightens_held_scale_keys() {
+    let channel = MidiChannel::default();
+    let mut keymap = LumatoneKeyMap::new();
+    for (key, note_num) in [(0, 60), (1, 61), (2, 72)] {
+      keymap.set_key(
+        key_loc_unchecked(1, key),
+        KeyDefinition {
+          function: LumatoneKeyFunction::NoteOnOff { channel, note_num },
+          color: RGBColor(0, 0, 0),
+        },
+      );
+    }
+    let major = [0, 2, 4, 5, 7, 9, 11];
+    let blue = RGBColor::blue();
+    let mut lighting =
+      GhostLighting::new(&keymap, &Tuning::equal(12), &major, blue).with_ghost_brightness(0.2);
+
+    let reset = lighting.reset();
+    assert_eq!(reset.len(), LumatoneKeyLocation::all().len());
+    assert!(reset.contains(&set_key_color(key_loc_unchecked(1, 0), RGBColor(0, 0, 51))));
+    assert!(reset.contains(&set_key_color(key_loc_unchecked(1, 1), RGBColor(0, 0, 0))));
+
+    let note_on = |note| ChannelMessage::NoteOn {
+      channel,
+      note,
+      velocity: 100,
+    };
+    assert_eq!(
+      lighting.handle(&note_on(72)),
+      vec![set_key_color(key_loc_unchecked(1, 2), blue)]
+    );
+    // not in the scale
+    assert!(lighting.handle(&note_on(61)).is_empty());
+
+    let note_off = ChannelMessage::NoteOff {
+      channel,
+      note: 72,
+      velocity: 0,
+    };
+    assert_eq!(
+      lighting.handle(&note_off),
+      vec![set_key_color(key_loc_unchecked(1, 2), RGBColor(0, 0, 51))]
+    );
+  }
+
+  #[test]
+  fn test_velocity_lighting_colors_by_velocity() {
+    let channel = MidiChannel::default();
+    let location = key_loc_unchecked(2, 10);
+    let green = RGBColor::green();
+    let mut keymap = LumatoneKeyMap::new();
+    keymap.set_key(
+      location,
+      KeyDefinition {
+        function: LumatoneKeyFunction::NoteOnOff {
+          channel,
+          note_num: 64,
+        },
+        color: green,
+      },
+    );
+    let mut lighting = VelocityLighting::blue_to_red(&keymap);
+
+    let note_on = |velocity| ChannelMessage::NoteOn {
+      channel,
+      note: 64,
+      velocity,
+    };
+    assert_eq!(
+      lighting.handle(&note_on(127)),
+      vec![set_key_color(location, RGBColor::red())]
+    );
+    let note_off = ChannelMessage::NoteOff {
+      channel,
+      note: 64,
+      velocity: 0,
+    };
+    assert_eq!(
+      lighting.handle(&note_off),
+      vec![set_key_color(location, green)]
+    );
+  }
+}
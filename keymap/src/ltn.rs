@@ -8,28 +8,35 @@
 //! which determines the type of key (note on/off, fader, lumatouch, etc) and the
 //! Midi note and channel number.
 //!
-//! You can convert [LumatoneKeyMap]s to and from strings in ini format.
+//! You can convert [LumatoneKeyMap]s to and from strings in ini format. The ini conversion
+//! requires the `ltn` feature, which is on by default.
 
 use lumatone_midi::{
   commands::Command,
-  constants::{
-    key_loc_unchecked, BoardIndex, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation,
-    MidiChannel, RGBColor,
-  },
+  constants::{LumatoneKeyFunction, LumatoneKeyLocation, RGBColor},
+  responses::PeripheralChannels,
 };
 
 use std::collections::HashMap;
 use std::fmt::Debug;
 
+use super::{
+  fingerprint::{Fingerprint, FingerprintHasher},
+  tables::ConfigurationTables,
+};
+
+#[cfg(feature = "ltn")]
 use ini::{Ini, Properties};
+#[cfg(feature = "ltn")]
+use lumatone_midi::constants::{key_loc_unchecked, BoardIndex, LumatoneKeyIndex, MidiChannel};
+#[cfg(feature = "ltn")]
 use num_traits::FromPrimitive;
 
-use super::{
-  error::LumatoneKeymapError,
-  tables::{
-    parse_velocity_intervals, velocity_intervals_to_string, ConfigTableDefinition,
-    ConfigurationTables,
-  },
+#[cfg(feature = "ltn")]
+use super::error::LumatoneKeymapError;
+#[cfg(feature = "ltn")]
+use super::tables::{
+  parse_velocity_intervals, velocity_intervals_to_string, ConfigTableDefinition, TableKind,
 };
 
 #[derive(Debug)]
@@ -38,6 +45,14 @@ pub struct KeyDefinition {
   pub color: RGBColor,
 }
 
+/// The colors of the two macro buttons.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MacroButtonColors {
+  /// The color of a button while it's pressed.
+  pub active: RGBColor,
+  pub inactive: RGBColor,
+}
+
 #[derive(Debug)]
 pub struct GeneralOptions {
   pub after_touch_active: bool,
@@ -46,29 +61,97 @@ pub struct GeneralOptions {
   pub invert_sustain: bool,
   pub expression_controller_sensitivity: u8,
 
+  /// Files from older versions of the editor don't set macro button colors, in which case
+  /// the device keeps the colors it has.
+  pub macro_button_colors: Option<MacroButtonColors>,
+
+  /// The channels the wheels and pedals send on. The official editor doesn't save these, so
+  /// they're usually unset, and the device keeps the channels it has.
+  pub peripheral_channels: Option<PeripheralChannels>,
+
   pub config_tables: ConfigurationTables,
 }
 
+/// Older versions of this crate wrote the on/off velocity table under this key.
+#[cfg(feature = "ltn")]
+const LEGACY_ON_OFF_VELOCITY_KEY: &str = "NoteOnOffVelocityCurveTbl";
+
+#[cfg(feature = "ltn")]
 fn config_table_from_ini_section(
   section: &Properties,
-  key: &str,
+  kind: TableKind,
 ) -> Result<Option<ConfigTableDefinition>, LumatoneKeymapError> {
-  match section.get(key) {
+  let val = section.get(kind.ltn_key()).or_else(|| match kind {
+    TableKind::OnOffVelocity => section.get(LEGACY_ON_OFF_VELOCITY_KEY),
+    _ => None,
+  });
+  match val {
     Some(val) => ConfigTableDefinition::from_str(val).map(|val| Some(val)),
     None => Ok(None),
   }
 }
 
+/// The .ltn keys for the pitch wheel, mod wheel, expression pedal and sustain channels.
+#[cfg(feature = "ltn")]
+const PERIPHERAL_CHANNEL_KEYS: [&str; 4] = [
+  "PitchWheelChannel",
+  "ModWheelChannel",
+  "ExpressionChannel",
+  "SustainChannel",
+];
+
+/// Reads the peripheral channels, if any are set. Channels that aren't set default to 1.
+#[cfg(feature = "ltn")]
+fn peripheral_channels_from_ini_section(
+  props: &Properties,
+) -> Result<Option<PeripheralChannels>, LumatoneKeymapError> {
+  if PERIPHERAL_CHANNEL_KEYS
+    .iter()
+    .all(|k| props.get(k).is_none())
+  {
+    return Ok(None);
+  }
+  let mut channels = [MidiChannel::default(); 4];
+  for (key, channel) in PERIPHERAL_CHANNEL_KEYS.iter().zip(channels.iter_mut()) {
+    if let Some(val) = props.get(key) {
+      *channel = val
+        .parse()
+        .ok()
+        .and_then(MidiChannel::new)
+        .ok_or(LumatoneKeymapError::ValueParseError)?;
+    }
+  }
+  let [pitch_wheel, mod_wheel, expression, sustain] = channels;
+  Ok(Some(PeripheralChannels {
+    pitch_wheel,
+    mod_wheel,
+    expression,
+    sustain,
+  }))
+}
+
+#[cfg(feature = "ltn")]
 impl GeneralOptions {
   fn from_ini_section(props: &Properties) -> Result<GeneralOptions, LumatoneKeymapError> {
-    let on_off_velocity = config_table_from_ini_section(props, "NoteOnOffVelocityCurveTbl")?;
-    let fader_velocity = config_table_from_ini_section(props, "FaderConfig")?;
-    let aftertouch_velocity = config_table_from_ini_section(props, "afterTouchConfig")?;
-    let lumatouch_velocity = config_table_from_ini_section(props, "LumaTouchConfig")?;
+    let on_off_velocity = config_table_from_ini_section(props, TableKind::OnOffVelocity)?;
+    let fader_velocity = config_table_from_ini_section(props, TableKind::FaderVelocity)?;
+    let aftertouch_velocity = config_table_from_ini_section(props, TableKind::AftertouchVelocity)?;
+    let lumatouch_velocity = config_table_from_ini_section(props, TableKind::LumatouchVelocity)?;
     let velocity_intervals = match props.get("VelocityIntrvlTbl") {
       Some(val) => Some(parse_velocity_intervals(val)?),
       None => None,
     };
+    let macro_button_colors = match (
+      props.get("ActiveMacroButtonColour"),
+      props.get("InactiveMacroButtonColour"),
+    ) {
+      (Some(active), Some(inactive)) => Some(MacroButtonColors {
+        active: color_from_ltn(active)?,
+        inactive: color_from_ltn(inactive)?,
+      }),
+      _ => None,
+    };
+    let peripheral_channels = peripheral_channels_from_ini_section(props)?;
 
     Ok(GeneralOptions {
       after_touch_active: props.get("AfterTouchActive").map(bool_val).unwrap_or(false),
@@ -86,6 +169,8 @@ impl GeneralOptions {
         .get("ExprCtrlSensivity")
         .map(|s| u8::from_str_radix(s, 10).expect("invalid int value"))
         .unwrap_or(0),
+      macro_button_colors,
+      peripheral_channels,
       config_tables: ConfigurationTables {
         on_off_velocity,
         fader_velocity,
@@ -105,6 +190,8 @@ impl Default for GeneralOptions {
       invert_foot_controller: false,
       invert_sustain: false,
       expression_controller_sensitivity: 0,
+      macro_button_colors: None,
+      peripheral_channels: None,
       config_tables: ConfigurationTables::default(),
     }
   }
@@ -144,6 +231,118 @@ impl LumatoneKeyMap {
     self
   }
 
+  pub fn global_options(&self) -> &GeneralOptions {
+    &self.general
+  }
+
+  pub fn global_options_mut(&mut self) -> &mut GeneralOptions {
+    &mut self.general
+  }
+
+  /// Returns a stable hash of the keymap's contents, which doesn't depend on the order that
+  /// keys were added. Useful for detecting whether a keymap has actually changed.
+  pub fn fingerprint(&self) -> Fingerprint {
+    let mut h = FingerprintHasher::new();
+
+    let opts = &self.general;
+    h.write_bool(opts.after_touch_active)
+      .write_bool(opts.light_on_key_strokes)
+      .write_bool(opts.invert_foot_controller)
+      .write_bool(opts.invert_sustain)
+      .write_u8(opts.expression_controller_sensitivity);
+    match &opts.macro_button_colors {
+      Some(colors) => h
+        .write_u8(1)
+        .write(&colors.active.to_bytes())
+        .write(&colors.inactive.to_bytes()),
+      None => h.write_u8(0),
+    };
+    match &opts.peripheral_channels {
+      Some(c) => h
+        .write_u8(1)
+        .write_u8(c.pitch_wheel.get())
+        .write_u8(c.mod_wheel.get())
+        .write_u8(c.expression.get())
+        .write_u8(c.sustain.get()),
+      None => h.write_u8(0),
+    };
+
+    let tables = &opts.config_tables;
+    for t in [
+      &tables.on_off_velocity,
+      &tables.fader_velocity,
+      &tables.aftertouch_velocity,
+      &tables.lumatouch_velocity,
+    ] {
+      match t {
+        Some(t) => h.write_u8(1).write(&t.table),
+        None => h.write_u8(0),
+      };
+    }
+    match &tables.velocity_intervals {
+      Some(t) => {
+        h.write_u8(1);
+        for v in t.iter() {
+          h.write(&v.to_le_bytes());
+        }
+      }
+      None => {
+        h.write_u8(0);
+      }
+    }
+
+    // visit keys in a fixed order, so HashMap iteration order doesn't matter
+    for loc in LumatoneKeyLocation::all() {
+      match self.keys.get(&loc) {
+        Some(def) => h
+          .write_u8(1)
+          .write_u8(def.function.type_code())
+          .write_u8(def.function.note_or_cc_num())
+          .write_u8(def.function.midi_channel_byte())
+          .write(&def.color.to_bytes()),
+        None => h.write_u8(0),
+      };
+    }
+
+    h.finish()
+  }
+
+  pub fn to_midi_commands(&self) -> Vec<Command> {
+    use Command::*;
+    let mut commands = vec![
+      SetAftertouchEnabled(self.general.after_touch_active),
+      SetLightOnKeystrokes(self.general.light_on_key_strokes),
+      InvertFootController(self.general.invert_foot_controller),
+      InvertSustainPedal(self.general.invert_sustain),
+      SetExpressionPedalSensitivity(self.general.expression_controller_sensitivity),
+    ];
+    if let Some(colors) = &self.general.macro_button_colors {
+      commands.push(SetMacroButtonActiveColor(colors.active));
+      commands.push(SetMacroButtonInactiveColor(colors.inactive));
+    }
+    if let Some(channels) = &self.general.peripheral_channels {
+      commands.push(channels.to_command());
+    }
+
+    commands.extend(self.general.config_tables.to_midi_commands());
+
+    for (location, definition) in self.keys.iter() {
+      commands.push(SetKeyFunction {
+        location: *location,
+        function: definition.function,
+      });
+      commands.push(SetKeyColor {
+        location: *location,
+        color: definition.color,
+      });
+    }
+
+    commands
+  }
+}
+
+#[cfg(feature = "ltn")]
+impl LumatoneKeyMap {
   pub fn to_ini(&self) -> Ini {
     let mut conf = Ini::new();
 
@@ -169,34 +368,34 @@ impl LumatoneKeyMap {
         self.general.expression_controller_sensitivity.to_string(),
       );
 
-    if let Some(t) = &self.general.config_tables.velocity_intervals {
-      conf
-        .with_general_section()
-        .set("VelocityIntrvlTbl", velocity_intervals_to_string(t));
-    }
-
-    if let Some(t) = &self.general.config_tables.on_off_velocity {
+    if let Some(colors) = &self.general.macro_button_colors {
       conf
         .with_general_section()
-        .set("NoteOnOffVelocityCrvTbl", t.to_string());
+        .set("ActiveMacroButtonColour", colors.active.to_hex_string())
+        .set("InactiveMacroButtonColour", colors.inactive.to_hex_string());
     }
 
-    if let Some(t) = &self.general.config_tables.fader_velocity {
-      conf
-        .with_general_section()
-        .set("FaderConfig", t.to_string());
+    if let Some(c) = &self.general.peripheral_channels {
+      let channels = [c.pitch_wheel, c.mod_wheel, c.expression, c.sustain];
+      for (key, channel) in PERIPHERAL_CHANNEL_KEYS.iter().zip(channels) {
+        conf
+          .with_general_section()
+          .set(*key, channel.get().to_string());
+      }
     }
 
-    if let Some(t) = &self.general.config_tables.aftertouch_velocity {
+    if let Some(t) = &self.general.config_tables.velocity_intervals {
       conf
         .with_general_section()
-        .set("afterTouchConfig", t.to_string());
+        .set("VelocityIntrvlTbl", velocity_intervals_to_string(t));
     }
 
-    if let Some(t) = &self.general.config_tables.lumatouch_velocity {
-      conf
-        .with_general_section()
-        .set("LumaTouchConfig", t.to_string());
+    for kind in TableKind::ALL {
+      if let Some(t) = self.general.config_tables.get(kind) {
+        conf
+          .with_general_section()
+          .set(kind.ltn_key(), t.to_string());
+      }
     }
 
     // Key definitions are split into sections, one for each board / octave
@@ -258,8 +457,10 @@ impl LumatoneKeyMap {
   }
 
   pub fn from_ini_str<S: AsRef<str>>(source: S) -> Result<LumatoneKeyMap, LumatoneKeymapError> {
-    let ini = Ini::load_from_str(source.as_ref())?;
+    LumatoneKeyMap::from_ini(&Ini::load_from_str(source.as_ref())?)
+  }
 
+  pub fn from_ini(ini: &Ini) -> Result<LumatoneKeyMap, LumatoneKeymapError> {
     let mut general = GeneralOptions::default();
     let mut keys: HashMap<LumatoneKeyLocation, KeyDefinition> = HashMap::new();
 
@@ -276,36 +477,9 @@ impl LumatoneKeyMap {
           let key_type_code = get_u8_or_default_from_ini_section(section, format!("KTyp_{k}"), 1);
           let note_or_cc_num = get_u8_or_default_from_ini_section(section, format!("Key_{k}"), 0);
           let chan = get_u8_or_default_from_ini_section(section, format!("Chan_{k}"), 1);
-          let color_str = section.get(format!("Col_{k}")).unwrap_or("000000");
-          // TODO: use error_stack here:
-          let color_u32 =
-            u32::from_str_radix(color_str, 16).map_err(|_| LumatoneKeymapError::ValueParseError)?;
-          let color = RGBColor::from(color_u32);
-
-          let channel = MidiChannel::new(chan).unwrap_or_default();
-          let function = match key_type_code {
-            1 => LumatoneKeyFunction::NoteOnOff {
-              channel,
-              note_num: note_or_cc_num,
-            },
-            // FIXME: figure out how the fader up thing is serialized in preset files...
-            // might be the same as in midi messages (left shift the type code by 4)
-            2 => LumatoneKeyFunction::ContinuousController {
-              channel,
-              cc_num: note_or_cc_num,
-              fader_up_is_null: false,
-            },
-            3 => LumatoneKeyFunction::LumaTouch {
-              channel,
-              note_num: note_or_cc_num,
-              fader_up_is_null: false,
-            },
-            4 => LumatoneKeyFunction::Disabled,
-            _ => {
-              log::warn!("unrecognized key type code: {key_type_code}");
-              LumatoneKeyFunction::Disabled
-            }
-          };
+          let color = color_from_ltn(section.get(format!("Col_{k}")).unwrap_or("000000"))?;
+
+          let function = key_function_from_ltn(key_type_code, chan, note_or_cc_num);
           let key_definition = KeyDefinition { function, color };
           let loc = key_loc_unchecked(b, k);
           keys.insert(loc, key_definition);
@@ -313,53 +487,65 @@ impl LumatoneKeyMap {
       }
     }
 
+    // Files written by `to_ini` keep the global options in the general section.
+    if let Some(section) = ini.section(None::<String>) {
+      if !section.is_empty() {
+        general = GeneralOptions::from_ini_section(section)?;
+      }
+    }
+
     Ok(LumatoneKeyMap { keys, general })
   }
+}
 
-  pub fn to_midi_commands(&self) -> Vec<Command> {
-    use Command::*;
-    let mut commands = vec![
-      SetAftertouchEnabled(self.general.after_touch_active),
-      SetLightOnKeystrokes(self.general.light_on_key_strokes),
-      InvertFootController(self.general.invert_foot_controller),
-      InvertSustainPedal(self.general.invert_sustain),
-      SetExpressionPedalSensitivity(self.general.expression_controller_sensitivity),
-    ];
-
-    let tables = &self.general.config_tables;
-    if let Some(t) = &tables.on_off_velocity {
-      commands.push(SetVelocityConfig(Box::new(t.table)));
-    }
-    if let Some(t) = &tables.aftertouch_velocity {
-      commands.push(SetAftertouchConfig(Box::new(t.table)));
-    }
-    if let Some(t) = &tables.fader_velocity {
-      commands.push(SetFaderConfig(Box::new(t.table)));
-    }
-    if let Some(t) = &tables.lumatouch_velocity {
-      commands.push(SetLumatouchConfig(Box::new(t.table)));
-    }
-    if let Some(t) = tables.velocity_intervals {
-      commands.push(SetVelocityIntervals(Box::new(t)));
-    }
+/// Parses a color written as a hex string, e.g. `ff8000`.
+#[cfg(feature = "ltn")]
+fn color_from_ltn(s: &str) -> Result<RGBColor, LumatoneKeymapError> {
+  // TODO: use error_stack here:
+  let color_u32 = u32::from_str_radix(s, 16).map_err(|_| LumatoneKeymapError::ValueParseError)?;
+  Ok(RGBColor::from(color_u32))
+}
 
-    for (location, definition) in self.keys.iter() {
-      commands.push(SetKeyFunction {
-        location: *location,
-        function: definition.function,
-      });
-      commands.push(SetKeyColor { location: *location, color: definition.color });
+/// Builds a key function from the `KTyp`, `Chan` and `Key` values in a .ltn file.
+#[cfg(feature = "ltn")]
+fn key_function_from_ltn(
+  key_type_code: u8,
+  chan: u8,
+  note_or_cc_num: u8,
+) -> LumatoneKeyFunction {
+  let channel = MidiChannel::new(chan).unwrap_or_default();
+  match key_type_code {
+    1 => LumatoneKeyFunction::NoteOnOff {
+      channel,
+      note_num: note_or_cc_num,
+    },
+    // FIXME: figure out how the fader up thing is serialized in preset files...
+    // might be the same as in midi messages (left shift the type code by 4)
+    2 => LumatoneKeyFunction::ContinuousController {
+      channel,
+      cc_num: note_or_cc_num,
+      fader_up_is_null: false,
+    },
+    3 => LumatoneKeyFunction::LumaTouch {
+      channel,
+      note_num: note_or_cc_num,
+      fader_up_is_null: false,
+    },
+    4 => LumatoneKeyFunction::Disabled,
+    _ => {
+      log::warn!("unrecognized key type code: {key_type_code}");
+      LumatoneKeyFunction::Disabled
     }
-
-    commands
   }
 }
 
+#[cfg(feature = "ltn")]
 fn bool_val(s: &str) -> bool {
   let i = i64::from_str_radix(s, 10).unwrap_or(0);
   i != 0
 }
 
+#[cfg(feature = "ltn")]
 fn get_u8_or_default_from_ini_section<S: AsRef<str>>(
   section: &Properties,
   key: S,
@@ -373,12 +559,18 @@ fn get_u8_or_default_from_ini_section<S: AsRef<str>>(
 
 #[cfg(test)]
 mod tests {
+  #[cfg(feature = "ltn")]
   use crate::tables::ConfigurationTables;
   use lumatone_midi::constants::{key_loc_unchecked, LumatoneKeyFunction, MidiChannel, RGBColor};
+  #[cfg(feature = "ltn")]
+  use lumatone_midi::responses::PeripheralChannels;
 
-  use super::{GeneralOptions, KeyDefinition, LumatoneKeyMap};
+  #[cfg(feature = "ltn")]
+  use super::{GeneralOptions, MacroButtonColors};
+  use super::{KeyDefinition, LumatoneKeyMap};
 
   #[test]
+  #[cfg(feature = "ltn")]
   fn test_keymap_to_ini() {
     let mut keymap = LumatoneKeyMap::new();
 
@@ -434,6 +626,32 @@ mod tests {
   }
 
   #[test]
+  fn test_fingerprint_ignores_insertion_order() {
+    let key = |note_num| KeyDefinition {
+      function: LumatoneKeyFunction::NoteOnOff {
+        channel: MidiChannel::default(),
+        note_num,
+      },
+      color: RGBColor::blue(),
+    };
+
+    let mut a = LumatoneKeyMap::new();
+    a.set_key(key_loc_unchecked(1, 0), key(60))
+      .set_key(key_loc_unchecked(3, 5), key(61));
+
+    let mut b = LumatoneKeyMap::new();
+    b.set_key(key_loc_unchecked(3, 5), key(61))
+      .set_key(key_loc_unchecked(1, 0), key(60));
+
+    assert_eq!(a.fingerprint(), b.fingerprint());
+
+    b.set_key(key_loc_unchecked(3, 5), key(62));
+    assert_ne!(a.fingerprint(), b.fingerprint());
+    assert_ne!(LumatoneKeyMap::new().fingerprint(), a.fingerprint());
+  }
+
+  #[test]
+  #[cfg(feature = "ltn")]
   fn test_general_opts_to_ini() {
     let mut keymap = LumatoneKeyMap::new();
 
@@ -443,6 +661,16 @@ mod tests {
       invert_foot_controller: true,
       invert_sustain: true,
       expression_controller_sensitivity: 100,
+      macro_button_colors: Some(MacroButtonColors {
+        active: RGBColor(0xff, 0x80, 0),
+        inactive: RGBColor(0, 0, 0x20),
+      }),
+      peripheral_channels: Some(PeripheralChannels {
+        pitch_wheel: MidiChannel::unchecked(2),
+        mod_wheel: MidiChannel::unchecked(3),
+        expression: MidiChannel::unchecked(4),
+        sustain: MidiChannel::unchecked(16),
+      }),
       config_tables: ConfigurationTables::default(),
     });
 
@@ -453,5 +681,36 @@ mod tests {
     assert_eq!(general.get("InvertFootController"), Some("1"));
     assert_eq!(general.get("InvertSustain"), Some("1"));
     assert_eq!(general.get("ExprCtrlSensivity"), Some("100"));
+    assert_eq!(general.get("ActiveMacroButtonColour"), Some("ff8000"));
+    assert_eq!(general.get("InactiveMacroButtonColour"), Some("000020"));
+    assert_eq!(general.get("PitchWheelChannel"), Some("2"));
+    assert_eq!(general.get("SustainChannel"), Some("16"));
+
+    let parsed = LumatoneKeyMap::from_ini_str(keymap.to_ini_string()).unwrap();
+    assert_eq!(
+      parsed.global_options().macro_button_colors,
+      keymap.global_options().macro_button_colors
+    );
+    assert_eq!(
+      parsed.global_options().expression_controller_sensitivity,
+      100
+    );
+    assert_eq!(
+      parsed.global_options().peripheral_channels,
+      keymap.global_options().peripheral_channels
+    );
+  }
+
+  #[test]
+  #[cfg(feature = "ltn")]
+  fn test_peripheral_channels_from_ini() {
+    let parsed = LumatoneKeyMap::from_ini_str("ExpressionChannel=5\n").unwrap();
+    let channels = parsed.global_options().peripheral_channels.unwrap();
+    assert_eq!(channels.expression, MidiChannel::unchecked(5));
+    assert_eq!(channels.pitch_wheel, MidiChannel::default());
+
+    let parsed = LumatoneKeyMap::from_ini_str("").unwrap();
+    assert!(parsed.global_options().peripheral_channels.is_none());
+    assert!(LumatoneKeyMap::from_ini_str("SustainChannel=17\n").is_err());
   }
 }
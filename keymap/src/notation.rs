@@ -0,0 +1,169 @@
+//! Exports a [Tuning] in forms that notation tools can use, so a score and the board layout
+//! can share one definition of the scale.
+//!
+//! - [note_legend] writes a plain text table of each scale degree, its pitch in cents and
+//!   the nearest 12-TET note name, with the deviation in cents. The deviation column is what
+//!   MuseScore expects in a note's "Tuning" property. [note_legend_with] writes it with the
+//!   user's [DisplayPreferences].
+//! - [lilypond_pitch_names] writes a LilyPond note-name language with one name per scale
+//!   degree, so music can be entered directly in the tuning's degrees.
+
+use std::fmt::Write;
+
+use super::{
+  analysis::DeviationReport,
+  preferences::DisplayPreferences,
+  spelling::{Spelling, SpellingPolicy},
+  tuning::{nearest_12tet, Tuning, CENTS_PER_SEMITONE},
+};
+
+/// Returns the name of a 12-TET MIDI note, using sharps, e.g. `C#4` for note 61. Use a
+/// [SpellingPolicy] for other spellings.
+pub fn note_name(note: i32) -> String {
+  SpellingPolicy::default().note_name(note)
+}
+
+/// Parses a 12-TET note name like `C4`, `F#3`, `Bb2` or `Cb4` into a MIDI note number, the
+/// inverse of [note_name]. Flats are written `b`, and the octave number goes with the
+/// letter, so `Cb4` is note 59.
+pub fn parse_note_name(name: &str) -> Option<i32> {
+  let mut chars = name.chars();
+  let letter = chars.next()?;
+  let rest = chars.as_str();
+  let octave = rest.trim_start_matches(['#', 'b']);
+  let accidentals = &rest[..rest.len() - octave.len()];
+  let alteration = accidentals.matches('#').count() as i8 - accidentals.matches('b').count() as i8;
+  let spelling = Spelling::from_letter(letter, alteration)?;
+  let octave: i32 = octave.parse().ok()?;
+  Some((octave + 1) * 12 + spelling.semitones_above_c())
+}
+
+/// Returns a text table with a row for each degree of one period of `tuning`, starting at
+/// the root. Notes are spelled with the tuning's [spelling policy](Tuning::spelling).
+pub fn note_legend(tuning: &Tuning) -> String {
+  legend(&tuning.name, &DeviationReport::for_period(tuning))
+}
+
+/// Like [note_legend], but names notes and writes cents as `prefs` says.
+pub fn note_legend_with(tuning: &Tuning, prefs: &DisplayPreferences) -> String {
+  legend(
+    &tuning.name,
+    &DeviationReport::for_period(tuning).with_preferences(prefs),
+  )
+}
+
+fn legend(name: &str, report: &DeviationReport) -> String {
+  let prefs = report.display_preferences();
+  let mut out = String::new();
+  writeln!(out, "# {name}").unwrap();
+  writeln!(out, "# degree\tcents\tnearest\tdeviation").unwrap();
+  for n in &report.notes {
+    writeln!(
+      out,
+      "{}\t{}\t{}\t{}",
+      n.degree,
+      prefs.cents(n.cents),
+      report.spelling.note_name(n.nearest_note),
+      prefs.deviation(n.deviation)
+    )
+    .unwrap();
+  }
+  out
+}
+
+/// Returns a name for a scale degree that LilyPond accepts, which can only contain letters:
+/// `dega`, `degb`, ... `degz`, `degba`, and so on.
+pub fn lilypond_degree_name(degree: usize) -> String {
+  let mut letters = vec![];
+  let mut n = degree;
+  loop {
+    letters.push((b'a' + (n % 26) as u8) as char);
+    n /= 26;
+    if n == 0 {
+      break;
+    }
+  }
+  letters.reverse();
+  format!("deg{}", letters.into_iter().collect::<String>())
+}
+
+/// Returns LilyPond source that defines a note-name language called `language`, with a
+/// name (see [lilypond_degree_name]) for each degree of `tuning`, and switches to it.
+///
+/// Each name is spelled as its nearest 12-TET note (using the tuning's
+/// [spelling policy](Tuning::spelling)), altered by the deviation in cents,
+/// relative to the octave of the tuning's root. Use LilyPond's octave marks for the other
+/// periods, which is only exact when the tuning's period is an octave.
+pub fn lilypond_pitch_names(tuning: &Tuning, language: &str) -> String {
+  let root_octave = (tuning.root_pitch.round() as i32).div_euclid(12);
+  let mut out = String::new();
+  writeln!(out, "% {}", tuning.name).unwrap();
+  writeln!(out, "#(set! language-pitch-names").unwrap();
+  writeln!(out, "  (append language-pitch-names").unwrap();
+  writeln!(out, "    (list `({language} . (").unwrap();
+  for degree in 0..tuning.len() {
+    let (note, deviation) = nearest_12tet(tuning.midi_pitch(tuning.root_index + degree as i32));
+    let spelling = tuning.spelling.spell(note);
+    let step = spelling.step;
+    // Like LilyPond's own note names, the root's octave is octave -1 (entered without
+    // octave marks). Alterations are in whole tones.
+    let octave = (note - spelling.alteration as i32).div_euclid(12) - root_octave - 1;
+    let alteration = (spelling.alteration as f64 * CENTS_PER_SEMITONE + deviation).round() as i32;
+    writeln!(
+      out,
+      "      ({} . ,(ly:make-pitch {octave} {step} (/ {alteration} 200)))",
+      lilypond_degree_name(degree)
+    )
+    .unwrap();
+  }
+  writeln!(out, "    )))))").unwrap();
+  writeln!(out, "\\language \"{language}\"").unwrap();
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::spelling::{Accidentals, OctaveNumbering};
+
+  #[test]
+  fn test_exports() {
+    let tuning = Tuning::from_scale("5-limit", &[203.91, 386.31, 1200.0]);
+    let legend = note_legend(&tuning);
+    assert_eq!(
+      legend.lines().collect::<Vec<_>>(),
+      vec![
+        "# 5-limit",
+        "# degree\tcents\tnearest\tdeviation",
+        "0\t0.00\tC4\t+0.00",
+        "1\t203.91\tD4\t+3.91",
+        "2\t386.31\tE4\t-13.69",
+      ]
+    );
+
+    assert_eq!(lilypond_degree_name(0), "dega");
+    assert_eq!(lilypond_degree_name(27), "degbb");
+
+    let ly = lilypond_pitch_names(&tuning, "fivelimit");
+    assert!(ly.contains("(degb . ,(ly:make-pitch -1 1 (/ 4 200)))"));
+    assert!(ly.contains("(degc . ,(ly:make-pitch -1 2 (/ -14 200)))"));
+    assert!(ly.ends_with("\\language \"fivelimit\"\n"));
+
+    let mut flats = Tuning::from_scale("flats", &[300.0, 1200.0]);
+    flats.spelling = SpellingPolicy::new(Accidentals::Flats);
+    assert!(note_legend(&flats).contains("1\t300.00\tEb4\t+0.00"));
+    assert!(
+      lilypond_pitch_names(&flats, "flats").contains("(degb . ,(ly:make-pitch -1 2 (/ -100 200)))")
+    );
+    assert_eq!(parse_note_name("Cb4"), Some(59));
+
+    let prefs = DisplayPreferences {
+      accidentals: Accidentals::Flats,
+      cents_precision: 1,
+      octave_numbering: OctaveNumbering::Yamaha,
+      ..Default::default()
+    };
+    let legend = note_legend_with(&Tuning::equal(12), &prefs);
+    assert!(legend.contains("1\t100.0\tDb3\t+0.0"));
+  }
+}
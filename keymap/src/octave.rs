@@ -0,0 +1,164 @@
+//! Groups keys by the octave (or, for tunings that don't repeat at the octave, the period)
+//! of the tuning they play, and colors each band differently.
+//!
+//! On large-EDO layouts the same scale degree shows up many times across the board, so
+//! coloring by degree alone makes it hard to tell which octave a key is in. [OctaveColoring]
+//! keeps the hue for each band and steps its lightness evenly in the Oklab color space (see
+//! [gradient](crate::gradient)), from dark in the lowest band to light in the highest, so
+//! the steps look the same size at every brightness.
+
+use std::collections::BTreeMap;
+
+use lumatone_midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation, RGBColor};
+
+use super::{
+  gradient::with_lightness,
+  ltn::{KeyDefinition, LumatoneKeyMap},
+  tuning::{pitch_index, Tuning},
+};
+
+/// The note keys of a keymap, grouped by the number of periods above the tuning's root they
+/// play. Keys that don't send notes aren't in any band.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctaveBands {
+  pub bands: BTreeMap<i32, Vec<LumatoneKeyLocation>>,
+}
+
+impl OctaveBands {
+  pub fn new(keymap: &LumatoneKeyMap, tuning: &Tuning) -> Self {
+    let mut bands: BTreeMap<i32, Vec<LumatoneKeyLocation>> = BTreeMap::new();
+    for location in LumatoneKeyLocation::all() {
+      let (channel, note) = match keymap.get_key(location).map(|def| def.function) {
+        Some(LumatoneKeyFunction::NoteOnOff { channel, note_num })
+        | Some(LumatoneKeyFunction::LumaTouch {
+          channel, note_num, ..
+        }) => (channel, note_num),
+        _ => continue,
+      };
+      let (_, octave) = tuning.degree(pitch_index(channel, note));
+      bands.entry(octave).or_default().push(location);
+    }
+    OctaveBands { bands }
+  }
+
+  /// The octave a key is in, relative to the tuning's root.
+  pub fn octave_of(&self, location: &LumatoneKeyLocation) -> Option<i32> {
+    self
+      .bands
+      .iter()
+      .find(|(_, keys)| keys.contains(location))
+      .map(|(octave, _)| *octave)
+  }
+
+  /// The lowest and highest octaves with keys in them.
+  pub fn range(&self) -> Option<(i32, i32)> {
+    let lowest = *self.bands.keys().next()?;
+    let highest = *self.bands.keys().next_back()?;
+    Some((lowest, highest))
+  }
+
+  pub fn len(&self) -> usize {
+    self.bands.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.bands.is_empty()
+  }
+}
+
+/// Colors each octave band of a keymap, cycling through `colors` for the hue and stepping
+/// the lightness from `darkest` in the lowest band to `lightest` in the highest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OctaveColoring {
+  pub colors: Vec<RGBColor>,
+
+  /// The Oklab lightness of the lowest band, from 0.0 to 1.0.
+  pub darkest: f32,
+
+  /// The Oklab lightness of the highest band, from 0.0 to 1.0.
+  pub lightest: f32,
+}
+
+impl Default for OctaveColoring {
+  fn default() -> Self {
+    OctaveColoring {
+      colors: vec![RGBColor(0x30, 0x80, 0xff)],
+      darkest: 0.35,
+      lightest: 0.9,
+    }
+  }
+}
+
+impl OctaveColoring {
+  /// Returns the color of each band in `bands`, by octave.
+  pub fn band_colors(&self, bands: &OctaveBands) -> BTreeMap<i32, RGBColor> {
+    let steps = bands.len().saturating_sub(1).max(1) as f32;
+    bands
+      .bands
+      .keys()
+      .enumerate()
+      .map(|(i, octave)| {
+        let hue = match self.colors.len() {
+          0 => RGBColor(0xff, 0xff, 0xff),
+          n => self.colors[i % n],
+        };
+        let lightness = self.darkest + (self.lightest - self.darkest) * i as f32 / steps;
+        (*octave, with_lightness(hue, lightness))
+      })
+      .collect()
+  }
+
+  /// Recolors the note keys of `keymap` by octave band, leaving their functions and the
+  /// other keys alone.
+  pub fn apply(&self, keymap: &mut LumatoneKeyMap, tuning: &Tuning) {
+    let bands = OctaveBands::new(keymap, tuning);
+    for (octave, color) in self.band_colors(&bands) {
+      for location in &bands.bands[&octave] {
+        let function = match keymap.get_key(*location) {
+          Some(def) => def.function,
+          None => continue,
+        };
+        keymap.set_key(*location, KeyDefinition { function, color });
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::layout::IsomorphicLayout;
+  use lumatone_midi::constants::key_loc_unchecked;
+
+  #[test]
+  fn test_octave_bands_step_lightness() {
+    let tuning = Tuning::equal(31);
+    let anchor = key_loc_unchecked(3, 27);
+    let mut keymap = IsomorphicLayout::new(5, -3, anchor, 60).generate(&tuning, &[]);
+
+    let bands = OctaveBands::new(&keymap, &tuning);
+    assert_eq!(bands.octave_of(&anchor), Some(0));
+    let (lowest, highest) = bands.range().unwrap();
+    assert!(lowest < 0 && highest > 0);
+    // keys whose pitch doesn't fit on a channel are disabled, and aren't in a band
+    let disabled = LumatoneKeyLocation::all()
+      .into_iter()
+      .filter(|loc| keymap.get_key(*loc).unwrap().function == LumatoneKeyFunction::Disabled)
+      .count();
+    assert_eq!(
+      bands.bands.values().map(Vec::len).sum::<usize>() + disabled,
+      LumatoneKeyLocation::all().len()
+    );
+
+    let coloring = OctaveColoring::default();
+    let colors = coloring.band_colors(&bands);
+    let lightness: Vec<u32> = colors
+      .values()
+      .map(|RGBColor(r, g, b)| *r as u32 + *g as u32 + *b as u32)
+      .collect();
+    assert!(lightness.windows(2).all(|w| w[0] < w[1]), "{lightness:?}");
+
+    coloring.apply(&mut keymap, &tuning);
+    assert_eq!(keymap.get_key(anchor).unwrap().color, colors[&0]);
+  }
+}
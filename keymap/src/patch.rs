@@ -0,0 +1,528 @@
+//! Patches: the difference between two keymaps, which can be saved, shared, and applied to
+//! another keymap. A patch can hold just part of a change, e.g. only the key colors, so a
+//! color scheme can be layered onto any layout.
+//!
+//! Each change records the value it expects to replace as well as the new value. Applying
+//! a patch to a keymap that has something else there is a [PatchConflict], much like a
+//! merge conflict, so a patch made against one layout doesn't silently clobber another.
+//!
+//! With the `ltn` feature, patches are saved as ini files alongside .ltn presets. Each
+//! change is written `<old>><new>`, with `-` for a key the old keymap didn't define, and key
+//! functions are written `<type>:<Chan>:<Key>`. The type is the code the device uses, which
+//! is the .ltn `KTyp` plus 16 for faders and LumaTouch keys whose fader up position is
+//! null, so that setting survives a round trip:
+//!
+//! ```text
+//! [Options]
+//! LightOnKeyStrokes=0>1
+//!
+//! [Board1]
+//! Col_5=ff0000>00ff00
+//! Func_5=1:1:60>1:2:60
+//! ```
+
+use std::{collections::HashMap, fmt::Display};
+
+use lumatone_midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation, RGBColor};
+
+use super::ltn::{GeneralOptions, KeyDefinition, LumatoneKeyMap};
+
+#[cfg(feature = "ltn")]
+use super::error::LumatoneKeymapError;
+#[cfg(feature = "ltn")]
+use ini::Ini;
+#[cfg(feature = "ltn")]
+use lumatone_midi::constants::{key_loc_unchecked, MidiChannel};
+
+/// A change from one value to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change<T> {
+  /// The value the change replaces, or `None` if it wasn't set.
+  pub from: Option<T>,
+  pub to: T,
+}
+
+/// Changes to one key. Fields that are `None` are left alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KeyPatch {
+  pub function: Option<Change<LumatoneKeyFunction>>,
+  pub color: Option<Change<RGBColor>>,
+}
+
+impl KeyPatch {
+  pub fn is_empty(&self) -> bool {
+    self.function.is_none() && self.color.is_none()
+  }
+}
+
+/// The general options a patch can change, named as in .ltn files. The configuration
+/// tables and macro button colors aren't patchable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeneralOption {
+  AfterTouchActive,
+  LightOnKeyStrokes,
+  InvertFootController,
+  InvertSustain,
+  ExprCtrlSensivity,
+}
+
+impl GeneralOption {
+  pub const ALL: [GeneralOption; 5] = [
+    GeneralOption::AfterTouchActive,
+    GeneralOption::LightOnKeyStrokes,
+    GeneralOption::InvertFootController,
+    GeneralOption::InvertSustain,
+    GeneralOption::ExprCtrlSensivity,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    use GeneralOption::*;
+    match self {
+      AfterTouchActive => "AfterTouchActive",
+      LightOnKeyStrokes => "LightOnKeyStrokes",
+      InvertFootController => "InvertFootController",
+      InvertSustain => "InvertSustain",
+      ExprCtrlSensivity => "ExprCtrlSensivity",
+    }
+  }
+
+  /// The option's value, with `true` as 1 and `false` as 0.
+  pub fn get(&self, opts: &GeneralOptions) -> u8 {
+    use GeneralOption::*;
+    match self {
+      AfterTouchActive => opts.after_touch_active as u8,
+      LightOnKeyStrokes => opts.light_on_key_strokes as u8,
+      InvertFootController => opts.invert_foot_controller as u8,
+      InvertSustain => opts.invert_sustain as u8,
+      ExprCtrlSensivity => opts.expression_controller_sensitivity,
+    }
+  }
+
+  pub fn set(&self, opts: &mut GeneralOptions, value: u8) {
+    use GeneralOption::*;
+    match self {
+      AfterTouchActive => opts.after_touch_active = value != 0,
+      LightOnKeyStrokes => opts.light_on_key_strokes = value != 0,
+      InvertFootController => opts.invert_foot_controller = value != 0,
+      InvertSustain => opts.invert_sustain = value != 0,
+      ExprCtrlSensivity => opts.expression_controller_sensitivity = value,
+    }
+  }
+}
+
+/// What a [PatchConflict] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchTarget {
+  Option(GeneralOption),
+  KeyFunction(LumatoneKeyLocation),
+  KeyColor(LumatoneKeyLocation),
+}
+
+/// A change whose old value doesn't match the keymap it's applied to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchConflict {
+  pub target: PatchTarget,
+  pub expected: String,
+  pub found: String,
+}
+
+impl Display for PatchConflict {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let target = match self.target {
+      PatchTarget::Option(opt) => opt.name().to_string(),
+      PatchTarget::KeyFunction(loc) => format!("function of {loc}"),
+      PatchTarget::KeyColor(loc) => format!("color of {loc}"),
+    };
+    write!(
+      f,
+      "{target}: expected {}, found {}",
+      self.expected, self.found
+    )
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeymapPatch {
+  pub options: HashMap<GeneralOption, Change<u8>>,
+  pub keys: HashMap<LumatoneKeyLocation, KeyPatch>,
+}
+
+impl KeymapPatch {
+  pub fn new() -> Self {
+    KeymapPatch::default()
+  }
+
+  /// The changes that turn `base` into `target`. Keys that `target` doesn't define are
+  /// left out, rather than patched to be removed.
+  pub fn diff(base: &LumatoneKeyMap, target: &LumatoneKeyMap) -> Self {
+    let mut patch = KeymapPatch::new();
+    for opt in GeneralOption::ALL {
+      let from = opt.get(base.global_options());
+      let to = opt.get(target.global_options());
+      if from != to {
+        patch.options.insert(
+          opt,
+          Change {
+            from: Some(from),
+            to,
+          },
+        );
+      }
+    }
+
+    for loc in LumatoneKeyLocation::all() {
+      let to = match target.get_key(loc) {
+        Some(def) => def,
+        None => continue,
+      };
+      let from = base.get_key(loc);
+      let key = KeyPatch {
+        function: match from {
+          Some(def) if def.function == to.function => None,
+          _ => Some(Change {
+            from: from.map(|def| def.function),
+            to: to.function,
+          }),
+        },
+        color: match from {
+          Some(def) if def.color == to.color => None,
+          _ => Some(Change {
+            from: from.map(|def| def.color),
+            to: to.color,
+          }),
+        },
+      };
+      if !key.is_empty() {
+        patch.keys.insert(loc, key);
+      }
+    }
+    patch
+  }
+
+  /// Just the color changes, e.g. to share a color scheme without the layout.
+  pub fn colors_only(&self) -> Self {
+    let keys = self
+      .keys
+      .iter()
+      .filter(|(_, key)| key.color.is_some())
+      .map(|(loc, key)| {
+        let key = KeyPatch {
+          function: None,
+          color: key.color,
+        };
+        (*loc, key)
+      })
+      .collect();
+    KeymapPatch {
+      options: HashMap::new(),
+      keys,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.options.is_empty() && self.keys.is_empty()
+  }
+
+  /// The changes whose old value doesn't match `keymap`, in [LumatoneKeyLocation::all]
+  /// order after any options. Changes that `keymap` already has aren't conflicts.
+  pub fn conflicts(&self, keymap: &LumatoneKeyMap) -> Vec<PatchConflict> {
+    let mut conflicts = vec![];
+    for opt in GeneralOption::ALL {
+      if let Some(change) = self.options.get(&opt) {
+        let found = opt.get(keymap.global_options());
+        if change.from.is_some() && change.from != Some(found) && change.to != found {
+          conflicts.push(PatchConflict {
+            target: PatchTarget::Option(opt),
+            expected: describe(change.from),
+            found: found.to_string(),
+          });
+        }
+      }
+    }
+
+    for loc in LumatoneKeyLocation::all() {
+      let key = match self.keys.get(&loc) {
+        Some(key) => key,
+        None => continue,
+      };
+      let current = keymap.get_key(loc);
+      if let Some(change) = &key.function {
+        let found = current.map(|def| def.function);
+        if found != change.from && found != Some(change.to) {
+          conflicts.push(PatchConflict {
+            target: PatchTarget::KeyFunction(loc),
+            expected: describe(change.from),
+            found: describe(found),
+          });
+        }
+      }
+      if let Some(change) = &key.color {
+        let found = current.map(|def| def.color);
+        if found != change.from && found != Some(change.to) {
+          conflicts.push(PatchConflict {
+            target: PatchTarget::KeyColor(loc),
+            expected: describe(change.from.map(|c| c.to_hex_string())),
+            found: describe(found.map(|c| c.to_hex_string())),
+          });
+        }
+      }
+    }
+    conflicts
+  }
+
+  /// Applies the patch to `keymap`, unless it conflicts, in which case `keymap` is left
+  /// as it was.
+  pub fn apply(&self, keymap: &mut LumatoneKeyMap) -> Result<(), Vec<PatchConflict>> {
+    let conflicts = self.conflicts(keymap);
+    if !conflicts.is_empty() {
+      return Err(conflicts);
+    }
+    self.apply_unchecked(keymap);
+    Ok(())
+  }
+
+  /// Applies the patch to `keymap`, overwriting whatever is there. Keys the keymap doesn't
+  /// define get a disabled function or a black color for whichever part isn't patched.
+  pub fn apply_unchecked(&self, keymap: &mut LumatoneKeyMap) {
+    for (opt, change) in &self.options {
+      opt.set(keymap.global_options_mut(), change.to);
+    }
+    for (loc, key) in &self.keys {
+      let current = keymap.get_key(*loc);
+      let function = match key.function {
+        Some(change) => change.to,
+        None => current.map_or(LumatoneKeyFunction::Disabled, |def| def.function),
+      };
+      let color = match key.color {
+        Some(change) => change.to,
+        None => current.map_or(RGBColor(0, 0, 0), |def| def.color),
+      };
+      keymap.set_key(*loc, KeyDefinition { function, color });
+    }
+  }
+}
+
+fn describe<T: std::fmt::Debug>(value: Option<T>) -> String {
+  match value {
+    Some(value) => format!("{value:?}"),
+    None => "nothing".to_string(),
+  }
+}
+
+#[cfg(feature = "ltn")]
+impl KeymapPatch {
+  pub fn to_ini(&self) -> Ini {
+    let mut conf = Ini::new();
+    for opt in GeneralOption::ALL {
+      if let Some(change) = self.options.get(&opt) {
+        let value = change_to_string(change, |v| v.to_string());
+        conf.with_section(Some("Options")).set(opt.name(), value);
+      }
+    }
+
+    for loc in LumatoneKeyLocation::all() {
+      let key = match self.keys.get(&loc) {
+        Some(key) => key,
+        None => continue,
+      };
+      let section = format!("Board{}", loc.board_index() as u8);
+      let key_index: u8 = loc.key_index().into();
+      if let Some(change) = &key.color {
+        let value = change_to_string(change, |c| c.to_hex_string());
+        conf
+          .with_section(Some(section.clone()))
+          .set(format!("Col_{key_index}"), value);
+      }
+      if let Some(change) = &key.function {
+        let value = change_to_string(change, |f| {
+          format!(
+            "{}:{}:{}",
+            f.type_code(),
+            f.midi_channel_num(),
+            f.note_or_cc_num()
+          )
+        });
+        conf
+          .with_section(Some(section))
+          .set(format!("Func_{key_index}"), value);
+      }
+    }
+    conf
+  }
+
+  pub fn to_ini_string(&self) -> String {
+    let mut w = Vec::new();
+    self.to_ini().write_to(&mut w).expect("ini to string error");
+    String::from_utf8(w).expect("utf8 error")
+  }
+
+  pub fn from_ini_str<S: AsRef<str>>(source: S) -> Result<KeymapPatch, LumatoneKeymapError> {
+    let ini = Ini::load_from_str(source.as_ref())?;
+    let invalid = |msg: String| LumatoneKeymapError::InvalidPatch(msg);
+    let mut patch = KeymapPatch::new();
+
+    if let Some(section) = ini.section(Some("Options")) {
+      for (name, value) in section.iter() {
+        let opt = GeneralOption::ALL
+          .into_iter()
+          .find(|opt| opt.name() == name)
+          .ok_or_else(|| invalid(format!("unknown option {name}")))?;
+        let change = parse_change(value, |v| v.parse::<u8>().ok())
+          .ok_or_else(|| invalid(format!("invalid change to {name}: {value}")))?;
+        patch.options.insert(opt, change);
+      }
+    }
+
+    for b in 1..=5 {
+      let section = match ini.section(Some(format!("Board{b}"))) {
+        Some(section) => section,
+        None => continue,
+      };
+      for (name, value) in section.iter() {
+        let bad_value = || invalid(format!("invalid change to Board{b} {name}: {value}"));
+        let (field, index) = name
+          .split_once('_')
+          .and_then(|(field, index)| Some((field, index.parse::<u8>().ok()?)))
+          .filter(|(_, index)| *index <= 55)
+          .ok_or_else(|| invalid(format!("unknown key Board{b} {name}")))?;
+        let key = patch.keys.entry(key_loc_unchecked(b, index)).or_default();
+        match field {
+          "Col" => key.color = Some(parse_change(value, parse_color).ok_or_else(bad_value)?),
+          "Func" => key.function = Some(parse_change(value, parse_function).ok_or_else(bad_value)?),
+          _ => return Err(invalid(format!("unknown key Board{b} {name}"))),
+        }
+      }
+    }
+    Ok(patch)
+  }
+}
+
+#[cfg(feature = "ltn")]
+fn change_to_string<T, F: Fn(&T) -> String>(change: &Change<T>, fmt: F) -> String {
+  let from = change.from.as_ref().map_or("-".to_string(), &fmt);
+  format!("{from}>{}", fmt(&change.to))
+}
+
+#[cfg(feature = "ltn")]
+fn parse_change<T, F: Fn(&str) -> Option<T>>(s: &str, parse: F) -> Option<Change<T>> {
+  let (from, to) = s.split_once('>')?;
+  let from = match from.trim() {
+    "-" => None,
+    from => Some(parse(from)?),
+  };
+  Some(Change {
+    from,
+    to: parse(to.trim())?,
+  })
+}
+
+#[cfg(feature = "ltn")]
+fn parse_color(s: &str) -> Option<RGBColor> {
+  match s.len() {
+    6 => u32::from_str_radix(s, 16).ok().map(RGBColor::from),
+    _ => None,
+  }
+}
+
+#[cfg(feature = "ltn")]
+fn parse_function(s: &str) -> Option<LumatoneKeyFunction> {
+  let mut parts = s.split(':').map(|part| part.parse::<u8>().ok());
+  match (parts.next(), parts.next(), parts.next(), parts.next()) {
+    (Some(Some(type_code)), Some(Some(chan)), Some(Some(num)), None) => {
+      LumatoneKeyFunction::from_type_code(type_code, MidiChannel::new(chan)?, num)
+    }
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::{key_loc_unchecked, MidiChannel};
+
+  fn note(note_num: u8, color: RGBColor) -> KeyDefinition {
+    KeyDefinition {
+      function: LumatoneKeyFunction::NoteOnOff {
+        channel: MidiChannel::default(),
+        note_num,
+      },
+      color,
+    }
+  }
+
+  #[test]
+  fn test_color_patch_applies_to_another_layout() {
+    let a = key_loc_unchecked(1, 0);
+    let b = key_loc_unchecked(1, 1);
+    let mut base = LumatoneKeyMap::new();
+    base
+      .set_key(a, note(60, RGBColor::red()))
+      .set_key(b, note(61, RGBColor::red()));
+    let mut themed = LumatoneKeyMap::new();
+    themed
+      .set_key(a, note(62, RGBColor::blue()))
+      .set_key(b, note(61, RGBColor::red()));
+    themed.global_options_mut().light_on_key_strokes = true;
+
+    let patch = KeymapPatch::diff(&base, &themed);
+    assert_eq!(patch.keys.len(), 1);
+    assert_eq!(patch.options.len(), 1);
+    let colors = patch.colors_only();
+    assert!(colors.options.is_empty());
+    assert_eq!(colors.keys[&a].function, None);
+
+    // another layout with the same colors takes the color scheme but keeps its notes
+    let mut other = LumatoneKeyMap::new();
+    other.set_key(a, note(48, RGBColor::red()));
+    colors.apply(&mut other).unwrap();
+    let key = other.get_key(a).unwrap();
+    assert_eq!(key.color, RGBColor::blue());
+    assert_eq!(key.function, note(48, RGBColor::red()).function);
+    // applying again is fine, since the change is already there
+    assert!(colors.apply(&mut other).is_ok());
+
+    let mut green = LumatoneKeyMap::new();
+    green.set_key(a, note(60, RGBColor::green()));
+    let conflicts = colors.apply(&mut green).unwrap_err();
+    assert_eq!(conflicts.len(), 1);
+    assert_eq!(conflicts[0].target, PatchTarget::KeyColor(a));
+    assert_eq!(green.get_key(a).unwrap().color, RGBColor::green());
+
+    #[cfg(feature = "ltn")]
+    {
+      let text = patch.to_ini_string();
+      assert!(text.contains("Col_0=ff0000>0000ff"));
+      assert!(text.contains("Func_0=1:1:60>1:1:62"));
+      assert!(text.contains("LightOnKeyStrokes=0>1"));
+      assert_eq!(KeymapPatch::from_ini_str(&text).unwrap(), patch);
+      assert!(KeymapPatch::from_ini_str("[Board1]\nCol_0=red>blue\n").is_err());
+    }
+  }
+
+  #[cfg(feature = "ltn")]
+  #[test]
+  fn test_fader_up_survives_a_round_trip() {
+    let a = key_loc_unchecked(1, 0);
+    let cc = |fader_up_is_null| KeyDefinition {
+      function: LumatoneKeyFunction::ContinuousController {
+        channel: MidiChannel::unchecked(2),
+        cc_num: 7,
+        fader_up_is_null,
+      },
+      color: RGBColor::red(),
+    };
+    let mut base = LumatoneKeyMap::new();
+    base.set_key(a, cc(false));
+    let mut faders = LumatoneKeyMap::new();
+    faders.set_key(a, cc(true));
+
+    let patch = KeymapPatch::diff(&base, &faders);
+    let text = patch.to_ini_string();
+    assert!(text.contains("Func_0=2:2:7>18:2:7"));
+    let parsed = KeymapPatch::from_ini_str(&text).unwrap();
+    assert_eq!(parsed, patch);
+
+    parsed.apply(&mut base).unwrap();
+    assert_eq!(base.get_key(a).unwrap().function, cc(true).function);
+  }
+}
@@ -0,0 +1,204 @@
+//! Static pitch-bend offsets, for playing a [Tuning] on synths that don't support MTS or
+//! MPE.
+//!
+//! Each key in a keymap sends a channel and note number, which picks a pitch in the tuning
+//! (see [pitch_index]). A 12-TET synth plays that note number in 12-TET, so the key needs a
+//! pitch bend of the difference. Pitch bend applies to a whole channel, so this only works
+//! when every key on a channel needs the same bend: [LumatoneKeyMap::plan_pitch_bends]
+//! works out one bend per channel and reports each key that doesn't fit.
+//!
+//! A key doesn't fit if:
+//!
+//! - its bend differs from the bend of the first key on its channel (in
+//!   [LumatoneKeyLocation::all] order) by more than the tolerance, or
+//! - its bend is outside the synth's pitch bend range.
+//!
+//! Send the plan's [messages](BendPlan::messages) once, e.g. when a scene loads, and set the
+//! synth's pitch bend range to match.
+
+use std::collections::BTreeMap;
+
+use lumatone_midi::{
+  constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel},
+  events::ChannelMessage,
+};
+
+use super::{
+  ltn::LumatoneKeyMap,
+  tuning::{pitch_index, Tuning},
+};
+
+/// The pitch bend range most synths default to, in semitones.
+pub const DEFAULT_BEND_RANGE: f64 = 2.0;
+
+/// The default tolerance for [LumatoneKeyMap::plan_pitch_bends], in cents.
+pub const DEFAULT_BEND_TOLERANCE: f64 = 1.0;
+
+const BEND_CENTER: f64 = 8192.0;
+const BEND_MAX: f64 = 16383.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelBend {
+  pub channel: MidiChannel,
+  pub semitones: f64,
+}
+
+impl ChannelBend {
+  /// Returns the pitch bend message for this bend, on a synth with the given bend range in
+  /// semitones.
+  pub fn to_message(&self, bend_range: f64) -> ChannelMessage {
+    let value = BEND_CENTER + self.semitones / bend_range * BEND_CENTER;
+    ChannelMessage::PitchBend {
+      channel: self.channel,
+      value: value.round().clamp(0.0, BEND_MAX) as u16,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BendProblem {
+  /// The key needs a different bend from the other keys on its channel.
+  Conflict {
+    location: LumatoneKeyLocation,
+    channel: MidiChannel,
+    channel_semitones: f64,
+    key_semitones: f64,
+  },
+
+  /// The key needs more bend than the synth's bend range allows.
+  OutOfRange {
+    location: LumatoneKeyLocation,
+    channel: MidiChannel,
+    key_semitones: f64,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BendPlan {
+  pub bend_range: f64,
+
+  /// One bend per channel used by the keymap's note keys, in channel order.
+  pub bends: Vec<ChannelBend>,
+
+  pub problems: Vec<BendProblem>,
+}
+
+impl BendPlan {
+  /// Returns true if every key can be played in tune with the planned bends.
+  pub fn is_valid(&self) -> bool {
+    self.problems.is_empty()
+  }
+
+  /// Returns a pitch bend message for each channel.
+  pub fn messages(&self) -> Vec<ChannelMessage> {
+    self
+      .bends
+      .iter()
+      .map(|b| b.to_message(self.bend_range))
+      .collect()
+  }
+}
+
+impl LumatoneKeyMap {
+  /// Works out a bend for each channel so that note keys play the pitches of `tuning` on a
+  /// 12-TET synth with a pitch bend range of `bend_range` semitones. Keys whose bends differ
+  /// by up to `tolerance` cents can share a channel.
+  pub fn plan_pitch_bends(&self, tuning: &Tuning, bend_range: f64, tolerance: f64) -> BendPlan {
+    let mut bends: BTreeMap<u8, ChannelBend> = BTreeMap::new();
+    let mut problems = vec![];
+
+    for location in LumatoneKeyLocation::all() {
+      let (channel, note) = match self.get_key(location).map(|def| def.function) {
+        Some(LumatoneKeyFunction::NoteOnOff { channel, note_num })
+        | Some(LumatoneKeyFunction::LumaTouch {
+          channel, note_num, ..
+        }) => (channel, note_num),
+        _ => continue,
+      };
+      let key_semitones = tuning.midi_pitch(pitch_index(channel, note)) - note as f64;
+
+      if key_semitones.abs() > bend_range {
+        problems.push(BendProblem::OutOfRange {
+          location,
+          channel,
+          key_semitones,
+        });
+        continue;
+      }
+
+      let bend = bends.entry(channel.get()).or_insert(ChannelBend {
+        channel,
+        semitones: key_semitones,
+      });
+      if (bend.semitones - key_semitones).abs() * 100.0 > tolerance {
+        problems.push(BendProblem::Conflict {
+          location,
+          channel,
+          channel_semitones: bend.semitones,
+          key_semitones,
+        });
+      }
+    }
+
+    BendPlan {
+      bend_range,
+      bends: bends.into_values().collect(),
+      problems,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ltn::KeyDefinition;
+  use lumatone_midi::constants::{key_loc_unchecked, RGBColor};
+
+  fn note_key(keymap: &mut LumatoneKeyMap, key: u8, channel: u8, note_num: u8) {
+    keymap.set_key(
+      key_loc_unchecked(1, key),
+      KeyDefinition {
+        function: LumatoneKeyFunction::NoteOnOff {
+          channel: MidiChannel::new(channel).unwrap(),
+          note_num,
+        },
+        color: RGBColor(0, 0, 0),
+      },
+    );
+  }
+
+  #[test]
+  fn test_plan_pitch_bends() {
+    // A 12-TET scale a quarter tone sharp, on channel 1
+    let mut tuning = Tuning::equal(12);
+    tuning.root_pitch = 60.5;
+
+    let mut keymap = LumatoneKeyMap::new();
+    note_key(&mut keymap, 0, 1, 60);
+    note_key(&mut keymap, 1, 1, 64);
+    let plan = keymap.plan_pitch_bends(&tuning, DEFAULT_BEND_RANGE, DEFAULT_BEND_TOLERANCE);
+    assert!(plan.is_valid());
+    assert_eq!(
+      plan.messages(),
+      vec![ChannelMessage::PitchBend {
+        channel: MidiChannel::new(1).unwrap(),
+        value: 10240,
+      }]
+    );
+
+    // channel 2 continues at pitch index 128, far outside the bend range
+    note_key(&mut keymap, 2, 2, 0);
+    let plan = keymap.plan_pitch_bends(&tuning, DEFAULT_BEND_RANGE, DEFAULT_BEND_TOLERANCE);
+    assert!(matches!(
+      plan.problems[..],
+      [BendProblem::OutOfRange { .. }]
+    ));
+
+    // a 24-EDO layout on one channel can't share a bend
+    let mut keymap = LumatoneKeyMap::new();
+    note_key(&mut keymap, 0, 1, 60);
+    note_key(&mut keymap, 1, 1, 61);
+    let plan = keymap.plan_pitch_bends(&Tuning::equal(24), 2.0, 1.0);
+    assert!(matches!(plan.problems[..], [BendProblem::Conflict { .. }]));
+  }
+}
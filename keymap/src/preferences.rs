@@ -0,0 +1,222 @@
+//! The user's display conventions: how notes are named and how pitches are labelled.
+//!
+//! [DisplayPreferences] covers the choices that differ between musicians and tools:
+//!
+//! - whether notes outside a key are written with sharps or flats,
+//! - how many decimal places cents are shown with,
+//! - whether just intervals are labelled as ratios (`3/2`) or in cents (`701.96`),
+//! - whether middle C is C4 or C3 (see [OctaveNumbering]).
+//!
+//! Note names come from a [Tuning]'s [spelling policy](Tuning::spelling), so
+//! [DisplayPreferences::apply] covers everything that names notes from a tuning, e.g.
+//! [RetuneClient::note_name](crate::retune::RetuneClient::note_name). Modules that format
+//! cents or intervals take the preferences directly: see
+//! [DeviationReport::with_preferences](crate::analysis::DeviationReport::with_preferences),
+//! [note_legend_with](crate::notation::note_legend_with),
+//! [Lattice::with_preferences](crate::lattice::Lattice::with_preferences) and
+//! [render_svg_with](crate::svg::render_svg_with).
+//!
+//! Preferences are saved as text, one `name=value` line per setting. Lines starting with `#`
+//! are comments, and settings that aren't recognized are ignored, so files written by newer
+//! versions still load:
+//!
+//! ```text
+//! accidentals=flats
+//! cents_precision=1
+//! interval_labels=cents
+//! octave_numbering=yamaha
+//! ```
+
+use std::{fmt::Display, str::FromStr};
+
+use super::{
+  error::LumatoneKeymapError,
+  spelling::{Accidentals, OctaveNumbering, SpellingPolicy},
+  tuning::Tuning,
+};
+
+/// The most decimal places cents are shown with.
+pub const MAX_CENTS_PRECISION: usize = 6;
+
+/// How intervals with a known frequency ratio are labelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IntervalLabels {
+  /// As ratios like `5/4`. Intervals without a ratio are labelled in cents.
+  #[default]
+  Ratios,
+
+  /// In cents, e.g. `386.31`.
+  Cents,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayPreferences {
+  /// The accidentals for notes that no key signature or scale spells.
+  pub accidentals: Accidentals,
+
+  /// The number of decimal places cents are shown with, up to [MAX_CENTS_PRECISION].
+  pub cents_precision: usize,
+
+  pub interval_labels: IntervalLabels,
+  pub octave_numbering: OctaveNumbering,
+}
+
+impl Default for DisplayPreferences {
+  fn default() -> Self {
+    DisplayPreferences {
+      accidentals: Accidentals::Sharps,
+      cents_precision: 2,
+      interval_labels: IntervalLabels::Ratios,
+      octave_numbering: OctaveNumbering::Scientific,
+    }
+  }
+}
+
+impl DisplayPreferences {
+  /// Names notes in `tuning` the way the user prefers. A key signature on the tuning's
+  /// spelling policy still decides its own accidentals.
+  pub fn apply(&self, tuning: &mut Tuning) {
+    self.apply_to_spelling(&mut tuning.spelling);
+  }
+
+  pub fn apply_to_spelling(&self, spelling: &mut SpellingPolicy) {
+    if spelling.key.is_none() {
+      spelling.prefer = self.accidentals;
+    }
+    spelling.octaves = self.octave_numbering;
+  }
+
+  /// A spelling policy with no key or scale, for naming notes outside of a tuning.
+  pub fn spelling(&self) -> SpellingPolicy {
+    let mut spelling = SpellingPolicy::new(self.accidentals);
+    spelling.octaves = self.octave_numbering;
+    spelling
+  }
+
+  /// Formats a pitch in cents, e.g. `386.31`.
+  pub fn cents(&self, cents: f64) -> String {
+    format!("{:.*}", self.precision(), cents)
+  }
+
+  /// Formats a deviation in cents, always with a sign, e.g. `-13.69`.
+  pub fn deviation(&self, cents: f64) -> String {
+    format!("{:+.*}", self.precision(), cents)
+  }
+
+  /// Labels an interval of `cents`, using `ratio` if there is one and ratios are preferred.
+  pub fn interval(&self, cents: f64, ratio: Option<(u32, u32)>) -> String {
+    match (self.interval_labels, ratio) {
+      (IntervalLabels::Ratios, Some((n, d))) => format!("{n}/{d}"),
+      _ => self.cents(cents),
+    }
+  }
+
+  fn precision(&self) -> usize {
+    self.cents_precision.min(MAX_CENTS_PRECISION)
+  }
+}
+
+impl Display for DisplayPreferences {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let accidentals = match self.accidentals {
+      Accidentals::Sharps => "sharps",
+      Accidentals::Flats => "flats",
+    };
+    let interval_labels = match self.interval_labels {
+      IntervalLabels::Ratios => "ratios",
+      IntervalLabels::Cents => "cents",
+    };
+    let octave_numbering = match self.octave_numbering {
+      OctaveNumbering::Scientific => "scientific",
+      OctaveNumbering::Yamaha => "yamaha",
+    };
+    writeln!(f, "accidentals={accidentals}")?;
+    writeln!(f, "cents_precision={}", self.cents_precision)?;
+    writeln!(f, "interval_labels={interval_labels}")?;
+    writeln!(f, "octave_numbering={octave_numbering}")
+  }
+}
+
+impl FromStr for DisplayPreferences {
+  type Err = LumatoneKeymapError;
+
+  /// Parses saved preferences. Settings that are missing keep their default.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut prefs = DisplayPreferences::default();
+    for line in s.lines().map(str::trim) {
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let invalid = || LumatoneKeymapError::InvalidPreferences(format!("invalid line {line:?}"));
+      let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+      match (name.trim(), value.trim()) {
+        ("accidentals", "sharps") => prefs.accidentals = Accidentals::Sharps,
+        ("accidentals", "flats") => prefs.accidentals = Accidentals::Flats,
+        ("cents_precision", n) => {
+          prefs.cents_precision = match n.parse() {
+            Ok(n) if n <= MAX_CENTS_PRECISION => n,
+            _ => return Err(invalid()),
+          }
+        }
+        ("interval_labels", "ratios") => prefs.interval_labels = IntervalLabels::Ratios,
+        ("interval_labels", "cents") => prefs.interval_labels = IntervalLabels::Cents,
+        ("octave_numbering", "scientific") => prefs.octave_numbering = OctaveNumbering::Scientific,
+        ("octave_numbering", "yamaha") => prefs.octave_numbering = OctaveNumbering::Yamaha,
+        ("accidentals" | "interval_labels" | "octave_numbering", _) => return Err(invalid()),
+        _ => log::debug!("ignoring unknown preference {line:?}"),
+      }
+    }
+    Ok(prefs)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_preferences_round_trip() {
+    let prefs = DisplayPreferences {
+      accidentals: Accidentals::Flats,
+      cents_precision: 1,
+      interval_labels: IntervalLabels::Cents,
+      octave_numbering: OctaveNumbering::Yamaha,
+    };
+    let parsed: DisplayPreferences = prefs.to_string().parse().unwrap();
+    assert_eq!(parsed, prefs);
+
+    let partial: DisplayPreferences = "# mine\naccidentals=flats\ntheme=dark\n".parse().unwrap();
+    assert_eq!(partial.accidentals, Accidentals::Flats);
+    assert_eq!(partial.cents_precision, 2);
+    assert!("accidentals=naturals"
+      .parse::<DisplayPreferences>()
+      .is_err());
+    assert!("cents_precision=12".parse::<DisplayPreferences>().is_err());
+  }
+
+  #[test]
+  fn test_labels() {
+    let prefs = DisplayPreferences {
+      accidentals: Accidentals::Flats,
+      cents_precision: 1,
+      interval_labels: IntervalLabels::Cents,
+      octave_numbering: OctaveNumbering::Yamaha,
+    };
+    let mut tuning = Tuning::equal(12);
+    prefs.apply(&mut tuning);
+    assert_eq!(tuning.spelling.note_name(61), "Db3");
+    assert_eq!(prefs.spelling().note_name(70), "Bb3");
+
+    assert_eq!(prefs.cents(386.3137), "386.3");
+    assert_eq!(prefs.deviation(3.91), "+3.9");
+    assert_eq!(prefs.interval(386.3137, Some((5, 4))), "386.3");
+    assert_eq!(
+      DisplayPreferences::default().interval(386.3137, Some((5, 4))),
+      "5/4"
+    );
+    assert_eq!(
+      DisplayPreferences::default().interval(700.0, None),
+      "700.00"
+    );
+  }
+}
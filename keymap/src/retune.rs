@@ -0,0 +1,304 @@
+//! Follows a tuning that changes while playing, e.g. adaptive just intonation driven by an
+//! MTS-ESP master or a tuning feed.
+//!
+//! Updates come as [TuningUpdate]s: either a whole new [Tuning], or new pitches for single
+//! MIDI notes, which is what the MIDI Tuning Standard carries (see
+//! [TuningUpdate::from_mts_sysex]). A [Tuning] repeats every period, so retuning a note
+//! retunes its scale degree in every period.
+//!
+//! A [RetuneClient] applies each update to its keymap and tuning, and returns a [Retuned]
+//! with everything that follows from it. It doesn't talk to any device itself; the CLI's
+//! `retune` module listens for MTS messages on a MIDI input and sends the results to the
+//! Lumatone and the note proxy. A [Retuned] holds:
+//!
+//! - the key commands to send to the device, for keys whose color changed with
+//!   [octave coloring](RetuneClient::with_octave_coloring), or whose function changed when
+//!   the layout was re-anchored,
+//! - a new [BendPlan] for the note proxy's output (see [crate::pitch_bend]).
+//!
+//! Note names for each key follow the tuning, see [RetuneClient::note_name].
+//!
+//! With [RetuneClient::with_reanchoring], a change of the tuning's root regenerates the
+//! keymap from an [IsomorphicLayout] anchored at the new root, so the root stays under the
+//! same key.
+
+use lumatone_midi::{
+  commands::Command,
+  constants::{LumatoneKeyFunction, LumatoneKeyLocation, RGBColor},
+};
+
+use super::{
+  error::LumatoneKeymapError,
+  layout::IsomorphicLayout,
+  ltn::LumatoneKeyMap,
+  octave::OctaveColoring,
+  pitch_bend::{BendPlan, DEFAULT_BEND_RANGE, DEFAULT_BEND_TOLERANCE},
+  tuning::{nearest_12tet, pitch_index, Tuning, CENTS_PER_SEMITONE},
+};
+
+/// The sub-ID#1 of MIDI Tuning Standard messages.
+const MTS_SUB_ID: u8 = 0x08;
+
+/// The sub-ID#2 of a real-time single note tuning change.
+const MTS_SINGLE_NOTE_CHANGE: u8 = 0x02;
+
+/// The sub-ID#2 of a non-real-time single note tuning change, which adds a bank number.
+const MTS_SINGLE_NOTE_CHANGE_BANK: u8 = 0x07;
+
+/// The frequency data MTS uses to mean "leave this note alone".
+const MTS_NO_CHANGE: [u8; 3] = [0x7f, 0x7f, 0x7f];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TuningUpdate {
+  /// Replaces the whole tuning, e.g. when the source switches scales.
+  Tuning(Tuning),
+
+  /// Retunes single MIDI notes (on channel 1) to pitches given as fractional 12-TET MIDI
+  /// note numbers.
+  Notes(Vec<(u8, f64)>),
+}
+
+impl TuningUpdate {
+  /// Parses a MIDI Tuning Standard single note tuning change, as sent by an MTS-ESP bridge.
+  /// Both forms are accepted:
+  ///
+  /// - real-time: `F0 7F <device> 08 02 <program> <count> [<note> xx yy zz]... F7`,
+  /// - non-real-time, with a bank: `F0 7E <device> 08 07 <bank> <program> <count> ... F7`.
+  ///
+  /// The bank and program are ignored, since there's only the one tuning to change. Notes
+  /// whose frequency data is `7F 7F 7F` are left alone.
+  pub fn from_mts_sysex(msg: &[u8]) -> Result<Self, LumatoneKeymapError> {
+    let invalid = |msg: &str| LumatoneKeymapError::InvalidTuningUpdate(msg.to_string());
+    let data = match msg {
+      [0xf0, 0x7f, _, MTS_SUB_ID, MTS_SINGLE_NOTE_CHANGE, _, data @ .., 0xf7]
+      | [0xf0, 0x7e, _, MTS_SUB_ID, MTS_SINGLE_NOTE_CHANGE_BANK, _, _, data @ .., 0xf7] => data,
+      _ => return Err(invalid("not an MTS single note tuning change")),
+    };
+    let (count, changes) = data
+      .split_first()
+      .ok_or_else(|| invalid("missing number of changes"))?;
+    if changes.len() != *count as usize * 4 {
+      return Err(invalid(
+        "number of changes doesn't match the message length",
+      ));
+    }
+    let notes = changes
+      .chunks(4)
+      .filter(|change| change[1..] != MTS_NO_CHANGE)
+      .map(|change| {
+        let fraction = ((change[2] as u32) << 7 | change[3] as u32) as f64 / 16384.0;
+        (change[0], change[1] as f64 + fraction)
+      })
+      .collect();
+    Ok(TuningUpdate::Notes(notes))
+  }
+}
+
+/// What changed when a [TuningUpdate] was applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Retuned {
+  /// The key commands whose values changed, to send to the device.
+  pub commands: Vec<Command>,
+
+  /// The pitch bends for the updated tuning.
+  pub bends: BendPlan,
+
+  /// The keymap was regenerated with the layout anchored at the tuning's new root.
+  pub reanchored: bool,
+}
+
+#[derive(Debug)]
+pub struct RetuneClient {
+  keymap: LumatoneKeyMap,
+  tuning: Tuning,
+  coloring: Option<OctaveColoring>,
+  layout: Option<(IsomorphicLayout, Vec<RGBColor>)>,
+  bend_range: f64,
+  bend_tolerance: f64,
+}
+
+impl RetuneClient {
+  pub fn new(keymap: LumatoneKeyMap, tuning: Tuning) -> Self {
+    RetuneClient {
+      keymap,
+      tuning,
+      coloring: None,
+      layout: None,
+      bend_range: DEFAULT_BEND_RANGE,
+      bend_tolerance: DEFAULT_BEND_TOLERANCE,
+    }
+  }
+
+  /// Recolors the keymap by octave band after every update, since retuning can move keys
+  /// into another band.
+  pub fn with_octave_coloring(mut self, coloring: OctaveColoring) -> Self {
+    self.coloring = Some(coloring);
+    self
+  }
+
+  /// Regenerates the keymap from `layout` whenever the tuning's root moves, with the anchor
+  /// key playing the new root. Keys are colored by degree with `colors`, as in
+  /// [IsomorphicLayout::generate].
+  pub fn with_reanchoring(mut self, layout: IsomorphicLayout, colors: Vec<RGBColor>) -> Self {
+    self.layout = Some((layout, colors));
+    self
+  }
+
+  /// Plans pitch bends for a synth with the given bend range, allowing keys that share a
+  /// channel to be out by up to `tolerance` cents. See [LumatoneKeyMap::plan_pitch_bends].
+  pub fn with_bends(mut self, bend_range: f64, tolerance: f64) -> Self {
+    self.bend_range = bend_range;
+    self.bend_tolerance = tolerance;
+    self
+  }
+
+  pub fn keymap(&self) -> &LumatoneKeyMap {
+    &self.keymap
+  }
+
+  pub fn tuning(&self) -> &Tuning {
+    &self.tuning
+  }
+
+  /// Returns the name of the nearest 12-TET note to the pitch the key at `location` plays,
+  /// spelled with the tuning's spelling policy, and its deviation in cents. Returns `None`
+  /// for keys that don't send notes.
+  pub fn note_name(&self, location: LumatoneKeyLocation) -> Option<(String, f64)> {
+    let (channel, note) = match self.keymap.get_key(location)?.function {
+      LumatoneKeyFunction::NoteOnOff { channel, note_num }
+      | LumatoneKeyFunction::LumaTouch {
+        channel, note_num, ..
+      } => (channel, note_num),
+      _ => return None,
+    };
+    let (nearest, deviation) = nearest_12tet(self.tuning.midi_pitch(pitch_index(channel, note)));
+    Some((self.tuning.spelling.note_name(nearest), deviation))
+  }
+
+  /// Applies `update`, returning what needs to be sent to the device and the synth.
+  pub fn apply(&mut self, update: TuningUpdate) -> Retuned {
+    let before = self.keymap.to_midi_commands();
+    let old_root = self.tuning.root_index;
+
+    match update {
+      TuningUpdate::Tuning(tuning) => self.tuning = tuning,
+      TuningUpdate::Notes(notes) => {
+        for (note, pitch) in notes {
+          self.retune_note(note, pitch);
+        }
+      }
+    }
+
+    let mut reanchored = false;
+    if let Some((layout, colors)) = self.layout.as_mut() {
+      if self.tuning.root_index != old_root {
+        layout.anchor_index = self.tuning.root_index;
+        self.keymap = layout.generate(&self.tuning, colors);
+        reanchored = true;
+      }
+    }
+    if let Some(coloring) = &self.coloring {
+      coloring.apply(&mut self.keymap, &self.tuning);
+    }
+
+    let commands = self
+      .keymap
+      .to_midi_commands()
+      .into_iter()
+      .filter(|command| !before.contains(command))
+      .collect();
+    Retuned {
+      commands,
+      bends: self
+        .keymap
+        .plan_pitch_bends(&self.tuning, self.bend_range, self.bend_tolerance),
+      reanchored,
+    }
+  }
+
+  /// Sets the degree that `note` plays so that it sounds at `pitch`.
+  fn retune_note(&mut self, note: u8, pitch: f64) {
+    let (degree, period) = self.tuning.degree(note as i32);
+    let cents = (pitch - self.tuning.root_pitch) * CENTS_PER_SEMITONE;
+    if let Some(degree_cents) = self.tuning.degrees.get_mut(degree) {
+      *degree_cents = cents - period as f64 * self.tuning.period;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::key_loc_unchecked;
+
+  #[test]
+  fn test_parse_mts_single_note_change() {
+    // note 64 to 63.863 (a just major third above C), note 67 unchanged
+    let msg = [
+      0xf0, 0x7f, 0x7f, 0x08, 0x02, 0x00, 0x02, 64, 63, 0x6e, 0x3b, 67, 0x7f, 0x7f, 0x7f, 0xf7,
+    ];
+    match TuningUpdate::from_mts_sysex(&msg).unwrap() {
+      TuningUpdate::Notes(notes) => {
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].0, 64);
+        assert!((notes[0].1 - 63.863).abs() < 0.001);
+      }
+      update => panic!("expected note changes, got {update:?}"),
+    }
+
+    assert!(TuningUpdate::from_mts_sysex(&msg[..msg.len() - 5]).is_err());
+    assert!(TuningUpdate::from_mts_sysex(&[0xf0, 0x7f, 0xf7]).is_err());
+
+    // the non-real-time form has a bank byte, and sub-ID#2 02 isn't a note change there
+    let bank = [
+      0xf0, 0x7e, 0x7f, 0x08, 0x07, 0x01, 0x00, 0x01, 64, 63, 0x6e, 0x3b, 0xf7,
+    ];
+    assert_eq!(
+      TuningUpdate::from_mts_sysex(&bank).unwrap(),
+      TuningUpdate::from_mts_sysex(&msg).unwrap()
+    );
+    let mut no_bank = msg;
+    no_bank[1] = 0x7e;
+    assert!(TuningUpdate::from_mts_sysex(&no_bank).is_err());
+  }
+
+  #[test]
+  fn test_retune_note_updates_names_and_bends() {
+    let anchor = key_loc_unchecked(3, 27);
+    let layout = IsomorphicLayout::new(2, -1, anchor, 60);
+    let tuning = Tuning::equal(12);
+    let mut client = RetuneClient::new(layout.generate(&tuning, &[]), tuning);
+
+    let retuned = client.apply(TuningUpdate::Notes(vec![(64, 63.8631)]));
+    assert!(retuned.commands.is_empty());
+    assert!(!retuned.reanchored);
+    assert!((client.tuning().cents(76) - 1586.31).abs() < 0.01);
+
+    // the key playing the third is now flat of E
+    let third = LumatoneKeyLocation::all()
+      .into_iter()
+      .find(|location| layout.pitch_index(*location) == Some(64))
+      .unwrap();
+    let (name, deviation) = client.note_name(third).unwrap();
+    assert_eq!(name, "E4");
+    assert!((deviation + 13.69).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_reanchor_on_new_root() {
+    let anchor = key_loc_unchecked(3, 27);
+    let layout = IsomorphicLayout::new(2, -1, anchor, 60);
+    let tuning = Tuning::equal(12);
+    let colors = vec![RGBColor::red(), RGBColor::blue()];
+    let keymap = layout.generate(&tuning, &colors);
+    let mut client = RetuneClient::new(keymap, tuning.clone()).with_reanchoring(layout, colors);
+
+    let mut moved = tuning;
+    moved.root_index = 62;
+    let retuned = client.apply(TuningUpdate::Tuning(moved));
+    assert!(retuned.reanchored);
+    assert!(!retuned.commands.is_empty());
+    let (name, _) = client.note_name(anchor).unwrap();
+    assert_eq!(name, "C4");
+  }
+}
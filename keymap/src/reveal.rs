@@ -0,0 +1,169 @@
+//! Reveals a keymap's colors a few keys at a time, instead of rewriting the whole board at
+//! once, e.g. when first connecting to the device.
+//!
+//! A [Reveal] splits the commands that send a keymap into frames: key functions and other
+//! settings go in the first frame, then key colors follow in the reveal's order. The host
+//! sends one frame at a time with a short pause between them, so no firmware support is
+//! needed.
+//!
+//! Reveals can be written as text: `sweep`, `boards`, `ripple` (from the middle of the
+//! keyboard), or `ripple:3:27` (from key 27 of board 3).
+
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use lumatone_midi::{commands::Command, constants::LumatoneKeyLocation};
+
+use super::{
+  error::LumatoneKeymapError,
+  geometry::{key_at, keyboard_center_x, DistanceMatrix, KeyCoord},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reveal {
+  /// Column by column, from left to right.
+  Sweep,
+
+  /// One board at a time, from the lowest octave.
+  Boards,
+
+  /// Rings of keys spreading out from `center`.
+  Ripple { center: LumatoneKeyLocation },
+}
+
+impl Reveal {
+  /// A ripple from the key nearest the middle of the keyboard.
+  pub fn ripple() -> Self {
+    let x = keyboard_center_x();
+    let coords: Vec<KeyCoord> = LumatoneKeyLocation::all()
+      .into_iter()
+      .filter_map(KeyCoord::of)
+      .collect();
+    let y = coords.iter().map(|c| c.center().1).sum::<f64>() / coords.len() as f64;
+    let center = coords
+      .iter()
+      .min_by(|a, b| {
+        let da = (a.center().0 - x).powi(2) + (a.center().1 - y).powi(2);
+        let db = (b.center().0 - x).powi(2) + (b.center().1 - y).powi(2);
+        da.total_cmp(&db)
+      })
+      .and_then(|c| c.location())
+      .expect("keyboard has keys");
+    Reveal::Ripple { center }
+  }
+
+  /// The frame a key is revealed in, relative to the others.
+  fn step(&self, location: LumatoneKeyLocation) -> i32 {
+    match self {
+      Reveal::Sweep => KeyCoord::of(location)
+        .map(|c| c.center().0.floor() as i32)
+        .unwrap_or_default(),
+      Reveal::Boards => location.board_index() as i32,
+      Reveal::Ripple { center } => DistanceMatrix::get()
+        .steps(*center, location)
+        .unwrap_or_default(),
+    }
+  }
+
+  /// Splits `commands` into frames to send in order. The first frame has every command
+  /// that isn't a key color, so the keys play correctly from the start.
+  pub fn frames(&self, commands: Vec<Command>) -> Vec<Vec<Command>> {
+    let mut settings = vec![];
+    let mut colors: BTreeMap<i32, Vec<Command>> = BTreeMap::new();
+    for command in commands {
+      match command {
+        Command::SetKeyColor { location, .. } => {
+          colors.entry(self.step(location)).or_default().push(command)
+        }
+        _ => settings.push(command),
+      }
+    }
+    let mut frames = vec![settings];
+    frames.extend(colors.into_values());
+    frames.retain(|frame| !frame.is_empty());
+    frames
+  }
+}
+
+impl Display for Reveal {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Reveal::Sweep => write!(f, "sweep"),
+      Reveal::Boards => write!(f, "boards"),
+      Reveal::Ripple { center } => write!(
+        f,
+        "ripple:{}:{}",
+        center.board_index() as u8,
+        center.key_index().get()
+      ),
+    }
+  }
+}
+
+impl FromStr for Reveal {
+  type Err = LumatoneKeymapError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || LumatoneKeymapError::InvalidReveal(s.to_string());
+    match s.trim().split(':').collect::<Vec<_>>().as_slice() {
+      ["sweep"] => Ok(Reveal::Sweep),
+      ["boards"] => Ok(Reveal::Boards),
+      ["ripple"] => Ok(Reveal::ripple()),
+      ["ripple", board, key] => {
+        let center = key_at(
+          board.parse().map_err(|_| invalid())?,
+          key.parse().map_err(|_| invalid())?,
+        )
+        .ok_or_else(invalid)?;
+        Ok(Reveal::Ripple { center })
+      }
+      _ => Err(invalid()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::{BoardIndex, RGBColor};
+
+  #[test]
+  fn test_reveal_frames() {
+    let commands: Vec<Command> = LumatoneKeyLocation::all()
+      .into_iter()
+      .flat_map(|location| {
+        [
+          Command::SetKeyColor {
+            location,
+            color: RGBColor::red(),
+          },
+          Command::Ping(0),
+        ]
+      })
+      .collect();
+
+    let frames = Reveal::Boards.frames(commands.clone());
+    assert_eq!(frames.len(), 6);
+    assert!(frames[0].iter().all(|c| *c == Command::Ping(0)));
+    assert!(frames[1].iter().all(|c| matches!(
+      c,
+      Command::SetKeyColor { location, .. } if location.board_index() == BoardIndex::Octave1
+    )));
+
+    let center = key_at(3, 27).unwrap();
+    let ripple = Reveal::Ripple { center };
+    let frames = ripple.frames(commands);
+    assert_eq!(
+      frames[1],
+      vec![Command::SetKeyColor {
+        location: center,
+        color: RGBColor::red()
+      }]
+    );
+    assert_eq!(frames.iter().map(Vec::len).sum::<usize>(), 560);
+
+    for reveal in [Reveal::Sweep, Reveal::Boards, ripple, Reveal::ripple()] {
+      assert_eq!(reveal.to_string().parse::<Reveal>().unwrap(), reveal);
+    }
+    assert!("ripple:9:0".parse::<Reveal>().is_err());
+  }
+}
@@ -0,0 +1,215 @@
+//! Warns about chord shapes and scale runs that are awkward to play on an
+//! [isomorphic layout](crate::layout) because of where they fall on the keyboard.
+//!
+//! The grid of keys continues across the five boards without gaps (see
+//! [crate::geometry]), so every shape keeps its form, but there's a physical seam between
+//! boards and the keys at the edges of the keyboard have nothing beyond them. A [Shape]
+//! played from a key near the anchor is fingered on the nearest keys that play its
+//! pitches, and [check_layout] flags fingerings that:
+//!
+//! - straddle a seam, with keys on more than one board, or
+//! - are out of reach: a pitch isn't within [MAX_REACH] keys of the root, usually because
+//!   the shape runs off the edge of the keyboard.
+//!
+//! If moving the anchor to a nearby key (keeping its pitch) would leave fewer warnings, the
+//! last warning suggests it.
+
+use std::collections::HashMap;
+
+use lumatone_midi::constants::{BoardIndex, LumatoneKeyFunction, LumatoneKeyLocation};
+
+use super::{
+  geometry::DistanceMatrix,
+  layout::{key_function, IsomorphicLayout},
+  selection::KeySelection,
+  tuning::Tuning,
+};
+
+/// The farthest a note of a shape can be from its root, in keys, to count as in reach.
+pub const MAX_REACH: i32 = 4;
+
+/// Shapes are checked from every key up to this many keys from the anchor.
+pub const HOME_RADIUS: i32 = 2;
+
+/// Anchors up to this many keys away are tried when suggesting a move.
+pub const SEARCH_RADIUS: i32 = 6;
+
+/// A chord shape or scale run, as intervals in scale steps above its root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shape {
+  pub name: String,
+  pub intervals: Vec<i32>,
+}
+
+impl Shape {
+  pub fn new<S: Into<String>>(name: S, intervals: Vec<i32>) -> Self {
+    Shape {
+      name: name.into(),
+      intervals,
+    }
+  }
+
+  /// A run up through the `degrees` of `tuning` to the root an octave (period) above.
+  pub fn scale_run<S: Into<String>>(name: S, tuning: &Tuning, degrees: &[usize]) -> Self {
+    let mut intervals: Vec<i32> = degrees.iter().map(|d| *d as i32).collect();
+    intervals.sort_unstable();
+    intervals.push(tuning.len() as i32);
+    Shape::new(name, intervals)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutWarning {
+  /// The shape played from `root` has keys on each of `boards`.
+  StraddlesSeam {
+    shape: String,
+    root: LumatoneKeyLocation,
+    boards: Vec<BoardIndex>,
+  },
+
+  /// Some of the shape's pitches aren't within [MAX_REACH] keys of `root`.
+  OutOfReach {
+    shape: String,
+    root: LumatoneKeyLocation,
+  },
+
+  /// Moving the anchor to `anchor` would leave `remaining` warnings instead.
+  MoveAnchor {
+    anchor: LumatoneKeyLocation,
+    remaining: usize,
+  },
+}
+
+/// Checks each of `shapes` on `layout`, played from every key near the anchor.
+pub fn check_layout(layout: &IsomorphicLayout, shapes: &[Shape]) -> Vec<LayoutWarning> {
+  let mut warnings = shape_warnings(layout, shapes);
+  if warnings.is_empty() {
+    return warnings;
+  }
+
+  let distances = DistanceMatrix::get();
+  let home = layout.anchor;
+  let best = KeySelection::disc(home, SEARCH_RADIUS)
+    .iter()
+    .filter(|anchor| **anchor != home)
+    .map(|anchor| {
+      let candidate = IsomorphicLayout {
+        anchor: *anchor,
+        ..layout.clone()
+      };
+      let remaining = shape_warnings(&candidate, shapes).len();
+      (remaining, distances.steps(home, *anchor), *anchor)
+    })
+    .min_by_key(|(remaining, steps, _)| (*remaining, *steps));
+  if let Some((remaining, _, anchor)) = best {
+    if remaining < warnings.len() {
+      warnings.push(LayoutWarning::MoveAnchor { anchor, remaining });
+    }
+  }
+  warnings
+}
+
+fn shape_warnings(layout: &IsomorphicLayout, shapes: &[Shape]) -> Vec<LayoutWarning> {
+  let distances = DistanceMatrix::get();
+  let mut keys: HashMap<i32, Vec<LumatoneKeyLocation>> = HashMap::new();
+  for location in LumatoneKeyLocation::all() {
+    match layout.pitch_index(location) {
+      Some(index) if key_function(index) != LumatoneKeyFunction::Disabled => {
+        keys.entry(index).or_default().push(location)
+      }
+      _ => {}
+    }
+  }
+
+  let mut warnings = vec![];
+  let roots = KeySelection::disc(layout.effective_anchor(), HOME_RADIUS);
+  for root in roots.iter() {
+    let root_index = match layout.pitch_index(*root) {
+      Some(index) => index,
+      None => continue,
+    };
+    for shape in shapes {
+      // the nearest key to the root for each pitch, if it's in reach
+      let fingering: Option<Vec<LumatoneKeyLocation>> = shape
+        .intervals
+        .iter()
+        .map(|interval| {
+          keys
+            .get(&(root_index + interval))?
+            .iter()
+            .filter_map(|k| Some((distances.steps(*root, *k)?, *k)))
+            .min_by_key(|(steps, _)| *steps)
+            .filter(|(steps, _)| *steps <= MAX_REACH)
+            .map(|(_, k)| k)
+        })
+        .collect();
+      let fingering = match fingering {
+        Some(fingering) => fingering,
+        None => {
+          warnings.push(LayoutWarning::OutOfReach {
+            shape: shape.name.clone(),
+            root: *root,
+          });
+          continue;
+        }
+      };
+      let mut boards: Vec<BoardIndex> = vec![root.board_index()];
+      for key in fingering {
+        if !boards.contains(&key.board_index()) {
+          boards.push(key.board_index());
+        }
+      }
+      if boards.len() > 1 {
+        boards.sort_by_key(|b| *b as u8);
+        warnings.push(LayoutWarning::StraddlesSeam {
+          shape: shape.name.clone(),
+          root: *root,
+          boards,
+        });
+      }
+    }
+  }
+  warnings
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::{key_at, KeyCoord};
+
+  #[test]
+  fn test_shapes_near_seams() {
+    let tuning = Tuning::equal(12);
+    let shapes = [
+      Shape::new("major triad", vec![0, 4, 7]),
+      Shape::scale_run("major scale", &tuning, &[0, 2, 4, 5, 7, 9, 11]),
+    ];
+
+    // Wicki-Hayden anchored right next to the seam between boards 1 and 2
+    let seam = KeyCoord::of(key_at(1, 55).unwrap()).unwrap();
+    let anchor = KeyCoord::new(seam.q - 1, seam.r - 2).location().unwrap();
+    let layout = IsomorphicLayout::new(2, -5, anchor, 60);
+    let (_, warnings) = layout.generate_checked(&tuning, &[], &shapes);
+    assert!(warnings.iter().any(|w| matches!(
+      w,
+      LayoutWarning::StraddlesSeam { boards, .. }
+        if boards == &vec![BoardIndex::Octave1, BoardIndex::Octave2]
+    )));
+
+    // the suggested anchor is an improvement, and checking it gives what it promised
+    let (anchor, remaining) = match warnings.last() {
+      Some(LayoutWarning::MoveAnchor { anchor, remaining }) => (*anchor, *remaining),
+      other => panic!("expected an anchor suggestion, got {other:?}"),
+    };
+    assert!(remaining < warnings.len() - 1);
+    let moved = IsomorphicLayout::new(2, -5, anchor, 60);
+    let moved_warnings = check_layout(&moved, &shapes);
+    assert_eq!(
+      moved_warnings
+        .iter()
+        .filter(|w| !matches!(w, LayoutWarning::MoveAnchor { .. }))
+        .count(),
+      remaining
+    );
+  }
+}
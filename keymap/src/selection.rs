@@ -0,0 +1,156 @@
+//! Sets of keys, with constructors for the common regions of the board, so features that
+//! work on part of the keyboard don't need key indices listed by hand.
+//!
+//! Regions are defined geometrically (see [crate::geometry]), so e.g. a split between boards
+//! follows the zig-zag seam between them rather than a straight line.
+
+use std::collections::HashSet;
+
+use lumatone_midi::constants::{BoardIndex, LumatoneKeyLocation};
+
+use super::geometry::{keyboard_center_x, KeyCoord};
+
+/// A set of keys. Iterates in [LumatoneKeyLocation::all] order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeySelection {
+  keys: Vec<LumatoneKeyLocation>,
+}
+
+impl KeySelection {
+  /// Selects the keys where `pred` returns true for the key and its coordinate.
+  pub fn from_fn<F: Fn(LumatoneKeyLocation, KeyCoord) -> bool>(pred: F) -> Self {
+    let keys = LumatoneKeyLocation::all()
+      .into_iter()
+      .filter(|loc| KeyCoord::of(*loc).is_some_and(|c| pred(*loc, c)))
+      .collect();
+    KeySelection { keys }
+  }
+
+  pub fn all() -> Self {
+    KeySelection::from_fn(|_, _| true)
+  }
+
+  /// All the keys of one board, i.e. one fifth of the keyboard.
+  pub fn board(board: BoardIndex) -> Self {
+    KeySelection::from_fn(|loc, _| loc.board_index() == board)
+  }
+
+  /// Splits the keyboard at the seam after `board`, returning the keys up to and including
+  /// that board, and the keys after it.
+  pub fn split_after(board: BoardIndex) -> (Self, Self) {
+    let left = KeySelection::from_fn(|loc, _| loc.board_index() as u8 <= board as u8);
+    (left.clone(), left.invert())
+  }
+
+  /// The keys whose centers are left of the middle of the keyboard.
+  pub fn left_half() -> Self {
+    let middle = keyboard_center_x();
+    KeySelection::from_fn(|_, c| c.center().0 < middle)
+  }
+
+  /// The keys whose centers are at or right of the middle of the keyboard.
+  pub fn right_half() -> Self {
+    KeySelection::left_half().invert()
+  }
+
+  /// The keys in rows `rows`, counting from the top row of the first board.
+  pub fn rows(rows: std::ops::RangeInclusive<i32>) -> Self {
+    KeySelection::from_fn(|_, c| rows.contains(&c.r))
+  }
+
+  /// The keys in the top `count` rows of each board.
+  pub fn upper_rows(count: i32) -> Self {
+    KeySelection::from_fn(|loc, c| c.r - 2 * (loc.board_index() as i32 - 1) < count)
+  }
+
+  /// The keys in the bottom `count` rows of each board.
+  pub fn lower_rows(count: i32) -> Self {
+    KeySelection::from_fn(|loc, c| c.r - 2 * (loc.board_index() as i32 - 1) > 10 - count)
+  }
+
+  /// The keys exactly `radius` steps from `center`. Radius 0 is the center key itself.
+  pub fn ring(center: LumatoneKeyLocation, radius: i32) -> Self {
+    match KeyCoord::of(center) {
+      Some(center) => KeySelection::from_fn(|_, c| c.distance(&center) == radius),
+      None => KeySelection::default(),
+    }
+  }
+
+  /// The keys up to `radius` steps from `center`.
+  pub fn disc(center: LumatoneKeyLocation, radius: i32) -> Self {
+    match KeyCoord::of(center) {
+      Some(center) => KeySelection::from_fn(|_, c| c.distance(&center) <= radius),
+      None => KeySelection::default(),
+    }
+  }
+
+  pub fn contains(&self, location: &LumatoneKeyLocation) -> bool {
+    self.keys.contains(location)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &LumatoneKeyLocation> {
+    self.keys.iter()
+  }
+
+  pub fn len(&self) -> usize {
+    self.keys.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.keys.is_empty()
+  }
+
+  /// The keys that aren't in this selection.
+  pub fn invert(&self) -> Self {
+    let keys: HashSet<_> = self.keys.iter().copied().collect();
+    KeySelection::from_fn(|loc, _| !keys.contains(&loc))
+  }
+
+  pub fn union(&self, other: &KeySelection) -> Self {
+    KeySelection::from_fn(|loc, _| self.contains(&loc) || other.contains(&loc))
+  }
+
+  pub fn intersection(&self, other: &KeySelection) -> Self {
+    KeySelection::from_fn(|loc, _| self.contains(&loc) && other.contains(&loc))
+  }
+
+  pub fn difference(&self, other: &KeySelection) -> Self {
+    KeySelection::from_fn(|loc, _| self.contains(&loc) && !other.contains(&loc))
+  }
+}
+
+impl FromIterator<LumatoneKeyLocation> for KeySelection {
+  fn from_iter<T: IntoIterator<Item = LumatoneKeyLocation>>(iter: T) -> Self {
+    let keys: HashSet<_> = iter.into_iter().collect();
+    KeySelection::from_fn(|loc, _| keys.contains(&loc))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::key_at;
+
+  #[test]
+  fn test_region_templates() {
+    assert_eq!(KeySelection::all().len(), 280);
+    assert_eq!(KeySelection::board(BoardIndex::Octave3).len(), 56);
+
+    let (left, right) = KeySelection::split_after(BoardIndex::Octave2);
+    assert_eq!((left.len(), right.len()), (112, 168));
+    assert!(left.intersection(&right).is_empty());
+
+    let halves = KeySelection::left_half().len() + KeySelection::right_half().len();
+    assert_eq!(halves, 280);
+
+    // 2 + 5 keys in the top two rows, and 5 + 2 in the bottom two
+    assert_eq!(KeySelection::upper_rows(2).len(), 35);
+    assert_eq!(KeySelection::lower_rows(2).len(), 35);
+
+    // a key in the middle of a board has a full ring of 6 neighbors
+    let center = key_at(3, 28).unwrap();
+    assert_eq!(KeySelection::ring(center, 0).len(), 1);
+    assert_eq!(KeySelection::ring(center, 1).len(), 6);
+    assert_eq!(KeySelection::disc(center, 1).len(), 7);
+  }
+}
@@ -0,0 +1,364 @@
+//! Enharmonic spelling: whether a 12-TET pitch class is written e.g. C# or Db.
+//!
+//! A [SpellingPolicy] decides, in order of precedence:
+//!
+//! 1. Pitch classes in the active scale (see [SpellingPolicy::with_scale]) are spelled to
+//!    use each letter at most once, with as few accidentals as possible, e.g. F# and C# in
+//!    D major, and Bb rather than A# in F major.
+//! 2. Pitch classes in the [key signature](KeySignature) are spelled as the signature
+//!    says, e.g. Ab in Eb major.
+//! 3. Anything else is a natural if it can be, otherwise a sharp or a flat as
+//!    [preferred](Accidentals).
+//!
+//! The policy is part of a [Tuning](crate::tuning::Tuning), so note names in reports and
+//! [notation](crate::notation) exports all spell the same pitch the same way. It also says
+//! how octaves are [numbered](OctaveNumbering).
+
+use std::{collections::BTreeMap, fmt::Display};
+
+const LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+
+/// The pitch class of each natural, C to B.
+const NATURALS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// The order sharps are added to key signatures in, as steps (C = 0 ... B = 6). Flats are
+/// added in the reverse order.
+const SHARP_ORDER: [u8; 7] = [3, 0, 4, 1, 5, 2, 6];
+
+/// Scales with up to this many notes are spelled as a whole; the search tries every
+/// spelling of each note.
+const MAX_SCALE_LEN: usize = 7;
+
+/// Repeated letters, double accidentals, accidentals, whether sharps and flats are mixed,
+/// and accidentals of the kind that isn't preferred. Lower is better.
+type ScaleCost = (usize, usize, usize, bool, usize);
+
+/// A letter name and an alteration in semitones, e.g. F# is step 3 with alteration 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Spelling {
+  /// The letter, from C = 0 to B = 6.
+  pub step: u8,
+  pub alteration: i8,
+}
+
+impl Spelling {
+  pub fn new(step: u8, alteration: i8) -> Self {
+    Spelling {
+      step: step % 7,
+      alteration,
+    }
+  }
+
+  /// Returns the spelling with letter `letter` (upper or lower case) and `alteration`.
+  pub fn from_letter(letter: char, alteration: i8) -> Option<Spelling> {
+    let step = LETTERS
+      .iter()
+      .position(|l| *l == letter.to_ascii_uppercase())?;
+    Some(Spelling::new(step as u8, alteration))
+  }
+
+  pub fn letter(&self) -> char {
+    LETTERS[self.step as usize]
+  }
+
+  pub fn pitch_class(&self) -> i32 {
+    self.semitones_above_c().rem_euclid(12)
+  }
+
+  /// The number of semitones above the C of the letter's octave, e.g. -1 for Cb and 12
+  /// for B#.
+  pub fn semitones_above_c(&self) -> i32 {
+    NATURALS[self.step as usize] + self.alteration as i32
+  }
+
+  /// The position on the line of fifths, where C is 0, G is 1 and F is -1.
+  pub fn fifths(&self) -> i32 {
+    // F C G D A E B are -1 to 5
+    const NATURAL_FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5];
+    NATURAL_FIFTHS[self.step as usize] + 7 * self.alteration as i32
+  }
+
+  /// Every spelling of `pitch_class` with at most a double sharp or flat, naturals first.
+  pub fn candidates(pitch_class: i32) -> Vec<Spelling> {
+    let pitch_class = pitch_class.rem_euclid(12);
+    let mut candidates: Vec<Spelling> = (0..7)
+      .filter_map(|step| {
+        let mut alteration = pitch_class - NATURALS[step as usize];
+        if alteration > 6 {
+          alteration -= 12;
+        } else if alteration < -6 {
+          alteration += 12;
+        }
+        (alteration.abs() <= 2).then(|| Spelling::new(step, alteration as i8))
+      })
+      .collect();
+    candidates.sort_by_key(|s| s.alteration.abs());
+    candidates
+  }
+}
+
+impl Display for Spelling {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let accidental = match self.alteration {
+      a if a > 0 => "#".repeat(a as usize),
+      a => "b".repeat(a.unsigned_abs() as usize),
+    };
+    write!(f, "{}{accidental}", self.letter())
+  }
+}
+
+/// Which accidental to use for pitch classes that aren't naturals, when nothing else
+/// decides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Accidentals {
+  #[default]
+  Sharps,
+  Flats,
+}
+
+/// How the octave numbers in note names are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OctaveNumbering {
+  /// Middle C is C4, as in scientific pitch notation.
+  #[default]
+  Scientific,
+
+  /// Middle C is C3, as on Yamaha instruments and in many DAWs.
+  Yamaha,
+}
+
+impl OctaveNumbering {
+  /// The octave number of middle C (MIDI note 60).
+  pub fn middle_c_octave(&self) -> i32 {
+    match self {
+      OctaveNumbering::Scientific => 4,
+      OctaveNumbering::Yamaha => 3,
+    }
+  }
+}
+
+/// A key signature, as a number of sharps (positive) or flats (negative).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeySignature {
+  pub fifths: i8,
+}
+
+impl KeySignature {
+  pub fn new(fifths: i8) -> Self {
+    KeySignature {
+      fifths: fifths.clamp(-7, 7),
+    }
+  }
+
+  /// The signature of the major key on `tonic`, e.g. two sharps for D.
+  pub fn major(tonic: Spelling) -> Self {
+    KeySignature::new(tonic.fifths().clamp(-7, 7) as i8)
+  }
+
+  /// The signature of the minor key on `tonic`, e.g. one flat for D.
+  pub fn minor(tonic: Spelling) -> Self {
+    KeySignature::new((tonic.fifths() - 3).clamp(-7, 7) as i8)
+  }
+
+  /// The alteration the signature gives the letter at `step`.
+  pub fn alteration(&self, step: u8) -> i8 {
+    let count = self.fifths.unsigned_abs() as usize;
+    match self.fifths {
+      f if f > 0 && SHARP_ORDER[..count].contains(&step) => 1,
+      f if f < 0 && SHARP_ORDER[7 - count..].contains(&step) => -1,
+      _ => 0,
+    }
+  }
+
+  /// The spelling of `pitch_class` if it's one of the key's seven notes.
+  pub fn spell(&self, pitch_class: i32) -> Option<Spelling> {
+    Spelling::candidates(pitch_class)
+      .into_iter()
+      .find(|s| s.alteration == self.alteration(s.step))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SpellingPolicy {
+  pub prefer: Accidentals,
+  pub key: Option<KeySignature>,
+  pub octaves: OctaveNumbering,
+
+  /// Spellings of the active scale's pitch classes.
+  scale: BTreeMap<i32, Spelling>,
+}
+
+impl SpellingPolicy {
+  pub fn new(prefer: Accidentals) -> Self {
+    SpellingPolicy {
+      prefer,
+      ..Default::default()
+    }
+  }
+
+  /// Spells notes in `key` as its signature does, and chromatic notes with the signature's
+  /// accidental.
+  pub fn in_key(key: KeySignature) -> Self {
+    SpellingPolicy {
+      prefer: match key.fifths < 0 {
+        true => Accidentals::Flats,
+        false => Accidentals::Sharps,
+      },
+      key: Some(key),
+      ..Default::default()
+    }
+  }
+
+  /// Spells the pitch classes in `scale` together, so they use as few accidentals as
+  /// possible without repeating a letter. Scales with more than seven notes have to repeat
+  /// letters, so their notes are spelled one at a time as usual.
+  pub fn with_scale(mut self, scale: &[i32]) -> Self {
+    let mut pitch_classes: Vec<i32> = vec![];
+    for pc in scale.iter().map(|pc| pc.rem_euclid(12)) {
+      if !pitch_classes.contains(&pc) {
+        pitch_classes.push(pc);
+      }
+    }
+    self.scale = match pitch_classes.len() <= MAX_SCALE_LEN {
+      true => self.spell_scale(&pitch_classes),
+      false => BTreeMap::new(),
+    };
+    self
+  }
+
+  /// Returns the spelling of a 12-TET pitch class.
+  pub fn spell(&self, pitch_class: i32) -> Spelling {
+    let pitch_class = pitch_class.rem_euclid(12);
+    if let Some(spelling) = self.scale.get(&pitch_class) {
+      return *spelling;
+    }
+    if let Some(spelling) = self.key.and_then(|key| key.spell(pitch_class)) {
+      return spelling;
+    }
+    let candidates = Spelling::candidates(pitch_class);
+    let single = |alteration| candidates.iter().find(|s| s.alteration == alteration);
+    *single(0)
+      .or_else(|| match self.prefer {
+        Accidentals::Sharps => single(1),
+        Accidentals::Flats => single(-1),
+      })
+      .unwrap_or(&candidates[0])
+  }
+
+  /// Returns the name of a 12-TET MIDI note, e.g. `C#4` or `Db4` for note 61. The octave
+  /// number goes with the letter, so note 59 spelled as Cb is `Cb4`.
+  pub fn note_name(&self, note: i32) -> String {
+    let spelling = self.spell(note);
+    let octave =
+      (note - spelling.alteration as i32).div_euclid(12) - 5 + self.octaves.middle_c_octave();
+    format!("{spelling}{octave}")
+  }
+
+  /// Picks the spellings of `pitch_classes` with the lowest [ScaleCost].
+  fn spell_scale(&self, pitch_classes: &[i32]) -> BTreeMap<i32, Spelling> {
+    let options: Vec<Vec<Spelling>> = pitch_classes
+      .iter()
+      .map(|pc| Spelling::candidates(*pc))
+      .collect();
+    let mut best: Option<(ScaleCost, Vec<Spelling>)> = None;
+    let mut choice = vec![0; options.len()];
+    loop {
+      let spellings: Vec<Spelling> = choice
+        .iter()
+        .zip(&options)
+        .map(|(i, candidates)| candidates[*i])
+        .collect();
+      let cost = self.scale_cost(&spellings);
+      if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+        best = Some((cost, spellings));
+      }
+
+      // Counts through every combination of candidates.
+      let mut i = 0;
+      while i < choice.len() {
+        choice[i] += 1;
+        if choice[i] < options[i].len() {
+          break;
+        }
+        choice[i] = 0;
+        i += 1;
+      }
+      if i == choice.len() {
+        break;
+      }
+    }
+    best
+      .map(|(_, spellings)| {
+        spellings
+          .into_iter()
+          .map(|s| (s.pitch_class(), s))
+          .collect()
+      })
+      .unwrap_or_default()
+  }
+
+  fn scale_cost(&self, spellings: &[Spelling]) -> ScaleCost {
+    let mut letters = [0; 7];
+    for s in spellings {
+      letters[s.step as usize] += 1;
+    }
+    let repeated = letters.iter().map(|n: &usize| n.saturating_sub(1)).sum();
+    let doubles = spellings.iter().filter(|s| s.alteration.abs() > 1).count();
+    let accidentals = spellings.iter().filter(|s| s.alteration != 0).count();
+    let sharps = spellings.iter().filter(|s| s.alteration > 0).count();
+    let flats = spellings.iter().filter(|s| s.alteration < 0).count();
+    let dispreferred = match self.prefer {
+      Accidentals::Sharps => flats,
+      Accidentals::Flats => sharps,
+    };
+    (
+      repeated,
+      doubles,
+      accidentals,
+      sharps > 0 && flats > 0,
+      dispreferred,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_spelling_policies() {
+    let sharps = SpellingPolicy::default();
+    let flats = SpellingPolicy::new(Accidentals::Flats);
+    assert_eq!(sharps.note_name(61), "C#4");
+    assert_eq!(flats.note_name(61), "Db4");
+    assert_eq!(flats.note_name(62), "D4");
+
+    // Eb major: Ab from the signature, and the chromatic F#/Gb as a flat
+    let eb = SpellingPolicy::in_key(KeySignature::major(Spelling::new(2, -1)));
+    assert_eq!(eb.key, Some(KeySignature::new(-3)));
+    assert_eq!(eb.spell(8).to_string(), "Ab");
+    assert_eq!(eb.spell(6).to_string(), "Gb");
+    assert_eq!(KeySignature::minor(Spelling::new(1, 0)).fifths, -1);
+
+    // scale spellings use each letter once
+    let f_major = sharps.clone().with_scale(&[5, 7, 9, 10, 0, 2, 4]);
+    assert_eq!(f_major.spell(10).to_string(), "Bb");
+    let d_major = flats.clone().with_scale(&[2, 4, 6, 7, 9, 11, 1]);
+    assert_eq!(d_major.note_name(61), "C#4");
+    // F# and Gb major have as many accidentals as each other
+    let gb_major = flats.with_scale(&[6, 8, 10, 11, 1, 3, 5]);
+    assert_eq!(gb_major.spell(11).to_string(), "Cb");
+    assert_eq!(gb_major.note_name(59), "Cb4");
+    let f_sharp_major = sharps.with_scale(&[6, 8, 10, 11, 1, 3, 5]);
+    assert_eq!(f_sharp_major.spell(5).to_string(), "E#");
+    // notes outside the scale fall back to the preference
+    assert_eq!(f_major.spell(6).to_string(), "F#");
+
+    let yamaha = SpellingPolicy {
+      octaves: OctaveNumbering::Yamaha,
+      ..Default::default()
+    };
+    assert_eq!(yamaha.note_name(60), "C3");
+    assert_eq!(yamaha.note_name(21), "A-1");
+  }
+}
@@ -0,0 +1,297 @@
+//! Renders a keymap as an SVG image for sharing or documentation: the keyboard drawn in its
+//! colors, with a [Legend] underneath.
+//!
+//! Keys are drawn as hexagons at their positions on the grid (see [crate::geometry]), and
+//! each note key is labelled with its scale degree in the [Tuning]. The legend gives the
+//! title (e.g. the scale's name), the tuning, which degrees each color is used for, and how
+//! many keys send on each MIDI channel. [render_svg_with] names the root and formats cents
+//! with the user's [DisplayPreferences].
+
+use std::fmt::Write;
+
+use lumatone_midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, RGBColor};
+
+use super::{
+  geometry::KeyCoord,
+  ltn::LumatoneKeyMap,
+  preferences::DisplayPreferences,
+  tuning::{nearest_12tet, pitch_index, Tuning},
+};
+
+/// The width of a key in the image, in pixels.
+const KEY_WIDTH: f64 = 28.0;
+
+const MARGIN: f64 = 20.0;
+const LINE_HEIGHT: f64 = 18.0;
+const UNLIT_COLOR: &str = "#202020";
+
+/// The keys sending on one MIDI channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelUse {
+  pub channel: MidiChannel,
+  pub keys: usize,
+  pub lowest: u8,
+  pub highest: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Legend {
+  /// Each lit color on a note key, in board order, with the scale degrees it's used for.
+  pub color_degrees: Vec<(RGBColor, Vec<usize>)>,
+
+  /// The channels the note and controller keys send on, lowest first.
+  pub channels: Vec<ChannelUse>,
+}
+
+impl Legend {
+  pub fn new(keymap: &LumatoneKeyMap, tuning: &Tuning) -> Self {
+    let mut color_degrees: Vec<(RGBColor, Vec<usize>)> = vec![];
+    let mut channels: Vec<ChannelUse> = vec![];
+    for location in LumatoneKeyLocation::all() {
+      let def = match keymap.get_key(location) {
+        Some(def) => def,
+        None => continue,
+      };
+      let (channel, number) = match channel_and_number(&def.function) {
+        Some(c) => c,
+        None => continue,
+      };
+      match channels.iter_mut().find(|c| c.channel == channel) {
+        Some(c) => {
+          c.keys += 1;
+          c.lowest = c.lowest.min(number);
+          c.highest = c.highest.max(number);
+        }
+        None => channels.push(ChannelUse {
+          channel,
+          keys: 1,
+          lowest: number,
+          highest: number,
+        }),
+      }
+
+      let degree = match degree(&def.function, tuning) {
+        Some(d) if def.color != RGBColor(0, 0, 0) => d,
+        _ => continue,
+      };
+      match color_degrees.iter_mut().find(|(c, _)| *c == def.color) {
+        Some((_, degrees)) if !degrees.contains(&degree) => degrees.push(degree),
+        Some(_) => {}
+        None => color_degrees.push((def.color, vec![degree])),
+      }
+    }
+    for (_, degrees) in color_degrees.iter_mut() {
+      degrees.sort_unstable();
+    }
+    channels.sort_by_key(|c| c.channel.get());
+    Legend {
+      color_degrees,
+      channels,
+    }
+  }
+}
+
+/// Renders `keymap` with a legend headed by `title`, with the default [DisplayPreferences].
+pub fn render_svg(keymap: &LumatoneKeyMap, title: &str, tuning: &Tuning) -> String {
+  render_svg_with(keymap, title, tuning, &DisplayPreferences::default())
+}
+
+/// Like [render_svg], naming the tuning's root and formatting its period with `prefs`.
+pub fn render_svg_with(
+  keymap: &LumatoneKeyMap,
+  title: &str,
+  tuning: &Tuning,
+  prefs: &DisplayPreferences,
+) -> String {
+  let legend = Legend::new(keymap, tuning);
+  let keys: Vec<(LumatoneKeyLocation, (f64, f64))> = LumatoneKeyLocation::all()
+    .into_iter()
+    .filter_map(|loc| Some((loc, KeyCoord::of(loc)?.center())))
+    .collect();
+  let max_x = keys.iter().map(|(_, (x, _))| *x).fold(0.0, f64::max);
+  let max_y = keys.iter().map(|(_, (_, y))| *y).fold(0.0, f64::max);
+  let width = (max_x + 1.0) * KEY_WIDTH + 2.0 * MARGIN;
+  let board_height = (max_y + 1.0) * KEY_WIDTH + MARGIN;
+  let legend_lines = 3 + legend.color_degrees.len() + legend.channels.len();
+  let height = board_height + legend_lines as f64 * LINE_HEIGHT + 2.0 * MARGIN;
+
+  let mut svg = String::new();
+  let _ = writeln!(
+    svg,
+    r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width:.0}" height="{height:.0}" font-family="sans-serif">"#
+  );
+  let _ = writeln!(
+    svg,
+    r##"<rect width="100%" height="100%" fill="#000000"/>"##
+  );
+
+  let size = KEY_WIDTH / 3f64.sqrt();
+  for (location, (x, y)) in keys {
+    let cx = MARGIN + (x + 0.5) * KEY_WIDTH;
+    let cy = MARGIN + (y + 0.5) * KEY_WIDTH;
+    let points: Vec<String> = (0..6)
+      .map(|i| {
+        let angle = (60.0 * i as f64 - 30.0).to_radians();
+        format!(
+          "{:.1},{:.1}",
+          cx + size * angle.cos(),
+          cy + size * angle.sin()
+        )
+      })
+      .collect();
+    let def = keymap.get_key(location);
+    let fill = match def.map(|d| d.color) {
+      Some(color) if color != RGBColor(0, 0, 0) => format!("#{}", color.to_hex_string()),
+      _ => UNLIT_COLOR.to_string(),
+    };
+    let _ = writeln!(
+      svg,
+      r##"<polygon points="{}" fill="{fill}" stroke="#555555" stroke-width="1"/>"##,
+      points.join(" ")
+    );
+    if let Some(degree) = def.and_then(|d| degree(&d.function, tuning)) {
+      let text = match def.map(|d| is_light(d.color)) {
+        Some(true) => "#000000",
+        _ => "#ffffff",
+      };
+      let _ = writeln!(
+        svg,
+        r#"<text x="{cx:.1}" y="{:.1}" font-size="9" text-anchor="middle" fill="{text}">{degree}</text>"#,
+        cy + 3.0
+      );
+    }
+  }
+
+  let mut y = board_height + MARGIN;
+  text(&mut svg, MARGIN, y, 16, title);
+  y += LINE_HEIGHT;
+  let mut spelling = tuning.spelling.clone();
+  prefs.apply_to_spelling(&mut spelling);
+  let (root, _) = nearest_12tet(tuning.root_pitch);
+  let summary = format!(
+    "{}: {} degrees per {} cents, from {}",
+    tuning.name,
+    tuning.len(),
+    prefs.cents(tuning.period),
+    spelling.note_name(root)
+  );
+  text(&mut svg, MARGIN, y, 12, &summary);
+  y += LINE_HEIGHT;
+  for (color, degrees) in &legend.color_degrees {
+    let _ = writeln!(
+      svg,
+      r##"<rect x="{MARGIN:.1}" y="{:.1}" width="12" height="12" fill="#{}" stroke="#555555"/>"##,
+      y - 11.0,
+      color.to_hex_string()
+    );
+    let degrees: Vec<String> = degrees.iter().map(|d| d.to_string()).collect();
+    let label = format!("degrees {}", degrees.join(", "));
+    text(&mut svg, MARGIN + 18.0, y, 12, &label);
+    y += LINE_HEIGHT;
+  }
+  let columns = [MARGIN, MARGIN + 70.0, MARGIN + 120.0];
+  for (x, heading) in columns.iter().zip(["Channel", "Keys", "Notes"]) {
+    text(&mut svg, *x, y, 12, heading);
+  }
+  for c in &legend.channels {
+    y += LINE_HEIGHT;
+    let row = [
+      c.channel.get().to_string(),
+      c.keys.to_string(),
+      format!("{}-{}", c.lowest, c.highest),
+    ];
+    for (x, cell) in columns.iter().zip(row) {
+      text(&mut svg, *x, y, 12, &cell);
+    }
+  }
+  svg.push_str("</svg>\n");
+  svg
+}
+
+fn text(svg: &mut String, x: f64, y: f64, size: u32, text: &str) {
+  let _ = writeln!(
+    svg,
+    r##"<text x="{x:.1}" y="{y:.1}" font-size="{size}" fill="#ffffff">{}</text>"##,
+    escape(text)
+  );
+}
+
+fn channel_and_number(function: &LumatoneKeyFunction) -> Option<(MidiChannel, u8)> {
+  match *function {
+    LumatoneKeyFunction::NoteOnOff { channel, note_num }
+    | LumatoneKeyFunction::LumaTouch {
+      channel, note_num, ..
+    } => Some((channel, note_num)),
+    LumatoneKeyFunction::ContinuousController {
+      channel, cc_num, ..
+    } => Some((channel, cc_num)),
+    LumatoneKeyFunction::Disabled => None,
+  }
+}
+
+/// The scale degree a note key plays, or `None` for other keys.
+fn degree(function: &LumatoneKeyFunction, tuning: &Tuning) -> Option<usize> {
+  match *function {
+    LumatoneKeyFunction::NoteOnOff { channel, note_num }
+    | LumatoneKeyFunction::LumaTouch {
+      channel, note_num, ..
+    } => Some(tuning.degree(pitch_index(channel, note_num)).0),
+    _ => None,
+  }
+}
+
+/// Whether dark text reads better than light text on `color`.
+fn is_light(color: RGBColor) -> bool {
+  let RGBColor(r, g, b) = color;
+  0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64 > 140.0
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{geometry::key_at, layout::IsomorphicLayout, spelling::OctaveNumbering};
+
+  #[test]
+  fn test_render_keymap_with_legend() {
+    let tuning = Tuning::equal(12);
+    let layout = IsomorphicLayout::new(2, -5, key_at(3, 27).unwrap(), 60);
+    let keymap = layout.generate(&tuning, &[RGBColor::red(), RGBColor::blue()]);
+
+    let legend = Legend::new(&keymap, &tuning);
+    let degrees = |color| {
+      let (_, degrees) = legend.color_degrees.iter().find(|(c, _)| *c == color)?;
+      Some(degrees.clone())
+    };
+    assert_eq!(legend.color_degrees.len(), 2);
+    assert_eq!(degrees(RGBColor::red()), Some(vec![0, 2, 4, 6, 8, 10]));
+    assert_eq!(degrees(RGBColor::blue()), Some(vec![1, 3, 5, 7, 9, 11]));
+    assert_eq!(
+      legend.channels.iter().map(|c| c.keys).sum::<usize>(),
+      LumatoneKeyLocation::all()
+        .into_iter()
+        .filter(|loc| keymap.get_key(*loc).unwrap().function != LumatoneKeyFunction::Disabled)
+        .count()
+    );
+
+    let svg = render_svg(&keymap, "Wicki-Hayden <12>", &tuning);
+    assert_eq!(svg.matches("<polygon").count(), 280);
+    assert!(svg.contains("Wicki-Hayden &lt;12&gt;"));
+    assert!(svg.contains("12-EDO: 12 degrees per 1200.00 cents, from C4"));
+    assert!(svg.ends_with("</svg>\n"));
+
+    let prefs = DisplayPreferences {
+      cents_precision: 0,
+      octave_numbering: OctaveNumbering::Yamaha,
+      ..Default::default()
+    };
+    let svg = render_svg_with(&keymap, "Wicki-Hayden", &tuning, &prefs);
+    assert!(svg.contains("12-EDO: 12 degrees per 1200 cents, from C3"));
+  }
+}
@@ -1,9 +1,87 @@
 #![allow(unused)]
 use super::{error::LumatoneKeymapError, table_defaults::*};
 use log::warn;
-use lumatone_midi::sysex::{SysexTable, VelocityIntervalTable};
+use lumatone_midi::{
+  commands::{
+    set_aftertouch_config, set_fader_config, set_lumatouch_config, set_velocity_config,
+    set_velocity_intervals, Command,
+  },
+  responses::Response,
+  sysex::{reverse_table, SysexTable, VelocityIntervalTable},
+};
 
-use ini::Ini;
+/// One of the device's 128-entry response tables.
+///
+/// Each kind is stored under its own key in .ltn files, and uploaded and read back with its
+/// own commands, so a [ConfigTableDefinition] can be written to a preset file or sent to
+/// the device as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TableKind {
+  OnOffVelocity,
+  FaderVelocity,
+  AftertouchVelocity,
+  LumatouchVelocity,
+}
+
+impl TableKind {
+  pub const ALL: [TableKind; 4] = [
+    TableKind::OnOffVelocity,
+    TableKind::AftertouchVelocity,
+    TableKind::FaderVelocity,
+    TableKind::LumatouchVelocity,
+  ];
+
+  /// The key the table is stored under in the general section of .ltn files.
+  pub fn ltn_key(&self) -> &'static str {
+    match self {
+      TableKind::OnOffVelocity => "NoteOnOffVelocityCrvTbl",
+      TableKind::FaderVelocity => "FaderConfig",
+      TableKind::AftertouchVelocity => "afterTouchConfig",
+      TableKind::LumatouchVelocity => "LumaTouchConfig",
+    }
+  }
+
+  /// Uploads `table` to the device.
+  pub fn set_command(&self, table: &SysexTable) -> Command {
+    match self {
+      TableKind::OnOffVelocity => set_velocity_config(*table),
+      TableKind::FaderVelocity => set_fader_config(*table),
+      TableKind::AftertouchVelocity => set_aftertouch_config(*table),
+      TableKind::LumatouchVelocity => set_lumatouch_config(*table),
+    }
+  }
+
+  /// Reads the table back from the device.
+  pub fn get_command(&self) -> Command {
+    match self {
+      TableKind::OnOffVelocity => Command::GetVelocityConfig,
+      TableKind::FaderVelocity => Command::GetFaderConfig,
+      TableKind::AftertouchVelocity => Command::GetAftertouchConfig,
+      TableKind::LumatouchVelocity => Command::GetLumatouchConfig,
+    }
+  }
+
+  /// Returns the table from the response to [TableKind::get_command], in the same order
+  /// as in .ltn files.
+  pub fn table_from_response(
+    &self,
+    response: &Response,
+  ) -> Result<SysexTable, LumatoneKeymapError> {
+    let table = match (self, response) {
+      // the device stores the velocity curve in the reverse order
+      (TableKind::OnOffVelocity, Response::OnOffVelocityConfig(t)) => reverse_table(t),
+      (TableKind::FaderVelocity, Response::FaderConfig(t))
+      | (TableKind::AftertouchVelocity, Response::AftertouchConfig(t))
+      | (TableKind::LumatouchVelocity, Response::LumatouchConfig(t)) => **t,
+      _ => {
+        return Err(LumatoneKeymapError::InvalidTableDefinition(format!(
+          "expected a {self:?} table, got {response}"
+        )))
+      }
+    };
+    Ok(table)
+  }
+}
 
 #[derive(Debug)]
 pub enum EditingStrategy {
@@ -21,6 +99,46 @@ pub struct ConfigurationTables {
   pub velocity_intervals: Option<VelocityIntervalTable>,
 }
 
+impl ConfigurationTables {
+  pub fn get(&self, kind: TableKind) -> Option<&ConfigTableDefinition> {
+    self.slot(kind).as_ref()
+  }
+
+  pub fn set(&mut self, kind: TableKind, table: Option<ConfigTableDefinition>) {
+    *self.slot_mut(kind) = table;
+  }
+
+  /// The commands that upload every table that's defined.
+  pub fn to_midi_commands(&self) -> Vec<Command> {
+    let mut commands: Vec<Command> = TableKind::ALL
+      .iter()
+      .filter_map(|kind| self.get(*kind).map(|t| t.to_command(*kind)))
+      .collect();
+    if let Some(t) = self.velocity_intervals {
+      commands.push(set_velocity_intervals(t));
+    }
+    commands
+  }
+
+  fn slot(&self, kind: TableKind) -> &Option<ConfigTableDefinition> {
+    match kind {
+      TableKind::OnOffVelocity => &self.on_off_velocity,
+      TableKind::FaderVelocity => &self.fader_velocity,
+      TableKind::AftertouchVelocity => &self.aftertouch_velocity,
+      TableKind::LumatouchVelocity => &self.lumatouch_velocity,
+    }
+  }
+
+  fn slot_mut(&mut self, kind: TableKind) -> &mut Option<ConfigTableDefinition> {
+    match kind {
+      TableKind::OnOffVelocity => &mut self.on_off_velocity,
+      TableKind::FaderVelocity => &mut self.fader_velocity,
+      TableKind::AftertouchVelocity => &mut self.aftertouch_velocity,
+      TableKind::LumatouchVelocity => &mut self.lumatouch_velocity,
+    }
+  }
+}
+
 impl Default for ConfigurationTables {
   fn default() -> Self {
     ConfigurationTables {
@@ -54,6 +172,16 @@ impl ConfigTableDefinition {
     }
   }
 
+  /// Reads a table back from the device, from the response to [TableKind::get_command].
+  pub fn from_response(kind: TableKind, response: &Response) -> Result<Self, LumatoneKeymapError> {
+    kind.table_from_response(response).map(Self::new)
+  }
+
+  /// The command that uploads this table to the device as the `kind` table.
+  pub fn to_command(&self, kind: TableKind) -> Command {
+    kind.set_command(&self.table)
+  }
+
   pub fn to_string(&self) -> String {
     let table_str = self
       .table
@@ -108,6 +236,9 @@ impl ConfigTableDefinition {
   }
 }
 
+/// The largest value in the velocity interval table, which is sent as 12-bit values.
+pub const MAX_VELOCITY_INTERVAL: u16 = 0xfff;
+
 pub fn parse_velocity_intervals(s: &str) -> Result<VelocityIntervalTable, LumatoneKeymapError> {
   use LumatoneKeymapError::InvalidTableDefinition;
   let tokens: Vec<&str> = s.split(char::is_whitespace).collect();
@@ -124,6 +255,11 @@ pub fn parse_velocity_intervals(s: &str) -> Result<VelocityIntervalTable, Lumato
     let val = u16::from_str_radix(s, 10).map_err(|e| {
       InvalidTableDefinition(format!("unable to parse in in table definition: {e}"))
     })?;
+    if val > MAX_VELOCITY_INTERVAL {
+      return Err(InvalidTableDefinition(format!(
+        "velocity interval {val} at index {i} is out of range (0 - {MAX_VELOCITY_INTERVAL})"
+      )));
+    }
     if i == 127 {
       warn!(
         "velocity table is more than 127 elements long, ignoring {} values",
@@ -143,3 +279,154 @@ pub fn velocity_intervals_to_string(table: &VelocityIntervalTable) -> String {
     .collect::<Vec<String>>()
     .join(" ")
 }
+
+/// Reads the table from the response to [Command::GetVelocityIntervalConfig]. Use
+/// [set_velocity_intervals](lumatone_midi::commands::set_velocity_intervals) to send a
+/// table to the device.
+pub fn velocity_intervals_from_response(
+  response: &Response,
+) -> Result<VelocityIntervalTable, LumatoneKeymapError> {
+  match response {
+    Response::VelocityIntervalConfig(table) => Ok(**table),
+    _ => Err(LumatoneKeymapError::InvalidTableDefinition(format!(
+      "expected a velocity interval response, got {response}"
+    ))),
+  }
+}
+
+/// The note on/off velocity curve: the velocity sent for each of the 128 steps of how hard
+/// a key is struck, softest first, as in `.ltn` files.
+///
+/// The device stores the table in the opposite order. [VelocityCurve::to_sysex_table] and
+/// [VelocityCurve::from_sysex_table] convert to and from the device's order, one 7-bit
+/// value per byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VelocityCurve(SysexTable);
+
+impl VelocityCurve {
+  /// Creates a curve from 128 velocities, softest first. Velocities can't be above 127.
+  pub fn new(values: &[u8]) -> Result<Self, LumatoneKeymapError> {
+    use LumatoneKeymapError::InvalidTableDefinition;
+    let table: SysexTable = values.try_into().map_err(|_| {
+      InvalidTableDefinition(format!(
+        "velocity curve requires 128 values, but has {}",
+        values.len()
+      ))
+    })?;
+    if let Some((i, v)) = table.iter().enumerate().find(|(_, v)| **v > 127) {
+      return Err(InvalidTableDefinition(format!(
+        "velocity curve value {v} at index {i} is out of range (0 - 127)"
+      )));
+    }
+    Ok(VelocityCurve(table))
+  }
+
+  pub fn values(&self) -> &SysexTable {
+    &self.0
+  }
+
+  /// The table in the order the device stores it.
+  pub fn to_sysex_table(&self) -> SysexTable {
+    reverse_table(&self.0)
+  }
+
+  /// Reads a table in the order the device stores it, e.g. from a
+  /// [Response::OnOffVelocityConfig].
+  pub fn from_sysex_table(table: &SysexTable) -> Result<Self, LumatoneKeymapError> {
+    Self::new(&reverse_table(table))
+  }
+
+  /// The command that uploads this curve. [Command::SetVelocityConfig] puts the table in the
+  /// device's order when it's encoded.
+  pub fn to_command(&self) -> Command {
+    TableKind::OnOffVelocity.set_command(&self.0)
+  }
+
+  /// Reads the curve from the response to [Command::GetVelocityConfig].
+  pub fn from_response(response: &Response) -> Result<Self, LumatoneKeymapError> {
+    Self::new(&TableKind::OnOffVelocity.table_from_response(response)?)
+  }
+}
+
+impl From<VelocityCurve> for ConfigTableDefinition {
+  fn from(curve: VelocityCurve) -> Self {
+    ConfigTableDefinition::new(curve.0)
+  }
+}
+
+impl Default for VelocityCurve {
+  fn default() -> Self {
+    VelocityCurve(DEFAULT_ON_OFF_VELOCITY_TABLE)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::{
+    constants::CommandId,
+    sysex::{strip_sysex_markers, MSG_STATUS},
+  };
+
+  #[test]
+  fn test_velocity_curve_round_trip() {
+    let curve = VelocityCurve::default();
+    let msg = curve.to_command().to_sysex_message();
+    // outgoing messages have no status byte, so the table starts where it would be
+    let payload: SysexTable = strip_sysex_markers(&msg)[MSG_STATUS..].try_into().unwrap();
+    assert_eq!(payload, curve.to_sysex_table());
+    assert_eq!(payload[0], 127);
+
+    let response = Response::OnOffVelocityConfig(Box::new(payload));
+    assert_eq!(VelocityCurve::from_response(&response).unwrap(), curve);
+    assert!(VelocityCurve::from_response(&Response::Ack(CommandId::GetVelocityConfig)).is_err());
+  }
+
+  #[test]
+  fn test_velocity_intervals() {
+    let text = velocity_intervals_to_string(&DEFAULT_VELOCITY_INTERVAL_TABLE);
+    let table = parse_velocity_intervals(&text).unwrap();
+    assert_eq!(table, DEFAULT_VELOCITY_INTERVAL_TABLE);
+    assert!(parse_velocity_intervals(&text.replace("310", "5000")).is_err());
+
+    let response = Response::VelocityIntervalConfig(Box::new(table));
+    assert_eq!(velocity_intervals_from_response(&response).unwrap(), table);
+    assert!(
+      velocity_intervals_from_response(&Response::Ack(CommandId::GetVelocityIntervals)).is_err()
+    );
+  }
+
+  #[test]
+  fn test_table_kinds() {
+    let mut table = [0; 128];
+    table[0] = 10;
+    let mut tables = ConfigurationTables::default();
+    tables.set(
+      TableKind::FaderVelocity,
+      Some(ConfigTableDefinition::new(table)),
+    );
+    tables.set(
+      TableKind::OnOffVelocity,
+      Some(VelocityCurve::default().into()),
+    );
+    let commands = tables.to_midi_commands();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(commands[1], set_fader_config(table));
+
+    let response = Response::FaderConfig(Box::new(table));
+    let read = ConfigTableDefinition::from_response(TableKind::FaderVelocity, &response).unwrap();
+    assert_eq!(read.table, table);
+    assert!(
+      ConfigTableDefinition::from_response(TableKind::AftertouchVelocity, &response).is_err()
+    );
+  }
+
+  #[test]
+  fn test_velocity_curve_validation() {
+    assert!(VelocityCurve::new(&[64; 127]).is_err());
+    let mut values = [64; 128];
+    assert!(VelocityCurve::new(&values).is_ok());
+    values[5] = 128;
+    assert!(VelocityCurve::new(&values).is_err());
+  }
+}
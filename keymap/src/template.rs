@@ -0,0 +1,302 @@
+//! Keymap templates: an [isomorphic layout](crate::layout) description with variables, so
+//! one file can generate a family of related keymaps (the same layout in every key, or in
+//! a few color schemes).
+//!
+//! Templates are ini files. Values in the `Template` and `Layout` sections can refer to
+//! variables as `{name}`; each variable needs a default in the `Variables` section, which
+//! [KeymapTemplate::render] can override. Palettes are named lists of colors.
+//!
+//! ```text
+//! [Template]
+//! Name=Wicki-Hayden in {root}
+//!
+//! [Variables]
+//! root=C
+//! octave=4
+//! palette=rainbow
+//!
+//! [Layout]
+//! Divisions=12
+//! Right=2
+//! DownRight=-5
+//! Anchor=3:27
+//! AnchorNote={root}{octave}
+//! Colors={palette}
+//!
+//! [Palettes]
+//! rainbow=ff0000,ff8000,ffff00,00ff00,00ffff,0000ff,8000ff
+//! mono=ffffff
+//! ```
+//!
+//! `AnchorNote` is a 12-TET note name like `D4`, or a [pitch index](crate::tuning::pitch_index).
+//! Keys are colored by scale degree counting from the anchor note, so the root keeps its
+//! color when the template is rendered in another key. `Colors` is a palette name or a
+//! comma-separated list of colors, and `Mirrored=1` flips the layout for left-handed
+//! players.
+
+use std::collections::{BTreeMap, HashMap};
+
+use ini::{Ini, Properties};
+use lumatone_midi::constants::RGBColor;
+
+use super::{
+  error::LumatoneKeymapError, geometry::key_at, layout::IsomorphicLayout, ltn::LumatoneKeyMap,
+  notation::parse_note_name, tuning::Tuning,
+};
+
+/// Variable values by name, to override a template's defaults.
+pub type TemplateVars = HashMap<String, String>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeymapTemplate {
+  name: String,
+  defaults: BTreeMap<String, String>,
+  layout: BTreeMap<String, String>,
+  palettes: BTreeMap<String, Vec<RGBColor>>,
+}
+
+/// A keymap generated from a template, with its name after substituting variables.
+#[derive(Debug)]
+pub struct RenderedTemplate {
+  pub name: String,
+  pub keymap: LumatoneKeyMap,
+}
+
+impl KeymapTemplate {
+  pub fn from_ini_str<S: AsRef<str>>(source: S) -> Result<KeymapTemplate, LumatoneKeymapError> {
+    let ini = Ini::load_from_str(source.as_ref())?;
+    let section = |name: &str| -> BTreeMap<String, String> {
+      ini
+        .section(Some(name))
+        .map(properties_to_map)
+        .unwrap_or_default()
+    };
+
+    let mut palettes = BTreeMap::new();
+    for (name, colors) in section("Palettes") {
+      palettes.insert(name, parse_colors(&colors)?);
+    }
+    let layout = section("Layout");
+    if layout.is_empty() {
+      return Err(invalid("missing [Layout] section"));
+    }
+    Ok(KeymapTemplate {
+      name: section("Template")
+        .remove("Name")
+        .unwrap_or_else(|| "Untitled".to_string()),
+      defaults: section("Variables"),
+      layout,
+      palettes,
+    })
+  }
+
+  /// The template's variables and their default values.
+  pub fn variables(&self) -> &BTreeMap<String, String> {
+    &self.defaults
+  }
+
+  /// Generates the keymap with `overrides` replacing the default variable values. Only
+  /// variables the template declares can be overridden, to catch typos.
+  pub fn render(&self, overrides: &TemplateVars) -> Result<RenderedTemplate, LumatoneKeymapError> {
+    self.render_with(overrides, None)
+  }
+
+  /// Like [KeymapTemplate::render], but lays out `scale` instead of the template's equal
+  /// division, e.g. one read from a Scala file. Keys are colored by degree of `scale`.
+  pub fn render_scale(
+    &self,
+    overrides: &TemplateVars,
+    scale: &Tuning,
+  ) -> Result<RenderedTemplate, LumatoneKeymapError> {
+    self.render_with(overrides, Some(scale))
+  }
+
+  fn render_with(
+    &self,
+    overrides: &TemplateVars,
+    scale: Option<&Tuning>,
+  ) -> Result<RenderedTemplate, LumatoneKeymapError> {
+    let mut vars = self.defaults.clone();
+    for (name, value) in overrides {
+      match vars.get_mut(name) {
+        Some(v) => *v = value.clone(),
+        None => return Err(invalid(&format!("unknown variable {name}"))),
+      }
+    }
+
+    let field = |name: &str| -> Result<String, LumatoneKeymapError> {
+      let value = self
+        .layout
+        .get(name)
+        .ok_or_else(|| invalid(&format!("missing {name} in [Layout]")))?;
+      substitute(value, &vars)
+    };
+    let number = |name: &str| -> Result<i32, LumatoneKeymapError> {
+      let value = field(name)?;
+      value
+        .trim()
+        .parse()
+        .map_err(|_| invalid(&format!("invalid {name}: {value}")))
+    };
+
+    let divisions = match self.layout.contains_key("Divisions") {
+      true => number("Divisions")?,
+      false => 12,
+    };
+    if divisions < 1 {
+      return Err(invalid("Divisions must be at least 1"));
+    }
+    let anchor_note = field("AnchorNote")?;
+    let anchor_index = match anchor_note.trim().parse::<i32>() {
+      Ok(index) => index,
+      Err(_) => parse_note_name(anchor_note.trim())
+        .ok_or_else(|| invalid(&format!("invalid AnchorNote: {anchor_note}")))?,
+    };
+    let anchor = field("Anchor")?;
+    let anchor = anchor
+      .split_once(':')
+      .and_then(|(board, key)| key_at(board.trim().parse().ok()?, key.trim().parse().ok()?))
+      .ok_or_else(|| invalid(&format!("invalid Anchor: {anchor}")))?;
+
+    let mut layout =
+      IsomorphicLayout::new(number("Right")?, number("DownRight")?, anchor, anchor_index);
+    if self.layout.contains_key("Mirrored") && number("Mirrored")? != 0 {
+      layout = layout.mirror();
+    }
+
+    let mut tuning = match scale {
+      Some(scale) => scale.clone(),
+      None => Tuning::equal(divisions as usize),
+    };
+    tuning.root_index = anchor_index;
+    let colors = match self.layout.contains_key("Colors") {
+      true => {
+        let colors = field("Colors")?;
+        match self.palettes.get(colors.trim()) {
+          Some(palette) => palette.clone(),
+          None => parse_colors(&colors)?,
+        }
+      }
+      false => vec![],
+    };
+
+    Ok(RenderedTemplate {
+      name: substitute(&self.name, &vars)?,
+      keymap: layout.generate(&tuning, &colors),
+    })
+  }
+}
+
+fn invalid(msg: &str) -> LumatoneKeymapError {
+  LumatoneKeymapError::InvalidTemplate(msg.to_string())
+}
+
+fn properties_to_map(props: &Properties) -> BTreeMap<String, String> {
+  props
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+/// Replaces each `{name}` in `text` with the variable's value.
+fn substitute(text: &str, vars: &BTreeMap<String, String>) -> Result<String, LumatoneKeymapError> {
+  let mut out = String::new();
+  let mut rest = text;
+  while let Some(start) = rest.find('{') {
+    out.push_str(&rest[..start]);
+    let end = rest[start..]
+      .find('}')
+      .ok_or_else(|| invalid(&format!("unclosed {{ in {text}")))?;
+    let name = rest[start + 1..start + end].trim();
+    let value = vars
+      .get(name)
+      .ok_or_else(|| LumatoneKeymapError::MissingTemplateVariable(name.to_string()))?;
+    out.push_str(value);
+    rest = &rest[start + end + 1..];
+  }
+  out.push_str(rest);
+  Ok(out)
+}
+
+fn parse_colors(s: &str) -> Result<Vec<RGBColor>, LumatoneKeymapError> {
+  s.split(',')
+    .map(|c| {
+      let c = c.trim().trim_start_matches('#');
+      match c.len() {
+        6 => u32::from_str_radix(c, 16).ok().map(RGBColor::from),
+        _ => None,
+      }
+      .ok_or_else(|| invalid(&format!("invalid color {c}")))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lumatone_midi::constants::{key_loc_unchecked, LumatoneKeyFunction, MidiChannel};
+
+  const TEMPLATE: &str = "
+[Template]
+Name=Wicki-Hayden in {root}
+
+[Variables]
+root=C
+octave=4
+palette=rgb
+
+[Layout]
+Right=2
+DownRight=-5
+Anchor=3:27
+AnchorNote={root}{octave}
+Colors={palette}
+
+[Palettes]
+rgb=ff0000,00ff00,0000ff
+";
+
+  #[test]
+  fn test_variables_generate_related_keymaps() {
+    let template = KeymapTemplate::from_ini_str(TEMPLATE).unwrap();
+    let anchor = key_loc_unchecked(3, 27);
+
+    let c = template.render(&TemplateVars::new()).unwrap();
+    assert_eq!(c.name, "Wicki-Hayden in C");
+    let key = c.keymap.get_key(anchor).unwrap();
+    assert_eq!(
+      key.function,
+      LumatoneKeyFunction::NoteOnOff {
+        channel: MidiChannel::default(),
+        note_num: 60
+      }
+    );
+    assert_eq!(key.color, RGBColor::red());
+
+    let vars = TemplateVars::from([
+      ("root".to_string(), "D".to_string()),
+      ("octave".to_string(), "3".to_string()),
+    ]);
+    let d = template.render(&vars).unwrap();
+    assert_eq!(d.name, "Wicki-Hayden in D");
+    let key = d.keymap.get_key(anchor).unwrap();
+    assert_eq!(
+      key.function,
+      LumatoneKeyFunction::NoteOnOff {
+        channel: MidiChannel::default(),
+        note_num: 50
+      }
+    );
+    // the root keeps the first color in every key
+    assert_eq!(key.color, RGBColor::red());
+
+    let typo = TemplateVars::from([("rot".to_string(), "D".to_string())]);
+    assert!(template.render(&typo).is_err());
+    assert!(matches!(
+      substitute("{missing}", &BTreeMap::new()),
+      Err(LumatoneKeymapError::MissingTemplateVariable(_))
+    ));
+    assert_eq!(parse_note_name("F#3"), Some(54));
+    assert_eq!(parse_note_name("Bb2"), Some(46));
+  }
+}
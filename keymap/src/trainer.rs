@@ -0,0 +1,341 @@
+//! A phrase trainer: loops a phrase (e.g. loaded from a MIDI file, see
+//! [lumatone_midi::smf]), lights each note's keys a little before it's due, and scores how
+//! accurately and on time the player plays it on each pass.
+//!
+//! The trainer doesn't keep time itself. The caller passes the time since the start of the
+//! pass to [PhraseTrainer::tick] and [PhraseTrainer::handle], and sends the returned
+//! [Command]s to the device. For [PhraseTrainer::handle], that's the time the note was
+//! played (see [lumatone_midi::timing]), so that scores don't depend on how quickly the
+//! message was handled.
+//!
+//! Once a note has been played or missed, its keys go back to their colors in the keymap.
+//! [PhraseTrainer::restore] does the same for every key the trainer lights, for when
+//! training stops.
+
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use lumatone_midi::{
+  commands::{set_key_color, Command},
+  constants::{LumatoneKeyLocation, MidiChannel, RGBColor},
+  events::ChannelMessage,
+  session::Replay,
+};
+
+use super::{lighting::keys_by_note, ltn::LumatoneKeyMap};
+
+/// How long before a note is due its keys light up.
+pub const DEFAULT_LEAD: Duration = Duration::from_millis(300);
+
+/// How far from the expected time a note can be played and still count.
+pub const DEFAULT_TOLERANCE: Duration = Duration::from_millis(150);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NoteState {
+  Waiting,
+  Lit,
+  Hit { error_ms: i64 },
+  Missed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ExpectedNote {
+  time: Duration,
+  channel: MidiChannel,
+  note: u8,
+  state: NoteState,
+}
+
+/// The result of one pass through the phrase.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PassScore {
+  /// Notes played within the tolerance of when they were due.
+  pub hits: usize,
+
+  /// Notes that weren't played in time.
+  pub misses: usize,
+
+  /// Notes played that didn't match an expected note.
+  pub wrong_notes: usize,
+
+  /// The mean of how early (negative) or late (positive) the hits were, in milliseconds.
+  pub mean_timing_error_ms: f64,
+
+  /// The mean of how far from the expected time the hits were, in milliseconds.
+  pub mean_abs_timing_error_ms: f64,
+}
+
+impl PassScore {
+  /// The fraction of expected notes that were hit, counting wrong notes against it.
+  pub fn accuracy(&self) -> f64 {
+    let total = self.hits + self.misses + self.wrong_notes;
+    match total {
+      0 => 1.0,
+      _ => self.hits as f64 / total as f64,
+    }
+  }
+}
+
+impl Display for PassScore {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "{:.0}% accuracy ({} hit, {} missed, {} wrong), timing {:+.0} ms (±{:.0} ms)",
+      self.accuracy() * 100.0,
+      self.hits,
+      self.misses,
+      self.wrong_notes,
+      self.mean_timing_error_ms,
+      self.mean_abs_timing_error_ms
+    )
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct PhraseTrainer {
+  keys: HashMap<(MidiChannel, u8), Vec<LumatoneKeyLocation>>,
+  /// The keymap's color for each key in `keys`.
+  resting: HashMap<LumatoneKeyLocation, RGBColor>,
+  expected: Vec<ExpectedNote>,
+  wrong_notes: usize,
+  pass_length: Duration,
+  history: Vec<PassScore>,
+
+  pub color: RGBColor,
+  pub lead: Duration,
+  pub tolerance: Duration,
+}
+
+impl PhraseTrainer {
+  /// Trains the notes of one pass of `replay`, lighting the keys of `keymap` that play them.
+  pub fn new(keymap: &LumatoneKeyMap, replay: &Replay) -> Self {
+    let expected = replay
+      .schedule()
+      .into_iter()
+      .filter_map(|e| match e.message {
+        ChannelMessage::NoteOn { channel, note, .. } => Some(ExpectedNote {
+          time: e.time,
+          channel,
+          note,
+          state: NoteState::Waiting,
+        }),
+        _ => None,
+      })
+      .collect();
+    let keys = keys_by_note(keymap);
+    let resting = keys
+      .values()
+      .flatten()
+      .filter_map(|loc| keymap.get_key(*loc).map(|def| (*loc, def.color)))
+      .collect();
+    PhraseTrainer {
+      keys,
+      resting,
+      expected,
+      wrong_notes: 0,
+      pass_length: replay.pass_length(),
+      history: vec![],
+      color: RGBColor::green(),
+      lead: DEFAULT_LEAD,
+      tolerance: DEFAULT_TOLERANCE,
+    }
+  }
+
+  /// The length of a pass, including the tolerance after the last note.
+  pub fn pass_length(&self) -> Duration {
+    let last_note = self.expected.last().map_or(Duration::ZERO, |n| n.time);
+    self.pass_length.max(last_note + self.tolerance)
+  }
+
+  /// The scores of the finished passes, oldest first.
+  pub fn history(&self) -> &[PassScore] {
+    &self.history
+  }
+
+  /// Starts a new pass. Returns the commands to restore the keys lit by the last one.
+  pub fn start_pass(&mut self) -> Vec<Command> {
+    let mut commands = vec![];
+    for i in 0..self.expected.len() {
+      if self.expected[i].state == NoteState::Lit {
+        commands.extend(self.restore_note(i));
+      }
+      self.expected[i].state = NoteState::Waiting;
+    }
+    self.wrong_notes = 0;
+    commands
+  }
+
+  /// Updates the expected notes for `elapsed` time into the pass: lights the notes that
+  /// are coming up, and marks the ones whose time has passed as missed.
+  pub fn tick(&mut self, elapsed: Duration) -> Vec<Command> {
+    let mut commands = vec![];
+    for i in 0..self.expected.len() {
+      let note = self.expected[i];
+      match note.state {
+        NoteState::Waiting if elapsed + self.lead >= note.time => {
+          self.expected[i].state = NoteState::Lit;
+          commands.extend(self.light_note(i));
+        }
+        _ => {}
+      }
+      if self.expected[i].state == NoteState::Lit && elapsed > note.time + self.tolerance {
+        self.expected[i].state = NoteState::Missed;
+        commands.extend(self.restore_note(i));
+      }
+    }
+    commands
+  }
+
+  /// Scores a message played `elapsed` time into the pass. A note on counts as a hit for
+  /// the earliest unplayed expected note with the same channel and note within the
+  /// tolerance, and otherwise as a wrong note.
+  pub fn handle(&mut self, elapsed: Duration, msg: &ChannelMessage) -> Vec<Command> {
+    let (channel, note) = match *msg {
+      ChannelMessage::NoteOn { channel, note, .. } => (channel, note),
+      _ => return vec![],
+    };
+    let tolerance = self.tolerance;
+    let matching = self.expected.iter().position(|n| {
+      let open = matches!(n.state, NoteState::Waiting | NoteState::Lit);
+      let in_time = elapsed + tolerance >= n.time && elapsed <= n.time + tolerance;
+      open && in_time && n.channel == channel && n.note == note
+    });
+    match matching {
+      Some(i) => {
+        let error_ms = elapsed.as_millis() as i64 - self.expected[i].time.as_millis() as i64;
+        self.expected[i].state = NoteState::Hit { error_ms };
+        self.restore_note(i)
+      }
+      None => {
+        self.wrong_notes += 1;
+        vec![]
+      }
+    }
+  }
+
+  /// Scores the pass, and adds the score to the [history](Self::history). Any notes that
+  /// weren't played count as misses.
+  pub fn finish_pass(&mut self) -> PassScore {
+    let errors: Vec<f64> = self
+      .expected
+      .iter()
+      .filter_map(|n| match n.state {
+        NoteState::Hit { error_ms } => Some(error_ms as f64),
+        _ => None,
+      })
+      .collect();
+    let mean = |values: Vec<f64>| match values.len() {
+      0 => 0.0,
+      n => values.iter().sum::<f64>() / n as f64,
+    };
+    let score = PassScore {
+      hits: errors.len(),
+      misses: self.expected.len() - errors.len(),
+      wrong_notes: self.wrong_notes,
+      mean_timing_error_ms: mean(errors.clone()),
+      mean_abs_timing_error_ms: mean(errors.iter().map(|e| e.abs()).collect()),
+    };
+    self.history.push(score.clone());
+    score
+  }
+
+  /// Returns the commands to set every key the trainer lights back to its keymap color.
+  pub fn restore(&self) -> Vec<Command> {
+    self
+      .keys
+      .values()
+      .flatten()
+      .map(|loc| set_key_color(*loc, self.resting_color(loc)))
+      .collect()
+  }
+
+  fn resting_color(&self, location: &LumatoneKeyLocation) -> RGBColor {
+    self
+      .resting
+      .get(location)
+      .copied()
+      .unwrap_or(RGBColor(0, 0, 0))
+  }
+
+  fn note_keys(&self, index: usize) -> impl Iterator<Item = &LumatoneKeyLocation> {
+    let ExpectedNote { channel, note, .. } = self.expected[index];
+    self.keys.get(&(channel, note)).into_iter().flatten()
+  }
+
+  fn light_note(&self, index: usize) -> Vec<Command> {
+    self
+      .note_keys(index)
+      .map(|loc| set_key_color(*loc, self.color))
+      .collect()
+  }
+
+  fn restore_note(&self, index: usize) -> Vec<Command> {
+    self
+      .note_keys(index)
+      .map(|loc| set_key_color(*loc, self.resting_color(loc)))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ltn::KeyDefinition;
+  use lumatone_midi::{
+    constants::{key_loc_unchecked, LumatoneKeyFunction},
+    session::Session,
+  };
+
+  #[test]
+  fn test_trainer_scores_a_pass() {
+    let ms = Duration::from_millis;
+    let channel = MidiChannel::default();
+    let note_on = |note| ChannelMessage::NoteOn {
+      channel,
+      note,
+      velocity: 100,
+    };
+    let mut session = Session::new();
+    session.push(ms(0), note_on(60));
+    session.push(ms(1000), note_on(62));
+    session.push(ms(2000), note_on(64));
+
+    let mut keymap = LumatoneKeyMap::new();
+    keymap.set_key(
+      key_loc_unchecked(1, 0),
+      KeyDefinition {
+        function: LumatoneKeyFunction::NoteOnOff {
+          channel,
+          note_num: 62,
+        },
+        color: RGBColor(0, 0, 255),
+      },
+    );
+    let mut trainer = PhraseTrainer::new(&keymap, &Replay::new(session));
+
+    trainer.start_pass();
+    trainer.handle(ms(20), &note_on(60));
+    assert!(trainer.tick(ms(600)).is_empty());
+    // lit 300ms ahead
+    assert_eq!(
+      trainer.tick(ms(700)),
+      vec![set_key_color(key_loc_unchecked(1, 0), RGBColor::green())]
+    );
+    assert_eq!(
+      trainer.handle(ms(960), &note_on(62)),
+      vec![set_key_color(key_loc_unchecked(1, 0), RGBColor(0, 0, 255))]
+    );
+    trainer.handle(ms(1500), &note_on(61));
+    trainer.tick(ms(2200));
+
+    let score = trainer.finish_pass();
+    assert_eq!((score.hits, score.misses, score.wrong_notes), (2, 1, 1));
+    assert_eq!(score.mean_timing_error_ms, -10.0);
+    assert_eq!(score.mean_abs_timing_error_ms, 30.0);
+    assert_eq!(score.accuracy(), 0.5);
+    assert_eq!(trainer.history().len(), 1);
+    assert_eq!(
+      trainer.restore(),
+      vec![set_key_color(key_loc_unchecked(1, 0), RGBColor(0, 0, 255))]
+    );
+  }
+}
@@ -0,0 +1,195 @@
+//! A minimal model of tunings and scales.
+//!
+//! A [Tuning] is a list of scale degrees, given in cents above the root, that repeats every
+//! `period` cents (usually an octave). Each key on the Lumatone sends a MIDI channel and
+//! note number, which map to a position in the tuning through a [pitch index](pitch_index):
+//! the note number plus 128 for each channel above channel 1, so a layout can spread a large
+//! scale across several channels.
+//!
+//! The root of the tuning is at `root_index`, and sounds at `root_pitch`, given as a
+//! (possibly fractional) 12-TET MIDI note number.
+
+use lumatone_midi::constants::MidiChannel;
+
+use super::{error::LumatoneKeymapError, spelling::SpellingPolicy};
+
+/// The number of cents in a 12-TET semitone.
+pub const CENTS_PER_SEMITONE: f64 = 100.0;
+
+/// Returns the position in a tuning of the note sent on `channel` with note number `note`.
+pub fn pitch_index(channel: MidiChannel, note: u8) -> i32 {
+  channel.get_as_zero_indexed() as i32 * 128 + note as i32
+}
+
+/// Returns the size of the frequency ratio `numerator / denominator`, in cents.
+pub fn ratio_to_cents(numerator: u32, denominator: u32) -> f64 {
+  1200.0 * (numerator as f64 / denominator as f64).log2()
+}
+
+/// Returns the nearest 12-TET MIDI note to a fractional MIDI pitch, and the deviation from
+/// it in cents.
+pub fn nearest_12tet(midi_pitch: f64) -> (i32, f64) {
+  let note = midi_pitch.round();
+  (note as i32, (midi_pitch - note) * CENTS_PER_SEMITONE)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+  pub name: String,
+
+  /// The pitch of each scale degree in cents above the root, starting with the root at 0.
+  pub degrees: Vec<f64>,
+
+  /// The interval the scale repeats at, in cents.
+  pub period: f64,
+
+  /// The [pitch index](pitch_index) that plays the root.
+  pub root_index: i32,
+
+  /// The pitch of the root, as a 12-TET MIDI note number (60.0 is middle C).
+  pub root_pitch: f64,
+
+  /// How to spell the names of the nearest 12-TET notes.
+  pub spelling: SpellingPolicy,
+}
+
+impl Tuning {
+  /// An equal division of the octave into `divisions` steps, rooted on middle C.
+  pub fn equal(divisions: usize) -> Self {
+    let divisions = divisions.max(1);
+    let step = 1200.0 / divisions as f64;
+    Tuning {
+      name: format!("{divisions}-EDO"),
+      degrees: (0..divisions).map(|i| i as f64 * step).collect(),
+      period: 1200.0,
+      root_index: 60,
+      root_pitch: 60.0,
+      spelling: SpellingPolicy::default(),
+    }
+  }
+
+  /// A scale in the style of a Scala file: the cents of each degree above the root, not
+  /// including the root itself, with the last entry being the period. Rooted on middle C.
+  pub fn from_scale<S: Into<String>>(name: S, steps: &[f64]) -> Self {
+    let period = steps.last().copied().unwrap_or(1200.0);
+    let mut degrees = vec![0.0];
+    degrees.extend(steps.iter().take(steps.len().saturating_sub(1)));
+    Tuning {
+      name: name.into(),
+      degrees,
+      period,
+      root_index: 60,
+      root_pitch: 60.0,
+      spelling: SpellingPolicy::default(),
+    }
+  }
+
+  /// A just intonation scale given as frequency ratios above the root, not including the
+  /// root itself, with the last ratio being the period. Rooted on middle C.
+  pub fn from_ratios<S: Into<String>>(name: S, ratios: &[(u32, u32)]) -> Self {
+    let steps: Vec<f64> = ratios.iter().map(|(n, d)| ratio_to_cents(*n, *d)).collect();
+    Tuning::from_scale(name, &steps)
+  }
+
+  /// Parses a Scala `.scl` file (<https://www.huygens-fokker.org/scala/scl_format.html>).
+  /// Pitches with a period are in cents, others are ratios like `3/2` or whole numbers like
+  /// `2`. The description line becomes the name.
+  pub fn from_scl_str<S: AsRef<str>>(source: S) -> Result<Self, LumatoneKeymapError> {
+    let invalid = |msg: String| LumatoneKeymapError::InvalidScale(msg);
+    let mut lines = source
+      .as_ref()
+      .lines()
+      .filter(|line| !line.starts_with('!'))
+      .map(str::trim);
+    let name = lines
+      .next()
+      .ok_or_else(|| invalid("missing description".to_string()))?;
+    let count = lines
+      .next()
+      .and_then(|line| line.split_whitespace().next()?.parse::<usize>().ok())
+      .ok_or_else(|| invalid("missing number of notes".to_string()))?;
+    let steps = lines
+      .filter(|line| !line.is_empty())
+      .take(count)
+      .map(|line| {
+        let pitch = line.split_whitespace().next().unwrap_or_default();
+        parse_scl_pitch(pitch).ok_or_else(|| invalid(format!("invalid pitch {pitch:?}")))
+      })
+      .collect::<Result<Vec<f64>, _>>()?;
+    if steps.len() != count {
+      return Err(invalid(format!(
+        "expected {count} notes, found {}",
+        steps.len()
+      )));
+    }
+    Ok(Tuning::from_scale(name, &steps))
+  }
+
+  /// The number of degrees in each period.
+  pub fn len(&self) -> usize {
+    self.degrees.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.degrees.is_empty()
+  }
+
+  /// Returns the scale degree and number of periods above the root for a pitch index.
+  pub fn degree(&self, index: i32) -> (usize, i32) {
+    let len = self.len().max(1) as i32;
+    let offset = index - self.root_index;
+    (offset.rem_euclid(len) as usize, offset.div_euclid(len))
+  }
+
+  /// Returns the pitch at `index` in cents above the root.
+  pub fn cents(&self, index: i32) -> f64 {
+    let (degree, period) = self.degree(index);
+    self.degrees.get(degree).copied().unwrap_or(0.0) + period as f64 * self.period
+  }
+
+  /// Returns the pitch at `index` as a fractional 12-TET MIDI note number.
+  pub fn midi_pitch(&self, index: i32) -> f64 {
+    self.root_pitch + self.cents(index) / CENTS_PER_SEMITONE
+  }
+}
+
+/// Parses a pitch in a Scala file, in cents.
+fn parse_scl_pitch(pitch: &str) -> Option<f64> {
+  if pitch.contains('.') {
+    return pitch.parse().ok();
+  }
+  let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+  match (numerator.parse(), denominator.parse()) {
+    (Ok(n), Ok(d)) if n > 0 && d > 0 => Some(ratio_to_cents(n, d)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pitches() {
+    let edo = Tuning::equal(31);
+    assert_eq!(edo.len(), 31);
+    assert_eq!(edo.degree(60 + 31 + 2), (2, 1));
+    assert_eq!(edo.degree(59), (30, -1));
+    assert!((edo.midi_pitch(60 + 31) - 72.0).abs() < 1e-9);
+
+    let just = Tuning::from_scale("just", &[203.91, 386.31, 498.04, 701.96, 1200.0]);
+    assert_eq!(just.degrees, vec![0.0, 203.91, 386.31, 498.04, 701.96]);
+    assert!((just.cents(66) - 1403.91).abs() < 1e-9);
+
+    let scl = "! just.scl\n!\nFive-limit pentatonic\n 5\n!\n 9/8\n 5/4\n 498.04\n 3/2 fifth\n 2\n";
+    let scale = Tuning::from_scl_str(scl).unwrap();
+    assert_eq!(scale.name, "Five-limit pentatonic");
+    assert_eq!(scale.len(), 5);
+    assert!((scale.degrees[1] - 203.91).abs() < 0.01);
+    assert!((scale.period - 1200.0).abs() < 1e-9);
+    assert!(Tuning::from_scl_str("Broken\n2\n3/2\n").is_err());
+
+    let ch2 = MidiChannel::new(2).unwrap();
+    assert_eq!(pitch_index(ch2, 3), 131);
+  }
+}
@@ -0,0 +1,131 @@
+//! Compares the key colors read back from a device with the colors a [LumatoneKeyMap]
+//! expects, e.g. to catch LEDs that have desynced after a power cycle.
+//!
+//! Read the colors with `Lumatone::get_all_key_colors`, then pass them to
+//! [LumatoneKeyMap::verify_colors].
+
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use lumatone_midi::constants::{LumatoneKeyLocation, RGBColor};
+
+use super::ltn::LumatoneKeyMap;
+
+/// The default per-channel tolerance for [LumatoneKeyMap::verify_colors]. Older firmware
+/// only stores 7 bits per channel, so colors read back can be off by one.
+pub const DEFAULT_COLOR_TOLERANCE: u8 = 1;
+
+/// A key whose color on the device doesn't match the keymap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorMismatch {
+  pub location: LumatoneKeyLocation,
+  pub expected: RGBColor,
+
+  /// The color read from the device, or `None` if the device didn't report one for this key.
+  pub actual: Option<RGBColor>,
+}
+
+impl Display for ColorMismatch {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.actual {
+      Some(actual) => write!(
+        f,
+        "{}: expected {}, found {}",
+        self.location, self.expected, actual
+      ),
+      None => write!(
+        f,
+        "{}: expected {}, found nothing",
+        self.location, self.expected
+      ),
+    }
+  }
+}
+
+/// Returns true if each channel of `a` is within `tolerance` of the same channel of `b`.
+pub fn colors_match(a: &RGBColor, b: &RGBColor, tolerance: u8) -> bool {
+  let RGBColor(r1, g1, b1) = *a;
+  let RGBColor(r2, g2, b2) = *b;
+  r1.abs_diff(r2) <= tolerance && g1.abs_diff(g2) <= tolerance && b1.abs_diff(b2) <= tolerance
+}
+
+impl LumatoneKeyMap {
+  /// Compares the keymap's colors with `device_colors`, and returns the keys that don't
+  /// match within `tolerance`, in [LumatoneKeyLocation::all] order. Keys that aren't
+  /// defined in the keymap are skipped.
+  pub fn verify_colors(
+    &self,
+    device_colors: &HashMap<LumatoneKeyLocation, RGBColor>,
+    tolerance: u8,
+  ) -> Vec<ColorMismatch> {
+    let mut mismatches = vec![];
+    for location in LumatoneKeyLocation::all() {
+      let expected = match self.get_key(location) {
+        Some(def) => def.color,
+        None => continue,
+      };
+      let actual = device_colors.get(&location).copied();
+      let matches = actual.map_or(false, |c| colors_match(&expected, &c, tolerance));
+      if !matches {
+        mismatches.push(ColorMismatch {
+          location,
+          expected,
+          actual,
+        });
+      }
+    }
+    mismatches
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ltn::KeyDefinition;
+  use lumatone_midi::constants::{key_loc_unchecked, LumatoneKeyFunction, MidiChannel};
+
+  fn key(color: RGBColor) -> KeyDefinition {
+    KeyDefinition {
+      function: LumatoneKeyFunction::NoteOnOff {
+        channel: MidiChannel::default(),
+        note_num: 60,
+      },
+      color,
+    }
+  }
+
+  #[test]
+  fn test_verify_colors() {
+    let mut keymap = LumatoneKeyMap::new();
+    keymap
+      .set_key(key_loc_unchecked(1, 0), key(RGBColor(0xff, 0x00, 0x80)))
+      .set_key(key_loc_unchecked(1, 1), key(RGBColor(0x10, 0x20, 0x30)))
+      .set_key(key_loc_unchecked(2, 5), key(RGBColor::blue()));
+
+    let mut device_colors = HashMap::new();
+    // off by one in each channel, which is within tolerance
+    device_colors.insert(key_loc_unchecked(1, 0), RGBColor(0xfe, 0x01, 0x81));
+    device_colors.insert(key_loc_unchecked(1, 1), RGBColor(0x10, 0x40, 0x30));
+    // a key that's not in the keymap is ignored
+    device_colors.insert(key_loc_unchecked(3, 3), RGBColor::red());
+
+    let mismatches = keymap.verify_colors(&device_colors, DEFAULT_COLOR_TOLERANCE);
+    assert_eq!(
+      mismatches,
+      vec![
+        ColorMismatch {
+          location: key_loc_unchecked(1, 1),
+          expected: RGBColor(0x10, 0x20, 0x30),
+          actual: Some(RGBColor(0x10, 0x40, 0x30)),
+        },
+        ColorMismatch {
+          location: key_loc_unchecked(2, 5),
+          expected: RGBColor::blue(),
+          actual: None,
+        },
+      ]
+    );
+
+    assert!(keymap.verify_colors(&device_colors, 0x20).len() == 1);
+  }
+}
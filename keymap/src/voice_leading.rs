@@ -0,0 +1,200 @@
+//! Suggests how to move from one scale to another on an [isomorphic layout](crate::layout),
+//! e.g. for a modulation, so the hand moves as little as possible.
+//!
+//! The hand is assumed to rest around the layout's anchor, playing each degree of the old
+//! scale on the nearest key that has it. A [Transition] either keeps the layout and only
+//! recolors it for the new scale, or moves the anchor to a nearby key, which moves every
+//! pitch with it. Each is scored by:
+//!
+//! - common-tone movement: how far each degree in both scales moves, from where it was
+//!   played to the nearest key that still plays the same pitch.
+//! - new-tone movement: how far each degree only in the new scale, in any octave, is from
+//!   the nearest old scale position, which is the step a voice takes to reach it.
+//!
+//! Distances are in keys (see [DistanceMatrix::steps]). Recoloring never moves common tones,
+//! but can leave the new tones out of reach, so a shifted anchor can rank higher.
+
+use std::collections::HashMap;
+
+use lumatone_midi::constants::{LumatoneKeyFunction, LumatoneKeyLocation, RGBColor};
+
+use super::{
+  geometry::DistanceMatrix,
+  layout::{key_function, IsomorphicLayout},
+  ltn::LumatoneKeyMap,
+  selection::KeySelection,
+  tuning::Tuning,
+};
+
+/// One way to move from the old scale to the new one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+  /// The layout to play the new scale on.
+  pub layout: IsomorphicLayout,
+
+  /// How far the anchor moved, in keys. 0 means the layout is only recolored.
+  pub anchor_shift: i32,
+
+  pub common_tone_movement: i32,
+  pub new_tone_movement: i32,
+}
+
+impl Transition {
+  /// The total movement, which transitions are ranked by.
+  pub fn cost(&self) -> i32 {
+    self.common_tone_movement + self.new_tone_movement
+  }
+
+  pub fn is_recolor(&self) -> bool {
+    self.anchor_shift == 0
+  }
+
+  /// Generates the keymap for the new scale, with its degrees in `color` and the other
+  /// keys dark.
+  pub fn generate(&self, tuning: &Tuning, scale: &[usize], color: RGBColor) -> LumatoneKeyMap {
+    let colors: Vec<RGBColor> = (0..tuning.len())
+      .map(|degree| match scale.contains(&degree) {
+        true => color,
+        false => RGBColor(0, 0, 0),
+      })
+      .collect();
+    self.layout.generate(tuning, &colors)
+  }
+}
+
+/// Returns the transitions from the `from` scale to the `to` scale (both given as degrees of
+/// `tuning`) on `layout`, best first. Candidates are recoloring the layout as it is, and
+/// moving the anchor to each key up to `radius` keys away.
+pub fn suggest_transitions(
+  layout: &IsomorphicLayout,
+  tuning: &Tuning,
+  from: &[usize],
+  to: &[usize],
+  radius: i32,
+) -> Vec<Transition> {
+  let distances = DistanceMatrix::get();
+  let home = layout.effective_anchor();
+  let old_keys = keys_by_pitch(layout);
+  // where the hand plays each degree of the old scale, and the pitch index it plays there
+  let old_positions: HashMap<usize, (LumatoneKeyLocation, i32)> = from
+    .iter()
+    .filter_map(|degree| {
+      let (index, keys) = old_keys
+        .iter()
+        .filter(|(index, _)| tuning.degree(**index).0 == *degree)
+        .min_by_key(|(_, keys)| nearest_distance(distances, keys, home))?;
+      Some((*degree, (nearest(distances, keys, home)?, *index)))
+    })
+    .collect();
+
+  let mut transitions: Vec<Transition> = KeySelection::disc(layout.anchor, radius)
+    .iter()
+    .filter_map(|anchor| {
+      let candidate = IsomorphicLayout {
+        anchor: *anchor,
+        ..layout.clone()
+      };
+      let new_keys = keys_by_pitch(&candidate);
+      let mut common_tone_movement = 0;
+      let mut new_tone_movement = 0;
+      for degree in to {
+        match old_positions.get(degree) {
+          // a common tone keeps its pitch
+          Some((old, index)) => {
+            common_tone_movement += nearest_distance(distances, new_keys.get(index)?, *old)
+          }
+          // a new tone can be in any octave
+          None => {
+            new_tone_movement += new_keys
+              .iter()
+              .filter(|(index, _)| tuning.degree(**index).0 == *degree)
+              .flat_map(|(_, keys)| {
+                old_positions
+                  .values()
+                  .map(|(old, _)| nearest_distance(distances, keys, *old))
+              })
+              .min()?
+          }
+        }
+      }
+      Some(Transition {
+        anchor_shift: distances.steps(home, candidate.effective_anchor())?,
+        layout: candidate,
+        common_tone_movement,
+        new_tone_movement,
+      })
+    })
+    .collect();
+  transitions.sort_by_key(|t| (t.cost(), t.anchor_shift));
+  transitions
+}
+
+/// The keys that play each pitch index on `layout`, leaving out keys whose pitch index
+/// doesn't fit on a MIDI channel.
+fn keys_by_pitch(layout: &IsomorphicLayout) -> HashMap<i32, Vec<LumatoneKeyLocation>> {
+  let mut keys: HashMap<i32, Vec<LumatoneKeyLocation>> = HashMap::new();
+  for location in LumatoneKeyLocation::all() {
+    match layout.pitch_index(location) {
+      Some(index) if key_function(index) != LumatoneKeyFunction::Disabled => {
+        keys.entry(index).or_default().push(location)
+      }
+      _ => {}
+    }
+  }
+  keys
+}
+
+fn nearest(
+  distances: &DistanceMatrix,
+  keys: &[LumatoneKeyLocation],
+  to: LumatoneKeyLocation,
+) -> Option<LumatoneKeyLocation> {
+  keys
+    .iter()
+    .min_by_key(|k| distances.steps(**k, to).unwrap_or(i32::MAX))
+    .copied()
+}
+
+fn nearest_distance(
+  distances: &DistanceMatrix,
+  keys: &[LumatoneKeyLocation],
+  to: LumatoneKeyLocation,
+) -> i32 {
+  keys
+    .iter()
+    .filter_map(|k| distances.steps(*k, to))
+    .min()
+    .unwrap_or(i32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::key_at;
+
+  #[test]
+  fn test_modulation_keeps_common_tones() {
+    // Wicki-Hayden in 12-TET, C major to G major
+    let layout = IsomorphicLayout::new(2, -5, key_at(3, 27).unwrap(), 60);
+    let tuning = Tuning::equal(12);
+    let c_major = [0, 2, 4, 5, 7, 9, 11];
+    let g_major = [7, 9, 11, 0, 2, 4, 6];
+
+    let transitions = suggest_transitions(&layout, &tuning, &c_major, &g_major, 2);
+    assert_eq!(transitions.len(), 19);
+    assert!(transitions.windows(2).all(|w| w[0].cost() <= w[1].cost()));
+
+    // recoloring leaves the six common tones where they are, and F# is next to an old tone
+    let best = &transitions[0];
+    assert!(best.is_recolor());
+    assert_eq!(best.common_tone_movement, 0);
+    assert_eq!(best.new_tone_movement, 1);
+
+    // shifting the anchor moves the common tones too
+    assert!(transitions[1..].iter().all(|t| t.common_tone_movement > 0));
+
+    let keymap = best.generate(&tuning, &g_major, RGBColor::green());
+    let anchor = keymap.get_key(layout.anchor).unwrap();
+    assert_eq!(anchor.color, RGBColor::green());
+  }
+}
@@ -0,0 +1,174 @@
+//! Host-side processing for polyphonic aftertouch, for synths that don't cope well with the
+//! raw pressure values from the keys.
+//!
+//! An [AftertouchProcessor] applies three stages to each key's pressure, in order:
+//!
+//! 1. a dead zone, so light resting pressure reads as zero,
+//! 2. smoothing, which averages out jitter between successive values,
+//! 3. a [ResponseCurve], which reshapes the response.
+//!
+//! Values that come out the same as the last value sent for that key are dropped, so
+//! smoothing doesn't flood the output with duplicates.
+//!
+//! The [note proxy](crate::proxy) runs incoming aftertouch through a processor when one
+//! is configured. It can also convert between polyphonic aftertouch and channel pressure
+//! (see [PressureConversion]), for synths that only respond to one of the two.
+
+use std::collections::HashMap;
+
+use super::{constants::MidiChannel, curve::ResponseCurve};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AftertouchConfig {
+  /// Pressure at or below this value is sent as zero. The remaining range is stretched to
+  /// cover 0 ..= 127.
+  pub dead_zone: u8,
+
+  /// How much of the previous value to keep when a new one arrives, from 0.0 (no
+  /// smoothing) up to but not including 1.0.
+  pub smoothing: f32,
+
+  pub curve: ResponseCurve,
+}
+
+impl Default for AftertouchConfig {
+  fn default() -> Self {
+    AftertouchConfig {
+      dead_zone: 0,
+      smoothing: 0.0,
+      curve: ResponseCurve::Linear,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureConversion {
+  /// Polyphonic aftertouch is sent as channel pressure, using the highest pressure of the
+  /// held keys.
+  PolyToChannelMax,
+
+  /// Polyphonic aftertouch is sent as channel pressure, using the mean pressure of the
+  /// held keys.
+  PolyToChannelMean,
+
+  /// Channel pressure is sent as polyphonic aftertouch to each held key.
+  ChannelToPoly,
+}
+
+impl PressureConversion {
+  /// Combines the pressures of the held keys into a single channel pressure value. Returns
+  /// zero if no keys are held, or for [PressureConversion::ChannelToPoly].
+  pub fn combine(&self, pressures: &[u8]) -> u8 {
+    if pressures.is_empty() {
+      return 0;
+    }
+    match self {
+      PressureConversion::PolyToChannelMax => pressures.iter().copied().max().unwrap_or(0),
+      PressureConversion::PolyToChannelMean => {
+        let sum: u32 = pressures.iter().map(|p| *p as u32).sum();
+        (sum as f32 / pressures.len() as f32).round() as u8
+      }
+      PressureConversion::ChannelToPoly => 0,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KeyState {
+  smoothed: f32,
+  last_sent: u8,
+}
+
+#[derive(Debug)]
+pub struct AftertouchProcessor {
+  config: AftertouchConfig,
+  keys: HashMap<(MidiChannel, u8), KeyState>,
+}
+
+impl AftertouchProcessor {
+  pub fn new(config: AftertouchConfig) -> Self {
+    AftertouchProcessor {
+      config,
+      keys: HashMap::new(),
+    }
+  }
+
+  pub fn config(&self) -> &AftertouchConfig {
+    &self.config
+  }
+
+  /// Processes a pressure value for a key, returning the value to send, or `None` if it's
+  /// the same as the last value sent.
+  ///
+  /// Pressure inside the dead zone is sent as zero straight away, without smoothing, so
+  /// releasing a key never leaves pressure hanging.
+  pub fn process(&mut self, channel: MidiChannel, note: u8, pressure: u8) -> Option<u8> {
+    let dead_zone = self.config.dead_zone.min(126);
+    let pressure = pressure.min(127);
+    let state = self.keys.entry((channel, note)).or_insert(KeyState {
+      smoothed: 0.0,
+      last_sent: 0,
+    });
+
+    let out = if pressure <= dead_zone {
+      state.smoothed = 0.0;
+      0
+    } else {
+      let scaled = (pressure - dead_zone) as f32 * 127.0 / (127 - dead_zone) as f32;
+      let keep = self.config.smoothing.clamp(0.0, 0.99);
+      state.smoothed = state.smoothed * keep + scaled * (1.0 - keep);
+      self.config.curve.apply(state.smoothed.round() as u8)
+    };
+
+    if out == state.last_sent {
+      return None;
+    }
+    state.last_sent = out;
+    Some(out)
+  }
+
+  /// Forgets the state for a key, e.g. after its note-off.
+  pub fn release(&mut self, channel: MidiChannel, note: u8) {
+    self.keys.remove(&(channel, note));
+  }
+
+  pub fn reset(&mut self) {
+    self.keys.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_dead_zone_and_smoothing() {
+    let ch = MidiChannel::default();
+    let mut p = AftertouchProcessor::new(AftertouchConfig {
+      dead_zone: 7,
+      smoothing: 0.5,
+      curve: ResponseCurve::Linear,
+    });
+
+    assert_eq!(p.process(ch, 60, 5), None);
+    assert_eq!(p.process(ch, 60, 127), Some(64));
+    assert_eq!(p.process(ch, 60, 127), Some(95));
+    // other keys are smoothed separately
+    assert_eq!(p.process(ch, 61, 127), Some(64));
+    assert_eq!(p.process(ch, 60, 0), Some(0));
+    assert_eq!(p.process(ch, 60, 0), None);
+  }
+
+  #[test]
+  fn test_combine_pressures() {
+    assert_eq!(
+      PressureConversion::PolyToChannelMax.combine(&[10, 90, 40]),
+      90
+    );
+    assert_eq!(
+      PressureConversion::PolyToChannelMean.combine(&[10, 90, 41]),
+      47
+    );
+    assert_eq!(PressureConversion::PolyToChannelMean.combine(&[]), 0);
+  }
+}
@@ -0,0 +1,140 @@
+//! A global brightness for the key and macro button LEDs, e.g. to dim the board in a dark
+//! venue without editing the keymap.
+//!
+//! A [Brightness] is shared by all clones of a [MidiDriver](crate::driver::MidiDriver),
+//! which scales the color of every key and macro button color command by it as the command
+//! is queued. Commands are recorded unscaled (see [crate::mirror] and [crate::recorder]), so
+//! re-sending them after a change picks up the new brightness.
+//!
+//! Changing the brightness doesn't touch colors that were already sent;
+//! [Lumatone::set_brightness](crate::controller::Lumatone::set_brightness) re-sends them.
+//! [ramp] gives the levels for fading smoothly to a new brightness instead of jumping.
+
+use std::{
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use super::commands::Command;
+
+/// How often a brightness ramp moves to its next level. Each frame re-sends every lit key,
+/// but a full keymap takes longer than a frame to get through, so keys the driver hasn't
+/// sent yet just pick up the newer level instead of falling behind (see
+/// [MidiDriver::send_and_forget_coalesced](crate::driver::MidiDriver::send_and_forget_coalesced)).
+pub const RAMP_FRAME: Duration = Duration::from_millis(250);
+
+/// A brightness between 0 (off) and 1 (the colors as sent), shared by its clones.
+#[derive(Debug, Clone)]
+pub struct Brightness(Arc<AtomicU32>);
+
+impl Default for Brightness {
+  fn default() -> Self {
+    Brightness::new(1.0)
+  }
+}
+
+impl Brightness {
+  pub fn new(level: f32) -> Self {
+    Brightness(Arc::new(AtomicU32::new(clamp(level).to_bits())))
+  }
+
+  pub fn get(&self) -> f32 {
+    f32::from_bits(self.0.load(Ordering::Relaxed))
+  }
+
+  /// Sets the brightness for all clones, clamped between 0 and 1.
+  pub fn set(&self, level: f32) {
+    self.0.store(clamp(level).to_bits(), Ordering::Relaxed);
+  }
+
+  /// Scales the color of a key or macro button color command. Other commands are unchanged.
+  pub fn apply(&self, command: Command) -> Command {
+    let level = self.get();
+    if level >= 1.0 {
+      return command;
+    }
+    match command {
+      Command::SetKeyColor { location, color } => Command::SetKeyColor {
+        location,
+        color: color.scaled(level),
+      },
+      Command::SetMacroButtonActiveColor(color) => {
+        Command::SetMacroButtonActiveColor(color.scaled(level))
+      }
+      Command::SetMacroButtonInactiveColor(color) => {
+        Command::SetMacroButtonInactiveColor(color.scaled(level))
+      }
+      other => other,
+    }
+  }
+}
+
+/// Returns whether `command` sets an LED color, and so is affected by the brightness.
+pub fn is_color_command(command: &Command) -> bool {
+  matches!(
+    command,
+    Command::SetKeyColor { .. }
+      | Command::SetMacroButtonActiveColor(_)
+      | Command::SetMacroButtonInactiveColor(_)
+  )
+}
+
+/// The levels to step through to fade from `from` to `to` in `steps` frames, ending at
+/// `to`. Eased at both ends, so the fade starts and stops gently.
+pub fn ramp(from: f32, to: f32, steps: usize) -> Vec<f32> {
+  let (from, to) = (clamp(from), clamp(to));
+  (1..=steps.max(1))
+    .map(|i| {
+      let t = i as f32 / steps.max(1) as f32;
+      let eased = t * t * (3.0 - 2.0 * t);
+      from * (1.0 - eased) + to * eased
+    })
+    .collect()
+}
+
+fn clamp(level: f32) -> f32 {
+  match level.is_nan() {
+    true => 1.0,
+    false => level.clamp(0.0, 1.0),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    commands::{set_key_color, Command},
+    constants::{key_loc_unchecked, RGBColor},
+  };
+
+  #[test]
+  fn test_brightness_scales_colors() {
+    let brightness = Brightness::default();
+    let shared = brightness.clone();
+    let command = set_key_color(key_loc_unchecked(1, 0), RGBColor(200, 100, 0));
+    assert_eq!(brightness.apply(command.clone()), command);
+
+    shared.set(0.5);
+    assert_eq!(brightness.get(), 0.5);
+    assert_eq!(
+      brightness.apply(command),
+      set_key_color(key_loc_unchecked(1, 0), RGBColor(100, 50, 0))
+    );
+    assert_eq!(
+      brightness.apply(Command::SetMacroButtonActiveColor(RGBColor(255, 255, 255))),
+      Command::SetMacroButtonActiveColor(RGBColor(128, 128, 128))
+    );
+    assert_eq!(brightness.apply(Command::Ping(1)), Command::Ping(1));
+
+    shared.set(3.0);
+    assert_eq!(brightness.get(), 1.0);
+
+    let levels = ramp(1.0, 0.2, 4);
+    assert_eq!(levels.len(), 4);
+    assert_eq!(*levels.last().unwrap(), 0.2);
+    assert!(levels.windows(2).all(|w| w[0] > w[1]));
+  }
+}
@@ -0,0 +1,268 @@
+//! Walks the user through one of the device's calibration routines.
+//!
+//! The firmware has four routines, each started by a command (see
+//! [start_key_calibration](crate::commands::start_key_calibration) and friends). The key and
+//! aftertouch routines end on the device: key calibration when both macro buttons of each
+//! octave have been pressed, and aftertouch calibration on its own. The wheel and pedal
+//! routines keep running until the host stops them, which also saves the new calibration.
+//!
+//! A [CalibrationSession] sends the right commands for its [CalibrationKind] and tracks
+//! where the user is, with a [prompt](CalibrationSession::prompt) to show at each step:
+//!
+//! ```ignore
+//! let mut session = CalibrationSession::new(driver, CalibrationKind::PitchModWheel);
+//! session.start().await?;
+//! println!("{}", session.prompt());
+//! // ... wait for the user ...
+//! session.finish().await?;
+//! ```
+//!
+//! While the wheel or pedal routine runs, the device reports the range it has seen so far
+//! every 100ms. Use [CalibrationSession::subscribe_status] (or
+//! [MidiDriver::subscribe_calibration]) to receive these as [CalibrationStatus] values, e.g.
+//! to draw the live range while the user moves the wheels.
+
+use std::fmt::Display;
+
+use super::{
+  commands::{
+    start_aftertouch_calibration, start_expression_pedal_calibration, start_key_calibration,
+    start_pitch_mod_wheel_calibration, stop_expression_pedal_calibration,
+    stop_pitch_mod_wheel_calibration, Command,
+  },
+  driver::MidiDriver,
+  error::LumatoneMidiError,
+  responses::{ExpressionCalibration, Response, WheelCalibration},
+  sysex::message_command_id,
+};
+
+use error_stack::{bail, Result};
+use log::info;
+use tokio::sync::broadcast;
+
+/// How many [CalibrationStatus] readings a slow subscriber can fall behind before missing some.
+pub const CALIBRATION_STATUS_BUFFER_SIZE: usize = 16;
+
+/// A live reading sent by the device while the wheel or pedal routine runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationStatus {
+  /// The pitch wheel's center and range, and the mod wheel's range, as 12-bit ADC values.
+  Wheels(WheelCalibration),
+
+  /// The expression pedal's range as 12-bit ADC values.
+  ExpressionPedal(ExpressionCalibration),
+}
+
+impl CalibrationStatus {
+  /// Decodes a status message. Returns `None` for any other message, including the ACKs to
+  /// the commands that start and stop calibration.
+  pub fn from_sysex_message(msg: &[u8]) -> Option<Self> {
+    use crate::constants::CommandId::*;
+    if !matches!(
+      message_command_id(msg),
+      Ok(CalibratePitchModWheel | CalibrateExpressionPedal)
+    ) {
+      return None;
+    }
+    match Response::from_sysex_message(msg) {
+      Ok(Response::WheelCalibrationStatus(status)) => Some(CalibrationStatus::Wheels(status)),
+      Ok(Response::ExpressionCalibrationStatus(status)) => {
+        Some(CalibrationStatus::ExpressionPedal(status))
+      }
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationKind {
+  Keys,
+  Aftertouch,
+  PitchModWheel,
+  ExpressionPedal,
+}
+
+impl CalibrationKind {
+  fn start_command(&self) -> Command {
+    match self {
+      CalibrationKind::Keys => start_key_calibration(),
+      CalibrationKind::Aftertouch => start_aftertouch_calibration(),
+      CalibrationKind::PitchModWheel => start_pitch_mod_wheel_calibration(),
+      CalibrationKind::ExpressionPedal => start_expression_pedal_calibration(),
+    }
+  }
+
+  /// The command that stops the routine, for routines that the host has to stop.
+  fn stop_command(&self) -> Option<Command> {
+    match self {
+      CalibrationKind::Keys | CalibrationKind::Aftertouch => None,
+      CalibrationKind::PitchModWheel => Some(stop_pitch_mod_wheel_calibration()),
+      CalibrationKind::ExpressionPedal => Some(stop_expression_pedal_calibration()),
+    }
+  }
+}
+
+impl Display for CalibrationKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CalibrationKind::Keys => write!(f, "key calibration"),
+      CalibrationKind::Aftertouch => write!(f, "aftertouch calibration"),
+      CalibrationKind::PitchModWheel => write!(f, "pitch and mod wheel calibration"),
+      CalibrationKind::ExpressionPedal => write!(f, "expression pedal calibration"),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationState {
+  NotStarted,
+  Running,
+  Finished,
+  Cancelled,
+}
+
+pub struct CalibrationSession {
+  driver: MidiDriver,
+  kind: CalibrationKind,
+  state: CalibrationState,
+}
+
+impl CalibrationSession {
+  pub fn new(driver: MidiDriver, kind: CalibrationKind) -> Self {
+    CalibrationSession {
+      driver,
+      kind,
+      state: CalibrationState::NotStarted,
+    }
+  }
+
+  pub fn kind(&self) -> CalibrationKind {
+    self.kind
+  }
+
+  pub fn state(&self) -> CalibrationState {
+    self.state
+  }
+
+  /// What the user should do next.
+  pub fn prompt(&self) -> &'static str {
+    use CalibrationKind::*;
+    use CalibrationState::*;
+    match (self.state, self.kind) {
+      (NotStarted, Keys) => "Release all keys, then start key calibration.",
+      (NotStarted, Aftertouch) => "Release all keys, then start aftertouch calibration.",
+      (NotStarted, PitchModWheel) => "Leave the wheels at rest, then start calibration.",
+      (NotStarted, ExpressionPedal) => "Connect the expression pedal, then start calibration.",
+      (Running, Keys) => {
+        "Press every key all the way down, then press both macro buttons on each octave."
+      }
+      (Running, Aftertouch) => "Wait for the device to finish, then continue.",
+      (Running, PitchModWheel) => {
+        "Move both wheels through their full range a few times, then finish."
+      }
+      (Running, ExpressionPedal) => {
+        "Move the pedal through its full range a few times, then finish."
+      }
+      (Finished, _) => "Calibration finished.",
+      (Cancelled, _) => "Calibration cancelled.",
+    }
+  }
+
+  /// Returns a receiver for the device's live readings. Only the wheel and pedal routines
+  /// send them. Subscribe before [start](Self::start) to get the first reading.
+  pub fn subscribe_status(
+    &self,
+  ) -> Result<broadcast::Receiver<CalibrationStatus>, LumatoneMidiError> {
+    self.driver.subscribe_calibration()
+  }
+
+  /// Starts the routine on the device.
+  pub async fn start(&mut self) -> Result<(), LumatoneMidiError> {
+    self.expect_state(CalibrationState::NotStarted, "start")?;
+    self.driver.send(self.kind.start_command()).await?;
+    info!("started {}", self.kind);
+    self.state = CalibrationState::Running;
+    Ok(())
+  }
+
+  /// Ends the routine, saving the new calibration. For the key and aftertouch routines,
+  /// which end on the device, this records that the user has finished.
+  pub async fn finish(&mut self) -> Result<(), LumatoneMidiError> {
+    self.stop(CalibrationState::Finished).await
+  }
+
+  /// Gives up on the routine. The wheel and pedal routines are stopped, but the key and
+  /// aftertouch routines can only be ended on the device.
+  pub async fn cancel(&mut self) -> Result<(), LumatoneMidiError> {
+    self.stop(CalibrationState::Cancelled).await
+  }
+
+  async fn stop(&mut self, next: CalibrationState) -> Result<(), LumatoneMidiError> {
+    self.expect_state(CalibrationState::Running, "stop")?;
+    if let Some(command) = self.kind.stop_command() {
+      self.driver.send(command).await?;
+    }
+    info!("{} {:?}", self.kind, next);
+    self.state = next;
+    Ok(())
+  }
+
+  fn expect_state(
+    &self,
+    expected: CalibrationState,
+    action: &str,
+  ) -> Result<(), LumatoneMidiError> {
+    if self.state != expected {
+      bail!(LumatoneMidiError::InvalidStateTransition(format!(
+        "can't {action} {} in state {:?}",
+        self.kind, self.state
+      )));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{shutdown::CancellationToken, testing::FakeDevice};
+
+  fn start_driver() -> (MidiDriver, CancellationToken) {
+    let shutdown = CancellationToken::new();
+    let (driver, driver_future) = MidiDriver::with_transport(FakeDevice::new(), shutdown.clone());
+    tokio::spawn(driver_future);
+    (driver, shutdown)
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_session_steps() {
+    let (driver, shutdown) = start_driver();
+    let mut session = CalibrationSession::new(driver, CalibrationKind::PitchModWheel);
+    let mut status = session.subscribe_status().unwrap();
+    assert!(session.finish().await.is_err());
+
+    session.start().await.unwrap();
+    assert_eq!(session.state(), CalibrationState::Running);
+    assert!(matches!(
+      status.recv().await.unwrap(),
+      CalibrationStatus::Wheels(_)
+    ));
+    assert!(session.start().await.is_err());
+
+    session.finish().await.unwrap();
+    assert_eq!(session.state(), CalibrationState::Finished);
+    assert!(session.cancel().await.is_err());
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_cancel_key_calibration() {
+    let (driver, shutdown) = start_driver();
+    let mut session = CalibrationSession::new(driver, CalibrationKind::Keys);
+    session.start().await.unwrap();
+    session.cancel().await.unwrap();
+    assert_eq!(session.state(), CalibrationState::Cancelled);
+    assert_eq!(session.prompt(), "Calibration cancelled.");
+    shutdown.cancel();
+  }
+}
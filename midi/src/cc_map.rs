@@ -0,0 +1,208 @@
+//! Remapping and filtering for control change messages, so the board's wheels, pedals and
+//! faders can be adapted to whatever a synth expects.
+//!
+//! A [CcMap] is a list of [CcRule]s. Each rule matches an incoming controller (optionally on
+//! one channel) and either blocks it or maps it with a [CcMapping], which can change the
+//! controller number, rescale or invert the value, and drop small changes from a noisy
+//! controller. When several controllers are mapped to the same outgoing controller, the
+//! highest of their values is sent, which merges e.g. an expression pedal and a fader into
+//! one expression source.
+//!
+//! Controllers that don't match any rule are passed through unchanged.
+
+use std::collections::HashMap;
+
+use super::constants::MidiChannel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcMapping {
+  /// The controller number to send.
+  pub controller: u8,
+
+  /// The values sent for incoming values of 0 and 127. The range is inverted if `min` is
+  /// greater than `max`.
+  pub min: u8,
+  pub max: u8,
+
+  /// Incoming values that differ from the last one by less than this are dropped, except
+  /// for 0 and 127, so the controller always reaches its end stops.
+  pub min_change: u8,
+}
+
+impl CcMapping {
+  /// Sends to `controller`, with the value unchanged.
+  pub fn to(controller: u8) -> Self {
+    CcMapping {
+      controller,
+      min: 0,
+      max: 127,
+      min_change: 0,
+    }
+  }
+
+  pub fn inverted(self) -> Self {
+    CcMapping {
+      min: self.max,
+      max: self.min,
+      ..self
+    }
+  }
+
+  fn scale(&self, value: u8) -> u8 {
+    let (min, max) = (self.min.min(127) as f32, self.max.min(127) as f32);
+    (min + (max - min) * value.min(127) as f32 / 127.0).round() as u8
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CcRoute {
+  /// The controller is dropped.
+  Block,
+
+  Map(CcMapping),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CcRule {
+  /// If `None`, the rule applies on every channel.
+  pub channel: Option<MidiChannel>,
+  pub controller: u8,
+  pub route: CcRoute,
+}
+
+impl CcRule {
+  pub fn block(controller: u8) -> Self {
+    CcRule {
+      channel: None,
+      controller,
+      route: CcRoute::Block,
+    }
+  }
+
+  pub fn map(controller: u8, mapping: CcMapping) -> Self {
+    CcRule {
+      channel: None,
+      controller,
+      route: CcRoute::Map(mapping),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SourceState {
+  /// The last incoming value that wasn't dropped as noise.
+  raw: u8,
+  mapped: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CcMap {
+  rules: Vec<CcRule>,
+
+  /// Keyed by incoming channel and controller.
+  sources: HashMap<(MidiChannel, u8), SourceState>,
+}
+
+impl CcMap {
+  pub fn new(rules: Vec<CcRule>) -> Self {
+    CcMap {
+      rules,
+      sources: HashMap::new(),
+    }
+  }
+
+  pub fn rules(&self) -> &[CcRule] {
+    &self.rules
+  }
+
+  /// Processes an incoming controller value, returning the controller number and value to
+  /// send, or `None` if the message should be dropped. Uses the first rule that matches.
+  pub fn process(&mut self, channel: MidiChannel, controller: u8, value: u8) -> Option<(u8, u8)> {
+    let rule = match self
+      .rules
+      .iter()
+      .find(|r| r.controller == controller && r.channel.is_none_or(|c| c == channel))
+    {
+      Some(r) => *r,
+      None => return Some((controller, value)),
+    };
+    let mapping = match rule.route {
+      CcRoute::Block => return None,
+      CcRoute::Map(m) => m,
+    };
+
+    let key = (channel, controller);
+    if let Some(state) = self.sources.get(&key) {
+      let end_stop = value == 0 || value >= 127;
+      if !end_stop && mapping.min_change > 0 && state.raw.abs_diff(value) < mapping.min_change {
+        return None;
+      }
+    }
+    self.sources.insert(
+      key,
+      SourceState {
+        raw: value,
+        mapped: mapping.scale(value),
+      },
+    );
+
+    // Merge every source on this channel that's mapped to the same controller.
+    let merged = self
+      .rules
+      .iter()
+      .filter(|r| matches!(r.route, CcRoute::Map(m) if m.controller == mapping.controller))
+      .filter_map(|r| self.sources.get(&(channel, r.controller)))
+      .map(|s| s.mapped)
+      .max()
+      .unwrap_or(0);
+    Some((mapping.controller, merged))
+  }
+
+  /// Forgets the last value of every controller.
+  pub fn reset(&mut self) {
+    self.sources.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cc_map() {
+    let ch = MidiChannel::default();
+    let mut map = CcMap::new(vec![
+      CcRule::block(3),
+      CcRule::map(1, CcMapping::to(74).inverted()),
+      CcRule::map(
+        4,
+        CcMapping {
+          min_change: 4,
+          ..CcMapping::to(11)
+        },
+      ),
+      CcRule::map(
+        7,
+        CcMapping {
+          max: 63,
+          ..CcMapping::to(11)
+        },
+      ),
+    ]);
+
+    assert_eq!(map.process(ch, 2, 10), Some((2, 10)));
+    assert_eq!(map.process(ch, 3, 10), None);
+    assert_eq!(map.process(ch, 1, 127), Some((74, 0)));
+    assert_eq!(map.process(ch, 1, 0), Some((74, 127)));
+
+    assert_eq!(map.process(ch, 4, 40), Some((11, 40)));
+    assert_eq!(map.process(ch, 4, 42), None);
+    assert_eq!(map.process(ch, 4, 2), Some((11, 2)));
+    assert_eq!(map.process(ch, 4, 0), Some((11, 0)));
+    assert_eq!(map.process(ch, 4, 40), Some((11, 40)));
+    // merged with CC 4, so the higher value wins
+    assert_eq!(map.process(ch, 7, 127), Some((11, 63)));
+    assert_eq!(map.process(ch, 4, 20), Some((11, 63)));
+    assert_eq!(map.process(ch, 7, 0), Some((11, 20)));
+  }
+}
@@ -0,0 +1,199 @@
+//! How much the driver buffers, and what happens when a consumer falls behind.
+//!
+//! Every channel the driver and its connection use is bounded, so a client that stops
+//! reading can't make a long-running daemon use more and more memory. What happens when a
+//! channel fills up depends on whether its messages can be lost ([OverflowPolicy]):
+//!
+//! | Channel                  | Default | When full                                            |
+//! |--------------------------|---------|------------------------------------------------------|
+//! | [Channel::Commands]      | 128     | senders wait                                         |
+//! | [Channel::SendQueue]     | 256     | the driver stops taking commands, so senders wait    |
+//! | [Channel::Incoming]      | 32      | new sysex is dropped and counted                     |
+//! | [Channel::Events]        | 256     | each slow subscriber loses its oldest events         |
+//! | [Channel::Transitions]   | 64      | each slow subscriber loses its oldest transitions    |
+//! | [Channel::Calibration]   | 16      | each slow subscriber loses its oldest readings       |
+//! | [Channel::Connection]    | 16      | each slow subscriber loses its oldest events         |
+//!
+//! Subscribers find out they've lost messages from the `RecvError::Lagged` their receiver
+//! returns, which says how many, and report it with
+//! [MidiDriver::count_missed](crate::driver::MidiDriver::count_missed) so it's counted in
+//! [MetricsSnapshot::missed_by_subscribers](crate::metrics::MetricsSnapshot::missed_by_subscribers).
+//! The note proxy counts its own (see [ProxyHandle::events_missed](crate::proxy::ProxyHandle::events_missed)).
+//! Dropped sysex is counted in
+//! [MetricsSnapshot::dropped_incoming](crate::metrics::MetricsSnapshot::dropped_incoming);
+//! a dropped response makes its command time out.
+//!
+//! Set the capacities with [LumatoneDevice::with_capacities](crate::device::LumatoneDevice::with_capacities),
+//! or with [DriverOptions::with_capacities](crate::driver::DriverOptions::with_capacities)
+//! for a driver over another [Transport](crate::transport::Transport).
+//!
+//! The channels outside the driver are bounded too, with fixed sizes: the
+//! [resync](crate::resync) and [reconcile](crate::reconcile) event broadcasts drop their
+//! oldest events, and senders wait for the [proxy](crate::proxy)'s control channel.
+//! Each command's response channel only ever holds its one response.
+
+use std::fmt::Display;
+
+use super::{
+  calibration::CALIBRATION_STATUS_BUFFER_SIZE, driver::TRANSITION_HISTORY_SIZE,
+  suspend::CONNECTION_EVENTS_BUFFER_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// The sender waits for room. For messages that mustn't be lost, like commands.
+  Block,
+
+  /// The message that didn't fit is dropped, and counted.
+  DropNewest,
+
+  /// Each receiver that's fallen behind loses its oldest unread messages. How tokio's
+  /// broadcast channels work.
+  DropOldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+  /// Commands from [MidiDriver](crate::driver::MidiDriver) handles, on their way to the
+  /// driver loop.
+  Commands,
+
+  /// Commands the driver loop has taken in but not sent yet.
+  SendQueue,
+
+  /// Sysex messages from the MIDI input callback, waiting for the driver loop.
+  Incoming,
+
+  /// Channel voice messages, for each event subscriber.
+  Events,
+
+  /// Driver [transitions](crate::driver::Transition), for each subscriber.
+  Transitions,
+
+  /// Calibration readings, for each subscriber.
+  Calibration,
+
+  /// [ConnectionEvent](crate::suspend::ConnectionEvent)s, for each subscriber.
+  Connection,
+}
+
+impl Channel {
+  pub const ALL: [Channel; 7] = [
+    Channel::Commands,
+    Channel::SendQueue,
+    Channel::Incoming,
+    Channel::Events,
+    Channel::Transitions,
+    Channel::Calibration,
+    Channel::Connection,
+  ];
+
+  pub fn overflow_policy(&self) -> OverflowPolicy {
+    use Channel::*;
+    match self {
+      Commands | SendQueue => OverflowPolicy::Block,
+      Incoming => OverflowPolicy::DropNewest,
+      Events | Transitions | Calibration | Connection => OverflowPolicy::DropOldest,
+    }
+  }
+}
+
+impl Display for Channel {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use Channel::*;
+    let name = match self {
+      Commands => "commands",
+      SendQueue => "send queue",
+      Incoming => "incoming sysex",
+      Events => "events",
+      Transitions => "transitions",
+      Calibration => "calibration",
+      Connection => "connection events",
+    };
+    write!(f, "{name}")
+  }
+}
+
+/// How many messages each [Channel] holds. See the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelCapacities {
+  commands: usize,
+  send_queue: usize,
+  incoming: usize,
+  events: usize,
+  transitions: usize,
+  calibration: usize,
+  connection: usize,
+}
+
+impl Default for ChannelCapacities {
+  fn default() -> Self {
+    ChannelCapacities {
+      commands: 128,
+      send_queue: 256,
+      incoming: 32,
+      events: 256,
+      transitions: TRANSITION_HISTORY_SIZE,
+      calibration: CALIBRATION_STATUS_BUFFER_SIZE,
+      connection: CONNECTION_EVENTS_BUFFER_SIZE,
+    }
+  }
+}
+
+impl ChannelCapacities {
+  pub fn get(&self, channel: Channel) -> usize {
+    use Channel::*;
+    match channel {
+      Commands => self.commands,
+      SendQueue => self.send_queue,
+      Incoming => self.incoming,
+      Events => self.events,
+      Transitions => self.transitions,
+      Calibration => self.calibration,
+      Connection => self.connection,
+    }
+  }
+
+  /// Sets `channel`'s capacity. Every channel holds at least one message.
+  pub fn with(mut self, channel: Channel, capacity: usize) -> Self {
+    use Channel::*;
+    let capacity = capacity.max(1);
+    match channel {
+      Commands => self.commands = capacity,
+      SendQueue => self.send_queue = capacity,
+      Incoming => self.incoming = capacity,
+      Events => self.events = capacity,
+      Transitions => self.transitions = capacity,
+      Calibration => self.calibration = capacity,
+      Connection => self.connection = capacity,
+    }
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_capacities() {
+    let capacities = ChannelCapacities::default();
+    assert_eq!(capacities.get(Channel::Commands), 128);
+    assert_eq!(capacities.get(Channel::Incoming), 32);
+
+    let capacities = capacities
+      .with(Channel::Events, 1024)
+      .with(Channel::Incoming, 0);
+    assert_eq!(capacities.get(Channel::Events), 1024);
+    assert_eq!(capacities.get(Channel::Incoming), 1);
+    assert_eq!(capacities.get(Channel::SendQueue), 256);
+
+    assert_eq!(
+      Channel::Incoming.overflow_policy(),
+      OverflowPolicy::DropNewest
+    );
+    assert!(Channel::ALL
+      .iter()
+      .all(|c| capacities.get(*c) > 0 && !c.to_string().is_empty()));
+  }
+}
@@ -0,0 +1,227 @@
+//! The time source for the driver's timeouts and for animations.
+//!
+//! The driver waits 30 seconds for a response before giving up, 3 seconds before retrying
+//! a command the device was busy for, and a short delay between commands that don't expect
+//! a response. It reads all of these, and the deadlines of queued commands, from a [Clock].
+//! By default that's the [TokioClock], which also follows tokio's paused time in tests
+//! (`#[tokio::test(start_paused = true)]`).
+//!
+//! A [MockClock] only moves when it's [advanced](MockClock::advance), so a test can step
+//! through timeouts one at a time, regardless of what else is running on the runtime:
+//!
+//! ```ignore
+//! let clock = MockClock::new();
+//! let (driver, driver_future) = MidiDriver::with_clock(transport, shutdown, clock.clone());
+//! tokio::spawn(driver_future);
+//! let response = tokio::spawn(async move { driver.send(ping(1)).await });
+//! clock.wait_for_sleepers(1).await;
+//! clock.advance(Duration::from_secs(30));
+//! ```
+//!
+//! Code that animates the board, like brightness ramps and scene crossfades, should use
+//! the driver's [clock](crate::driver::MidiDriver::clock) too, so its frames can be stepped
+//! through the same way.
+//!
+//! The driver's check for the host having been asleep (see [crate::suspend]) always uses
+//! the real clocks, since comparing them is the point.
+
+use std::{
+  fmt::Debug,
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use tokio::{sync::oneshot, time::Instant};
+
+/// A future that completes when a [Clock]'s sleep is over.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: Debug + Send + Sync + 'static {
+  fn now(&self) -> Instant;
+
+  fn sleep(&self, duration: Duration) -> Sleep;
+
+  fn sleep_until(&self, deadline: Instant) -> Sleep {
+    self.sleep(deadline.saturating_duration_since(self.now()))
+  }
+
+  /// Returns a [Ticker] that ticks every `period`, starting straight away.
+  fn ticker(&self, period: Duration) -> Ticker<'_, Self>
+  where
+    Self: Sized,
+  {
+    Ticker {
+      clock: self,
+      next: self.now(),
+      period,
+    }
+  }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+  fn now(&self) -> Instant {
+    (**self).now()
+  }
+
+  fn sleep(&self, duration: Duration) -> Sleep {
+    (**self).sleep(duration)
+  }
+
+  fn sleep_until(&self, deadline: Instant) -> Sleep {
+    (**self).sleep_until(deadline)
+  }
+}
+
+/// Ticks at a fixed rate, like [tokio::time::Interval]. Ticks that are missed because the
+/// caller was busy come straight away, to catch up.
+pub struct Ticker<'a, C: Clock> {
+  clock: &'a C,
+  next: Instant,
+  period: Duration,
+}
+
+impl<'a, C: Clock> Ticker<'a, C> {
+  pub async fn tick(&mut self) {
+    self.clock.sleep_until(self.next).await;
+    self.next += self.period;
+  }
+}
+
+/// Real time, from tokio.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+
+  fn sleep(&self, duration: Duration) -> Sleep {
+    Box::pin(tokio::time::sleep(duration))
+  }
+
+  fn sleep_until(&self, deadline: Instant) -> Sleep {
+    Box::pin(tokio::time::sleep_until(deadline))
+  }
+}
+
+#[derive(Debug)]
+struct MockState {
+  now: Instant,
+  sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+/// A clock that only moves when it's told to. Clones share the same time. See the
+/// [module docs](self).
+#[derive(Debug, Clone)]
+pub struct MockClock {
+  state: Arc<Mutex<MockState>>,
+}
+
+impl MockClock {
+  /// Creates a clock that starts at the current time.
+  pub fn new() -> Self {
+    MockClock {
+      state: Arc::new(Mutex::new(MockState {
+        now: Instant::now(),
+        sleepers: vec![],
+      })),
+    }
+  }
+
+  /// Moves the clock forward, waking every sleep that's over.
+  pub fn advance(&self, duration: Duration) {
+    let mut state = self.state.lock().unwrap();
+    state.now += duration;
+    let now = state.now;
+    let (done, waiting) = std::mem::take(&mut state.sleepers)
+      .into_iter()
+      .partition(|(deadline, _)| *deadline <= now);
+    state.sleepers = waiting;
+    for (_, tx) in done {
+      // the sleep may have been dropped
+      let _ = tx.send(());
+    }
+  }
+
+  /// How many sleeps are waiting for the clock to move, not counting ones that have been
+  /// dropped.
+  pub fn sleepers(&self) -> usize {
+    let mut state = self.state.lock().unwrap();
+    state.sleepers.retain(|(_, tx)| !tx.is_closed());
+    state.sleepers.len()
+  }
+
+  /// Yields to other tasks until at least `n` sleeps are waiting, e.g. until the driver has
+  /// started a timeout.
+  pub async fn wait_for_sleepers(&self, n: usize) {
+    while self.sleepers() < n {
+      tokio::task::yield_now().await;
+    }
+  }
+}
+
+impl Default for MockClock {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Clock for MockClock {
+  fn now(&self) -> Instant {
+    self.state.lock().unwrap().now
+  }
+
+  fn sleep(&self, duration: Duration) -> Sleep {
+    let mut state = self.state.lock().unwrap();
+    let deadline = match state.now.checked_add(duration) {
+      Some(deadline) if duration > Duration::ZERO => deadline,
+      Some(_) => return Box::pin(async {}),
+      // too far in the future to ever wake
+      None => return Box::pin(std::future::pending()),
+    };
+    let (tx, rx) = oneshot::channel();
+    state.sleepers.push((deadline, tx));
+    Box::pin(async move {
+      if rx.await.is_err() {
+        // the clock was dropped, so it won't move again
+        std::future::pending::<()>().await;
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_mock_clock() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let short = tokio::spawn(clock.sleep(Duration::from_secs(1)));
+    let long = tokio::spawn(clock.sleep_until(start + Duration::from_secs(30)));
+    clock.wait_for_sleepers(2).await;
+
+    clock.advance(Duration::from_secs(1));
+    short.await.unwrap();
+    assert_eq!(clock.sleepers(), 1);
+    assert!(!long.is_finished());
+
+    clock.advance(Duration::from_secs(29));
+    long.await.unwrap();
+    assert_eq!(clock.now() - start, Duration::from_secs(30));
+
+    let ticking = clock.clone();
+    let tick = tokio::spawn(async move {
+      let mut ticker = ticking.ticker(Duration::from_millis(10));
+      ticker.tick().await;
+      ticker.tick().await;
+    });
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_millis(10));
+    tick.await.unwrap();
+  }
+}
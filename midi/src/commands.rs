@@ -4,16 +4,22 @@ use std::fmt::Debug;
 
 use super::{
   constants::{
-    BoardIndex, CommandId, LumatoneKeyFunction, LumatoneKeyLocation, MidiChannel, PresetNumber,
-    RGBColor, TEST_ECHO,
+    BoardIndex, CommandId, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation, MidiChannel,
+    PresetNumber, RGBColor, TEST_ECHO,
   },
+  error::LumatoneMidiError,
+  firmware::{ColorEncoding, FirmwareSupport},
+  responses::Response,
   sysex::{
     create_extended_key_color_sysex, create_extended_macro_color_sysex,
     create_single_arg_server_sysex, create_sysex, create_sysex_toggle, create_table_sysex,
-    create_zero_arg_server_sysex, create_zero_arg_sysex, reverse_table, EncodedSysex, SysexTable,
-    VelocityIntervalTable,
+    create_zero_arg_server_sysex, create_zero_arg_sysex, message_command_id, reverse_table,
+    strip_sysex_markers, to_hex_debug_str, validate_raw_sysex, EncodedSysex, SysexTable,
+    VelocityIntervalTable, CMD_ID,
   },
 };
+use error_stack::ensure;
+use num_traits::FromPrimitive;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
@@ -29,7 +35,8 @@ pub enum Command {
     location: LumatoneKeyLocation,
     color: RGBColor,
   },
-  /// Save current configuration to specified preset index
+  /// Save current configuration to specified preset index. Presets can't be recalled or
+  /// queried over sysex (see [crate::firmware]).
   SaveProgram(PresetNumber),
   /// Send expression pedal sensitivity
   SetExpressionPedalSensitivity(u8),
@@ -193,6 +200,10 @@ pub enum Command {
 
   /// Get the current expression pedal ADC threshold value
   GetExpressionPedalADCThreshold,
+
+  /// An arbitrary sysex message, sent as-is. Useful for experimenting with undocumented commands.
+  /// Use [raw_sysex] to create one, which makes sure the message is well-formed.
+  Raw(EncodedSysex),
 }
 
 impl Command {
@@ -281,17 +292,54 @@ impl Command {
       GetLumatouchNoteOffDelay(_) => CommandId::GetLumatouchNoteOffDelay,
       SetExpressionPedalADCThreshold(_) => CommandId::SetExpressionPedalThreshold,
       GetExpressionPedalADCThreshold => CommandId::GetExpressionPedalThreshold,
+
+      Raw(ref msg) => {
+        let data = strip_sysex_markers(msg);
+        data
+          .get(CMD_ID)
+          .and_then(|id| FromPrimitive::from_u8(*id))
+          .unwrap_or(CommandId::Unknown)
+      }
+    }
+  }
+
+  /// Decodes the device's reply to this command, for a device with the given [FirmwareSupport].
+  /// Fails if `msg` is a reply to some other command.
+  ///
+  /// Replies to [Command::Raw] are passed through as-is, since we may not know how to decode them.
+  pub fn decode_response(
+    &self,
+    msg: &[u8],
+    firmware: &FirmwareSupport,
+  ) -> error_stack::Result<Response, LumatoneMidiError> {
+    if let Command::Raw(_) = self {
+      return Ok(Response::Raw(msg.to_vec()));
     }
+    let cmd_id = message_command_id(msg)?;
+    ensure!(
+      cmd_id == self.command_id(),
+      LumatoneMidiError::InvalidResponseMessage(format!(
+        "expected a reply to {self}, got one for {cmd_id:?}"
+      ))
+    );
+    Response::from_sysex_message_for(msg, firmware)
   }
 
+  /// Encodes the command using the latest firmware's payload layouts.
   pub fn to_sysex_message(&self) -> EncodedSysex {
+    self.to_sysex_message_for(&FirmwareSupport::default())
+  }
+
+  /// Encodes the command for a device with the given [FirmwareSupport].
+  pub fn to_sysex_message_for(&self, firmware: &FirmwareSupport) -> EncodedSysex {
     use Command::*;
+    let color_encoding = firmware.color_encoding;
     match self {
       Ping(value) => encode_ping(*value),
 
       SetKeyFunction { location, function } => encode_set_key_function(location, function),
 
-      SetKeyColor { location, color } => encode_set_key_color(location, color),
+      SetKeyColor { location, color } => encode_set_key_color(location, color, color_encoding),
 
       SaveProgram(preset_number) => {
         create_single_arg_server_sysex(self.command_id(), (*preset_number).into())
@@ -334,11 +382,11 @@ impl Command {
       }
 
       SetMacroButtonActiveColor(color) => {
-        create_extended_macro_color_sysex(self.command_id(), color)
+        create_extended_macro_color_sysex(self.command_id(), color, color_encoding)
       }
 
       SetMacroButtonInactiveColor(color) => {
-        create_extended_macro_color_sysex(self.command_id(), color)
+        create_extended_macro_color_sysex(self.command_id(), color, color_encoding)
       }
 
       SetLightOnKeystrokes(active) => {
@@ -507,6 +555,8 @@ impl Command {
       ),
 
       GetExpressionPedalADCThreshold => create_zero_arg_server_sysex(self.command_id()),
+
+      Raw(msg) => msg.clone(),
     }
   }
 }
@@ -628,6 +678,7 @@ impl std::fmt::Display for Command {
         write!(f, "SetExpressionPedalADCThreshold({val})")
       }
       Command::GetExpressionPedalADCThreshold => write!(f, "GetExpressionPedalADCThreshold"),
+      Command::Raw(msg) => write!(f, "Raw({})", to_hex_debug_str(msg)),
     }
   }
 }
@@ -646,6 +697,378 @@ pub fn set_key_function(location: LumatoneKeyLocation, function: LumatoneKeyFunc
   Command::SetKeyFunction { location, function }
 }
 
+/// Sets every key on an octave board to `color`.
+///
+/// The firmware has no board-wide color command (see [crate::firmware]), so this is one
+/// [Command::SetKeyColor] per key. Send them as a `CommandGroup` to apply them as a unit.
+pub fn set_board_color(board_index: BoardIndex, color: RGBColor) -> Vec<Command> {
+  board_locations(board_index)
+    .map(|location| set_key_color(location, color))
+    .collect()
+}
+
+/// Sets every key on an octave board to `function`, one [Command::SetKeyFunction] per key.
+pub fn set_board_function(board_index: BoardIndex, function: LumatoneKeyFunction) -> Vec<Command> {
+  board_locations(board_index)
+    .map(|location| set_key_function(location, function))
+    .collect()
+}
+
+/// Turns off the lights of every key on an octave board and disables the keys.
+pub fn clear_board(board_index: BoardIndex) -> Vec<Command> {
+  let mut commands = set_board_function(board_index, LumatoneKeyFunction::Disabled);
+  commands.extend(set_board_color(board_index, RGBColor(0, 0, 0)));
+  commands
+}
+
+fn board_locations(board_index: BoardIndex) -> impl Iterator<Item = LumatoneKeyLocation> {
+  LumatoneKeyIndex::all()
+    .into_iter()
+    .map(move |key_index| LumatoneKeyLocation(board_index, key_index))
+}
+
+pub fn save_program(preset: PresetNumber) -> Command {
+  Command::SaveProgram(preset)
+}
+
+/// Sets the color of the macro buttons while they're pressed.
+pub fn set_macro_button_active_color(color: RGBColor) -> Command {
+  Command::SetMacroButtonActiveColor(color)
+}
+
+/// Sets the color of the macro buttons while they're not pressed.
+pub fn set_macro_button_inactive_color(color: RGBColor) -> Command {
+  Command::SetMacroButtonInactiveColor(color)
+}
+
+pub fn set_macro_button_colors(active: RGBColor, inactive: RGBColor) -> [Command; 2] {
+  [
+    set_macro_button_active_color(active),
+    set_macro_button_inactive_color(inactive),
+  ]
+}
+
+/// Sets whether keys light up while they're pressed.
+pub fn set_light_on_keystrokes(active: bool) -> Command {
+  Command::SetLightOnKeystrokes(active)
+}
+
+pub fn invert_sustain_pedal(invert: bool) -> Command {
+  Command::InvertSustainPedal(invert)
+}
+
+pub fn invert_foot_controller(invert: bool) -> Command {
+  Command::InvertFootController(invert)
+}
+
+pub fn set_aftertouch_enabled(enabled: bool) -> Command {
+  Command::SetAftertouchEnabled(enabled)
+}
+
+pub fn set_velocity_config(table: SysexTable) -> Command {
+  Command::SetVelocityConfig(Box::new(table))
+}
+
+pub fn set_fader_config(table: SysexTable) -> Command {
+  Command::SetFaderConfig(Box::new(table))
+}
+
+pub fn set_aftertouch_config(table: SysexTable) -> Command {
+  Command::SetAftertouchConfig(Box::new(table))
+}
+
+pub fn set_lumatouch_config(table: SysexTable) -> Command {
+  Command::SetLumatouchConfig(Box::new(table))
+}
+
+pub fn set_velocity_intervals(table: VelocityIntervalTable) -> Command {
+  Command::SetVelocityIntervals(Box::new(table))
+}
+
+pub fn set_key_maximum_threshold(
+  board_index: BoardIndex,
+  max_threshold: u8,
+  aftertouch_max: u8,
+) -> Command {
+  Command::SetKeyMaximumThreshold {
+    board_index,
+    max_threshold,
+    aftertouch_max,
+  }
+}
+
+pub fn set_key_minimum_threshold(
+  board_index: BoardIndex,
+  threshold_high: u8,
+  threshold_low: u8,
+) -> Command {
+  Command::SetKeyMinimumThreshold {
+    board_index,
+    threshold_high,
+    threshold_low,
+  }
+}
+
+pub fn set_peripheral_channels(
+  pitch_wheel: MidiChannel,
+  mod_wheel: MidiChannel,
+  expression: MidiChannel,
+  sustain: MidiChannel,
+) -> Command {
+  Command::SetPeripheralChannels {
+    pitch_wheel,
+    mod_wheel,
+    expression,
+    sustain,
+  }
+}
+
+/// Retrieves the MIDI channels of the peripheral controllers.
+pub fn get_peripheral_channels() -> Command {
+  Command::GetPeripheralChannels
+}
+
+pub fn set_expression_pedal_sensitivity(sensitivity: u8) -> Command {
+  Command::SetExpressionPedalSensitivity(sensitivity)
+}
+
+pub fn set_mod_wheel_sensitivity(sensitivity: u8) -> Command {
+  Command::SetModWheelSensitivity(sensitivity)
+}
+
+pub fn set_pitch_wheel_sensitivity(sensitivity: u16) -> Command {
+  Command::SetPitchWheelSensitivity(sensitivity)
+}
+
+/// Sets how far the pitch wheel can move from its calibrated center before it sends pitch
+/// bends, from 0x00 to 0x7f.
+pub fn set_pitch_wheel_zero_threshold(threshold: u8) -> Command {
+  Command::SetPitchWheelZeroThreshold(threshold)
+}
+
+/// Resets the pitch and mod wheel thresholds to their factory settings.
+pub fn reset_wheel_thresholds() -> Command {
+  Command::ResetWheelThresholds
+}
+
+/// Sets the expression pedal's 12-bit ADC threshold.
+pub fn set_expression_pedal_adc_threshold(threshold: u16) -> Command {
+  Command::SetExpressionPedalADCThreshold(threshold)
+}
+
+pub fn get_expression_pedal_adc_threshold() -> Command {
+  Command::GetExpressionPedalADCThreshold
+}
+
+/// Resets the expression pedal's minimum and maximum bounds to their factory settings.
+pub fn reset_expression_pedal_bounds() -> Command {
+  Command::ResetExpressionPedalBounds
+}
+
+pub fn set_key_fader_sensitivity(board_index: BoardIndex, sensitivity: u8) -> Command {
+  Command::SetKeyFaderSensitivity(board_index, sensitivity)
+}
+
+pub fn set_key_aftertouch_sensitivity(board_index: BoardIndex, sensitivity: u8) -> Command {
+  Command::SetKeyAftertouchSensitivity(board_index, sensitivity)
+}
+
+pub fn set_cc_active_threshold(board_index: BoardIndex, threshold: u8) -> Command {
+  Command::SetCCActiveThreshold(board_index, threshold)
+}
+
+/// Resets a board's thresholds, and its fader and aftertouch sensitivities, to their
+/// factory settings.
+pub fn reset_board_thresholds(board_index: BoardIndex) -> Command {
+  Command::ResetBoardThresholds(board_index)
+}
+
+pub fn get_board_threshold_values(board_index: BoardIndex) -> Command {
+  Command::GetBoardThresholdValues(board_index)
+}
+
+pub fn get_board_sensitivity_values(board_index: BoardIndex) -> Command {
+  Command::GetBoardSensitivityValues(board_index)
+}
+
+/// Sets the time between a note on and the start of its aftertouch events.
+pub fn set_aftertouch_trigger_delay(board_index: BoardIndex, delay: u8) -> Command {
+  Command::SetAftertouchTriggerDelay(board_index, delay)
+}
+
+pub fn get_aftertouch_trigger_delay(board_index: BoardIndex) -> Command {
+  Command::GetAftertouchTriggerDelay(board_index)
+}
+
+/// Sets how many 1.1ms ticks a LumaTouch key waits after it's released before sending its
+/// note off, as an 11-bit value.
+pub fn set_lumatouch_note_off_delay(board_index: BoardIndex, delay: u16) -> Command {
+  Command::SetLumatouchNoteOffDelay(board_index, delay)
+}
+
+pub fn get_lumatouch_note_off_delay(board_index: BoardIndex) -> Command {
+  Command::GetLumatouchNoteOffDelay(board_index)
+}
+
+/// Reads back the red, green and blue intensities of every key on a board, one command per
+/// channel.
+pub fn get_led_config(board_index: BoardIndex) -> [Command; 3] {
+  [
+    Command::GetRedLEDConfig(board_index),
+    Command::GetGreenLEDConfig(board_index),
+    Command::GetBlueLEDConfig(board_index),
+  ]
+}
+
+pub fn get_midi_channel_config(board_index: BoardIndex) -> Command {
+  Command::GetMidiChannelConfig(board_index)
+}
+
+pub fn get_note_config(board_index: BoardIndex) -> Command {
+  Command::GetNoteConfig(board_index)
+}
+
+pub fn get_key_type_config(board_index: BoardIndex) -> Command {
+  Command::GetKeyTypeConfig(board_index)
+}
+
+pub fn get_fader_type_config(board_index: BoardIndex) -> Command {
+  Command::GetFaderTypeConfig(board_index)
+}
+
+pub fn get_max_fader_threshold(board_index: BoardIndex) -> Command {
+  Command::GetMaxFaderThreshold(board_index)
+}
+
+pub fn get_min_fader_threshold(board_index: BoardIndex) -> Command {
+  Command::GetMinFaderThreshold(board_index)
+}
+
+pub fn get_max_aftertouch_threshold(board_index: BoardIndex) -> Command {
+  Command::GetMaxAftertouchThreshold(board_index)
+}
+
+/// Reads back whether each key on a board meets its minimum threshold.
+pub fn get_key_validity(board_index: BoardIndex) -> Command {
+  Command::GetKeyValidity(board_index)
+}
+
+pub fn get_velocity_config() -> Command {
+  Command::GetVelocityConfig
+}
+
+pub fn get_velocity_intervals() -> Command {
+  Command::GetVelocityIntervalConfig
+}
+
+pub fn get_fader_config() -> Command {
+  Command::GetFaderConfig
+}
+
+pub fn get_aftertouch_config() -> Command {
+  Command::GetAftertouchConfig
+}
+
+pub fn get_lumatouch_config() -> Command {
+  Command::GetLumatouchConfig
+}
+
+/// Saves the velocity table to the device's EEPROM, so it survives a power cycle.
+pub fn save_velocity_config() -> Command {
+  Command::SaveVelocityConfig
+}
+
+/// Replaces the velocity table with the one saved in EEPROM.
+pub fn reset_velocity_config() -> Command {
+  Command::ResetVelocityConfig
+}
+
+pub fn save_fader_config() -> Command {
+  Command::SaveFaderConfig
+}
+
+/// Resets the fader table to its factory settings.
+pub fn reset_fader_config() -> Command {
+  Command::ResetFaderConfig
+}
+
+pub fn save_aftertouch_config() -> Command {
+  Command::SaveAftertouchConfig
+}
+
+/// Resets the aftertouch table to its factory settings.
+pub fn reset_aftertouch_config() -> Command {
+  Command::ResetAftertouchConfig
+}
+
+pub fn save_lumatouch_config() -> Command {
+  Command::SaveLumatouchConfig
+}
+
+/// Resets the LumaTouch table to its factory settings.
+pub fn reset_lumatouch_config() -> Command {
+  Command::ResetLumatouchConfig
+}
+
+pub fn get_serial_id() -> Command {
+  Command::GetSerialId
+}
+
+pub fn get_firmware_revision() -> Command {
+  Command::GetFirmwareRevision
+}
+
+/// Starts or stops the device's demo mode.
+pub fn set_demo_mode(enabled: bool) -> Command {
+  Command::EnableDemoMode(enabled)
+}
+
+/// Turns key sampling on or off for a board.
+pub fn set_key_sampling(board_index: BoardIndex, enabled: bool) -> Command {
+  Command::EnableKeySampling(board_index, enabled)
+}
+
+/// Starts the key calibration routine. It ends on the device, once both macro buttons of
+/// each octave have been pressed.
+pub fn start_key_calibration() -> Command {
+  Command::StartKeyCalibration
+}
+
+/// Starts the aftertouch calibration routine, which ends on its own.
+pub fn start_aftertouch_calibration() -> Command {
+  Command::StartAftertouchCalibration
+}
+
+/// Starts the wheel calibration routine. The pitch and mod wheels are calibrated together,
+/// and the device sends a [WheelCalibration](crate::responses::WheelCalibration) status
+/// every 100ms until the routine is stopped.
+pub fn start_pitch_mod_wheel_calibration() -> Command {
+  Command::EnablePitchModWheelCalibrationMode(true)
+}
+
+/// Stops the wheel calibration routine and saves the new calibration.
+pub fn stop_pitch_mod_wheel_calibration() -> Command {
+  Command::EnablePitchModWheelCalibrationMode(false)
+}
+
+/// Starts the expression pedal calibration routine. The device sends an
+/// [ExpressionCalibration](crate::responses::ExpressionCalibration) status every 100ms until
+/// the routine is stopped.
+pub fn start_expression_pedal_calibration() -> Command {
+  Command::EnableExpressionPedalCalibrationMode(true)
+}
+
+/// Stops the expression pedal calibration routine and saves the new calibration.
+pub fn stop_expression_pedal_calibration() -> Command {
+  Command::EnableExpressionPedalCalibrationMode(false)
+}
+
+/// Creates a [Command::Raw], failing if `msg` isn't a well-formed sysex message for the Lumatone.
+pub fn raw_sysex(msg: &[u8]) -> Result<Command, LumatoneMidiError> {
+  validate_raw_sysex(msg)?;
+  Ok(Command::Raw(msg.to_vec()))
+}
+
 // endregion
 
 // region: Sysex Encoders
@@ -680,12 +1103,17 @@ fn encode_set_key_function(
   )
 }
 
-fn encode_set_key_color(location: &LumatoneKeyLocation, color: &RGBColor) -> EncodedSysex {
+fn encode_set_key_color(
+  location: &LumatoneKeyLocation,
+  color: &RGBColor,
+  encoding: ColorEncoding,
+) -> EncodedSysex {
   create_extended_key_color_sysex(
     location.board_index(),
     CommandId::SetKeyColour,
     location.key_index().into(),
     color,
+    encoding,
   )
 }
 
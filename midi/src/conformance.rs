@@ -0,0 +1,374 @@
+//! Records how a real device answers each command, as fixtures for the emulator and golden
+//! tests.
+//!
+//! [record_fixtures] sends each command to a connected device and keeps the encoded request
+//! together with the response the device sent back. The result is a [FixtureSet], saved as
+//! text, one `name = value` line per field:
+//!
+//! ```text
+//! # recorded from a Lumatone with firmware 1.0.12
+//! firmware = 1.0.12
+//!
+//! command = GetFirmwareRevision
+//! request = f0 00 21 50 00 31 00 00 00 00 f7
+//! response = f0 00 21 50 00 31 01 01 00 0c f7
+//! ```
+//!
+//! Lines starting with `#` are comments. Re-record the fixtures when new firmware comes
+//! out, then use `testing::FakeDevice::check_conformance` (with the `testing` feature) to
+//! find where the emulator no longer answers like the device, and `testing::ReplayDevice`
+//! to run tests against the recorded answers.
+//!
+//! [conformance_queries] only reads from the device. [restoring_commands] exercises the
+//! commands that change settings by writing back the values the queries read, so the
+//! device ends up configured the way it started.
+
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use super::{
+  commands::{
+    set_aftertouch_config, set_fader_config, set_lumatouch_config, set_velocity_config,
+    set_velocity_intervals, Command,
+  },
+  constants::{BoardIndex, CommandId, ResponseStatusCode},
+  driver::MidiDriver,
+  error::LumatoneMidiError,
+  firmware::FirmwareSupport,
+  responses::{FirmwareVersion, Response},
+  sysex::{message_answer_code, message_command_id, reverse_table, EncodedSysex},
+  traffic::TrafficDirection,
+};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+use log::warn;
+
+/// A request and the device's response to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+  /// The command, as [Command]'s `Display` prints it.
+  pub command: String,
+  pub request: EncodedSysex,
+  pub response: EncodedSysex,
+}
+
+impl Fixture {
+  pub fn command_id(&self) -> Result<CommandId, LumatoneMidiError> {
+    Ok(message_command_id(&self.request)?)
+  }
+
+  /// Decodes the recorded response, the way the driver would.
+  pub fn decode(&self, firmware: &FirmwareSupport) -> Result<Response, LumatoneMidiError> {
+    let cmd_id = self.command_id()?;
+    let response_id = message_command_id(&self.response)?;
+    if cmd_id != response_id {
+      bail!(LumatoneMidiError::UnexpectedCommandId {
+        expected: cmd_id,
+        actual: response_id,
+      });
+    }
+    Response::from_sysex_message_for(&self.response, firmware)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FixtureSet {
+  /// The firmware version of the device the fixtures were recorded from.
+  pub firmware: Option<FirmwareVersion>,
+  pub fixtures: Vec<Fixture>,
+}
+
+impl FixtureSet {
+  /// How to encode and decode messages for the device the fixtures were recorded from.
+  pub fn firmware_support(&self) -> FirmwareSupport {
+    self
+      .firmware
+      .as_ref()
+      .map(FirmwareSupport::for_version)
+      .unwrap_or_default()
+  }
+
+  /// Returns the first fixture whose request is exactly `request`.
+  pub fn find(&self, request: &[u8]) -> Option<&Fixture> {
+    self.fixtures.iter().find(|f| f.request == request)
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<FixtureSet, LumatoneMidiError> {
+    let path = path.as_ref();
+    std::fs::read_to_string(path)
+      .report()
+      .change_context(LumatoneMidiError::FixtureFileError(
+        path.display().to_string(),
+      ))?
+      .parse()
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LumatoneMidiError> {
+    let path = path.as_ref();
+    std::fs::write(path, self.to_string())
+      .report()
+      .change_context(LumatoneMidiError::FixtureFileError(
+        path.display().to_string(),
+      ))
+  }
+}
+
+fn parse_firmware_version(s: &str) -> Option<FirmwareVersion> {
+  let parts: Vec<u8> = s
+    .split('.')
+    .map(|p| p.parse().ok())
+    .collect::<Option<_>>()?;
+  match parts[..] {
+    [major, minor, revision] => Some(FirmwareVersion::new(major, minor, revision)),
+    _ => None,
+  }
+}
+
+fn hex_string(msg: &[u8]) -> String {
+  let bytes: Vec<String> = msg.iter().map(|b| format!("{b:02x}")).collect();
+  bytes.join(" ")
+}
+
+fn parse_hex(s: &str) -> Result<EncodedSysex, LumatoneMidiError> {
+  s.split_whitespace()
+    .map(|b| {
+      u8::from_str_radix(b, 16)
+        .report()
+        .change_context_lazy(|| LumatoneMidiError::InvalidFixture(format!("invalid byte: {b}")))
+    })
+    .collect()
+}
+
+impl Display for FixtureSet {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if let Some(firmware) = &self.firmware {
+      writeln!(f, "firmware = {firmware}")?;
+    }
+    for fixture in &self.fixtures {
+      writeln!(f)?;
+      writeln!(f, "command = {}", fixture.command)?;
+      writeln!(f, "request = {}", hex_string(&fixture.request))?;
+      writeln!(f, "response = {}", hex_string(&fixture.response))?;
+    }
+    Ok(())
+  }
+}
+
+/// A fixture that's still being parsed: its command, request and response.
+type PartialFixture = (String, Option<EncodedSysex>, Option<EncodedSysex>);
+
+fn finish_fixture(
+  partial: Option<PartialFixture>,
+  set: &mut FixtureSet,
+) -> Result<(), LumatoneMidiError> {
+  match partial {
+    Some((command, Some(request), Some(response))) => set.fixtures.push(Fixture {
+      command,
+      request,
+      response,
+    }),
+    Some((command, _, _)) => bail!(LumatoneMidiError::InvalidFixture(format!(
+      "{command} needs a request and a response"
+    ))),
+    None => {}
+  }
+  Ok(())
+}
+
+impl FromStr for FixtureSet {
+  type Err = error_stack::Report<LumatoneMidiError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let mut set = FixtureSet::default();
+    let mut current: Option<PartialFixture> = None;
+    for (n, line) in s.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let invalid = || LumatoneMidiError::InvalidFixture(format!("line {}: {line}", n + 1));
+      let (name, value) = line.split_once('=').ok_or_else(|| report!(invalid()))?;
+      let value = value.trim();
+      match (name.trim(), current.as_mut()) {
+        ("firmware", None) if set.fixtures.is_empty() => {
+          set.firmware = Some(parse_firmware_version(value).ok_or_else(|| report!(invalid()))?)
+        }
+        ("command", _) => {
+          finish_fixture(current.take(), &mut set)?;
+          current = Some((value.to_string(), None, None));
+        }
+        ("request", Some((_, request, _))) => *request = Some(parse_hex(value)?),
+        ("response", Some((_, _, response))) => *response = Some(parse_hex(value)?),
+        _ => bail!(invalid()),
+      }
+    }
+    finish_fixture(current, &mut set)?;
+    Ok(set)
+  }
+}
+
+/// The commands that only read from the device: every `Get*` command, for each board where
+/// it takes one, and a ping.
+pub fn conformance_queries() -> Vec<Command> {
+  use Command::*;
+  let mut commands = vec![
+    Ping(0x1234),
+    GetSerialId,
+    GetFirmwareRevision,
+    GetVelocityConfig,
+    GetVelocityIntervalConfig,
+    GetFaderConfig,
+    GetAftertouchConfig,
+    GetLumatouchConfig,
+    GetPeripheralChannels,
+    GetExpressionPedalADCThreshold,
+  ];
+  for board in BoardIndex::all_octaves() {
+    commands.extend([
+      GetRedLEDConfig(board),
+      GetGreenLEDConfig(board),
+      GetBlueLEDConfig(board),
+      GetMidiChannelConfig(board),
+      GetNoteConfig(board),
+      GetKeyTypeConfig(board),
+      GetMaxFaderThreshold(board),
+      GetMinFaderThreshold(board),
+      GetMaxAftertouchThreshold(board),
+      GetKeyValidity(board),
+      GetFaderTypeConfig(board),
+      GetBoardThresholdValues(board),
+      GetBoardSensitivityValues(board),
+      GetAftertouchTriggerDelay(board),
+      GetLumatouchNoteOffDelay(board),
+    ]);
+  }
+  commands
+}
+
+/// The commands that set back the values in the responses to [conformance_queries], for
+/// the settings that can be read back.
+pub fn restoring_commands(fixtures: &FixtureSet) -> Vec<Command> {
+  let firmware = fixtures.firmware_support();
+  fixtures
+    .fixtures
+    .iter()
+    .filter_map(|fixture| fixture.decode(&firmware).ok())
+    .filter_map(|response| match response {
+      // the device sends the velocity curve in the reverse of the order it's set in
+      Response::OnOffVelocityConfig(t) => Some(set_velocity_config(reverse_table(&t))),
+      Response::FaderConfig(t) => Some(set_fader_config(*t)),
+      Response::AftertouchConfig(t) => Some(set_aftertouch_config(*t)),
+      Response::LumatouchConfig(t) => Some(set_lumatouch_config(*t)),
+      Response::VelocityIntervalConfig(t) => Some(set_velocity_intervals(*t)),
+      Response::PeripheralChannels(c) => Some(c.to_command()),
+      Response::AftertouchTriggerDelay(board, delay) => {
+        Some(Command::SetAftertouchTriggerDelay(board, delay))
+      }
+      Response::LumatouchNoteOffDelay(board, delay) => {
+        Some(Command::SetLumatouchNoteOffDelay(board, delay))
+      }
+      Response::ExpressionPedalThreshold(threshold) => {
+        Some(Command::SetExpressionPedalADCThreshold(threshold))
+      }
+      _ => None,
+    })
+    .collect()
+}
+
+/// Sends each of `commands` to the device, and returns a fixture for each one it answered.
+///
+/// Commands that fail, e.g. because the firmware doesn't support them, are left out and
+/// returned with the reason, so that one missing command doesn't spoil the whole recording.
+pub async fn record_fixtures(
+  driver: &MidiDriver,
+  commands: Vec<Command>,
+) -> (Vec<Fixture>, Vec<(Command, String)>) {
+  let mut fixtures = vec![];
+  let mut failures = vec![];
+  for command in commands {
+    let request = command.to_sysex_message_for(&driver.firmware());
+    if let Err(err) = driver.send(command.clone()).await {
+      warn!("no fixture for {command}: {err}");
+      failures.push((command, err.current_context().to_string()));
+      continue;
+    }
+
+    // the response the command succeeded with is the latest one for its command id
+    let cmd_id = command.command_id();
+    let response = driver.recent_traffic().into_iter().rev().find(|entry| {
+      entry.direction == TrafficDirection::Inbound
+        && message_command_id(&entry.message).ok() == Some(cmd_id)
+        && message_answer_code(&entry.message) == ResponseStatusCode::Ack
+    });
+    match response {
+      Some(entry) => fixtures.push(Fixture {
+        command: command.to_string(),
+        request,
+        response: entry.message,
+      }),
+      None => failures.push((
+        command,
+        "response wasn't kept in the traffic log".to_string(),
+      )),
+    }
+  }
+  (fixtures, failures)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    commands::ping,
+    constants::MANUFACTURER_ID,
+    shutdown::CancellationToken,
+    testing::{FakeDevice, FAKE_FIRMWARE_VERSION},
+  };
+
+  #[test]
+  fn test_fixture_file_round_trip() {
+    let set = FixtureSet {
+      firmware: Some(FirmwareVersion::new(1, 0, 12)),
+      fixtures: vec![Fixture {
+        command: "Ping(1)".to_string(),
+        request: ping(1).to_sysex_message(),
+        response: vec![0xf0, MANUFACTURER_ID[0], 0xf7],
+      }],
+    };
+    let parsed: FixtureSet = set.to_string().parse().unwrap();
+    assert_eq!(parsed, set);
+
+    assert!("command = Ping(1)\nrequest = f0 f7\n"
+      .parse::<FixtureSet>()
+      .is_err());
+    assert!("request = f0 f7\n".parse::<FixtureSet>().is_err());
+    assert!("firmware = 1.0\n".parse::<FixtureSet>().is_err());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_record_fixtures() {
+    let shutdown = CancellationToken::new();
+    let (driver, driver_future) = MidiDriver::with_transport(FakeDevice::new(), shutdown.clone());
+    tokio::spawn(driver_future);
+
+    let (fixtures, failures) = record_fixtures(
+      &driver,
+      vec![Command::GetFirmwareRevision, Command::GetSerialId],
+    )
+    .await;
+    assert!(failures.is_empty());
+    assert_eq!(fixtures.len(), 2);
+    assert_eq!(fixtures[0].command, "GetFirmwareRevision");
+    match fixtures[0].decode(&FirmwareSupport::default()) {
+      Ok(Response::FirmwareRevision(v)) => assert_eq!(
+        v,
+        FirmwareVersion::new(
+          FAKE_FIRMWARE_VERSION[0],
+          FAKE_FIRMWARE_VERSION[1],
+          FAKE_FIRMWARE_VERSION[2]
+        )
+      ),
+      res => panic!("unexpected result: {res:?}"),
+    }
+    shutdown.cancel();
+  }
+}
@@ -34,6 +34,13 @@ impl RGBColor {
     RGBColor(rand::random(), rand::random(), rand::random())
   }
 
+  /// Returns the color with each channel multiplied by `brightness`, clamped to 0.0 ..= 1.0.
+  pub fn scaled(&self, brightness: f32) -> RGBColor {
+    let brightness = brightness.clamp(0.0, 1.0);
+    let scale = |c: u8| (c as f32 * brightness).round() as u8;
+    RGBColor(scale(self.0), scale(self.1), scale(self.2))
+  }
+
   pub fn to_hex_string(&self) -> String {
     let RGBColor(r, g, b) = self;
     format!("{r:02x}{g:02x}{b:02x}")
@@ -50,6 +57,13 @@ impl RGBColor {
     let blue_lo = blue & 0xf;
     vec![red_hi, red_lo, green_hi, green_lo, blue_hi, blue_lo]
   }
+
+  /// Returns the color encoded into 3 u8's, one 7-bit value per channel, as expected
+  /// by older firmware. The lowest bit of each channel is dropped.
+  pub fn to_7bit_bytes(&self) -> Vec<u8> {
+    let RGBColor(red, green, blue) = *self;
+    vec![red >> 1, green >> 1, blue >> 1]
+  }
 }
 
 impl From<u32> for RGBColor {
@@ -282,6 +296,32 @@ impl LumatoneKeyFunction {
     }
   }
 
+  /// The inverse of [LumatoneKeyFunction::type_code]: builds a key's function from the
+  /// type code, channel and note or CC number read back from the device. Returns `None`
+  /// for an unknown type code.
+  pub fn from_type_code(type_code: u8, channel: MidiChannel, number: u8) -> Option<Self> {
+    use LumatoneKeyFunction::*;
+    let fader_up_is_null = type_code & (1 << 4) != 0;
+    match type_code & 0xf {
+      1 => Some(NoteOnOff {
+        channel,
+        note_num: number,
+      }),
+      2 => Some(ContinuousController {
+        channel,
+        cc_num: number,
+        fader_up_is_null,
+      }),
+      3 => Some(LumaTouch {
+        channel,
+        note_num: number,
+        fader_up_is_null,
+      }),
+      4 => Some(Disabled),
+      _ => None,
+    }
+  }
+
   pub fn key_type_code(&self) -> u8 {
     use LumatoneKeyFunction::*;
     match *self {
@@ -359,7 +399,7 @@ impl Into<u8> for ResponseStatusCode {
 }
 
 /// Identifies a Lumatone command.
-#[derive(Debug, FromPrimitive, PartialEq)]
+#[derive(Debug, FromPrimitive, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum CommandId {
   // Start support at 55-keys firmware version, Developmental versions
   ChangeKeyNote = 0x00,
@@ -471,6 +511,9 @@ pub enum CommandId {
   SetExpressionPedalThreshold = 0x43,
   GetExpressionPedalThreshold = 0x44,
   InvertSustainPedal = 0x45,
+
+  /// Unknown - Not a real Lumatone command. Used for raw messages whose command id isn't recognized.
+  Unknown = 0xff,
 }
 
 impl Into<u8> for CommandId {
@@ -481,10 +524,40 @@ impl Into<u8> for CommandId {
 
 #[cfg(test)]
 mod tests {
-  use super::RGBColor;
+  use super::{LumatoneKeyFunction, MidiChannel, RGBColor};
 
   #[test]
   fn test_rgb_color() {
     assert_eq!(RGBColor::from(0x00aabbcc), RGBColor(0xaa, 0xbb, 0xcc));
   }
+
+  #[test]
+  fn test_key_function_type_codes() {
+    let channel = MidiChannel::unchecked(3);
+    let functions = [
+      LumatoneKeyFunction::NoteOnOff {
+        channel,
+        note_num: 60,
+      },
+      LumatoneKeyFunction::ContinuousController {
+        channel,
+        cc_num: 7,
+        fader_up_is_null: true,
+      },
+      LumatoneKeyFunction::LumaTouch {
+        channel,
+        note_num: 61,
+        fader_up_is_null: false,
+      },
+    ];
+    for f in functions {
+      let decoded = LumatoneKeyFunction::from_type_code(f.type_code(), channel, f.note_or_cc_num());
+      assert_eq!(decoded, Some(f));
+    }
+    assert_eq!(
+      LumatoneKeyFunction::from_type_code(4, channel, 0),
+      Some(LumatoneKeyFunction::Disabled)
+    );
+    assert_eq!(LumatoneKeyFunction::from_type_code(9, channel, 0), None);
+  }
 }
@@ -0,0 +1,397 @@
+//! A high-level handle to a connected Lumatone, which owns the background tasks needed
+//! to talk to the device.
+//!
+//! [Lumatone] spawns the [MidiDriver] loop, plus any other tasks that need to live as long
+//! as the connection (e.g. the [note proxy](crate::proxy)). All of them share one
+//! [CancellationToken], so [Lumatone::shutdown] stops everything and waits for the tasks
+//! to finish.
+//!
+//! The methods that spawn tasks must be called from within a tokio runtime.
+
+use futures::Future;
+use log::{debug, warn};
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::{sync::broadcast, task::JoinHandle};
+
+use super::{
+  brightness::{is_color_command, ramp, RAMP_FRAME},
+  clock::Clock,
+  commands::Command,
+  detect::detect_device_until,
+  device::LumatoneDevice,
+  driver::{CommandGroup, MidiDriver, Priority},
+  error::LumatoneMidiError,
+  info::DeviceInfo,
+  mirror::ConfigMirror,
+  proxy::{NoteMapping, NoteProxy, ProxyHandle, ProxyOutput},
+  reconcile::{converge, start_reconciler, DesiredState, ReconcileReport, ReconcilerHandle},
+  recorder::{CommandMacro, MacroParams},
+  responses::{FirmwareVersion, Response},
+  resync::{run_watchdog, ResyncEvent, RESYNC_EVENTS_BUFFER_SIZE},
+  shutdown::CancellationToken,
+  traffic::TrafficEntry,
+};
+
+use error_stack::{bail, Report, Result};
+
+/// How many of the most recent messages to attach to errors from [Lumatone::send].
+const ERROR_TRAFFIC_CONTEXT: usize = 8;
+
+pub struct Lumatone {
+  driver: MidiDriver,
+  shutdown: CancellationToken,
+  tasks: Vec<JoinHandle<()>>,
+  proxy: Option<ProxyHandle>,
+  device: LumatoneDevice,
+  info: Option<DeviceInfo>,
+  mirror: Arc<Mutex<ConfigMirror>>,
+  resync_events: Option<broadcast::Sender<ResyncEvent>>,
+  recording: Mutex<Option<CommandMacro>>,
+}
+
+impl Lumatone {
+  /// Connects to the given device and starts the driver loop.
+  pub fn connect(device: &LumatoneDevice) -> Result<Lumatone, LumatoneMidiError> {
+    Self::connect_with_shutdown_token(device, CancellationToken::new())
+  }
+
+  /// Detects a connected device (see [crate::detect]) and connects to it.
+  ///
+  /// Also reads the [DeviceInfo], including the firmware version, so that version-specific
+  /// commands are encoded correctly (see [crate::firmware]).
+  pub async fn detect() -> Result<Lumatone, LumatoneMidiError> {
+    let shutdown = CancellationToken::new();
+    let device = detect_device_until(&shutdown).await?;
+    let mut lumatone = Self::connect_with_shutdown_token(&device, shutdown)?;
+    if let Err(err) = lumatone.refresh_device_info().await {
+      warn!("unable to read device info, assuming latest firmware: {err:?}");
+    }
+    Ok(lumatone)
+  }
+
+  fn connect_with_shutdown_token(
+    device: &LumatoneDevice,
+    shutdown: CancellationToken,
+  ) -> Result<Lumatone, LumatoneMidiError> {
+    let (driver, driver_future) = MidiDriver::with_shutdown_token(device, shutdown.clone())?;
+    let mut lumatone = Lumatone {
+      driver,
+      shutdown,
+      tasks: vec![],
+      proxy: None,
+      device: device.clone(),
+      info: None,
+      mirror: Arc::new(Mutex::new(ConfigMirror::new())),
+      resync_events: None,
+      recording: Mutex::new(None),
+    };
+    lumatone.spawn(driver_future);
+    Ok(lumatone)
+  }
+
+  /// Returns a new handle to the driver, which can be moved to another task.
+  pub fn driver(&self) -> MidiDriver {
+    self.driver.clone()
+  }
+
+  /// Sends a command to the device. See [MidiDriver::send].
+  ///
+  /// Commands that change the device's configuration are recorded in a [ConfigMirror] once
+  /// the device acknowledges them, so they can be re-sent after a reset (see [crate::resync]).
+  ///
+  /// If a macro is being recorded (see [Lumatone::start_recording]), the command is added
+  /// to it once the device acknowledges it.
+  ///
+  /// If the [DeviceInfo] has been read, it's attached to any error report, along with the
+  /// last few messages exchanged with the device (see [crate::traffic]).
+  pub async fn send(&self, command: Command) -> Result<Response, LumatoneMidiError> {
+    match self.driver.send(command.clone()).await {
+      Ok(response) => {
+        self.record(&command);
+        Ok(response)
+      }
+      Err(err) => Err(self.with_context(err)),
+    }
+  }
+
+  /// Sends a bulk upload, e.g. a keymap's commands, as one high priority [CommandGroup]. The
+  /// driver still slips other clients' commands in between (see
+  /// [DriverOptions::with_priority_ratio](crate::driver::DriverOptions::with_priority_ratio)),
+  /// so an LED animation keeps running while the upload goes out.
+  ///
+  /// Stops at the first command that fails, like [MidiDriver::send_group]. Commands are
+  /// recorded like [Lumatone::send]'s once the whole upload has been acknowledged.
+  pub async fn upload(&self, commands: Vec<Command>) -> Result<Vec<Response>, LumatoneMidiError> {
+    let group = CommandGroup::new(commands.clone()).with_priority(Priority::High);
+    let responses = self
+      .driver
+      .send_group(group)
+      .await
+      .map_err(|err| self.with_context(err))?;
+    for command in &commands {
+      self.record(command);
+    }
+    Ok(responses)
+  }
+
+  /// Records an acknowledged command in the mirror and any macro being recorded.
+  fn record(&self, command: &Command) {
+    self.mirror.lock().unwrap().record(command);
+    if let Some(recording) = self.recording.lock().unwrap().as_mut() {
+      recording.record(command);
+    }
+  }
+
+  /// Attaches the [DeviceInfo] and recent traffic to an error from the driver.
+  fn with_context(&self, err: Report<LumatoneMidiError>) -> Report<LumatoneMidiError> {
+    let traffic = self.driver.recent_traffic();
+    let recent = &traffic[traffic.len().saturating_sub(ERROR_TRAFFIC_CONTEXT)..];
+    let err = match &self.info {
+      Some(info) => err.attach_printable(info.to_string()),
+      None => err,
+    };
+    match recent.is_empty() {
+      true => err,
+      false => err.attach_printable(format!(
+        "recent traffic:\n{}",
+        recent
+          .iter()
+          .map(|e| e.to_string())
+          .collect::<Vec<_>>()
+          .join("\n")
+      )),
+    }
+  }
+
+  /// Returns the configuration commands that would be re-sent if the device reset, in the
+  /// order they were first sent.
+  pub fn mirrored_config(&self) -> Vec<Command> {
+    self.mirror.lock().unwrap().commands()
+  }
+
+  /// Returns the current LED brightness. See [crate::brightness].
+  pub fn brightness(&self) -> f32 {
+    self.driver.brightness().get()
+  }
+
+  /// Changes the LED brightness, and re-sends the mirrored key and macro button colors so
+  /// the whole board changes at once.
+  pub async fn set_brightness(&self, level: f32) -> Result<(), LumatoneMidiError> {
+    self.driver.brightness().set(level);
+    for command in self.mirrored_colors() {
+      self.driver.send(command).await?;
+    }
+    Ok(())
+  }
+
+  /// Fades the LED brightness to `level` over `duration`, re-sending the mirrored colors
+  /// every [RAMP_FRAME] of the driver's [clock](crate::clock). The colors are coalesced, so
+  /// a key that hasn't been sent by the next frame is sent once at the newer level, and
+  /// every key keeps fading however many are lit. The last frame is sent like
+  /// [Lumatone::set_brightness], so the board always ends up at `level`.
+  pub async fn ramp_brightness(
+    &self,
+    level: f32,
+    duration: Duration,
+  ) -> Result<(), LumatoneMidiError> {
+    let frames = (duration.as_millis() / RAMP_FRAME.as_millis()) as usize;
+    let mut levels = ramp(self.brightness(), level, frames);
+    levels.pop();
+    let clock = self.driver.clock();
+    let mut ticker = clock.ticker(RAMP_FRAME);
+    for frame_level in levels {
+      ticker.tick().await;
+      self.driver.brightness().set(frame_level);
+      for command in self.mirrored_colors() {
+        self.driver.send_and_forget_coalesced(command).await?;
+      }
+    }
+    ticker.tick().await;
+    self.set_brightness(level).await
+  }
+
+  fn mirrored_colors(&self) -> Vec<Command> {
+    self
+      .mirrored_config()
+      .into_iter()
+      .filter(is_color_command)
+      .collect()
+  }
+
+  /// Starts a task that pings the device every `interval`, and re-sends the mirrored
+  /// configuration if the device stops responding and then comes back. Returns a receiver
+  /// for [ResyncEvent]s.
+  ///
+  /// If the watchdog is already running, returns a new receiver for its events.
+  pub fn start_resync_watchdog(&mut self, interval: Duration) -> broadcast::Receiver<ResyncEvent> {
+    if let Some(events) = &self.resync_events {
+      return events.subscribe();
+    }
+    let (events, receiver) = broadcast::channel(RESYNC_EVENTS_BUFFER_SIZE);
+    self.spawn(run_watchdog(
+      self.driver(),
+      self.mirror.clone(),
+      events.clone(),
+      interval,
+      self.shutdown.clone(),
+    ));
+    self.resync_events = Some(events);
+    receiver
+  }
+
+  /// Sends the commands needed to give the device the `desired` configuration, skipping
+  /// settings that already have the desired value. See [crate::reconcile].
+  pub async fn reconcile(
+    &self,
+    desired: &DesiredState,
+  ) -> Result<ReconcileReport, LumatoneMidiError> {
+    converge(&self.driver, &self.mirror, desired).await
+  }
+
+  /// Starts a task that keeps the device converged to `desired`, pinging it every
+  /// `interval` and sending the whole desired state again after a reset. Use the returned
+  /// [ReconcilerHandle] to change the desired state.
+  ///
+  /// The reconciler does its own reset detection, so there's no need to also run the
+  /// [resync watchdog](Lumatone::start_resync_watchdog).
+  pub fn start_reconciler(
+    &mut self,
+    desired: DesiredState,
+    interval: Duration,
+  ) -> ReconcilerHandle {
+    let (handle, reconciler_future) = start_reconciler(
+      self.driver(),
+      self.mirror.clone(),
+      desired,
+      interval,
+      self.shutdown.clone(),
+    );
+    self.spawn(reconciler_future);
+    handle
+  }
+
+  /// Starts recording the commands sent with [Lumatone::send] into a new [CommandMacro].
+  /// Discards any recording already in progress.
+  pub fn start_recording<S: Into<String>>(&self, name: S) {
+    *self.recording.lock().unwrap() = Some(CommandMacro::new(name));
+  }
+
+  /// Stops recording and returns the macro, or `None` if nothing was being recorded.
+  pub fn stop_recording(&self) -> Option<CommandMacro> {
+    self.recording.lock().unwrap().take()
+  }
+
+  /// Sends each command in `command_macro`, with `params` substituted for its parameters.
+  /// Stops at the first command that fails.
+  pub async fn play_macro(
+    &self,
+    command_macro: &CommandMacro,
+    params: &MacroParams,
+  ) -> Result<Vec<Response>, LumatoneMidiError> {
+    let mut responses = vec![];
+    for command in command_macro.render(params)? {
+      responses.push(self.send(command).await?);
+    }
+    Ok(responses)
+  }
+
+  /// Sends a raw sysex message to the device. See [MidiDriver::send_raw].
+  pub async fn send_raw(&self, msg: &[u8]) -> Result<Response, LumatoneMidiError> {
+    self.driver.send_raw(msg).await
+  }
+
+  /// Returns the most recent messages sent to and received from the device. See
+  /// [MidiDriver::recent_traffic].
+  pub fn recent_traffic(&self) -> Vec<TrafficEntry> {
+    self.driver.recent_traffic()
+  }
+
+  /// Returns the [DeviceInfo] read by the last call to [Lumatone::refresh_device_info].
+  /// [Lumatone::detect] reads it when connecting, but [Lumatone::connect] doesn't.
+  pub fn device_info(&self) -> Option<&DeviceInfo> {
+    self.info.as_ref()
+  }
+
+  /// Queries the device for its [DeviceInfo] and caches the result. Also updates the
+  /// firmware version used by the driver (see [Lumatone::sync_firmware_version]).
+  pub async fn refresh_device_info(&mut self) -> Result<&DeviceInfo, LumatoneMidiError> {
+    let info = self.query_device_info(&self.device).await?;
+    debug!("device info: {info}");
+    Ok(self.info.insert(info))
+  }
+
+  /// Reads the device's firmware version and configures the driver to use the matching
+  /// payload layouts.
+  pub async fn sync_firmware_version(&self) -> Result<FirmwareVersion, LumatoneMidiError> {
+    let version = self.get_firmware_version().await?;
+    debug!("device firmware version: {version}");
+    self.driver.set_firmware_version(&version);
+    Ok(version)
+  }
+
+  /// Returns the token that's cancelled when this Lumatone shuts down. Tasks that aren't
+  /// started with [Lumatone::spawn] can use it to stop at the same time.
+  pub fn shutdown_token(&self) -> CancellationToken {
+    self.shutdown.clone()
+  }
+
+  /// Spawns a task that will be awaited by [Lumatone::shutdown]. The task should watch
+  /// [Lumatone::shutdown_token] and exit when it's cancelled.
+  pub fn spawn<F>(&mut self, task: F)
+  where
+    F: Future<Output = ()> + Send + 'static,
+  {
+    self.tasks.push(tokio::spawn(task));
+  }
+
+  /// Starts proxying notes played on the device to `output`, re-mapped according to `mapping`.
+  ///
+  /// Fails if a proxy is already running; use the returned [ProxyHandle] to change its mapping.
+  pub fn start_proxy<O: ProxyOutput>(
+    &mut self,
+    mapping: NoteMapping,
+    output: O,
+  ) -> Result<ProxyHandle, LumatoneMidiError> {
+    if self.proxy.is_some() {
+      bail!(LumatoneMidiError::NoteProxyError(
+        "proxy is already running".to_string()
+      ));
+    }
+    let events = self.driver.subscribe_events()?;
+    let (handle, proxy_future) =
+      NoteProxy::new(mapping).start(events, output, self.shutdown.clone());
+    self.spawn(proxy_future);
+    self.proxy = Some(handle.clone());
+    Ok(handle)
+  }
+
+  /// Returns the handle for the proxy started with [Lumatone::start_proxy], if it's running.
+  pub fn proxy(&self) -> Option<&ProxyHandle> {
+    self.proxy.as_ref()
+  }
+
+  /// Sends "all notes off" and "all sound off" on every channel of the proxy's output,
+  /// and resets the proxy's held-note state.
+  pub async fn panic(&self) -> Result<(), LumatoneMidiError> {
+    match &self.proxy {
+      Some(proxy) => proxy.panic().await,
+      None => bail!(LumatoneMidiError::NoteProxyError(
+        "proxy is not running".to_string()
+      )),
+    }
+  }
+
+  /// Stops the driver and all other tasks, and waits for them to exit.
+  pub async fn shutdown(self) {
+    debug!("shutting down lumatone tasks");
+    self.shutdown.cancel();
+    for task in self.tasks {
+      if let Err(err) = task.await {
+        warn!("error joining task: {err}");
+      }
+    }
+  }
+}
@@ -0,0 +1,47 @@
+//! Response curves for reshaping 7-bit values (velocity, pressure) in software, on top of
+//! the tables in the device's firmware.
+
+use super::sysex::SysexTable;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseCurve {
+  Linear,
+
+  /// `output = input ^ exponent`, with both scaled to 0.0 ..= 1.0. Exponents above 1 need
+  /// more force to reach high values, below 1 need less.
+  Power(f32),
+
+  /// Maps each input value to an output value.
+  Table(Box<SysexTable>),
+}
+
+impl ResponseCurve {
+  pub fn apply(&self, value: u8) -> u8 {
+    let value = value.min(127);
+    match self {
+      ResponseCurve::Linear => value,
+      ResponseCurve::Power(exponent) => {
+        let x = value as f32 / 127.0;
+        (x.powf(*exponent) * 127.0).round().clamp(0.0, 127.0) as u8
+      }
+      ResponseCurve::Table(table) => table[value as usize].min(127),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_curves() {
+    assert_eq!(ResponseCurve::Linear.apply(64), 64);
+    assert_eq!(ResponseCurve::Power(2.0).apply(127), 127);
+    assert_eq!(ResponseCurve::Power(2.0).apply(64), 32);
+    assert_eq!(ResponseCurve::Power(0.5).apply(32), 64);
+
+    let mut table = [0; 128];
+    table[10] = 100;
+    assert_eq!(ResponseCurve::Table(Box::new(table)).apply(10), 100);
+  }
+}
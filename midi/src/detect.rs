@@ -3,27 +3,67 @@ use tokio::sync::mpsc;
 use tokio::time::timeout;
 
 use super::{
-  commands::ping, device::LumatoneDevice, error::LumatoneMidiError, responses::decode_ping,
+  commands::ping,
+  device::LumatoneDevice,
+  diagnostics::{diagnose, DiagnosticHint},
+  error::LumatoneMidiError,
+  ports::lumatone_ports,
+  responses::decode_ping,
+  shutdown::CancellationToken,
 };
 use midir::{MidiInput, MidiOutput};
 
 use log::{debug, info, warn};
 
-use error_stack::{report, IntoReport, Result, ResultExt};
+use error_stack::{report, IntoReport, Report, Result, ResultExt};
 
 const CLIENT_NAME: &'static str = "lumatone_rs";
 
 pub async fn detect_device() -> Result<LumatoneDevice, LumatoneMidiError> {
+  detect_device_until(&CancellationToken::new()).await
+}
+
+/// Returns the indices of the ports to ping: the ones that look like a Lumatone (see
+/// [crate::ports]), or all of them if none do, e.g. when the device is connected through a
+/// MIDI interface.
+fn ports_to_ping(names: &[String]) -> Vec<usize> {
+  let lumatones = lumatone_ports(names);
+  if lumatones.is_empty() {
+    (0..names.len()).collect()
+  } else {
+    lumatones
+  }
+}
+
+/// Attaches troubleshooting hints to a detection error (see [crate::diagnostics]).
+fn with_hints(
+  mut report: Report<LumatoneMidiError>,
+  hints: Vec<DiagnosticHint>,
+) -> Report<LumatoneMidiError> {
+  for hint in hints {
+    report = report.attach_printable(hint);
+  }
+  report
+}
+
+fn service_unavailable<E: std::fmt::Display>(err: E) -> Report<LumatoneMidiError> {
+  with_hints(
+    report!(LumatoneMidiError::DeviceDetectionFailed),
+    vec![DiagnosticHint::MidiServiceUnavailable(err.to_string())],
+  )
+}
+
+/// Like [detect_device], but gives up early if `shutdown` is cancelled.
+///
+/// If the device isn't found, the error has [DiagnosticHint]s attached that explain why.
+pub async fn detect_device_until(
+  shutdown: &CancellationToken,
+) -> Result<LumatoneDevice, LumatoneMidiError> {
   use LumatoneMidiError::DeviceDetectionFailed;
   debug!("beginning lumatone device detection");
 
-  let output = MidiOutput::new(CLIENT_NAME)
-    .report()
-    .change_context(DeviceDetectionFailed)?;
-
-  let input = MidiInput::new(CLIENT_NAME)
-    .report()
-    .change_context(DeviceDetectionFailed)?;
+  let output = MidiOutput::new(CLIENT_NAME).map_err(service_unavailable)?;
+  let input = MidiInput::new(CLIENT_NAME).map_err(service_unavailable)?;
   let in_ports = input.ports();
   let out_ports = output.ports();
 
@@ -33,23 +73,41 @@ pub async fn detect_device() -> Result<LumatoneDevice, LumatoneMidiError> {
     out_ports.len()
   );
 
+  let in_names = in_ports
+    .iter()
+    .map(|p| input.port_name(p))
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .report()
+    .change_context(DeviceDetectionFailed)?;
+  let out_names = out_ports
+    .iter()
+    .map(|p| output.port_name(p))
+    .collect::<std::result::Result<Vec<_>, _>>()
+    .report()
+    .change_context(DeviceDetectionFailed)?;
+
+  if in_names.is_empty() || out_names.is_empty() {
+    return Err(with_hints(
+      report!(DeviceDetectionFailed).attach_printable("no ports found"),
+      diagnose(&in_names, &[]),
+    ));
+  }
+
   let (tx, mut rx) = mpsc::channel(in_ports.len());
 
+  // ports that couldn't be opened, e.g. because another application has them
+  let mut unavailable = vec![];
   let mut input_connections = vec![];
-  for (port_index, p) in in_ports.iter().enumerate() {
+  for port_index in ports_to_ping(&in_names) {
     // unfortunately, it doesn't seem to be possible to use the same MidiInput to connect to
     // multiple ports in parallel, since MidiInput.connect consumes self.
-    let midi_in = MidiInput::new(CLIENT_NAME)
-      .report()
-      .change_context(DeviceDetectionFailed)?;
-    let port_name = midi_in
-      .port_name(p)
-      .report()
-      .change_context(DeviceDetectionFailed)?;
+    let midi_in = MidiInput::new(CLIENT_NAME).map_err(service_unavailable)?;
+    let p = &in_ports[port_index];
+    let port_name = &in_names[port_index];
     let my_tx = tx.clone();
     let conn_res = midi_in.connect(
       p,
-      &port_name,
+      port_name,
       move |_, msg, _| {
         match decode_ping(msg) {
           Ok(output_port_index) => {
@@ -68,53 +126,63 @@ pub async fn detect_device() -> Result<LumatoneDevice, LumatoneMidiError> {
         info!("connected to input port {port_name}");
         input_connections.push(conn);
       }
-      Err(e) => warn!("input connection error for port {port_name}: {e}"),
+      Err(e) => {
+        warn!("input connection error for port {port_name}: {e}");
+        unavailable.push(port_name.clone());
+      }
     }
   }
 
   // send a ping message on all output ports, with the ping value set to the output port index
-  for (port_index, p) in out_ports.iter().enumerate() {
-    let midi_out = MidiOutput::new(CLIENT_NAME)
-      .report()
-      .change_context(DeviceDetectionFailed)?;
-    let port_name = midi_out
-      .port_name(p)
-      .report()
-      .change_context(DeviceDetectionFailed)?;
-    if let Ok(mut conn) = midi_out.connect(p, &port_name) {
-      let cmd = ping(port_index as u32);
-      if let Err(send_err) = conn.send(&cmd.to_sysex_message()) {
-        warn!("send error: {send_err}");
+  for port_index in ports_to_ping(&out_names) {
+    let midi_out = MidiOutput::new(CLIENT_NAME).map_err(service_unavailable)?;
+    let p = &out_ports[port_index];
+    let port_name = &out_names[port_index];
+    match midi_out.connect(p, port_name) {
+      Ok(mut conn) => {
+        let cmd = ping(port_index as u32);
+        if let Err(send_err) = conn.send(&cmd.to_sysex_message()) {
+          warn!("send error: {send_err}");
+        }
+        debug!("sent ping on output {port_index} - {port_name}");
+        conn.close();
+      }
+      Err(e) => {
+        warn!("output connection error for port {port_name}: {e}");
+        unavailable.push(port_name.clone());
       }
-      debug!("sent ping on output {port_index} - {port_name}");
-      conn.close();
     }
   }
 
   let mut in_port_idx: Option<usize> = None;
   let mut out_port_idx: Option<usize> = None;
   let with_timeout = timeout(Duration::from_secs(30), rx.recv());
-  while let Ok(Some((in_port_index, out_port_index))) = with_timeout.await {
-    in_port_idx = Some(in_port_index);
-    out_port_idx = Some(out_port_index);
-    break;
+  tokio::select! {
+    res = with_timeout => {
+      if let Ok(Some((in_port_index, out_port_index))) = res {
+        in_port_idx = Some(in_port_index);
+        out_port_idx = Some(out_port_index);
+      }
+    }
+    _ = shutdown.cancelled() => {
+      return Err(report!(DeviceDetectionFailed).attach_printable("cancelled"));
+    }
   }
 
   if in_port_idx.is_none() || out_port_idx.is_none() {
-    return Err(report!(LumatoneMidiError::DeviceDetectionFailed).attach_printable("timed out"));
+    return Err(with_hints(
+      report!(DeviceDetectionFailed).attach_printable("timed out"),
+      diagnose(&in_names, &unavailable),
+    ));
   }
 
-  let output_port_name = output
-    .port_name(&out_ports[out_port_idx.unwrap()])
-    .report()
-    .change_context(DeviceDetectionFailed)?;
-  let input_port_name = input
-    .port_name(&in_ports[in_port_idx.unwrap()])
-    .report()
-    .change_context(DeviceDetectionFailed)?;
+  let output_port_name = out_names
+    .get(out_port_idx.unwrap())
+    .ok_or_else(|| report!(DeviceDetectionFailed).attach_printable("invalid output port index"))?;
+  let input_port_name = &in_names[in_port_idx.unwrap()];
 
   info!("detected lumatone ports: in: {input_port_name}, out: {output_port_name}");
 
-  let device = LumatoneDevice::new(&output_port_name, &input_port_name);
+  let device = LumatoneDevice::new(output_port_name, input_port_name);
   Ok(device)
 }
@@ -1,12 +1,30 @@
 #![allow(dead_code)]
 
-use log::{debug, warn};
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Weak,
+  },
+  time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
 use midir::{MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
-use tokio::sync::mpsc;
+use tokio::sync::{
+  broadcast,
+  mpsc::{self, error::TrySendError},
+};
 
 use crate::sysex::SYSEX_START;
 
-use super::{error::LumatoneMidiError, sysex::EncodedSysex};
+use super::{
+  channels::{Channel, ChannelCapacities},
+  error::LumatoneMidiError,
+  events::ChannelMessage,
+  ports::find_port,
+  sysex::EncodedSysex,
+  timing::TimedMessage,
+};
 use error_stack::{report, IntoReport, Result, ResultExt};
 
 /// Identifies the MIDI input and output ports that the Lumatone is connected to.
@@ -15,6 +33,7 @@ use error_stack::{report, IntoReport, Result, ResultExt};
 pub struct LumatoneDevice {
   out_port_name: String,
   in_port_name: String,
+  capacities: ChannelCapacities,
 }
 
 impl LumatoneDevice {
@@ -22,12 +41,50 @@ impl LumatoneDevice {
     LumatoneDevice {
       out_port_name: output_port_name.to_string(),
       in_port_name: input_port_name.to_string(),
+      capacities: ChannelCapacities::default(),
     }
   }
 
+  /// Sets how much the connection and the driver buffer (see [crate::channels]).
+  pub fn with_capacities(mut self, capacities: ChannelCapacities) -> Self {
+    self.capacities = capacities;
+    self
+  }
+
+  pub fn capacities(&self) -> ChannelCapacities {
+    self.capacities
+  }
+
+  pub fn out_port_name(&self) -> &str {
+    &self.out_port_name
+  }
+
+  pub fn in_port_name(&self) -> &str {
+    &self.in_port_name
+  }
+
   /// Connects to the MIDI ports for this LumatoneDevice.
   /// Returns a [`LumatoneIO`] on success.
   pub fn connect(&self) -> Result<LumatoneIO, LumatoneMidiError> {
+    let capacity = self.capacities.get(Channel::Events);
+    let (events_tx, _) = broadcast::channel(capacity);
+    let (timed_events_tx, _) = broadcast::channel(capacity);
+    self.connect_with_events(
+      Arc::new(events_tx),
+      Arc::new(timed_events_tx),
+      Arc::new(AtomicU64::new(0)),
+    )
+  }
+
+  /// Connects to the MIDI ports, broadcasting channel voice messages on `events` and
+  /// `timed_events` and counting dropped sysex in `dropped`, so that a reconnected
+  /// [LumatoneIO] keeps its subscribers and count.
+  fn connect_with_events(
+    &self,
+    events: Arc<broadcast::Sender<ChannelMessage>>,
+    timed_events: Arc<broadcast::Sender<TimedMessage>>,
+    dropped: Arc<AtomicU64>,
+  ) -> Result<LumatoneIO, LumatoneMidiError> {
     use LumatoneMidiError::DeviceConnectionError;
 
     let client_name = "lumatone-rs";
@@ -43,21 +100,42 @@ impl LumatoneDevice {
     let out_port =
       get_port_by_name(&output, &self.out_port_name).change_context(DeviceConnectionError)?;
 
-    let buf_size = 32;
-    let (incoming_tx, incoming_messages) = mpsc::channel(buf_size);
+    let (incoming_tx, incoming_messages) = mpsc::channel(self.capacities.get(Channel::Incoming));
+    let callback_events = events.clone();
+    let callback_timed_events = timed_events.clone();
+    let callback_dropped = dropped.clone();
 
     let input_conn = input
       .connect(
         &in_port,
         &self.in_port_name,
-        move |_, msg, _| {
+        move |stamp, msg, _| {
+          // before anything else, so the time doesn't include our own processing
+          let received = Instant::now();
           let msg = msg.to_vec();
-          if msg.is_empty() || msg[0] != SYSEX_START {
-            debug!("received non sysex message, ignoring");
+          if msg.is_empty() {
             return;
           }
-          if let Err(err) = incoming_tx.blocking_send(msg) {
-            warn!("error sending incoming message on channel: {err}");
+          if msg[0] != SYSEX_START {
+            match ChannelMessage::from_bytes(&msg) {
+              Ok(event) => {
+                // send only fails if there are no subscribers, which is fine
+                let _ = callback_events.send(event);
+                let timed = TimedMessage::new(event, received, Duration::from_micros(stamp));
+                let _ = callback_timed_events.send(timed);
+              }
+              Err(_) => debug!("received non sysex message, ignoring"),
+            }
+            return;
+          }
+          // don't hold up the MIDI thread if the driver loop has fallen behind
+          match incoming_tx.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) => {
+              callback_dropped.fetch_add(1, Ordering::Relaxed);
+              warn!("driver is behind, dropping incoming message {msg:02x?}");
+            }
+            Err(TrySendError::Closed(_)) => debug!("driver has stopped, ignoring message"),
           }
         },
         (),
@@ -73,9 +151,13 @@ impl LumatoneDevice {
           .attach_printable(format!("midi input connection error: {e}")))?;
 
     let io = LumatoneIO {
+      device: self.clone(),
       input_conn,
       output_conn,
       incoming_messages,
+      events,
+      timed_events,
+      dropped,
     };
     Ok(io)
   }
@@ -83,12 +165,24 @@ impl LumatoneDevice {
 
 /// Represents an open connection to a Lumatone device that can send and receive messages.
 pub struct LumatoneIO {
+  device: LumatoneDevice,
   input_conn: MidiInputConnection<()>,
   output_conn: MidiOutputConnection,
 
   /// All incoming MIDI messages will be pushed onto this channel.
   // TODO: should this be a broadcast instead?
   pub incoming_messages: mpsc::Receiver<EncodedSysex>,
+
+  /// Channel voice messages (notes, controllers, etc) are broadcast to all subscribers.
+  /// The sender is shared with the input callback, so the channel closes when the
+  /// connection does.
+  events: Arc<broadcast::Sender<ChannelMessage>>,
+
+  /// The same messages, with the time they arrived. See [crate::timing].
+  timed_events: Arc<broadcast::Sender<TimedMessage>>,
+
+  /// Incoming sysex messages dropped because `incoming_messages` was full.
+  dropped: Arc<AtomicU64>,
 }
 
 impl LumatoneIO {
@@ -101,6 +195,45 @@ impl LumatoneIO {
       .change_context(LumatoneMidiError::DeviceSendError)
   }
 
+  /// Returns a receiver for channel voice messages sent by the device when it's played.
+  pub fn subscribe_events(&self) -> broadcast::Receiver<ChannelMessage> {
+    self.events.subscribe()
+  }
+
+  /// Returns a weak reference to the event sender, which can be used to subscribe to
+  /// events for as long as this connection stays open.
+  pub fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+    Arc::downgrade(&self.events)
+  }
+
+  /// How many incoming sysex messages have been dropped because they weren't received in
+  /// time (see [crate::channels]), including on earlier connections.
+  pub fn dropped_messages(&self) -> u64 {
+    self.dropped.load(Ordering::Relaxed)
+  }
+
+  /// Like [LumatoneIO::events_sender], for messages stamped with the time they arrived.
+  pub fn timed_events_sender(&self) -> Weak<broadcast::Sender<TimedMessage>> {
+    Arc::downgrade(&self.timed_events)
+  }
+
+  /// Closes the MIDI connections and opens them again, e.g. after the host wakes from
+  /// sleep and the old connections have gone stale. Event subscribers stay subscribed.
+  /// The ports are looked up by name again, so they're found even if the OS renamed them
+  /// (see [get_port_by_name]).
+  ///
+  /// Messages that arrived on the old connection but haven't been received yet are lost.
+  pub fn reconnect(&mut self) -> Result<(), LumatoneMidiError> {
+    let io = self.device.connect_with_events(
+      self.events.clone(),
+      self.timed_events.clone(),
+      self.dropped.clone(),
+    )?;
+    let old = std::mem::replace(self, io);
+    old.close();
+    Ok(())
+  }
+
   /// Closes MIDI connections and consumes `self`, making this LumatoneIO unusable.
   /// A new connection can be established using [`LumatoneDevice::connect`].
   pub fn close(self) {
@@ -109,18 +242,30 @@ impl LumatoneIO {
   }
 }
 
-fn get_port_by_name<IO: MidiIO>(io: &IO, name: &str) -> Result<IO::Port, LumatoneMidiError> {
-  for p in io.ports() {
-    let port_name = io.port_name(&p).map_err(|e| {
+/// Returns the port with the given name, or an error if there isn't one.
+///
+/// If no port has exactly that name, this falls back to a port whose name only differs by
+/// platform decorations, e.g. a changed ALSA address (see [find_port]).
+pub fn get_port_by_name<IO: MidiIO>(io: &IO, name: &str) -> Result<IO::Port, LumatoneMidiError> {
+  let ports = io.ports();
+  let mut names = Vec::with_capacity(ports.len());
+  for p in &ports {
+    let port_name = io.port_name(p).map_err(|e| {
       report!(LumatoneMidiError::DeviceConnectionError)
         .attach_printable(format!("unable to get port with name '{name}': {e}"))
     })?;
-    if port_name == name {
-      return Ok(p);
+    names.push(port_name);
+  }
+  match find_port(&names, name) {
+    Some(i) => {
+      if names[i] != name {
+        info!("no port named '{name}', using '{}'", names[i]);
+      }
+      Ok(ports[i].clone())
     }
+    None => Err(
+      report!(LumatoneMidiError::DeviceConnectionError)
+        .attach_printable(format!("unable to get port with name: {name}")),
+    ),
   }
-  Err(
-    report!(LumatoneMidiError::DeviceConnectionError)
-      .attach_printable(format!("unable to get port with name: {name}")),
-  )
 }
@@ -0,0 +1,187 @@
+//! Works out why the device can't be found, for troubleshooting.
+//!
+//! When detection fails, [detect_device](crate::detect::detect_device) attaches the
+//! [DiagnosticHint]s that apply to its error report. They're printed with the report, and
+//! can be pulled out as values with [hints], e.g. to explain to the user why no ports are
+//! visible instead of just "no port found":
+//!
+//! ```ignore
+//! if let Err(report) = detect_device().await {
+//!   for hint in hints(&report) {
+//!     println!("{hint}");
+//!   }
+//! }
+//! ```
+//!
+//! Some hints come from what detection saw (no ports at all, ports that couldn't be
+//! opened), and some from probing the platform's MIDI system, see [probe_platform].
+
+use std::fmt::Display;
+
+use error_stack::Report;
+
+use super::ports::lumatone_ports;
+
+#[cfg(target_os = "linux")]
+const ALSA_SEQUENCER_DEVICE: &str = "/dev/snd/seq";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticHint {
+  /// The ALSA sequencer device doesn't exist, usually because the `snd-seq` kernel module
+  /// isn't loaded.
+  AlsaSequencerMissing,
+
+  /// The ALSA sequencer device exists, but this user can't open it.
+  AlsaSequencerPermission { path: String },
+
+  /// Creating a MIDI client failed, so the OS's MIDI service isn't available. On macOS
+  /// this is the CoreMIDI server.
+  MidiServiceUnavailable(String),
+
+  /// The MIDI system works, but no ports are visible.
+  NoPorts,
+
+  /// There are MIDI ports, but none of them look like a Lumatone. It may be connected
+  /// through a MIDI interface, or not at all.
+  NoLumatonePorts { ports: usize },
+
+  /// A port couldn't be opened. On Windows only one application can have a port open, so
+  /// this usually means another application is using the device.
+  PortUnavailable { port: String },
+}
+
+impl DiagnosticHint {
+  /// What the user can do about it.
+  pub fn suggestion(&self) -> &'static str {
+    use DiagnosticHint::*;
+    match self {
+      AlsaSequencerMissing => "load the ALSA sequencer with `sudo modprobe snd-seq`",
+      AlsaSequencerPermission { .. } => {
+        "add your user to the `audio` group, then log out and back in"
+      }
+      MidiServiceUnavailable(_) if cfg!(target_os = "macos") => {
+        "open Audio MIDI Setup to restart the MIDI server, or restart the computer"
+      }
+      MidiServiceUnavailable(_) => "check that the OS's MIDI service is installed and running",
+      NoPorts => "check the USB cable, and that the Lumatone is switched on",
+      NoLumatonePorts { .. } => {
+        "check the USB cable, or pass the port names if it's connected through a MIDI interface"
+      }
+      PortUnavailable { .. } if cfg!(target_os = "windows") => {
+        "close other applications that use the Lumatone, e.g. the Lumatone Editor or a DAW"
+      }
+      PortUnavailable { .. } => "check whether another application is using the port",
+    }
+  }
+}
+
+impl Display for DiagnosticHint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use DiagnosticHint::*;
+    match self {
+      AlsaSequencerMissing => write!(f, "the ALSA sequencer isn't available")?,
+      AlsaSequencerPermission { path } => write!(f, "no permission to open {path}")?,
+      MidiServiceUnavailable(reason) => write!(f, "the MIDI service isn't available: {reason}")?,
+      NoPorts => write!(f, "no MIDI ports are visible")?,
+      NoLumatonePorts { ports } => write!(f, "none of the {ports} MIDI ports is a Lumatone")?,
+      PortUnavailable { port } => write!(f, "unable to open port {port}")?,
+    }
+    write!(f, " - {}", self.suggestion())
+  }
+}
+
+/// Checks the platform's MIDI system for problems that would hide every port.
+pub fn probe_platform() -> Vec<DiagnosticHint> {
+  #[cfg(target_os = "linux")]
+  {
+    probe_alsa(std::path::Path::new(ALSA_SEQUENCER_DEVICE))
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    vec![]
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn probe_alsa(device: &std::path::Path) -> Vec<DiagnosticHint> {
+  if !device.exists() {
+    return vec![DiagnosticHint::AlsaSequencerMissing];
+  }
+  match std::fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open(device)
+  {
+    Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+      vec![DiagnosticHint::AlsaSequencerPermission {
+        path: device.display().to_string(),
+      }]
+    }
+    _ => vec![],
+  }
+}
+
+/// Returns the hints that explain what detection saw: the names of the ports it found,
+/// and the ports it couldn't open. Includes [probe_platform]'s hints when there are no
+/// ports.
+pub fn diagnose<S: AsRef<str>>(port_names: &[S], unavailable: &[String]) -> Vec<DiagnosticHint> {
+  let mut hints = vec![];
+  if port_names.is_empty() {
+    hints.extend(probe_platform());
+    hints.push(DiagnosticHint::NoPorts);
+  } else if lumatone_ports(port_names).is_empty() {
+    hints.push(DiagnosticHint::NoLumatonePorts {
+      ports: port_names.len(),
+    });
+  }
+  hints.extend(
+    unavailable
+      .iter()
+      .map(|port| DiagnosticHint::PortUnavailable { port: port.clone() }),
+  );
+  hints
+}
+
+/// Returns the hints attached to an error report.
+pub fn hints<C>(report: &Report<C>) -> Vec<&DiagnosticHint> {
+  report
+    .frames()
+    .filter_map(|frame| frame.downcast_ref::<DiagnosticHint>())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diagnose() {
+    let none: [&str; 0] = [];
+    let hints = diagnose(&none, &[]);
+    assert_eq!(hints.last(), Some(&DiagnosticHint::NoPorts));
+
+    let hints = diagnose(&["IAC Driver Bus 1"], &[]);
+    assert_eq!(hints, vec![DiagnosticHint::NoLumatonePorts { ports: 1 }]);
+
+    let hints = diagnose(&["Lumatone"], &["Lumatone".to_string()]);
+    assert_eq!(
+      hints,
+      vec![DiagnosticHint::PortUnavailable {
+        port: "Lumatone".to_string()
+      }]
+    );
+    assert!(hints[0]
+      .to_string()
+      .starts_with("unable to open port Lumatone - "));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn test_probe_missing_alsa_sequencer() {
+    let missing = std::env::temp_dir().join("lumatone-no-such-seq");
+    assert_eq!(
+      probe_alsa(&missing),
+      vec![DiagnosticHint::AlsaSequencerMissing]
+    );
+  }
+}
@@ -10,8 +10,101 @@
 //! `(MidiDriver, Future)`. The Future needs to be spawned and `await`ed in order to start the
 //! driver's event loop.
 //!
-//! To shutdown the driver loop, use [MidiDriver::done].
+//! To shutdown the driver loop, use [MidiDriver::done], or cancel the [CancellationToken]
+//! passed to [MidiDriver::with_shutdown_token].
 //!
+//! [MidiDriver] is cheap to clone, and clones can be moved to other tasks to share a single
+//! device connection. Each clone is a separate "client" of the driver, and the send queue
+//! interleaves commands from different clients round-robin, so one client submitting a long
+//! burst of commands can't starve the others.
+//!
+//! For high-volume commands where the response doesn't matter (e.g. LED animation frames),
+//! [`send_and_forget`](MidiDriver::send_and_forget) skips response tracking entirely. After
+//! sending, the driver waits a short fixed interval in the `WaitingToSend` state instead
+//! of `AwaitingResponse`, so the device isn't flooded.
+//!
+//! Commands can also carry a deadline (see [`send_with_deadline`](MidiDriver::send_with_deadline)
+//! and [`send_and_forget_with_deadline`](MidiDriver::send_and_forget_with_deadline)). If a
+//! command is still queued when its deadline passes, the driver drops it instead of sending
+//! it, and fails it with [LumatoneMidiError::CommandShed]. After the device has been busy
+//! for a while, this skips straight to the newest LED updates instead of replaying stale ones.
+//!
+//! Key colors that must arrive eventually, like a key going back to its resting color, can
+//! be sent with [`send_and_forget_coalesced`](MidiDriver::send_and_forget_coalesced)
+//! instead. A queued color for the same key from the same handle is replaced rather than
+//! added to, so the queue holds at most one update per key and the newest one is never
+//! dropped.
+//!
+//! Commands that depend on each other, like the chunks of a table upload, can be sent as a
+//! [CommandGroup] with [`send_group`](MidiDriver::send_group). Group members are sent in
+//! order, and if one fails (or times out, or misses its deadline), the members that haven't
+//! been sent yet are dropped and fail with [LumatoneMidiError::GroupAborted]. Every member
+//! inherits the group's [Priority]: high priority commands are queued ahead of normal ones,
+//! so a long upload isn't held up behind other clients' traffic.
+//!
+//! High priority traffic doesn't starve everything else, though. While commands of both
+//! priorities are waiting, the driver sends one normal priority command after every few
+//! high priority ones (see [DriverOptions::with_priority_ratio]), so an LED animation keeps
+//! running during a long upload instead of freezing until it finishes.
+//!
+//! The queue is bounded: once [Channel::SendQueue] commands are waiting, the driver stops
+//! taking in more, and `send` waits for room instead. See [crate::channels] for how much
+//! the driver buffers, and [DriverOptions] to change it.
+//!
+//! ## Failures
+//!
+//! When something goes wrong inside the driver loop, the state machine enters the `Failed`
+//! state, and the failure is classified with a [FailureKind]:
+//!
+//! - Transient failures (I/O errors while sending) restart the driver after a short delay,
+//!   in `Idle` or, if commands are waiting, `WaitingToSend`. The command that couldn't be
+//!   sent goes back to the front of the send queue, so it's retried first, and the rest of
+//!   the queue is kept. If failures keep happening, the [RecoveryPolicy] gives up and fails
+//!   every queued command.
+//! - Unrecoverable failures (invalid state transitions, which mean the driver and the device
+//!   disagree about the protocol) stop the driver loop.
+//!
+//! When the driver stops, either way, the queued commands get a
+//! [LumatoneMidiError::DriverFailed] error with the classification.
+//!
+//! While waiting for a response, messages that don't answer the command in flight (a
+//! different command id or board index, e.g. a late answer to a command that timed out)
+//! are logged, counted in [MetricsSnapshot::unexpected], and otherwise ignored. The driver
+//! keeps waiting for the matching response, with the original receive timeout.
+//!
+//! ## Calibration
+//!
+//! While the wheel or expression pedal calibration routine runs, the device sends its live
+//! readings every 100ms. These aren't answers to any command, so the driver decodes them and
+//! forwards them to [MidiDriver::subscribe_calibration] receivers instead (see
+//! [crate::calibration]).
+//!
+//! ## Sleep and resume
+//!
+//! MIDI connections often go stale while the host is asleep. The driver checks its clocks
+//! every [SUSPEND_CHECK_INTERVAL] to notice when the host has been suspended, and then
+//! replaces the connection with [Transport::reconnect]. It does the same when the
+//! connection closes. Commands that were in flight time out as usual, and queued commands
+//! are sent on the new connection. Subscribe with [MidiDriver::subscribe_connection] to be
+//! told about it (see [crate::suspend]).
+//!
+//! ## Time
+//!
+//! The timeouts, send delays and command deadlines all come from the driver's [Clock],
+//! which is real time unless the driver was created with [MidiDriver::with_clock]. Tests
+//! can pass a [MockClock](crate::clock::MockClock) to step through timeouts (see
+//! [crate::clock]).
+//!
+//! ## Debugging
+//!
+//! Every state machine step is described by a [Transition] (the old state, the action that
+//! was applied, the new state and the effect it requested). Transitions are logged at
+//! `trace` level under the `lumatone_midi::driver::transitions` target, with `from`,
+//! `action`, `to` and `effect` as key-value fields, and can be received as values with
+//! [MidiDriver::subscribe_transitions]. The driver's other log events also carry the
+//! command, message and state they concern as fields. The driver also keeps the last
+//! [TRANSITION_HISTORY_SIZE] transitions, and logs them all if it fails when
+//! [MidiDriver::set_dump_transitions_on_failure] is turned on.
 //!
 //! ## State machine internals
 //!
@@ -62,52 +155,331 @@
 //! ```
 
 use super::{
-  commands::Command,
+  brightness::Brightness,
+  calibration::CalibrationStatus,
+  channels::{Channel, ChannelCapacities},
+  clock::{Clock, Sleep, TokioClock},
+  commands::{raw_sysex, Command},
   constants::ResponseStatusCode,
-  device::{LumatoneDevice, LumatoneIO},
-  error::LumatoneMidiError,
-  responses::Response,
+  device::LumatoneDevice,
+  error::{FailureKind, LumatoneMidiError},
+  events::ChannelMessage,
+  firmware::FirmwareSupport,
+  metrics::{DriverMetrics, MetricsSnapshot},
+  responses::{FirmwareVersion, Response},
+  shutdown::CancellationToken,
+  suspend::{ClockJumpDetector, ConnectionEvent, SUSPEND_CHECK_INTERVAL},
   sysex::{is_response_to_message, message_answer_code, EncodedSysex},
+  timing::{estimate_input_latency, TimedMessage},
+  traffic::{TrafficEntry, TrafficLog},
+  transport::Transport,
 };
 use std::{
-  collections::VecDeque,
+  collections::{HashMap, VecDeque},
   fmt::{Debug, Display},
-  pin::Pin,
-  time::Duration,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, RwLock, Weak,
+  },
+  time::{Duration, SystemTime},
 };
 
 use futures::{Future, TryFutureExt};
-use log::{debug, error, info, warn};
+use log::{debug, error, info, log, trace, warn, Level};
 use tokio::{
-  sync::mpsc,
-  time::{sleep, Sleep},
+  sync::{broadcast, mpsc},
+  time::{interval, Instant, MissedTickBehavior},
 };
 
 use crate::driver::Action::{MessageSent, QueueEmpty, ResponseDispatched};
-use error_stack::{report, IntoReport, Report, Result, ResultExt};
 use crate::sysex::to_hex_debug_str;
+use error_stack::{report, IntoReport, Report, Result, ResultExt};
 
 /// Result type returned in response to a command submission
 type ResponseResult = Result<Response, LumatoneMidiError>;
 
+/// How long to wait after sending a fire-and-forget command before sending the next one.
+/// Streams of [MidiDriver::send_and_forget] commands faster than this fall behind.
+pub const SEND_AND_FORGET_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many times a command is retried while the device reports it's busy, before the
+/// command fails with [LumatoneMidiError::DeviceBusy].
+const MAX_BUSY_RETRIES: usize = 5;
+
+/// How long to wait after a restart before sending the preserved queue.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// How many [Transition]s the driver keeps for [MidiDriver::set_dump_transitions_on_failure].
+pub const TRANSITION_HISTORY_SIZE: usize = 64;
+
+const TRANSITIONS_LOG_TARGET: &str = "lumatone_midi::driver::transitions";
+
+/// Identifies the [MidiDriver] handle that submitted a command.
+type ClientId = usize;
+
 /// Request to send a command to the device, with a channel to send a response on.
 #[derive(Clone)]
 struct CommandSubmission {
   command: Command,
-  response_tx: mpsc::Sender<ResponseResult>,
+  /// Where to send the command's response. If `None`, the command is "fire and forget",
+  /// and the driver won't wait for a response.
+  response_tx: Option<mpsc::Sender<ResponseResult>>,
+  client_id: ClientId,
+  /// Which payload layouts to use when encoding the command and decoding its response.
+  firmware: FirmwareSupport,
+  /// If the command is still queued at this time, it's dropped instead of being sent.
+  deadline: Option<Instant>,
+  priority: Priority,
+  /// Shared by all members of a [CommandGroup].
+  group: Option<Arc<GroupState>>,
+  /// How many times the command has been retried because the device was busy.
+  busy_retries: usize,
+  /// How many times the command has been sent, counting retries and resends after a
+  /// restart.
+  sends: usize,
+  /// Whether the command replaces a queued color for the same key from the same client.
+  coalesce: bool,
+}
+
+/// How urgently a command should be sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+  /// Interleaved fairly with other clients' commands.
+  #[default]
+  Normal,
+  /// Queued ahead of all normal priority commands.
+  High,
+}
+
+/// Commands that must be sent in order, and are abandoned together if one fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandGroup {
+  pub commands: Vec<Command>,
+  pub priority: Priority,
+}
+
+impl CommandGroup {
+  pub fn new(commands: Vec<Command>) -> Self {
+    CommandGroup {
+      commands,
+      priority: Priority::Normal,
+    }
+  }
+
+  pub fn with_priority(mut self, priority: Priority) -> Self {
+    self.priority = priority;
+    self
+  }
+}
+
+#[derive(Debug, Default)]
+struct GroupState {
+  aborted: AtomicBool,
 }
 
 impl CommandSubmission {
   /// Creates a new CommandSubmission and returns it, along with the receive channel
   /// for the command's [ResponseResult].
+  #[cfg(test)]
   fn new(command: Command) -> (Self, mpsc::Receiver<ResponseResult>) {
+    Self::for_client(command, 0)
+  }
+
+  /// Like [CommandSubmission::new], but for a specific client.
+  fn for_client(command: Command, client_id: ClientId) -> (Self, mpsc::Receiver<ResponseResult>) {
     let (response_tx, response_rx) = mpsc::channel(1);
     let sub = CommandSubmission {
       command,
-      response_tx,
+      response_tx: Some(response_tx),
+      client_id,
+      firmware: FirmwareSupport::default(),
+      deadline: None,
+      priority: Priority::Normal,
+      group: None,
+      busy_retries: 0,
+      sends: 0,
+      coalesce: false,
     };
     (sub, response_rx)
   }
+
+  /// Creates a CommandSubmission that doesn't expect a response.
+  fn fire_and_forget(command: Command, client_id: ClientId) -> Self {
+    CommandSubmission {
+      command,
+      response_tx: None,
+      client_id,
+      firmware: FirmwareSupport::default(),
+      deadline: None,
+      priority: Priority::Normal,
+      group: None,
+      busy_retries: 0,
+      sends: 0,
+      coalesce: false,
+    }
+  }
+
+  fn with_firmware(mut self, firmware: FirmwareSupport) -> Self {
+    self.firmware = firmware;
+    self
+  }
+
+  /// Scales the command's LED color, if it has one (see [crate::brightness]).
+  fn with_brightness(mut self, brightness: &Brightness) -> Self {
+    self.command = brightness.apply(self.command);
+    self
+  }
+
+  fn with_deadline(mut self, deadline: Instant) -> Self {
+    self.deadline = Some(deadline);
+    self
+  }
+
+  fn coalesced(mut self) -> Self {
+    self.coalesce = true;
+    self
+  }
+
+  /// Whether this command should take the place of `queued` instead of being queued too.
+  fn replaces(&self, queued: &CommandSubmission) -> bool {
+    match (&self.command, &queued.command) {
+      (Command::SetKeyColor { location: a, .. }, Command::SetKeyColor { location: b, .. }) => {
+        self.coalesce && queued.coalesce && self.client_id == queued.client_id && a == b
+      }
+      _ => false,
+    }
+  }
+
+  fn is_expired(&self, now: Instant) -> bool {
+    self.deadline.is_some_and(|deadline| deadline <= now)
+  }
+
+  fn in_group(mut self, group: Arc<GroupState>, priority: Priority) -> Self {
+    self.group = Some(group);
+    self.priority = priority;
+    self
+  }
+
+  /// Marks the rest of this command's group as abandoned, if it's in one.
+  fn abort_group(&self) {
+    if let Some(group) = &self.group {
+      group.aborted.store(true, Ordering::Relaxed);
+    }
+  }
+
+  fn group_aborted(&self) -> bool {
+    self
+      .group
+      .as_ref()
+      .is_some_and(|g| g.aborted.load(Ordering::Relaxed))
+  }
+
+  /// Whether the command should be dropped from the queue instead of sent.
+  fn should_shed(&self, now: Instant) -> bool {
+    self.is_expired(now) || self.group_aborted()
+  }
+
+  fn expects_response(&self) -> bool {
+    self.response_tx.is_some()
+  }
+
+  /// Whether `msg` is the device's answer to this command: it has the same command id and
+  /// board index.
+  fn is_answered_by(&self, msg: &[u8]) -> bool {
+    let outgoing = self.command.to_sysex_message_for(&self.firmware);
+    is_response_to_message(&outgoing, msg)
+  }
+}
+
+/// Adds a command to the send queue, keeping commands from different clients interleaved.
+///
+/// A client's Nth queued command is placed after every other client's Nth command, so
+/// with a single client this is the same as `push_back`. High priority commands go ahead of
+/// all normal priority ones, in the order they were submitted. A coalesced key color takes
+/// the place of the same client's queued color for that key, if there is one.
+fn enqueue(send_queue: &mut VecDeque<CommandSubmission>, cmd: CommandSubmission) {
+  if let Some(queued) = send_queue.iter_mut().find(|queued| cmd.replaces(queued)) {
+    *queued = cmd;
+    return;
+  }
+
+  let high_priority = send_queue
+    .iter()
+    .take_while(|c| c.priority == Priority::High)
+    .count();
+  if cmd.priority == Priority::High {
+    send_queue.insert(high_priority, cmd);
+    return;
+  }
+
+  let round = send_queue
+    .iter()
+    .skip(high_priority)
+    .filter(|c| c.client_id == cmd.client_id)
+    .count();
+
+  let mut rounds: HashMap<ClientId, usize> = HashMap::new();
+  let position = send_queue.iter().skip(high_priority).position(|c| {
+    let r = rounds.entry(c.client_id).or_insert(0);
+    *r += 1;
+    *r > round + 1
+  });
+
+  match position {
+    Some(i) => send_queue.insert(high_priority + i, cmd),
+    None => send_queue.push_back(cmd),
+  }
+}
+
+/// How many high priority commands are sent for each normal priority one while both are
+/// waiting, unless set with [DriverOptions::with_priority_ratio].
+pub const DEFAULT_PRIORITY_RATIO: usize = 4;
+
+/// Shares the device between high and normal priority commands (see
+/// [DriverOptions::with_priority_ratio]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PriorityScheduler {
+  ratio: usize,
+  /// High priority commands sent in a row while normal priority ones were waiting.
+  high_run: usize,
+}
+
+impl PriorityScheduler {
+  fn new(ratio: usize) -> Self {
+    PriorityScheduler {
+      ratio: ratio.max(1),
+      high_run: 0,
+    }
+  }
+
+  /// Takes the next command to send off the queue: the front of the queue, unless
+  /// `ratio` high priority commands have gone ahead of waiting normal priority
+  /// ones in a row, in which case it's the first normal priority command.
+  fn dequeue(&mut self, send_queue: &mut VecDeque<CommandSubmission>) -> Option<CommandSubmission> {
+    let normal = send_queue
+      .iter()
+      .position(|c| c.priority == Priority::Normal);
+    match normal {
+      Some(0) | None => {
+        self.high_run = 0;
+        send_queue.pop_front()
+      }
+      Some(i) if self.high_run >= self.ratio => {
+        self.high_run = 0;
+        send_queue.remove(i)
+      }
+      Some(_) => {
+        self.high_run += 1;
+        send_queue.pop_front()
+      }
+    }
+  }
+}
+
+impl Default for PriorityScheduler {
+  fn default() -> Self {
+    PriorityScheduler::new(DEFAULT_PRIORITY_RATIO)
+  }
 }
 
 impl Debug for CommandSubmission {
@@ -143,6 +515,12 @@ enum State {
     command_sent: CommandSubmission,
   },
 
+  /// We've sent a message that doesn't need a response, and are waiting a moment before
+  /// sending the next one.
+  WaitingToSend {
+    send_queue: VecDeque<CommandSubmission>,
+  },
+
   /// We've unpacked a Response from a device message and are ready to
   /// notify the user.
   ProcessingResponse {
@@ -159,8 +537,121 @@ enum State {
     to_retry: CommandSubmission,
   },
 
-  /// Something has gone horribly wrong, and we've shut down the state machine loop.
-  Failed(Report<LumatoneMidiError>),
+  /// Something has gone wrong. Depending on the [Failure]'s kind, the driver loop either
+  /// restarts or shuts down.
+  Failed(Failure),
+}
+
+/// Why the state machine failed, and the commands it was still holding when it did.
+struct Failure {
+  error: Report<LumatoneMidiError>,
+  kind: FailureKind,
+  pending: VecDeque<CommandSubmission>,
+}
+
+impl Failure {
+  fn unrecoverable(error: Report<LumatoneMidiError>, pending: VecDeque<CommandSubmission>) -> Self {
+    Failure {
+      error,
+      kind: FailureKind::Unrecoverable,
+      pending,
+    }
+  }
+
+  fn transient(error: Report<LumatoneMidiError>, pending: VecDeque<CommandSubmission>) -> Self {
+    Failure {
+      error,
+      kind: FailureKind::Transient,
+      pending,
+    }
+  }
+}
+
+impl Debug for Failure {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Failure")
+      .field("error", &self.error)
+      .field("kind", &self.kind)
+      .field("pending", &self.pending.len())
+      .finish()
+  }
+}
+
+impl Display for Failure {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} failure: {}", self.kind, self.error)
+  }
+}
+
+/// Limits how often the driver restarts after [FailureKind::Transient] failures. If there are
+/// more than `max_restarts` failures within `window`, the next one is treated as
+/// unrecoverable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryPolicy {
+  pub max_restarts: usize,
+  pub window: Duration,
+}
+
+impl RecoveryPolicy {
+  /// Never restarts, so every failure stops the driver.
+  pub fn never() -> Self {
+    RecoveryPolicy {
+      max_restarts: 0,
+      window: Duration::ZERO,
+    }
+  }
+}
+
+impl Default for RecoveryPolicy {
+  fn default() -> Self {
+    RecoveryPolicy {
+      max_restarts: 3,
+      window: Duration::from_secs(10),
+    }
+  }
+}
+
+/// How a driver created with [MidiDriver::with_options] keeps time and buffers messages.
+#[derive(Debug, Clone)]
+pub struct DriverOptions {
+  clock: Arc<dyn Clock>,
+  capacities: ChannelCapacities,
+  priority_ratio: usize,
+}
+
+impl Default for DriverOptions {
+  fn default() -> Self {
+    DriverOptions {
+      clock: Arc::new(TokioClock),
+      capacities: ChannelCapacities::default(),
+      priority_ratio: DEFAULT_PRIORITY_RATIO,
+    }
+  }
+}
+
+impl DriverOptions {
+  /// Sets where the driver's timeouts and deadlines come from (see [crate::clock]).
+  pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+    self.clock = Arc::new(clock);
+    self
+  }
+
+  /// Sets how many commands and subscriber messages the driver buffers (see
+  /// [crate::channels]). The transport's own channels are set when it's created, e.g. with
+  /// [LumatoneDevice::with_capacities].
+  pub fn with_capacities(mut self, capacities: ChannelCapacities) -> Self {
+    self.capacities = capacities;
+    self
+  }
+
+  /// Sets how many high priority commands (e.g. a [CommandGroup] upload) are sent for each
+  /// normal priority one while both are waiting. The default, [DEFAULT_PRIORITY_RATIO],
+  /// gives an upload four fifths of the device's bandwidth and whatever else is running,
+  /// like an LED animation, the rest. Ratios below 1 are treated as 1.
+  pub fn with_priority_ratio(mut self, ratio: usize) -> Self {
+    self.priority_ratio = ratio;
+    self
+  }
 }
 
 impl Display for State {
@@ -178,6 +669,7 @@ impl Display for State {
         command_sent.command,
         send_queue.len()
       ),
+      WaitingToSend { send_queue } => write!(f, "WaitingToSend({} in queue)", send_queue.len()),
       ProcessingResponse {
         send_queue,
         command_sent,
@@ -231,8 +723,14 @@ enum Action {
   /// The retry timeout has tripped while waiting to retry a message send.
   ReadyToRetry,
 
+  /// The delay after sending a fire-and-forget message has elapsed.
+  ReadyToSend,
+
   /// The send queue is empty, and we can return to the Idle state.
   QueueEmpty,
+
+  /// Commands whose deadline passed have been dropped from the queue.
+  CommandsShed,
 }
 
 impl Display for Action {
@@ -246,7 +744,9 @@ impl Display for Action {
       ResponseDispatched => write!(f, "ResponseDispatched"),
       ResponseTimedOut => write!(f, "ResponseTimedOut"),
       ReadyToRetry => write!(f, "ReadyToRetry"),
+      ReadyToSend => write!(f, "ReadyToSend"),
       QueueEmpty => write!(f, "QueueEmpty"),
+      CommandsShed => write!(f, "CommandsShed"),
     }
   }
 }
@@ -263,10 +763,17 @@ enum Effect {
   /// The state machine wants to start the busy/retry timeout.
   StartRetryTimeout,
 
+  /// The state machine wants to wait a bit before sending the next message.
+  StartSendDelay,
+
   /// The state machine has received a response to a message and wants to notify
   /// the outside world about its success or failure.
   NotifyMessageResponse(CommandSubmission, Result<Response, LumatoneMidiError>),
 
+  /// The state machine has dropped queued commands whose deadline passed, and wants to
+  /// tell their senders.
+  ShedCommands(Vec<CommandSubmission>),
+
   /// The [State] we just [enter](State::enter)ed wants to transition to a new state,
   /// and we should feed the given [Action] into the state machine next.
   DispatchAction(Action),
@@ -279,14 +786,80 @@ impl Display for Effect {
       SendMidiMessage(cmd) => write!(f, "SendMidiMessage({})", cmd.command),
       StartReceiveTimeout => write!(f, "StartReceiveTimeout"),
       StartRetryTimeout => write!(f, "StartRetryTimeout"),
+      StartSendDelay => write!(f, "StartSendDelay"),
       NotifyMessageResponse(cmd, res) => {
         write!(f, "NotfiyMessageResponse({}, {:?})", cmd.command, res)
       }
+      ShedCommands(cmds) => write!(f, "ShedCommands({} commands)", cmds.len()),
       DispatchAction(action) => write!(f, "DispatchAction({})", action),
     }
   }
 }
 
+impl State {
+  /// How many commands are waiting to be sent.
+  fn queue_len(&self) -> usize {
+    use State::*;
+    match self {
+      Idle | Failed(_) => 0,
+      ProcessingQueue { send_queue }
+      | WaitingToSend { send_queue }
+      | AwaitingResponse { send_queue, .. }
+      | ProcessingResponse { send_queue, .. } => send_queue.len(),
+      WaitingToRetry { send_queue, .. } => send_queue.len() + 1,
+    }
+  }
+
+  /// Takes the commands this state is holding: the in-flight command, if any, followed by
+  /// the send queue.
+  fn into_pending(self) -> VecDeque<CommandSubmission> {
+    use State::*;
+    match self {
+      Idle => VecDeque::new(),
+      ProcessingQueue { send_queue } | WaitingToSend { send_queue } => send_queue,
+      AwaitingResponse {
+        mut send_queue,
+        command_sent,
+      }
+      | ProcessingResponse {
+        mut send_queue,
+        command_sent,
+        ..
+      }
+      | WaitingToRetry {
+        mut send_queue,
+        to_retry: command_sent,
+      } => {
+        send_queue.push_front(command_sent);
+        send_queue
+      }
+      Failed(failure) => failure.pending,
+    }
+  }
+}
+
+/// One step of the driver's state machine. The states, action and effect are rendered as
+/// strings, since the types themselves are internal to the driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+  pub time: SystemTime,
+  pub from: String,
+  pub action: String,
+  pub to: String,
+  /// The effect requested on entering the new state, if any.
+  pub effect: Option<String>,
+}
+
+impl Display for Transition {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} --{}--> {}", self.from, self.action, self.to)?;
+    if let Some(effect) = &self.effect {
+      write!(f, " => {effect}")?;
+    }
+    Ok(())
+  }
+}
+
 impl State {
   /// Applies an [Action] to the current [State] and returns the new State.
   /// Note that this may be the same as the original state, in cases where the given
@@ -295,14 +868,11 @@ impl State {
     use Action::*;
     use State::*;
 
-    // debug!("Current state: {} --- action: {}", self, action);
-
-    // debug!("handling action {:?}. current state: {:?}", action, self);
     match (action, self) {
       // Submitting a command in the Idle state transitions to ProcessingQueue, with the new message as the only queue member.
       (SubmitCommand(cmd), Idle) => {
         let mut send_queue = VecDeque::new();
-        send_queue.push_back(cmd);
+        enqueue(&mut send_queue, cmd);
         ProcessingQueue { send_queue }
       }
 
@@ -316,7 +886,7 @@ impl State {
         },
       ) => {
         // add new command to the send_queue
-        send_queue.push_back(cmd);
+        enqueue(&mut send_queue, cmd);
         AwaitingResponse {
           send_queue,
           command_sent,
@@ -333,17 +903,24 @@ impl State {
         },
       ) => {
         // add new command to the send queue
-        send_queue.push_back(cmd);
+        enqueue(&mut send_queue, cmd);
         WaitingToRetry {
           send_queue,
           to_retry,
         }
       }
 
+      // Submitting a command while we're waiting to send transitions to a new WaitingToSend state
+      // with the new command pushed onto the queue.
+      (SubmitCommand(cmd), WaitingToSend { mut send_queue }) => {
+        enqueue(&mut send_queue, cmd);
+        WaitingToSend { send_queue }
+      }
+
       // Submitting a command while we're processing the queue transitions to a new ProcessingQueue state
       // with the new command pushed onto the queue.
       (SubmitCommand(cmd), ProcessingQueue { mut send_queue }) => {
-        send_queue.push_back(cmd);
+        enqueue(&mut send_queue, cmd);
         ProcessingQueue { send_queue }
       }
 
@@ -357,7 +934,7 @@ impl State {
           response_msg,
         },
       ) => {
-        send_queue.push_back(cmd);
+        enqueue(&mut send_queue, cmd);
         ProcessingResponse {
           send_queue,
           command_sent,
@@ -366,31 +943,72 @@ impl State {
       }
 
       // Getting confirmation that a message was sent out while we're processing the queue transitions to
-      // the AwaitingResponse state.
-      (MessageSent(command_sent), ProcessingQueue { send_queue }) => AwaitingResponse {
-        send_queue,
-        command_sent,
-      },
+      // the AwaitingResponse state, or to WaitingToSend if the message doesn't need a response.
+      (MessageSent(command_sent), ProcessingQueue { send_queue }) => {
+        if command_sent.expects_response() {
+          AwaitingResponse {
+            send_queue,
+            command_sent,
+          }
+        } else {
+          WaitingToSend { send_queue }
+        }
+      }
 
-      // Receiving a message when we're awaiting a response transitions to ProcessingResponse
+      // Getting a ReadyToSend action while waiting to send transitions to ProcessingQueue.
+      (ReadyToSend, WaitingToSend { send_queue }) => ProcessingQueue { send_queue },
+
+      // Once expired commands have been dropped, carry on processing the rest of the queue.
+      (CommandsShed, ProcessingQueue { send_queue }) => ProcessingQueue { send_queue },
+
+      // Receiving the response to the command we sent transitions to ProcessingResponse.
+      // Any other message is logged and ignored, and we keep waiting.
       (
         MessageReceived(response_msg),
         AwaitingResponse {
           send_queue,
           command_sent,
         },
-      ) => ProcessingResponse {
-        send_queue,
-        command_sent,
-        response_msg,
-      },
+      ) => {
+        if command_sent.is_answered_by(&response_msg) {
+          ProcessingResponse {
+            send_queue,
+            command_sent,
+            response_msg,
+          }
+        } else {
+          warn!(
+            command:% = command_sent.command,
+            message:% = to_hex_debug_str(&response_msg);
+            "ignoring message that doesn't answer the command sent"
+          );
+          AwaitingResponse {
+            send_queue,
+            command_sent,
+          }
+        }
+      }
+
+      // Responses to fire-and-forget messages are expected to show up while we're waiting to send,
+      // and are ignored.
+      (MessageReceived(msg), state @ WaitingToSend { .. }) => {
+        debug!(message:% = to_hex_debug_str(&msg); "ignoring message while waiting to send");
+        state
+      }
+
+      // Calibration readings arrive unprompted while a calibration routine runs, and are
+      // forwarded to subscribers outside the state machine.
+      (MessageReceived(msg), state) if CalibrationStatus::from_sysex_message(&msg).is_some() => {
+        trace!(message:% = to_hex_debug_str(&msg); "calibration status");
+        state
+      }
 
       // Receiving a message when we're not expecting one logs a warning.
       (MessageReceived(msg), state) => {
         warn!(
-          "Message received when not awaiting response. msg: {:?} current state: {}",
-          to_hex_debug_str(&msg),
-          state
+          message:% = to_hex_debug_str(&msg),
+          state:% = state;
+          "ignoring message received when not awaiting a response"
         );
         state
       }
@@ -409,10 +1027,14 @@ impl State {
           command_sent,
           ..
         },
-      ) => WaitingToRetry {
-        send_queue,
-        to_retry: command_sent,
-      },
+      ) => {
+        let mut to_retry = command_sent;
+        to_retry.busy_retries += 1;
+        WaitingToRetry {
+          send_queue,
+          to_retry,
+        }
+      }
 
       // Getting a ResponseTimedOut action while waiting for a response logs a warning
       // and transitions to ProcessingQueue.
@@ -424,13 +1046,18 @@ impl State {
           command_sent,
         },
       ) => {
-        warn!("Timed out waiting for response to msg: {:?}", command_sent);
+        warn!(
+          command:% = command_sent.command,
+          client = command_sent.client_id;
+          "timed out waiting for response"
+        );
+        command_sent.abort_group();
         ProcessingQueue { send_queue }
       }
 
       // Getting a ResponseTimedOut when we're not waiting for a response logs a warning.
       (ResponseTimedOut, state) => {
-        warn!("Response timeout action received, but not awaiting response");
+        warn!(state:% = state; "response timeout received when not awaiting a response");
         state
       }
 
@@ -455,7 +1082,10 @@ impl State {
             "Received QueueEmpty action, but queue has {} elements",
             send_queue.len()
           );
-          Failed(report!(LumatoneMidiError::InvalidStateTransition(msg)))
+          Failed(Failure::unrecoverable(
+            report!(LumatoneMidiError::InvalidStateTransition(msg)),
+            send_queue,
+          ))
         } else {
           Idle
         }
@@ -463,14 +1093,21 @@ impl State {
 
       // Getting a ReadyToRetry action in any state except WaitingToRetry logs a warning.
       (ReadyToRetry, state) => {
-        warn!("ReadyToRetry action received but not in WaitingToRetry state");
+        warn!(state:% = state; "ReadyToRetry received when not waiting to retry");
         state
       }
 
       // All other state transitions are undefined and result in a Failed state, causing the driver loop to exit with an error.
       (action, state) => {
         let msg = format!("invalid action {:?} for current state {:?}", action, state);
-        Failed(report!(LumatoneMidiError::InvalidStateTransition(msg)))
+        let mut pending = state.into_pending();
+        if let SubmitCommand(cmd) = action {
+          pending.push_back(cmd);
+        }
+        Failed(Failure::unrecoverable(
+          report!(LumatoneMidiError::InvalidStateTransition(msg)),
+          pending,
+        ))
       }
     }
   }
@@ -481,37 +1118,60 @@ impl State {
   /// Note that `enter` does not perform any effects or apply actions, just returns instructions
   /// to do so. See [MidiDriverInternal] for the bit that performs effects and advances the state
   /// machine.
+  #[cfg(test)]
   fn enter(&mut self) -> Option<Effect> {
+    self.enter_at(Instant::now(), &mut PriorityScheduler::default())
+  }
+
+  /// Like `enter`, with the driver's clock reading `now`, which commands' deadlines are
+  /// compared with, and `scheduler` picking the next command to send.
+  fn enter_at(&mut self, now: Instant, scheduler: &mut PriorityScheduler) -> Option<Effect> {
     use Effect::*;
     use State::*;
 
-    // debug!("entering state {:?}", self);
-
     match self {
       Idle => None,
-      ProcessingQueue { send_queue } => match send_queue.pop_front() {
-        None => Some(DispatchAction(QueueEmpty)),
-        Some(cmd) => Some(SendMidiMessage(cmd.clone())),
-      },
+      ProcessingQueue { send_queue } => {
+        if send_queue.iter().any(|c| c.should_shed(now)) {
+          let (expired, live): (VecDeque<_>, _) = std::mem::take(send_queue)
+            .into_iter()
+            .partition(|c| c.should_shed(now));
+          *send_queue = live;
+          return Some(ShedCommands(expired.into()));
+        }
+        match scheduler.dequeue(send_queue) {
+          None => Some(DispatchAction(QueueEmpty)),
+          Some(cmd) => Some(SendMidiMessage(cmd.clone())),
+        }
+      }
       WaitingToRetry { .. } => Some(StartRetryTimeout),
+      WaitingToSend { .. } => Some(StartSendDelay),
       AwaitingResponse { .. } => Some(StartReceiveTimeout),
       ProcessingResponse {
         command_sent,
         response_msg,
         ..
       } => {
-        if !is_response_to_message(&command_sent.command.to_sysex_message(), &response_msg) {
-          warn!("received message that doesn't match expected response. outgoing message: {} - incoming: {}", command_sent.command, to_hex_debug_str(response_msg));
-        }
-
         let status = message_answer_code(&response_msg);
         log_message_status(&status, &command_sent.command);
 
         match status {
+          ResponseStatusCode::Busy | ResponseStatusCode::State
+            if command_sent.busy_retries >= MAX_BUSY_RETRIES =>
+          {
+            warn!(
+              command:% = command_sent.command,
+              retries = MAX_BUSY_RETRIES;
+              "device still busy, giving up on command"
+            );
+            let res = Err(report!(LumatoneMidiError::DeviceBusy));
+            Some(NotifyMessageResponse(command_sent.clone(), res))
+          }
+
           ResponseStatusCode::Busy => Some(DispatchAction(Action::DeviceBusy)),
 
           ResponseStatusCode::State => {
-            warn!("device is in demo mode!");
+            warn!(command:% = command_sent.command; "device is in demo mode");
             // FIXME: demo mode should probably have its own action that triggers
             // sending a command to exit demo mode.
             Some(DispatchAction(Action::DeviceBusy))
@@ -534,7 +1194,9 @@ impl State {
           }
 
           ResponseStatusCode::Ack => {
-            let response_res = Response::from_sysex_message(response_msg);
+            let response_res = command_sent
+              .command
+              .decode_response(response_msg, &command_sent.firmware);
 
             let effect = NotifyMessageResponse(command_sent.clone(), response_res);
             Some(effect)
@@ -543,14 +1205,18 @@ impl State {
           ResponseStatusCode::Unknown => {
             // Unknown means the device sent a status code we don't know about.
             // log a warning and pretend it's all good
-            warn!("Unknown response status code received");
+            warn!(
+              command:% = command_sent.command,
+              message:% = to_hex_debug_str(response_msg);
+              "unknown response status code"
+            );
             None
           }
         }
       }
-      Failed(err) => {
-        error!("midi driver - unrecoverable error: {err}");
-        None // todo: return ExitWithError effect
+      Failed(failure) => {
+        error!(failure:% = failure; "midi driver failed");
+        None
       }
     }
   }
@@ -559,32 +1225,261 @@ impl State {
 /// An internal helper struct for the [MidiDriver] that owns the connection to the device
 /// and timeouts needed by some "waiting" states.
 struct MidiDriverInternal {
-  device_io: LumatoneIO,
-  receive_timeout: Option<Pin<Box<Sleep>>>,
-  retry_timeout: Option<Pin<Box<Sleep>>>,
+  transport: Box<dyn Transport>,
+  traffic: Arc<Mutex<TrafficLog>>,
+  transitions: Arc<broadcast::Sender<Transition>>,
+  transition_history: VecDeque<Transition>,
+  calibration: Arc<broadcast::Sender<CalibrationStatus>>,
+  connection: Arc<broadcast::Sender<ConnectionEvent>>,
+  clock: Arc<dyn Clock>,
+  /// The driver stops taking in commands while this many are queued, so that senders
+  /// wait instead of the queue growing without bound.
+  max_queued: usize,
+  scheduler: PriorityScheduler,
+  jump_detector: ClockJumpDetector,
+  /// The connection needs replacing, because the host was asleep or it closed.
+  stale: bool,
+  /// The transport's `recv` reported that the connection closed.
+  closed: bool,
+  dump_transitions_on_failure: Arc<AtomicBool>,
+  recovery_policy: Arc<RwLock<RecoveryPolicy>>,
+  /// When the recent restarts happened, for applying the [RecoveryPolicy].
+  restarts: VecDeque<Instant>,
+  /// A command whose send failed, to put back at the front of the queue.
+  unsent: Option<CommandSubmission>,
+  metrics: Arc<DriverMetrics>,
+  /// When the command we're waiting for a response to was sent.
+  sent_at: Option<Instant>,
+  receive_timeout: Option<Sleep>,
+  retry_timeout: Option<Sleep>,
+  send_delay: Option<Sleep>,
 }
 
 /// The MidiDriver provides an interface for sending [Command]s to a Lumatone device
 /// and receiving [Response]s (or [LumatoneMidiError]s).
 ///
 /// Use the async [send] method
+///
+/// Cloning a MidiDriver gives you a new handle to the same driver loop, which can be used
+/// from another task. Commands from each handle are scheduled fairly (see [the module
+/// docs](self)).
 pub struct MidiDriver {
   command_tx: mpsc::Sender<CommandSubmission>,
-  done_tx: mpsc::Sender<()>,
+  shutdown: CancellationToken,
+  events: Weak<broadcast::Sender<ChannelMessage>>,
+  timed_events: Weak<broadcast::Sender<TimedMessage>>,
+  client_id: ClientId,
+  next_client_id: Arc<AtomicUsize>,
+  firmware: Arc<RwLock<FirmwareSupport>>,
+  brightness: Brightness,
+  traffic: Arc<Mutex<TrafficLog>>,
+  transitions: Weak<broadcast::Sender<Transition>>,
+  calibration: Weak<broadcast::Sender<CalibrationStatus>>,
+  connection: Weak<broadcast::Sender<ConnectionEvent>>,
+  dump_transitions_on_failure: Arc<AtomicBool>,
+  recovery_policy: Arc<RwLock<RecoveryPolicy>>,
+  metrics: Arc<DriverMetrics>,
+  clock: Arc<dyn Clock>,
+}
+
+impl Clone for MidiDriver {
+  fn clone(&self) -> Self {
+    MidiDriver {
+      command_tx: self.command_tx.clone(),
+      shutdown: self.shutdown.clone(),
+      events: self.events.clone(),
+      timed_events: self.timed_events.clone(),
+      client_id: self.next_client_id.fetch_add(1, Ordering::Relaxed),
+      next_client_id: self.next_client_id.clone(),
+      firmware: self.firmware.clone(),
+      brightness: self.brightness.clone(),
+      traffic: self.traffic.clone(),
+      transitions: self.transitions.clone(),
+      calibration: self.calibration.clone(),
+      connection: self.connection.clone(),
+      dump_transitions_on_failure: self.dump_transitions_on_failure.clone(),
+      recovery_policy: self.recovery_policy.clone(),
+      metrics: self.metrics.clone(),
+      clock: self.clock.clone(),
+    }
+  }
 }
 
 impl MidiDriver {
   /// Sends a [Command] to the device asynchronously, returning a Future that will resolve
   /// with the Command's [Response] on success, or a [LumatoneMidiError] report on failure.
   pub async fn send(&self, command: Command) -> Result<Response, LumatoneMidiError> {
-    let (submission, mut response_rx) = CommandSubmission::new(command);
+    let (submission, response_rx) = CommandSubmission::for_client(command, self.client_id);
+    self
+      .submit(
+        submission
+          .with_firmware(self.firmware())
+          .with_brightness(&self.brightness),
+        response_rx,
+      )
+      .await
+  }
+
+  /// Queues a submission and waits for its response.
+  async fn submit(
+    &self,
+    submission: CommandSubmission,
+    mut response_rx: mpsc::Receiver<ResponseResult>,
+  ) -> Result<Response, LumatoneMidiError> {
     let send_f = self
       .command_tx
       .send(submission)
-      .map_err(|e| report!(e).change_context(LumatoneMidiError::DeviceSendError));
+      .map_err(|e| report!(e).change_context(LumatoneMidiError::DeviceSendError));
+
+    send_f.await?;
+    // The driver drops the response channel without sending if the response times out.
+    response_rx
+      .recv()
+      .await
+      .unwrap_or_else(|| Err(report!(LumatoneMidiError::ResponseTimedOut)))
+  }
+
+  /// Sends a raw sysex message to the device, going through the same queue as typed commands.
+  /// Resolves with a [Response::Raw] containing the device's reply.
+  ///
+  /// Fails without sending anything if `msg` isn't well-formed sysex addressed to the Lumatone.
+  pub async fn send_raw(&self, msg: &[u8]) -> Result<Response, LumatoneMidiError> {
+    let command = raw_sysex(msg).report()?;
+    self.send(command).await
+  }
+
+  /// Queues a [Command] to send to the device without waiting for (or checking) the response.
+  /// The returned future resolves as soon as the command has been queued.
+  ///
+  /// Useful for streams of commands like LED animation frames, where an occasional dropped
+  /// message doesn't matter. Note that if the device is busy, the command is not retried.
+  pub async fn send_and_forget(&self, command: Command) -> Result<(), LumatoneMidiError> {
+    let submission = CommandSubmission::fire_and_forget(command, self.client_id)
+      .with_firmware(self.firmware())
+      .with_brightness(&self.brightness);
+    self
+      .command_tx
+      .send(submission)
+      .await
+      .map_err(|e| report!(e).change_context(LumatoneMidiError::DeviceSendError))
+  }
+
+  /// Like [MidiDriver::send_and_forget], but a [Command::SetKeyColor] replaces any color for
+  /// the same key that this handle has queued and the driver hasn't sent yet. Other commands
+  /// are queued as usual.
+  ///
+  /// However far behind the device falls, each key ends up with the last color sent for
+  /// it, and the queue never holds more than one update per key.
+  pub async fn send_and_forget_coalesced(&self, command: Command) -> Result<(), LumatoneMidiError> {
+    let submission = CommandSubmission::fire_and_forget(command, self.client_id)
+      .with_firmware(self.firmware())
+      .with_brightness(&self.brightness)
+      .coalesced();
+    self
+      .command_tx
+      .send(submission)
+      .await
+      .map_err(|e| report!(e).change_context(LumatoneMidiError::DeviceSendError))
+  }
+
+  /// Like [MidiDriver::send], but if the command is still queued after `timeout`, it's
+  /// dropped instead of being sent, and fails with [LumatoneMidiError::CommandShed].
+  pub async fn send_with_deadline(
+    &self,
+    command: Command,
+    timeout: Duration,
+  ) -> Result<Response, LumatoneMidiError> {
+    let (submission, response_rx) = CommandSubmission::for_client(command, self.client_id);
+    let submission = submission
+      .with_firmware(self.firmware())
+      .with_brightness(&self.brightness)
+      .with_deadline(self.clock.now() + timeout);
+    self.submit(submission, response_rx).await
+  }
+
+  /// Sends a [CommandGroup], returning the responses in order.
+  ///
+  /// Stops at the first command that fails, and returns its error. The group's remaining
+  /// commands are dropped without being sent.
+  pub async fn send_group(&self, group: CommandGroup) -> Result<Vec<Response>, LumatoneMidiError> {
+    let state = Arc::new(GroupState::default());
+    let mut receivers = Vec::with_capacity(group.commands.len());
+    for command in group.commands {
+      let (submission, response_rx) = CommandSubmission::for_client(command, self.client_id);
+      let submission = submission
+        .with_firmware(self.firmware())
+        .with_brightness(&self.brightness)
+        .in_group(state.clone(), group.priority);
+      self
+        .command_tx
+        .send(submission)
+        .await
+        .map_err(|e| report!(e).change_context(LumatoneMidiError::DeviceSendError))?;
+      receivers.push(response_rx);
+    }
+
+    let total = receivers.len();
+    let mut responses = Vec::with_capacity(total);
+    for mut response_rx in receivers {
+      let result = response_rx
+        .recv()
+        .await
+        .unwrap_or_else(|| Err(report!(LumatoneMidiError::ResponseTimedOut)));
+      match result {
+        Ok(response) => responses.push(response),
+        Err(err) => {
+          let abandoned = total - responses.len() - 1;
+          return Err(err.attach_printable(format!(
+            "command {} of {total} in group failed, {abandoned} not sent",
+            responses.len() + 1
+          )));
+        }
+      }
+    }
+    Ok(responses)
+  }
+
+  /// Like [MidiDriver::send_and_forget], but if the command is still queued after `timeout`,
+  /// it's dropped instead of being sent. Useful for animation frames, which are pointless
+  /// once the next frame is due.
+  pub async fn send_and_forget_with_deadline(
+    &self,
+    command: Command,
+    timeout: Duration,
+  ) -> Result<(), LumatoneMidiError> {
+    let submission = CommandSubmission::fire_and_forget(command, self.client_id)
+      .with_firmware(self.firmware())
+      .with_brightness(&self.brightness)
+      .with_deadline(self.clock.now() + timeout);
+    self
+      .command_tx
+      .send(submission)
+      .await
+      .map_err(|e| report!(e).change_context(LumatoneMidiError::DeviceSendError))
+  }
 
-    send_f.await?;
-    response_rx.recv().await.unwrap()
+  /// How many commands have been dropped because their deadline passed, across all clones
+  /// of this driver.
+  pub fn shed_count(&self) -> usize {
+    self.metrics.snapshot().shed as usize
+  }
+
+  /// Returns the driver's counters (see [crate::metrics]), across all clones of this driver.
+  pub fn metrics(&self) -> MetricsSnapshot {
+    self.metrics.snapshot()
+  }
+
+  /// Counts `missed` messages that a subscriber to one of the driver's broadcasts, or to
+  /// something built on them, lost by falling behind (the `n` of a `RecvError::Lagged`).
+  /// Shows up in [MetricsSnapshot::missed_by_subscribers].
+  pub fn count_missed(&self, missed: u64) {
+    self.metrics.subscriber_lagged(missed);
+  }
+
+  /// The clock the driver's timeouts come from. Animations should pace their frames with
+  /// it (see [crate::clock]).
+  pub fn clock(&self) -> Arc<dyn Clock> {
+    self.clock.clone()
   }
 
   /// Like [MidiDriver::send], but blocks the thread and returns a Result when the response is received.
@@ -593,11 +1488,10 @@ impl MidiDriver {
     &self,
     command: Command,
   ) -> Result<mpsc::Receiver<ResponseResult>, LumatoneMidiError> {
-    let (response_tx, response_rx) = mpsc::channel(1);
-    let submission = CommandSubmission {
-      command,
-      response_tx,
-    };
+    let (submission, response_rx) = CommandSubmission::for_client(command, self.client_id);
+    let submission = submission
+      .with_firmware(self.firmware())
+      .with_brightness(&self.brightness);
     self
       .command_tx
       .blocking_send(submission)
@@ -606,14 +1500,140 @@ impl MidiDriver {
     Ok(response_rx)
   }
 
+  /// Returns a receiver for the notes, controller changes, etc. sent by the device as it's played.
+  ///
+  /// Fails if the connection to the device has already closed. Once the connection closes,
+  /// existing receivers will return [`RecvError::Closed`](broadcast::error::RecvError::Closed).
+  pub fn subscribe_events(&self) -> Result<broadcast::Receiver<ChannelMessage>, LumatoneMidiError> {
+    self
+      .events
+      .upgrade()
+      .map(|tx| tx.subscribe())
+      .ok_or_else(|| {
+        report!(LumatoneMidiError::DeviceConnectionError).attach_printable("connection closed")
+      })
+  }
+
+  /// Like [MidiDriver::subscribe_events], but each message comes with the time it arrived.
+  /// See [crate::timing].
+  ///
+  /// Also fails if the transport doesn't time its events.
+  pub fn subscribe_timed_events(
+    &self,
+  ) -> Result<broadcast::Receiver<TimedMessage>, LumatoneMidiError> {
+    self
+      .timed_events
+      .upgrade()
+      .map(|tx| tx.subscribe())
+      .ok_or_else(|| {
+        report!(LumatoneMidiError::DeviceConnectionError)
+          .attach_printable("connection closed, or events aren't timed")
+      })
+  }
+
+  /// Estimates how long messages take to get from the device to the input callback, from
+  /// how long the device has taken to answer commands. See [crate::timing].
+  pub fn input_latency(&self) -> Duration {
+    estimate_input_latency(&self.metrics.snapshot().latency)
+  }
+
+  /// Tells the driver which firmware the device is running, so that commands and responses
+  /// whose layout depends on the version are encoded correctly. Applies to all clones of
+  /// this driver. Until this is called, the latest firmware is assumed.
+  pub fn set_firmware_version(&self, version: &FirmwareVersion) {
+    *self.firmware.write().unwrap() = FirmwareSupport::for_version(version);
+  }
+
+  /// Returns the [FirmwareSupport] used for new commands.
+  pub fn firmware(&self) -> FirmwareSupport {
+    *self.firmware.read().unwrap()
+  }
+
+  /// Returns the LED brightness applied to new color commands, shared by all clones of this
+  /// driver. See [crate::brightness].
+  pub fn brightness(&self) -> &Brightness {
+    &self.brightness
+  }
+
+  /// Returns the most recent messages sent to and received from the device, oldest first.
+  /// See [crate::traffic].
+  pub fn recent_traffic(&self) -> Vec<TrafficEntry> {
+    self.traffic.lock().unwrap().entries()
+  }
+
+  /// Sets how many messages [MidiDriver::recent_traffic] keeps. Zero turns recording off.
+  pub fn set_traffic_capacity(&self, capacity: usize) {
+    self.traffic.lock().unwrap().set_capacity(capacity);
+  }
+
+  /// Returns a receiver for the driver's state machine [Transition]s, for debugging.
+  ///
+  /// Fails if the driver loop has already exited.
+  pub fn subscribe_transitions(
+    &self,
+  ) -> Result<broadcast::Receiver<Transition>, LumatoneMidiError> {
+    self
+      .transitions
+      .upgrade()
+      .map(|tx| tx.subscribe())
+      .ok_or_else(|| {
+        report!(LumatoneMidiError::DeviceConnectionError).attach_printable("driver loop exited")
+      })
+  }
+
+  /// Returns a receiver for the live readings the device sends while a wheel or expression
+  /// pedal calibration routine is running.
+  ///
+  /// Fails if the driver loop has already exited.
+  pub fn subscribe_calibration(
+    &self,
+  ) -> Result<broadcast::Receiver<CalibrationStatus>, LumatoneMidiError> {
+    self
+      .calibration
+      .upgrade()
+      .map(|tx| tx.subscribe())
+      .ok_or_else(|| {
+        report!(LumatoneMidiError::DeviceConnectionError).attach_printable("driver loop exited")
+      })
+  }
+
+  /// Returns a receiver for [ConnectionEvent]s, sent when the driver replaces its connection
+  /// after the host wakes from sleep or the connection closes (see [crate::suspend]).
+  ///
+  /// Fails if the driver loop has already exited.
+  pub fn subscribe_connection(
+    &self,
+  ) -> Result<broadcast::Receiver<ConnectionEvent>, LumatoneMidiError> {
+    self
+      .connection
+      .upgrade()
+      .map(|tx| tx.subscribe())
+      .ok_or_else(|| {
+        report!(LumatoneMidiError::DeviceConnectionError).attach_printable("driver loop exited")
+      })
+  }
+
+  /// When `dump` is true, the driver logs its last [TRANSITION_HISTORY_SIZE] transitions if
+  /// the state machine fails. Off by default. Applies to all clones of this driver.
+  pub fn set_dump_transitions_on_failure(&self, dump: bool) {
+    self
+      .dump_transitions_on_failure
+      .store(dump, Ordering::Relaxed);
+  }
+
+  /// Sets how the driver recovers from transient failures (see [the module docs](self)).
+  /// Applies to all clones of this driver.
+  pub fn set_recovery_policy(&self, policy: RecoveryPolicy) {
+    *self.recovery_policy.write().unwrap() = policy;
+  }
+
   /// Signals to the driver to shutdown the event loop.
+  ///
+  /// Note that this cancels the driver's [CancellationToken], so any other tasks sharing
+  /// the token will also stop.
   pub async fn done(&self) -> Result<(), LumatoneMidiError> {
-    self
-      .done_tx
-      .send(())
-      .await
-      .report()
-      .change_context(LumatoneMidiError::DeviceSendError)
+    self.shutdown.cancel();
+    Ok(())
   }
 }
 
@@ -627,31 +1647,260 @@ impl MidiDriver {
   /// You probably want to spawn a new task for the driver future,
   /// since it will not resolve until you either call [MidiDriver::done]
   /// or an error causes the driver loop to exit.
-  // TODO: maybe have this take an already connected LumatoneIO, so we
-  // don't need to return a Result.
+  ///
+  /// To use an already connected [Transport], see [MidiDriver::with_transport].
   pub fn new(
     device: &LumatoneDevice,
   ) -> Result<(MidiDriver, impl Future<Output = ()>), LumatoneMidiError> {
-    let internal = MidiDriverInternal::new(device)?;
-    let (command_tx, command_rx) = mpsc::channel(128);
-    let (done_tx, done_rx) = mpsc::channel(1);
+    Self::with_shutdown_token(device, CancellationToken::new())
+  }
+
+  /// Like [MidiDriver::new], but the driver loop will exit when `shutdown` is cancelled.
+  pub fn with_shutdown_token(
+    device: &LumatoneDevice,
+    shutdown: CancellationToken,
+  ) -> Result<(MidiDriver, impl Future<Output = ()>), LumatoneMidiError> {
+    let device_io = device.connect()?;
+    let options = DriverOptions::default().with_capacities(device.capacities());
+    Ok(Self::with_options(device_io, shutdown, options))
+  }
+
+  /// Creates a [MidiDriver] that talks to the device over an already connected [Transport].
+  /// Useful for testing the driver against a fake device (see [crate::testing]).
+  pub fn with_transport<T: Transport>(
+    transport: T,
+    shutdown: CancellationToken,
+  ) -> (MidiDriver, impl Future<Output = ()>) {
+    Self::with_options(transport, shutdown, DriverOptions::default())
+  }
+
+  /// Like [MidiDriver::with_transport], but the driver's timeouts and deadlines come from
+  /// `clock` (see [crate::clock]).
+  pub fn with_clock<T: Transport, C: Clock>(
+    transport: T,
+    shutdown: CancellationToken,
+    clock: C,
+  ) -> (MidiDriver, impl Future<Output = ()>) {
+    Self::with_options(
+      transport,
+      shutdown,
+      DriverOptions::default().with_clock(clock),
+    )
+  }
+
+  /// Like [MidiDriver::with_transport], with the given [DriverOptions].
+  pub fn with_options<T: Transport>(
+    transport: T,
+    shutdown: CancellationToken,
+    options: DriverOptions,
+  ) -> (MidiDriver, impl Future<Output = ()>) {
+    let capacities = options.capacities;
+    let internal = MidiDriverInternal::new(Box::new(transport), options);
+    let (command_tx, command_rx) = mpsc::channel(capacities.get(Channel::Commands));
+    let traffic = internal.traffic.clone();
+    let transitions = Arc::downgrade(&internal.transitions);
+    let calibration = Arc::downgrade(&internal.calibration);
+    let connection = Arc::downgrade(&internal.connection);
+    let dump_transitions_on_failure = internal.dump_transitions_on_failure.clone();
+    let recovery_policy = internal.recovery_policy.clone();
+    let metrics = internal.metrics.clone();
+    let clock = internal.clock.clone();
 
     let driver = MidiDriver {
       command_tx,
-      done_tx,
+      shutdown: shutdown.clone(),
+      events: internal.transport.events_sender(),
+      timed_events: internal.transport.timed_events_sender(),
+      client_id: 0,
+      next_client_id: Arc::new(AtomicUsize::new(1)),
+      firmware: Arc::new(RwLock::new(FirmwareSupport::default())),
+      brightness: Brightness::default(),
+      traffic,
+      transitions,
+      calibration,
+      connection,
+      dump_transitions_on_failure,
+      recovery_policy,
+      metrics,
+      clock,
     };
-    Ok((driver, internal.run(command_rx, done_rx)))
+    (driver, internal.run(command_rx, shutdown))
   }
 }
 
 impl MidiDriverInternal {
-  fn new(device: &LumatoneDevice) -> Result<Self, LumatoneMidiError> {
-    let device_io = device.connect()?;
-    Ok(MidiDriverInternal {
-      device_io,
+  fn new(transport: Box<dyn Transport>, options: DriverOptions) -> Self {
+    let capacities = options.capacities;
+    MidiDriverInternal {
+      transport,
+      traffic: Arc::new(Mutex::new(TrafficLog::default())),
+      transitions: Arc::new(broadcast::channel(capacities.get(Channel::Transitions)).0),
+      transition_history: VecDeque::with_capacity(TRANSITION_HISTORY_SIZE),
+      calibration: Arc::new(broadcast::channel(capacities.get(Channel::Calibration)).0),
+      connection: Arc::new(broadcast::channel(capacities.get(Channel::Connection)).0),
+      clock: options.clock,
+      max_queued: capacities.get(Channel::SendQueue),
+      scheduler: PriorityScheduler::new(options.priority_ratio),
+      jump_detector: ClockJumpDetector::new(SUSPEND_CHECK_INTERVAL),
+      stale: false,
+      closed: false,
+      dump_transitions_on_failure: Arc::new(AtomicBool::new(false)),
+      recovery_policy: Arc::new(RwLock::new(RecoveryPolicy::default())),
+      restarts: VecDeque::new(),
+      unsent: None,
+      metrics: Arc::new(DriverMetrics::default()),
+      sent_at: None,
       receive_timeout: None,
       retry_timeout: None,
-    })
+      send_delay: None,
+    }
+  }
+
+  /// Logs a transition, sends it to any subscribers and adds it to the history.
+  fn record_transition(&mut self, transition: Transition) {
+    trace!(
+      target: TRANSITIONS_LOG_TARGET,
+      from = transition.from.as_str(),
+      action = transition.action.as_str(),
+      to = transition.to.as_str(),
+      effect = transition.effect.as_deref().unwrap_or("none");
+      "state transition"
+    );
+    // there may not be any subscribers
+    let _ = self.transitions.send(transition.clone());
+    if self.transition_history.len() == TRANSITION_HISTORY_SIZE {
+      self.transition_history.pop_front();
+    }
+    self.transition_history.push_back(transition);
+  }
+
+  /// Checks the [RecoveryPolicy] to see whether the driver can restart now, and if so
+  /// counts the restart.
+  fn can_restart(&mut self) -> bool {
+    let policy = *self.recovery_policy.read().unwrap();
+    let now = self.clock.now();
+    while let Some(t) = self.restarts.front() {
+      if now.duration_since(*t) <= policy.window {
+        break;
+      }
+      self.restarts.pop_front();
+    }
+    if self.restarts.len() >= policy.max_restarts {
+      return false;
+    }
+    self.restarts.push_back(now);
+    true
+  }
+
+  /// How long ago the command in flight was sent, if we're waiting for its response.
+  fn take_latency(&mut self) -> Option<Duration> {
+    let now = self.clock.now();
+    self
+      .sent_at
+      .take()
+      .map(|t| now.saturating_duration_since(t))
+  }
+
+  /// Replaces the connection if the host has been asleep since the last check, or if the
+  /// connection has closed. If reconnecting fails, it's tried again on the next check.
+  fn check_connection(&mut self) {
+    if let Some(slept) = self.jump_detector.check(SystemTime::now(), Instant::now()) {
+      warn!(
+        "host was asleep for about {}s, reconnecting to device",
+        slept.as_secs()
+      );
+      self.stale = true;
+      // there may not be any subscribers
+      let _ = self.connection.send(ConnectionEvent::Resumed { slept });
+    }
+    if !self.stale {
+      return;
+    }
+    match self.transport.reconnect() {
+      Ok(()) => {
+        info!("reconnected to device");
+        self.metrics.reconnected();
+        self.stale = false;
+        self.closed = false;
+        let _ = self.connection.send(ConnectionEvent::Reconnected);
+      }
+      Err(err) => {
+        warn!("unable to reconnect to device: {err:?}");
+        let _ = self
+          .connection
+          .send(ConnectionEvent::ReconnectFailed(err.to_string()));
+      }
+    }
+  }
+
+  /// Handles a [State::Failed]. Returns the state to restart in, or `None` if the driver
+  /// should stop, in which case every pending command is failed with the classification.
+  fn recover(&mut self, failure: Failure) -> Option<State> {
+    error!("state machine error: {failure}");
+    if self.dump_transitions_on_failure.load(Ordering::Relaxed) {
+      error!("last {} transitions:", self.transition_history.len());
+      for transition in &self.transition_history {
+        error!("  {transition}");
+      }
+    }
+
+    let Failure {
+      error,
+      kind,
+      pending,
+    } = failure;
+    if kind == FailureKind::Transient && self.can_restart() {
+      self.log_traffic(Level::Debug);
+      info!("restarting driver with {} commands queued", pending.len());
+      self.metrics.restarted();
+      self.receive_timeout = None;
+      self.retry_timeout = None;
+      let state = if pending.is_empty() {
+        self.send_delay = None;
+        State::Idle
+      } else {
+        // WaitingToSend moves to ProcessingQueue once the delay is up
+        self.send_delay = Some(self.clock.sleep(RESTART_DELAY));
+        State::WaitingToSend {
+          send_queue: pending,
+        }
+      };
+      self.record_transition(Transition {
+        time: SystemTime::now(),
+        from: format!("Failed({kind})"),
+        action: "Restart".to_string(),
+        to: state.to_string(),
+        effect: None,
+      });
+      return Some(state);
+    }
+
+    self.log_traffic(Level::Error);
+    let gave_up = kind == FailureKind::Transient;
+    for cmd in pending {
+      if let Some(response_tx) = &cmd.response_tx {
+        let mut err =
+          report!(LumatoneMidiError::DriverFailed(kind)).attach_printable(format!("{error}"));
+        if gave_up {
+          err = err.attach_printable("too many restarts, giving up");
+        }
+        // the caller may have stopped waiting
+        let _ = response_tx.try_send(Err(err));
+      }
+    }
+    None
+  }
+
+  /// Logs the recent MIDI traffic, e.g. when the driver fails.
+  fn log_traffic(&self, level: Level) {
+    if !log::log_enabled!(level) {
+      return;
+    }
+    let entries = self.traffic.lock().unwrap().entries();
+    log!(level, "last {} messages:", entries.len());
+    for entry in entries {
+      log!(level, "  {entry}");
+    }
   }
 
   /// Performs some Effect. On success, returns an `Option<Action>`, which should be fed into
@@ -659,28 +1908,77 @@ impl MidiDriverInternal {
   async fn perform_effect(&mut self, effect: Effect) -> Result<Option<Action>, LumatoneMidiError> {
     use Effect::*;
     let maybe_action = match effect {
-      SendMidiMessage(cmd) => {
-        self.device_io.send(&cmd.command.to_sysex_message())?;
+      SendMidiMessage(mut cmd) => {
+        let msg = cmd.command.to_sysex_message_for(&cmd.firmware);
+        if let Err(err) = self.transport.send(&msg) {
+          self.unsent = Some(cmd);
+          return Err(err);
+        }
+        self
+          .traffic
+          .lock()
+          .unwrap()
+          .record(TrafficEntry::outbound(&cmd.command, &msg));
+        match cmd.sends {
+          0 => self.metrics.command_sent(),
+          _ => self.metrics.command_resent(),
+        }
+        cmd.sends += 1;
+        if cmd.expects_response() {
+          self.sent_at = Some(self.clock.now());
+        }
         Some(MessageSent(cmd))
       }
       StartReceiveTimeout => {
-        let timeout_sec = 30;
-        let timeout = sleep(Duration::from_secs(timeout_sec));
-        self.receive_timeout = Some(Box::pin(timeout));
+        // an unrelated message doesn't restart the wait for the real response
+        if self.receive_timeout.is_none() {
+          let timeout_sec = 30;
+          let timeout = self.clock.sleep(Duration::from_secs(timeout_sec));
+          self.receive_timeout = Some(timeout);
+        }
         None
       }
       StartRetryTimeout => {
         let timeout_sec = 3;
-        let timeout = sleep(Duration::from_secs(timeout_sec));
-        self.retry_timeout = Some(Box::pin(timeout));
+        let timeout = self.clock.sleep(Duration::from_secs(timeout_sec));
+        self.retry_timeout = Some(timeout);
+        None
+      }
+      StartSendDelay => {
+        self.send_delay = Some(self.clock.sleep(SEND_AND_FORGET_INTERVAL));
         None
       }
       NotifyMessageResponse(cmd_submission, result) => {
-        if let Err(err) = cmd_submission.response_tx.send(result).await {
-          error!("error sending response notification: {err}");
+        let latency = self.take_latency();
+        self.metrics.response(result.is_ok(), latency);
+        if result.is_err() {
+          cmd_submission.abort_group();
+        }
+        if let Some(response_tx) = &cmd_submission.response_tx {
+          if let Err(err) = response_tx.send(result).await {
+            error!("error sending response notification: {err}");
+          }
         }
         Some(ResponseDispatched)
       }
+      ShedCommands(cmds) => {
+        debug!("dropping {} expired or abandoned commands", cmds.len());
+        for cmd in cmds {
+          let err = if cmd.group_aborted() {
+            LumatoneMidiError::GroupAborted
+          } else {
+            self.metrics.shed();
+            // the rest of the group can't be sent without this one
+            cmd.abort_group();
+            LumatoneMidiError::CommandShed
+          };
+          if let Some(response_tx) = &cmd.response_tx {
+            // the caller may have stopped waiting
+            let _ = response_tx.send(Err(report!(err))).await;
+          }
+        }
+        Some(Action::CommandsShed)
+      }
       DispatchAction(action) => Some(action),
     };
     Ok(maybe_action)
@@ -689,32 +1987,39 @@ impl MidiDriverInternal {
   /// Run the MidiDriver I/O event loop.
   /// Commands to send to the device should be sent on the `commands` channel.
   ///
-  /// To exit the loop, send `()` on the `done_signal` channel.
+  /// To exit the loop, cancel the `shutdown` token.
   ///
   async fn run(
     mut self,
     mut commands: mpsc::Receiver<CommandSubmission>,
-    mut done_signal: mpsc::Receiver<()>,
+    shutdown: CancellationToken,
   ) {
     let mut state = State::Idle;
     let mut next_action: Option<Action> = None;
+    let mut suspend_check = interval(SUSPEND_CHECK_INTERVAL);
+    suspend_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
       // The previous state may have resulted in an Action that we should feed into the
       // state machine. If not, we poll our inputs until something happens.
       let a = match next_action {
         Some(action) => action.clone(),
         None => {
-          // if either timeout is None, use a timeout with Duration::MAX, to make the select! logic a bit simpler
-          let mut receive_timeout = &mut Box::pin(sleep(Duration::MAX));
+          // if either timeout is None, use a future that never completes, to make the select! logic a bit simpler
+          let mut receive_timeout: &mut Sleep = &mut Box::pin(std::future::pending());
           if let Some(t) = &mut self.receive_timeout {
             receive_timeout = t;
           }
 
-          let mut retry_timeout = &mut Box::pin(sleep(Duration::MAX));
+          let mut retry_timeout: &mut Sleep = &mut Box::pin(std::future::pending());
           if let Some(t) = &mut self.retry_timeout {
             retry_timeout = t;
           }
 
+          let mut send_delay: &mut Sleep = &mut Box::pin(std::future::pending());
+          if let Some(t) = &mut self.send_delay {
+            send_delay = t;
+          }
+
           // There are two incoming streams of information: incoming midi messages,
           // and incoming commands (requests to send out midi messages)
           // There are also two timeouts: receive_timeout for when we're waiting for a response to a command,
@@ -725,6 +2030,12 @@ impl MidiDriverInternal {
             _ = receive_timeout => {
               info!("receive timeout triggered");
               self.receive_timeout = None;
+              // only a command still waiting for its response is given up on, otherwise its
+              // outcome is counted when the response is dispatched
+              if matches!(state, State::AwaitingResponse { .. }) {
+                let latency = self.take_latency();
+                self.metrics.response(false, latency);
+              }
               Action::ResponseTimedOut
             },
 
@@ -734,18 +2045,49 @@ impl MidiDriverInternal {
               Action::ReadyToRetry
             },
 
-            Some(msg) = self.device_io.incoming_messages.recv() => {
+            _ = send_delay => {
+              self.send_delay = None;
+              Action::ReadyToSend
+            },
+
+            msg = self.transport.recv(), if !self.closed => {
+              let msg = match msg {
+                Some(msg) => msg,
+                None => {
+                  warn!("connection to device closed");
+                  self.closed = true;
+                  self.stale = true;
+                  let _ = self.connection.send(ConnectionEvent::Closed);
+                  continue;
+                }
+              };
               // info!("message received, forwarding to state machine");
-              self.receive_timeout = None;
+              match &state {
+                State::AwaitingResponse { command_sent, .. } if !command_sent.is_answered_by(&msg) => {
+                  self.metrics.unexpected_message();
+                }
+                _ => self.receive_timeout = None,
+              }
+              if let Some(status) = CalibrationStatus::from_sysex_message(&msg) {
+                // no receivers is fine, nobody is showing the readings
+                let _ = self.calibration.send(status);
+              }
+              self.traffic.lock().unwrap().record(TrafficEntry::inbound(&msg));
               Action::MessageReceived(msg)
             }
 
-            Some(cmd) = commands.recv() => {
+            Some(cmd) = commands.recv(), if state.queue_len() < self.max_queued => {
               Action::SubmitCommand(cmd)
             }
 
-            _ = done_signal.recv() => {
-              debug!("done signal received, exiting");
+            _ = suspend_check.tick() => {
+              self.metrics.set_dropped_incoming(self.transport.dropped_messages());
+              self.check_connection();
+              continue;
+            }
+
+            _ = shutdown.cancelled() => {
+              debug!("shutdown requested, exiting");
               return;
             }
           }
@@ -753,16 +2095,36 @@ impl MidiDriverInternal {
       };
 
       // Transition to next state based on action
+      let from = state.to_string();
+      let action = a.to_string();
       state = state.next(a);
+      self.metrics.set_queue_depth(state.queue_len());
 
-      if let State::Failed(err) = state {
-        // TODO: propagate fatal error & return it from `run`
-        error!("state machine error: {err}");
-        break;
+      // The new state's `enter` fn may return an Effect.
+      let effect = match &mut state {
+        State::Failed(_) => None,
+        state => state.enter_at(self.clock.now(), &mut self.scheduler),
+      };
+      self.record_transition(Transition {
+        time: SystemTime::now(),
+        from,
+        action,
+        to: state.to_string(),
+        effect: effect.as_ref().map(|e| e.to_string()),
+      });
+
+      if let State::Failed(failure) = state {
+        match self.recover(failure) {
+          Some(restarted) => {
+            state = restarted;
+            next_action = None;
+            continue;
+          }
+          None => break,
+        }
       }
 
-      // The new state's `enter` fn may return an Effect.
-      next_action = match state.enter() {
+      next_action = match effect {
         // if there was no effect, there's no next_action
         None => None,
 
@@ -774,10 +2136,19 @@ impl MidiDriverInternal {
           match self.perform_effect(effect).await {
             Ok(maybe_action) => maybe_action,
 
-            // TODO: propagate fatal error & return it from `run`
+            // Effects only fail on I/O errors, which may go away
             Err(err) => {
-              error!("effect error: {err}");
-              break;
+              let mut pending = std::mem::replace(&mut state, State::Idle).into_pending();
+              if let Some(cmd) = self.unsent.take() {
+                pending.push_front(cmd);
+              }
+              match self.recover(Failure::transient(err, pending)) {
+                Some(restarted) => {
+                  state = restarted;
+                  None
+                }
+                None => break,
+              }
             }
           }
         }
@@ -789,20 +2160,19 @@ impl MidiDriverInternal {
 fn log_message_status(status: &ResponseStatusCode, outgoing: &Command) {
   use ResponseStatusCode::*;
   match *status {
-    Nack => debug!("received NACK response to command {:?}", outgoing),
     Ack => {}
-    Busy => debug!("received Busy response to command {:?}", outgoing),
-    Error => debug!("received Error response to command {:?}", outgoing),
-    State => debug!("received State response to command {:?}", outgoing),
-    Unknown => warn!(
-      "received unknown response status in response to command: {:?}",
-      outgoing
-    ),
+    Nack | Busy | Error | State => {
+      debug!(status:? = status, command:% = outgoing; "response status")
+    }
+    Unknown => warn!(command:% = outgoing; "unknown response status"),
   }
 }
 
 mod tests {
-  use crate::constants::{CommandId, MANUFACTURER_ID};
+  use crate::{
+    commands::set_key_color,
+    constants::{CommandId, LumatoneKeyLocation, RGBColor, MANUFACTURER_ID},
+  };
 
   #[allow(unused_imports)]
   use super::*;
@@ -975,7 +2345,7 @@ mod tests {
       send_queue,
       command_sent: sub,
     };
-    let response: Vec<u8> = vec![0xf0, 0x00];
+    let response = response_with_status(ResponseStatusCode::Ack);
     let action = Action::MessageReceived(response.clone());
 
     match init.next(action) {
@@ -993,6 +2363,28 @@ mod tests {
     }
   }
 
+  #[test]
+  fn unrelated_message_while_awaiting_response_keeps_waiting() {
+    let (sub, _) = CommandSubmission::new(Command::Ping(1));
+    let init = State::AwaitingResponse {
+      send_queue: VecDeque::new(),
+      command_sent: sub,
+    };
+
+    let mut other_board = response_with_status(ResponseStatusCode::Ack);
+    other_board[MANUFACTURER_ID.len()] = crate::constants::BoardIndex::Octave2.into();
+    let state = init.next(Action::MessageReceived(other_board));
+    let state = state.next(Action::MessageReceived(vec![0xf0, 0x00]));
+    match state.next(Action::MessageReceived(response_with_status(
+      ResponseStatusCode::Ack,
+    ))) {
+      State::ProcessingResponse { command_sent, .. } => {
+        assert_eq!(command_sent.command, Command::Ping(1))
+      }
+      s => panic!("unexpected state: {:?}", s),
+    }
+  }
+
   #[test]
   fn message_received_while_not_awaiting_response_does_not_transition() {
     let response: Vec<u8> = vec![0xf0, 0x00];
@@ -1131,10 +2523,68 @@ mod tests {
     }
   }
 
+  #[test]
+  fn submit_command_interleaves_clients_in_queue() {
+    let mut state = State::Idle;
+    // client 1 submits a burst of commands, then client 2 submits two
+    for (client, n) in [(1, 1), (1, 2), (1, 3), (2, 10), (2, 11)] {
+      let (sub, _) = CommandSubmission::for_client(Command::Ping(n), client);
+      state = state.next(Action::SubmitCommand(sub));
+    }
+
+    match state {
+      State::ProcessingQueue { send_queue } => {
+        let order: Vec<Command> = send_queue.into_iter().map(|c| c.command).collect();
+        assert_eq!(
+          order,
+          vec![
+            Command::Ping(1),
+            Command::Ping(10),
+            Command::Ping(2),
+            Command::Ping(11),
+            Command::Ping(3)
+          ]
+        );
+      }
+      s => panic!("unexpected state: {:?}", s),
+    }
+  }
+
+  #[test]
+  fn fire_and_forget_message_sent_transitions_to_waiting_to_send() {
+    let sub = CommandSubmission::fire_and_forget(Command::Ping(1), 0);
+    let init = State::ProcessingQueue {
+      send_queue: VecDeque::new(),
+    };
+
+    let state = match init.next(MessageSent(sub)) {
+      s @ State::WaitingToSend { .. } => s,
+      s => panic!("unexpected state: {:?}", s),
+    };
+
+    // the device's response is ignored
+    let state = state.next(Action::MessageReceived(vec![0xf0, 0x00]));
+    match state.next(Action::ReadyToSend) {
+      State::ProcessingQueue { send_queue } => assert!(send_queue.is_empty()),
+      s => panic!("unexpected state: {:?}", s),
+    }
+  }
+
   // endregion
 
   // region State entry tests (for expected Effect)
 
+  #[test]
+  fn entering_waiting_to_send_returns_start_send_delay_effect() {
+    let mut s = State::WaitingToSend {
+      send_queue: VecDeque::new(),
+    };
+    match s.enter() {
+      Some(Effect::StartSendDelay) => (),
+      e => panic!("unexpected effect: {:?}", e),
+    }
+  }
+
   #[test]
   fn entering_idle_state_has_no_effect() {
     let mut s = State::Idle;
@@ -1324,6 +2774,40 @@ mod tests {
     }
   }
 
+  #[test]
+  fn device_busy_fails_command_after_max_retries() {
+    use Action::DeviceBusy;
+    use Effect::{DispatchAction, NotifyMessageResponse};
+    use State::{ProcessingResponse, WaitingToRetry};
+
+    let (mut sub, _) = CommandSubmission::new(Command::Ping(1));
+    for retry in 1..=MAX_BUSY_RETRIES {
+      let s = ProcessingResponse {
+        send_queue: VecDeque::new(),
+        command_sent: sub,
+        response_msg: response_with_status(ResponseStatusCode::Busy),
+      };
+      sub = match s.next(DeviceBusy) {
+        WaitingToRetry { to_retry, .. } => to_retry,
+        s => panic!("unexpected state: {s}"),
+      };
+      assert_eq!(sub.busy_retries, retry);
+    }
+
+    let mut s = ProcessingResponse {
+      send_queue: VecDeque::new(),
+      command_sent: sub,
+      response_msg: response_with_status(ResponseStatusCode::Busy),
+    };
+    match s.enter() {
+      Some(NotifyMessageResponse(_, Err(e))) => {
+        assert!(matches!(e.current_context(), LumatoneMidiError::DeviceBusy))
+      }
+      Some(DispatchAction(DeviceBusy)) => panic!("retried past the limit"),
+      e => panic!("unexpected effect: {:?}", e),
+    }
+  }
+
   #[test]
   fn entering_processing_response_with_status_unknown_returns_no_effect() {
     let cmd = Command::Ping(1);
@@ -1342,4 +2826,259 @@ mod tests {
   }
 
   // endregion
+
+  #[tokio::test]
+  async fn transitions_are_sent_to_subscribers() {
+    let (driver, driver_future) =
+      MidiDriver::with_transport(crate::testing::FakeDevice::new(), CancellationToken::new());
+    let mut transitions = driver.subscribe_transitions().unwrap();
+    tokio::spawn(driver_future);
+
+    driver.send(Command::Ping(1)).await.unwrap();
+    driver.done().await.unwrap();
+
+    let mut steps = vec![];
+    while let Ok(t) = transitions.try_recv() {
+      steps.push((t.action.split('(').next().unwrap().to_string(), t.to));
+    }
+    let actions = steps.iter().map(|(a, _)| a.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+      actions[..4],
+      [
+        "SubmitCommand",
+        "MessageSent",
+        "MessageReceived",
+        "ResponseDispatched"
+      ]
+    );
+    assert_eq!(steps[0].1, "ProcessingQueue(0 in queue)");
+    assert!(steps[1].1.starts_with("AwaitingResponse(Ping(1)"));
+  }
+
+  #[test]
+  fn undefined_state_transitions_keep_pending_commands() {
+    let (sent, _) = CommandSubmission::new(Command::Ping(1));
+    let (queued, _) = CommandSubmission::new(Command::Ping(2));
+    let init = State::AwaitingResponse {
+      send_queue: VecDeque::from(vec![queued]),
+      command_sent: sent,
+    };
+    match init.next(Action::ReadyToSend) {
+      State::Failed(failure) => {
+        assert_eq!(failure.kind, FailureKind::Unrecoverable);
+        let pending = failure.pending.iter().map(|c| c.command.clone());
+        assert_eq!(
+          pending.collect::<Vec<_>>(),
+          vec![Command::Ping(1), Command::Ping(2)]
+        );
+      }
+      s => panic!("unexpected state: {:?}", s),
+    }
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn transient_failures_restart_the_driver() {
+    // a FakeDevice whose first `failures` sends fail
+    struct FlakyTransport {
+      inner: crate::testing::FakeDevice,
+      failures: usize,
+    }
+
+    impl Transport for FlakyTransport {
+      fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+        if self.failures > 0 {
+          self.failures -= 1;
+          return Err(report!(LumatoneMidiError::DeviceSendError));
+        }
+        self.inner.send(msg)
+      }
+
+      fn recv(&mut self) -> futures::future::BoxFuture<'_, Option<EncodedSysex>> {
+        self.inner.recv()
+      }
+
+      fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+        self.inner.events_sender()
+      }
+    }
+
+    let flaky = |failures| FlakyTransport {
+      inner: crate::testing::FakeDevice::new(),
+      failures,
+    };
+
+    let (driver, driver_future) = MidiDriver::with_transport(flaky(2), CancellationToken::new());
+    tokio::spawn(driver_future);
+    assert!(matches!(
+      driver.send(Command::Ping(1)).await,
+      Ok(Response::Pong(1))
+    ));
+
+    let (driver, driver_future) = MidiDriver::with_transport(flaky(1), CancellationToken::new());
+    driver.set_recovery_policy(RecoveryPolicy::never());
+    tokio::spawn(driver_future);
+    let err = driver.send(Command::Ping(1)).await.unwrap_err();
+    assert!(matches!(
+      err.current_context(),
+      LumatoneMidiError::DriverFailed(FailureKind::Transient)
+    ));
+  }
+
+  #[test]
+  fn entering_processing_queue_sheds_expired_commands() {
+    let now = Instant::now();
+    let (expired, _) = CommandSubmission::new(Command::Ping(1));
+    let (live, _) = CommandSubmission::new(Command::Ping(2));
+    let mut s = State::ProcessingQueue {
+      send_queue: VecDeque::from(vec![
+        expired.with_deadline(now),
+        live.with_deadline(now + Duration::from_secs(1)),
+      ]),
+    };
+
+    match s.enter() {
+      Some(Effect::ShedCommands(cmds)) => {
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].command, Command::Ping(1));
+      }
+      e => panic!("unexpected effect: {:?}", e),
+    }
+    let mut s = s.next(Action::CommandsShed);
+    match s.enter() {
+      Some(Effect::SendMidiMessage(cmd)) => assert_eq!(cmd.command, Command::Ping(2)),
+      e => panic!("unexpected effect: {:?}", e),
+    }
+  }
+
+  #[test]
+  fn high_priority_commands_are_queued_first() {
+    let group = Arc::new(GroupState::default());
+    let mut queue = VecDeque::new();
+    for (client, n, priority) in [
+      (1, 1, Priority::Normal),
+      (2, 2, Priority::Normal),
+      (3, 3, Priority::High),
+      (3, 4, Priority::High),
+      (1, 5, Priority::Normal),
+    ] {
+      let (sub, _) = CommandSubmission::for_client(Command::Ping(n), client);
+      enqueue(&mut queue, sub.in_group(group.clone(), priority));
+    }
+    let order = queue.iter().map(|c| c.command.clone()).collect::<Vec<_>>();
+    assert_eq!(order, [3, 4, 1, 2, 5].map(Command::Ping).to_vec(),);
+  }
+
+  #[test]
+  fn normal_priority_commands_get_a_share_while_high_priority_ones_wait() {
+    let group = Arc::new(GroupState::default());
+    let mut queue = VecDeque::new();
+    for n in 1..=5 {
+      let (sub, _) = CommandSubmission::for_client(Command::Ping(n), 1);
+      enqueue(&mut queue, sub.in_group(group.clone(), Priority::High));
+    }
+    for n in 6..=8 {
+      enqueue(
+        &mut queue,
+        CommandSubmission::fire_and_forget(Command::Ping(n), 2),
+      );
+    }
+
+    let mut scheduler = PriorityScheduler::new(2);
+    let order = std::iter::from_fn(|| scheduler.dequeue(&mut queue))
+      .map(|c| c.command)
+      .collect::<Vec<_>>();
+    assert_eq!(order, [1, 2, 6, 3, 4, 7, 5, 8].map(Command::Ping).to_vec());
+  }
+
+  #[test]
+  fn coalesced_key_colors_replace_queued_ones() {
+    let location = LumatoneKeyLocation::all()[0];
+    let color = |n| set_key_color(location, RGBColor(n, 0, 0));
+    let mut queue = VecDeque::new();
+    enqueue(
+      &mut queue,
+      CommandSubmission::fire_and_forget(color(1), 1).coalesced(),
+    );
+    enqueue(
+      &mut queue,
+      CommandSubmission::fire_and_forget(Command::Ping(1), 1),
+    );
+    enqueue(
+      &mut queue,
+      CommandSubmission::fire_and_forget(color(2), 1).coalesced(),
+    );
+    // another client's update for the same key is queued separately
+    enqueue(
+      &mut queue,
+      CommandSubmission::fire_and_forget(color(3), 2).coalesced(),
+    );
+
+    let order = queue.iter().map(|c| c.command.clone()).collect::<Vec<_>>();
+    assert_eq!(order, vec![color(2), color(3), Command::Ping(1)]);
+  }
+
+  #[tokio::test]
+  async fn full_send_queue_makes_senders_wait() {
+    use crate::{clock::MockClock, testing::FakeDevice};
+
+    let clock = MockClock::new();
+    let capacities = ChannelCapacities::default()
+      .with(Channel::Commands, 2)
+      .with(Channel::SendQueue, 2);
+    let options = DriverOptions::default()
+      .with_clock(clock.clone())
+      .with_capacities(capacities);
+    let (driver, driver_future) =
+      MidiDriver::with_options(FakeDevice::new(), CancellationToken::new(), options);
+    tokio::spawn(driver_future);
+
+    // the clock doesn't move, so the driver waits after sending the first command
+    let sender = driver.clone();
+    let sends = tokio::spawn(async move {
+      for n in 0..10 {
+        sender.send_and_forget(Command::Ping(n)).await.unwrap();
+      }
+    });
+    clock.wait_for_sleepers(1).await;
+    for _ in 0..100 {
+      tokio::task::yield_now().await;
+    }
+    assert!(!sends.is_finished());
+    assert_eq!(driver.metrics().queue_depth, 2);
+
+    while !sends.is_finished() {
+      clock.advance(SEND_AND_FORGET_INTERVAL);
+      tokio::task::yield_now().await;
+    }
+    assert!(driver.metrics().queue_depth <= 2);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn failed_group_member_aborts_the_rest() {
+    use crate::testing::{FakeDevice, Fault, FaultInjector};
+
+    let transport = FaultInjector::new(FakeDevice::new(), [Fault::None, Fault::Drop]);
+    let (driver, driver_future) = MidiDriver::with_transport(transport, CancellationToken::new());
+    tokio::spawn(driver_future);
+
+    let group = CommandGroup::new((1..=4).map(Command::Ping).collect());
+    let err = driver.send_group(group).await.unwrap_err();
+    assert!(matches!(
+      err.current_context(),
+      LumatoneMidiError::ResponseTimedOut
+    ));
+    // only the first two pings were sent
+    let sent = driver
+      .recent_traffic()
+      .into_iter()
+      .filter(|e| e.direction == crate::traffic::TrafficDirection::Outbound)
+      .count();
+    assert_eq!(sent, 2);
+
+    // the driver carries on with commands outside the group
+    assert!(matches!(
+      driver.send(Command::Ping(5)).await,
+      Ok(Response::Pong(5))
+    ));
+  }
 }
@@ -7,6 +7,8 @@ use std::fmt::Display;
 pub enum LumatoneMidiError {
   // InvalidCommandInput(CommandId, String),
   NotLumatoneMessage(Vec<u8>),
+  MalformedSysex(String),
+  InvalidChannelMessage(Vec<u8>),
   MessageTooShort {
     expected: usize,
     actual: usize,
@@ -25,9 +27,24 @@ pub enum LumatoneMidiError {
   InvalidResponseMessage(String),
 
   InvalidStateTransition(String),
+  DriverFailed(FailureKind),
   DeviceDetectionFailed,
   DeviceConnectionError,
   DeviceSendError,
+  ResponseTimedOut,
+  DeviceBusy,
+  CommandShed,
+  GroupAborted,
+  NoteProxyError(String),
+  SoakTestFailed(String),
+  InvalidMacro(String),
+  MissingMacroParameter(String),
+  MacroFileError(String),
+  InvalidSession(String),
+  SessionFileError(String),
+  InvalidMidiFile(String),
+  InvalidFixture(String),
+  FixtureFileError(String),
 
   ResponseDecodingError,
 
@@ -37,6 +54,26 @@ pub enum LumatoneMidiError {
   InvalidPresetIndex(u8),
 }
 
+/// How bad a failure of the driver's state machine was. See [crate::driver].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+  /// An I/O error that may go away, e.g. a failed write to the MIDI port. The driver
+  /// restarts, keeping its queue.
+  Transient,
+  /// The state machine got into a state it can't get out of, e.g. because of a protocol
+  /// violation. The driver stops.
+  Unrecoverable,
+}
+
+impl Display for FailureKind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FailureKind::Transient => write!(f, "transient"),
+      FailureKind::Unrecoverable => write!(f, "unrecoverable"),
+    }
+  }
+}
+
 impl Context for LumatoneMidiError {}
 
 impl Display for LumatoneMidiError {
@@ -45,6 +82,10 @@ impl Display for LumatoneMidiError {
     match self {
       NotLumatoneMessage(msg) => write!(f, "message is not a lumatone message: {:?}", msg),
 
+      MalformedSysex(msg) => write!(f, "malformed sysex message: {msg}"),
+
+      InvalidChannelMessage(msg) => write!(f, "message is not a channel voice message: {:?}", msg),
+
       MessageTooShort { expected, actual } => write!(
         f,
         "expected message to have length of at least {expected}, but received {actual}"
@@ -69,12 +110,45 @@ impl Display for LumatoneMidiError {
 
       InvalidStateTransition(msg) => write!(f, "invalid state transition: {msg}"),
 
+      DriverFailed(kind) => write!(f, "midi driver failed ({kind})"),
+
       DeviceDetectionFailed => write!(f, "device detection failed"),
 
       DeviceConnectionError => write!(f, "failed to connect to device"),
 
       DeviceSendError => write!(f, "failed to send message to device"),
 
+      ResponseTimedOut => write!(f, "timed out waiting for response from device"),
+
+      DeviceBusy => write!(f, "device stayed busy after retrying the command"),
+
+      CommandShed => write!(f, "command was dropped because its deadline passed"),
+
+      GroupAborted => write!(
+        f,
+        "command wasn't sent because an earlier command in its group failed"
+      ),
+
+      NoteProxyError(msg) => write!(f, "note proxy error: {msg}"),
+
+      SoakTestFailed(msg) => write!(f, "soak test failed: {msg}"),
+
+      InvalidMacro(msg) => write!(f, "invalid macro: {msg}"),
+
+      MissingMacroParameter(name) => write!(f, "no value given for macro parameter {name}"),
+
+      MacroFileError(path) => write!(f, "unable to read or write macro file {path}"),
+
+      InvalidSession(msg) => write!(f, "invalid session recording: {msg}"),
+
+      SessionFileError(path) => write!(f, "unable to read or write session file {path}"),
+
+      InvalidMidiFile(msg) => write!(f, "invalid MIDI file: {msg}"),
+
+      InvalidFixture(msg) => write!(f, "invalid conformance fixture: {msg}"),
+
+      FixtureFileError(path) => write!(f, "unable to read or write fixture file {path}"),
+
       ResponseDecodingError => write!(f, "failed to decode response from device"),
 
       InvalidBoardIndex(n) => write!(f, "invalid board index: {n}"),
@@ -0,0 +1,245 @@
+//! Channel voice messages, e.g. the note on/off and controller messages the Lumatone
+//! sends when keys are played.
+//!
+//! These are distinct from the sysex messages used to configure the device (see [crate::sysex]).
+
+use std::fmt::Display;
+
+use super::{constants::MidiChannel, error::LumatoneMidiError, sysex::to_hex_debug_str};
+
+use error_stack::{bail, Result};
+
+/// Controller number of the sustain (damper) pedal.
+pub const CC_SUSTAIN: u8 = 64;
+
+/// Controller number of the sostenuto pedal.
+pub const CC_SOSTENUTO: u8 = 66;
+
+/// Channel mode message that immediately silences all sound, including release tails.
+pub const CC_ALL_SOUND_OFF: u8 = 120;
+
+/// Channel mode message that releases all notes (except those held by a pedal).
+pub const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// Controller values at or above this threshold mean "pedal down".
+pub const PEDAL_DOWN_THRESHOLD: u8 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMessage {
+  NoteOff {
+    channel: MidiChannel,
+    note: u8,
+    velocity: u8,
+  },
+  NoteOn {
+    channel: MidiChannel,
+    note: u8,
+    velocity: u8,
+  },
+  PolyAftertouch {
+    channel: MidiChannel,
+    note: u8,
+    pressure: u8,
+  },
+  ControlChange {
+    channel: MidiChannel,
+    controller: u8,
+    value: u8,
+  },
+  ProgramChange {
+    channel: MidiChannel,
+    program: u8,
+  },
+  ChannelPressure {
+    channel: MidiChannel,
+    pressure: u8,
+  },
+  /// 14-bit pitch bend value, with 0x2000 as the center position
+  PitchBend {
+    channel: MidiChannel,
+    value: u16,
+  },
+}
+
+impl ChannelMessage {
+  /// Decodes a channel voice message.
+  ///
+  /// A NoteOn with a velocity of zero is decoded as a NoteOff, since that's what it means.
+  pub fn from_bytes(msg: &[u8]) -> Result<ChannelMessage, LumatoneMidiError> {
+    use ChannelMessage::*;
+
+    if msg.is_empty() || msg[0] & 0x80 == 0 || msg[0] >= 0xf0 {
+      bail!(LumatoneMidiError::InvalidChannelMessage(msg.to_vec()));
+    }
+
+    let status = msg[0] & 0xf0;
+    let channel = MidiChannel::unchecked((msg[0] & 0x0f) + 1);
+    let expected_len = match status {
+      0xc0 | 0xd0 => 2,
+      _ => 3,
+    };
+    if msg.len() < expected_len {
+      bail!(LumatoneMidiError::MessageTooShort {
+        expected: expected_len,
+        actual: msg.len(),
+      });
+    }
+
+    let d1 = msg[1] & 0x7f;
+    let d2 = if expected_len == 3 { msg[2] & 0x7f } else { 0 };
+    let decoded = match status {
+      0x80 => NoteOff {
+        channel,
+        note: d1,
+        velocity: d2,
+      },
+      0x90 if d2 == 0 => NoteOff {
+        channel,
+        note: d1,
+        velocity: 0,
+      },
+      0x90 => NoteOn {
+        channel,
+        note: d1,
+        velocity: d2,
+      },
+      0xa0 => PolyAftertouch {
+        channel,
+        note: d1,
+        pressure: d2,
+      },
+      0xb0 => ControlChange {
+        channel,
+        controller: d1,
+        value: d2,
+      },
+      0xc0 => ProgramChange {
+        channel,
+        program: d1,
+      },
+      0xd0 => ChannelPressure {
+        channel,
+        pressure: d1,
+      },
+      _ => PitchBend {
+        channel,
+        value: ((d2 as u16) << 7) | (d1 as u16),
+      },
+    };
+    Ok(decoded)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    use ChannelMessage::*;
+    let status = |kind: u8| kind | self.channel().get_as_zero_indexed();
+    match *self {
+      NoteOff { note, velocity, .. } => vec![status(0x80), note & 0x7f, velocity & 0x7f],
+      NoteOn { note, velocity, .. } => vec![status(0x90), note & 0x7f, velocity & 0x7f],
+      PolyAftertouch { note, pressure, .. } => vec![status(0xa0), note & 0x7f, pressure & 0x7f],
+      ControlChange {
+        controller, value, ..
+      } => vec![status(0xb0), controller & 0x7f, value & 0x7f],
+      ProgramChange { program, .. } => vec![status(0xc0), program & 0x7f],
+      ChannelPressure { pressure, .. } => vec![status(0xd0), pressure & 0x7f],
+      PitchBend { value, .. } => vec![
+        status(0xe0),
+        (value & 0x7f) as u8,
+        ((value >> 7) & 0x7f) as u8,
+      ],
+    }
+  }
+
+  pub fn channel(&self) -> MidiChannel {
+    use ChannelMessage::*;
+    match *self {
+      NoteOff { channel, .. } => channel,
+      NoteOn { channel, .. } => channel,
+      PolyAftertouch { channel, .. } => channel,
+      ControlChange { channel, .. } => channel,
+      ProgramChange { channel, .. } => channel,
+      ChannelPressure { channel, .. } => channel,
+      PitchBend { channel, .. } => channel,
+    }
+  }
+
+  /// Returns a copy of this message, sent on a different channel.
+  pub fn with_channel(&self, channel: MidiChannel) -> ChannelMessage {
+    use ChannelMessage::*;
+    let mut msg = *self;
+    match &mut msg {
+      NoteOff { channel: c, .. } => *c = channel,
+      NoteOn { channel: c, .. } => *c = channel,
+      PolyAftertouch { channel: c, .. } => *c = channel,
+      ControlChange { channel: c, .. } => *c = channel,
+      ProgramChange { channel: c, .. } => *c = channel,
+      ChannelPressure { channel: c, .. } => *c = channel,
+      PitchBend { channel: c, .. } => *c = channel,
+    }
+    msg
+  }
+}
+
+impl Display for ChannelMessage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?} {}", self, to_hex_debug_str(&self.to_bytes()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_channel_message_round_trip() {
+    let channel = MidiChannel::unchecked(3);
+    let messages = vec![
+      ChannelMessage::NoteOn {
+        channel,
+        note: 60,
+        velocity: 100,
+      },
+      ChannelMessage::NoteOff {
+        channel,
+        note: 60,
+        velocity: 10,
+      },
+      ChannelMessage::ControlChange {
+        channel,
+        controller: CC_SUSTAIN,
+        value: 127,
+      },
+      ChannelMessage::ChannelPressure {
+        channel,
+        pressure: 42,
+      },
+      ChannelMessage::PitchBend {
+        channel,
+        value: 0x2001,
+      },
+    ];
+
+    for msg in messages {
+      let decoded = ChannelMessage::from_bytes(&msg.to_bytes()).unwrap();
+      assert_eq!(decoded, msg);
+    }
+  }
+
+  #[test]
+  fn test_note_on_with_zero_velocity_is_note_off() {
+    let decoded = ChannelMessage::from_bytes(&[0x90, 60, 0]).unwrap();
+    assert_eq!(
+      decoded,
+      ChannelMessage::NoteOff {
+        channel: MidiChannel::default(),
+        note: 60,
+        velocity: 0
+      }
+    );
+  }
+
+  #[test]
+  fn test_sysex_is_not_a_channel_message() {
+    assert!(ChannelMessage::from_bytes(&[0xf0, 0x00, 0x21, 0x50, 0xf7]).is_err());
+    assert!(ChannelMessage::from_bytes(&[0x90, 60]).is_err());
+  }
+}
@@ -0,0 +1,138 @@
+//! Selects between payload layouts that changed across firmware revisions.
+//!
+//! Firmware older than [EXTENDED_COLOR_VERSION] sends and receives LED colors as one 7-bit
+//! value per channel. Newer firmware splits each 8-bit channel into a pair of 4-bit nibbles.
+//!
+//! A [FirmwareSupport] is built from the device's [FirmwareVersion] and passed to
+//! [Command::to_sysex_message_for](crate::commands::Command::to_sysex_message_for) and
+//! [Response::from_sysex_message_for](crate::responses::Response::from_sysex_message_for).
+//! When the version isn't known, [FirmwareSupport::default] assumes the latest layouts.
+//!
+//! ## Batched key colors
+//!
+//! No released firmware accepts more than one key per color message: every command up to
+//! [InvertSustainPedal](crate::constants::CommandId::InvertSustainPedal) addresses a single
+//! key, or reads back a whole board. A full-board recolor is therefore always one
+//! [SetKeyColor](crate::commands::Command::SetKeyColor) per key. To keep recolors fast, send
+//! only the keys that change, e.g. with [DesiredState::plan](crate::reconcile::DesiredState::plan).
+//! If a future firmware adds a batched color command, its layout belongs here alongside
+//! [ColorEncoding], so older devices keep getting per-key commands.
+//!
+//! ## Presets
+//!
+//! [SaveProgram](crate::commands::Command::SaveProgram) is the only preset command. No
+//! released firmware has a sysex command that recalls a preset slot or reports which slot is
+//! active: presets are recalled with the buttons above the keyboard, and nothing in the
+//! sysex protocol tells the host when that happens. To switch layouts from code, send the
+//! layout itself, e.g. the commands from the keymap crate's
+//! `LumatoneKeyMap::to_midi_commands`. If a future firmware adds recall or query commands,
+//! they belong in [CommandId](crate::constants::CommandId) next to `SaveProgram`.
+//!
+//! ## Updating firmware
+//!
+//! This crate can't install firmware. The device has no sysex command for receiving an
+//! image: the official editor copies the update over SSH to the board's USB network
+//! interface, which is outside what a MIDI connection can reach.
+
+use super::{constants::RGBColor, responses::FirmwareVersion};
+
+/// The first firmware version that uses 8-bit ("extended") LED colors.
+pub const EXTENDED_COLOR_VERSION: FirmwareVersion = FirmwareVersion::new(1, 0, 9);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorEncoding {
+  /// One 7-bit value per channel.
+  SevenBit,
+
+  /// Each 8-bit channel is split into a high and low nibble.
+  Extended,
+}
+
+impl ColorEncoding {
+  /// Encodes a color for a key or macro button color command.
+  pub fn encode(&self, color: &RGBColor) -> Vec<u8> {
+    match self {
+      ColorEncoding::SevenBit => color.to_7bit_bytes(),
+      ColorEncoding::Extended => color.to_bytes(),
+    }
+  }
+}
+
+/// The payload layouts supported by a particular firmware version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareSupport {
+  pub color_encoding: ColorEncoding,
+}
+
+impl FirmwareSupport {
+  pub fn for_version(version: &FirmwareVersion) -> Self {
+    let color_encoding = if *version >= EXTENDED_COLOR_VERSION {
+      ColorEncoding::Extended
+    } else {
+      ColorEncoding::SevenBit
+    };
+    FirmwareSupport { color_encoding }
+  }
+}
+
+impl Default for FirmwareSupport {
+  fn default() -> Self {
+    FirmwareSupport {
+      color_encoding: ColorEncoding::Extended,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    commands::set_key_color,
+    constants::{BoardIndex, LumatoneKeyIndex, LumatoneKeyLocation},
+    sysex::PAYLOAD_INIT,
+  };
+
+  #[test]
+  fn test_support_for_version() {
+    let old = FirmwareSupport::for_version(&FirmwareVersion::new(1, 0, 8));
+    assert_eq!(old.color_encoding, ColorEncoding::SevenBit);
+
+    let new = FirmwareSupport::for_version(&EXTENDED_COLOR_VERSION);
+    assert_eq!(new.color_encoding, ColorEncoding::Extended);
+
+    let newer = FirmwareSupport::for_version(&FirmwareVersion::new(1, 1, 0));
+    assert_eq!(newer, FirmwareSupport::default());
+  }
+
+  #[test]
+  fn test_encode_color() {
+    let color = RGBColor(0xff, 0x80, 0x13);
+    assert_eq!(
+      ColorEncoding::SevenBit.encode(&color),
+      vec![0x7f, 0x40, 0x09]
+    );
+    assert_eq!(
+      ColorEncoding::Extended.encode(&color),
+      vec![0xf, 0xf, 0x8, 0x0, 0x1, 0x3]
+    );
+  }
+
+  #[test]
+  fn test_encode_key_color_command() {
+    let location = LumatoneKeyLocation(BoardIndex::Octave3, LumatoneKeyIndex::unchecked(7));
+    let cmd = set_key_color(location, RGBColor(0xff, 0x80, 0x13));
+    let old_firmware = FirmwareSupport::for_version(&FirmwareVersion::new(1, 0, 8));
+
+    let msg = cmd.to_sysex_message_for(&old_firmware);
+    assert_eq!(
+      &msg[PAYLOAD_INIT..PAYLOAD_INIT + 4],
+      &[7, 0x7f, 0x40, 0x09]
+    );
+
+    let msg = cmd.to_sysex_message();
+    assert_eq!(
+      &msg[PAYLOAD_INIT..PAYLOAD_INIT + 7],
+      &[7, 0xf, 0xf, 0x8, 0x0, 0x1, 0x3]
+    );
+  }
+}
@@ -0,0 +1,120 @@
+//! A summary of the connected device's identity and hardware, for display and diagnostics.
+
+use std::fmt::Display;
+
+use super::{
+  constants::BoardIndex,
+  controller::Lumatone,
+  device::LumatoneDevice,
+  error::LumatoneMidiError,
+  responses::{FirmwareVersion, SerialIdentity},
+};
+
+use error_stack::Result;
+use log::debug;
+
+/// A key board that responded when the device was queried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoardInfo {
+  pub board_index: BoardIndex,
+
+  /// The number of keys on the board. 56 on current hardware, 55 on some older boards.
+  pub key_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+  pub serial: SerialIdentity,
+  pub firmware_version: FirmwareVersion,
+  pub boards: Vec<BoardInfo>,
+  pub in_port_name: String,
+  pub out_port_name: String,
+}
+
+impl DeviceInfo {
+  pub fn board_count(&self) -> usize {
+    self.boards.len()
+  }
+}
+
+impl Display for DeviceInfo {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Lumatone serial {}, firmware {}, {} boards",
+      self.serial,
+      self.firmware_version,
+      self.board_count()
+    )?;
+    if let Some(board) = self.boards.iter().find(|b| b.key_count != 56) {
+      write!(f, " ({} has {} keys)", board.board_index, board.key_count)?;
+    }
+    write!(
+      f,
+      ", input port \"{}\", output port \"{}\"",
+      self.in_port_name, self.out_port_name
+    )
+  }
+}
+
+impl Lumatone {
+  /// Queries the device for its serial number, firmware version, and boards.
+  ///
+  /// A board counts as present if it answers a key validity query. Boards that don't
+  /// answer are logged and left out.
+  pub(crate) async fn query_device_info(
+    &self,
+    device: &LumatoneDevice,
+  ) -> Result<DeviceInfo, LumatoneMidiError> {
+    let serial = self.get_serial_id().await?;
+    let firmware_version = self.sync_firmware_version().await?;
+
+    let mut boards = vec![];
+    for board_index in BoardIndex::all_octaves() {
+      match self.get_key_validity(board_index).await {
+        Ok(validity) => boards.push(BoardInfo {
+          board_index,
+          key_count: validity.values.len(),
+        }),
+        Err(err) => debug!("no response from {board_index}: {err:?}"),
+      }
+    }
+
+    Ok(DeviceInfo {
+      serial,
+      firmware_version,
+      boards,
+      in_port_name: device.in_port_name().to_string(),
+      out_port_name: device.out_port_name().to_string(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_display() {
+    let mut info = DeviceInfo {
+      serial: SerialIdentity([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]),
+      firmware_version: FirmwareVersion::new(1, 0, 12),
+      boards: BoardIndex::all_octaves()
+        .into_iter()
+        .map(|board_index| BoardInfo {
+          board_index,
+          key_count: 56,
+        })
+        .collect(),
+      in_port_name: "Lumatone".to_string(),
+      out_port_name: "Lumatone".to_string(),
+    };
+    assert_eq!(
+      info.to_string(),
+      "Lumatone serial 123456789abc, firmware 1.0.12, 5 boards, input port \"Lumatone\", output port \"Lumatone\""
+    );
+
+    info.boards[1].key_count = 55;
+    assert!(info.to_string().contains("5 boards (Octave2 has 55 keys)"));
+  }
+}
@@ -1,10 +1,65 @@
+pub mod aftertouch;
+pub mod brightness;
+#[cfg(feature = "driver")]
+pub mod calibration;
+pub mod cc_map;
+#[cfg(feature = "driver")]
+pub mod channels;
+#[cfg(feature = "driver")]
+pub mod clock;
 pub mod commands;
+#[cfg(feature = "driver")]
+pub mod conformance;
 pub mod constants;
+#[cfg(feature = "driver")]
+pub mod controller;
+pub mod curve;
+#[cfg(feature = "driver")]
 pub mod detect;
+#[cfg(feature = "driver")]
 pub mod device;
+#[cfg(feature = "driver")]
+pub mod diagnostics;
+#[cfg(feature = "driver")]
 pub mod driver;
 pub mod error;
+pub mod events;
+pub mod firmware;
+#[cfg(feature = "driver")]
+pub mod info;
+pub mod metrics;
+pub mod mirror;
+pub mod ports;
+#[cfg(feature = "driver")]
+pub mod proxy;
+#[cfg(feature = "driver")]
+pub mod queries;
+#[cfg(feature = "driver")]
+pub mod readback;
+#[cfg(feature = "driver")]
+pub mod reconcile;
+pub mod recorder;
 pub mod responses;
+pub mod routing;
+#[cfg(feature = "driver")]
+pub mod resync;
+pub mod session;
+#[cfg(feature = "driver")]
+pub mod settings;
+#[cfg(feature = "driver")]
+pub mod shutdown;
+pub mod smf;
+#[cfg(feature = "driver")]
+pub mod suspend;
+#[cfg(feature = "soak")]
+pub mod soak;
 pub mod sysex;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timing;
+pub mod traffic;
+#[cfg(feature = "driver")]
+pub mod transport;
+pub mod zones;
 
 // TODO: public API entrypoints go here
@@ -0,0 +1,219 @@
+//! Counters for monitoring a long-running driver.
+//!
+//! The driver updates a [DriverMetrics] as it goes, using atomics so that reading them
+//! never blocks the driver loop. [DriverMetrics::snapshot] copies the current values into a
+//! [MetricsSnapshot], which is what [MidiDriver::metrics](crate::driver::MidiDriver::metrics)
+//! returns. Counters only ever go up, so rates (e.g. commands per second) can be worked out
+//! by whatever is scraping them.
+
+use std::{
+  sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+  time::Duration,
+};
+
+/// Upper bounds of the response latency histogram buckets. The device usually answers in a
+/// few milliseconds, and the driver gives up after 30 seconds.
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+  Duration::from_millis(5),
+  Duration::from_millis(10),
+  Duration::from_millis(25),
+  Duration::from_millis(50),
+  Duration::from_millis(100),
+  Duration::from_millis(250),
+  Duration::from_millis(500),
+  Duration::from_secs(1),
+  Duration::from_secs(5),
+  Duration::from_secs(30),
+];
+
+#[derive(Debug, Default)]
+pub struct DriverMetrics {
+  commands_sent: AtomicU64,
+  commands_resent: AtomicU64,
+  responses: AtomicU64,
+  failures: AtomicU64,
+  restarts: AtomicU64,
+  reconnects: AtomicU64,
+  shed: AtomicU64,
+  unexpected: AtomicU64,
+  dropped_incoming: AtomicU64,
+  missed_by_subscribers: AtomicU64,
+  queue_depth: AtomicUsize,
+  latency: LatencyHistogram,
+}
+
+impl DriverMetrics {
+  pub(crate) fn command_sent(&self) {
+    self.commands_sent.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn command_resent(&self) {
+    self.commands_resent.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a response (or an error in place of one) and how long it took to arrive.
+  pub(crate) fn response(&self, ok: bool, latency: Option<Duration>) {
+    match ok {
+      true => self.responses.fetch_add(1, Ordering::Relaxed),
+      false => self.failures.fetch_add(1, Ordering::Relaxed),
+    };
+    if let Some(latency) = latency {
+      self.latency.observe(latency);
+    }
+  }
+
+  pub(crate) fn restarted(&self) {
+    self.restarts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn reconnected(&self) {
+    self.reconnects.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn shed(&self) {
+    self.shed.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub(crate) fn unexpected_message(&self) {
+    self.unexpected.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// The transport keeps its own count of dropped messages, which the driver copies here.
+  pub(crate) fn set_dropped_incoming(&self, dropped: u64) {
+    self.dropped_incoming.store(dropped, Ordering::Relaxed);
+  }
+
+  pub(crate) fn subscriber_lagged(&self, missed: u64) {
+    self
+      .missed_by_subscribers
+      .fetch_add(missed, Ordering::Relaxed);
+  }
+
+  pub(crate) fn set_queue_depth(&self, depth: usize) {
+    self.queue_depth.store(depth, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> MetricsSnapshot {
+    MetricsSnapshot {
+      commands_sent: self.commands_sent.load(Ordering::Relaxed),
+      commands_resent: self.commands_resent.load(Ordering::Relaxed),
+      responses: self.responses.load(Ordering::Relaxed),
+      failures: self.failures.load(Ordering::Relaxed),
+      restarts: self.restarts.load(Ordering::Relaxed),
+      reconnects: self.reconnects.load(Ordering::Relaxed),
+      shed: self.shed.load(Ordering::Relaxed),
+      unexpected: self.unexpected.load(Ordering::Relaxed),
+      dropped_incoming: self.dropped_incoming.load(Ordering::Relaxed),
+      missed_by_subscribers: self.missed_by_subscribers.load(Ordering::Relaxed),
+      queue_depth: self.queue_depth.load(Ordering::Relaxed),
+      latency: self.latency.snapshot(),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct LatencyHistogram {
+  buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+  count: AtomicU64,
+  sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+  fn observe(&self, latency: Duration) {
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|b| latency <= *b) {
+      self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self
+      .sum_micros
+      .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+  }
+
+  fn snapshot(&self) -> HistogramSnapshot {
+    let mut total = 0;
+    let buckets = LATENCY_BUCKETS
+      .iter()
+      .zip(&self.buckets)
+      .map(|(bound, count)| {
+        total += count.load(Ordering::Relaxed);
+        (*bound, total)
+      })
+      .collect();
+    HistogramSnapshot {
+      buckets,
+      count: self.count.load(Ordering::Relaxed),
+      sum: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+  /// Commands sent to the device, each counted once however many times it was sent.
+  pub commands_sent: u64,
+  /// Commands sent again, because the device was busy or the driver restarted before
+  /// they were answered.
+  pub commands_resent: u64,
+  /// Commands the device answered successfully.
+  pub responses: u64,
+  /// Commands that failed, e.g. with a NACK or a timeout. Each command is counted as
+  /// answered or failed at most once.
+  pub failures: u64,
+  /// How many times the driver restarted after a transient failure.
+  pub restarts: u64,
+  /// How many times the driver reopened the MIDI connection, e.g. after the host slept or
+  /// the device was unplugged.
+  pub reconnects: u64,
+  /// Commands dropped because their deadline passed.
+  pub shed: u64,
+  /// Messages that arrived while waiting for a response, but didn't answer the command
+  /// that was sent.
+  pub unexpected: u64,
+  /// Messages from the device dropped because the driver fell behind. Updated about once
+  /// a second.
+  pub dropped_incoming: u64,
+  /// Broadcast messages that subscribers lost because they fell behind, as reported with
+  /// [MidiDriver::count_missed](crate::driver::MidiDriver::count_missed).
+  pub missed_by_subscribers: u64,
+  /// Commands waiting to be sent.
+  pub queue_depth: usize,
+  pub latency: HistogramSnapshot,
+}
+
+/// Response latencies, in the cumulative form Prometheus expects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+  /// Each bucket's upper bound, and how many observations were at or below it.
+  pub buckets: Vec<(Duration, u64)>,
+  /// The total number of observations, including any above the largest bucket.
+  pub count: u64,
+  pub sum: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_latency_buckets_are_cumulative() {
+    let metrics = DriverMetrics::default();
+    metrics.command_sent();
+    metrics.response(true, Some(Duration::from_millis(3)));
+    metrics.response(true, Some(Duration::from_millis(40)));
+    metrics.response(false, Some(Duration::from_secs(60)));
+    metrics.set_queue_depth(4);
+    metrics.subscriber_lagged(3);
+    metrics.subscriber_lagged(2);
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.responses, 2);
+    assert_eq!(snapshot.failures, 1);
+    assert_eq!(snapshot.queue_depth, 4);
+    assert_eq!(snapshot.missed_by_subscribers, 5);
+    assert_eq!(snapshot.latency.buckets[0], (Duration::from_millis(5), 1));
+    assert_eq!(snapshot.latency.buckets[3], (Duration::from_millis(50), 2));
+    assert_eq!(snapshot.latency.buckets.last().unwrap().1, 2);
+    assert_eq!(snapshot.latency.count, 3);
+    assert_eq!(snapshot.latency.sum, Duration::from_millis(60_043));
+  }
+}
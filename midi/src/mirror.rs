@@ -0,0 +1,145 @@
+//! Keeps a copy of the configuration sent to the device, so it can be re-applied if the
+//! device resets (see [crate::resync]).
+//!
+//! The [ConfigMirror] stores the most recent [Command] for each setting, so sending the
+//! same key color twice only keeps the second one. Commands that don't change the
+//! configuration (queries, pings, calibration, etc.) aren't stored.
+
+use std::collections::HashMap;
+
+use super::{
+  commands::Command,
+  constants::{BoardIndex, CommandId, LumatoneKeyLocation},
+};
+
+/// Identifies the setting that a [Command] changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum MirrorSlot {
+  Key(CommandId, LumatoneKeyLocation),
+  Board(CommandId, BoardIndex),
+  Global(CommandId),
+}
+
+impl MirrorSlot {
+  fn for_command(command: &Command) -> Option<MirrorSlot> {
+    use Command::*;
+    let id = command.command_id();
+    match command {
+      SetKeyFunction { location, .. } | SetKeyColor { location, .. } => {
+        Some(MirrorSlot::Key(id, *location))
+      }
+
+      SetKeyMaximumThreshold { board_index, .. }
+      | SetKeyMinimumThreshold { board_index, .. }
+      | SetKeyFaderSensitivity(board_index, _)
+      | SetKeyAftertouchSensitivity(board_index, _)
+      | SetCCActiveThreshold(board_index, _)
+      | SetAftertouchTriggerDelay(board_index, _)
+      | SetLumatouchNoteOffDelay(board_index, _) => Some(MirrorSlot::Board(id, *board_index)),
+
+      SetExpressionPedalSensitivity(_)
+      | SetModWheelSensitivity(_)
+      | SetPitchWheelSensitivity(_)
+      | InvertFootController(_)
+      | InvertSustainPedal(_)
+      | SetLightOnKeystrokes(_)
+      | SetAftertouchEnabled(_)
+      | SetMacroButtonActiveColor(_)
+      | SetMacroButtonInactiveColor(_)
+      | SetVelocityConfig(_)
+      | SetFaderConfig(_)
+      | SetAftertouchConfig(_)
+      | SetLumatouchConfig(_)
+      | SetVelocityIntervals(_)
+      | SetPitchWheelZeroThreshold(_)
+      | SetPeripheralChannels { .. }
+      | SetExpressionPedalADCThreshold(_) => Some(MirrorSlot::Global(id)),
+
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigMirror {
+  slots: HashMap<MirrorSlot, (u64, Command)>,
+  next_seq: u64,
+}
+
+impl ConfigMirror {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Stores `command` if it changes the device configuration, replacing any earlier
+  /// command for the same setting.
+  pub fn record(&mut self, command: &Command) {
+    if let Some(slot) = MirrorSlot::for_command(command) {
+      self.slots.insert(slot, (self.next_seq, command.clone()));
+      self.next_seq += 1;
+    }
+  }
+
+  /// Returns the stored commands, in the order they were recorded.
+  pub fn commands(&self) -> Vec<Command> {
+    let mut entries: Vec<&(u64, Command)> = self.slots.values().collect();
+    entries.sort_by_key(|(seq, _)| *seq);
+    entries.into_iter().map(|(_, cmd)| cmd.clone()).collect()
+  }
+
+  /// Whether `command` is the stored command for the setting it changes, i.e. sending it
+  /// again wouldn't change anything.
+  pub fn contains(&self, command: &Command) -> bool {
+    MirrorSlot::for_command(command)
+      .and_then(|slot| self.slots.get(&slot))
+      .is_some_and(|(_, stored)| stored == command)
+  }
+
+  pub fn len(&self) -> usize {
+    self.slots.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.slots.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.slots.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    commands::{ping, set_key_color},
+    constants::{key_loc_unchecked, RGBColor},
+  };
+
+  #[test]
+  fn test_mirror_keeps_latest_per_setting() {
+    let mut mirror = ConfigMirror::new();
+    mirror.record(&set_key_color(key_loc_unchecked(1, 0), RGBColor::red()));
+    mirror.record(&Command::SetLightOnKeystrokes(true));
+    mirror.record(&set_key_color(key_loc_unchecked(1, 1), RGBColor::green()));
+    mirror.record(&ping(1));
+    mirror.record(&Command::GetSerialId);
+    mirror.record(&set_key_color(key_loc_unchecked(1, 0), RGBColor::blue()));
+    mirror.record(&Command::SetKeyFaderSensitivity(BoardIndex::Octave2, 10));
+    mirror.record(&Command::SetKeyFaderSensitivity(BoardIndex::Octave3, 20));
+
+    assert_eq!(
+      mirror.commands(),
+      vec![
+        Command::SetLightOnKeystrokes(true),
+        set_key_color(key_loc_unchecked(1, 1), RGBColor::green()),
+        set_key_color(key_loc_unchecked(1, 0), RGBColor::blue()),
+        Command::SetKeyFaderSensitivity(BoardIndex::Octave2, 10),
+        Command::SetKeyFaderSensitivity(BoardIndex::Octave3, 20),
+      ]
+    );
+
+    mirror.clear();
+    assert!(mirror.is_empty());
+  }
+}
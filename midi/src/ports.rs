@@ -0,0 +1,203 @@
+//! Matches MIDI port names across platforms.
+//!
+//! The same device shows up under different port names depending on the OS and MIDI API:
+//!
+//! - Windows adds a number to the front of duplicates: `Lumatone`, `2- Lumatone`,
+//! - ALSA adds the client name and a client:port address: `Lumatone:Lumatone MIDI 1 24:0`,
+//!   and the address can change when the device is plugged back in,
+//! - CoreMIDI and other apps number duplicates at the end: `Lumatone 2`, `Lumatone (2)`.
+//!
+//! [PortName] splits a name into a normalized base name and a duplicate number, so that
+//! [find_port] can find a port again after it's been renamed (e.g. when reconnecting after
+//! the host wakes from sleep), and [lumatone_ports] can pick out the ports that look like a
+//! Lumatone during detection.
+
+use std::{cmp::Ordering, collections::HashSet};
+
+/// The word that identifies a Lumatone's ports, compared case-insensitively.
+pub const LUMATONE_PORT_KEYWORD: &str = "lumatone";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortName {
+  /// The name as the MIDI API reported it.
+  pub name: String,
+
+  /// The name in lower case, without platform decorations or a duplicate number.
+  pub base: String,
+
+  /// Which of several ports with the same base name this is. 0 for the first one, which
+  /// usually has no number.
+  pub duplicate: u32,
+}
+
+impl PortName {
+  pub fn parse(name: &str) -> PortName {
+    let mut base = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut duplicate = 0;
+
+    // ALSA: "client:port 24:0"
+    if let Some((rest, address)) = base.rsplit_once(' ') {
+      if is_alsa_address(address) {
+        base = rest.to_string();
+      }
+    }
+    if let Some((_, port)) = base.split_once(':') {
+      base = port.trim().to_string();
+    }
+
+    // Windows: "2- Lumatone"
+    if let Some((n, rest)) = base.split_once("- ") {
+      if let Ok(n) = n.parse() {
+        duplicate = n;
+        base = rest.to_string();
+      }
+    }
+
+    // "Lumatone (2)", "Lumatone #2" or "Lumatone 2"
+    if duplicate == 0 {
+      if let Some((rest, n)) = base.rsplit_once(' ') {
+        let n = n.trim_start_matches('#').trim_start_matches('(');
+        if let Ok(n) = n.trim_end_matches(')').parse() {
+          duplicate = n;
+          base = rest.to_string();
+        }
+      }
+    }
+
+    PortName {
+      name: name.to_string(),
+      base: base.to_lowercase(),
+      duplicate,
+    }
+  }
+
+  pub fn is_lumatone(&self) -> bool {
+    self.base.contains(LUMATONE_PORT_KEYWORD)
+  }
+}
+
+fn is_alsa_address(s: &str) -> bool {
+  match s.split_once(':') {
+    Some((client, port)) => {
+      !client.is_empty()
+        && !port.is_empty()
+        && client.chars().all(|c| c.is_ascii_digit())
+        && port.chars().all(|c| c.is_ascii_digit())
+    }
+    None => false,
+  }
+}
+
+/// Orders ports by duplicate number, then by their position in the port list.
+fn by_duplicate(a: &(usize, PortName), b: &(usize, PortName)) -> Ordering {
+  (a.1.duplicate, a.0).cmp(&(b.1.duplicate, b.0))
+}
+
+/// Returns the index of the port in `names` that best matches `wanted`:
+///
+/// 1. a port with exactly that name,
+/// 2. a port with the same base name and duplicate number,
+/// 3. the first port with the same base name,
+/// 4. if `wanted` is a Lumatone port, the first port that looks like a Lumatone.
+///
+/// "First" means the lowest duplicate number, so the choice doesn't depend on the order the
+/// OS lists ports in.
+pub fn find_port<S: AsRef<str>>(names: &[S], wanted: &str) -> Option<usize> {
+  if let Some(i) = names.iter().position(|n| n.as_ref() == wanted) {
+    return Some(i);
+  }
+
+  let wanted = PortName::parse(wanted);
+  let mut ports: Vec<(usize, PortName)> = names
+    .iter()
+    .map(|n| PortName::parse(n.as_ref()))
+    .enumerate()
+    .collect();
+  ports.sort_by(by_duplicate);
+
+  ports
+    .iter()
+    .find(|(_, p)| p.base == wanted.base && p.duplicate == wanted.duplicate)
+    .or_else(|| ports.iter().find(|(_, p)| p.base == wanted.base))
+    .or_else(|| {
+      ports
+        .iter()
+        .find(|(_, p)| wanted.is_lumatone() && p.is_lumatone())
+    })
+    .map(|(i, _)| *i)
+}
+
+/// Returns the indices of the ports in `names` that look like a Lumatone, lowest duplicate
+/// number first. Ports that have the same base name and duplicate number as an earlier one
+/// are left out.
+pub fn lumatone_ports<S: AsRef<str>>(names: &[S]) -> Vec<usize> {
+  let mut ports: Vec<(usize, PortName)> = names
+    .iter()
+    .map(|n| PortName::parse(n.as_ref()))
+    .enumerate()
+    .filter(|(_, p)| p.is_lumatone())
+    .collect();
+  ports.sort_by(by_duplicate);
+  let mut seen = HashSet::new();
+  ports
+    .into_iter()
+    .filter(|(_, p)| seen.insert((p.base.clone(), p.duplicate)))
+    .map(|(i, _)| i)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_port_names() {
+    let parse = |name| {
+      let p = PortName::parse(name);
+      (p.base, p.duplicate)
+    };
+    assert_eq!(parse("Lumatone"), ("lumatone".to_string(), 0));
+    assert_eq!(parse("2- Lumatone"), ("lumatone".to_string(), 2));
+    assert_eq!(parse("Lumatone (3)"), ("lumatone".to_string(), 3));
+    assert_eq!(parse("Lumatone #2"), ("lumatone".to_string(), 2));
+    assert_eq!(
+      parse("Lumatone:Lumatone MIDI 1 24:0"),
+      ("lumatone midi".to_string(), 1)
+    );
+    assert!(PortName::parse("LUMATONE  Port").is_lumatone());
+    assert!(!PortName::parse("IAC Driver Bus 1").is_lumatone());
+  }
+
+  #[test]
+  fn test_find_port() {
+    let names = [
+      "Midi Through:Midi Through Port-0 14:0",
+      "Lumatone:Lumatone MIDI 1 28:0",
+    ];
+    assert_eq!(find_port(&names, "Lumatone:Lumatone MIDI 1 28:0"), Some(1));
+    // the ALSA address changed after the device was plugged back in
+    assert_eq!(find_port(&names, "Lumatone:Lumatone MIDI 1 24:0"), Some(1));
+    assert_eq!(find_port(&names, "Lumatone"), Some(1));
+    assert_eq!(find_port(&names, "Keystep"), None);
+
+    let names = ["2- Lumatone", "Keystep", "Lumatone"];
+    assert_eq!(find_port(&names, "Lumatone"), Some(2));
+    assert_eq!(find_port(&names, "3- Lumatone"), Some(2));
+    assert_eq!(find_port(&names, "Lumatone 2"), Some(0));
+  }
+
+  #[test]
+  fn test_lumatone_ports() {
+    let names = [
+      "2- Lumatone",
+      "Keystep",
+      "Lumatone",
+      "Lumatone",
+      "Lumatone (2)",
+    ];
+    assert_eq!(lumatone_ports(&names), vec![2, 0]);
+    let names = ["Lumatone", "Lumatone MIDI", "Lumatone"];
+    assert_eq!(lumatone_ports(&names), vec![0, 1]);
+    assert!(lumatone_ports(&["Keystep"]).is_empty());
+  }
+}
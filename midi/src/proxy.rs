@@ -0,0 +1,1185 @@
+//! A note proxy that sits between the Lumatone's note output and a synth, re-mapping
+//! notes on the way through.
+//!
+//! The proxy keeps a table of "held" notes, recording the outgoing note that each
+//! incoming key was mapped to. Note-offs are always sent to the recorded outgoing note,
+//! so changing the [NoteMapping] while keys are down (or while the sustain or sostenuto
+//! pedal is holding them) never leaves a note stuck on. When the mapping changes, the
+//! proxy emits corrective note-offs for anything that would now map somewhere else.
+//!
+//! [NoteProxy] doesn't do any I/O itself. Feed it incoming [ChannelMessage]s with
+//! [NoteProxy::process], and send the messages it returns to the output port.
+//! Or use [NoteProxy::start] to run the proxy in its own task, reading from the device's
+//! event stream and writing to a [ProxyOutput].
+//!
+//! Polyphonic aftertouch can optionally be smoothed and reshaped on the way through (see
+//! [crate::aftertouch] and [NoteProxy::set_aftertouch]), and note-on velocities can be
+//! reshaped per [zone](crate::zones) with [NoteProxy::set_velocity_zones]. Zones can also
+//! convert polyphonic aftertouch to channel pressure or back again, with
+//! [NoteProxy::set_pressure_zones].
+//!
+//! Control changes go through a [CcMap] first, so controllers can be renamed, rescaled,
+//! blocked or merged (see [crate::cc_map]). A controller mapped to the sustain or sostenuto
+//! pedal is treated as that pedal.
+//!
+//! The proxy can send to several outputs at once (see [MultiOutput]), with a
+//! [RoutingMatrix] deciding which messages go where.
+//!
+//! When the event stream closes (e.g. because the device was disconnected) or the proxy is
+//! shut down, the proxy [panic](NoteProxy::panic)s before exiting, so nothing is left sounding.
+
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+};
+
+use futures::Future;
+use log::{debug, info, warn};
+use midir::{MidiOutput, MidiOutputConnection};
+use tokio::sync::{broadcast, mpsc};
+
+use super::{
+  aftertouch::{AftertouchConfig, AftertouchProcessor, PressureConversion},
+  cc_map::CcMap,
+  constants::MidiChannel,
+  device::get_port_by_name,
+  error::LumatoneMidiError,
+  events::{
+    ChannelMessage, CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF, CC_SOSTENUTO, CC_SUSTAIN,
+    PEDAL_DOWN_THRESHOLD,
+  },
+  routing::RoutingMatrix,
+  shutdown::CancellationToken,
+  sysex::to_hex_debug_str,
+  zones::{apply_velocity_zones, PressureZone, VelocityZone},
+};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+/// Identifies a single note on a single MIDI channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NoteKey {
+  pub channel: MidiChannel,
+  pub note: u8,
+}
+
+/// Determines where an incoming note gets sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NoteMapping {
+  /// Number of semitones to shift each note by.
+  pub transpose: i8,
+
+  /// If set, all messages are sent on this channel instead of the channel they arrived on.
+  pub output_channel: Option<MidiChannel>,
+}
+
+impl NoteMapping {
+  /// Returns the outgoing channel for messages arriving on `channel`.
+  pub fn map_channel(&self, channel: MidiChannel) -> MidiChannel {
+    self.output_channel.unwrap_or(channel)
+  }
+
+  /// Returns the outgoing note for an incoming note, or `None` if transposition
+  /// would push it outside the valid MIDI note range.
+  pub fn map_note(&self, key: NoteKey) -> Option<NoteKey> {
+    let note = key.note as i16 + self.transpose as i16;
+    if !(0..=127).contains(&note) {
+      return None;
+    }
+    Some(NoteKey {
+      channel: self.map_channel(key.channel),
+      note: note as u8,
+    })
+  }
+}
+
+/// A note that's been sent to the output and is (probably) still sounding.
+#[derive(Debug, Clone, Copy)]
+struct HeldNote {
+  input: NoteKey,
+  output: NoteKey,
+
+  /// The key is physically held down.
+  key_down: bool,
+
+  /// The key was released while the sustain pedal was down.
+  sustained: bool,
+
+  /// The key was held when the sostenuto pedal went down.
+  sostenuto: bool,
+
+  /// The last aftertouch pressure for the key.
+  pressure: u8,
+}
+
+impl HeldNote {
+  fn is_sounding(&self) -> bool {
+    self.key_down || self.sustained || self.sostenuto
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PedalState {
+  sustain: bool,
+  sostenuto: bool,
+}
+
+#[derive(Debug)]
+pub struct NoteProxy {
+  mapping: NoteMapping,
+  held: Vec<HeldNote>,
+
+  /// Pedal state, keyed by the channel the pedal messages arrive on.
+  pedals: HashMap<MidiChannel, PedalState>,
+
+  aftertouch: Option<AftertouchProcessor>,
+  cc_map: CcMap,
+  velocity_zones: Vec<VelocityZone>,
+  pressure_zones: Vec<PressureZone>,
+
+  /// The last channel pressure sent for converted aftertouch, keyed by outgoing channel.
+  channel_pressure: HashMap<MidiChannel, u8>,
+
+  /// If `None`, every message goes to every output.
+  routing: Option<RoutingMatrix>,
+}
+
+impl NoteProxy {
+  pub fn new(mapping: NoteMapping) -> Self {
+    NoteProxy {
+      mapping,
+      held: vec![],
+      pedals: HashMap::new(),
+      aftertouch: None,
+      cc_map: CcMap::default(),
+      velocity_zones: vec![],
+      pressure_zones: vec![],
+      channel_pressure: HashMap::new(),
+      routing: None,
+    }
+  }
+
+  pub fn mapping(&self) -> &NoteMapping {
+    &self.mapping
+  }
+
+  /// Sets how polyphonic aftertouch is processed before it's sent, or passes it through
+  /// unchanged if `config` is `None`.
+  pub fn set_aftertouch(&mut self, config: Option<AftertouchConfig>) {
+    self.aftertouch = config.map(AftertouchProcessor::new);
+  }
+
+  /// Replaces the map applied to incoming control changes. An empty map passes every
+  /// controller through unchanged.
+  pub fn set_cc_map(&mut self, cc_map: CcMap) {
+    self.cc_map = cc_map;
+  }
+
+  /// Sets the velocity curves for note-ons, applied after the device's own velocity table.
+  /// Each note uses the first zone that contains it; notes outside every zone are unchanged.
+  pub fn set_velocity_zones(&mut self, zones: Vec<VelocityZone>) {
+    self.velocity_zones = zones;
+  }
+
+  /// Sets which zones convert polyphonic aftertouch to channel pressure, or channel pressure
+  /// to polyphonic aftertouch. Each key uses the first zone that contains it; aftertouch
+  /// outside every zone is passed through unchanged.
+  ///
+  /// Polyphonic aftertouch is run through the [aftertouch processor](Self::set_aftertouch)
+  /// before it's converted.
+  pub fn set_pressure_zones(&mut self, zones: Vec<PressureZone>) {
+    self.pressure_zones = zones;
+  }
+
+  /// Returns the outgoing notes that are currently sounding, either because the key is
+  /// held down or because a pedal is holding them.
+  pub fn sounding_notes(&self) -> Vec<NoteKey> {
+    self.held.iter().map(|h| h.output).collect()
+  }
+
+  /// Processes an incoming message and returns the messages that should be sent to the output.
+  pub fn process(&mut self, msg: ChannelMessage) -> Vec<ChannelMessage> {
+    use ChannelMessage::*;
+    let msg = match msg {
+      ControlChange {
+        channel,
+        controller,
+        value,
+      } => match self.cc_map.process(channel, controller, value) {
+        Some((controller, value)) => ControlChange {
+          channel,
+          controller,
+          value,
+        },
+        None => return vec![],
+      },
+      msg => msg,
+    };
+
+    match msg {
+      NoteOn {
+        channel,
+        note,
+        velocity,
+      } => {
+        let velocity = apply_velocity_zones(&self.velocity_zones, channel, note, velocity);
+        self.note_on(NoteKey { channel, note }, velocity)
+      }
+
+      NoteOff {
+        channel,
+        note,
+        velocity,
+      } => {
+        if let Some(processor) = &mut self.aftertouch {
+          processor.release(channel, note);
+        }
+        let input = NoteKey { channel, note };
+        let mut out = self.note_off(input, velocity);
+        // The released key no longer counts towards any converted channel pressure.
+        if let Some(zone) = self.pressure_zone(input) {
+          let channel = self.mapping.map_channel(channel);
+          out.extend(self.update_channel_pressure(zone, channel));
+        }
+        out
+      }
+
+      PolyAftertouch {
+        channel,
+        note,
+        pressure,
+      } => {
+        let input = NoteKey { channel, note };
+        if !self.held.iter().any(|h| h.input == input && h.key_down) {
+          return vec![];
+        }
+        let pressure = match &mut self.aftertouch {
+          Some(processor) => match processor.process(channel, note, pressure) {
+            Some(p) => p,
+            None => return vec![],
+          },
+          None => pressure,
+        };
+
+        let held = self
+          .held
+          .iter_mut()
+          .find(|h| h.input == input && h.key_down)
+          .expect("held note was just found");
+        held.pressure = pressure;
+        let output = held.output;
+
+        match self.pressure_zone(input) {
+          Some(zone) => self.update_channel_pressure(zone, output.channel),
+          None => vec![PolyAftertouch {
+            channel: output.channel,
+            note: output.note,
+            pressure,
+          }],
+        }
+      }
+
+      ChannelPressure { channel, pressure } if self.converts_channel_pressure(channel) => self
+        .held
+        .iter()
+        .filter(|h| h.key_down && h.input.channel == channel)
+        .filter(|h| {
+          self
+            .pressure_zones
+            .iter()
+            .find(|z| z.zone.contains(channel, h.input.note))
+            .is_some_and(|z| z.conversion == PressureConversion::ChannelToPoly)
+        })
+        .map(|h| PolyAftertouch {
+          channel: h.output.channel,
+          note: h.output.note,
+          pressure,
+        })
+        .collect(),
+
+      ControlChange {
+        channel,
+        controller,
+        value,
+      } if controller == CC_SUSTAIN || controller == CC_SOSTENUTO => {
+        self.pedal_change(channel, controller, value >= PEDAL_DOWN_THRESHOLD);
+        vec![msg.with_channel(self.mapping.map_channel(channel))]
+      }
+
+      _ => vec![msg.with_channel(self.mapping.map_channel(msg.channel()))],
+    }
+  }
+
+  /// Replaces the current mapping, returning any messages needed to release notes that
+  /// would no longer be released correctly under the new mapping.
+  pub fn set_mapping(&mut self, mapping: NoteMapping) -> Vec<ChannelMessage> {
+    let mut out = vec![];
+
+    // Release any note whose outgoing note would change. Their physical note-offs
+    // will arrive later and be ignored, since they're no longer in the held table.
+    let (changed, unchanged): (Vec<HeldNote>, Vec<HeldNote>) = self
+      .held
+      .drain(..)
+      .partition(|h| mapping.map_note(h.input) != Some(h.output));
+    for h in changed.iter() {
+      debug!("releasing {:?} after mapping change", h.output);
+      out.push(ChannelMessage::NoteOff {
+        channel: h.output.channel,
+        note: h.output.note,
+        velocity: 0,
+      });
+    }
+    self.held = unchanged;
+
+    // If pedals are down on a channel that's moving to a new outgoing channel, lift them on
+    // the old channel and press them on the new one.
+    for (channel, state) in self.pedals.iter() {
+      let old_channel = self.mapping.map_channel(*channel);
+      let new_channel = mapping.map_channel(*channel);
+      if old_channel == new_channel {
+        continue;
+      }
+      for (controller, down) in [(CC_SUSTAIN, state.sustain), (CC_SOSTENUTO, state.sostenuto)] {
+        if !down {
+          continue;
+        }
+        out.push(ChannelMessage::ControlChange {
+          channel: old_channel,
+          controller,
+          value: 0,
+        });
+        out.push(ChannelMessage::ControlChange {
+          channel: new_channel,
+          controller,
+          value: 127,
+        });
+      }
+    }
+
+    self.mapping = mapping;
+    out
+  }
+
+  /// Returns messages to silence everything on every channel of the output, and resets the
+  /// held-note, pedal and control change state.
+  ///
+  /// Notes the proxy knows about get explicit note-offs, in case the receiving synth
+  /// ignores "all notes off".
+  pub fn panic(&mut self) -> Vec<ChannelMessage> {
+    let mut out: Vec<ChannelMessage> = self
+      .held
+      .drain(..)
+      .map(|h| ChannelMessage::NoteOff {
+        channel: h.output.channel,
+        note: h.output.note,
+        velocity: 0,
+      })
+      .collect();
+    self.pedals.clear();
+    self.channel_pressure.clear();
+    self.cc_map.reset();
+    if let Some(processor) = &mut self.aftertouch {
+      processor.reset();
+    }
+
+    for n in MidiChannel::MIN_VALUE..=MidiChannel::MAX_VALUE {
+      let channel = MidiChannel::unchecked(n);
+      // Pedals go up first, since notes held by the sustain pedal survive "all notes off".
+      for controller in [CC_SUSTAIN, CC_SOSTENUTO, CC_ALL_NOTES_OFF, CC_ALL_SOUND_OFF] {
+        out.push(ChannelMessage::ControlChange {
+          channel,
+          controller,
+          value: 0,
+        });
+      }
+    }
+    out
+  }
+
+  /// Starts running the proxy in its own event loop, processing messages from `events` and
+  /// sending the results to `output`.
+  ///
+  /// Returns a tuple of ([ProxyHandle], Future). The future must be `await`ed (probably in
+  /// a spawned task) to run the proxy. It resolves when the event stream closes or `shutdown`
+  /// is cancelled, after sending a [panic](Self::panic) to the output.
+  pub fn start<O: ProxyOutput>(
+    self,
+    events: broadcast::Receiver<ChannelMessage>,
+    output: O,
+    shutdown: CancellationToken,
+  ) -> (ProxyHandle, impl Future<Output = ()>) {
+    let (control_tx, control_rx) = mpsc::channel(16);
+    let notes_played = Arc::new(AtomicU64::new(0));
+    let events_missed = Arc::new(AtomicU64::new(0));
+    let handle = ProxyHandle {
+      control_tx,
+      notes_played: notes_played.clone(),
+      events_missed: events_missed.clone(),
+    };
+    let proxy_future = self.run(
+      events,
+      control_rx,
+      output,
+      notes_played,
+      events_missed,
+      shutdown,
+    );
+    (handle, proxy_future)
+  }
+
+  async fn run<O: ProxyOutput>(
+    mut self,
+    mut events: broadcast::Receiver<ChannelMessage>,
+    mut controls: mpsc::Receiver<ProxyControl>,
+    mut output: O,
+    notes_played: Arc<AtomicU64>,
+    events_missed: Arc<AtomicU64>,
+    shutdown: CancellationToken,
+  ) {
+    use broadcast::error::RecvError;
+
+    loop {
+      let to_send = tokio::select! {
+        res = events.recv() => match res {
+          Ok(msg) => {
+            if matches!(msg, ChannelMessage::NoteOn { velocity, .. } if velocity > 0) {
+              notes_played.fetch_add(1, Ordering::Relaxed);
+            }
+            self.process(msg)
+          }
+          Err(RecvError::Lagged(n)) => {
+            // We may have missed note-offs, so the only safe thing to do is silence everything.
+            warn!("note proxy fell behind and missed {n} events, sending panic");
+            events_missed.fetch_add(n, Ordering::Relaxed);
+            self.panic()
+          }
+          Err(RecvError::Closed) => {
+            info!("event stream closed, sending panic and stopping note proxy");
+            let messages = self.panic();
+            self.send_all(&mut output, messages);
+            return;
+          }
+        },
+
+        Some(control) = controls.recv() => match control {
+          ProxyControl::SetMapping(mapping) => self.set_mapping(mapping),
+          ProxyControl::Panic => self.panic(),
+          ProxyControl::Send(messages) => messages,
+          ProxyControl::SendSysex { destination, message } => {
+            let result = match &destination {
+              Some(dest) => output.send_to(dest, &message),
+              None => output.send(&message),
+            };
+            if let Err(err) = result {
+              warn!("note proxy unable to send sysex: {err:?}");
+            }
+            vec![]
+          }
+          ProxyControl::SetCcMap(cc_map) => {
+            self.set_cc_map(cc_map);
+            vec![]
+          }
+          ProxyControl::SetAftertouch(config) => {
+            self.set_aftertouch(config);
+            vec![]
+          }
+          ProxyControl::SetVelocityZones(zones) => {
+            self.set_velocity_zones(zones);
+            vec![]
+          }
+          ProxyControl::SetPressureZones(zones) => {
+            self.set_pressure_zones(zones);
+            vec![]
+          }
+          ProxyControl::SetRouting(routing) => {
+            // The note-offs have to go to the outputs the notes were sent to.
+            let messages = self.reroute(routing.as_ref());
+            self.send_all(&mut output, messages);
+            self.routing = routing;
+            vec![]
+          }
+        },
+
+        _ = shutdown.cancelled() => {
+          info!("stopping note proxy");
+          let messages = self.panic();
+          self.send_all(&mut output, messages);
+          return;
+        }
+      };
+      self.send_all(&mut output, to_send);
+    }
+  }
+
+  /// Sends `messages` to the outputs picked by the routing matrix, or to every output if
+  /// there isn't one.
+  fn send_all<O: ProxyOutput>(&self, output: &mut O, messages: Vec<ChannelMessage>) {
+    for msg in messages {
+      let bytes = msg.to_bytes();
+      let results = match &self.routing {
+        None => vec![output.send(&bytes)],
+        Some(routing) => routing
+          .destinations(&msg)
+          .into_iter()
+          .map(|dest| output.send_to(dest, &bytes))
+          .collect(),
+      };
+      for err in results.into_iter().filter_map(|r| r.err()) {
+        warn!("note proxy unable to send {msg}: {err:?}");
+      }
+    }
+  }
+
+  /// Releases held notes that would be sent to different outputs under `routing`, returning
+  /// the note-offs to send under the current routing.
+  fn reroute(&mut self, routing: Option<&RoutingMatrix>) -> Vec<ChannelMessage> {
+    let current = self.routing.as_ref();
+    let destinations = |r: Option<&RoutingMatrix>, msg: &ChannelMessage| {
+      r.map(|r| {
+        r.destinations(msg)
+          .into_iter()
+          .map(String::from)
+          .collect::<Vec<_>>()
+      })
+    };
+
+    let mut out = vec![];
+    self.held.retain(|h| {
+      let off = ChannelMessage::NoteOff {
+        channel: h.output.channel,
+        note: h.output.note,
+        velocity: 0,
+      };
+      if destinations(current, &off) == destinations(routing, &off) {
+        return true;
+      }
+      debug!("releasing {:?} after routing change", h.output);
+      out.push(off);
+      false
+    });
+    out
+  }
+
+  fn note_on(&mut self, input: NoteKey, velocity: u8) -> Vec<ChannelMessage> {
+    let output = match self.mapping.map_note(input) {
+      Some(o) => o,
+      None => {
+        debug!("dropping note {:?}, out of range after mapping", input);
+        return vec![];
+      }
+    };
+
+    self.held.push(HeldNote {
+      input,
+      output,
+      key_down: true,
+      sustained: false,
+      sostenuto: false,
+      pressure: 0,
+    });
+
+    vec![ChannelMessage::NoteOn {
+      channel: output.channel,
+      note: output.note,
+      velocity,
+    }]
+  }
+
+  fn note_off(&mut self, input: NoteKey, velocity: u8) -> Vec<ChannelMessage> {
+    let pedals = self.pedals.get(&input.channel).copied().unwrap_or_default();
+    let held = match self
+      .held
+      .iter_mut()
+      .find(|h| h.input == input && h.key_down)
+    {
+      Some(h) => h,
+      None => {
+        debug!("ignoring note off for {:?}, which isn't held", input);
+        return vec![];
+      }
+    };
+
+    held.key_down = false;
+    held.sustained = pedals.sustain;
+    let output = held.output;
+    self.held.retain(HeldNote::is_sounding);
+
+    vec![ChannelMessage::NoteOff {
+      channel: output.channel,
+      note: output.note,
+      velocity,
+    }]
+  }
+
+  /// Returns the index of the first pressure zone containing `input`, if it converts
+  /// polyphonic aftertouch to channel pressure.
+  fn pressure_zone(&self, input: NoteKey) -> Option<usize> {
+    let index = self
+      .pressure_zones
+      .iter()
+      .position(|z| z.zone.contains(input.channel, input.note))?;
+    match self.pressure_zones[index].conversion {
+      PressureConversion::ChannelToPoly => None,
+      _ => Some(index),
+    }
+  }
+
+  /// Returns true if the first pressure zone for `channel` converts channel pressure to
+  /// polyphonic aftertouch.
+  fn converts_channel_pressure(&self, channel: MidiChannel) -> bool {
+    self
+      .pressure_zones
+      .iter()
+      .find(|z| z.zone.channel.is_none_or(|c| c == channel))
+      .is_some_and(|z| z.conversion == PressureConversion::ChannelToPoly)
+  }
+
+  /// Recalculates the channel pressure for the keys in pressure zone `zone` that are sent on
+  /// `channel`, returning a channel pressure message if it's changed.
+  fn update_channel_pressure(&mut self, zone: usize, channel: MidiChannel) -> Vec<ChannelMessage> {
+    let pressures: Vec<u8> = self
+      .held
+      .iter()
+      .filter(|h| h.key_down && h.output.channel == channel)
+      .filter(|h| self.pressure_zone(h.input) == Some(zone))
+      .map(|h| h.pressure)
+      .collect();
+    let pressure = self.pressure_zones[zone].conversion.combine(&pressures);
+
+    let last = self.channel_pressure.insert(channel, pressure).unwrap_or(0);
+    if last == pressure {
+      return vec![];
+    }
+    vec![ChannelMessage::ChannelPressure { channel, pressure }]
+  }
+
+  fn pedal_change(&mut self, channel: MidiChannel, controller: u8, down: bool) {
+    let state = self.pedals.entry(channel).or_default();
+    let on_channel = |h: &&mut HeldNote| h.input.channel == channel;
+
+    if controller == CC_SUSTAIN {
+      state.sustain = down;
+      if !down {
+        self
+          .held
+          .iter_mut()
+          .filter(on_channel)
+          .for_each(|h| h.sustained = false);
+      }
+    } else {
+      state.sostenuto = down;
+      // Sostenuto only holds the notes that are down at the moment the pedal is pressed.
+      self
+        .held
+        .iter_mut()
+        .filter(on_channel)
+        .for_each(|h| h.sostenuto = down && h.key_down);
+    }
+
+    self.held.retain(HeldNote::is_sounding);
+  }
+}
+
+/// Somewhere the proxy can send its outgoing messages.
+pub trait ProxyOutput: Send + 'static {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError>;
+
+  /// Sends `msg` to the output named `destination` by a [RoutingMatrix]. Outputs that only
+  /// have one destination ignore the name.
+  fn send_to(&mut self, destination: &str, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    let _ = destination;
+    self.send(msg)
+  }
+}
+
+impl ProxyOutput for MidiOutputConnection {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    MidiOutputConnection::send(self, msg)
+      .report()
+      .change_context(LumatoneMidiError::DeviceSendError)
+  }
+}
+
+/// Sends to several named outputs. Use with [ProxyHandle::set_routing] to pick which
+/// messages go to which output; without a routing matrix, every message goes to every
+/// output.
+#[derive(Default)]
+pub struct MultiOutput {
+  outputs: Vec<(String, Box<dyn ProxyOutput>)>,
+}
+
+impl MultiOutput {
+  pub fn new() -> Self {
+    MultiOutput::default()
+  }
+
+  /// Adds `output`, which routes refer to as `name`.
+  pub fn add<S: Into<String>, O: ProxyOutput>(mut self, name: S, output: O) -> Self {
+    self.outputs.push((name.into(), Box::new(output)));
+    self
+  }
+
+  /// Connects to the MIDI output port called `port_name` and adds it under the same name.
+  pub fn connect_port(self, port_name: &str) -> Result<Self, LumatoneMidiError> {
+    use LumatoneMidiError::DeviceConnectionError;
+
+    let output = MidiOutput::new("lumatone-rs")
+      .report()
+      .change_context(DeviceConnectionError)?;
+    let port = get_port_by_name(&output, port_name)?;
+    let conn = output.connect(&port, port_name).map_err(|e|
+      // ConnectError isn't thread-safe, so we stringify instead of report()-ing directly
+      report!(DeviceConnectionError).attach_printable(format!("midi output connection error: {e}")))?;
+    Ok(self.add(port_name, conn))
+  }
+
+  /// Creates a virtual MIDI output port called `port_name` that other applications can
+  /// connect to, and adds it under the same name.
+  #[cfg(unix)]
+  pub fn create_virtual_port(self, port_name: &str) -> Result<Self, LumatoneMidiError> {
+    use midir::os::unix::VirtualOutput;
+    use LumatoneMidiError::DeviceConnectionError;
+
+    let output = MidiOutput::new("lumatone-rs")
+      .report()
+      .change_context(DeviceConnectionError)?;
+    let conn = output.create_virtual(port_name).map_err(|e| {
+      report!(DeviceConnectionError).attach_printable(format!("unable to create virtual port: {e}"))
+    })?;
+    Ok(self.add(port_name, conn))
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.outputs.iter().map(|(name, _)| name.as_str())
+  }
+}
+
+impl ProxyOutput for MultiOutput {
+  /// Sends `msg` to every output, returning the last error if any of them fail.
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    let mut result = Ok(());
+    for (_, output) in self.outputs.iter_mut() {
+      if let Err(err) = output.send(msg) {
+        result = Err(err);
+      }
+    }
+    result
+  }
+
+  fn send_to(&mut self, destination: &str, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    match self
+      .outputs
+      .iter_mut()
+      .find(|(name, _)| name == destination)
+    {
+      Some((_, output)) => output.send(msg),
+      None => bail!(LumatoneMidiError::NoteProxyError(format!(
+        "no output named {destination}"
+      ))),
+    }
+  }
+}
+
+/// Requests sent from a [ProxyHandle] to a running proxy.
+#[derive(Debug)]
+enum ProxyControl {
+  SetMapping(NoteMapping),
+  Panic,
+  Send(Vec<ChannelMessage>),
+  SendSysex {
+    destination: Option<String>,
+    message: Vec<u8>,
+  },
+  SetAftertouch(Option<AftertouchConfig>),
+  SetCcMap(CcMap),
+  SetVelocityZones(Vec<VelocityZone>),
+  SetPressureZones(Vec<PressureZone>),
+  SetRouting(Option<RoutingMatrix>),
+}
+
+/// Controls a proxy that was started with [NoteProxy::start].
+#[derive(Debug, Clone)]
+pub struct ProxyHandle {
+  control_tx: mpsc::Sender<ProxyControl>,
+  notes_played: Arc<AtomicU64>,
+  events_missed: Arc<AtomicU64>,
+}
+
+impl ProxyHandle {
+  /// Changes the proxy's note mapping. Any notes that would map differently are released.
+  pub async fn set_mapping(&self, mapping: NoteMapping) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::SetMapping(mapping)).await
+  }
+
+  /// Sends "all notes off" and "all sound off" on every channel and forgets all held notes.
+  pub async fn panic(&self) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::Panic).await
+  }
+
+  /// Changes how polyphonic aftertouch is processed. See [NoteProxy::set_aftertouch].
+  pub async fn set_aftertouch(
+    &self,
+    config: Option<AftertouchConfig>,
+  ) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::SetAftertouch(config)).await
+  }
+
+  /// Replaces the control change map. See [NoteProxy::set_cc_map].
+  pub async fn set_cc_map(&self, cc_map: CcMap) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::SetCcMap(cc_map)).await
+  }
+
+  /// Changes the per-zone velocity curves. See [NoteProxy::set_velocity_zones].
+  pub async fn set_velocity_zones(
+    &self,
+    zones: Vec<VelocityZone>,
+  ) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::SetVelocityZones(zones)).await
+  }
+
+  /// Changes the aftertouch conversion zones. See [NoteProxy::set_pressure_zones].
+  pub async fn set_pressure_zones(
+    &self,
+    zones: Vec<PressureZone>,
+  ) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::SetPressureZones(zones)).await
+  }
+
+  /// Changes which outputs messages are sent to, or sends everything to every output if
+  /// `routing` is `None`. Notes that would move to different outputs are released first.
+  pub async fn set_routing(&self, routing: Option<RoutingMatrix>) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::SetRouting(routing)).await
+  }
+
+  /// Sends `messages` to the proxy's output as-is, e.g. program changes for the synth.
+  pub async fn send_messages(
+    &self,
+    messages: Vec<ChannelMessage>,
+  ) -> Result<(), LumatoneMidiError> {
+    self.send(ProxyControl::Send(messages)).await
+  }
+
+  /// Sends a system exclusive message to the output named `destination` (see
+  /// [MultiOutput]), or to every output if it's `None`. The routing matrix doesn't apply,
+  /// since sysex messages have no channel.
+  ///
+  /// Fails if `message` doesn't start with 0xF0 and end with 0xF7.
+  pub async fn send_sysex(
+    &self,
+    destination: Option<String>,
+    message: Vec<u8>,
+  ) -> Result<(), LumatoneMidiError> {
+    if message.len() < 2 || message[0] != 0xf0 || message[message.len() - 1] != 0xf7 {
+      bail!(LumatoneMidiError::MalformedSysex(to_hex_debug_str(
+        &message
+      )));
+    }
+    self
+      .send(ProxyControl::SendSysex {
+        destination,
+        message,
+      })
+      .await
+  }
+
+  /// How many notes have been played on the device since the proxy started.
+  pub fn notes_played(&self) -> u64 {
+    self.notes_played.load(Ordering::Relaxed)
+  }
+
+  /// How many events from the device the proxy lost by falling behind.
+  pub fn events_missed(&self) -> u64 {
+    self.events_missed.load(Ordering::Relaxed)
+  }
+
+  async fn send(&self, control: ProxyControl) -> Result<(), LumatoneMidiError> {
+    self
+      .control_tx
+      .send(control)
+      .await
+      .map_err(|e| report!(LumatoneMidiError::DeviceSendError).attach_printable(format!("{e}")))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    cc_map::{CcMapping, CcRule},
+    curve::ResponseCurve,
+    zones::Zone,
+  };
+
+  fn poly(note: u8, pressure: u8) -> ChannelMessage {
+    ChannelMessage::PolyAftertouch {
+      channel: ch(1),
+      note,
+      pressure,
+    }
+  }
+
+  fn channel_pressure(pressure: u8) -> ChannelMessage {
+    ChannelMessage::ChannelPressure {
+      channel: ch(1),
+      pressure,
+    }
+  }
+
+  fn ch(n: u8) -> MidiChannel {
+    MidiChannel::unchecked(n)
+  }
+
+  fn note_on(note: u8) -> ChannelMessage {
+    ChannelMessage::NoteOn {
+      channel: ch(1),
+      note,
+      velocity: 100,
+    }
+  }
+
+  fn note_off(note: u8) -> ChannelMessage {
+    ChannelMessage::NoteOff {
+      channel: ch(1),
+      note,
+      velocity: 0,
+    }
+  }
+
+  fn cc(controller: u8, value: u8) -> ChannelMessage {
+    ChannelMessage::ControlChange {
+      channel: ch(1),
+      controller,
+      value,
+    }
+  }
+
+  fn transpose(semitones: i8) -> NoteMapping {
+    NoteMapping {
+      transpose: semitones,
+      output_channel: None,
+    }
+  }
+
+  #[test]
+  fn note_off_goes_to_note_that_was_sent_after_transpose() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.process(note_on(60));
+
+    let corrections = proxy.set_mapping(transpose(2));
+    assert_eq!(corrections, vec![note_off(60)]);
+
+    // The physical note off arrives later, but the note has already been released.
+    assert_eq!(proxy.process(note_off(60)), vec![]);
+    assert!(proxy.sounding_notes().is_empty());
+  }
+
+  #[test]
+  fn unchanged_mapping_needs_no_corrections() {
+    let mut proxy = NoteProxy::new(transpose(2));
+    proxy.process(note_on(60));
+    assert_eq!(proxy.set_mapping(transpose(2)), vec![]);
+    assert_eq!(proxy.process(note_off(60)), vec![note_off(62)]);
+  }
+
+  #[test]
+  fn sustained_notes_are_held_until_pedal_up() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.process(note_on(60));
+    proxy.process(cc(CC_SUSTAIN, 127));
+    proxy.process(note_off(60));
+    assert_eq!(proxy.sounding_notes().len(), 1);
+
+    proxy.process(cc(CC_SUSTAIN, 0));
+    assert!(proxy.sounding_notes().is_empty());
+  }
+
+  #[test]
+  fn sustained_notes_are_released_when_mapping_changes_mid_sustain() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.process(cc(CC_SUSTAIN, 127));
+    proxy.process(note_on(60));
+    proxy.process(note_off(60));
+
+    let corrections = proxy.set_mapping(transpose(-12));
+    assert_eq!(corrections, vec![note_off(60)]);
+    assert!(proxy.sounding_notes().is_empty());
+  }
+
+  #[test]
+  fn sostenuto_only_holds_notes_down_when_pressed() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.process(note_on(60));
+    proxy.process(cc(CC_SOSTENUTO, 127));
+    proxy.process(note_on(64));
+    proxy.process(note_off(60));
+    proxy.process(note_off(64));
+
+    let sounding: Vec<u8> = proxy.sounding_notes().iter().map(|n| n.note).collect();
+    assert_eq!(sounding, vec![60]);
+
+    proxy.process(cc(CC_SOSTENUTO, 0));
+    assert!(proxy.sounding_notes().is_empty());
+  }
+
+  #[test]
+  fn panic_releases_held_notes_and_silences_every_channel() {
+    let mut proxy = NoteProxy::new(transpose(1));
+    proxy.process(cc(CC_SUSTAIN, 127));
+    proxy.process(note_on(60));
+
+    let out = proxy.panic();
+    assert_eq!(out[0], note_off(61));
+    assert_eq!(out.len(), 1 + 16 * 4);
+    assert!(out.contains(&ChannelMessage::ControlChange {
+      channel: ch(16),
+      controller: CC_ALL_NOTES_OFF,
+      value: 0
+    }));
+    assert!(proxy.sounding_notes().is_empty());
+
+    // pedal state was reset too, so a new note isn't held after release
+    proxy.process(note_on(60));
+    proxy.process(note_off(60));
+    assert!(proxy.sounding_notes().is_empty());
+  }
+
+  #[test]
+  fn pedals_move_with_output_channel() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.process(cc(CC_SUSTAIN, 127));
+
+    let corrections = proxy.set_mapping(NoteMapping {
+      transpose: 0,
+      output_channel: Some(ch(2)),
+    });
+    assert_eq!(
+      corrections,
+      vec![
+        cc(CC_SUSTAIN, 0),
+        ChannelMessage::ControlChange {
+          channel: ch(2),
+          controller: CC_SUSTAIN,
+          value: 127
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn aftertouch_is_processed_when_configured() {
+    let pressure = |pressure| ChannelMessage::PolyAftertouch {
+      channel: ch(1),
+      note: 60,
+      pressure,
+    };
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.set_aftertouch(Some(AftertouchConfig {
+      dead_zone: 10,
+      ..AftertouchConfig::default()
+    }));
+
+    // ignored while the key isn't held
+    assert_eq!(proxy.process(pressure(100)), vec![]);
+
+    proxy.process(note_on(60));
+    assert_eq!(proxy.process(pressure(5)), vec![]);
+    assert_eq!(proxy.process(pressure(127)), vec![pressure(127)]);
+    assert_eq!(proxy.process(pressure(127)), vec![]);
+
+    proxy.set_aftertouch(None);
+    assert_eq!(proxy.process(pressure(5)), vec![pressure(5)]);
+  }
+
+  #[test]
+  fn velocity_curve_applies_per_zone() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.set_velocity_zones(vec![VelocityZone {
+      zone: Zone::notes(0, 59),
+      curve: ResponseCurve::Table(Box::new([127; 128])),
+    }]);
+
+    let on = |note, velocity| ChannelMessage::NoteOn {
+      channel: ch(1),
+      note,
+      velocity,
+    };
+    assert_eq!(proxy.process(on(48, 100)), vec![on(48, 127)]);
+    assert_eq!(proxy.process(note_on(60)), vec![note_on(60)]);
+  }
+
+  #[test]
+  fn poly_aftertouch_converts_to_channel_pressure() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.set_pressure_zones(vec![PressureZone {
+      zone: Zone::all(),
+      conversion: PressureConversion::PolyToChannelMax,
+    }]);
+
+    proxy.process(note_on(60));
+    proxy.process(note_on(64));
+    assert_eq!(proxy.process(poly(60, 50)), vec![channel_pressure(50)]);
+    assert_eq!(proxy.process(poly(64, 80)), vec![channel_pressure(80)]);
+    assert_eq!(proxy.process(poly(60, 70)), vec![]);
+    // releasing the hardest-pressed key drops back to the other one
+    assert_eq!(
+      proxy.process(note_off(64)),
+      vec![note_off(64), channel_pressure(70)]
+    );
+  }
+
+  #[test]
+  fn channel_pressure_converts_to_poly_aftertouch_in_zone() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.set_pressure_zones(vec![PressureZone {
+      zone: Zone::notes(0, 61),
+      conversion: PressureConversion::ChannelToPoly,
+    }]);
+
+    proxy.process(note_on(60));
+    proxy.process(note_on(62));
+    assert_eq!(proxy.process(channel_pressure(90)), vec![poly(60, 90)]);
+
+    proxy.set_pressure_zones(vec![]);
+    assert_eq!(
+      proxy.process(channel_pressure(90)),
+      vec![channel_pressure(90)]
+    );
+  }
+
+  #[test]
+  fn remapped_controller_can_act_as_sustain_pedal() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.set_cc_map(CcMap::new(vec![
+      CcRule::map(4, CcMapping::to(CC_SUSTAIN)),
+      CcRule::block(1),
+    ]));
+
+    assert_eq!(proxy.process(cc(1, 64)), vec![]);
+    proxy.process(note_on(60));
+    assert_eq!(proxy.process(cc(4, 127)), vec![cc(CC_SUSTAIN, 127)]);
+    proxy.process(note_off(60));
+    assert_eq!(proxy.sounding_notes().len(), 1);
+  }
+
+  #[test]
+  fn routing_change_releases_notes_that_move_outputs() {
+    let mut proxy = NoteProxy::new(NoteMapping::default());
+    proxy.routing = Some(RoutingMatrix::default().route(Zone::all(), "a"));
+    proxy.process(note_on(40));
+    proxy.process(note_on(80));
+
+    let routing = RoutingMatrix::default()
+      .route(Zone::notes(0, 59), "a")
+      .route(Zone::notes(60, 127), "b");
+    let off = |note| ChannelMessage::NoteOff {
+      channel: ch(1),
+      note,
+      velocity: 0,
+    };
+    assert_eq!(proxy.reroute(Some(&routing)), vec![off(80)]);
+    assert_eq!(proxy.sounding_notes().len(), 1);
+  }
+}
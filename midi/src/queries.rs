@@ -0,0 +1,367 @@
+//! Typed wrappers for the `Get*` commands, which send the command and unwrap the
+//! expected [Response] variant.
+
+use super::{
+  commands::Command,
+  constants::{BoardIndex, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation, RGBColor},
+  controller::Lumatone,
+  error::LumatoneMidiError,
+  responses::{
+    BlueLedConfigResponse, BoardKeyValues, BoardSensitivityValues, BoardThresholdValues,
+    ChannelConfigResponse, FaderTypeConfigResponse, FirmwareVersion, GreenLedConfigResponse,
+    KeyThresholdsResponse, KeyTypeConfigResponse, KeyValidityResponse, NoteConfigResponse,
+    PeripheralChannels, RedLedConfigResponse, Response, SerialIdentity,
+  },
+  sysex::{SysexTable, VelocityIntervalTable},
+};
+
+use std::collections::HashMap;
+
+use error_stack::{report, Result};
+
+impl Lumatone {
+  /// Sends `command`, and returns the payload pulled out of the response by `extract`.
+  /// Fails if the device sends some other kind of response.
+  async fn query<T>(
+    &self,
+    command: Command,
+    extract: fn(Response) -> Option<T>,
+  ) -> Result<T, LumatoneMidiError> {
+    let response = self.send(command.clone()).await?;
+    let description = response.to_string();
+    extract(response).ok_or_else(|| {
+      report!(LumatoneMidiError::InvalidResponseMessage(format!(
+        "unexpected response to {command}: {description}"
+      )))
+    })
+  }
+
+  pub async fn get_red_led_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<RedLedConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetRedLEDConfig(board), |r| match r {
+        Response::RedLEDConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_green_led_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<GreenLedConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetGreenLEDConfig(board), |r| match r {
+        Response::GreenLEDConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_blue_led_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<BlueLedConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetBlueLEDConfig(board), |r| match r {
+        Response::BlueLEDConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  /// Reads back the current color of each key on a board, by combining the red, green and
+  /// blue LED intensities.
+  pub async fn get_key_colors(
+    &self,
+    board: BoardIndex,
+  ) -> Result<BoardKeyValues<RGBColor>, LumatoneMidiError> {
+    let red = self.get_red_led_config(board).await?;
+    let green = self.get_green_led_config(board).await?;
+    let blue = self.get_blue_led_config(board).await?;
+    let values = red
+      .values
+      .iter()
+      .zip(green.values.iter())
+      .zip(blue.values.iter())
+      .map(|((r, g), b)| RGBColor(*r, *g, *b))
+      .collect();
+    Ok(BoardKeyValues {
+      board_index: board,
+      values,
+    })
+  }
+
+  /// Reads back the current color of every key on every board.
+  ///
+  /// There's no command to read back macro button colors, so those aren't included.
+  pub async fn get_all_key_colors(
+    &self,
+  ) -> Result<HashMap<LumatoneKeyLocation, RGBColor>, LumatoneMidiError> {
+    let mut colors = HashMap::new();
+    for board in BoardIndex::all_octaves() {
+      let board_colors = self.get_key_colors(board).await?;
+      for (key_index, color) in LumatoneKeyIndex::all().into_iter().zip(board_colors.values) {
+        colors.insert(LumatoneKeyLocation(board, key_index), color);
+      }
+    }
+    Ok(colors)
+  }
+
+  pub async fn get_channel_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<ChannelConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetMidiChannelConfig(board), |r| match r {
+        Response::ChannelConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_note_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<NoteConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetNoteConfig(board), |r| match r {
+        Response::NoteConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_key_type_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<KeyTypeConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetKeyTypeConfig(board), |r| match r {
+        Response::KeyTypeConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  /// Reads back the function of each key on a board, by combining the channel, note and
+  /// key type configs.
+  pub async fn get_key_functions(
+    &self,
+    board: BoardIndex,
+  ) -> Result<BoardKeyValues<LumatoneKeyFunction>, LumatoneMidiError> {
+    let channels = self.get_channel_config(board).await?;
+    let notes = self.get_note_config(board).await?;
+    let types = self.get_key_type_config(board).await?;
+    let values = channels
+      .values
+      .iter()
+      .zip(notes.values.iter())
+      .zip(types.values.iter())
+      .map(|((channel, note), type_code)| {
+        LumatoneKeyFunction::from_type_code(*type_code, *channel, *note).ok_or_else(|| {
+          report!(LumatoneMidiError::InvalidResponseMessage(format!(
+            "unknown key type {type_code} on {board}"
+          )))
+        })
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    Ok(BoardKeyValues {
+      board_index: board,
+      values,
+    })
+  }
+
+  pub async fn get_max_fader_thresholds(
+    &self,
+    board: BoardIndex,
+  ) -> Result<KeyThresholdsResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetMaxFaderThreshold(board), |r| match r {
+        Response::KeyMaxThresholds(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_min_fader_thresholds(
+    &self,
+    board: BoardIndex,
+  ) -> Result<KeyThresholdsResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetMinFaderThreshold(board), |r| match r {
+        Response::KeyMinThresholds(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_max_aftertouch_thresholds(
+    &self,
+    board: BoardIndex,
+  ) -> Result<KeyThresholdsResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetMaxAftertouchThreshold(board), |r| match r {
+        Response::AftertouchMaxThresholds(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_key_validity(
+    &self,
+    board: BoardIndex,
+  ) -> Result<KeyValidityResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetKeyValidity(board), |r| match r {
+        Response::KeyValidity(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_fader_type_config(
+    &self,
+    board: BoardIndex,
+  ) -> Result<FaderTypeConfigResponse, LumatoneMidiError> {
+    self
+      .query(Command::GetFaderTypeConfig(board), |r| match r {
+        Response::FaderTypeConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_board_thresholds(
+    &self,
+    board: BoardIndex,
+  ) -> Result<BoardThresholdValues, LumatoneMidiError> {
+    self
+      .query(Command::GetBoardThresholdValues(board), |r| match r {
+        Response::BoardThresholds(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_board_sensitivity(
+    &self,
+    board: BoardIndex,
+  ) -> Result<BoardSensitivityValues, LumatoneMidiError> {
+    self
+      .query(Command::GetBoardSensitivityValues(board), |r| match r {
+        Response::BoardSensitivity(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_aftertouch_trigger_delay(
+    &self,
+    board: BoardIndex,
+  ) -> Result<u8, LumatoneMidiError> {
+    self
+      .query(Command::GetAftertouchTriggerDelay(board), |r| match r {
+        Response::AftertouchTriggerDelay(_, v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_lumatouch_note_off_delay(
+    &self,
+    board: BoardIndex,
+  ) -> Result<u16, LumatoneMidiError> {
+    self
+      .query(Command::GetLumatouchNoteOffDelay(board), |r| match r {
+        Response::LumatouchNoteOffDelay(_, v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_velocity_config(&self) -> Result<Box<SysexTable>, LumatoneMidiError> {
+    self
+      .query(Command::GetVelocityConfig, |r| match r {
+        Response::OnOffVelocityConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_velocity_intervals(
+    &self,
+  ) -> Result<Box<VelocityIntervalTable>, LumatoneMidiError> {
+    self
+      .query(Command::GetVelocityIntervalConfig, |r| match r {
+        Response::VelocityIntervalConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_fader_config(&self) -> Result<Box<SysexTable>, LumatoneMidiError> {
+    self
+      .query(Command::GetFaderConfig, |r| match r {
+        Response::FaderConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_aftertouch_config(&self) -> Result<Box<SysexTable>, LumatoneMidiError> {
+    self
+      .query(Command::GetAftertouchConfig, |r| match r {
+        Response::AftertouchConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_lumatouch_config(&self) -> Result<Box<SysexTable>, LumatoneMidiError> {
+    self
+      .query(Command::GetLumatouchConfig, |r| match r {
+        Response::LumatouchConfig(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_serial_id(&self) -> Result<SerialIdentity, LumatoneMidiError> {
+    self
+      .query(Command::GetSerialId, |r| match r {
+        Response::SerialId(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_firmware_version(&self) -> Result<FirmwareVersion, LumatoneMidiError> {
+    self
+      .query(Command::GetFirmwareRevision, |r| match r {
+        Response::FirmwareRevision(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_peripheral_channels(&self) -> Result<PeripheralChannels, LumatoneMidiError> {
+    self
+      .query(Command::GetPeripheralChannels, |r| match r {
+        Response::PeripheralChannels(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+
+  pub async fn get_expression_pedal_threshold(&self) -> Result<u16, LumatoneMidiError> {
+    self
+      .query(Command::GetExpressionPedalADCThreshold, |r| match r {
+        Response::ExpressionPedalThreshold(v) => Some(v),
+        _ => None,
+      })
+      .await
+  }
+}
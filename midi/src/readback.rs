@@ -0,0 +1,338 @@
+//! Verified uploads, for checking that a keymap really landed before a show.
+//!
+//! [Lumatone::send_verified] sends a set of commands, then reads back the color and function
+//! of some or all of the keys they set, and compares them with what was sent. Keys that
+//! don't match are sent again, up to [VerifyOptions::max_retries] times, and the
+//! [VerifyReport] says how many keys were checked, fixed and still wrong.
+//!
+//! The device reads back a whole board at a time, so checking a [Sample::Random] of keys
+//! saves time by only reading the boards those keys are on. When a random sample comes back
+//! clean, the report gives an upper bound on the share of keys that could still be wrong
+//! (the "rule of three": with `n` clean samples, at most `3 / n` of the keys are wrong, with
+//! 95% confidence). If any key in the sample is wrong, the keys outside it probably are
+//! too, so every key is read back and checked instead.
+
+use std::{collections::HashMap, fmt::Display};
+
+use log::{info, warn};
+use rand::seq::SliceRandom;
+
+use super::{
+  commands::Command,
+  constants::{BoardIndex, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation, RGBColor},
+  controller::Lumatone,
+  error::LumatoneMidiError,
+};
+
+use error_stack::Result;
+
+/// Which keys to read back after sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sample {
+  All,
+
+  /// This many keys, chosen at random.
+  Random(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyOptions {
+  pub sample: Sample,
+
+  /// How many times to re-send keys that don't match before giving up on them.
+  pub max_retries: usize,
+
+  /// How far each color channel read back can be from the one sent. Older firmware only
+  /// stores 7 bits per channel.
+  pub color_tolerance: u8,
+}
+
+impl Default for VerifyOptions {
+  fn default() -> Self {
+    VerifyOptions {
+      sample: Sample::All,
+      max_retries: 2,
+      color_tolerance: 1,
+    }
+  }
+}
+
+/// What a key should be set to, from the commands that were sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ExpectedKey {
+  color: Option<RGBColor>,
+  function: Option<LumatoneKeyFunction>,
+}
+
+/// What [Lumatone::send_verified] found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+  /// Keys set by the commands that were sent.
+  pub total: usize,
+
+  /// Keys that were read back and compared.
+  pub checked: usize,
+
+  /// Keys that didn't match at first, but did after being sent again.
+  pub fixed: Vec<LumatoneKeyLocation>,
+
+  /// Keys that still didn't match after the last retry.
+  pub mismatched: Vec<LumatoneKeyLocation>,
+}
+
+impl VerifyReport {
+  pub fn is_verified(&self) -> bool {
+    self.mismatched.is_empty()
+  }
+
+  /// The most keys, as a share of all keys sent, that could be wrong without showing up
+  /// in the sample, with 95% confidence. Zero if every key was checked, and one if the
+  /// sample wasn't clean, since the bound only holds for a clean sample.
+  /// [Lumatone::send_verified] checks every key when the sample isn't clean.
+  pub fn max_error_rate(&self) -> f64 {
+    match self.checked {
+      0 => 1.0,
+      n if n >= self.total => 0.0,
+      _ if !self.fixed.is_empty() || !self.mismatched.is_empty() => 1.0,
+      n => (3.0 / n as f64).min(1.0),
+    }
+  }
+}
+
+impl Display for VerifyReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "checked {} of {} keys, {} fixed by retrying, {} still wrong",
+      self.checked,
+      self.total,
+      self.fixed.len(),
+      self.mismatched.len()
+    )?;
+    match (self.is_verified(), self.checked >= self.total) {
+      (false, _) => Ok(()),
+      (true, true) => write!(f, "; every key verified"),
+      (true, false) => write!(
+        f,
+        "; 95% confident at most {:.1}% of keys are wrong",
+        self.max_error_rate() * 100.0
+      ),
+    }
+  }
+}
+
+impl Lumatone {
+  /// Sends `commands`, then reads back the keys they set and re-sends any that don't match.
+  /// See [crate::readback].
+  ///
+  /// Fails if sending or reading back fails. Keys that are still wrong after the last retry
+  /// are listed in the report instead.
+  pub async fn send_verified(
+    &self,
+    commands: Vec<Command>,
+    options: &VerifyOptions,
+  ) -> Result<VerifyReport, LumatoneMidiError> {
+    for command in commands.iter().cloned() {
+      self.send(command).await?;
+    }
+
+    let brightness = self.driver().brightness().clone();
+    let mut expected: HashMap<LumatoneKeyLocation, ExpectedKey> = HashMap::new();
+    let mut key_commands: HashMap<LumatoneKeyLocation, Vec<Command>> = HashMap::new();
+    for command in commands {
+      let location = match &command {
+        Command::SetKeyColor { location, color } => {
+          // the device stores the color after the brightness is applied
+          let scaled = match brightness.apply(command.clone()) {
+            Command::SetKeyColor { color, .. } => color,
+            _ => *color,
+          };
+          expected.entry(*location).or_default().color = Some(scaled);
+          *location
+        }
+        Command::SetKeyFunction { location, function } => {
+          expected.entry(*location).or_default().function = Some(*function);
+          *location
+        }
+        _ => continue,
+      };
+      key_commands.entry(location).or_default().push(command);
+    }
+
+    let all_keys: Vec<LumatoneKeyLocation> = LumatoneKeyLocation::all()
+      .into_iter()
+      .filter(|location| expected.contains_key(location))
+      .collect();
+    let mut keys = all_keys.clone();
+    if let Sample::Random(n) = options.sample {
+      let sample: Vec<_> = keys
+        .choose_multiple(&mut rand::thread_rng(), n)
+        .copied()
+        .collect();
+      keys.retain(|location| sample.contains(location));
+    }
+    let mut report = VerifyReport {
+      total: expected.len(),
+      checked: keys.len(),
+      ..Default::default()
+    };
+
+    let mut first_mismatches = None;
+    for attempt in 0..=options.max_retries {
+      let device = self.read_back(&keys).await?;
+      keys = mismatches(&keys, &expected, &device, options.color_tolerance);
+      if attempt == 0 && !keys.is_empty() && report.checked < report.total {
+        warn!(
+          "{} of {} sampled keys don't match after upload, checking every key",
+          keys.len(),
+          report.checked
+        );
+        let device = self.read_back(&all_keys).await?;
+        keys = mismatches(&all_keys, &expected, &device, options.color_tolerance);
+        report.checked = report.total;
+      }
+      let first = first_mismatches.get_or_insert_with(|| keys.clone());
+      if keys.is_empty() || attempt == options.max_retries {
+        report.fixed = first
+          .iter()
+          .filter(|l| !keys.contains(l))
+          .copied()
+          .collect();
+        break;
+      }
+      warn!(
+        "{} keys don't match after upload, re-sending (retry {} of {})",
+        keys.len(),
+        attempt + 1,
+        options.max_retries
+      );
+      for location in &keys {
+        for command in key_commands.get(location).into_iter().flatten() {
+          self.send(command.clone()).await?;
+        }
+      }
+    }
+    report.mismatched = keys;
+    info!("verified upload: {report}");
+    Ok(report)
+  }
+
+  /// Reads the color and function of each key on the boards `keys` are on.
+  async fn read_back(
+    &self,
+    keys: &[LumatoneKeyLocation],
+  ) -> Result<HashMap<LumatoneKeyLocation, ExpectedKey>, LumatoneMidiError> {
+    let mut device = HashMap::new();
+    for board in BoardIndex::all_octaves() {
+      if !keys.iter().any(|l| l.board_index() == board) {
+        continue;
+      }
+      let colors = self.get_key_colors(board).await?;
+      let functions = self.get_key_functions(board).await?;
+      for ((key_index, color), function) in LumatoneKeyIndex::all()
+        .into_iter()
+        .zip(colors.values)
+        .zip(functions.values)
+      {
+        device.insert(
+          LumatoneKeyLocation(board, key_index),
+          ExpectedKey {
+            color: Some(color),
+            function: Some(function),
+          },
+        );
+      }
+    }
+    Ok(device)
+  }
+}
+
+/// Returns the `keys` whose color or function on the device doesn't match `expected`.
+fn mismatches(
+  keys: &[LumatoneKeyLocation],
+  expected: &HashMap<LumatoneKeyLocation, ExpectedKey>,
+  device: &HashMap<LumatoneKeyLocation, ExpectedKey>,
+  color_tolerance: u8,
+) -> Vec<LumatoneKeyLocation> {
+  keys
+    .iter()
+    .filter(|location| {
+      let (want, have) = match (expected.get(location), device.get(location)) {
+        (Some(want), Some(have)) => (want, have),
+        (Some(_), None) => return true,
+        _ => return false,
+      };
+      let color_ok = match (want.color, have.color) {
+        (Some(a), Some(b)) => colors_within(a, b, color_tolerance),
+        (Some(_), None) => false,
+        (None, _) => true,
+      };
+      let function_ok = want.function.is_none() || want.function == have.function;
+      !(color_ok && function_ok)
+    })
+    .copied()
+    .collect()
+}
+
+fn colors_within(a: RGBColor, b: RGBColor, tolerance: u8) -> bool {
+  let (RGBColor(r1, g1, b1), RGBColor(r2, g2, b2)) = (a, b);
+  r1.abs_diff(r2) <= tolerance && g1.abs_diff(g2) <= tolerance && b1.abs_diff(b2) <= tolerance
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::constants::{key_loc_unchecked, MidiChannel};
+
+  #[test]
+  fn test_readback_mismatches_and_report() {
+    let note = LumatoneKeyFunction::NoteOnOff {
+      channel: MidiChannel::default(),
+      note_num: 60,
+    };
+    let key = |color| ExpectedKey {
+      color: Some(color),
+      function: Some(note),
+    };
+    let (a, b, c) = (
+      key_loc_unchecked(1, 0),
+      key_loc_unchecked(1, 1),
+      key_loc_unchecked(2, 0),
+    );
+    let expected = HashMap::from([
+      (a, key(RGBColor(200, 100, 0))),
+      (b, key(RGBColor::red())),
+      (c, key(RGBColor::blue())),
+    ]);
+    let device = HashMap::from([
+      // off by one, which is within tolerance
+      (a, key(RGBColor(199, 101, 0))),
+      (
+        b,
+        ExpectedKey {
+          color: Some(RGBColor::red()),
+          function: Some(LumatoneKeyFunction::Disabled),
+        },
+      ),
+    ]);
+    assert_eq!(mismatches(&[a, b, c], &expected, &device, 1), vec![b, c]);
+    assert_eq!(mismatches(&[a], &expected, &device, 0), vec![a]);
+
+    let mut report = VerifyReport {
+      total: 280,
+      checked: 60,
+      ..Default::default()
+    };
+    assert!((report.max_error_rate() - 0.05).abs() < 1e-9);
+    assert!(report
+      .to_string()
+      .ends_with("at most 5.0% of keys are wrong"));
+    report.fixed = vec![a];
+    assert_eq!(report.max_error_rate(), 1.0);
+    report.checked = 280;
+    assert_eq!(report.max_error_rate(), 0.0);
+    report.mismatched = vec![b];
+    assert!(!report.is_verified());
+    assert!(report.to_string().ends_with("1 still wrong"));
+  }
+}
@@ -0,0 +1,298 @@
+//! Declarative device configuration.
+//!
+//! Instead of sending configuration commands one by one, an application can describe the
+//! configuration it wants as a [DesiredState] (usually built from a keymap's commands) and
+//! let the crate work out what to send. [DesiredState::plan] compares it against the
+//! [ConfigMirror] of what the device has already acknowledged, and only keeps the commands
+//! that would change something, so converging twice in a row sends nothing the second time.
+//!
+//! Use [Lumatone::reconcile](crate::controller::Lumatone::reconcile) to converge once, or
+//! [Lumatone::start_reconciler](crate::controller::Lumatone::start_reconciler) to keep the
+//! device converged. The running reconciler pings the device like the
+//! [resync watchdog](crate::resync), and after a reset it forgets what the device had and
+//! sends the whole desired state again. It does the same after the driver reconnects, e.g.
+//! when the host wakes from sleep (see [crate::suspend]).
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::sync::{broadcast, watch};
+
+use super::{
+  commands::{ping, Command},
+  driver::MidiDriver,
+  error::LumatoneMidiError,
+  mirror::ConfigMirror,
+  responses::Response,
+  resync::{PingOutcome, ResetDetector},
+  shutdown::CancellationToken,
+  suspend::ConnectionEvent,
+};
+
+use error_stack::Result;
+
+/// How many [ReconcileEvent]s a slow receiver can fall behind before the oldest are dropped.
+pub(crate) const RECONCILE_EVENTS_BUFFER_SIZE: usize = 16;
+
+/// The configuration the device should have.
+///
+/// Holds the latest command for each setting, like a [ConfigMirror]. Commands that don't
+/// change the configuration (queries, pings, etc.) are ignored.
+#[derive(Debug, Default)]
+pub struct DesiredState {
+  config: ConfigMirror,
+}
+
+impl DesiredState {
+  pub fn new() -> Self {
+    DesiredState::default()
+  }
+
+  pub fn from_commands<I: IntoIterator<Item = Command>>(commands: I) -> Self {
+    let mut state = DesiredState::new();
+    for command in commands {
+      state.set(command);
+    }
+    state
+  }
+
+  /// Sets the value of the setting that `command` changes, replacing any earlier value.
+  pub fn set(&mut self, command: Command) {
+    self.config.record(&command);
+  }
+
+  pub fn commands(&self) -> Vec<Command> {
+    self.config.commands()
+  }
+
+  /// Returns the commands needed to bring a device whose configuration is `current` to
+  /// this state, in the order they were set.
+  pub fn plan(&self, current: &ConfigMirror) -> Vec<Command> {
+    self
+      .config
+      .commands()
+      .into_iter()
+      .filter(|command| !current.contains(command))
+      .collect()
+  }
+}
+
+/// What a call to [converge] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+  /// Commands sent to the device.
+  pub sent: usize,
+  /// Settings that already had the desired value.
+  pub unchanged: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconcileEvent {
+  /// The device has the desired configuration.
+  Converged(ReconcileReport),
+
+  /// A ping failed. The device may be disconnected or restarting.
+  DeviceUnresponsive,
+
+  /// Sending the configuration failed. It will be tried again after the next successful
+  /// ping, sending the whole desired state in case the device reset.
+  Failed(String),
+}
+
+/// Sends the commands from [DesiredState::plan] and records each one in `mirror` once the
+/// device acknowledges it.
+///
+/// Stops at the first command that fails. The commands sent before it stay recorded, so
+/// converging again picks up where this left off.
+pub async fn converge(
+  driver: &MidiDriver,
+  mirror: &Mutex<ConfigMirror>,
+  desired: &DesiredState,
+) -> Result<ReconcileReport, LumatoneMidiError> {
+  let plan = desired.plan(&mirror.lock().unwrap());
+  let report = ReconcileReport {
+    sent: plan.len(),
+    unchanged: desired.config.len() - plan.len(),
+  };
+  debug!(
+    "reconciling: {} commands to send, {} settings unchanged",
+    report.sent, report.unchanged
+  );
+  for command in plan {
+    driver.send(command.clone()).await?;
+    mirror.lock().unwrap().record(&command);
+  }
+  Ok(report)
+}
+
+/// Controls a reconciler started with
+/// [Lumatone::start_reconciler](crate::controller::Lumatone::start_reconciler).
+pub struct ReconcilerHandle {
+  desired: watch::Sender<DesiredState>,
+  events: broadcast::Sender<ReconcileEvent>,
+}
+
+impl ReconcilerHandle {
+  /// Replaces the desired state. The reconciler converges to it straight away.
+  pub fn set_desired(&self, desired: DesiredState) {
+    self.desired.send_replace(desired);
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<ReconcileEvent> {
+    self.events.subscribe()
+  }
+}
+
+/// Returns a [ReconcilerHandle], and a future that runs the reconciler until `shutdown` is
+/// cancelled or the handle is dropped.
+pub(crate) fn start_reconciler(
+  driver: MidiDriver,
+  mirror: Arc<Mutex<ConfigMirror>>,
+  desired: DesiredState,
+  interval: Duration,
+  shutdown: CancellationToken,
+) -> (ReconcilerHandle, impl futures::Future<Output = ()>) {
+  let (desired_tx, desired_rx) = watch::channel(desired);
+  let (events, _) = broadcast::channel(RECONCILE_EVENTS_BUFFER_SIZE);
+  let handle = ReconcilerHandle {
+    desired: desired_tx,
+    events: events.clone(),
+  };
+  let future = run_reconciler(driver, mirror, desired_rx, events, interval, shutdown);
+  (handle, future)
+}
+
+async fn run_reconciler(
+  driver: MidiDriver,
+  mirror: Arc<Mutex<ConfigMirror>>,
+  mut desired: watch::Receiver<DesiredState>,
+  events: broadcast::Sender<ReconcileEvent>,
+  interval: Duration,
+  shutdown: CancellationToken,
+) {
+  let mut detector = ResetDetector::default();
+  let mut ticker = tokio::time::interval(interval);
+  let mut ping_value: u32 = 0;
+  let mut needs_converge = true;
+  let mut connection = match driver.subscribe_connection() {
+    Ok(connection) => connection,
+    Err(err) => {
+      warn!("not starting the reconciler: {err:?}");
+      return;
+    }
+  };
+
+  loop {
+    if needs_converge {
+      // the state can't be borrowed across the sends, so converge on a copy
+      let state = DesiredState::from_commands(desired.borrow().commands());
+      match converge(&driver, &mirror, &state).await {
+        Ok(report) => {
+          needs_converge = false;
+          let _ = events.send(ReconcileEvent::Converged(report));
+        }
+        Err(err) => {
+          warn!("reconciling failed: {err:?}");
+          detector.mark_unresponsive();
+          let _ = events.send(ReconcileEvent::Failed(err.to_string()));
+        }
+      }
+    }
+
+    tokio::select! {
+      _ = shutdown.cancelled() => break,
+      changed = desired.changed() => match changed {
+        Ok(()) => {
+          needs_converge = true;
+          continue;
+        }
+        Err(_) => break,
+      },
+      event = connection.recv() => match event {
+        // the device may have reset while the connection was down, so check it now
+        Ok(ConnectionEvent::Reconnected) => detector.mark_unresponsive(),
+        Err(broadcast::error::RecvError::Closed) => break,
+        _ => continue,
+      },
+      _ = ticker.tick() => {}
+    }
+
+    ping_value = ping_value.wrapping_add(1) & 0xfffffff;
+    let ok = matches!(driver.send(ping(ping_value)).await, Ok(Response::Pong(_)));
+
+    match detector.ping_result(ok) {
+      PingOutcome::Unchanged => {}
+
+      PingOutcome::WentUnresponsive => {
+        info!("device stopped responding to pings");
+        let _ = events.send(ReconcileEvent::DeviceUnresponsive);
+      }
+
+      PingOutcome::Recovered => {
+        info!("device responding again, sending the whole desired state");
+        mirror.lock().unwrap().clear();
+        needs_converge = true;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    commands::set_key_color,
+    constants::{key_loc_unchecked, RGBColor},
+    testing::FakeDevice,
+  };
+
+  #[tokio::test(start_paused = true)]
+  async fn converging_only_sends_changed_settings() {
+    let (driver, driver_future) =
+      MidiDriver::with_transport(FakeDevice::new(), CancellationToken::new());
+    tokio::spawn(driver_future);
+    let mirror = Mutex::new(ConfigMirror::new());
+
+    let mut desired = DesiredState::from_commands([
+      set_key_color(key_loc_unchecked(1, 0), RGBColor::red()),
+      set_key_color(key_loc_unchecked(1, 1), RGBColor::green()),
+      Command::SetLightOnKeystrokes(true),
+      ping(1),
+    ]);
+    let report = converge(&driver, &mirror, &desired).await.unwrap();
+    assert_eq!(
+      report,
+      ReconcileReport {
+        sent: 3,
+        unchanged: 0
+      }
+    );
+
+    let report = converge(&driver, &mirror, &desired).await.unwrap();
+    assert_eq!(
+      report,
+      ReconcileReport {
+        sent: 0,
+        unchanged: 3
+      }
+    );
+
+    desired.set(set_key_color(key_loc_unchecked(1, 0), RGBColor::blue()));
+    assert_eq!(
+      desired.plan(&mirror.lock().unwrap()),
+      vec![set_key_color(key_loc_unchecked(1, 0), RGBColor::blue())]
+    );
+    let report = converge(&driver, &mirror, &desired).await.unwrap();
+    assert_eq!(
+      report,
+      ReconcileReport {
+        sent: 1,
+        unchanged: 2
+      }
+    );
+    assert_eq!(driver.recent_traffic().len(), 8);
+  }
+}
@@ -0,0 +1,817 @@
+//! Records sequences of [Command]s as named macros that can be saved to a file and
+//! replayed later.
+//!
+//! Each step of a [CommandMacro] is a typed command, saved as the command's name followed by
+//! its fields, e.g. `SetKeyColor board=1 key=5 red=255 green=128 blue=0`. Tables are written
+//! as comma-separated values. Any number can be replaced with a `{name}` placeholder
+//! (optionally with an offset after a colon, e.g. `{cc:+1}` or `{channel:-1}`), which makes
+//! the macro parameterizable. Values for each parameter are passed to [CommandMacro::render]
+//! when the macro is played. Parameter names can't contain whitespace, `:`, `=`, `,` or
+//! braces.
+//!
+//! For example, this macro sets two keys on board 1 to consecutive CC numbers on the same
+//! channel:
+//!
+//! ```text
+//! name = pedalboard
+//! params = channel, cc
+//! SetKeyFunction board=1 key=0 type=2 channel={channel} number={cc}
+//! SetKeyFunction board=1 key=1 type=2 channel={channel} number={cc:+1}
+//! ```
+//!
+//! Key functions are saved as the type code sent to the device (see
+//! [LumatoneKeyFunction::type_code]), the channel counted from 1, and the note or CC number.
+//!
+//! Since the steps are typed commands, they're encoded for the connected firmware when the
+//! macro is played, and the changes they make are mirrored like any other command's (see
+//! [crate::mirror]).
+//!
+//! Lines starting with `#` are comments. Use [Lumatone::start_recording](crate::controller::Lumatone::start_recording)
+//! to record commands as they're sent.
+
+use std::{collections::HashMap, fmt::Display, path::Path, str::FromStr};
+
+use super::{
+  commands::Command,
+  constants::{
+    BoardIndex, LumatoneKeyFunction, LumatoneKeyIndex, LumatoneKeyLocation, MidiChannel,
+    PresetNumber, RGBColor,
+  },
+  error::LumatoneMidiError,
+  sysex::{SysexTable, VelocityIntervalTable},
+};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+/// The values to substitute for a macro's parameters, by name.
+pub type MacroParams = HashMap<String, u8>;
+
+fn invalid<S: Into<String>>(msg: S) -> LumatoneMidiError {
+  LumatoneMidiError::InvalidMacro(msg.into())
+}
+
+fn is_valid_param_name(name: &str) -> bool {
+  !name.is_empty()
+    && !name
+      .chars()
+      .any(|c| c.is_whitespace() || ":=,{}".contains(c))
+}
+
+/// The value of one of a [MacroStep]'s fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroValue {
+  Number(u32),
+
+  /// Replaced with the value of the named parameter, plus `offset`.
+  Param {
+    name: String,
+    offset: i32,
+  },
+
+  Table(Vec<u16>),
+}
+
+impl MacroValue {
+  /// Substitutes `params` for a parameter, leaving other values as they are.
+  fn render(&self, params: &MacroParams) -> Result<MacroValue, LumatoneMidiError> {
+    match self {
+      MacroValue::Param { name, offset } => {
+        let value = match params.get(name) {
+          Some(v) => *v as i64 + *offset as i64,
+          None => bail!(LumatoneMidiError::MissingMacroParameter(name.clone())),
+        };
+        match u32::try_from(value) {
+          Ok(value) => Ok(MacroValue::Number(value)),
+          Err(_) => bail!(invalid(format!(
+            "value {value} for parameter {{{name}}} is negative"
+          ))),
+        }
+      }
+      value => Ok(value.clone()),
+    }
+  }
+}
+
+impl Display for MacroValue {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      MacroValue::Number(n) => write!(f, "{n}"),
+      MacroValue::Param { name, offset: 0 } => write!(f, "{{{name}}}"),
+      MacroValue::Param { name, offset } => write!(f, "{{{name}:{offset:+}}}"),
+      MacroValue::Table(values) => {
+        let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        write!(f, "{}", values.join(","))
+      }
+    }
+  }
+}
+
+impl FromStr for MacroValue {
+  type Err = error_stack::Report<LumatoneMidiError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let invalid_value = || invalid(format!("invalid value: {s}"));
+
+    if let Some(param) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+      let (name, offset) = match param.split_once(':') {
+        Some((name, offset)) => {
+          let offset = offset
+            .parse::<i32>()
+            .report()
+            .change_context_lazy(invalid_value)?;
+          (name, offset)
+        }
+        None => (param, 0),
+      };
+      if !is_valid_param_name(name) {
+        return Err(report!(invalid_value()));
+      }
+      return Ok(MacroValue::Param {
+        name: name.to_string(),
+        offset,
+      });
+    }
+
+    if s.contains(',') {
+      return s
+        .split(',')
+        .map(|v| v.parse::<u16>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .report()
+        .change_context_lazy(invalid_value)
+        .map(MacroValue::Table);
+    }
+
+    s.parse::<u32>()
+      .report()
+      .change_context_lazy(invalid_value)
+      .map(MacroValue::Number)
+  }
+}
+
+/// One command in a [CommandMacro]: the name of the [Command] variant, and its fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroStep {
+  pub command: String,
+  pub fields: Vec<(String, MacroValue)>,
+}
+
+impl MacroStep {
+  fn new(command: &Command) -> Self {
+    let name = command.to_string();
+    let name = name
+      .split(|c: char| !c.is_ascii_alphanumeric())
+      .next()
+      .unwrap_or_default();
+    MacroStep {
+      command: name.to_string(),
+      fields: vec![],
+    }
+  }
+
+  fn number<N: Into<u32>>(&mut self, field: &str, value: N) {
+    let value = MacroValue::Number(value.into());
+    self.fields.push((field.to_string(), value));
+  }
+
+  fn table<I: IntoIterator<Item = u16>>(&mut self, field: &str, values: I) {
+    let value = MacroValue::Table(values.into_iter().collect());
+    self.fields.push((field.to_string(), value));
+  }
+
+  fn board(&mut self, board: BoardIndex) {
+    self.number::<u8>("board", board.into());
+  }
+
+  fn location(&mut self, location: &LumatoneKeyLocation) {
+    self.board(location.board_index());
+    self.number("key", location.key_index().get());
+  }
+
+  fn color(&mut self, color: &RGBColor) {
+    let RGBColor(r, g, b) = *color;
+    self.number("red", r);
+    self.number("green", g);
+    self.number("blue", b);
+  }
+
+  /// Builds the step's command, with `params` substituted for its parameters.
+  pub fn render(&self, params: &MacroParams) -> Result<Command, LumatoneMidiError> {
+    let mut fields = HashMap::new();
+    for (name, value) in &self.fields {
+      fields.insert(name.as_str(), value.render(params)?);
+    }
+    let f = Fields(fields);
+
+    use Command::*;
+    let command = match self.command.as_str() {
+      "Ping" => Ping(f.number("value")?),
+      "SetKeyFunction" => {
+        let channel = f.channel("channel")?;
+        let function =
+          LumatoneKeyFunction::from_type_code(f.number("type")?, channel, f.number("number")?)
+            .ok_or_else(|| report!(invalid("unknown key type")))?;
+        SetKeyFunction {
+          location: f.location()?,
+          function,
+        }
+      }
+      "SetKeyColor" => SetKeyColor {
+        location: f.location()?,
+        color: f.color()?,
+      },
+      "SaveProgram" => {
+        let preset = PresetNumber::new(f.number("preset")?)
+          .ok_or_else(|| report!(invalid("preset is out of range")))?;
+        SaveProgram(preset)
+      }
+      "SetExpressionPedalSensitivity" => SetExpressionPedalSensitivity(f.number("value")?),
+      "SetModWheelSensitivity" => SetModWheelSensitivity(f.number("value")?),
+      "SetPitchWheelSensitivity" => SetPitchWheelSensitivity(f.number("value")?),
+      "SetPitchWheelZeroThreshold" => SetPitchWheelZeroThreshold(f.number("value")?),
+      "SetExpressionPedalADCThreshold" => SetExpressionPedalADCThreshold(f.number("value")?),
+      "InvertFootController" => InvertFootController(f.flag("value")?),
+      "InvertSustainPedal" => InvertSustainPedal(f.flag("value")?),
+      "SetLightOnKeystrokes" => SetLightOnKeystrokes(f.flag("value")?),
+      "SetAftertouchEnabled" => SetAftertouchEnabled(f.flag("value")?),
+      "EnableDemoMode" => EnableDemoMode(f.flag("value")?),
+      "EnablePitchModWheelCalibrationMode" => EnablePitchModWheelCalibrationMode(f.flag("value")?),
+      "EnableExpressionPedalCalibrationMode" => {
+        EnableExpressionPedalCalibrationMode(f.flag("value")?)
+      }
+      "SetMacroButtonActiveColor" => SetMacroButtonActiveColor(f.color()?),
+      "SetMacroButtonInactiveColor" => SetMacroButtonInactiveColor(f.color()?),
+      "SetVelocityConfig" => SetVelocityConfig(f.sysex_table()?),
+      "SetFaderConfig" => SetFaderConfig(f.sysex_table()?),
+      "SetAftertouchConfig" => SetAftertouchConfig(f.sysex_table()?),
+      "SetLumatouchConfig" => SetLumatouchConfig(f.sysex_table()?),
+      "SetVelocityIntervals" => {
+        let table: VelocityIntervalTable = f
+          .table("table")?
+          .try_into()
+          .map_err(|_| report!(invalid("table must have 127 values")))?;
+        SetVelocityIntervals(Box::new(table))
+      }
+      "SetKeyMaximumThreshold" => SetKeyMaximumThreshold {
+        board_index: f.board()?,
+        max_threshold: f.number("max_threshold")?,
+        aftertouch_max: f.number("aftertouch_max")?,
+      },
+      "SetKeyMinimumThreshold" => SetKeyMinimumThreshold {
+        board_index: f.board()?,
+        threshold_high: f.number("threshold_high")?,
+        threshold_low: f.number("threshold_low")?,
+      },
+      "SetKeyFaderSensitivity" => SetKeyFaderSensitivity(f.board()?, f.number("value")?),
+      "SetKeyAftertouchSensitivity" => SetKeyAftertouchSensitivity(f.board()?, f.number("value")?),
+      "SetCCActiveThreshold" => SetCCActiveThreshold(f.board()?, f.number("value")?),
+      "SetAftertouchTriggerDelay" => SetAftertouchTriggerDelay(f.board()?, f.number("value")?),
+      "SetLumatouchNoteOffDelay" => SetLumatouchNoteOffDelay(f.board()?, f.number("value")?),
+      "EnableKeySampling" => EnableKeySampling(f.board()?, f.flag("value")?),
+      "SetPeripheralChannels" => SetPeripheralChannels {
+        pitch_wheel: f.channel("pitch_wheel")?,
+        mod_wheel: f.channel("mod_wheel")?,
+        expression: f.channel("expression")?,
+        sustain: f.channel("sustain")?,
+      },
+      "Raw" => {
+        let data = f
+          .table("data")?
+          .into_iter()
+          .map(u8::try_from)
+          .collect::<std::result::Result<Vec<_>, _>>()
+          .map_err(|_| report!(invalid("raw data must be bytes")))?;
+        super::commands::raw_sysex(&data)
+          .report()
+          .change_context(invalid("raw data isn't a valid message"))?
+      }
+      name => match unit_command(name) {
+        Some(command) => command,
+        None => match board_command(name, f.board()?) {
+          Some(command) => command,
+          None => bail!(invalid(format!("unknown command: {name}"))),
+        },
+      },
+    };
+    Ok(command)
+  }
+}
+
+impl From<&Command> for MacroStep {
+  fn from(command: &Command) -> Self {
+    use Command::*;
+    let mut step = MacroStep::new(command);
+    match command {
+      Ping(value) => step.number("value", *value),
+      SetKeyFunction { location, function } => {
+        step.location(location);
+        step.number("type", function.type_code());
+        step.number("channel", function.midi_channel_num());
+        step.number("number", function.note_or_cc_num());
+      }
+      SetKeyColor { location, color } => {
+        step.location(location);
+        step.color(color);
+      }
+      SaveProgram(preset) => step.number("preset", preset.get()),
+      SetExpressionPedalSensitivity(value)
+      | SetModWheelSensitivity(value)
+      | SetPitchWheelZeroThreshold(value) => step.number("value", *value),
+      SetPitchWheelSensitivity(value) | SetExpressionPedalADCThreshold(value) => {
+        step.number("value", *value)
+      }
+      InvertFootController(on)
+      | InvertSustainPedal(on)
+      | SetLightOnKeystrokes(on)
+      | SetAftertouchEnabled(on)
+      | EnableDemoMode(on)
+      | EnablePitchModWheelCalibrationMode(on)
+      | EnableExpressionPedalCalibrationMode(on) => step.number("value", *on),
+      SetMacroButtonActiveColor(color) | SetMacroButtonInactiveColor(color) => step.color(color),
+      SetVelocityConfig(table)
+      | SetFaderConfig(table)
+      | SetAftertouchConfig(table)
+      | SetLumatouchConfig(table) => step.table("table", table.iter().map(|v| *v as u16)),
+      SetVelocityIntervals(table) => step.table("table", table.iter().copied()),
+      SetKeyMaximumThreshold {
+        board_index,
+        max_threshold,
+        aftertouch_max,
+      } => {
+        step.board(*board_index);
+        step.number("max_threshold", *max_threshold);
+        step.number("aftertouch_max", *aftertouch_max);
+      }
+      SetKeyMinimumThreshold {
+        board_index,
+        threshold_high,
+        threshold_low,
+      } => {
+        step.board(*board_index);
+        step.number("threshold_high", *threshold_high);
+        step.number("threshold_low", *threshold_low);
+      }
+      SetKeyFaderSensitivity(board, value)
+      | SetKeyAftertouchSensitivity(board, value)
+      | SetCCActiveThreshold(board, value)
+      | SetAftertouchTriggerDelay(board, value) => {
+        step.board(*board);
+        step.number("value", *value);
+      }
+      SetLumatouchNoteOffDelay(board, value) => {
+        step.board(*board);
+        step.number("value", *value);
+      }
+      EnableKeySampling(board, on) => {
+        step.board(*board);
+        step.number("value", *on);
+      }
+      SetPeripheralChannels {
+        pitch_wheel,
+        mod_wheel,
+        expression,
+        sustain,
+      } => {
+        step.number("pitch_wheel", pitch_wheel.get());
+        step.number("mod_wheel", mod_wheel.get());
+        step.number("expression", expression.get());
+        step.number("sustain", sustain.get());
+      }
+      Raw(msg) => step.table("data", msg.iter().map(|b| *b as u16)),
+      ResetBoardThresholds(board)
+      | GetAftertouchTriggerDelay(board)
+      | GetLumatouchNoteOffDelay(board)
+      | GetRedLEDConfig(board)
+      | GetGreenLEDConfig(board)
+      | GetBlueLEDConfig(board)
+      | GetMidiChannelConfig(board)
+      | GetNoteConfig(board)
+      | GetKeyTypeConfig(board)
+      | GetMaxFaderThreshold(board)
+      | GetMinFaderThreshold(board)
+      | GetMaxAftertouchThreshold(board)
+      | GetKeyValidity(board)
+      | GetFaderTypeConfig(board)
+      | GetBoardThresholdValues(board)
+      | GetBoardSensitivityValues(board) => step.board(*board),
+      _ => {}
+    }
+    step
+  }
+}
+
+/// The commands without any fields, by name.
+fn unit_command(name: &str) -> Option<Command> {
+  use Command::*;
+  let command = match name {
+    "GetVelocityConfig" => GetVelocityConfig,
+    "GetVelocityIntervalConfig" => GetVelocityIntervalConfig,
+    "GetFaderConfig" => GetFaderConfig,
+    "GetAftertouchConfig" => GetAftertouchConfig,
+    "GetLumatouchConfig" => GetLumatouchConfig,
+    "GetSerialId" => GetSerialId,
+    "GetFirmwareRevision" => GetFirmwareRevision,
+    "StartAftertouchCalibration" => StartAftertouchCalibration,
+    "StartKeyCalibration" => StartKeyCalibration,
+    "SaveVelocityConfig" => SaveVelocityConfig,
+    "ResetVelocityConfig" => ResetVelocityConfig,
+    "SaveFaderConfig" => SaveFaderConfig,
+    "ResetFaderConfig" => ResetFaderConfig,
+    "SaveAftertouchConfig" => SaveAftertouchConfig,
+    "ResetAftertouchConfig" => ResetAftertouchConfig,
+    "SaveLumatouchConfig" => SaveLumatouchConfig,
+    "ResetLumatouchConfig" => ResetLumatouchConfig,
+    "ResetWheelThresholds" => ResetWheelThresholds,
+    "ResetExpressionPedalBounds" => ResetExpressionPedalBounds,
+    "GetPeripheralChannels" => GetPeripheralChannels,
+    "GetExpressionPedalADCThreshold" => GetExpressionPedalADCThreshold,
+    _ => return None,
+  };
+  Some(command)
+}
+
+/// The commands that only take a board, by name.
+fn board_command(name: &str, board: BoardIndex) -> Option<Command> {
+  use Command::*;
+  let command = match name {
+    "ResetBoardThresholds" => ResetBoardThresholds(board),
+    "GetAftertouchTriggerDelay" => GetAftertouchTriggerDelay(board),
+    "GetLumatouchNoteOffDelay" => GetLumatouchNoteOffDelay(board),
+    "GetRedLEDConfig" => GetRedLEDConfig(board),
+    "GetGreenLEDConfig" => GetGreenLEDConfig(board),
+    "GetBlueLEDConfig" => GetBlueLEDConfig(board),
+    "GetMidiChannelConfig" => GetMidiChannelConfig(board),
+    "GetNoteConfig" => GetNoteConfig(board),
+    "GetKeyTypeConfig" => GetKeyTypeConfig(board),
+    "GetMaxFaderThreshold" => GetMaxFaderThreshold(board),
+    "GetMinFaderThreshold" => GetMinFaderThreshold(board),
+    "GetMaxAftertouchThreshold" => GetMaxAftertouchThreshold(board),
+    "GetKeyValidity" => GetKeyValidity(board),
+    "GetFaderTypeConfig" => GetFaderTypeConfig(board),
+    "GetBoardThresholdValues" => GetBoardThresholdValues(board),
+    "GetBoardSensitivityValues" => GetBoardSensitivityValues(board),
+    _ => return None,
+  };
+  Some(command)
+}
+
+/// A step's fields, after its parameters have been substituted.
+struct Fields<'a>(HashMap<&'a str, MacroValue>);
+
+impl Fields<'_> {
+  fn number<N: TryFrom<u32>>(&self, field: &str) -> Result<N, LumatoneMidiError> {
+    match self.0.get(field) {
+      Some(MacroValue::Number(n)) => {
+        N::try_from(*n).map_err(|_| report!(invalid(format!("{field} is out of range: {n}"))))
+      }
+      Some(_) => bail!(invalid(format!("{field} must be a number"))),
+      None => bail!(invalid(format!("missing field: {field}"))),
+    }
+  }
+
+  fn flag(&self, field: &str) -> Result<bool, LumatoneMidiError> {
+    match self.number::<u8>(field)? {
+      0 => Ok(false),
+      1 => Ok(true),
+      _ => bail!(invalid(format!("{field} must be 0 or 1"))),
+    }
+  }
+
+  fn table(&self, field: &str) -> Result<Vec<u16>, LumatoneMidiError> {
+    match self.0.get(field) {
+      Some(MacroValue::Table(values)) => Ok(values.clone()),
+      Some(MacroValue::Number(_)) => Ok(vec![self.number(field)?]),
+      Some(_) => bail!(invalid(format!("{field} must be a table"))),
+      None => bail!(invalid(format!("missing field: {field}"))),
+    }
+  }
+
+  fn sysex_table(&self) -> Result<Box<SysexTable>, LumatoneMidiError> {
+    let table: SysexTable = self
+      .table("table")?
+      .into_iter()
+      .map(u8::try_from)
+      .collect::<std::result::Result<Vec<_>, _>>()
+      .ok()
+      .and_then(|values| values.try_into().ok())
+      .ok_or_else(|| report!(invalid("table must have 128 values from 0 to 255")))?;
+    Ok(Box::new(table))
+  }
+
+  fn board(&self) -> Result<BoardIndex, LumatoneMidiError> {
+    let board = self.number::<u8>("board")?;
+    BoardIndex::try_from(board).map_err(|_| report!(invalid(format!("invalid board: {board}"))))
+  }
+
+  fn location(&self) -> Result<LumatoneKeyLocation, LumatoneMidiError> {
+    let key = self.number::<u8>("key")?;
+    let key = LumatoneKeyIndex::new(key)
+      .ok_or_else(|| report!(invalid(format!("invalid key index: {key}"))))?;
+    Ok(LumatoneKeyLocation(self.board()?, key))
+  }
+
+  fn channel(&self, field: &str) -> Result<MidiChannel, LumatoneMidiError> {
+    let channel = self.number::<u8>(field)?;
+    MidiChannel::try_from(channel)
+      .map_err(|_| report!(invalid(format!("invalid {field}: {channel}"))))
+  }
+
+  fn color(&self) -> Result<RGBColor, LumatoneMidiError> {
+    Ok(RGBColor(
+      self.number("red")?,
+      self.number("green")?,
+      self.number("blue")?,
+    ))
+  }
+}
+
+impl Display for MacroStep {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.command)?;
+    for (name, value) in &self.fields {
+      write!(f, " {name}={value}")?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for MacroStep {
+  type Err = error_stack::Report<LumatoneMidiError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let mut words = s.split_whitespace();
+    let command = match words.next() {
+      Some(command) => command.to_string(),
+      None => bail!(invalid("empty step")),
+    };
+    let mut fields = vec![];
+    for word in words {
+      match word.split_once('=') {
+        Some((name, value)) => fields.push((name.to_string(), value.parse::<MacroValue>()?)),
+        None => bail!(invalid(format!("expected name=value, got {word}"))),
+      }
+    }
+    Ok(MacroStep { command, fields })
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandMacro {
+  pub name: String,
+  params: Vec<String>,
+  steps: Vec<MacroStep>,
+}
+
+impl CommandMacro {
+  pub fn new<S: Into<String>>(name: S) -> Self {
+    CommandMacro {
+      name: name.into(),
+      params: vec![],
+      steps: vec![],
+    }
+  }
+
+  /// Appends `command`.
+  pub fn record(&mut self, command: &Command) {
+    self.steps.push(MacroStep::from(command));
+  }
+
+  /// Replaces the value of `field` in step `step` with a placeholder for the parameter
+  /// `name`, plus `offset`.
+  pub fn bind_param(
+    &mut self,
+    step: usize,
+    field: &str,
+    name: &str,
+    offset: i32,
+  ) -> Result<(), LumatoneMidiError> {
+    if !is_valid_param_name(name) {
+      bail!(invalid(format!("invalid parameter name: {name}")));
+    }
+    let value = self
+      .steps
+      .get_mut(step)
+      .and_then(|s| s.fields.iter_mut().find(|(f, _)| f == field))
+      .map(|(_, v)| v);
+    match value {
+      Some(value) if matches!(value, MacroValue::Number(_)) => {
+        *value = MacroValue::Param {
+          name: name.to_string(),
+          offset,
+        }
+      }
+      Some(_) => bail!(invalid(format!("{field} of step {step} isn't a number"))),
+      None => bail!(invalid(format!("step {step} has no field {field}"))),
+    }
+    if !self.params.iter().any(|p| p == name) {
+      self.params.push(name.to_string());
+    }
+    Ok(())
+  }
+
+  /// The names of the macro's parameters, in the order they were declared.
+  pub fn params(&self) -> &[String] {
+    &self.params
+  }
+
+  pub fn steps(&self) -> &[MacroStep] {
+    &self.steps
+  }
+
+  pub fn len(&self) -> usize {
+    self.steps.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.steps.is_empty()
+  }
+
+  /// Substitutes `params` into each step, returning the commands to send.
+  pub fn render(&self, params: &MacroParams) -> Result<Vec<Command>, LumatoneMidiError> {
+    self
+      .steps
+      .iter()
+      .enumerate()
+      .map(|(i, step)| {
+        step
+          .render(params)
+          .attach_printable_lazy(|| format!("in step {i}: {step}"))
+      })
+      .collect()
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<CommandMacro, LumatoneMidiError> {
+    let path = path.as_ref();
+    std::fs::read_to_string(path)
+      .report()
+      .change_context(LumatoneMidiError::MacroFileError(
+        path.display().to_string(),
+      ))?
+      .parse()
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LumatoneMidiError> {
+    let path = path.as_ref();
+    std::fs::write(path, self.to_string())
+      .report()
+      .change_context(LumatoneMidiError::MacroFileError(
+        path.display().to_string(),
+      ))
+  }
+}
+
+impl Display for CommandMacro {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "name = {}", self.name)?;
+    if !self.params.is_empty() {
+      writeln!(f, "params = {}", self.params.join(", "))?;
+    }
+    for step in &self.steps {
+      writeln!(f, "{step}")?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for CommandMacro {
+  type Err = error_stack::Report<LumatoneMidiError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let mut m = CommandMacro::new("");
+    for (n, line) in s.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      if let Some((key, value)) = line.split_once(" = ") {
+        match key.trim() {
+          "name" => m.name = value.trim().to_string(),
+          "params" => {
+            m.params = value
+              .split(',')
+              .map(|p| p.trim().to_string())
+              .filter(|p| !p.is_empty())
+              .collect()
+          }
+          key => bail!(invalid(format!("unknown key on line {}: {key}", n + 1))),
+        }
+        continue;
+      }
+
+      let step: MacroStep = line
+        .parse()
+        .attach_printable_lazy(|| format!("on line {}", n + 1))?;
+      for (_, value) in &step.fields {
+        if let MacroValue::Param { name, .. } = value {
+          if !m.params.contains(name) {
+            bail!(invalid(format!(
+              "parameter {{{name}}} on line {} isn't declared in params",
+              n + 1
+            )));
+          }
+        }
+      }
+      m.steps.push(step);
+    }
+    Ok(m)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    commands::{set_key_color, set_key_function},
+    constants::key_loc_unchecked,
+  };
+
+  fn cc_key(key: u8, channel: u8, cc_num: u8) -> Command {
+    set_key_function(
+      key_loc_unchecked(1, key),
+      LumatoneKeyFunction::ContinuousController {
+        channel: MidiChannel::unchecked(channel),
+        cc_num,
+        fader_up_is_null: false,
+      },
+    )
+  }
+
+  #[test]
+  fn test_record_parameterize_and_render() {
+    let mut m = CommandMacro::new("pedalboard");
+    m.record(&cc_key(0, 1, 20));
+    m.record(&cc_key(1, 1, 21));
+
+    m.bind_param(0, "number", "cc-base", 0).unwrap();
+    m.bind_param(1, "number", "cc-base", 1).unwrap();
+    m.bind_param(1, "channel", "channel", -1).unwrap();
+    assert_eq!(m.params(), ["cc-base".to_string(), "channel".to_string()]);
+    assert!(m.to_string().contains("number={cc-base:+1}"));
+    assert!(m.to_string().contains("channel={channel:-1}"));
+
+    let parsed: CommandMacro = m.to_string().parse().unwrap();
+    assert_eq!(parsed, m);
+
+    let params = MacroParams::from([("cc-base".to_string(), 64), ("channel".to_string(), 3)]);
+    assert_eq!(
+      parsed.render(&params).unwrap(),
+      vec![cc_key(0, 1, 64), cc_key(1, 2, 65)]
+    );
+
+    let err = parsed.render(&MacroParams::new()).unwrap_err();
+    assert!(matches!(
+      err.current_context(),
+      LumatoneMidiError::MissingMacroParameter(_)
+    ));
+  }
+
+  #[test]
+  fn test_commands_round_trip() {
+    let commands = vec![
+      set_key_color(key_loc_unchecked(3, 55), RGBColor(255, 128, 0)),
+      set_key_function(
+        key_loc_unchecked(2, 4),
+        LumatoneKeyFunction::LumaTouch {
+          channel: MidiChannel::unchecked(16),
+          note_num: 60,
+          fader_up_is_null: true,
+        },
+      ),
+      set_key_function(key_loc_unchecked(5, 0), LumatoneKeyFunction::Disabled),
+      Command::SetLightOnKeystrokes(true),
+      Command::SetLumatouchNoteOffDelay(BoardIndex::Octave4, 1000),
+      Command::SetFaderConfig(Box::new([7; 128])),
+      Command::GetRedLEDConfig(BoardIndex::Octave2),
+      Command::GetFirmwareRevision,
+      Command::Raw(vec![0xf0, 0x00, 0x21, 0x50, 0x00, 0x31, 0x00, 0xf7].into()),
+    ];
+    let mut m = CommandMacro::new("everything");
+    for command in &commands {
+      m.record(command);
+    }
+    let parsed: CommandMacro = m.to_string().parse().unwrap();
+    assert_eq!(parsed.render(&MacroParams::new()).unwrap(), commands);
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    assert!("name = x\nSetKeyColor board={cc}"
+      .parse::<CommandMacro>()
+      .is_err());
+    assert!("name = x\nSetKeyColor board=zz"
+      .parse::<CommandMacro>()
+      .is_err());
+    assert!("name = x\nparams = a\nSetKeyColor board={a:x}"
+      .parse::<CommandMacro>()
+      .is_err());
+    assert!("foo = x".parse::<CommandMacro>().is_err());
+
+    let m: CommandMacro = "name = x\nNope board=1".parse().unwrap();
+    assert!(m.render(&MacroParams::new()).is_err());
+    let m: CommandMacro = "name = x\nSetKeyColor board=1 key=56 red=0 green=0 blue=0"
+      .parse()
+      .unwrap();
+    assert!(m.render(&MacroParams::new()).is_err());
+  }
+}
@@ -2,11 +2,13 @@
 use std::fmt::Display;
 
 use super::{
-  constants::{BoardIndex, CommandId, MidiChannel, TEST_ECHO},
+  commands::{set_peripheral_channels, Command},
+  constants::{BoardIndex, CommandId, LumatoneKeyIndex, MidiChannel, TEST_ECHO},
   error::LumatoneMidiError,
+  firmware::{ColorEncoding, FirmwareSupport},
   sysex::{
-    is_lumatone_message, message_command_id, message_payload, strip_sysex_markers, SysexTable,
-    VelocityIntervalTable, BOARD_IND,
+    is_lumatone_message, message_command_id, message_payload, strip_sysex_markers,
+    to_hex_debug_str, EncodedSysex, SysexTable, VelocityIntervalTable, BOARD_IND,
   },
 };
 
@@ -20,37 +22,37 @@ pub enum Response {
   Pong(u32),
 
   /// 8-bit key data for red LED intensity. 112 bytes, lower and upper nibbles for 56 values
-  RedLEDConfig(BoardIndex, Vec<u8>),
+  RedLEDConfig(RedLedConfigResponse),
 
   /// 8-bit key data for green LED intensity. 112 bytes, lower and upper nibbles for 56 values
-  GreenLEDConfig(BoardIndex, Vec<u8>),
+  GreenLEDConfig(GreenLedConfigResponse),
 
   /// 8-bit key data for blue LED intensity. 112 bytes, lower and upper nibbles for 56 values
-  BlueLEDConfig(BoardIndex, Vec<u8>),
+  BlueLEDConfig(BlueLedConfigResponse),
 
   /// channel data for note configuration. 55 or 56 bytes
-  ChannelConfig(BoardIndex, Vec<MidiChannel>),
+  ChannelConfig(ChannelConfigResponse),
 
   /// 7-bit key data for note configuration. 55 or 56 bytes
-  NoteConfig(BoardIndex, Vec<u8>),
+  NoteConfig(NoteConfigResponse),
 
   /// 7-bit key type data for key configuration. 55 or 56 bytes
-  KeyTypeConfig(BoardIndex, Vec<u8>),
+  KeyTypeConfig(KeyTypeConfigResponse),
 
   /// 8-bit key data for maximums of adc threshold. 55 or 56 bytes
-  KeyMaxThresholds(BoardIndex, Vec<u8>),
+  KeyMaxThresholds(KeyThresholdsResponse),
 
   /// 8-bit key data for minimums of adc threshold. 55 or 56 bytes
-  KeyMinThresholds(BoardIndex, Vec<u8>),
+  KeyMinThresholds(KeyThresholdsResponse),
 
   /// 8-bit key data for maximums of adc threshold for aftertouch triggering. 55 or 56 bytes
-  AftertouchMaxThresholds(BoardIndex, Vec<u8>),
+  AftertouchMaxThresholds(KeyThresholdsResponse),
 
   /// key validity data for board, whether or not each key meets threshold specs
-  KeyValidity(BoardIndex, Vec<bool>),
+  KeyValidity(KeyValidityResponse),
 
   /// 7-bit fader type configuration of board, 56 bytes
-  FaderTypeConfig(BoardIndex, Vec<u8>),
+  FaderTypeConfig(FaderTypeConfigResponse),
 
   /// 7-bit velocity configuration of keyboard, 128 bytes
   OnOffVelocityConfig(Box<SysexTable>),
@@ -68,55 +70,25 @@ pub enum Response {
   VelocityIntervalConfig(Box<VelocityIntervalTable>),
 
   /// Serial ID of keyboard (6 bytes).
-  SerialId([u8; 6]),
+  SerialId(SerialIdentity),
 
   /// Firmware version number
-  FirmwareRevision {
-    major: u8,
-    minor: u8,
-    revision: u8,
-  },
+  FirmwareRevision(FirmwareVersion),
 
   /// All threshold values for a given board
-  BoardThresholds {
-    board_index: BoardIndex,
-    min_high: u8,
-    min_low: u8,
-    max: u8,
-    aftertouch: u8,
-    cc: u8,
-  },
+  BoardThresholds(BoardThresholdValues),
 
   /// The continuous controller and aftertouch sensitivity values for a given board
-  BoardSensitivity {
-    board_index: BoardIndex,
-    cc: u8,
-    aftertouch: u8,
-  },
+  BoardSensitivity(BoardSensitivityValues),
 
   /// The MIDI channel numbers for all peripherals
-  PeripheralChannels {
-    pitch_wheel: MidiChannel,
-    mod_wheel: MidiChannel,
-    expression: MidiChannel,
-    sustain: MidiChannel,
-  },
+  PeripheralChannels(PeripheralChannels),
 
   /// 12-bit expression pedal calibration status values, automatically sent every 100ms when in expression calibration mode
-  ExpressionCalibrationStatus {
-    min_bound: u16,
-    max_bound: u16,
-    valid: bool,
-  },
+  ExpressionCalibrationStatus(ExpressionCalibration),
 
   /// 12-bit pitch & mod wheel calibration status values, automatically sent every 100ms when in pitch/mod calibration mode
-  WheelCalibrationStatus {
-    center_pitch: u16,
-    min_pitch: u16,
-    max_pitch: u16,
-    min_mod: u16,
-    max_mod: u16,
-  },
+  WheelCalibrationStatus(WheelCalibration),
 
   /// Aftertouch trigger delay value for a given board
   AftertouchTriggerDelay(BoardIndex, u8),
@@ -126,42 +98,59 @@ pub enum Response {
 
   /// 12-bit expression pedal adc threshold, a 12-bit value
   ExpressionPedalThreshold(u16),
+
+  /// The unparsed response to a [Command::Raw](crate::commands::Command::Raw) message.
+  Raw(EncodedSysex),
 }
 
 impl Response {
+  /// Decodes a response using the latest firmware's payload layouts.
   pub fn from_sysex_message(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
+    Self::from_sysex_message_for(msg, &FirmwareSupport::default())
+  }
+
+  /// Decodes a response from a device with the given [FirmwareSupport].
+  pub fn from_sysex_message_for(
+    msg: &[u8],
+    firmware: &FirmwareSupport,
+  ) -> Result<Response, LumatoneMidiError> {
     use CommandId::*;
     let cmd_id = message_command_id(msg)?;
+    let color_encoding = firmware.color_encoding;
     match cmd_id {
       LumaPing => decode_ping(msg).map(|val| Response::Pong(val)),
 
-      GetRedLedConfig => unpack_octave_data_8bit(msg).map(|(b, d)| Response::RedLEDConfig(b, d)),
+      GetRedLedConfig => {
+        BoardKeyValues::from_led_message(msg, color_encoding).map(Response::RedLEDConfig)
+      }
 
       GetGreenLedConfig => {
-        unpack_octave_data_8bit(msg).map(|(b, d)| Response::GreenLEDConfig(b, d))
+        BoardKeyValues::from_led_message(msg, color_encoding).map(Response::GreenLEDConfig)
       }
 
-      GetBlueLedConfig => unpack_octave_data_8bit(msg).map(|(b, d)| Response::BlueLEDConfig(b, d)),
+      GetBlueLedConfig => {
+        BoardKeyValues::from_led_message(msg, color_encoding).map(Response::BlueLEDConfig)
+      }
 
-      GetChannelConfig => unpack_channel_config(msg),
+      GetChannelConfig => BoardKeyValues::from_channel_message(msg).map(Response::ChannelConfig),
 
-      GetNoteConfig => unpack_octave_data_7bit(msg).map(|(b, d)| Response::NoteConfig(b, d)),
+      GetNoteConfig => BoardKeyValues::from_7bit_message(msg).map(Response::NoteConfig),
 
-      GetKeytypeConfig => unpack_octave_data_7bit(msg).map(|(b, d)| Response::KeyTypeConfig(b, d)),
+      GetKeytypeConfig => BoardKeyValues::from_7bit_message(msg).map(Response::KeyTypeConfig),
 
-      GetMaxThreshold => {
-        unpack_octave_data_8bit(msg).map(|(b, d)| Response::KeyMaxThresholds(b, d))
-      }
+      GetMaxThreshold => BoardKeyValues::from_8bit_message(msg).map(Response::KeyMaxThresholds),
 
-      GetMinThreshold => {
-        unpack_octave_data_8bit(msg).map(|(b, d)| Response::KeyMinThresholds(b, d))
-      }
+      GetMinThreshold => BoardKeyValues::from_8bit_message(msg).map(Response::KeyMinThresholds),
 
       GetAftertouchMax => {
-        unpack_octave_data_8bit(msg).map(|(b, d)| Response::AftertouchMaxThresholds(b, d))
+        BoardKeyValues::from_8bit_message(msg).map(Response::AftertouchMaxThresholds)
       }
 
-      GetKeyValidity => unpack_key_validity(msg),
+      GetKeyValidity => BoardKeyValues::from_validity_message(msg).map(Response::KeyValidity),
+
+      GetFaderTypeConfiguration => {
+        BoardKeyValues::from_7bit_message(msg).map(Response::FaderTypeConfig)
+      }
 
       GetVelocityConfig => unpack_sysex_config_table(msg).map(Response::OnOffVelocityConfig),
 
@@ -173,19 +162,33 @@ impl Response {
 
       GetVelocityIntervals => unpack_velocity_intervals(msg),
 
-      GetSerialIdentity => unpack_serial_id(msg),
+      GetSerialIdentity => SerialIdentity::from_sysex_message(msg).map(Response::SerialId),
 
-      GetFirmwareRevision => unpack_firmware_revision(msg),
+      GetFirmwareRevision => {
+        FirmwareVersion::from_sysex_message(msg).map(Response::FirmwareRevision)
+      }
 
-      GetBoardThresholdValues => unpack_board_thresholds(msg),
+      GetBoardThresholdValues => {
+        BoardThresholdValues::from_sysex_message(msg).map(Response::BoardThresholds)
+      }
 
-      GetBoardSensitivityValues => unpack_board_sensitivity(msg),
+      GetBoardSensitivityValues => {
+        BoardSensitivityValues::from_sysex_message(msg).map(Response::BoardSensitivity)
+      }
 
-      GetPeripheralChannels => unpack_peripheral_channels(msg),
+      GetPeripheralChannels => {
+        PeripheralChannels::from_sysex_message(msg).map(Response::PeripheralChannels)
+      }
 
-      CalibrateExpressionPedal => unpack_expression_calibration_status(msg),
+      // Starting or stopping calibration is answered with a plain ACK, and the status
+      // messages sent while it runs have the same command id
+      CalibrateExpressionPedal if is_calibration_status(msg) => {
+        ExpressionCalibration::from_sysex_message(msg).map(Response::ExpressionCalibrationStatus)
+      }
 
-      CalibratePitchModWheel => unpack_wheel_calibration_status(msg),
+      CalibratePitchModWheel if is_calibration_status(msg) => {
+        WheelCalibration::from_sysex_message(msg).map(Response::WheelCalibrationStatus)
+      }
 
       GetAftertouchTriggerDelay => unpack_aftertouch_trigger_delay(msg),
 
@@ -205,69 +208,339 @@ impl Display for Response {
     match self {
       Ack(cmd_id) => write!(f, "Ack({cmd_id:?})"),
       Pong(val) => write!(f, "Pong({val})"),
-      RedLEDConfig(board, _) => write!(f, "RedLEDConfig({board}, <table...>)"),
-      GreenLEDConfig(board, _) => write!(f, "GreenLEDConfig({board}, <table..>)"),
-      BlueLEDConfig(board, _) => write!(f, "BlueLEDConfig({board}, <table..>)"),
-      ChannelConfig(board, _) => write!(f, "ChannelConfig({board}, <table..>)"),
-      NoteConfig(board, _) => write!(f, "NoteConfig({board}, <table..>)"),
-      KeyTypeConfig(board, _) => write!(f, "KeyTypeConfig({board}, <table..>)"),
-      KeyMaxThresholds(board, _) => write!(f, "KeyMaxThresholds({board}, <table..>)"),
-      KeyMinThresholds(board, _) => write!(f, "KeyMinThresholds({board}, <table..>)"),
-      AftertouchMaxThresholds(board, _) => write!(f, "AftertouchMaxThresholds({board}, <table..>)"),
-      KeyValidity(board, _) => write!(f, "KeyValidity({board}, <table..>)"),
-      FaderTypeConfig(board, _) => write!(f, "FaderTypeConfig({board}, <table..>)"),
+      RedLEDConfig(r) => write!(f, "RedLEDConfig({}, <table...>)", r.board_index),
+      GreenLEDConfig(r) => write!(f, "GreenLEDConfig({}, <table..>)", r.board_index),
+      BlueLEDConfig(r) => write!(f, "BlueLEDConfig({}, <table..>)", r.board_index),
+      ChannelConfig(r) => write!(f, "ChannelConfig({}, <table..>)", r.board_index),
+      NoteConfig(r) => write!(f, "NoteConfig({}, <table..>)", r.board_index),
+      KeyTypeConfig(r) => write!(f, "KeyTypeConfig({}, <table..>)", r.board_index),
+      KeyMaxThresholds(r) => write!(f, "KeyMaxThresholds({}, <table..>)", r.board_index),
+      KeyMinThresholds(r) => write!(f, "KeyMinThresholds({}, <table..>)", r.board_index),
+      AftertouchMaxThresholds(r) => {
+        write!(f, "AftertouchMaxThresholds({}, <table..>)", r.board_index)
+      }
+      KeyValidity(r) => write!(f, "KeyValidity({}, <table..>)", r.board_index),
+      FaderTypeConfig(r) => write!(f, "FaderTypeConfig({}, <table..>)", r.board_index),
       OnOffVelocityConfig(_) => write!(f, "OnOffVelocityConfig(<table...>)"),
       FaderConfig(_) => write!(f, "FaderConfig(<table...>)"),
       AftertouchConfig(_) => write!(f, "AftertouchConfig(<table...>)"),
       LumatouchConfig(_) => write!(f, "LumatouchConfig(<table...>)"),
       VelocityIntervalConfig(_) => write!(f, "VelocityIntervalConfig(<table...>)"),
-      SerialId(id) => write!(f, "SerialId({id:?})"),
-      FirmwareRevision {
-        major,
-        minor,
-        revision,
-      } => write!(f, "FirmwareRevision(\"{major}.{minor}.{revision}\")"),
-      BoardThresholds {
-        board_index,
-        min_high,
-        min_low,
-        max,
-        aftertouch,
-        cc,
-      } => todo!(),
-      BoardSensitivity {
-        board_index,
-        cc,
-        aftertouch,
-      } => todo!(),
-      PeripheralChannels {
-        pitch_wheel,
-        mod_wheel,
-        expression,
-        sustain,
-      } => todo!(),
-      ExpressionCalibrationStatus {
-        min_bound,
-        max_bound,
-        valid,
-      } => todo!(),
-      WheelCalibrationStatus {
-        center_pitch,
-        min_pitch,
-        max_pitch,
-        min_mod,
-        max_mod,
-      } => todo!(),
+      SerialId(id) => write!(f, "SerialId({id})"),
+      FirmwareRevision(version) => write!(f, "FirmwareRevision(\"{version}\")"),
+      BoardThresholds(t) => write!(f, "{t:?}"),
+      BoardSensitivity(s) => write!(f, "{s:?}"),
+      PeripheralChannels(c) => write!(f, "{c:?}"),
+      ExpressionCalibrationStatus(s) => write!(f, "{s:?}"),
+      WheelCalibrationStatus(s) => write!(f, "{s:?}"),
       AftertouchTriggerDelay(board, val) => write!(f, "AftertouchTriggerDelay({board}, {val})"),
       LumatouchNoteOffDelay(board, val) => write!(f, "LumatouchNoteOffDelay({board}, {val})"),
       ExpressionPedalThreshold(val) => write!(f, "ExpressionPedalThreshold({val})"),
+      Raw(msg) => write!(f, "Raw({})", to_hex_debug_str(msg)),
+    }
+  }
+}
+
+// region: Response payload types
+
+/// Per-key values read back from a single board, indexed by [LumatoneKeyIndex].
+///
+/// Older 55-key boards return 55 values instead of 56.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardKeyValues<T> {
+  pub board_index: BoardIndex,
+  pub values: Vec<T>,
+}
+
+impl<T> BoardKeyValues<T> {
+  /// Returns the value for the given key, or `None` if the board didn't send one.
+  pub fn get(&self, key_index: LumatoneKeyIndex) -> Option<&T> {
+    self.values.get(key_index.get() as usize)
+  }
+}
+
+pub type RedLedConfigResponse = BoardKeyValues<u8>;
+pub type GreenLedConfigResponse = BoardKeyValues<u8>;
+pub type BlueLedConfigResponse = BoardKeyValues<u8>;
+pub type ChannelConfigResponse = BoardKeyValues<MidiChannel>;
+pub type NoteConfigResponse = BoardKeyValues<u8>;
+pub type KeyTypeConfigResponse = BoardKeyValues<u8>;
+pub type KeyThresholdsResponse = BoardKeyValues<u8>;
+pub type KeyValidityResponse = BoardKeyValues<bool>;
+pub type FaderTypeConfigResponse = BoardKeyValues<u8>;
+
+impl BoardKeyValues<u8> {
+  /// Decodes a message with one 8-bit value per key, each sent as a pair of 4-bit nibbles.
+  pub fn from_8bit_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let msg = valid_lumatone_msg(msg)?;
+    let board_index = message_board_index(msg)?;
+    let payload = message_payload(msg)?;
+    Ok(BoardKeyValues {
+      board_index,
+      values: unpack_8bit(payload),
+    })
+  }
+
+  /// Decodes a message with one LED intensity per key. With [ColorEncoding::SevenBit],
+  /// values are scaled up to the 8-bit range.
+  pub fn from_led_message(msg: &[u8], encoding: ColorEncoding) -> Result<Self, LumatoneMidiError> {
+    match encoding {
+      ColorEncoding::Extended => Self::from_8bit_message(msg),
+      ColorEncoding::SevenBit => {
+        let mut values = Self::from_7bit_message(msg)?;
+        for v in values.values.iter_mut() {
+          *v <<= 1;
+        }
+        Ok(values)
+      }
+    }
+  }
+
+  /// Decodes a message with one 7-bit value per key.
+  pub fn from_7bit_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let msg = valid_lumatone_msg(msg)?;
+    let board_index = message_board_index(msg)?;
+    let payload = message_payload(msg)?;
+    Ok(BoardKeyValues {
+      board_index,
+      values: payload.to_vec(),
+    })
+  }
+}
+
+impl BoardKeyValues<MidiChannel> {
+  /// Decodes a message with one zero-indexed MIDI channel per key.
+  pub fn from_channel_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let msg = valid_lumatone_msg(msg)?;
+    let board_index = message_board_index(msg)?;
+    let payload = message_payload(msg)?;
+    let mut values = Vec::with_capacity(payload.len());
+    for byte in payload {
+      values.push(MidiChannel::try_from_zero_indexed(*byte)?);
+    }
+    Ok(BoardKeyValues {
+      board_index,
+      values,
+    })
+  }
+}
+
+impl BoardKeyValues<bool> {
+  /// Decodes a message with one validity flag per key.
+  pub fn from_validity_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let msg = valid_lumatone_msg(msg)?;
+    let board_index = message_board_index(msg)?;
+    let payload = message_payload(msg)?;
+    Ok(BoardKeyValues {
+      board_index,
+      values: payload.iter().map(|n| *n != 0).collect(),
+    })
+  }
+}
+
+/// The serial number of a Lumatone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SerialIdentity(pub [u8; 6]);
+
+impl SerialIdentity {
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    // TODO: the C++ driver has a check for msg[MSG_STATUS] == TEST_ECHO
+    // add that if it seems necessary.
+
+    // Also note that we're not handling early firmware versions that respond with an ACK but no serial number.
+
+    let payload = payload_with_len(msg, 6)?;
+    let serial: [u8; 6] = payload.try_into().unwrap();
+    Ok(SerialIdentity(serial))
+  }
+}
+
+impl Display for SerialIdentity {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for b in self.0 {
+      write!(f, "{b:02x}")?;
     }
+    Ok(())
+  }
+}
+
+/// A Lumatone firmware version number. Versions are ordered, so you can check for support
+/// with e.g. `version >= FirmwareVersion::new(1, 0, 13)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+  pub major: u8,
+  pub minor: u8,
+  pub revision: u8,
+}
+
+impl FirmwareVersion {
+  pub const fn new(major: u8, minor: u8, revision: u8) -> Self {
+    FirmwareVersion {
+      major,
+      minor,
+      revision,
+    }
+  }
+
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let payload = payload_with_len(msg, 3)?;
+    Ok(FirmwareVersion::new(payload[0], payload[1], payload[2]))
+  }
+}
+
+impl Display for FirmwareVersion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{}.{}", self.major, self.minor, self.revision)
+  }
+}
+
+/// All threshold values for a given board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardThresholdValues {
+  pub board_index: BoardIndex,
+  pub min_high: u8,
+  pub min_low: u8,
+  pub max: u8,
+  pub aftertouch: u8,
+  pub cc: u8,
+}
+
+impl BoardThresholdValues {
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let board_index = message_board_index(msg)?;
+    let data = unpack_8bit(payload_with_len(msg, 10)?);
+    Ok(BoardThresholdValues {
+      board_index,
+      min_high: data[0],
+      min_low: data[1],
+      max: data[2],
+      aftertouch: data[3],
+      cc: data[4],
+    })
+  }
+}
+
+/// The continuous controller and aftertouch sensitivity values for a given board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardSensitivityValues {
+  pub board_index: BoardIndex,
+  pub cc: u8,
+  pub aftertouch: u8,
+}
+
+impl BoardSensitivityValues {
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let board_index = message_board_index(msg)?;
+    let data = unpack_8bit(payload_with_len(msg, 4)?);
+    Ok(BoardSensitivityValues {
+      board_index,
+      cc: data[0],
+      aftertouch: data[1],
+    })
+  }
+}
+
+/// The MIDI channels used by the pitch & mod wheels and the pedals.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeripheralChannels {
+  pub pitch_wheel: MidiChannel,
+  pub mod_wheel: MidiChannel,
+  pub expression: MidiChannel,
+  pub sustain: MidiChannel,
+}
+
+impl PeripheralChannels {
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let data = payload_with_len(msg, 4)?;
+    Ok(PeripheralChannels {
+      pitch_wheel: MidiChannel::try_from_zero_indexed(data[0])?,
+      mod_wheel: MidiChannel::try_from_zero_indexed(data[1])?,
+      expression: MidiChannel::try_from_zero_indexed(data[2])?,
+      sustain: MidiChannel::try_from_zero_indexed(data[3])?,
+    })
+  }
+
+  /// The command that sets the device to use these channels.
+  pub fn to_command(&self) -> Command {
+    set_peripheral_channels(
+      self.pitch_wheel,
+      self.mod_wheel,
+      self.expression,
+      self.sustain,
+    )
+  }
+}
+
+/// The length of the payload of the status messages sent while calibrating the wheels or
+/// the expression pedal.
+const CALIBRATION_STATUS_LEN: usize = 15;
+
+/// Returns whether `msg` has room for calibration status values, rather than being the ACK
+/// to a command that starts or stops calibration.
+fn is_calibration_status(msg: &[u8]) -> bool {
+  message_payload(msg).is_ok_and(|payload| payload.len() >= CALIBRATION_STATUS_LEN)
+}
+
+/// 12-bit expression pedal calibration values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpressionCalibration {
+  pub min_bound: u16,
+  pub max_bound: u16,
+  pub valid: bool,
+}
+
+impl ExpressionCalibration {
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let payload = payload_with_len(msg, CALIBRATION_STATUS_LEN)?;
+
+    // the min and max bounds are encoded into the first six bytes of the payload
+    let bounds_data = unpack_12bit_from_4bit(&payload[0..6]);
+
+    // The C++ version looks incorrect to me... they have:
+    // ```
+    // valid = response.getSysExData()[PAYLOAD_INIT + 3];
+    // ```
+    // but the max bound is at [PAYLOAD_INIT + 3], since each 12bit value takes 3 bytes.
+    // I'm going to assume this is supposed to be PAYLOAD_INIT + 6
+    let valid = payload[6] != 0;
+    Ok(ExpressionCalibration {
+      min_bound: bounds_data[0],
+      max_bound: bounds_data[1],
+      valid,
+    })
   }
 }
 
+/// 12-bit pitch & mod wheel calibration values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WheelCalibration {
+  pub center_pitch: u16,
+  pub min_pitch: u16,
+  pub max_pitch: u16,
+  pub min_mod: u16,
+  pub max_mod: u16,
+}
+
+impl WheelCalibration {
+  pub fn from_sysex_message(msg: &[u8]) -> Result<Self, LumatoneMidiError> {
+    let payload = payload_with_len(msg, CALIBRATION_STATUS_LEN)?;
+    let data = unpack_12bit_from_4bit(payload);
+    Ok(WheelCalibration {
+      center_pitch: data[0],
+      min_pitch: data[1],
+      max_pitch: data[2],
+      min_mod: data[3],
+      max_mod: data[4],
+    })
+  }
+}
+
+// endregion
+
 fn message_board_index(msg: &[u8]) -> Result<BoardIndex, LumatoneMidiError> {
+  let msg = strip_sysex_markers(msg);
   ensure!(
-    msg.len() <= BOARD_IND,
+    msg.len() > BOARD_IND,
     LumatoneMidiError::MessageTooShort {
       expected: BOARD_IND + 1,
       actual: msg.len()
@@ -346,41 +619,6 @@ fn unpack_sysex_config_table(msg: &[u8]) -> Result<Box<SysexTable>, LumatoneMidi
   Ok(Box::new(table))
 }
 
-fn unpack_octave_data_8bit(msg: &[u8]) -> Result<(BoardIndex, Vec<u8>), LumatoneMidiError> {
-  let msg = valid_lumatone_msg(msg)?;
-  let board_index = message_board_index(msg)?;
-  let payload = message_payload(msg)?;
-  Ok((board_index, unpack_8bit(payload)))
-}
-
-fn unpack_octave_data_7bit(msg: &[u8]) -> Result<(BoardIndex, Vec<u8>), LumatoneMidiError> {
-  let msg = valid_lumatone_msg(msg)?;
-  let board_index = message_board_index(msg)?;
-  let payload = message_payload(msg)?;
-  Ok((board_index, payload.to_vec()))
-}
-
-fn unpack_channel_config(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let msg = valid_lumatone_msg(msg)?;
-  let board_index = message_board_index(msg)?;
-  let payload = message_payload(msg)?;
-  let mut channels = Vec::with_capacity(payload.len());
-  for byte in payload {
-    let ch = MidiChannel::try_from_zero_indexed(*byte)?;
-    channels.push(ch);
-  }
-  let response = Response::ChannelConfig(board_index, channels);
-  Ok(response)
-}
-
-fn unpack_key_validity(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let msg = valid_lumatone_msg(msg)?;
-  let board_index = message_board_index(msg)?;
-  let payload = message_payload(msg)?;
-  let bools = payload.iter().map(|n| *n != 0).collect();
-  Ok(Response::KeyValidity(board_index, bools))
-}
-
 fn unpack_velocity_intervals(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
   let payload = payload_with_len(msg, 254)?;
   let data = unpack_12bit_from_7bit(payload);
@@ -388,108 +626,9 @@ fn unpack_velocity_intervals(msg: &[u8]) -> Result<Response, LumatoneMidiError>
   Ok(Response::VelocityIntervalConfig(Box::new(table)))
 }
 
-fn unpack_serial_id(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  // TODO: the C++ driver has a check for msg[MSG_STATUS] == TEST_ECHO
-  // add that if it seems necessary.
-
-  // Also note that we're not handling early firmware versions that respond with an ACK but no serial number.
-
-  let payload = payload_with_len(msg, 6)?;
-  let serial: [u8; 6] = payload.try_into().unwrap();
-  Ok(Response::SerialId(serial))
-}
-
-fn unpack_firmware_revision(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 3)?;
-  Ok(Response::FirmwareRevision {
-    major: payload[0],
-    minor: payload[1],
-    revision: payload[2],
-  })
-}
-
-fn unpack_board_thresholds(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 10)?;
-  let (board_index, data) = unpack_octave_data_8bit(payload)?;
-  Ok(Response::BoardThresholds {
-    board_index,
-    min_high: data[0],
-    min_low: data[1],
-    max: data[2],
-    aftertouch: data[3],
-    cc: data[4],
-  })
-}
-
-fn unpack_board_sensitivity(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 4)?;
-  let (board_index, data) = unpack_octave_data_8bit(msg)?;
-  Ok(Response::BoardSensitivity {
-    board_index,
-    cc: data[0],
-    aftertouch: data[1],
-  })
-}
-
-fn unpack_peripheral_channels(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 4)?;
-  let (board_index, data) = unpack_octave_data_7bit(msg)?;
-
-  let pitch_wheel = MidiChannel::try_from_zero_indexed(data[0])?;
-  let mod_wheel = MidiChannel::try_from_zero_indexed(data[1])?;
-  let expression = MidiChannel::try_from_zero_indexed(data[2])?;
-  let sustain = MidiChannel::try_from_zero_indexed(data[3])?;
-
-  Ok(Response::PeripheralChannels {
-    pitch_wheel,
-    mod_wheel,
-    expression,
-    sustain,
-  })
-}
-
-fn unpack_expression_calibration_status(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 15)?;
-
-  // the min and max bounds are encoded into the first six bytes of the payload
-  let bounds_data = unpack_12bit_from_4bit(payload);
-  let min_bound = bounds_data[0];
-  let max_bound = bounds_data[1];
-
-  // The C++ version looks incorrect to me... they have:
-  // ```
-  // valid = response.getSysExData()[PAYLOAD_INIT + 3];
-  // ```
-  // but the max bound is at [PAYLOAD_INIT + 3], since each 12bit value takes 3 bytes.
-  // I'm going to assume this is supposed to be PAYLOAD_INIT + 6
-  let valid = payload[6] != 0;
-  Ok(Response::ExpressionCalibrationStatus {
-    min_bound,
-    max_bound,
-    valid,
-  })
-}
-
-fn unpack_wheel_calibration_status(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 15)?;
-  let data = unpack_12bit_from_4bit(payload);
-  let center_pitch = data[0];
-  let min_pitch = data[1];
-  let max_pitch = data[2];
-  let min_mod = data[3];
-  let max_mod = data[4];
-  Ok(Response::WheelCalibrationStatus {
-    center_pitch,
-    min_pitch,
-    max_pitch,
-    min_mod,
-    max_mod,
-  })
-}
-
 fn unpack_aftertouch_trigger_delay(msg: &[u8]) -> Result<Response, LumatoneMidiError> {
-  let payload = payload_with_len(msg, 2)?;
-  let (board_index, data) = unpack_octave_data_8bit(payload)?;
+  let board_index = message_board_index(msg)?;
+  let data = unpack_8bit(payload_with_len(msg, 2)?);
   Ok(Response::AftertouchTriggerDelay(board_index, data[0]))
 }
 
@@ -508,11 +647,12 @@ fn unpack_expression_threshold(msg: &[u8]) -> Result<Response, LumatoneMidiError
   Ok(Response::ExpressionPedalThreshold(threshold))
 }
 
-/// Generic unpacking of 8-bit data from a SysEx message payload
+/// Generic unpacking of 8-bit data from a SysEx message payload, where each value is
+/// sent as two 4-bit nibbles (high nibble first).
 fn unpack_8bit(payload: &[u8]) -> Vec<u8> {
   payload
     .chunks_exact(2)
-    .flat_map(|c| vec![c[0] << 4, c[1]])
+    .map(|c| (c[0] << 4) | (c[1] & 0xf))
     .collect()
 }
 
@@ -533,3 +673,193 @@ fn unpack_12bit_from_4bit(payload: &[u8]) -> Vec<u16> {
 }
 
 // endregion
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sysex::create_sysex;
+
+  /// Builds a response message with an ACK status byte and the given payload.
+  fn response_msg(board_index: BoardIndex, cmd: CommandId, payload: &[u8]) -> EncodedSysex {
+    let mut data = vec![0x01];
+    data.extend(payload);
+    create_sysex(board_index, cmd, data)
+  }
+
+  #[test]
+  fn test_decode_8bit_key_values() {
+    let payload: Vec<u8> = (0..56u8).flat_map(|n| [n >> 4, n & 0xf]).collect();
+    let msg = response_msg(BoardIndex::Octave2, CommandId::GetRedLedConfig, &payload);
+    match Response::from_sysex_message(&msg).unwrap() {
+      Response::RedLEDConfig(r) => {
+        assert_eq!(r.board_index, BoardIndex::Octave2);
+        assert_eq!(r.values.len(), 56);
+        assert_eq!(r.get(LumatoneKeyIndex::unchecked(42)), Some(&42));
+      }
+      r => panic!("unexpected response: {r:?}"),
+    }
+  }
+
+  #[test]
+  fn test_command_decodes_only_its_own_response() {
+    use crate::commands::Command;
+
+    let payload: Vec<u8> = (0..56u8).collect();
+    let msg = response_msg(BoardIndex::Octave3, CommandId::GetNoteConfig, &payload);
+    let firmware = FirmwareSupport::default();
+    match Command::GetNoteConfig(BoardIndex::Octave3).decode_response(&msg, &firmware) {
+      Ok(Response::NoteConfig(r)) => assert_eq!(r.get(LumatoneKeyIndex::unchecked(7)), Some(&7)),
+      r => panic!("unexpected response: {r:?}"),
+    }
+    assert!(Command::GetKeyTypeConfig(BoardIndex::Octave3)
+      .decode_response(&msg, &firmware)
+      .is_err());
+    assert!(matches!(
+      Command::Raw(msg.clone()).decode_response(&msg, &firmware),
+      Ok(Response::Raw(_))
+    ));
+  }
+
+  #[test]
+  fn test_decode_7bit_led_values() {
+    let payload: Vec<u8> = (0..56u8).collect();
+    let msg = response_msg(BoardIndex::Octave1, CommandId::GetBlueLedConfig, &payload);
+    let old_firmware = FirmwareSupport::for_version(&FirmwareVersion::new(1, 0, 8));
+    match Response::from_sysex_message_for(&msg, &old_firmware).unwrap() {
+      Response::BlueLEDConfig(r) => {
+        assert_eq!(r.values.len(), 56);
+        assert_eq!(r.get(LumatoneKeyIndex::unchecked(42)), Some(&84));
+      }
+      r => panic!("unexpected response: {r:?}"),
+    }
+  }
+
+  #[test]
+  fn test_decode_channel_config() {
+    let msg = response_msg(
+      BoardIndex::Octave1,
+      CommandId::GetChannelConfig,
+      &[0, 15, 3],
+    );
+    let r = BoardKeyValues::from_channel_message(&msg).unwrap();
+    assert_eq!(
+      r.values,
+      vec![
+        MidiChannel::unchecked(1),
+        MidiChannel::unchecked(16),
+        MidiChannel::unchecked(4)
+      ]
+    );
+  }
+
+  #[test]
+  fn test_decode_board_thresholds() {
+    let payload = [0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0x8, 0x9, 0xa];
+    let msg = response_msg(
+      BoardIndex::Octave3,
+      CommandId::GetBoardThresholdValues,
+      &payload,
+    );
+    let t = BoardThresholdValues::from_sysex_message(&msg).unwrap();
+    assert_eq!(
+      t,
+      BoardThresholdValues {
+        board_index: BoardIndex::Octave3,
+        min_high: 0x12,
+        min_low: 0x34,
+        max: 0x56,
+        aftertouch: 0x78,
+        cc: 0x9a,
+      }
+    );
+  }
+
+  #[test]
+  fn test_decode_serial_and_firmware() {
+    let msg = response_msg(
+      BoardIndex::Server,
+      CommandId::GetSerialIdentity,
+      &[0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f],
+    );
+    let serial = SerialIdentity::from_sysex_message(&msg).unwrap();
+    assert_eq!(serial.to_string(), "0a0b0c0d0e0f");
+
+    let msg = response_msg(
+      BoardIndex::Server,
+      CommandId::GetFirmwareRevision,
+      &[1, 0, 15],
+    );
+    let version = FirmwareVersion::from_sysex_message(&msg).unwrap();
+    assert_eq!(version, FirmwareVersion::new(1, 0, 15));
+    assert!(version > FirmwareVersion::new(1, 0, 9));
+    assert_eq!(version.to_string(), "1.0.15");
+  }
+
+  #[test]
+  fn test_decode_aftertouch_trigger_delay() {
+    let msg = response_msg(
+      BoardIndex::Octave5,
+      CommandId::GetAftertouchTriggerDelay,
+      &[0x7, 0xf],
+    );
+    match Response::from_sysex_message(&msg).unwrap() {
+      Response::AftertouchTriggerDelay(board, delay) => {
+        assert_eq!(board, BoardIndex::Octave5);
+        assert_eq!(delay, 0x7f);
+      }
+      r => panic!("unexpected response: {r:?}"),
+    }
+  }
+
+  #[test]
+  fn test_decode_wheel_calibration() {
+    let ack = response_msg(BoardIndex::Server, CommandId::CalibratePitchModWheel, &[1]);
+    match Response::from_sysex_message(&ack).unwrap() {
+      Response::Ack(CommandId::CalibratePitchModWheel) => {}
+      r => panic!("unexpected response: {r:?}"),
+    }
+
+    let payload = [
+      0x8, 0x0, 0x0, 0x0, 0x1, 0x0, 0xf, 0xf, 0x0, 0x0, 0x0, 0x2, 0xf, 0xe, 0x0,
+    ];
+    let status = response_msg(
+      BoardIndex::Server,
+      CommandId::CalibratePitchModWheel,
+      &payload,
+    );
+    match Response::from_sysex_message(&status).unwrap() {
+      Response::WheelCalibrationStatus(c) => {
+        assert_eq!(c.center_pitch, 0x800);
+        assert_eq!(c.min_pitch, 0x010);
+        assert_eq!(c.max_pitch, 0xff0);
+        assert_eq!(c.min_mod, 0x002);
+        assert_eq!(c.max_mod, 0xfe0);
+      }
+      r => panic!("unexpected response: {r:?}"),
+    }
+  }
+
+  #[test]
+  fn test_velocity_intervals_round_trip() {
+    use crate::{commands::set_velocity_intervals, sysex::MSG_STATUS};
+
+    let mut table: VelocityIntervalTable = [0; 127];
+    for (i, v) in table.iter_mut().enumerate() {
+      *v = (i as u16 * 32).min(0xfff);
+    }
+    // outgoing messages have no status byte, so the payload starts where the status would be
+    let msg = set_velocity_intervals(table).to_sysex_message();
+    let payload = &strip_sysex_markers(&msg)[MSG_STATUS..];
+    let msg = response_msg(BoardIndex::Server, CommandId::GetVelocityIntervals, payload);
+    match Response::from_sysex_message(&msg).unwrap() {
+      Response::VelocityIntervalConfig(t) => assert_eq!(*t, table),
+      r => panic!("unexpected response: {r:?}"),
+    }
+  }
+
+  #[test]
+  fn test_decode_payload_too_short() {
+    let msg = response_msg(BoardIndex::Server, CommandId::GetSerialIdentity, &[1, 2]);
+    assert!(Response::from_sysex_message(&msg).is_err());
+  }
+}
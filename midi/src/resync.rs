@@ -0,0 +1,176 @@
+//! Detects when the device resets (e.g. after a power cycle) and re-applies the
+//! configuration stored in the [ConfigMirror].
+//!
+//! The watchdog pings the device at a fixed interval. A failed ping followed by a
+//! successful one is treated as a reset: the mirrored commands are sent again, and
+//! [ResyncEvent]s are broadcast so the application knows what happened. The same happens
+//! after the driver reconnects, e.g. when the host wakes from sleep (see [crate::suspend]):
+//! the device is pinged straight away, and the configuration is re-sent once it responds.
+//!
+//! Start the watchdog with [Lumatone::start_resync_watchdog](crate::controller::Lumatone::start_resync_watchdog).
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::sync::broadcast;
+
+use super::{
+  commands::ping, driver::MidiDriver, mirror::ConfigMirror, responses::Response,
+  shutdown::CancellationToken, suspend::ConnectionEvent,
+};
+
+pub const DEFAULT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many [ResyncEvent]s a slow receiver can fall behind before the oldest are dropped.
+pub(crate) const RESYNC_EVENTS_BUFFER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResyncEvent {
+  /// A ping failed. The device may be disconnected or restarting.
+  DeviceUnresponsive,
+
+  /// The device is responding again, and the mirrored configuration is being re-sent.
+  ResyncStarted { commands: usize },
+
+  /// All mirrored commands were re-sent.
+  ResyncCompleted { commands: usize },
+
+  /// Re-sending the configuration failed. It will be tried again after the next
+  /// successful ping.
+  ResyncFailed(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PingOutcome {
+  Unchanged,
+  WentUnresponsive,
+  Recovered,
+}
+
+/// Tracks whether the device is responding to pings.
+#[derive(Debug, Default)]
+pub(crate) struct ResetDetector {
+  unresponsive: bool,
+}
+
+impl ResetDetector {
+  pub(crate) fn ping_result(&mut self, ok: bool) -> PingOutcome {
+    let was_unresponsive = self.unresponsive;
+    self.unresponsive = !ok;
+    match (was_unresponsive, ok) {
+      (false, false) => PingOutcome::WentUnresponsive,
+      (true, true) => PingOutcome::Recovered,
+      _ => PingOutcome::Unchanged,
+    }
+  }
+
+  /// Makes the next successful ping count as a recovery, e.g. after a failed resync.
+  pub(crate) fn mark_unresponsive(&mut self) {
+    self.unresponsive = true;
+  }
+}
+
+/// Runs until `shutdown` is cancelled.
+pub(crate) async fn run_watchdog(
+  driver: MidiDriver,
+  mirror: Arc<Mutex<ConfigMirror>>,
+  events: broadcast::Sender<ResyncEvent>,
+  interval: Duration,
+  shutdown: CancellationToken,
+) {
+  let mut detector = ResetDetector::default();
+  let mut ticker = tokio::time::interval(interval);
+  let mut ping_value: u32 = 0;
+  let mut connection = match driver.subscribe_connection() {
+    Ok(connection) => connection,
+    Err(err) => {
+      warn!("not starting the resync watchdog: {err:?}");
+      return;
+    }
+  };
+
+  loop {
+    tokio::select! {
+      _ = shutdown.cancelled() => break,
+      event = connection.recv() => match event {
+        // the device may have reset while the connection was down, so check it now
+        Ok(ConnectionEvent::Reconnected) => detector.mark_unresponsive(),
+        Err(broadcast::error::RecvError::Closed) => break,
+        _ => continue,
+      },
+      _ = ticker.tick() => {}
+    }
+
+    ping_value = ping_value.wrapping_add(1) & 0xfffffff;
+    let ok = match driver.send(ping(ping_value)).await {
+      Ok(Response::Pong(_)) => true,
+      Ok(res) => {
+        warn!("unexpected response to ping: {res}");
+        false
+      }
+      Err(err) => {
+        debug!("ping failed: {err:?}");
+        false
+      }
+    };
+
+    match detector.ping_result(ok) {
+      PingOutcome::Unchanged => {}
+
+      PingOutcome::WentUnresponsive => {
+        info!("device stopped responding to pings");
+        let _ = events.send(ResyncEvent::DeviceUnresponsive);
+      }
+
+      PingOutcome::Recovered => {
+        let commands = mirror.lock().unwrap().commands();
+        info!(
+          "device responding again, re-sending {} commands",
+          commands.len()
+        );
+        let count = commands.len();
+        let _ = events.send(ResyncEvent::ResyncStarted { commands: count });
+
+        let mut failure = None;
+        for command in commands {
+          if let Err(err) = driver.send(command).await {
+            failure = Some(err);
+            break;
+          }
+        }
+
+        match failure {
+          None => {
+            let _ = events.send(ResyncEvent::ResyncCompleted { commands: count });
+          }
+          Some(err) => {
+            warn!("resync failed: {err:?}");
+            detector.mark_unresponsive();
+            let _ = events.send(ResyncEvent::ResyncFailed(err.to_string()));
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reset_detector() {
+    let mut detector = ResetDetector::default();
+    assert_eq!(detector.ping_result(true), PingOutcome::Unchanged);
+    assert_eq!(detector.ping_result(false), PingOutcome::WentUnresponsive);
+    assert_eq!(detector.ping_result(false), PingOutcome::Unchanged);
+    assert_eq!(detector.ping_result(true), PingOutcome::Recovered);
+    assert_eq!(detector.ping_result(true), PingOutcome::Unchanged);
+
+    detector.mark_unresponsive();
+    assert_eq!(detector.ping_result(true), PingOutcome::Recovered);
+  }
+}
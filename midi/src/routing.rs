@@ -0,0 +1,94 @@
+//! A routing matrix for sending different parts of the keyboard to different MIDI outputs,
+//! e.g. the left-hand zone to a bass synth and the right-hand zone to a DAW.
+//!
+//! Unlike the other [zones](crate::zones) used by the note proxy, routes match the
+//! outgoing message, after the proxy's [NoteMapping](crate::proxy::NoteMapping) has been
+//! applied. That way a note-off always goes to the same outputs as its note-on.
+//!
+//! Note messages (note on/off and polyphonic aftertouch) go to every route whose zone
+//! contains the note. Other channel messages go to every route whose zone includes their
+//! channel. Messages that don't match any route are dropped.
+
+use super::{events::ChannelMessage, zones::Zone};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+  pub zone: Zone,
+
+  /// The name of the output to send to.
+  pub output: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingMatrix {
+  pub routes: Vec<Route>,
+}
+
+impl RoutingMatrix {
+  pub fn new(routes: Vec<Route>) -> Self {
+    RoutingMatrix { routes }
+  }
+
+  /// Adds a route sending `zone` to the output named `output`.
+  pub fn route<S: Into<String>>(mut self, zone: Zone, output: S) -> Self {
+    self.routes.push(Route {
+      zone,
+      output: output.into(),
+    });
+    self
+  }
+
+  /// Returns the names of the outputs `msg` should be sent to, without duplicates.
+  pub fn destinations(&self, msg: &ChannelMessage) -> Vec<&str> {
+    use ChannelMessage::*;
+    let note = match *msg {
+      NoteOn { note, .. } | NoteOff { note, .. } | PolyAftertouch { note, .. } => Some(note),
+      _ => None,
+    };
+    let channel = msg.channel();
+
+    let mut outputs: Vec<&str> = vec![];
+    for route in &self.routes {
+      let matches = match note {
+        Some(note) => route.zone.contains(channel, note),
+        None => route.zone.channel.is_none_or(|c| c == channel),
+      };
+      if matches && !outputs.contains(&route.output.as_str()) {
+        outputs.push(&route.output);
+      }
+    }
+    outputs
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::constants::MidiChannel;
+
+  #[test]
+  fn test_destinations() {
+    let ch1 = MidiChannel::unchecked(1);
+    let ch2 = MidiChannel::unchecked(2);
+    let matrix = RoutingMatrix::default()
+      .route(Zone::notes(0, 59), "bass")
+      .route(Zone::notes(60, 127), "daw")
+      .route(Zone::channel(ch2), "daw");
+
+    let note_on = |channel, note| ChannelMessage::NoteOn {
+      channel,
+      note,
+      velocity: 100,
+    };
+    assert_eq!(matrix.destinations(&note_on(ch1, 40)), vec!["bass"]);
+    assert_eq!(matrix.destinations(&note_on(ch1, 72)), vec!["daw"]);
+    assert_eq!(matrix.destinations(&note_on(ch2, 72)), vec!["daw"]);
+
+    let bend = |channel| ChannelMessage::PitchBend {
+      channel,
+      value: 0x2000,
+    };
+    assert_eq!(matrix.destinations(&bend(ch1)), vec!["bass", "daw"]);
+    assert!(RoutingMatrix::default().destinations(&bend(ch1)).is_empty());
+  }
+}
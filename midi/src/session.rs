@@ -0,0 +1,403 @@
+//! Recordings of what was played on the device, as timestamped [ChannelMessage]s, and a
+//! [Replay] schedule for playing part of a recording back, e.g. on the key LEDs.
+//!
+//! Sessions are saved as text, one message per line: the time in milliseconds since the
+//! start of the recording, then the message bytes in hex. Lines starting with `#` are
+//! comments.
+//!
+//! ```text
+//! # C major triad
+//! 0 90 3c 64
+//! 10 90 40 60
+//! 480 80 3c 00
+//! 490 80 40 00
+//! ```
+//!
+//! With the `driver` feature, [SessionRecorder::run] records what's played on a connected
+//! device.
+
+use std::{
+  collections::HashMap,
+  fmt::Display,
+  ops::Range,
+  path::Path,
+  str::FromStr,
+  time::{Duration, Instant},
+};
+
+use super::{
+  constants::MidiChannel, error::LumatoneMidiError, events::ChannelMessage, timing::TimedMessage,
+};
+
+#[cfg(feature = "driver")]
+use super::{driver::MidiDriver, shutdown::CancellationToken};
+#[cfg(feature = "driver")]
+use log::warn;
+#[cfg(feature = "driver")]
+use tokio::sync::broadcast::error::RecvError;
+
+use error_stack::{bail, IntoReport, Result, ResultExt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionEvent {
+  /// The time since the start of the session.
+  pub time: Duration,
+  pub message: ChannelMessage,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Session {
+  events: Vec<SessionEvent>,
+}
+
+impl Session {
+  pub fn new() -> Self {
+    Session::default()
+  }
+
+  /// Adds `message` at `time`, after any other messages at the same time.
+  pub fn push(&mut self, time: Duration, message: ChannelMessage) {
+    let index = self.events.partition_point(|e| e.time <= time);
+    self.events.insert(index, SessionEvent { time, message });
+  }
+
+  /// The session's messages, in time order.
+  pub fn events(&self) -> &[SessionEvent] {
+    &self.events
+  }
+
+  pub fn len(&self) -> usize {
+    self.events.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.events.is_empty()
+  }
+
+  /// The time of the last message.
+  pub fn duration(&self) -> Duration {
+    self.events.last().map_or(Duration::ZERO, |e| e.time)
+  }
+
+  /// Returns the part of the session within `range`, with times relative to its start.
+  ///
+  /// Notes held when the range starts are turned on at its start, and notes still held when
+  /// it ends are turned off at its end, so every note in the excerpt is released.
+  pub fn excerpt(&self, range: Range<Duration>) -> Session {
+    let mut held: HashMap<(MidiChannel, u8), u8> = HashMap::new();
+    let before = self.events.partition_point(|e| e.time < range.start);
+    for event in &self.events[..before] {
+      track_held(&mut held, &event.message);
+    }
+
+    let mut excerpt = Session::new();
+    for (&(channel, note), &velocity) in &held {
+      let message = ChannelMessage::NoteOn {
+        channel,
+        note,
+        velocity,
+      };
+      excerpt.push(Duration::ZERO, message);
+    }
+    for event in self.events[before..]
+      .iter()
+      .take_while(|e| e.time < range.end)
+    {
+      track_held(&mut held, &event.message);
+      excerpt.push(event.time - range.start, event.message);
+    }
+
+    let end = range.end.saturating_sub(range.start);
+    for ((channel, note), _) in held {
+      let message = ChannelMessage::NoteOff {
+        channel,
+        note,
+        velocity: 0,
+      };
+      excerpt.push(end, message);
+    }
+    excerpt
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Session, LumatoneMidiError> {
+    let path = path.as_ref();
+    std::fs::read_to_string(path)
+      .report()
+      .change_context(LumatoneMidiError::SessionFileError(
+        path.display().to_string(),
+      ))?
+      .parse()
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), LumatoneMidiError> {
+    let path = path.as_ref();
+    std::fs::write(path, self.to_string())
+      .report()
+      .change_context(LumatoneMidiError::SessionFileError(
+        path.display().to_string(),
+      ))
+  }
+}
+
+/// Updates the notes held in `held` (with their velocities) for `message`.
+fn track_held(held: &mut HashMap<(MidiChannel, u8), u8>, message: &ChannelMessage) {
+  match *message {
+    ChannelMessage::NoteOn {
+      channel,
+      note,
+      velocity,
+    } => {
+      held.insert((channel, note), velocity);
+    }
+    ChannelMessage::NoteOff { channel, note, .. } => {
+      held.remove(&(channel, note));
+    }
+    _ => {}
+  }
+}
+
+impl Display for Session {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    for event in &self.events {
+      let bytes: Vec<String> = event
+        .message
+        .to_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+      writeln!(f, "{} {}", event.time.as_millis(), bytes.join(" "))?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Session {
+  type Err = error_stack::Report<LumatoneMidiError>;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let mut session = Session::new();
+    for (n, line) in s.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let invalid = || LumatoneMidiError::InvalidSession(format!("invalid line {}", n + 1));
+
+      let mut parts = line.split_whitespace();
+      let millis: u64 = match parts.next().map(str::parse) {
+        Some(Ok(ms)) => ms,
+        _ => bail!(invalid()),
+      };
+      let bytes = parts
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .report()
+        .change_context_lazy(invalid)?;
+      let message = ChannelMessage::from_bytes(&bytes).change_context_lazy(invalid)?;
+      session.push(Duration::from_millis(millis), message);
+    }
+    Ok(session)
+  }
+}
+
+/// Records messages into a [Session], timestamped from when the recorder was created.
+#[derive(Debug, Clone)]
+pub struct SessionRecorder {
+  start: Instant,
+  session: Session,
+}
+
+impl SessionRecorder {
+  pub fn new() -> Self {
+    SessionRecorder {
+      start: Instant::now(),
+      session: Session::new(),
+    }
+  }
+
+  /// Records `message` at the current time.
+  pub fn record(&mut self, message: ChannelMessage) {
+    self.session.push(self.start.elapsed(), message);
+  }
+
+  /// Records `message` at the time it was played, allowing `latency` for it to get from the
+  /// device to the input callback (see [crate::timing]). Unlike [SessionRecorder::record],
+  /// the time doesn't depend on how long the message waited to be handled.
+  pub fn record_timed(&mut self, message: &TimedMessage, latency: Duration) {
+    let time = message
+      .played_at(latency)
+      .saturating_duration_since(self.start);
+    self.session.push(time, message.message);
+  }
+
+  pub fn finish(self) -> Session {
+    self.session
+  }
+}
+
+#[cfg(feature = "driver")]
+impl SessionRecorder {
+  /// Records the messages played on the device until `stop` is cancelled or the connection
+  /// closes, each at the time it was played (see [SessionRecorder::record_timed]).
+  ///
+  /// Fails if the driver's transport doesn't time its events.
+  pub async fn run(
+    &mut self,
+    driver: &MidiDriver,
+    stop: &CancellationToken,
+  ) -> Result<(), LumatoneMidiError> {
+    let mut events = driver.subscribe_timed_events()?;
+    loop {
+      tokio::select! {
+        _ = stop.cancelled() => return Ok(()),
+        res = events.recv() => match res {
+          Ok(timed) => self.record_timed(&timed, driver.input_latency()),
+          Err(RecvError::Lagged(n)) => {
+            warn!("session recorder missed {n} events");
+            driver.count_missed(n);
+          }
+          Err(RecvError::Closed) => return Ok(()),
+        },
+      }
+    }
+  }
+}
+
+impl Default for SessionRecorder {
+  fn default() -> Self {
+    SessionRecorder::new()
+  }
+}
+
+/// Plays back part of a [Session] at a different speed, optionally looping.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+  session: Session,
+  pub range: Range<Duration>,
+
+  /// How fast to play, e.g. 0.5 for half speed.
+  pub speed: f32,
+  pub looping: bool,
+}
+
+impl Replay {
+  /// Plays the whole session once, at normal speed.
+  pub fn new(session: Session) -> Self {
+    let range = Duration::ZERO..session.duration() + Duration::from_millis(1);
+    Replay {
+      session,
+      range,
+      speed: 1.0,
+      looping: false,
+    }
+  }
+
+  pub fn with_range(mut self, range: Range<Duration>) -> Self {
+    self.range = range;
+    self
+  }
+
+  pub fn with_speed(mut self, speed: f32) -> Self {
+    self.speed = speed;
+    self
+  }
+
+  pub fn with_looping(mut self, looping: bool) -> Self {
+    self.looping = looping;
+    self
+  }
+
+  /// The length of one pass through the range, at the replay's speed.
+  pub fn pass_length(&self) -> Duration {
+    let range = self.range.end.saturating_sub(self.range.start);
+    range.div_f32(self.speed.max(f32::EPSILON))
+  }
+
+  /// Returns the messages for one pass through the range, with times from the start of the
+  /// pass, adjusted for the replay's speed. Every note is released by the end of the pass.
+  pub fn schedule(&self) -> Vec<SessionEvent> {
+    let speed = self.speed.max(f32::EPSILON);
+    self
+      .session
+      .excerpt(self.range.clone())
+      .events
+      .into_iter()
+      .map(|e| SessionEvent {
+        time: e.time.div_f32(speed),
+        message: e.message,
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn note_on(note: u8) -> ChannelMessage {
+    ChannelMessage::NoteOn {
+      channel: MidiChannel::default(),
+      note,
+      velocity: 100,
+    }
+  }
+
+  fn note_off(note: u8) -> ChannelMessage {
+    ChannelMessage::NoteOff {
+      channel: MidiChannel::default(),
+      note,
+      velocity: 0,
+    }
+  }
+
+  #[test]
+  fn test_replay_excerpt_releases_held_notes() {
+    let ms = Duration::from_millis;
+    let mut session = Session::new();
+    session.push(ms(0), note_on(60));
+    session.push(ms(500), note_on(64));
+    session.push(ms(1000), note_off(60));
+    session.push(ms(1500), note_off(64));
+
+    let parsed: Session = session.to_string().parse().unwrap();
+    assert_eq!(parsed, session);
+
+    let replay = Replay::new(session)
+      .with_range(ms(250)..ms(1250))
+      .with_speed(2.0);
+    assert_eq!(replay.pass_length(), ms(500));
+    let schedule: Vec<(Duration, ChannelMessage)> = replay
+      .schedule()
+      .into_iter()
+      .map(|e| (e.time, e.message))
+      .collect();
+    assert_eq!(
+      schedule,
+      vec![
+        (ms(0), note_on(60)),
+        (ms(125), note_on(64)),
+        (ms(375), note_off(60)),
+        (ms(500), note_off(64)),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_record_timed() {
+    let ms = Duration::from_millis;
+    let mut recorder = SessionRecorder::new();
+    let start = recorder.start;
+    recorder.record_timed(
+      &TimedMessage::new(note_on(60), start + ms(105), ms(0)),
+      ms(5),
+    );
+    // played before the recorder started
+    recorder.record_timed(
+      &TimedMessage::new(note_off(60), start + ms(2), ms(0)),
+      ms(5),
+    );
+    let session = recorder.finish();
+    assert_eq!(session.events()[0].time, ms(0));
+    assert_eq!(session.events()[1].time, ms(100));
+  }
+}
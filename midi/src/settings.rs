@@ -0,0 +1,141 @@
+//! Typed access to the device's global on/off settings.
+//!
+//! The Lumatone has a handful of settings that apply to the whole keyboard and are either
+//! on or off, listed in [Toggle]. [Lumatone::settings] returns a [DeviceSettings], which
+//! sends them through [Lumatone::send], so they're re-applied after a reset like any other
+//! configuration (see [crate::mirror]):
+//!
+//! ```ignore
+//! let settings = lumatone.settings();
+//! settings.set_light_on_keystrokes(true).await?;
+//! settings.set_invert_sustain(false).await?;
+//! ```
+//!
+//! The device has no commands for reading these settings back, so [DeviceSettings::get]
+//! only knows a setting's value once it's been sent.
+
+use super::{
+  commands::{
+    invert_foot_controller, invert_sustain_pedal, set_aftertouch_enabled, set_light_on_keystrokes,
+    Command,
+  },
+  controller::Lumatone,
+  error::LumatoneMidiError,
+};
+
+use error_stack::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Toggle {
+  /// Keys light up while they're pressed.
+  LightOnKeystrokes,
+
+  /// The sustain pedal's polarity is reversed, for pedals that are closed when released.
+  InvertSustain,
+
+  /// The expression pedal's range is reversed.
+  InvertFootController,
+
+  /// Keys send polyphonic aftertouch.
+  AftertouchEnabled,
+}
+
+impl Toggle {
+  pub const ALL: [Toggle; 4] = [
+    Toggle::LightOnKeystrokes,
+    Toggle::InvertSustain,
+    Toggle::InvertFootController,
+    Toggle::AftertouchEnabled,
+  ];
+
+  /// The command that turns the setting on or off.
+  pub fn command(&self, on: bool) -> Command {
+    match self {
+      Toggle::LightOnKeystrokes => set_light_on_keystrokes(on),
+      Toggle::InvertSustain => invert_sustain_pedal(on),
+      Toggle::InvertFootController => invert_foot_controller(on),
+      Toggle::AftertouchEnabled => set_aftertouch_enabled(on),
+    }
+  }
+
+  /// Returns the setting `command` changes, and whether it turns it on.
+  pub fn from_command(command: &Command) -> Option<(Toggle, bool)> {
+    match command {
+      Command::SetLightOnKeystrokes(on) => Some((Toggle::LightOnKeystrokes, *on)),
+      Command::InvertSustainPedal(on) => Some((Toggle::InvertSustain, *on)),
+      Command::InvertFootController(on) => Some((Toggle::InvertFootController, *on)),
+      Command::SetAftertouchEnabled(on) => Some((Toggle::AftertouchEnabled, *on)),
+      _ => None,
+    }
+  }
+}
+
+/// Changes a connected device's [Toggle]s. See the [module docs](self).
+pub struct DeviceSettings<'a> {
+  lumatone: &'a Lumatone,
+}
+
+impl<'a> DeviceSettings<'a> {
+  pub async fn set(&self, toggle: Toggle, on: bool) -> Result<(), LumatoneMidiError> {
+    self.lumatone.send(toggle.command(on)).await?;
+    Ok(())
+  }
+
+  /// The value the setting was last given through this connection, if any.
+  pub fn get(&self, toggle: Toggle) -> Option<bool> {
+    self
+      .lumatone
+      .mirrored_config()
+      .iter()
+      .filter_map(Toggle::from_command)
+      .find(|(t, _)| *t == toggle)
+      .map(|(_, on)| on)
+  }
+
+  pub async fn set_light_on_keystrokes(&self, on: bool) -> Result<(), LumatoneMidiError> {
+    self.set(Toggle::LightOnKeystrokes, on).await
+  }
+
+  pub async fn set_invert_sustain(&self, invert: bool) -> Result<(), LumatoneMidiError> {
+    self.set(Toggle::InvertSustain, invert).await
+  }
+
+  pub async fn set_invert_foot_controller(&self, invert: bool) -> Result<(), LumatoneMidiError> {
+    self.set(Toggle::InvertFootController, invert).await
+  }
+
+  pub async fn set_aftertouch_enabled(&self, enabled: bool) -> Result<(), LumatoneMidiError> {
+    self.set(Toggle::AftertouchEnabled, enabled).await
+  }
+}
+
+impl Lumatone {
+  /// Returns a [DeviceSettings] for changing the device's global on/off settings.
+  pub fn settings(&self) -> DeviceSettings<'_> {
+    DeviceSettings { lumatone: self }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    constants::{BoardIndex, CommandId},
+    sysex::create_sysex_toggle,
+  };
+
+  #[test]
+  fn test_toggle_commands() {
+    for toggle in Toggle::ALL {
+      for on in [true, false] {
+        let command = toggle.command(on);
+        assert_eq!(Toggle::from_command(&command), Some((toggle, on)));
+      }
+    }
+    assert_eq!(
+      Toggle::InvertSustain.command(true).to_sysex_message(),
+      create_sysex_toggle(BoardIndex::Server, CommandId::InvertSustainPedal, true)
+    );
+    assert_eq!(Toggle::from_command(&Command::GetSerialId), None);
+  }
+}
@@ -0,0 +1,75 @@
+//! Structured shutdown for the driver's background tasks.
+//!
+//! A [CancellationToken] is shared between every task that should stop together. Each
+//! task `select!`s on [CancellationToken::cancelled] in its event loop, and exits (after
+//! any cleanup) once the token is cancelled. See [crate::controller::Lumatone::shutdown].
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// A cloneable signal that tells tasks to stop. Cancelling any clone cancels them all.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+  // Keeping a receiver alive means `send` never fails for lack of receivers.
+  inner: Arc<(watch::Sender<bool>, watch::Receiver<bool>)>,
+}
+
+impl CancellationToken {
+  pub fn new() -> Self {
+    CancellationToken {
+      inner: Arc::new(watch::channel(false)),
+    }
+  }
+
+  /// Cancels the token, waking up all tasks waiting on [Self::cancelled].
+  pub fn cancel(&self) {
+    let _ = self.inner.0.send(true);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    *self.inner.1.borrow()
+  }
+
+  /// Resolves once the token has been cancelled. Resolves immediately if it already was.
+  pub async fn cancelled(&self) {
+    let mut rx = self.inner.1.clone();
+    while !*rx.borrow() {
+      if rx.changed().await.is_err() {
+        return;
+      }
+    }
+  }
+}
+
+impl Default for CancellationToken {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_cancel_wakes_all_clones() {
+    let token = CancellationToken::new();
+    let waiters: Vec<_> = (0..3)
+      .map(|_| {
+        let t = token.clone();
+        tokio::spawn(async move { t.cancelled().await })
+      })
+      .collect();
+
+    assert!(!token.is_cancelled());
+    token.clone().cancel();
+    for w in waiters {
+      w.await.unwrap();
+    }
+    assert!(token.is_cancelled());
+
+    // already cancelled, so this resolves immediately
+    token.cancelled().await;
+  }
+}
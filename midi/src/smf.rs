@@ -0,0 +1,290 @@
+//! Reads Standard MIDI Files (.mid) into [Session]s, e.g. to load a phrase to practice.
+//!
+//! The channel messages of every track are merged into one session, with tick times
+//! converted to real time using the file's tempo changes. Meta events other than tempo
+//! changes, and sysex events, are skipped.
+
+use std::{path::Path, time::Duration};
+
+use super::{error::LumatoneMidiError, events::ChannelMessage, session::Session};
+
+use error_stack::{bail, report, IntoReport, Result, ResultExt};
+
+/// The tempo until the first tempo change, in microseconds per quarter note (120 bpm).
+const DEFAULT_TEMPO: u32 = 500_000;
+
+const META_TEMPO: u8 = 0x51;
+
+/// Reads from a byte slice, failing if it runs out.
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn new(bytes: &'a [u8]) -> Self {
+    Reader { bytes, pos: 0 }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.pos >= self.bytes.len()
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], LumatoneMidiError> {
+    match self.bytes.get(self.pos..self.pos + n) {
+      Some(bytes) => {
+        self.pos += n;
+        Ok(bytes)
+      }
+      None => bail!(invalid("unexpected end of file")),
+    }
+  }
+
+  fn u8(&mut self) -> Result<u8, LumatoneMidiError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn peek(&self) -> Result<u8, LumatoneMidiError> {
+    self
+      .bytes
+      .get(self.pos)
+      .copied()
+      .ok_or_else(|| report!(invalid("unexpected end of file")))
+  }
+
+  fn u16(&mut self) -> Result<u16, LumatoneMidiError> {
+    let b = self.take(2)?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, LumatoneMidiError> {
+    let b = self.take(4)?;
+    Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+  }
+
+  /// Reads a variable-length quantity: 7 bits per byte, high bit set on all but the last.
+  fn vlq(&mut self) -> Result<u32, LumatoneMidiError> {
+    let mut value = 0u32;
+    for _ in 0..4 {
+      let b = self.u8()?;
+      value = (value << 7) | (b & 0x7f) as u32;
+      if b & 0x80 == 0 {
+        return Ok(value);
+      }
+    }
+    bail!(invalid("variable-length value is too long"))
+  }
+
+  /// Reads a chunk header, returning its id and contents.
+  fn chunk(&mut self) -> Result<([u8; 4], &'a [u8]), LumatoneMidiError> {
+    let id = self.take(4)?;
+    let len = self.u32()? as usize;
+    Ok(([id[0], id[1], id[2], id[3]], self.take(len)?))
+  }
+}
+
+fn invalid(msg: &str) -> LumatoneMidiError {
+  LumatoneMidiError::InvalidMidiFile(msg.to_string())
+}
+
+/// How to convert ticks to time, from the header's division field.
+#[derive(Debug, Clone, Copy)]
+enum Division {
+  TicksPerQuarter(u16),
+
+  /// SMPTE timing, which doesn't depend on tempo.
+  MicrosPerTick(f64),
+}
+
+impl Division {
+  fn from_u16(division: u16) -> Result<Division, LumatoneMidiError> {
+    if division & 0x8000 == 0 {
+      if division == 0 {
+        bail!(invalid("division is zero"));
+      }
+      return Ok(Division::TicksPerQuarter(division));
+    }
+    let frames_per_second = -((division >> 8) as u8 as i8) as f64;
+    let ticks_per_frame = (division & 0xff) as f64;
+    if frames_per_second <= 0.0 || ticks_per_frame == 0.0 {
+      bail!(invalid("invalid SMPTE division"));
+    }
+    Ok(Division::MicrosPerTick(
+      1_000_000.0 / (frames_per_second * ticks_per_frame),
+    ))
+  }
+}
+
+/// Parses a Standard MIDI File.
+pub fn parse_smf(bytes: &[u8]) -> Result<Session, LumatoneMidiError> {
+  let mut reader = Reader::new(bytes);
+  let (id, header) = reader.chunk()?;
+  if &id != b"MThd" || header.len() < 6 {
+    bail!(invalid("missing MThd header"));
+  }
+  let mut header = Reader::new(header);
+  let _format = header.u16()?;
+  let _track_count = header.u16()?;
+  let division = Division::from_u16(header.u16()?)?;
+
+  // (tick, message) for every track, and (tick, microseconds per quarter)
+  let mut messages: Vec<(u64, ChannelMessage)> = vec![];
+  let mut tempos: Vec<(u64, u32)> = vec![];
+  while !reader.is_empty() {
+    let (id, track) = reader.chunk()?;
+    // unknown chunks are allowed, and should be skipped
+    if &id == b"MTrk" {
+      read_track(track, &mut messages, &mut tempos)?;
+    }
+  }
+  messages.sort_by_key(|(tick, _)| *tick);
+  tempos.sort_by_key(|(tick, _)| *tick);
+
+  let mut session = Session::new();
+  let (mut tempo, mut tempo_tick, mut tempo_micros) = (DEFAULT_TEMPO, 0u64, 0f64);
+  let mut tempos = tempos.into_iter().peekable();
+  for (tick, message) in messages {
+    let micros_at = |tick: u64, tempo: u32, from_tick: u64, from_micros: f64| match division {
+      Division::TicksPerQuarter(tpq) => {
+        from_micros + (tick - from_tick) as f64 * tempo as f64 / tpq as f64
+      }
+      Division::MicrosPerTick(us) => tick as f64 * us,
+    };
+    while let Some((change_tick, new_tempo)) = tempos.next_if(|(t, _)| *t <= tick) {
+      tempo_micros = micros_at(change_tick, tempo, tempo_tick, tempo_micros);
+      tempo_tick = change_tick;
+      tempo = new_tempo;
+    }
+    let micros = micros_at(tick, tempo, tempo_tick, tempo_micros);
+    session.push(Duration::from_micros(micros.round() as u64), message);
+  }
+  Ok(session)
+}
+
+fn read_track(
+  track: &[u8],
+  messages: &mut Vec<(u64, ChannelMessage)>,
+  tempos: &mut Vec<(u64, u32)>,
+) -> Result<(), LumatoneMidiError> {
+  let mut reader = Reader::new(track);
+  let mut tick = 0u64;
+  let mut running_status: Option<u8> = None;
+  while !reader.is_empty() {
+    tick += reader.vlq()? as u64;
+    let status = match reader.peek()? {
+      b if b & 0x80 != 0 => reader.u8()?,
+      _ => running_status.ok_or_else(|| report!(invalid("data byte without a status")))?,
+    };
+    match status {
+      0xff => {
+        let kind = reader.u8()?;
+        let len = reader.vlq()? as usize;
+        let data = reader.take(len)?;
+        match kind {
+          META_TEMPO if len == 3 => {
+            tempos.push((tick, u32::from_be_bytes([0, data[0], data[1], data[2]])));
+          }
+          0x2f => break,
+          _ => {}
+        }
+      }
+      0xf0 | 0xf7 => {
+        running_status = None;
+        let len = reader.vlq()? as usize;
+        reader.take(len)?;
+      }
+      _ => {
+        running_status = Some(status);
+        let data_len = match status & 0xf0 {
+          0xc0 | 0xd0 => 1,
+          _ => 2,
+        };
+        let mut msg = vec![status];
+        msg.extend_from_slice(reader.take(data_len)?);
+        let message =
+          ChannelMessage::from_bytes(&msg).change_context(invalid("invalid channel message"))?;
+        messages.push((tick, message));
+      }
+    }
+  }
+  Ok(())
+}
+
+impl Session {
+  /// Loads a Standard MIDI File. See [parse_smf].
+  pub fn load_midi_file<P: AsRef<Path>>(path: P) -> Result<Session, LumatoneMidiError> {
+    let path = path.as_ref();
+    let bytes =
+      std::fs::read(path)
+        .report()
+        .change_context(LumatoneMidiError::SessionFileError(
+          path.display().to_string(),
+        ))?;
+    parse_smf(&bytes).attach_printable_lazy(|| format!("in {}", path.display()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::constants::MidiChannel;
+
+  #[test]
+  fn test_parse_smf_with_tempo_change_and_running_status() {
+    #[rustfmt::skip]
+    let track: &[u8] = &[
+      // 120 bpm
+      0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20,
+      0x00, 0x90, 0x3c, 0x64,
+      // a quarter note later, running status note on with velocity 0
+      0x60, 0x3c, 0x00,
+      // 60 bpm from here
+      0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40,
+      0x81, 0x40, 0x90, 0x40, 0x50,
+      0x00, 0xff, 0x2f, 0x00,
+    ];
+    let mut file = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk".to_vec();
+    file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    file.extend_from_slice(track);
+
+    let session = parse_smf(&file).unwrap();
+    let channel = MidiChannel::default();
+    let events: Vec<_> = session
+      .events()
+      .iter()
+      .map(|e| (e.time.as_millis(), e.message))
+      .collect();
+    assert_eq!(
+      events,
+      vec![
+        (
+          0,
+          ChannelMessage::NoteOn {
+            channel,
+            note: 60,
+            velocity: 100
+          }
+        ),
+        (
+          500,
+          ChannelMessage::NoteOff {
+            channel,
+            note: 60,
+            velocity: 0
+          }
+        ),
+        // 192 ticks = 2 quarter notes at 60 bpm
+        (
+          2500,
+          ChannelMessage::NoteOn {
+            channel,
+            note: 64,
+            velocity: 80
+          }
+        ),
+      ]
+    );
+
+    assert!(parse_smf(b"MThd\x00\x00").is_err());
+  }
+}
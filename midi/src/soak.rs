@@ -0,0 +1,326 @@
+//! A long-running stability test that drives a mix of traffic through a [MidiDriver].
+//!
+//! Three clients share the driver for the configured duration:
+//!
+//! - an animation client that streams random key colors with
+//!   [`send_and_forget`](MidiDriver::send_and_forget), as many per frame as the driver's
+//!   pacing (see [SEND_AND_FORGET_INTERVAL]) lets through alongside the other clients,
+//! - an editing client that sends key colors and waits for each response,
+//! - a readback client that queries the firmware version and LED colors.
+//!
+//! Every command has a deadline. A command that doesn't resolve before the deadline is
+//! counted as stalled, which would mean the driver's state machine is stuck. After the
+//! clients stop, the animation client sends a final ping, which is only answered once its
+//! queued frames have been sent, and the driver's queue must then be empty. Resident memory
+//! is sampled at the start and end on Linux.
+//!
+//! Only built with the `soak` feature. Run it against a [FakeDevice](crate::testing::FakeDevice)
+//! in CI, or against real hardware with the CLI's `soak` command.
+
+use std::{
+  fmt::Display,
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use log::{info, warn};
+use tokio::time::{sleep, timeout, Instant};
+
+use super::{
+  commands::{ping, set_key_color, Command},
+  constants::{BoardIndex, LumatoneKeyLocation, RGBColor},
+  driver::{MidiDriver, SEND_AND_FORGET_INTERVAL},
+  error::LumatoneMidiError,
+  responses::Response,
+};
+
+use error_stack::{report, Result};
+
+#[derive(Debug, Clone)]
+pub struct SoakConfig {
+  pub duration: Duration,
+
+  /// How long a single command may take before it's counted as stalled.
+  pub command_deadline: Duration,
+
+  /// Pause between animation frames.
+  pub frame_interval: Duration,
+
+  /// The largest allowed growth in resident memory, in kilobytes.
+  pub max_memory_growth_kb: u64,
+}
+
+impl Default for SoakConfig {
+  fn default() -> Self {
+    SoakConfig {
+      duration: Duration::from_secs(60 * 60),
+      command_deadline: Duration::from_secs(60),
+      frame_interval: Duration::from_millis(50),
+      max_memory_growth_kb: 10 * 1024,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+  sent: AtomicUsize,
+  failed: AtomicUsize,
+  stalled: AtomicUsize,
+  max_latency_ms: AtomicU64,
+}
+
+impl Counters {
+  /// Sends `command` and waits for the response, recording the outcome.
+  async fn send(&self, driver: &MidiDriver, command: Command, deadline: Duration) {
+    let start = Instant::now();
+    self.sent.fetch_add(1, Ordering::Relaxed);
+    match timeout(deadline, driver.send(command)).await {
+      Ok(Ok(_)) => {
+        let latency = start.elapsed().as_millis() as u64;
+        self.max_latency_ms.fetch_max(latency, Ordering::Relaxed);
+      }
+      Ok(Err(err)) => {
+        warn!("soak command failed: {err:?}");
+        self.failed.fetch_add(1, Ordering::Relaxed);
+      }
+      Err(_) => {
+        warn!("soak command stalled");
+        self.stalled.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoakReport {
+  pub elapsed: Duration,
+  pub commands_sent: usize,
+  pub frames_sent: usize,
+  pub failures: usize,
+  pub stalls: usize,
+  pub max_latency: Duration,
+
+  /// Whether the driver answered a ping queued behind the animation frames after all
+  /// clients had stopped.
+  pub drained: bool,
+
+  /// Commands still in the driver's queue after the final ping was answered.
+  pub queue_depth_after: usize,
+
+  /// The most commands seen waiting in the driver's queue during the run.
+  pub max_queue_depth: usize,
+
+  /// Resident memory at the start and end of the run, if it could be measured.
+  pub memory_start_kb: Option<u64>,
+  pub memory_end_kb: Option<u64>,
+}
+
+impl SoakReport {
+  pub fn memory_growth_kb(&self) -> Option<u64> {
+    match (self.memory_start_kb, self.memory_end_kb) {
+      (Some(start), Some(end)) => Some(end.saturating_sub(start)),
+      _ => None,
+    }
+  }
+
+  /// Returns a description of each check that failed: stalled commands, an undrained
+  /// or leaking queue, or memory growth over the configured limit.
+  pub fn problems(&self, config: &SoakConfig) -> Vec<String> {
+    let mut problems = vec![];
+    if self.stalls > 0 {
+      problems.push(format!("{} commands stalled", self.stalls));
+    }
+    if !self.drained {
+      problems.push("driver didn't respond after clients stopped".to_string());
+    }
+    if self.queue_depth_after > 0 {
+      problems.push(format!(
+        "{} commands left in the queue",
+        self.queue_depth_after
+      ));
+    }
+    if let Some(growth) = self.memory_growth_kb() {
+      if growth > config.max_memory_growth_kb {
+        problems.push(format!("resident memory grew by {growth} KB"));
+      }
+    }
+    problems
+  }
+}
+
+impl Display for SoakReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "elapsed:          {:?}", self.elapsed)?;
+    writeln!(f, "commands sent:    {}", self.commands_sent)?;
+    writeln!(f, "frames sent:      {}", self.frames_sent)?;
+    writeln!(f, "failures:         {}", self.failures)?;
+    writeln!(f, "stalls:           {}", self.stalls)?;
+    writeln!(f, "max latency:      {:?}", self.max_latency)?;
+    writeln!(f, "drained:          {}", self.drained)?;
+    writeln!(f, "max queue depth:  {}", self.max_queue_depth)?;
+    writeln!(f, "queue afterwards: {}", self.queue_depth_after)?;
+    match self.memory_growth_kb() {
+      Some(growth) => write!(f, "memory growth:    {growth} KB"),
+      None => write!(f, "memory growth:    unknown"),
+    }
+  }
+}
+
+/// Returns the resident set size of this process in kilobytes. Only supported on Linux.
+fn resident_memory_kb() -> Option<u64> {
+  let status = std::fs::read_to_string("/proc/self/status").ok()?;
+  let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+  line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Runs the soak test. See the [module docs](self).
+pub async fn run_soak(driver: &MidiDriver, config: &SoakConfig) -> SoakReport {
+  let memory_start_kb = resident_memory_kb();
+  let counters = Arc::new(Counters::default());
+  let frames = Arc::new(AtomicUsize::new(0));
+  let max_queue_depth = Arc::new(AtomicUsize::new(0));
+  let start = Instant::now();
+  let end = start + config.duration;
+
+  info!("starting soak test for {:?}", config.duration);
+
+  let animation = {
+    let driver = driver.clone();
+    let frames = frames.clone();
+    let max_queue_depth = max_queue_depth.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+      // Leave half of the driver's send slots for the other clients.
+      let keys_per_frame =
+        (config.frame_interval.as_millis() / SEND_AND_FORGET_INTERVAL.as_millis() / 2).max(1);
+      let locations = LumatoneKeyLocation::all();
+      let mut keys = locations.iter().cycle();
+      while Instant::now() < end {
+        for location in keys.by_ref().take(keys_per_frame as usize) {
+          let command = set_key_color(*location, RGBColor::random());
+          if let Err(err) = driver.send_and_forget(command).await {
+            warn!("error queueing animation frame: {err:?}");
+          }
+        }
+        frames.fetch_add(1, Ordering::Relaxed);
+        max_queue_depth.fetch_max(driver.metrics().queue_depth, Ordering::Relaxed);
+        sleep(config.frame_interval).await;
+      }
+      driver
+    })
+  };
+
+  let edits = {
+    let driver = driver.clone();
+    let counters = counters.clone();
+    let deadline = config.command_deadline;
+    tokio::spawn(async move {
+      let locations = LumatoneKeyLocation::all();
+      let mut i = 0;
+      while Instant::now() < end {
+        let command = set_key_color(locations[i % locations.len()], RGBColor::random());
+        counters.send(&driver, command, deadline).await;
+        i += 1;
+      }
+    })
+  };
+
+  let readbacks = {
+    let driver = driver.clone();
+    let counters = counters.clone();
+    let deadline = config.command_deadline;
+    tokio::spawn(async move {
+      let boards = BoardIndex::all_octaves();
+      let mut i = 0;
+      while Instant::now() < end {
+        let board = boards[i % boards.len()];
+        counters
+          .send(&driver, Command::GetFirmwareRevision, deadline)
+          .await;
+        counters
+          .send(&driver, Command::GetRedLEDConfig(board), deadline)
+          .await;
+        i += 1;
+      }
+    })
+  };
+
+  for task in [edits, readbacks] {
+    if let Err(err) = task.await {
+      warn!("soak client panicked: {err}");
+    }
+  }
+
+  // Each client's commands are sent in order, so the ping is only answered once the
+  // animation frames ahead of it have gone out.
+  let drained = match animation.await {
+    Ok(driver) => matches!(
+      timeout(config.command_deadline, driver.send(ping(0))).await,
+      Ok(Ok(Response::Pong(_)))
+    ),
+    Err(err) => {
+      warn!("soak client panicked: {err}");
+      false
+    }
+  };
+
+  SoakReport {
+    elapsed: start.elapsed(),
+    commands_sent: counters.sent.load(Ordering::Relaxed),
+    frames_sent: frames.load(Ordering::Relaxed),
+    failures: counters.failed.load(Ordering::Relaxed),
+    stalls: counters.stalled.load(Ordering::Relaxed),
+    max_latency: Duration::from_millis(counters.max_latency_ms.load(Ordering::Relaxed)),
+    drained,
+    queue_depth_after: driver.metrics().queue_depth,
+    max_queue_depth: max_queue_depth.load(Ordering::Relaxed),
+    memory_start_kb,
+    memory_end_kb: resident_memory_kb(),
+  }
+}
+
+/// Runs the soak test and fails if any of the [SoakReport::problems] checks fail.
+pub async fn run_soak_checked(
+  driver: &MidiDriver,
+  config: &SoakConfig,
+) -> Result<SoakReport, LumatoneMidiError> {
+  let report = run_soak(driver, config).await;
+  let problems = report.problems(config);
+  if !problems.is_empty() {
+    return Err(
+      report!(LumatoneMidiError::SoakTestFailed(problems.join(", ")))
+        .attach_printable(report.to_string()),
+    );
+  }
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{shutdown::CancellationToken, testing::FakeDevice};
+
+  #[tokio::test]
+  #[ignore = "runs for two seconds"]
+  async fn test_short_soak_against_fake_device() {
+    let shutdown = CancellationToken::new();
+    let (driver, driver_future) = MidiDriver::with_transport(FakeDevice::new(), shutdown.clone());
+    tokio::spawn(driver_future);
+
+    let config = SoakConfig {
+      duration: Duration::from_secs(2),
+      command_deadline: Duration::from_secs(5),
+      ..SoakConfig::default()
+    };
+    let report = run_soak_checked(&driver, &config).await.unwrap();
+    assert!(report.commands_sent > 0);
+    assert!(report.frames_sent > 0);
+    assert_eq!(report.failures, 0);
+    assert!(report.drained);
+
+    shutdown.cancel();
+  }
+}
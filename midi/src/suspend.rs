@@ -0,0 +1,119 @@
+//! Detects when the host has been asleep, so the driver can replace connections that went
+//! stale while it was suspended.
+//!
+//! Sleeping doesn't close MIDI connections, but after the host wakes they often stop
+//! delivering messages, and the driver would wait on them forever. So the driver compares
+//! its clocks every [SUSPEND_CHECK_INTERVAL]. On most platforms the monotonic clock stops
+//! while the host sleeps and the wall clock doesn't, so a gap between them means the host
+//! was suspended. Where the monotonic clock keeps running, the check itself arrives late,
+//! which is treated the same way.
+//!
+//! After a suspend, or when the connection closes, the driver calls
+//! [Transport::reconnect](crate::transport::Transport::reconnect) until it succeeds, and
+//! broadcasts [ConnectionEvent]s to
+//! [MidiDriver::subscribe_connection](crate::driver::MidiDriver::subscribe_connection)
+//! receivers. The device may have been power cycled while the host slept, so the
+//! [reconciler](crate::reconcile) and the [resync watchdog](crate::resync) treat a
+//! reconnection like a reset: they ping the device straight away, and re-send the
+//! configuration once it responds.
+
+use std::time::{Duration, SystemTime};
+
+use tokio::time::Instant;
+
+/// How often the driver checks whether the host has been asleep.
+pub const SUSPEND_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far the clocks have to disagree before the host is considered to have been asleep.
+pub const SUSPEND_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How many [ConnectionEvent]s a slow receiver can fall behind before the oldest are dropped.
+pub(crate) const CONNECTION_EVENTS_BUFFER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+  /// The host was asleep for about this long. The connection will be replaced.
+  Resumed { slept: Duration },
+
+  /// The connection closed unexpectedly, e.g. because the device was unplugged. It will
+  /// be replaced.
+  Closed,
+
+  /// A new connection was established.
+  Reconnected,
+
+  /// Reconnecting failed. The driver tries again after [SUSPEND_CHECK_INTERVAL].
+  ReconnectFailed(String),
+}
+
+/// Compares the wall clock with the monotonic clock at each check.
+#[derive(Debug)]
+pub(crate) struct ClockJumpDetector {
+  interval: Duration,
+  wall: SystemTime,
+  monotonic: Instant,
+}
+
+impl ClockJumpDetector {
+  /// Creates a detector that expects to be checked every `interval`.
+  pub(crate) fn new(interval: Duration) -> Self {
+    ClockJumpDetector {
+      interval,
+      wall: SystemTime::now(),
+      monotonic: Instant::now(),
+    }
+  }
+
+  /// Records a check made at `wall` and `monotonic`. Returns roughly how long the host was
+  /// asleep since the last check, if it looks like it was.
+  ///
+  /// A large manual change to the wall clock also looks like a suspend. That only costs a
+  /// reconnection, so it isn't worth telling apart.
+  pub(crate) fn check(&mut self, wall: SystemTime, monotonic: Instant) -> Option<Duration> {
+    // the wall clock going backwards is an adjustment, not a suspend
+    let wall_elapsed = wall.duration_since(self.wall).unwrap_or_default();
+    let monotonic_elapsed = monotonic.saturating_duration_since(self.monotonic);
+    self.wall = wall;
+    self.monotonic = monotonic;
+
+    let slept = wall_elapsed
+      .saturating_sub(monotonic_elapsed)
+      .max(monotonic_elapsed.saturating_sub(self.interval));
+    (slept >= SUSPEND_THRESHOLD).then_some(slept)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test(start_paused = true)]
+  async fn test_detect_clock_jumps() {
+    let mut detector = ClockJumpDetector::new(SUSPEND_CHECK_INTERVAL);
+    let (mut wall, mut monotonic) = (detector.wall, detector.monotonic);
+    let mut check = |wall_step: u64, monotonic_step: u64| {
+      wall += Duration::from_secs(wall_step);
+      monotonic += Duration::from_secs(monotonic_step);
+      detector.check(wall, monotonic)
+    };
+
+    assert_eq!(check(1, 1), None);
+    assert_eq!(check(3, 1), None);
+
+    // the monotonic clock stopped while the wall clock kept going
+    assert_eq!(check(61, 1), Some(Duration::from_secs(60)));
+    assert_eq!(check(1, 1), None);
+
+    // both clocks kept going, but the check was late
+    assert_eq!(check(31, 31), Some(Duration::from_secs(30)));
+
+    // the wall clock was set back
+    assert_eq!(
+      detector.check(
+        wall - Duration::from_secs(60),
+        monotonic + SUSPEND_CHECK_INTERVAL
+      ),
+      None
+    );
+  }
+}
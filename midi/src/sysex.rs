@@ -3,6 +3,7 @@
 use super::{
   constants::{BoardIndex, CommandId, RGBColor, ResponseStatusCode, MANUFACTURER_ID},
   error::LumatoneMidiError,
+  firmware::ColorEncoding,
 };
 use num_traits::FromPrimitive;
 
@@ -84,14 +85,19 @@ pub fn create_extended_key_color_sysex(
   cmd: CommandId,
   key_index: u8,
   color: &RGBColor,
+  encoding: ColorEncoding,
 ) -> EncodedSysex {
   let mut data = vec![key_index];
-  data.extend(color.to_bytes());
+  data.extend(encoding.encode(color));
   create_sysex(board_index, cmd, data)
 }
 
-pub fn create_extended_macro_color_sysex(cmd: CommandId, color: &RGBColor) -> EncodedSysex {
-  create_sysex(BoardIndex::Server, cmd, color.to_bytes())
+pub fn create_extended_macro_color_sysex(
+  cmd: CommandId,
+  color: &RGBColor,
+  encoding: ColorEncoding,
+) -> EncodedSysex {
+  create_sysex(BoardIndex::Server, cmd, encoding.encode(color))
 }
 
 pub fn create_table_sysex(cmd: CommandId, table: &SysexTable) -> EncodedSysex {
@@ -160,6 +166,35 @@ pub fn message_answer_code(msg: &[u8]) -> ResponseStatusCode {
   status.unwrap_or(ResponseStatusCode::Unknown)
 }
 
+/// Checks that a message is a complete sysex message addressed to the Lumatone, with a valid
+/// board index and only 7-bit data bytes.
+pub fn validate_raw_sysex(msg: &[u8]) -> Result<(), LumatoneMidiError> {
+  use LumatoneMidiError::MalformedSysex;
+
+  if msg.first() != Some(&SYSEX_START) || msg.last() != Some(&SYSEX_END) {
+    return Err(MalformedSysex(
+      "message must begin with 0xf0 and end with 0xf7".to_string(),
+    ));
+  }
+  let data = strip_sysex_markers(msg);
+  if let Some(b) = data.iter().find(|b| **b & 0x80 != 0) {
+    return Err(MalformedSysex(format!(
+      "data byte {b:#04x} is out of range 0x00 ..= 0x7f"
+    )));
+  }
+  if !is_lumatone_message(data) {
+    return Err(LumatoneMidiError::NotLumatoneMessage(msg.to_vec()));
+  }
+  if data.len() <= CMD_ID {
+    return Err(LumatoneMidiError::MessageTooShort {
+      expected: CMD_ID + 3,
+      actual: msg.len(),
+    });
+  }
+  BoardIndex::try_from(data[BOARD_IND])?;
+  Ok(())
+}
+
 pub fn is_response_to_message(outgoing: &[u8], incoming: &[u8]) -> bool {
   let outgoing = strip_sysex_markers(outgoing);
   let incoming = strip_sysex_markers(incoming);
@@ -174,3 +209,36 @@ pub fn is_response_to_message(outgoing: &[u8], incoming: &[u8]) -> bool {
 
   incoming[CMD_ID] == outgoing[CMD_ID] && incoming[BOARD_IND] == outgoing[BOARD_IND]
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::constants::TEST_ECHO;
+
+  #[test]
+  fn test_validate_raw_sysex() {
+    let ping = create_sysex(BoardIndex::Server, CommandId::LumaPing, vec![TEST_ECHO]);
+    assert!(validate_raw_sysex(&ping).is_ok());
+
+    // missing end marker
+    assert!(validate_raw_sysex(&ping[..ping.len() - 1]).is_err());
+
+    // wrong manufacturer id
+    let mut wrong_manu = ping.clone();
+    wrong_manu[1] = 0x7e;
+    assert!(validate_raw_sysex(&wrong_manu).is_err());
+
+    // 8-bit data byte
+    let mut bad_data = ping.clone();
+    bad_data[6] = 0x80;
+    assert!(validate_raw_sysex(&bad_data).is_err());
+
+    // invalid board index
+    let mut bad_board = ping;
+    bad_board[4] = 0x10;
+    assert!(validate_raw_sysex(&bad_board).is_err());
+
+    // too short to have a command id
+    assert!(validate_raw_sysex(&[0xf0, 0x00, 0x21, 0x50, 0x00, 0xf7]).is_err());
+  }
+}
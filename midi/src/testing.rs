@@ -0,0 +1,714 @@
+//! Fake [Transport]s for exercising the driver without hardware.
+//!
+//! [FakeDevice] acknowledges every message it's sent. Wrap it (or any other transport) in
+//! a [FaultInjector] to script failures: each incoming message is paired with the next
+//! [Fault] in the script, and once the script runs out, messages pass through unchanged.
+//!
+//! ```ignore
+//! let transport = FaultInjector::new(FakeDevice::new(), [Fault::None, Fault::Drop]);
+//! let (driver, driver_future) = MidiDriver::with_transport(transport, CancellationToken::new());
+//! tokio::spawn(driver_future);
+//! // the first command succeeds, the second times out
+//! ```
+//!
+//! [ReplayDevice] answers with responses recorded from a real device, see
+//! [crate::conformance].
+//!
+//! For timing-sensitive code, [SimulatedLatency] makes a transport respond like real
+//! hardware: after a variable delay, and now and then with BUSY. See [LatencyProfile].
+
+use std::{
+  collections::VecDeque,
+  mem::discriminant,
+  sync::{Arc, Weak},
+  time::Duration,
+};
+
+use futures::future::{pending, BoxFuture};
+use log::debug;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+  sync::broadcast,
+  time::{sleep_until, Instant},
+};
+
+use super::{
+  conformance::{Fixture, FixtureSet},
+  constants::{BoardIndex, CommandId, ResponseStatusCode, MANUFACTURER_ID},
+  error::LumatoneMidiError,
+  events::ChannelMessage,
+  sysex::{
+    create_sysex, message_answer_code, message_command_id, strip_sysex_markers, EncodedSysex,
+    BOARD_IND, CALIB_MODE, CMD_ID, MANU_0, MSG_STATUS,
+  },
+  timing::TimedMessage,
+  transport::Transport,
+};
+
+use error_stack::{bail, Result};
+
+/// A [Transport] that answers every message with an ACK.
+///
+/// Serial number, firmware version and LED readback queries get a fixed response. Anything
+/// else gets its payload echoed back, which makes ping responses valid, and other commands
+/// decode as [Response::Ack](crate::responses::Response::Ack). Starting wheel or pedal
+/// calibration is followed by one calibration status message.
+pub struct FakeDevice {
+  responses: VecDeque<EncodedSysex>,
+  events: Arc<broadcast::Sender<ChannelMessage>>,
+}
+
+impl FakeDevice {
+  pub fn new() -> Self {
+    let (events, _) = broadcast::channel(16);
+    FakeDevice {
+      responses: VecDeque::new(),
+      events: Arc::new(events),
+    }
+  }
+}
+
+impl FakeDevice {
+  /// Compares the responses a [FakeDevice] gives with the ones a real device gave, and
+  /// describes each fixture it answers differently: with another status, or with a response
+  /// that doesn't decode to the same kind of [Response](crate::responses::Response).
+  /// Payload values, like the serial number, are allowed to differ.
+  pub fn check_conformance(fixtures: &FixtureSet) -> Vec<String> {
+    let firmware = fixtures.firmware_support();
+    fixtures
+      .fixtures
+      .iter()
+      .filter_map(|fixture| {
+        let fake = Fixture {
+          response: fake_response(&fixture.request),
+          ..fixture.clone()
+        };
+        let (real_status, fake_status) = (
+          message_answer_code(&fixture.response),
+          message_answer_code(&fake.response),
+        );
+        let problem = match (fixture.decode(&firmware), fake.decode(&firmware)) {
+          _ if real_status != fake_status => {
+            format!("expected status {real_status:?}, got {fake_status:?}")
+          }
+          (Err(err), _) => format!(
+            "the recorded response doesn't decode: {}",
+            err.current_context()
+          ),
+          (Ok(real), Err(err)) => format!(
+            "expected {real}, but the fake response doesn't decode: {}",
+            err.current_context()
+          ),
+          (Ok(real), Ok(fake)) if discriminant(&real) != discriminant(&fake) => {
+            format!("expected {real}, got {fake}")
+          }
+          _ => return None,
+        };
+        Some(format!("{}: {problem}", fixture.command))
+      })
+      .collect()
+  }
+}
+
+impl Default for FakeDevice {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+pub const FAKE_SERIAL_ID: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+pub const FAKE_FIRMWARE_VERSION: [u8; 3] = [1, 0, 12];
+
+/// Builds the response that the [FakeDevice] sends for `msg`.
+fn fake_response(msg: &[u8]) -> EncodedSysex {
+  use CommandId::*;
+  let stripped = strip_sysex_markers(msg);
+  let payload = match message_command_id(msg) {
+    Ok(GetSerialIdentity) => FAKE_SERIAL_ID.to_vec(),
+    Ok(GetFirmwareRevision) => FAKE_FIRMWARE_VERSION.to_vec(),
+    Ok(GetRedLedConfig | GetGreenLedConfig | GetBlueLedConfig) => vec![0; 112],
+    _ => {
+      // the outgoing message includes the sysex start byte, so the status goes after CMD_ID + 1
+      let mut response = msg.to_vec();
+      response.insert(CMD_ID + 2, ResponseStatusCode::Ack.into());
+      return response;
+    }
+  };
+
+  let board_index = BoardIndex::try_from(stripped[BOARD_IND]).unwrap_or(BoardIndex::Server);
+  let cmd_id = message_command_id(msg).unwrap();
+  let mut data = vec![ResponseStatusCode::Ack.into()];
+  data.extend(payload);
+  create_sysex(board_index, cmd_id, data)
+}
+
+/// The status message a calibration routine started by `msg` sends, if any.
+fn fake_calibration_status(msg: &[u8]) -> Option<EncodedSysex> {
+  let stripped = strip_sysex_markers(msg);
+  match message_command_id(msg) {
+    Ok(cmd_id @ (CommandId::CalibratePitchModWheel | CommandId::CalibrateExpressionPedal))
+      if stripped.get(CALIB_MODE) == Some(&1) =>
+    {
+      let mut data = vec![ResponseStatusCode::Ack.into()];
+      data.extend([0; 15]);
+      Some(create_sysex(BoardIndex::Server, cmd_id, data))
+    }
+    _ => None,
+  }
+}
+
+impl Transport for FakeDevice {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    self.responses.push_back(fake_response(msg));
+    self.responses.extend(fake_calibration_status(msg));
+    Ok(())
+  }
+
+  fn recv(&mut self) -> BoxFuture<'_, Option<EncodedSysex>> {
+    Box::pin(async move {
+      match self.responses.pop_front() {
+        Some(msg) => Some(msg),
+        None => pending().await,
+      }
+    })
+  }
+
+  fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+    Arc::downgrade(&self.events)
+  }
+}
+
+/// A [Transport] that answers with the responses in a [FixtureSet].
+///
+/// A message that matches a fixture's request gets the recorded response. Anything else is
+/// answered the way a [FakeDevice] would.
+pub struct ReplayDevice {
+  fixtures: FixtureSet,
+  responses: VecDeque<EncodedSysex>,
+  events: Arc<broadcast::Sender<ChannelMessage>>,
+}
+
+impl ReplayDevice {
+  pub fn new(fixtures: FixtureSet) -> Self {
+    let (events, _) = broadcast::channel(16);
+    ReplayDevice {
+      fixtures,
+      responses: VecDeque::new(),
+      events: Arc::new(events),
+    }
+  }
+}
+
+impl Transport for ReplayDevice {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    match self.fixtures.find(msg) {
+      Some(fixture) => self.responses.push_back(fixture.response.clone()),
+      None => {
+        debug!("no fixture for {msg:x?}, answering like a FakeDevice");
+        self.responses.push_back(fake_response(msg));
+        self.responses.extend(fake_calibration_status(msg));
+      }
+    }
+    Ok(())
+  }
+
+  fn recv(&mut self) -> BoxFuture<'_, Option<EncodedSysex>> {
+    Box::pin(async move {
+      match self.responses.pop_front() {
+        Some(msg) => Some(msg),
+        None => pending().await,
+      }
+    })
+  }
+
+  fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+    Arc::downgrade(&self.events)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+  /// Pass the message through unchanged.
+  None,
+
+  /// Drop the message, as if the device never responded.
+  Drop,
+
+  /// Hold the message back for a while before delivering it.
+  Delay(Duration),
+
+  /// Replace the response status with BUSY.
+  Busy,
+
+  /// Overwrite the manufacturer id, so the message can't be decoded.
+  Corrupt,
+
+  /// Close the connection. The message is dropped, all later messages are dropped, and
+  /// sending fails.
+  Disconnect,
+}
+
+/// Wraps a [Transport] and applies a script of [Fault]s to the messages it receives.
+pub struct FaultInjector<T: Transport> {
+  inner: T,
+  script: VecDeque<Fault>,
+  delayed: Option<(Instant, EncodedSysex)>,
+  disconnected: bool,
+  reconnectable: bool,
+}
+
+impl<T: Transport> FaultInjector<T> {
+  pub fn new<I: IntoIterator<Item = Fault>>(inner: T, script: I) -> Self {
+    FaultInjector {
+      inner,
+      script: script.into_iter().collect(),
+      delayed: None,
+      disconnected: false,
+      reconnectable: false,
+    }
+  }
+
+  /// Lets the driver reconnect after a [Fault::Disconnect], as if the device had come back.
+  /// Otherwise reconnecting is left to the wrapped transport.
+  pub fn with_reconnect(mut self) -> Self {
+    self.reconnectable = true;
+    self
+  }
+}
+
+impl<T: Transport> Transport for FaultInjector<T> {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    if self.disconnected {
+      bail!(LumatoneMidiError::DeviceConnectionError);
+    }
+    self.inner.send(msg)
+  }
+
+  fn recv(&mut self) -> BoxFuture<'_, Option<EncodedSysex>> {
+    Box::pin(async move {
+      loop {
+        if self.disconnected {
+          return None;
+        }
+
+        // Delayed messages are stored on self rather than in this future, so they aren't
+        // lost if the driver drops the future before it resolves.
+        if let Some((deliver_at, _)) = &self.delayed {
+          sleep_until(*deliver_at).await;
+          return self.delayed.take().map(|(_, msg)| msg);
+        }
+
+        let mut msg = self.inner.recv().await?;
+        match self.script.pop_front().unwrap_or(Fault::None) {
+          Fault::None => return Some(msg),
+
+          Fault::Drop => continue,
+
+          Fault::Delay(duration) => {
+            self.delayed = Some((Instant::now() + duration, msg));
+          }
+
+          Fault::Busy => {
+            msg[MSG_STATUS + 1] = ResponseStatusCode::Busy.into();
+            return Some(msg);
+          }
+
+          Fault::Corrupt => {
+            msg[MANU_0 + 1] = !MANUFACTURER_ID[0] & 0x7f;
+            return Some(msg);
+          }
+
+          Fault::Disconnect => {
+            self.disconnected = true;
+          }
+        }
+      }
+    })
+  }
+
+  fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+    self.inner.events_sender()
+  }
+
+  fn timed_events_sender(&self) -> Weak<broadcast::Sender<TimedMessage>> {
+    self.inner.timed_events_sender()
+  }
+
+  fn dropped_messages(&self) -> u64 {
+    self.inner.dropped_messages()
+  }
+
+  fn reconnect(&mut self) -> Result<(), LumatoneMidiError> {
+    if !self.reconnectable {
+      self.inner.reconnect()?;
+    }
+    self.disconnected = false;
+    self.delayed = None;
+    Ok(())
+  }
+}
+
+/// How long responses take to arrive, and how often the device is busy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyProfile {
+  /// The typical time between sending a message and receiving its response.
+  pub latency: Duration,
+
+  /// Each response's delay varies by up to this much either way.
+  pub jitter: Duration,
+
+  /// The fraction of responses, from 0.0 to 1.0, that come back as BUSY.
+  pub busy_rate: f64,
+}
+
+impl LatencyProfile {
+  /// Responds straight away and is never busy.
+  pub fn instant() -> Self {
+    LatencyProfile {
+      latency: Duration::ZERO,
+      jitter: Duration::ZERO,
+      busy_rate: 0.0,
+    }
+  }
+
+  /// Rough figures for a Lumatone on USB: responses take 10-40ms, and a few percent of
+  /// commands get BUSY while the device is busy with the previous one. Replace them with
+  /// measurements from your own board when tuning against a specific setup.
+  pub fn hardware() -> Self {
+    LatencyProfile {
+      latency: Duration::from_millis(25),
+      jitter: Duration::from_millis(15),
+      busy_rate: 0.02,
+    }
+  }
+
+  fn sample_delay<R: Rng>(&self, rng: &mut R) -> Duration {
+    if self.jitter.is_zero() {
+      return self.latency;
+    }
+    let jitter = rng.gen_range(-1.0..=1.0) * self.jitter.as_secs_f64();
+    Duration::from_secs_f64((self.latency.as_secs_f64() + jitter).max(0.0))
+  }
+}
+
+impl Default for LatencyProfile {
+  fn default() -> Self {
+    Self::hardware()
+  }
+}
+
+/// Wraps a [Transport] and delivers the messages it receives according to a
+/// [LatencyProfile].
+///
+/// Messages are delivered in the order the wrapped transport received them, like the
+/// device's serial connection, so a response that draws a short delay still waits for
+/// the one before it.
+pub struct SimulatedLatency<T: Transport> {
+  inner: T,
+  profile: LatencyProfile,
+  rng: StdRng,
+  pending: VecDeque<(Instant, EncodedSysex)>,
+}
+
+impl<T: Transport> SimulatedLatency<T> {
+  pub fn new(inner: T, profile: LatencyProfile) -> Self {
+    SimulatedLatency {
+      inner,
+      profile,
+      rng: StdRng::from_entropy(),
+      pending: VecDeque::new(),
+    }
+  }
+
+  /// Draws delays and BUSY responses from a fixed seed, so that runs can be repeated.
+  pub fn with_seed(mut self, seed: u64) -> Self {
+    self.rng = StdRng::seed_from_u64(seed);
+    self
+  }
+
+  fn schedule(&mut self, mut msg: EncodedSysex) {
+    if msg.len() > MSG_STATUS + 1 && self.rng.gen_bool(self.profile.busy_rate.clamp(0.0, 1.0)) {
+      msg[MSG_STATUS + 1] = ResponseStatusCode::Busy.into();
+    }
+    let mut deliver_at = Instant::now() + self.profile.sample_delay(&mut self.rng);
+    if let Some((last, _)) = self.pending.back() {
+      deliver_at = deliver_at.max(*last);
+    }
+    self.pending.push_back((deliver_at, msg));
+  }
+}
+
+impl<T: Transport> Transport for SimulatedLatency<T> {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    self.inner.send(msg)
+  }
+
+  fn recv(&mut self) -> BoxFuture<'_, Option<EncodedSysex>> {
+    Box::pin(async move {
+      loop {
+        // Like FaultInjector's delayed messages, pending messages are kept on self so
+        // they survive the driver dropping this future.
+        let next = self.pending.front().map(|(deliver_at, _)| *deliver_at);
+        let msg = match next {
+          Some(deliver_at) => {
+            tokio::select! {
+              _ = sleep_until(deliver_at) => {
+                return self.pending.pop_front().map(|(_, msg)| msg);
+              }
+              msg = self.inner.recv() => msg,
+            }
+          }
+          None => self.inner.recv().await,
+        };
+        match msg {
+          Some(msg) => self.schedule(msg),
+          // the connection closed, so whatever was still in flight is lost
+          None => {
+            self.pending.clear();
+            return None;
+          }
+        }
+      }
+    })
+  }
+
+  fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+    self.inner.events_sender()
+  }
+
+  fn timed_events_sender(&self) -> Weak<broadcast::Sender<TimedMessage>> {
+    self.inner.timed_events_sender()
+  }
+
+  fn dropped_messages(&self) -> u64 {
+    self.inner.dropped_messages()
+  }
+
+  fn reconnect(&mut self) -> Result<(), LumatoneMidiError> {
+    self.inner.reconnect()?;
+    self.pending.clear();
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    clock::MockClock,
+    commands::{ping, set_key_color, Command},
+    constants::{key_loc_unchecked, RGBColor},
+    driver::MidiDriver,
+    responses::Response,
+    shutdown::CancellationToken,
+    suspend::ConnectionEvent,
+  };
+
+  fn start_driver(script: Vec<Fault>) -> (MidiDriver, CancellationToken) {
+    let shutdown = CancellationToken::new();
+    let transport = FaultInjector::new(FakeDevice::new(), script);
+    let (driver, driver_future) = MidiDriver::with_transport(transport, shutdown.clone());
+    tokio::spawn(driver_future);
+    (driver, shutdown)
+  }
+
+  async fn assert_pong(driver: &MidiDriver, value: u32) {
+    match driver.send(ping(value)).await {
+      Ok(Response::Pong(v)) => assert_eq!(v, value),
+      res => panic!("unexpected result: {res:?}"),
+    }
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_no_faults() {
+    let (driver, shutdown) = start_driver(vec![]);
+    assert_pong(&driver, 1).await;
+    let res = driver
+      .send(set_key_color(key_loc_unchecked(1, 1), RGBColor::red()))
+      .await;
+    assert!(matches!(res, Ok(Response::Ack(_))));
+    let res = driver.send(Command::GetFirmwareRevision).await;
+    assert!(matches!(res, Ok(Response::FirmwareRevision(_))));
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_dropped_response_times_out() {
+    let (driver, shutdown) = start_driver(vec![Fault::Drop]);
+    let err = driver.send(ping(1)).await.unwrap_err();
+    assert!(matches!(
+      err.current_context(),
+      LumatoneMidiError::ResponseTimedOut
+    ));
+    assert_pong(&driver, 2).await;
+    shutdown.cancel();
+  }
+
+  #[tokio::test]
+  async fn test_receive_timeout_follows_mock_clock() {
+    let clock = MockClock::new();
+    let shutdown = CancellationToken::new();
+    let transport = FaultInjector::new(FakeDevice::new(), vec![Fault::Drop]);
+    let (driver, driver_future) =
+      MidiDriver::with_clock(transport, shutdown.clone(), clock.clone());
+    tokio::spawn(driver_future);
+
+    let sender = driver.clone();
+    let response = tokio::spawn(async move { sender.send(ping(1)).await });
+    clock.wait_for_sleepers(1).await;
+    clock.advance(Duration::from_secs(29));
+    assert!(!response.is_finished());
+
+    clock.advance(Duration::from_secs(1));
+    let err = response.await.unwrap().unwrap_err();
+    assert!(matches!(
+      err.current_context(),
+      LumatoneMidiError::ResponseTimedOut
+    ));
+    assert_pong(&driver, 2).await;
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_delayed_response() {
+    let (driver, shutdown) = start_driver(vec![Fault::Delay(Duration::from_secs(2))]);
+    let start = Instant::now();
+    assert_pong(&driver, 1).await;
+    assert!(start.elapsed() >= Duration::from_secs(2));
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_busy_response_is_retried() {
+    let (driver, shutdown) = start_driver(vec![Fault::Busy, Fault::Busy]);
+    assert_pong(&driver, 1).await;
+    let metrics = driver.metrics();
+    assert_eq!(metrics.commands_sent, 1);
+    assert_eq!(metrics.commands_resent, 2);
+    assert_eq!((metrics.responses, metrics.failures), (1, 0));
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_corrupted_response_fails() {
+    let (driver, shutdown) = start_driver(vec![Fault::Corrupt]);
+    assert!(driver.send(ping(1)).await.is_err());
+    assert_pong(&driver, 2).await;
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_disconnect_stops_driver() {
+    let (driver, shutdown) = start_driver(vec![Fault::None, Fault::Disconnect]);
+    assert_pong(&driver, 1).await;
+    assert!(driver.send(ping(2)).await.is_err());
+    assert!(driver.send(ping(3)).await.is_err());
+    shutdown.cancel();
+  }
+
+  #[test]
+  fn test_check_conformance() {
+    let mut table_response = vec![ResponseStatusCode::Ack.into()];
+    table_response.extend([0; 128]);
+    let fixtures = FixtureSet {
+      firmware: None,
+      fixtures: vec![
+        Fixture {
+          command: "Ping(1)".to_string(),
+          request: ping(1).to_sysex_message(),
+          response: fake_response(&ping(1).to_sysex_message()),
+        },
+        Fixture {
+          command: "GetLumatouchConfig".to_string(),
+          request: Command::GetLumatouchConfig.to_sysex_message(),
+          response: create_sysex(
+            BoardIndex::Server,
+            CommandId::GetLumatouchConfig,
+            table_response,
+          ),
+        },
+      ],
+    };
+    let problems = FakeDevice::check_conformance(&fixtures);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].starts_with("GetLumatouchConfig: "));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_replay_device() {
+    let request = Command::GetFirmwareRevision.to_sysex_message();
+    let response = create_sysex(
+      BoardIndex::Server,
+      CommandId::GetFirmwareRevision,
+      vec![ResponseStatusCode::Ack.into(), 1, 1, 0],
+    );
+    let fixtures = FixtureSet {
+      firmware: None,
+      fixtures: vec![Fixture {
+        command: "GetFirmwareRevision".to_string(),
+        request,
+        response,
+      }],
+    };
+    let shutdown = CancellationToken::new();
+    let (driver, driver_future) =
+      MidiDriver::with_transport(ReplayDevice::new(fixtures), shutdown.clone());
+    tokio::spawn(driver_future);
+
+    match driver.send(Command::GetFirmwareRevision).await {
+      Ok(Response::FirmwareRevision(v)) => assert_eq!(v.to_string(), "1.1.0"),
+      res => panic!("unexpected result: {res:?}"),
+    }
+    // not in the fixtures, so answered like a FakeDevice
+    assert_pong(&driver, 1).await;
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_simulated_latency() {
+    let shutdown = CancellationToken::new();
+    let profile = LatencyProfile {
+      latency: Duration::from_millis(30),
+      jitter: Duration::from_millis(10),
+      busy_rate: 0.5,
+    };
+    let transport = SimulatedLatency::new(FakeDevice::new(), profile).with_seed(1);
+    let (driver, driver_future) = MidiDriver::with_transport(transport, shutdown.clone());
+    tokio::spawn(driver_future);
+
+    for value in 1..=5 {
+      let start = Instant::now();
+      // BUSY responses are retried, so a ping only fails if the device stays busy
+      match driver.send(ping(value)).await {
+        Ok(Response::Pong(v)) => assert_eq!(v, value),
+        Err(err) => assert!(matches!(
+          err.current_context(),
+          LumatoneMidiError::DeviceBusy
+        )),
+        res => panic!("unexpected result: {res:?}"),
+      }
+      assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+    shutdown.cancel();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_reconnect_after_disconnect() {
+    let shutdown = CancellationToken::new();
+    let transport =
+      FaultInjector::new(FakeDevice::new(), [Fault::None, Fault::Disconnect]).with_reconnect();
+    let (driver, driver_future) = MidiDriver::with_transport(transport, shutdown.clone());
+    let mut connection = driver.subscribe_connection().unwrap();
+    tokio::spawn(driver_future);
+
+    assert_pong(&driver, 1).await;
+    assert!(driver.send(ping(2)).await.is_err());
+    assert_eq!(connection.recv().await.unwrap(), ConnectionEvent::Closed);
+    assert_eq!(
+      connection.recv().await.unwrap(),
+      ConnectionEvent::Reconnected
+    );
+    assert_pong(&driver, 3).await;
+    shutdown.cancel();
+  }
+}
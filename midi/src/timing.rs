@@ -0,0 +1,113 @@
+//! When events were played, as opposed to when they were handled.
+//!
+//! The input callback stamps every channel message with the [Instant] it arrived, before
+//! it's queued anywhere, and broadcasts it as a [TimedMessage] (see
+//! [MidiDriver::subscribe_timed_events](crate::driver::MidiDriver::subscribe_timed_events)).
+//! Code that cares about timing, like the [SessionRecorder](crate::session::SessionRecorder)
+//! or a phrase trainer, should use that time rather than the time it gets around to the
+//! message, which depends on how busy the runtime is.
+//!
+//! Messages also take a while to get from the device to the callback. That part can't be
+//! measured per message, but it's about half the round trip time of a sysex command, so
+//! [estimate_input_latency] works it out from the driver's
+//! [response latencies](crate::metrics::HistogramSnapshot). Subtract it with
+//! [TimedMessage::played_at] to get roughly when the key was pressed.
+
+use std::time::{Duration, Instant};
+
+use super::{events::ChannelMessage, metrics::HistogramSnapshot};
+
+/// The input latency assumed until the driver has timed some responses.
+pub const DEFAULT_INPUT_LATENCY: Duration = Duration::from_millis(2);
+
+/// Round trips longer than this are retries or timeouts, and don't say anything about the
+/// connection, so the estimate ignores them.
+const MAX_ROUND_TRIP: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedMessage {
+  pub message: ChannelMessage,
+
+  /// When the input callback received the message.
+  pub received: Instant,
+
+  /// The timestamp the MIDI backend gave the message. Its origin depends on the backend, so
+  /// only the difference between two messages' stamps means anything.
+  pub stamp: Duration,
+}
+
+impl TimedMessage {
+  pub fn new(message: ChannelMessage, received: Instant, stamp: Duration) -> Self {
+    TimedMessage {
+      message,
+      received,
+      stamp,
+    }
+  }
+
+  /// Roughly when the message was played, given the latency between the device and the
+  /// input callback.
+  pub fn played_at(&self, latency: Duration) -> Instant {
+    self.received.checked_sub(latency).unwrap_or(self.received)
+  }
+}
+
+/// Estimates the time between the device sending a message and the input callback receiving
+/// it, as half the mean time the device took to answer commands. Falls back to
+/// [DEFAULT_INPUT_LATENCY] if no commands have been timed.
+pub fn estimate_input_latency(round_trips: &HistogramSnapshot) -> Duration {
+  // only the buckets up to MAX_ROUND_TRIP count; take each one's midpoint
+  let mut lower = Duration::ZERO;
+  let mut counted = 0;
+  let mut sum = Duration::ZERO;
+  for (upper, cumulative) in &round_trips.buckets {
+    if *upper > MAX_ROUND_TRIP {
+      break;
+    }
+    let n = cumulative - counted;
+    sum += (lower + *upper) / 2 * n as u32;
+    counted = *cumulative;
+    lower = *upper;
+  }
+  if counted == 0 {
+    return DEFAULT_INPUT_LATENCY;
+  }
+  sum / counted as u32 / 2
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::constants::MidiChannel;
+
+  #[test]
+  fn test_estimate_input_latency() {
+    assert_eq!(
+      estimate_input_latency(&HistogramSnapshot::default()),
+      DEFAULT_INPUT_LATENCY
+    );
+
+    let ms = Duration::from_millis;
+    let round_trips = HistogramSnapshot {
+      buckets: vec![(ms(5), 2), (ms(10), 4), (ms(250), 5), (ms(30_000), 6)],
+      count: 6,
+      sum: ms(30_050),
+    };
+    // two at 2.5ms and two at 7.5ms; the slow ones are ignored
+    assert_eq!(
+      estimate_input_latency(&round_trips),
+      Duration::from_micros(2500)
+    );
+
+    let msg = TimedMessage::new(
+      ChannelMessage::NoteOn {
+        channel: MidiChannel::unchecked(1),
+        note: 60,
+        velocity: 100,
+      },
+      Instant::now(),
+      Duration::ZERO,
+    );
+    assert_eq!(msg.received - msg.played_at(ms(3)), ms(3));
+  }
+}
@@ -0,0 +1,177 @@
+//! A bounded log of recent sysex traffic between the driver and the device.
+//!
+//! The driver keeps the last [DEFAULT_TRAFFIC_CAPACITY] messages it sent and received in a
+//! [TrafficLog], with timestamps and a short decoded summary, so that after something goes
+//! wrong it's possible to see what just happened without having had a full capture running.
+//! Use [MidiDriver::recent_traffic](crate::driver::MidiDriver::recent_traffic) or
+//! [Lumatone::recent_traffic](crate::controller::Lumatone::recent_traffic) to read it.
+
+use std::{
+  collections::VecDeque,
+  fmt::Display,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+  commands::Command,
+  sysex::{message_answer_code, message_command_id, to_hex_debug_str},
+};
+
+/// How many messages the driver keeps by default.
+pub const DEFAULT_TRAFFIC_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficDirection {
+  /// Sent to the device.
+  Outbound,
+  /// Received from the device.
+  Inbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficEntry {
+  pub time: SystemTime,
+  pub direction: TrafficDirection,
+  /// The decoded command, or the command id and status of a response.
+  pub summary: String,
+  pub message: Vec<u8>,
+}
+
+impl TrafficEntry {
+  pub fn outbound(command: &Command, message: &[u8]) -> Self {
+    TrafficEntry {
+      time: SystemTime::now(),
+      direction: TrafficDirection::Outbound,
+      summary: command.to_string(),
+      message: message.to_vec(),
+    }
+  }
+
+  pub fn inbound(message: &[u8]) -> Self {
+    let summary = match message_command_id(message) {
+      Ok(cmd) => format!("{cmd:?} {:?}", message_answer_code(message)),
+      Err(e) => format!("undecodable ({e})"),
+    };
+    TrafficEntry {
+      time: SystemTime::now(),
+      direction: TrafficDirection::Inbound,
+      summary,
+      message: message.to_vec(),
+    }
+  }
+}
+
+impl Display for TrafficEntry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let since_epoch = self
+      .time
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or(Duration::ZERO);
+    let arrow = match self.direction {
+      TrafficDirection::Outbound => "->",
+      TrafficDirection::Inbound => "<-",
+    };
+    write!(
+      f,
+      "{}.{:03} {arrow} {} {}",
+      since_epoch.as_secs(),
+      since_epoch.subsec_millis(),
+      self.summary,
+      to_hex_debug_str(&self.message)
+    )
+  }
+}
+
+/// A ring buffer of [TrafficEntry]s. Once full, recording a message drops the oldest.
+#[derive(Debug, Clone)]
+pub struct TrafficLog {
+  capacity: usize,
+  entries: VecDeque<TrafficEntry>,
+}
+
+impl TrafficLog {
+  pub fn new(capacity: usize) -> Self {
+    TrafficLog {
+      capacity,
+      entries: VecDeque::with_capacity(capacity),
+    }
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  /// Changes the capacity, dropping the oldest entries if there are now too many.
+  pub fn set_capacity(&mut self, capacity: usize) {
+    self.capacity = capacity;
+    while self.entries.len() > capacity {
+      self.entries.pop_front();
+    }
+  }
+
+  pub fn record(&mut self, entry: TrafficEntry) {
+    if self.capacity == 0 {
+      return;
+    }
+    if self.entries.len() == self.capacity {
+      self.entries.pop_front();
+    }
+    self.entries.push_back(entry);
+  }
+
+  /// The recorded entries, oldest first.
+  pub fn entries(&self) -> Vec<TrafficEntry> {
+    self.entries.iter().cloned().collect()
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.entries.clear();
+  }
+}
+
+impl Default for TrafficLog {
+  fn default() -> Self {
+    TrafficLog::new(DEFAULT_TRAFFIC_CAPACITY)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::commands::ping;
+
+  #[test]
+  fn test_traffic_log_keeps_most_recent() {
+    let mut log = TrafficLog::new(2);
+    for i in 0..3 {
+      let cmd = ping(i);
+      log.record(TrafficEntry::outbound(&cmd, &cmd.to_sysex_message()));
+    }
+    let summaries = log
+      .entries()
+      .into_iter()
+      .map(|e| e.summary)
+      .collect::<Vec<_>>();
+    assert_eq!(summaries, vec!["Ping(1)", "Ping(2)"]);
+
+    let reply = ping(2).to_sysex_message();
+    let entry = TrafficEntry::inbound(&reply);
+    assert_eq!(entry.direction, TrafficDirection::Inbound);
+    assert!(entry.summary.starts_with("LumaPing"), "{}", entry.summary);
+    assert!(entry.to_string().contains(" <- LumaPing"));
+
+    log.set_capacity(1);
+    assert_eq!(log.len(), 1);
+    log.set_capacity(0);
+    log.record(entry);
+    assert!(log.is_empty());
+  }
+}
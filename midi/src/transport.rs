@@ -0,0 +1,84 @@
+//! The connection that the [MidiDriver](crate::driver::MidiDriver) sends and receives
+//! sysex messages on.
+//!
+//! [LumatoneIO] is the real MIDI connection. Other implementations can stand in for the
+//! device in tests (see [crate::testing]).
+
+use std::sync::Weak;
+
+use futures::future::BoxFuture;
+use tokio::sync::broadcast;
+
+use super::{
+  device::LumatoneIO, error::LumatoneMidiError, events::ChannelMessage, sysex::EncodedSysex,
+  timing::TimedMessage,
+};
+
+use error_stack::{bail, Result};
+
+pub trait Transport: Send + 'static {
+  /// Sends an encoded sysex message to the device.
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError>;
+
+  /// Resolves with the next sysex message from the device, or `None` if the connection
+  /// has closed.
+  ///
+  /// The driver polls this inside a `select!`, so the returned future must be cancel-safe:
+  /// dropping it before it resolves must not lose a message.
+  fn recv(&mut self) -> BoxFuture<'_, Option<EncodedSysex>>;
+
+  /// Returns a weak reference to the sender for notes, controller changes, etc. played on
+  /// the device. See [LumatoneIO::events_sender].
+  fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>>;
+
+  /// Like [events_sender](Transport::events_sender), for messages stamped with the time
+  /// they arrived. See [crate::timing].
+  ///
+  /// The default implementation returns a sender that's already gone, for transports that
+  /// don't time their events.
+  fn timed_events_sender(&self) -> Weak<broadcast::Sender<TimedMessage>> {
+    Weak::new()
+  }
+
+  /// How many incoming sysex messages the transport has dropped because they weren't
+  /// received in time (see [crate::channels]).
+  ///
+  /// The default implementation returns 0, for transports that never drop messages.
+  fn dropped_messages(&self) -> u64 {
+    0
+  }
+
+  /// Closes the connection and opens it again. The driver calls this after the host wakes
+  /// from sleep, or when [recv](Transport::recv) reports that the connection has closed.
+  ///
+  /// The default implementation fails, for transports that can't reconnect.
+  fn reconnect(&mut self) -> Result<(), LumatoneMidiError> {
+    bail!(LumatoneMidiError::DeviceConnectionError)
+  }
+}
+
+impl Transport for LumatoneIO {
+  fn send(&mut self, msg: &[u8]) -> Result<(), LumatoneMidiError> {
+    LumatoneIO::send(self, msg)
+  }
+
+  fn recv(&mut self) -> BoxFuture<'_, Option<EncodedSysex>> {
+    Box::pin(self.incoming_messages.recv())
+  }
+
+  fn events_sender(&self) -> Weak<broadcast::Sender<ChannelMessage>> {
+    LumatoneIO::events_sender(self)
+  }
+
+  fn timed_events_sender(&self) -> Weak<broadcast::Sender<TimedMessage>> {
+    LumatoneIO::timed_events_sender(self)
+  }
+
+  fn dropped_messages(&self) -> u64 {
+    LumatoneIO::dropped_messages(self)
+  }
+
+  fn reconnect(&mut self) -> Result<(), LumatoneMidiError> {
+    LumatoneIO::reconnect(self)
+  }
+}
@@ -0,0 +1,114 @@
+//! Zones pick out a region of the keyboard by the channel and note number that keys send
+//! on, so the [note proxy](crate::proxy) can treat parts of the board differently.
+//!
+//! Zones match the incoming message, before the proxy's [NoteMapping](crate::proxy::NoteMapping)
+//! is applied. Since each board usually sends on its own channel, a zone is often just a
+//! channel.
+
+use super::{aftertouch::PressureConversion, constants::MidiChannel, curve::ResponseCurve};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zone {
+  /// If `None`, the zone includes every channel.
+  pub channel: Option<MidiChannel>,
+  pub low_note: u8,
+  pub high_note: u8,
+}
+
+impl Zone {
+  /// A zone covering every note on every channel.
+  pub fn all() -> Self {
+    Zone {
+      channel: None,
+      low_note: 0,
+      high_note: 127,
+    }
+  }
+
+  /// A zone covering every note on one channel.
+  pub fn channel(channel: MidiChannel) -> Self {
+    Zone {
+      channel: Some(channel),
+      ..Zone::all()
+    }
+  }
+
+  /// A zone covering a range of notes on every channel.
+  pub fn notes(low_note: u8, high_note: u8) -> Self {
+    Zone {
+      channel: None,
+      low_note,
+      high_note,
+    }
+  }
+
+  pub fn contains(&self, channel: MidiChannel, note: u8) -> bool {
+    self.channel.is_none_or(|c| c == channel) && (self.low_note..=self.high_note).contains(&note)
+  }
+}
+
+impl Default for Zone {
+  fn default() -> Self {
+    Zone::all()
+  }
+}
+
+/// A velocity curve applied to note-ons in a [Zone].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VelocityZone {
+  pub zone: Zone,
+  pub curve: ResponseCurve,
+}
+
+/// Converts aftertouch in a [Zone] between polyphonic aftertouch and channel pressure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PressureZone {
+  pub zone: Zone,
+  pub conversion: PressureConversion,
+}
+
+/// Returns the velocity to send for a note-on, using the curve of the first zone in `zones`
+/// that contains the note. Notes outside every zone are unchanged.
+///
+/// A non-zero velocity never maps to zero, since a note-on with velocity zero is a note-off.
+pub fn apply_velocity_zones(
+  zones: &[VelocityZone],
+  channel: MidiChannel,
+  note: u8,
+  velocity: u8,
+) -> u8 {
+  if velocity == 0 {
+    return 0;
+  }
+  match zones.iter().find(|z| z.zone.contains(channel, note)) {
+    Some(z) => z.curve.apply(velocity).max(1),
+    None => velocity,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_velocity_zones() {
+    let ch1 = MidiChannel::unchecked(1);
+    let ch2 = MidiChannel::unchecked(2);
+    let zones = vec![
+      VelocityZone {
+        zone: Zone::channel(ch1),
+        curve: ResponseCurve::Power(2.0),
+      },
+      VelocityZone {
+        zone: Zone::notes(60, 72),
+        curve: ResponseCurve::Table(Box::new([127; 128])),
+      },
+    ];
+
+    assert_eq!(apply_velocity_zones(&zones, ch1, 64, 64), 32);
+    assert_eq!(apply_velocity_zones(&zones, ch1, 64, 1), 1);
+    assert_eq!(apply_velocity_zones(&zones, ch2, 64, 64), 127);
+    assert_eq!(apply_velocity_zones(&zones, ch2, 80, 64), 64);
+    assert_eq!(apply_velocity_zones(&zones, ch2, 64, 0), 0);
+  }
+}